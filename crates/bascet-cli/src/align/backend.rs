@@ -0,0 +1,416 @@
+//! Aligner backend trait and the shared TIRP → tagged BAM → sorted BAM driver.
+//!
+//! Backends only align. `run_aligner` owns everything around them: it streams the TIRP input
+//! into memory-bounded [`ReadBatch`]es named `{cell}:{umi}:{n}`, hands each batch to the
+//! backend, turns the SAM it emits into `CB:Z` / `UB:Z`-tagged BAM records, publishes the
+//! unsorted BAM and sorts + indexes it.
+
+use anyhow::{Context, Result};
+use bascet_core::{
+    attr::{meta::*, quality::*, sequence::*},
+    *,
+};
+use bascet_io::{parse, tirp};
+use bounded_integer::BoundedU64;
+use bytesize::ByteSize;
+use std::path::Path;
+use tracing::{debug, info};
+
+use super::output::{TaggedBamOutput, write_bascet_read_name};
+use crate::command::{
+    bamsort::{BamIndexArgs, sort_and_index_bam},
+    samtools_rs::sort::ReferenceOrder,
+};
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
+use crate::utils::{
+    atomic_temp_path_in_dir, publish_atomic_output, rename_or_copy_across_filesystems,
+};
+
+/// Upper bound for the decode stream buffer once the index is resident. Batches are bounded by
+/// [`BatchLimits`], so a larger read-ahead only costs RAM.
+const MAX_STREAM_BUFFER: ByteSize = ByteSize::mib(256);
+
+/// Where a batch ends: whichever limit is reached first.
+#[derive(Debug, Clone, Copy)]
+pub struct BatchLimits {
+    /// Total sequence bases (R1 + R2) per batch.
+    pub target_bases: usize,
+    /// Read pairs per batch. Bounds per-batch scratch for aligners whose memory scales with the
+    /// number of reads rather than their length.
+    pub max_pairs: usize,
+}
+
+/// Paths and resources of one alignment run, shared by every backend.
+pub struct AlignJob<'a> {
+    pub path_in: &'a Path,
+    pub path_out_unsorted: &'a Path,
+    pub path_out_sorted: Option<&'a Path>,
+    pub path_temp: &'a Path,
+    pub report_outputs: bool,
+    pub total_memory: ByteSize,
+    pub total_threads: u64,
+    pub read_threads: BoundedU64<1, { u64::MAX }>,
+    pub write_bam_threads: usize,
+    pub sizeof_stream_arena: ByteSize,
+    pub sizeof_stream_buffer: ByteSize,
+    pub max_read_pairs: Option<u64>,
+}
+
+/// A read aligner: batches of read pairs in, SAM records out.
+///
+/// Implementations must keep the read name as QNAME; cell and UMI tags are recovered from it.
+pub trait Aligner {
+    /// Short name used in logs and SAM parse errors.
+    fn name(&self) -> &'static str;
+
+    /// SAM header of the backend's output, or `None` if it is only known from the `@` lines the
+    /// backend writes ahead of its first record (e.g. an external process).
+    fn sam_header(&self) -> Result<Option<String>>;
+
+    fn batch_limits(&self) -> BatchLimits;
+
+    /// Align one batch and write its SAM records to `output`. Records may lag behind their
+    /// batch (an external process still holding reads); `finish` must flush whatever is left.
+    fn align_batch(&mut self, batch: &ReadBatch, output: &mut TaggedBamOutput) -> Result<()>;
+
+    /// Called once after the last batch.
+    fn finish(&mut self, _output: &mut TaggedBamOutput) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Borrowed view of one read pair in a [`ReadBatch`]. Single-end reads have empty `r2`/`q2`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadPair<'a> {
+    pub name: &'a str,
+    pub cell_id: &'a str,
+    pub umi: Option<&'a str>,
+    pub r1: &'a [u8],
+    pub q1: &'a [u8],
+    pub r2: &'a [u8],
+    pub q2: &'a [u8],
+}
+
+/// Offsets/lengths of one pair's fields inside `ReadBatch::bytes`: name, cell, umi, r1, q1, r2,
+/// q2.
+type PairSlices = [(u32, u32); 7];
+
+/// Arena-backed batch of read pairs. All field bytes live in one contiguous `Vec<u8>` (one
+/// allocation per batch instead of seven per pair) and [`ReadPair`] views borrow into it. The
+/// arena is reused across batches via `clear()`, so steady-state allocation is bounded.
+#[derive(Default, Clone)]
+pub struct ReadBatch {
+    bytes: Vec<u8>,
+    pairs: Vec<PairSlices>,
+    bases: usize,
+}
+
+impl ReadBatch {
+    pub fn push(&mut self, pair: &ReadPair<'_>) -> Result<()> {
+        if pair.r1.len() != pair.q1.len() {
+            anyhow::bail!(
+                "R1 sequence/quality length mismatch for {}: {} != {}",
+                pair.name,
+                pair.r1.len(),
+                pair.q1.len()
+            );
+        }
+        if pair.r2.len() != pair.q2.len() {
+            anyhow::bail!(
+                "R2 sequence/quality length mismatch for {}: {} != {}",
+                pair.name,
+                pair.r2.len(),
+                pair.q2.len()
+            );
+        }
+
+        let fields = [
+            pair.name.as_bytes(),
+            pair.cell_id.as_bytes(),
+            pair.umi.unwrap_or_default().as_bytes(),
+            pair.r1,
+            pair.q1,
+            pair.r2,
+            pair.q2,
+        ];
+        let mut slices = PairSlices::default();
+        for (slice, field) in slices.iter_mut().zip(fields) {
+            *slice = self.push_bytes(field)?;
+        }
+        self.pairs.push(slices);
+        self.bases += pair.r1.len() + pair.r2.len();
+        Ok(())
+    }
+
+    fn push_bytes(&mut self, src: &[u8]) -> Result<(u32, u32)> {
+        let off = u32::try_from(self.bytes.len())
+            .map_err(|_| anyhow::anyhow!("read batch arena exceeded 4 GiB"))?;
+        let len = u32::try_from(src.len())
+            .map_err(|_| anyhow::anyhow!("read batch field exceeded 4 GiB"))?;
+        self.bytes.extend_from_slice(src);
+        Ok((off, len))
+    }
+
+    pub fn clear(&mut self) {
+        self.bytes.clear();
+        self.pairs.clear();
+        self.bases = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    /// Total sequence bases (R1 + R2) over all pairs.
+    pub fn bases(&self) -> usize {
+        self.bases
+    }
+
+    pub fn get(&self, i: usize) -> ReadPair<'_> {
+        let [name, cell_id, umi, r1, q1, r2, q2] =
+            self.pairs[i].map(|(off, len)| &self.bytes[off as usize..off as usize + len as usize]);
+        // SAFETY: name, cell id and UMI were copied from `&str`s in `push`; the arena is
+        // append-only within a batch.
+        let (name, cell_id, umi) = unsafe {
+            (
+                std::str::from_utf8_unchecked(name),
+                std::str::from_utf8_unchecked(cell_id),
+                std::str::from_utf8_unchecked(umi),
+            )
+        };
+        ReadPair {
+            name,
+            cell_id,
+            umi: (!umi.is_empty()).then_some(umi),
+            r1,
+            q1,
+            r2,
+            q2,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ReadPair<'_>> {
+        (0..self.len()).map(|i| self.get(i))
+    }
+}
+
+/// Align a TIRP file end-to-end with `aligner`: TIRP → batches → tagged BAM (published to
+/// `job.path_out_unsorted`) → sort → index (`job.path_out_sorted`, skipped if unset).
+pub fn run_aligner<A: Aligner>(mut aligner: A, job: &AlignJob<'_>) -> Result<()> {
+    let name = aligner.name();
+    info!(
+        aligner = name,
+        input = %job.path_in.display(),
+        output = %job.path_out_unsorted.display(),
+        "Starting alignment"
+    );
+    std::fs::create_dir_all(job.path_temp)
+        .with_context(|| format!("failed to create temp dir {}", job.path_temp.display()))?;
+
+    // Pre-flight: the aligner index is already resident. If it alone does not fit in the budget,
+    // fail fast instead of thrashing. The streaming driver itself needs only ~one batch on top.
+    if let Some(mem) = memory_stats::memory_stats() {
+        let rss = ByteSize(mem.physical_mem as u64);
+        if rss.as_u64() >= job.total_memory.as_u64() {
+            anyhow::bail!(
+                "{name} index/runtime RSS ({rss}) already meets or exceeds --memory {}; increase --memory",
+                job.total_memory
+            );
+        }
+        debug!(aligner = name, index_loaded_rss = %rss, "Alignment driver: memory pre-flight");
+    }
+    let sizeof_stream_buffer = super::stream_helpers::stream_buffer_after_index_load(
+        name,
+        job.total_memory,
+        job.sizeof_stream_buffer,
+        job.sizeof_stream_arena,
+        MAX_STREAM_BUFFER,
+    );
+
+    // Output BAM (published atomically on success). Staged under --temp until complete.
+    let path_out_tmp = atomic_temp_path_in_dir(job.path_out_unsorted, job.path_temp);
+    let mut output = match aligner.sam_header()? {
+        Some(header) => {
+            TaggedBamOutput::with_header(&path_out_tmp, &header, job.write_bam_threads, name)?
+        }
+        None => TaggedBamOutput::new(&path_out_tmp, job.write_bam_threads, name),
+    };
+    let aligned = align_tirp_batches(&mut aligner, job, sizeof_stream_buffer, &mut output)
+        .and_then(|n_pairs| {
+            aligner.finish(&mut output)?;
+            Ok(n_pairs)
+        })
+        .and_then(|n_pairs| Ok((n_pairs, output.finish()?)));
+    let (n_pairs, n_records) = match aligned {
+        Ok(counts) => counts,
+        Err(err) => {
+            let _ = std::fs::remove_file(&path_out_tmp);
+            return Err(err);
+        }
+    };
+    if job.report_outputs {
+        publish_atomic_output(&path_out_tmp, job.path_out_unsorted)?;
+    } else {
+        rename_or_copy_across_filesystems(&path_out_tmp, job.path_out_unsorted)?;
+    }
+    info!(
+        aligner = name,
+        read_pairs = n_pairs,
+        records = n_records,
+        "Alignment done"
+    );
+
+    // Free the aligner (index, workers, child process) before the sort phase so the in-process
+    // sort gets the full memory budget.
+    drop(aligner);
+
+    let Some(path_out_sorted) = job.path_out_sorted else {
+        info!(aligner = name, "All alignment steps complete");
+        return Ok(());
+    };
+    info!("Sorting + indexing BAM file (in-process)");
+    sort_and_index_bam(
+        job.path_out_unsorted,
+        path_out_sorted,
+        job.path_temp,
+        job.total_memory,
+        job.total_threads as usize,
+        ReferenceOrder::Lexicographic,
+        &BamIndexArgs::default(),
+    )?;
+
+    info!(aligner = name, "All alignment steps complete");
+    Ok(())
+}
+
+/// Stream the TIRP input through `aligner` in [`BatchLimits`]-sized batches. Returns the number
+/// of read pairs aligned.
+fn align_tirp_batches<A: Aligner>(
+    aligner: &mut A,
+    job: &AlignJob<'_>,
+    sizeof_stream_buffer: ByteSize,
+    output: &mut TaggedBamOutput,
+) -> Result<u64> {
+    let decoder = open_bbgz_decoder(job.path_in, job.read_threads, None)?;
+    let parser = parse::Tirp::builder().build();
+    let mut stream = Stream::builder()
+        .with_decoder(decoder)
+        .with_parser(parser)
+        .sizeof_decode_arena(job.sizeof_stream_arena)
+        .sizeof_decode_buffer(sizeof_stream_buffer)
+        .build();
+    let mut query = stream.query::<tirp::Record>();
+
+    let limits = aligner.batch_limits();
+    let max_pairs = limits.max_pairs.max(1);
+    debug!(
+        aligner = aligner.name(),
+        target_bases = limits.target_bases,
+        max_pairs,
+        "Alignment driver: batch limits"
+    );
+
+    let mut batch = ReadBatch::default();
+    let mut name_buf = String::new();
+    let mut num_read = 0_u64;
+    loop {
+        if job.max_read_pairs.is_some_and(|limit| num_read >= limit) {
+            info!(
+                read_pairs = num_read,
+                "Stopped input after requested read-pair limit"
+            );
+            break;
+        }
+        let record = match query.next_into::<tirp::Record>() {
+            Ok(Some(record)) => record,
+            Ok(None) => break,
+            Err(err) => anyhow::bail!("failed to read TIRP input: {err:?}"),
+        };
+        let record_id = *record.get_ref::<Id>();
+        let record_umi = *record.get_ref::<Umi>();
+        name_buf.clear();
+        write_bascet_read_name(&mut name_buf, record_id, record_umi, num_read);
+        let cell_id = std::str::from_utf8(record_id)
+            .with_context(|| format!("cell id is not UTF-8: {record_id:?}"))?;
+        let umi = std::str::from_utf8(record_umi)
+            .with_context(|| format!("UMI is not UTF-8: {record_umi:?}"))?;
+        batch.push(&ReadPair {
+            name: &name_buf,
+            cell_id,
+            umi: (!umi.is_empty()).then_some(umi),
+            r1: *record.get_ref::<R1>(),
+            q1: *record.get_ref::<Q1>(),
+            r2: *record.get_ref::<R2>(),
+            q2: *record.get_ref::<Q2>(),
+        })?;
+        num_read += 1;
+
+        if batch.bases() >= limits.target_bases || batch.len() >= max_pairs {
+            aligner.align_batch(&batch, output)?;
+            batch.clear();
+        }
+        if num_read % 1_000_000 == 0 {
+            info!("{}M read pairs aligned", num_read / 1_000_000);
+        }
+    }
+
+    if !batch.is_empty() {
+        aligner.align_batch(&batch, output)?;
+    }
+    Ok(num_read)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ReadBatch, ReadPair};
+
+    fn pair<'a>(name: &'a str, umi: Option<&'a str>, r2: &'a [u8]) -> ReadPair<'a> {
+        ReadPair {
+            name,
+            cell_id: "CELL",
+            umi,
+            r1: b"ACGT",
+            q1: b"FFFF",
+            r2,
+            q2: &b"IIIIII"[..r2.len()],
+        }
+    }
+
+    #[test]
+    fn batch_round_trips_pairs_and_counts_bases() {
+        let mut batch = ReadBatch::default();
+        batch
+            .push(&pair("CELL:UMI:0", Some("UMI"), b"TTGGCC"))
+            .unwrap();
+        batch.push(&pair("CELL::1", None, b"")).unwrap();
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch.bases(), 14);
+        let first = batch.get(0);
+        assert_eq!(first.name, "CELL:UMI:0");
+        assert_eq!(first.cell_id, "CELL");
+        assert_eq!(first.umi, Some("UMI"));
+        assert_eq!(first.r2, b"TTGGCC");
+        let second = batch.get(1);
+        assert_eq!(second.umi, None);
+        assert!(second.r2.is_empty() && second.q2.is_empty());
+
+        batch.clear();
+        assert!(batch.is_empty());
+        assert_eq!(batch.bases(), 0);
+    }
+
+    #[test]
+    fn batch_rejects_quality_length_mismatch() {
+        let mut batch = ReadBatch::default();
+        let mut bad = pair("CELL:UMI:0", Some("UMI"), b"TTGG");
+        bad.q1 = b"FF";
+
+        assert!(batch.push(&bad).is_err());
+        assert!(batch.is_empty());
+    }
+}
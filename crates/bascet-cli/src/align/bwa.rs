@@ -4,23 +4,17 @@ use std::{
 };
 
 use anyhow::{Context, Result};
-use bytesize::ByteSize;
 use tracing::info;
 
-use super::bwa_stock_driver::{StockDriverState, run_stock_driver_tirp_to_bam};
-use crate::command::{bamsort::sort_and_index_bam, samtools_rs::sort::ReferenceOrder};
+use super::backend::{AlignJob, run_aligner};
+use super::bwa_stock_driver::StockDriverState;
 
-/// Drive the BWAMEM2 stock driver end-to-end: TIRP → pipelined BAM (reader → aligner →
-/// compressor pool → writer with bounded memory + in-flight limiters) → sort → index.
+/// Drive the BWAMEM2 stock driver end-to-end through the shared alignment driver: TIRP →
+/// stock-sized batches → tagged BAM → sort → index.
 pub fn try_execute_bwa_mem2(
-    path_in: &Path,
+    job: &AlignJob<'_>,
     path_genome: &Path,
-    path_out_unsorted: &PathBuf,
-    path_out_sorted: &PathBuf,
-    path_temp: &PathBuf,
     align_threads: usize,
-    total_memory: ByteSize,
-    total_threads: u64,
     worker_pool: Arc<rayon::ThreadPool>,
     max_batch_pairs: u64,
 ) -> Result<()> {
@@ -30,36 +24,21 @@ pub fn try_execute_bwa_mem2(
         "BWAMEM2",
         path_genome,
         index_disk_size,
-        total_memory,
+        job.total_memory,
     );
 
     info!(index_prefix = %path_genome.display(), "Loading BWAMEM2 index");
-    let mut state = StockDriverState::new(path_genome, align_threads, Arc::clone(&worker_pool))?;
-    info!("BWAMEM2 index loaded");
-
-    run_stock_driver_tirp_to_bam(
-        &mut state,
-        path_in,
-        path_out_unsorted,
-        path_temp,
-        total_memory,
-        total_threads,
+    let state = StockDriverState::new(
+        path_genome,
+        align_threads,
         worker_pool,
         max_batch_pairs as usize,
     )?;
-    // Free the BWA aligner before the sort phase so the in-process sort gets the full memory
-    // budget.
-    drop(state);
+    info!("BWAMEM2 index loaded");
 
-    info!("Sorting + indexing BAM file (in-process)");
-    sort_and_index_bam(
-        path_out_unsorted,
-        path_out_sorted,
-        path_temp,
-        total_memory,
-        total_threads as usize,
-        ReferenceOrder::Lexicographic,
-    )?;
+    // `run_aligner` frees the BWA aligner before the sort phase so the in-process sort gets the
+    // full memory budget.
+    run_aligner(state, job)?;
 
     info!("BWAMEM2 alignment complete");
     Ok(())
@@ -7,11 +7,13 @@ use anyhow::Result;
 use bascet_core::Stream;
 use bascet_core::attr::{meta::*, quality::*, sequence::*};
 use bascet_core::*;
-use bascet_io::{codec, parse, tirp};
+use bascet_io::{parse, tirp};
 use bounded_integer::BoundedU64;
 use bytesize::ByteSize;
 use tracing::{debug, info, warn};
 
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
+
 /// Warn (don't fail) if the on-disk size of an aligner's index exceeds the user's memory
 /// budget. Indexes are typically mmap'd or fully loaded — exceeding budget is a soft signal
 /// the run will swap or OOM, but some setups can tolerate it.
@@ -47,10 +49,7 @@ pub fn write_tirp_to_2fq<P>(
 where
     P: AsRef<Path>,
 {
-    let decoder = codec::BBGZDecoder::builder()
-        .with_path(path_in)
-        .countof_threads(num_threads)
-        .build();
+    let decoder = open_bbgz_decoder(path_in.as_ref(), num_threads, None)?;
     let parser = parse::Tirp::builder().build();
 
     let mut stream = Stream::builder()
@@ -1,27 +1,15 @@
-use std::{
-    path::{Path, PathBuf},
-    sync::Arc,
-};
+use std::{path::Path, sync::Arc};
 
 use anyhow::{Context, Result};
-use bascet_core::{
-    attr::{meta::*, quality::*, sequence::*},
-    *,
-};
-use bascet_io::{codec, parse, tirp};
-use bounded_integer::BoundedU64;
 use bytesize::ByteSize;
-use minimap2::{aligner::Aligner, flags::MapFlags, format::sam as minimap_sam, index, map};
-use noodles::sam;
+use minimap2::{
+    aligner::Aligner as MinimapAligner, flags::MapFlags, format::sam as minimap_sam, index, map,
+};
 use rayon::prelude::*;
 use tracing::info;
 
-use super::output::{
-    SamRecordSink, TaggedBamSamSink, TaggedBamWriter, create_tagged_bam_writer,
-    finish_tagged_bam_writer,
-};
-use crate::command::{bamsort::sort_and_index_bam, samtools_rs::sort::ReferenceOrder};
-use crate::utils::{atomic_temp_path_in_dir, publish_atomic_output};
+use super::backend::{AlignJob, Aligner, BatchLimits, ReadBatch, ReadPair, run_aligner};
+use super::output::{SamRecordSink, TaggedBamOutput};
 
 // Outer batch scales with thread count so each parallel mapping scope amortizes spawn/join +
 // serial SAM/BAM write between scopes — same rationale as the bwa-mem2 path.
@@ -35,349 +23,149 @@ const MINIMAP2_BATCH_BASES_MAX: usize = 128 * 1024 * 1024;
 const MINIMAP2_RSS_BYTES_PER_INPUT_BYTE: u64 = 200;
 
 pub fn try_execute_minimap2(
-    path_in: &Path,
+    job: &AlignJob<'_>,
     path_genome: &Path,
-    path_out_unsorted: &PathBuf,
-    path_out_sorted: &PathBuf,
-    path_temp: &PathBuf,
-    numof_threads_writebam: usize,
     align_threads: usize,
-    numof_threads_read: BoundedU64<1, { u64::MAX }>,
-    sizeof_stream_arena: ByteSize,
-    sizeof_stream_buffer: ByteSize,
     preset: &str,
-    total_memory: ByteSize,
-    total_threads: u64,
     rayon_pool: Arc<rayon::ThreadPool>,
 ) -> Result<()> {
     info!(
         preset,
-        total_threads,
+        total_threads = job.total_threads,
         align_threads,
-        read_threads = numof_threads_read.get(),
-        write_bam_threads = numof_threads_writebam,
+        read_threads = job.read_threads.get(),
+        write_bam_threads = job.write_bam_threads,
         "Using direct minimap2-rs aligner"
     );
 
-    let genome_path = path_genome
-        .to_str()
-        .with_context(|| format!("minimap2 genome path is not UTF-8: {path_genome:?}"))?;
-    anyhow::ensure!(
-        index::io::is_idx_file(genome_path)
-            .with_context(|| format!("failed to read minimap2 index path: {path_genome:?}"))?,
-        "minimap2 aligner requires an existing .mmi index; got {path_genome:?}. Build one first, for example with `bascet exttool minimap2 -d <ref.mmi> <ref.fa>`, then pass `--genome <ref.mmi>`."
-    );
-    let index_disk_size = path_genome
-        .metadata()
-        .with_context(|| format!("failed to stat minimap2 index path: {path_genome:?}"))?
-        .len();
-    super::common::warn_if_index_disk_size_exceeds_memory(
-        "minimap2",
+    let aligner = Minimap2Aligner::new(
         path_genome,
-        index_disk_size,
-        total_memory,
-    );
-    let mut aligner = Aligner::builder()
-        .preset(preset)
-        .index(genome_path)
-        .with_cigar()
-        .build()
-        .map_err(anyhow::Error::msg)?;
-    aligner.map_opt.flag |= MapFlags::OUT_SAM | MapFlags::CIGAR;
-    info!("minimap2 index loaded");
-    let sizeof_stream_buffer = super::stream_helpers::stream_buffer_after_index_load(
-        "minimap2",
-        total_memory,
-        sizeof_stream_buffer,
-        sizeof_stream_arena,
-        ByteSize::mib(256),
-    );
-
-    let sam_header = minimap_sam::write_sam_hdr(&aligner.idx, None, &[]);
-    let bam_header = sam_header.parse::<sam::Header>()?;
-    std::fs::create_dir_all(path_temp)
-        .with_context(|| format!("failed to create temp dir {}", path_temp.display()))?;
-    let path_out_unsorted_tmp = atomic_temp_path_in_dir(path_out_unsorted, path_temp);
-    let mut writer_bam =
-        create_tagged_bam_writer(&path_out_unsorted_tmp, &bam_header, numof_threads_writebam)?;
-
-    let pool = rayon_pool;
-
-    align_tirp_with_minimap2(
-        path_in,
-        &aligner,
-        &pool,
-        &bam_header,
-        &mut writer_bam,
-        numof_threads_read,
-        sizeof_stream_arena,
-        sizeof_stream_buffer,
+        preset,
         align_threads,
-        total_memory,
-        Arc::clone(&pool),
+        job.total_memory,
+        rayon_pool,
     )?;
-    finish_tagged_bam_writer(writer_bam)?;
-    publish_atomic_output(&path_out_unsorted_tmp, path_out_unsorted)?;
-
-    info!("Sorting + indexing BAM file (in-process)");
-    sort_and_index_bam(
-        path_out_unsorted,
-        path_out_sorted,
-        path_temp,
-        total_memory,
-        total_threads as usize,
-        ReferenceOrder::Lexicographic,
-    )?;
-
-    info!("All alignment steps complete");
-    Ok(())
+    run_aligner(aligner, job)
 }
 
-fn align_tirp_with_minimap2<P>(
-    path_in: P,
-    aligner: &Aligner,
-    pool: &rayon::ThreadPool,
-    header: &sam::Header,
-    writer_bam: &mut TaggedBamWriter,
-    num_threads: BoundedU64<1, { u64::MAX }>,
-    sizeof_stream_arena: ByteSize,
-    sizeof_stream_buffer: ByteSize,
-    align_threads: usize,
-    total_memory: ByteSize,
-    rayon_pool: Arc<rayon::ThreadPool>,
-) -> Result<()>
-where
-    P: AsRef<Path>,
-{
-    // Don't share the alignment rayon pool with BBGZDecoder; see align_bwa.rs comment.
-    let _ = rayon_pool;
-    let decoder = codec::BBGZDecoder::builder()
-        .with_path(path_in)
-        .countof_threads(num_threads)
-        .build();
-    let parser = parse::Tirp::builder().build();
-
-    let mut stream = Stream::builder()
-        .with_decoder(decoder)
-        .with_parser(parser)
-        .sizeof_decode_arena(sizeof_stream_arena)
-        .sizeof_decode_buffer(sizeof_stream_buffer)
-        .build();
-
-    let mut query = stream.query::<tirp::Record>();
-    let mut batch = MinimapReadBatch::default();
-    let memory_cap = super::stream_helpers::aligner_batch_bases_cap(
-        "minimap2",
-        total_memory,
-        MINIMAP2_RSS_BYTES_PER_INPUT_BYTE,
-        MINIMAP2_BATCH_BASES_MAX,
-    );
-    let uncapped_batch_target_bases =
-        MINIMAP2_BASES_PER_THREAD.saturating_mul(align_threads.max(1));
-    let batch_target_bases = uncapped_batch_target_bases.min(memory_cap);
-    if batch_target_bases < uncapped_batch_target_bases {
-        info!(
-            align_threads,
-            per_thread_bases = MINIMAP2_BASES_PER_THREAD,
-            uncapped_batch_target_bases,
-            batch_target_bases,
-            memory_cap,
-            absolute_cap = MINIMAP2_BATCH_BASES_MAX,
-            "Capping minimap2 outer batch to limit per-batch RAM"
-        );
-    }
-    let mut num_read = 0_u64;
-    let mut name_buf = String::new();
-
-    loop {
-        match query.next_into::<tirp::Record>() {
-            Ok(Some(record)) => {
-                let record_id = *record.get_ref::<Id>();
-                let record_r1 = *record.get_ref::<R1>();
-                let record_q1 = *record.get_ref::<Q1>();
-                let record_umi = *record.get_ref::<Umi>();
-
-                name_buf.clear();
-                write_bascet_read_name(&mut name_buf, record_id, record_umi, num_read);
-                batch.push(
-                    name_buf.as_bytes(),
-                    record_id,
-                    record_umi,
-                    record_r1,
-                    record_q1,
-                )?;
-
-                num_read += 1;
-                if num_read % 1_000_000 == 0 {
-                    info!("{}M reads aligned", num_read / 1_000_000);
-                }
-                if batch.bases >= batch_target_bases {
-                    flush_minimap2_batch(aligner, pool, header, writer_bam, &batch)?;
-                    batch.clear();
-                }
-            }
-            Ok(None) => break,
-            Err(e) => panic!("{:?}", e),
-        };
-    }
-
-    flush_minimap2_batch(aligner, pool, header, writer_bam, &batch)?;
-    Ok(())
+/// minimap2 backend. Maps R1 of each pair as a single-end read; reads within a batch are mapped
+/// in parallel on the shared Rayon pool.
+pub struct Minimap2Aligner {
+    aligner: MinimapAligner,
+    pool: Arc<rayon::ThreadPool>,
+    batch_target_bases: usize,
 }
 
-#[derive(Clone, Copy)]
-struct MinimapReadSlices {
-    name_off: u32,
-    name_len: u32,
-    cell_off: u32,
-    cell_len: u32,
-    umi_off: u32,
-    umi_len: u32,
-    seq_off: u32,
-    seq_len: u32,
-    qual_off: u32,
-    qual_len: u32,
-}
-
-struct MinimapReadView<'a> {
-    name: &'a str,
-    cell_id: &'a str,
-    umi: Option<&'a str>,
-    seq: &'a [u8],
-    qual: &'a [u8],
-}
-
-#[derive(Default)]
-struct MinimapReadBatch {
-    arena: Vec<u8>,
-    reads: Vec<MinimapReadSlices>,
-    bases: usize,
-}
-
-impl MinimapReadBatch {
-    fn push(
-        &mut self,
-        name: &[u8],
-        cell_id: &[u8],
-        umi: &[u8],
-        seq: &[u8],
-        qual: &[u8],
-    ) -> Result<()> {
-        std::str::from_utf8(name).context("minimap2 read name is not UTF-8")?;
-        std::str::from_utf8(cell_id)
-            .with_context(|| format!("cell id is not UTF-8: {cell_id:?}"))?;
-        if !umi.is_empty() {
-            std::str::from_utf8(umi).with_context(|| format!("UMI is not UTF-8: {umi:?}"))?;
+impl Minimap2Aligner {
+    pub fn new(
+        path_genome: &Path,
+        preset: &str,
+        align_threads: usize,
+        total_memory: ByteSize,
+        pool: Arc<rayon::ThreadPool>,
+    ) -> Result<Self> {
+        let genome_path = path_genome
+            .to_str()
+            .with_context(|| format!("minimap2 genome path is not UTF-8: {path_genome:?}"))?;
+        anyhow::ensure!(
+            index::io::is_idx_file(genome_path)
+                .with_context(|| format!("failed to read minimap2 index path: {path_genome:?}"))?,
+            "minimap2 aligner requires an existing .mmi index; got {path_genome:?}. Build one first, for example with `bascet exttool minimap2 -d <ref.mmi> <ref.fa>`, then pass `--genome <ref.mmi>`."
+        );
+        let index_disk_size = path_genome
+            .metadata()
+            .with_context(|| format!("failed to stat minimap2 index path: {path_genome:?}"))?
+            .len();
+        super::common::warn_if_index_disk_size_exceeds_memory(
+            "minimap2",
+            path_genome,
+            index_disk_size,
+            total_memory,
+        );
+        let mut aligner = MinimapAligner::builder()
+            .preset(preset)
+            .index(genome_path)
+            .with_cigar()
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        aligner.map_opt.flag |= MapFlags::OUT_SAM | MapFlags::CIGAR;
+        info!("minimap2 index loaded");
+
+        let memory_cap = super::stream_helpers::aligner_batch_bases_cap(
+            "minimap2",
+            total_memory,
+            MINIMAP2_RSS_BYTES_PER_INPUT_BYTE,
+            MINIMAP2_BATCH_BASES_MAX,
+        );
+        let uncapped_batch_target_bases =
+            MINIMAP2_BASES_PER_THREAD.saturating_mul(align_threads.max(1));
+        let batch_target_bases = uncapped_batch_target_bases.min(memory_cap);
+        if batch_target_bases < uncapped_batch_target_bases {
+            info!(
+                align_threads,
+                per_thread_bases = MINIMAP2_BASES_PER_THREAD,
+                uncapped_batch_target_bases,
+                batch_target_bases,
+                memory_cap,
+                absolute_cap = MINIMAP2_BATCH_BASES_MAX,
+                "Capping minimap2 outer batch to limit per-batch RAM"
+            );
         }
 
-        let (name_off, name_len) = self.push_bytes(name)?;
-        let (cell_off, cell_len) = self.push_bytes(cell_id)?;
-        let (umi_off, umi_len) = self.push_bytes(umi)?;
-        let (seq_off, seq_len) = self.push_bytes(seq)?;
-        let (qual_off, qual_len) = self.push_bytes(qual)?;
-
-        self.reads.push(MinimapReadSlices {
-            name_off,
-            name_len,
-            cell_off,
-            cell_len,
-            umi_off,
-            umi_len,
-            seq_off,
-            seq_len,
-            qual_off,
-            qual_len,
-        });
-        self.bases += seq.len();
-        Ok(())
+        Ok(Self {
+            aligner,
+            pool,
+            batch_target_bases,
+        })
     }
+}
 
-    fn push_bytes(&mut self, src: &[u8]) -> Result<(u32, u32)> {
-        let off = u32::try_from(self.arena.len())
-            .map_err(|_| anyhow::anyhow!("minimap2 read batch arena exceeded 4 GiB"))?;
-        let len = u32::try_from(src.len())
-            .map_err(|_| anyhow::anyhow!("minimap2 read batch field exceeded 4 GiB"))?;
-        self.arena.extend_from_slice(src);
-        Ok((off, len))
+impl Aligner for Minimap2Aligner {
+    fn name(&self) -> &'static str {
+        "minimap2"
     }
 
-    fn clear(&mut self) {
-        self.arena.clear();
-        self.reads.clear();
-        self.bases = 0;
+    fn sam_header(&self) -> Result<Option<String>> {
+        Ok(Some(minimap_sam::write_sam_hdr(
+            &self.aligner.idx,
+            None,
+            &[],
+        )))
     }
 
-    fn view(&self, i: usize) -> MinimapReadView<'_> {
-        let r = &self.reads[i];
-        let bytes = &self.arena;
-        // SAFETY: name/cell_id/umi were validated as UTF-8 in `push`; arena is append-only.
-        unsafe {
-            let name = std::str::from_utf8_unchecked(slice(bytes, r.name_off, r.name_len));
-            let cell_id = std::str::from_utf8_unchecked(slice(bytes, r.cell_off, r.cell_len));
-            let umi = if r.umi_len == 0 {
-                None
-            } else {
-                Some(std::str::from_utf8_unchecked(slice(
-                    bytes, r.umi_off, r.umi_len,
-                )))
-            };
-            MinimapReadView {
-                name,
-                cell_id,
-                umi,
-                seq: slice(bytes, r.seq_off, r.seq_len),
-                qual: slice(bytes, r.qual_off, r.qual_len),
-            }
+    fn batch_limits(&self) -> BatchLimits {
+        // NOTE:    the target counts R2 bases too although only R1 is mapped; for single-end
+        //          (e.g. ONT) TIRPs R2 is empty and the two agree.
+        BatchLimits {
+            target_bases: self.batch_target_bases,
+            max_pairs: usize::MAX,
         }
     }
-}
-
-#[inline]
-fn slice(bytes: &[u8], off: u32, len: u32) -> &[u8] {
-    &bytes[off as usize..(off as usize + len as usize)]
-}
-
-fn write_bascet_read_name(dst: &mut String, record_id: &[u8], record_umi: &[u8], num_read: u64) {
-    use std::fmt::Write;
-    dst.reserve(record_id.len() + record_umi.len() + 24);
-    dst.push_str(&String::from_utf8_lossy(record_id));
-    dst.push(':');
-    dst.push_str(&String::from_utf8_lossy(record_umi));
-    dst.push(':');
-    let _ = write!(dst, "{num_read}");
-}
 
-fn flush_minimap2_batch(
-    aligner: &Aligner,
-    pool: &rayon::ThreadPool,
-    header: &sam::Header,
-    writer: &mut TaggedBamWriter,
-    batch: &MinimapReadBatch,
-) -> Result<()> {
-    if batch.reads.is_empty() {
-        return Ok(());
-    }
+    fn align_batch(&mut self, batch: &ReadBatch, output: &mut TaggedBamOutput) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
 
-    let sam_lines: Vec<Result<Vec<String>>> = pool.install(|| {
-        (0..batch.reads.len())
-            .into_par_iter()
-            .map(|i| {
-                let v = batch.view(i);
-                let mut sink = VecSamSink::default();
-                emit_minimap2_sam_records(aligner, &v, &mut sink)?;
-                Ok(sink.lines)
-            })
-            .collect()
-    });
+        let aligner = &self.aligner;
+        let sam_lines: Vec<Result<Vec<String>>> = self.pool.install(|| {
+            (0..batch.len())
+                .into_par_iter()
+                .map(|i| {
+                    let mut sink = VecSamSink::default();
+                    emit_minimap2_sam_records(aligner, &batch.get(i), &mut sink)?;
+                    Ok(sink.lines)
+                })
+                .collect()
+        });
 
-    let mut sink = TaggedBamSamSink::new(writer, header, "minimap2");
-    for (i, lines) in sam_lines.into_iter().enumerate() {
-        let v = batch.view(i);
-        for line in lines? {
-            sink.record_with_cell_umi(&line, v.cell_id, v.umi)?;
+        for (read, lines) in batch.iter().zip(sam_lines) {
+            for line in lines? {
+                output.record_with_cell_umi(&line, read.cell_id, read.umi)?;
+            }
         }
+        Ok(())
     }
-    Ok(())
 }
 
 #[derive(Default)]
@@ -393,19 +181,19 @@ impl SamRecordSink for VecSamSink {
 }
 
 fn emit_minimap2_sam_records(
-    aligner: &Aligner,
-    read: &MinimapReadView<'_>,
+    aligner: &MinimapAligner,
+    read: &ReadPair<'_>,
     sink: &mut impl SamRecordSink,
 ) -> Result<()> {
-    let result = map::map_query(&aligner.idx, &aligner.map_opt, read.name, read.seq);
+    let result = map::map_query(&aligner.idx, &aligner.map_opt, read.name, read.r1);
 
     if result.regs.is_empty() {
         if !aligner.map_opt.flag.contains(MapFlags::SAM_HIT_ONLY) {
             let line = minimap_sam::write_sam_record(
                 &aligner.idx,
                 read.name,
-                read.seq,
-                read.qual,
+                read.r1,
+                read.q1,
                 None,
                 0,
                 &[],
@@ -421,8 +209,8 @@ fn emit_minimap2_sam_records(
             let line = minimap_sam::write_sam_record(
                 &aligner.idx,
                 read.name,
-                read.seq,
-                read.qual,
+                read.r1,
+                read.q1,
                 Some(region),
                 result.regs.len(),
                 &result.regs,
@@ -2,17 +2,22 @@
 //! budget + thread allocation and dispatches to the per-aligner implementations here.
 //!
 //! Layout:
-//! - `bwa` (+ `bwa_stock_driver`): pipelined BWAMEM2 stock-driver port (TIRP → BAM)
-//! - `minimap2`, `star`: their respective aligner integrations
-//! - `output`: shared BAM writer wrapper + cell-tag injection (used by minimap2/star)
-//! - `stream_helpers`: memory-budget helpers shared by Stream-based aligners (minimap2/star)
+//! - `backend`: the `Aligner` trait (batch in, SAM out, header) and the shared driver around
+//!   it — TIRP batching, read naming, cell/UMI-tagged BAM output and sort + index
+//! - `bwa` (+ `bwa_stock_driver`), `minimap2`, `star`: in-process `Aligner` backends. STAR also
+//!   keeps its own pipelined driver (streamed sort), which `align` uses for STARsolo
+//! - `subprocess`: `Aligner` backend speaking FASTQ/SAM over pipes to an external command
+//! - `star_solo`: STARsolo-like gene/velocity quantification on STAR's output records
+//! - `output`: shared BAM writer wrapper + cell-tag injection
+//! - `stream_helpers`: memory-budget helpers for the stream-based drivers
 //! - `common`: helpers used by the CLI dispatch (`warn_if_index_disk_size_exceeds_memory`)
 //!   and the `tofq` subcommand (`write_tirp_to_2fq`)
 
+pub mod backend;
 pub mod common;
 pub mod output;
-#[cfg(any(feature = "star-rs-align", feature = "minimap2-rs-align"))]
 pub mod stream_helpers;
+pub mod subprocess;
 
 #[cfg(feature = "bwa-mem2-rs-align")]
 pub mod bwa;
@@ -22,3 +27,5 @@ pub mod bwa_stock_driver;
 pub mod minimap2;
 #[cfg(feature = "star-rs-align")]
 pub mod star;
+#[cfg(feature = "star-rs-align")]
+pub mod star_solo;
@@ -1,4 +1,10 @@
-use std::{borrow::Cow, fs::File, io::Cursor, num::NonZeroUsize, path::Path};
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::Cursor,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{Context, Result};
 use noodles::{
@@ -12,14 +18,27 @@ pub type TaggedBamWriter = bam::io::Writer<bgzf::io::MultithreadedWriter<File>>;
 
 pub fn make_bascet_read_name(record_id: &[u8], record_umi: &[u8], num_read: u64) -> String {
     let mut read_name = String::with_capacity(record_id.len() + record_umi.len() + 32);
-    read_name.push_str(&String::from_utf8_lossy(record_id));
-    read_name.push(':');
-    read_name.push_str(&String::from_utf8_lossy(record_umi));
-    read_name.push(':');
-    read_name.push_str(&num_read.to_string());
+    write_bascet_read_name(&mut read_name, record_id, record_umi, num_read);
     read_name
 }
 
+/// Append a bascet-style read name, `{cell_id}:{umi}:{num_read}`, to `dst`. Aligners carry it
+/// through as QNAME so the cell and UMI can be recovered from their SAM output.
+pub fn write_bascet_read_name(
+    dst: &mut String,
+    record_id: &[u8],
+    record_umi: &[u8],
+    num_read: u64,
+) {
+    use std::fmt::Write;
+    dst.reserve(record_id.len() + record_umi.len() + 24);
+    dst.push_str(&String::from_utf8_lossy(record_id));
+    dst.push(':');
+    dst.push_str(&String::from_utf8_lossy(record_umi));
+    dst.push(':');
+    let _ = write!(dst, "{num_read}");
+}
+
 pub trait SamRecordSource {
     fn write_tagged_records(
         self,
@@ -169,6 +188,132 @@ impl TaggedBamSamSink<'_> {
     }
 }
 
+/// Owned tagged BAM output of one alignment run. The writer is opened lazily: either with a
+/// header given up front (`with_header`), or with the `@` lines an aligner emits ahead of its
+/// first record (`new`), so SAM streamed from an external process can be fed in line by line.
+pub struct TaggedBamOutput {
+    path: PathBuf,
+    num_threads: usize,
+    source_name: String,
+    header_text: String,
+    open: Option<(TaggedBamWriter, sam::Header)>,
+    record: RecordBuf,
+    n_records: u64,
+}
+
+impl TaggedBamOutput {
+    pub fn new(path: &Path, num_threads: usize, source_name: &str) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            num_threads,
+            source_name: source_name.to_owned(),
+            header_text: String::new(),
+            open: None,
+            record: RecordBuf::default(),
+            n_records: 0,
+        }
+    }
+
+    pub fn with_header(
+        path: &Path,
+        header_text: &str,
+        num_threads: usize,
+        source_name: &str,
+    ) -> Result<Self> {
+        let mut output = Self::new(path, num_threads, source_name);
+        output.header_text.push_str(header_text);
+        output.open = Some(output.open_writer()?);
+        Ok(output)
+    }
+
+    /// The parsed BAM header, once the writer is open.
+    pub fn header(&self) -> Option<&sam::Header> {
+        self.open.as_ref().map(|(_, header)| header)
+    }
+
+    pub fn n_records(&self) -> u64 {
+        self.n_records
+    }
+
+    fn open_writer(&self) -> Result<(TaggedBamWriter, sam::Header)> {
+        let header = self
+            .header_text
+            .parse::<sam::Header>()
+            .with_context(|| format!("failed to parse {} SAM header", self.source_name))?;
+        let writer = create_tagged_bam_writer(&self.path, &header, self.num_threads)?;
+        Ok((writer, header))
+    }
+
+    fn header_line(&mut self, line: &str) -> Result<()> {
+        if self.open.is_some() {
+            anyhow::bail!(
+                "{} emitted a SAM header line after its first record: {line}",
+                self.source_name
+            );
+        }
+        self.header_text.push_str(line);
+        self.header_text.push('\n');
+        Ok(())
+    }
+
+    pub fn record_with_cell_umi(
+        &mut self,
+        line: &str,
+        cell_id: &str,
+        umi: Option<&str>,
+    ) -> Result<()> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.is_empty() {
+            return Ok(());
+        }
+        if line.starts_with('@') {
+            return self.header_line(line);
+        }
+
+        let open = match self.open.take() {
+            Some(open) => open,
+            None => self.open_writer()?,
+        };
+        let (writer, header) = self.open.insert(open);
+        parse_sam_line_with_cell_umi(
+            line,
+            header,
+            &mut self.record,
+            &self.source_name,
+            cell_id,
+            umi,
+        )?;
+        writer.write_alignment_record(header, &self.record)?;
+        self.n_records += 1;
+        Ok(())
+    }
+
+    /// Flush and close the BAM. A run that produced no records still gets a valid (empty) BAM
+    /// with whatever header was seen.
+    pub fn finish(mut self) -> Result<u64> {
+        let (writer, _) = match self.open.take() {
+            Some(open) => open,
+            None => self.open_writer()?,
+        };
+        finish_tagged_bam_writer(writer)?;
+        Ok(self.n_records)
+    }
+}
+
+impl SamRecordSink for TaggedBamOutput {
+    fn record(&mut self, line: &str) -> Result<()> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        if line.is_empty() {
+            return Ok(());
+        }
+        if line.starts_with('@') {
+            return self.header_line(line);
+        }
+        let (cell_id, umi) = cell_umi_from_sam_qname(line, &self.source_name)?;
+        self.record_with_cell_umi(line, &cell_id, umi.as_deref())
+    }
+}
+
 fn write_tagged_bam_alignment(
     writer: &mut TaggedBamWriter,
     header: &sam::Header,
@@ -309,7 +454,7 @@ fn cell_umi_from_sam_qname(line: &str, source_name: &str) -> Result<(String, Opt
         .next()
         .filter(|field| !field.is_empty())
         .with_context(|| format!("{source_name} SAM record is missing QNAME"))?;
-    let (cell_id, umi) = crate::fileformat::bam::readname_to_cell_umi(read_name.as_bytes());
+    let (cell_id, umi) = crate::fileformat::bam::readname_to_cell_umi(read_name.as_bytes())?;
     let cell_id = std::str::from_utf8(cell_id)
         .with_context(|| format!("cell id in read name is not UTF-8: {read_name:?}"))?
         .to_string();
@@ -357,11 +502,14 @@ fn add_cell_umi_tags_to_sam_line_with_values(
 
 #[cfg(test)]
 mod tests {
-    use noodles::sam;
+    use noodles::{
+        bam, sam,
+        sam::alignment::{record::data::field::Tag, record_buf::data::field::Value},
+    };
 
     use super::{
-        add_cell_umi_tags_to_sam_line_with_values, cell_umi_from_sam_qname,
-        parse_tagged_record_with_cell_umi,
+        SamRecordSink, TaggedBamOutput, add_cell_umi_tags_to_sam_line_with_values,
+        cell_umi_from_sam_qname, parse_tagged_record_with_cell_umi,
     };
 
     #[test]
@@ -417,4 +565,33 @@ mod tests {
         assert!(record.quality_scores().is_empty());
         assert_eq!(record.data().len(), 4);
     }
+
+    #[test]
+    fn tagged_output_takes_header_from_leading_sam_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.bam");
+        let mut output = TaggedBamOutput::new(&path, 1, "test");
+        output.record("@HD\tVN:1.6\tSO:unsorted\n").unwrap();
+        output.record("@SQ\tSN:chr1\tLN:100\n").unwrap();
+        output
+            .record("CELL:UMI:0\t4\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF\n")
+            .unwrap();
+        assert!(output.record("@CO\ttoo late").is_err());
+        assert_eq!(output.finish().unwrap(), 1);
+
+        let mut reader = bam::io::reader::Builder::default()
+            .build_from_path(&path)
+            .unwrap();
+        let header = reader.read_header().unwrap();
+        assert_eq!(header.reference_sequences().len(), 1);
+        let records = reader
+            .record_bufs(&header)
+            .collect::<std::io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(matches!(
+            records[0].data().get(&Tag::CELL_BARCODE_ID),
+            Some(Value::String(cell)) if cell == "CELL"
+        ));
+    }
 }
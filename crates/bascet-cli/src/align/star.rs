@@ -21,16 +21,22 @@ use bytesize::ByteSize;
 use noodles::{bam, sam, sam::alignment::io::Write as _};
 use tracing::{debug, info};
 
+use super::backend::{AlignJob, Aligner, BatchLimits, ReadBatch, ReadPair, run_aligner};
 use super::output::{
-    create_tagged_bam_writer, finish_tagged_bam_writer, make_bascet_read_name, parse_tagged_record,
+    SamRecordSink, TaggedBamOutput, create_tagged_bam_writer, finish_tagged_bam_writer,
+    make_bascet_read_name, parse_tagged_record,
 };
+use super::star_solo::{SoloAnnotation, SoloCounter};
 use crate::command::{
-    bamsort::sort_and_index_encoded_bam_chunk_receiver,
+    align::SoloStrand,
+    bamsort::{BamIndexArgs, sort_and_index_encoded_bam_chunk_receiver},
     samtools_rs::sort::{EncodedBamChunk, ReferenceOrder},
 };
+use crate::fileformat::sample_sheet::SampleAnnotations;
 use crate::utils::{
     atomic_temp_path, atomic_temp_path_in_dir, current_rss_display, max_rss_display,
-    process_cpu_seconds, publish_atomic_output, thread_cpu_seconds,
+    process_cpu_seconds, publish_atomic_output, rename_or_copy_across_filesystems,
+    thread_cpu_seconds,
 };
 use star_rs::{
     ReadAlignChunkMapChunkResult, ReadAlignChunkProcessChunksResult, Stats,
@@ -49,21 +55,56 @@ const STAR_OUTPUT_PAYLOAD_SAFETY_DENOMINATOR: u64 = 4;
 const STAR_WRITER_QUEUE_CHUNKS: usize = 8;
 const STAR_WRITER_CONVERTER_THREADS: usize = 8;
 
+/// Align with STAR through the shared batch driver, as [`StarAligner`]. Runs that count genes
+/// use [`try_execute_star_rs`] instead
+pub fn try_execute_star(
+    job: &AlignJob<'_>,
+    path_genome: &Path,
+    align_threads: usize,
+    rayon_pool: Arc<rayon::ThreadPool>,
+) -> Result<()> {
+    info!(
+        total_threads = job.total_threads,
+        align_threads,
+        read_threads = job.read_threads.get(),
+        write_bam_threads = job.write_bam_threads,
+        "Using direct star-rs aligner"
+    );
+
+    let aligner = StarAligner::new(
+        path_genome,
+        job.path_temp,
+        align_threads,
+        job.total_memory,
+        rayon_pool,
+    )?;
+    run_aligner(aligner, job)
+}
+
+/// Align with STAR through its own pipelined scheduler rather than the shared batch driver:
+/// mapping, BAM conversion and sorting overlap, and genes are counted on the way (STARsolo).
+/// Without `solo`, [`try_execute_star`] is the `Aligner` path.
 pub fn try_execute_star_rs(
-    path_in: &Path,
+    job: &AlignJob<'_>,
     path_genome: &Path,
-    path_out_unsorted: &PathBuf,
-    path_out_sorted: &PathBuf,
-    path_temp: &PathBuf,
     numof_threads_writebam: usize,
     align_threads: usize,
-    sizeof_stream_arena: ByteSize,
-    sizeof_stream_buffer: ByteSize,
-    total_memory: ByteSize,
-    total_threads: u64,
     rayon_pool: Arc<rayon::ThreadPool>,
-    max_read_pairs: Option<u64>,
+    solo: Option<(&Path, SoloStrand)>,
 ) -> Result<()> {
+    let AlignJob {
+        path_in,
+        path_out_unsorted,
+        path_out_sorted,
+        path_temp,
+        report_outputs,
+        total_memory,
+        total_threads,
+        sizeof_stream_arena,
+        sizeof_stream_buffer,
+        max_read_pairs,
+        ..
+    } = *job;
     info!("Using direct star-rs aligner");
     let index_disk_size = validate_star_index_dir(path_genome)?;
     super::common::warn_if_index_disk_size_exceeds_memory(
@@ -102,6 +143,7 @@ pub fn try_execute_star_rs(
         total_threads,
         rayon_pool,
         max_read_pairs,
+        solo.map(|(_, strand)| strand),
     ) {
         Ok(result) => result,
         Err(err) => {
@@ -110,10 +152,28 @@ pub fn try_execute_star_rs(
             return Err(err);
         }
     };
-    publish_atomic_output(&path_out_unsorted_tmp, path_out_unsorted)?;
+    if report_outputs {
+        publish_atomic_output(&path_out_unsorted_tmp, path_out_unsorted)?;
+    } else {
+        rename_or_copy_across_filesystems(&path_out_unsorted_tmp, path_out_unsorted)?;
+    }
 
     cleanup_star_temp(&path_star_tmp);
 
+    if let (Some((path_out_solo, _)), Some((annotation, solo_counts))) =
+        (solo, star_run.solo_counts)
+    {
+        info!("Saving STAR gene counts");
+        let path_out_solo = path_out_solo.to_path_buf();
+        let path_solo_tmp = atomic_temp_path(&path_out_solo);
+        solo_counts.save_to_anndata(
+            &annotation,
+            SampleAnnotations::from_paths([path_in]),
+            &path_solo_tmp,
+        )?;
+        publish_atomic_output(&path_solo_tmp, &path_out_solo)?;
+    }
+
     let star_output_budget =
         estimate_star_output_budget(star_run.output_records, star_run.read_sample);
     info!(
@@ -165,17 +225,20 @@ fn collect_star_map_chunk_records(
     map_chunk: &ReadAlignChunkMapChunkResult,
     header: &sam::Header,
     writer: &mut bam::io::Writer<Vec<u8>>,
+    solo: &mut Option<(&SoloAnnotation, SoloCounter)>,
 ) -> Result<u64> {
     let mut records_collected = 0_u64;
     records_collected = records_collected.saturating_add(collect_star_sam_bytes(
         &map_chunk.direct_sam_output,
         header,
         writer,
+        solo,
     )?);
     records_collected = records_collected.saturating_add(collect_star_sam_bytes(
         &map_chunk.paired_keep_input_order_tmp,
         header,
         writer,
+        solo,
     )?);
     Ok(records_collected)
 }
@@ -184,6 +247,7 @@ fn collect_star_sam_bytes(
     bytes: &[u8],
     header: &sam::Header,
     writer: &mut bam::io::Writer<Vec<u8>>,
+    solo: &mut Option<(&SoloAnnotation, SoloCounter)>,
 ) -> Result<u64> {
     let mut records_collected = 0_u64;
     for line in bytes.split(|byte| *byte == b'\n') {
@@ -192,6 +256,9 @@ fn collect_star_sam_bytes(
         }
         let line = std::str::from_utf8(line).context("STAR SAM output is not UTF-8")?;
         let record = parse_tagged_record(line, header, "STAR")?;
+        if let Some((annotation, counter)) = solo {
+            counter.record(annotation, &record);
+        }
         writer.write_alignment_record(header, &record)?;
         records_collected += 1;
     }
@@ -256,20 +323,25 @@ fn spawn_star_chunk_collector(
 
 fn spawn_star_converter_workers(
     header: sam::Header,
+    solo_annotation: Option<Arc<SoloAnnotation>>,
     converter_rx: crossbeam_channel::Receiver<StarWriterChunk>,
     writer_tx: crossbeam_channel::Sender<StarConvertedChunk>,
     worker_count: usize,
     metrics: Arc<StarWriterPipelineMetrics>,
-) -> Vec<JoinHandle<std::result::Result<(), String>>> {
+) -> Vec<JoinHandle<std::result::Result<Option<SoloCounter>, String>>> {
     let mut handles = Vec::with_capacity(worker_count);
     for worker_id in 0..worker_count {
         let header = header.clone();
+        let solo_annotation = solo_annotation.clone();
         let converter_rx = converter_rx.clone();
         let writer_tx = writer_tx.clone();
         let metrics = Arc::clone(&metrics);
         let handle = std::thread::Builder::new()
             .name(format!("STARSamConvert@{worker_id}"))
             .spawn(move || {
+                let mut solo = solo_annotation
+                    .as_deref()
+                    .map(|annotation| (annotation, SoloCounter::default()));
                 while let Ok(chunk) = converter_rx.recv() {
                     let mut bam_record_writer = bam::io::Writer::from(Vec::new());
                     let convert_start = Instant::now();
@@ -277,6 +349,7 @@ fn spawn_star_converter_workers(
                         &chunk.map_result,
                         &header,
                         &mut bam_record_writer,
+                        &mut solo,
                     )
                     .map_err(|err| format!("failed to convert STAR SAM chunk to BAM: {err:?}"))?;
                     let bam_records = bam_record_writer.into_inner();
@@ -302,7 +375,7 @@ fn spawn_star_converter_workers(
                         .fetch_add(records_written, Ordering::Relaxed);
                     metrics.update_max_writer_queue_len(writer_tx.len());
                 }
-                Ok(())
+                Ok(solo.map(|(_, counter)| counter))
             })
             .expect("failed to spawn STAR SAM converter thread");
         handles.push(handle);
@@ -319,14 +392,21 @@ fn join_star_chunk_collector(
 }
 
 fn join_star_converter_workers(
-    converter_handles: Vec<JoinHandle<std::result::Result<(), String>>>,
-) -> std::result::Result<(), String> {
+    converter_handles: Vec<JoinHandle<std::result::Result<Option<SoloCounter>, String>>>,
+) -> std::result::Result<Option<SoloCounter>, String> {
+    let mut solo_counts: Option<SoloCounter> = None;
     for handle in converter_handles {
-        handle
+        let worker_counts = handle
             .join()
             .map_err(|_| "STAR SAM converter thread panicked".to_string())??;
+        if let Some(worker_counts) = worker_counts {
+            match solo_counts.as_mut() {
+                Some(solo_counts) => solo_counts.merge(worker_counts),
+                None => solo_counts = Some(worker_counts),
+            }
+        }
     }
-    Ok(())
+    Ok(solo_counts)
 }
 
 struct StarWriterChunk {
@@ -434,24 +514,8 @@ fn streaming_sort_memory_budget(total_memory: ByteSize) -> ByteSize {
     ByteSize(budget.clamp(ByteSize::gib(1).as_u64(), ByteSize::gib(4).as_u64()))
 }
 
-fn run_star_rs(
-    path_genome: &Path,
-    path_in: &Path,
-    path_star_tmp: &Path,
-    path_out_unsorted_tmp: &Path,
-    path_out_sorted: &Path,
-    path_temp: &Path,
-    numof_threads_writebam: usize,
-    align_threads: usize,
-    sizeof_stream_arena: ByteSize,
-    sizeof_stream_buffer: ByteSize,
-    total_memory: ByteSize,
-    total_threads: u64,
-    rayon_pool: Arc<rayon::ThreadPool>,
-    max_read_pairs: Option<u64>,
-) -> Result<StarRunResult> {
-    info!("Starting star-rs alignment");
-    let args = vec![
+fn star_args(path_genome: &Path, path_star_tmp: &Path, align_threads: usize) -> Vec<String> {
+    vec![
         "STAR".to_string(),
         "--genomeDir".to_string(),
         path_genome.display().to_string(),
@@ -472,11 +536,33 @@ fn run_star_rs(
         path_star_tmp.display().to_string(),
         "--outFileNamePrefix".to_string(),
         "./".to_string(),
-    ];
+    ]
+}
+
+fn run_star_rs(
+    path_genome: &Path,
+    path_in: &Path,
+    path_star_tmp: &Path,
+    path_out_unsorted_tmp: &Path,
+    path_out_sorted: Option<&Path>,
+    path_temp: &Path,
+    numof_threads_writebam: usize,
+    align_threads: usize,
+    sizeof_stream_arena: ByteSize,
+    sizeof_stream_buffer: ByteSize,
+    total_memory: ByteSize,
+    total_threads: u64,
+    rayon_pool: Arc<rayon::ThreadPool>,
+    max_read_pairs: Option<u64>,
+    solo_strand: Option<SoloStrand>,
+) -> Result<StarRunResult> {
+    info!("Starting star-rs alignment");
+    let args = star_args(path_genome, path_star_tmp, align_threads);
 
     debug!(?args, "Running star-rs");
     let star_run = run_star_rs_with_tirp(
         &args,
+        path_genome,
         path_in,
         path_out_unsorted_tmp,
         path_out_sorted,
@@ -488,6 +574,7 @@ fn run_star_rs(
         total_threads,
         rayon_pool,
         max_read_pairs,
+        solo_strand,
     )
     .map_err(anyhow::Error::msg)?;
     if star_run.exit_code != 0 {
@@ -506,9 +593,10 @@ fn run_star_rs(
 
 fn run_star_rs_with_tirp(
     args: &[String],
+    path_genome: &Path,
     path_in: &Path,
     path_out_unsorted_tmp: &Path,
-    path_out_sorted: &Path,
+    path_out_sorted: Option<&Path>,
     path_temp: &Path,
     numof_threads_writebam: usize,
     sizeof_stream_arena: ByteSize,
@@ -517,6 +605,7 @@ fn run_star_rs_with_tirp(
     total_threads: u64,
     rayon_pool: Arc<rayon::ThreadPool>,
     max_read_pairs: Option<u64>,
+    solo_strand: Option<SoloStrand>,
 ) -> std::result::Result<StarRunResult, String> {
     let index_cpu_start = CpuSnapshot::now();
     let context = Arc::new(DirectStarContext::new(args)?);
@@ -541,15 +630,28 @@ fn run_star_rs_with_tirp(
         .write_header(&header)
         .map_err(|err| format!("failed to encode STAR BAM header: {err:?}"))?;
     let header_bytes = header_writer.into_inner();
+    let solo_annotation = match solo_strand {
+        Some(strand) => Some(Arc::new(
+            SoloAnnotation::from_star_genome_dir(path_genome, &header, strand)
+                .map_err(|err| format!("{err:?}"))?,
+        )),
+        None => None,
+    };
     let (sort_tx, sort_rx) =
         crossbeam_channel::bounded::<EncodedBamChunk>(STAR_WRITER_QUEUE_CHUNKS);
     let sort_memory = streaming_sort_memory_budget(total_memory);
     let sort_threads = (total_threads as usize).max(1);
-    let path_out_sorted = path_out_sorted.to_path_buf();
+    let path_out_sorted = path_out_sorted.map(Path::to_path_buf);
     let path_temp = path_temp.to_path_buf();
     let sort_handle = std::thread::Builder::new()
         .name("STARStreamedBamSort".to_string())
         .spawn(move || {
+            // NOTE:    without a sorted output the chunks are drained so the collector never
+            //          blocks on a full queue
+            let Some(path_out_sorted) = path_out_sorted else {
+                sort_rx.iter().for_each(drop);
+                return Ok(());
+            };
             sort_and_index_encoded_bam_chunk_receiver(
                 header_bytes,
                 sort_rx,
@@ -558,6 +660,7 @@ fn run_star_rs_with_tirp(
                 sort_memory,
                 sort_threads,
                 ReferenceOrder::Lexicographic,
+                &BamIndexArgs::default(),
             )
             .map_err(|err| format!("{err:?}"))
         })
@@ -582,6 +685,7 @@ fn run_star_rs_with_tirp(
     drop(sort_tx);
     let converter_handles = spawn_star_converter_workers(
         header,
+        solo_annotation.clone(),
         converter_rx,
         writer_tx.clone(),
         converter_count,
@@ -603,7 +707,8 @@ fn run_star_rs_with_tirp(
         .with_path(path_in)
         .with_opt_rayon_pool(Arc::clone(&rayon_pool))
         .with_opt_rayon_pool_max_inflight(BoundedU64::new_saturating(decode_inflight_cap as u64))
-        .build();
+        .build()
+        .map_err(|err| format!("{err:#}"))?;
     let parser = parse::Tirp::builder().build();
 
     let mut stream = Stream::builder()
@@ -830,7 +935,7 @@ fn run_star_rs_with_tirp(
     }
 
     drop(converter_tx);
-    join_star_converter_workers(converter_handles)?;
+    let solo_counts = join_star_converter_workers(converter_handles)?;
     drop(writer_tx_metrics);
     let output_records = join_star_chunk_collector(writer_handle)?;
     sort_handle
@@ -843,6 +948,7 @@ fn run_star_rs_with_tirp(
         log_final_out: String::new(),
         read_sample,
         output_records,
+        solo_counts: solo_annotation.zip(solo_counts),
     })
 }
 
@@ -853,6 +959,7 @@ struct StarRunResult {
     log_final_out: String,
     read_sample: StarReadLengthSample,
     output_records: u64,
+    solo_counts: Option<(Arc<SoloAnnotation>, SoloCounter)>,
 }
 
 type MappedStarChunk = DirectStarMappedChunk<BascetStarReadChunk>;
@@ -1218,6 +1325,27 @@ fn estimated_star_read_pair_packed_bytes(
         .saturating_add(record.get_ref::<Umi>().len())
         .saturating_add(1)
         .saturating_add(decimal_len(zero_based_read_index));
+    estimated_star_packed_bytes(
+        read_name_len,
+        zero_based_read_index,
+        [
+            record
+                .get_ref::<R1>()
+                .len()
+                .saturating_add(record.get_ref::<Q1>().len()),
+            record
+                .get_ref::<R2>()
+                .len()
+                .saturating_add(record.get_ref::<Q2>().len()),
+        ],
+    )
+}
+
+fn estimated_star_packed_bytes(
+    read_name_len: usize,
+    zero_based_read_index: u64,
+    mate_payload_bytes: [usize; 2],
+) -> StarReadPairPackedBytes {
     let read_number_len = decimal_len(zero_based_read_index.saturating_add(1));
     // Matches STAR's FASTQ-shaped direct chunk record:
     // "@{name} {read_number} N {read_files_index}\n{seq}\n+\n{qual}\n".
@@ -1233,12 +1361,8 @@ fn estimated_star_read_pair_packed_bytes(
     let mate_overhead = header_len.saturating_add(5);
 
     StarReadPairPackedBytes {
-        mate1: mate_overhead
-            .saturating_add(record.get_ref::<R1>().len())
-            .saturating_add(record.get_ref::<Q1>().len()),
-        mate2: mate_overhead
-            .saturating_add(record.get_ref::<R2>().len())
-            .saturating_add(record.get_ref::<Q2>().len()),
+        mate1: mate_overhead.saturating_add(mate_payload_bytes[0]),
+        mate2: mate_overhead.saturating_add(mate_payload_bytes[1]),
     }
 }
 
@@ -1250,3 +1374,273 @@ fn decimal_len(mut value: u64) -> usize {
     }
     len
 }
+
+/// STAR backend for the shared batch driver (`super::backend`). Each batch is split into
+/// STAR-sized chunks that are mapped on all workers at once and written back in chunk order.
+/// Unlike `try_execute_star_rs`, mapping and BAM conversion do not overlap and genes are not
+/// counted.
+pub struct StarAligner {
+    context: Arc<DirectStarContext>,
+    idle_workers: Vec<DirectStarWorker>,
+    pool: Arc<rayon::ThreadPool>,
+    path_star_tmp: PathBuf,
+    next_chunk_index: u32,
+    next_read_index: u64,
+}
+
+impl StarAligner {
+    pub fn new(
+        path_genome: &Path,
+        path_temp: &Path,
+        align_threads: usize,
+        total_memory: ByteSize,
+        pool: Arc<rayon::ThreadPool>,
+    ) -> Result<Self> {
+        let index_disk_size = validate_star_index_dir(path_genome)?;
+        super::common::warn_if_index_disk_size_exceeds_memory(
+            "STAR",
+            path_genome,
+            index_disk_size,
+            total_memory,
+        );
+        let path_star_tmp = atomic_temp_path(path_temp.join("star-rs-tmp"));
+        fs::create_dir_all(&path_star_tmp)
+            .with_context(|| format!("failed to create STAR work directory {:?}", path_star_tmp))?;
+
+        let args = star_args(path_genome, &path_star_tmp, align_threads);
+        debug!(?args, "Loading star-rs");
+        let loaded = DirectStarContext::new(&args).and_then(|context| {
+            let workers = (0..context.run_thread_n().max(1))
+                .map(|worker_id| context.make_worker(worker_id as i32))
+                .collect::<std::result::Result<Vec<_>, _>>()?;
+            Ok((context, workers))
+        });
+        let (context, idle_workers) = match loaded {
+            Ok(loaded) => loaded,
+            Err(err) => {
+                cleanup_star_temp(&path_star_tmp);
+                return Err(anyhow::Error::msg(err));
+            }
+        };
+        info!(workers = idle_workers.len(), "STAR index loaded");
+
+        Ok(Self {
+            context: Arc::new(context),
+            idle_workers,
+            pool,
+            path_star_tmp,
+            next_chunk_index: 0,
+            next_read_index: 0,
+        })
+    }
+
+    fn split_batch(&mut self, batch: &ReadBatch) -> Result<Vec<StarBatchChunk>> {
+        let chunk_input_limit = self.context.chunk_input_limit_bytes().max(1) as usize;
+        let mut chunks = Vec::new();
+        let mut chunk = StarBatchChunk::new(self.next_chunk_index, self.next_read_index + 1);
+        for pair in batch.iter() {
+            let estimated_bytes = estimated_star_packed_bytes(
+                pair.name.len(),
+                self.next_read_index,
+                [pair.r1.len() + pair.q1.len(), pair.r2.len() + pair.q2.len()],
+            );
+            if chunk.would_exceed(estimated_bytes, chunk_input_limit) {
+                self.next_chunk_index += 1;
+                let next = StarBatchChunk::new(self.next_chunk_index, self.next_read_index + 1);
+                chunks.push(std::mem::replace(&mut chunk, next));
+            }
+            chunk.push(&pair, estimated_bytes)?;
+            self.next_read_index += 1;
+        }
+        if !chunk.reads.is_empty() {
+            self.next_chunk_index += 1;
+            chunks.push(chunk);
+        }
+        Ok(chunks)
+    }
+}
+
+impl Aligner for StarAligner {
+    fn name(&self) -> &'static str {
+        "STAR"
+    }
+
+    fn sam_header(&self) -> Result<Option<String>> {
+        let header = self.context.sam_header();
+        if header.is_empty() {
+            anyhow::bail!("star-rs did not return a SAM header");
+        }
+        Ok(Some(header.to_string()))
+    }
+
+    fn batch_limits(&self) -> BatchLimits {
+        // One full chunk per worker keeps every worker busy for each batch.
+        let workers = self.idle_workers.len().max(1);
+        BatchLimits {
+            target_bases: (self.context.chunk_input_limit_bytes().max(1) as usize)
+                .saturating_mul(workers),
+            max_pairs: STAR_READ_PAIRS_PER_CHUNK.saturating_mul(workers),
+        }
+    }
+
+    fn align_batch(&mut self, batch: &ReadBatch, output: &mut TaggedBamOutput) -> Result<()> {
+        let mut chunks = self.split_batch(batch)?.into_iter();
+        let Some(first_chunk_index) = chunks.as_slice().first().map(|chunk| chunk.chunk_index)
+        else {
+            return Ok(());
+        };
+
+        let (mapped_tx, mapped_rx) = crossbeam::channel::unbounded();
+        let mut completed = BTreeMap::<u32, ReadAlignChunkMapChunkResult>::new();
+        let mut next_emit_chunk = first_chunk_index;
+        let mut in_flight = 0_usize;
+        loop {
+            while let Some(worker) = self.idle_workers.pop() {
+                let Some(chunk) = chunks.next() else {
+                    self.idle_workers.push(worker);
+                    break;
+                };
+                let context = Arc::clone(&self.context);
+                let tx = mapped_tx.clone();
+                in_flight += 1;
+                self.pool.spawn(move || {
+                    let _ = tx.send(context.map_read_chunk(worker, chunk));
+                });
+            }
+            if in_flight == 0 {
+                break;
+            }
+
+            let mapped = mapped_rx
+                .recv()
+                .context("STAR worker channel closed unexpectedly")?
+                .map_err(anyhow::Error::msg)?;
+            in_flight -= 1;
+            let DirectStarMappedChunk {
+                chunk_index,
+                worker,
+                map_result,
+                ..
+            } = mapped;
+            self.idle_workers.push(worker);
+            completed.insert(chunk_index, map_result);
+            while let Some(map_result) = completed.remove(&next_emit_chunk) {
+                for bytes in [
+                    &map_result.direct_sam_output,
+                    &map_result.paired_keep_input_order_tmp,
+                ] {
+                    write_star_sam_bytes(bytes, output)?;
+                }
+                next_emit_chunk += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StarAligner {
+    fn drop(&mut self) {
+        cleanup_star_temp(&self.path_star_tmp);
+    }
+}
+
+fn write_star_sam_bytes(bytes: &[u8], output: &mut TaggedBamOutput) -> Result<()> {
+    for line in bytes.split(|byte| *byte == b'\n') {
+        if line.is_empty() || line.starts_with(b"@") {
+            continue;
+        }
+        output.record(std::str::from_utf8(line).context("STAR SAM output is not UTF-8")?)?;
+    }
+    Ok(())
+}
+
+/// Owned slice of a driver batch handed to one STAR worker.
+struct StarBatchChunk {
+    chunk_index: u32,
+    first_read_number: u64,
+    reads: ReadBatch,
+    estimated_mate_input_bytes: [usize; 2],
+}
+
+impl StarBatchChunk {
+    fn new(chunk_index: u32, first_read_number: u64) -> Self {
+        Self {
+            chunk_index,
+            first_read_number,
+            reads: ReadBatch::default(),
+            estimated_mate_input_bytes: [0; 2],
+        }
+    }
+
+    fn would_exceed(&self, estimated_bytes: StarReadPairPackedBytes, limit: usize) -> bool {
+        !self.reads.is_empty()
+            && (self.estimated_mate_input_bytes[0].saturating_add(estimated_bytes.mate1) > limit
+                || self.estimated_mate_input_bytes[1].saturating_add(estimated_bytes.mate2) > limit
+                || self.reads.len() >= STAR_READ_PAIRS_PER_CHUNK)
+    }
+
+    fn push(
+        &mut self,
+        pair: &ReadPair<'_>,
+        estimated_bytes: StarReadPairPackedBytes,
+    ) -> Result<()> {
+        self.reads.push(pair)?;
+        self.estimated_mate_input_bytes[0] =
+            self.estimated_mate_input_bytes[0].saturating_add(estimated_bytes.mate1);
+        self.estimated_mate_input_bytes[1] =
+            self.estimated_mate_input_bytes[1].saturating_add(estimated_bytes.mate2);
+        Ok(())
+    }
+}
+
+struct StarBatchChunkIter<'a> {
+    chunk: &'a StarBatchChunk,
+    index: usize,
+}
+
+impl<'a> Iterator for StarBatchChunkIter<'a> {
+    type Item = StarReadPair<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.chunk.reads.len() {
+            return None;
+        }
+        let pair = self.chunk.reads.get(self.index);
+        let read_number = self.chunk.first_read_number + self.index as u64;
+        self.index += 1;
+        Some(StarReadPair {
+            name: pair.name,
+            mate1: StarReadMate {
+                seq: pair.r1,
+                qual: Some(pair.q1),
+            },
+            mate2: Some(StarReadMate {
+                seq: pair.r2,
+                qual: Some(pair.q2),
+            }),
+            read_number,
+            read_files_index: 0,
+            filter: 0,
+            extra: "",
+        })
+    }
+}
+
+impl StarReadChunk for StarBatchChunk {
+    type Iter<'a> = StarBatchChunkIter<'a>;
+
+    fn chunk_index(&self) -> u32 {
+        self.chunk_index
+    }
+
+    fn reads(&self) -> Self::Iter<'_> {
+        StarBatchChunkIter {
+            chunk: self,
+            index: 0,
+        }
+    }
+
+    fn estimated_input_bytes(&self) -> usize {
+        self.estimated_mate_input_bytes[0].saturating_add(self.estimated_mate_input_bytes[1])
+    }
+}
@@ -0,0 +1,699 @@
+//! STARsolo-like gene quantification performed while aligning with STAR.
+//!
+//! The gene annotation is read from the STAR genome directory (the `*.tab` files STAR writes
+//! when the index is built with `--sjdbGTFfile`). Every uniquely mapped record is classified
+//! velocyto-style against the genes it overlaps:
+//! - spliced: all aligned blocks are exonic and the alignment contains a junction (`N`)
+//! - ambiguous: all aligned blocks are exonic, but there is no junction
+//! - unspliced: the alignment lies within the gene but touches an intron
+//!
+//! Only genes on the strand expected from `--star-solo-strand` are considered, and records
+//! overlapping more than one such gene are not counted. The cell and UMI come from the CB/UB
+//! tags. Counts are collapsed per (cell, gene, UMI); reads without a UMI are counted once per
+//! read pair. The resulting h5ad has the exonic (spliced + ambiguous) counts in `X` and one
+//! layer per category.
+
+use std::{
+    fs,
+    hash::{Hash, Hasher},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use ahash::{AHashMap, AHasher};
+use anyhow::{Context, Result};
+use noodles::sam::{
+    self,
+    alignment::RecordBuf,
+    alignment::record::{cigar::op::Kind as CigarKind, data::field::Tag},
+    alignment::record_buf::data::field::Value,
+};
+use sprs::{CsMat, TriMat};
+use tracing::info;
+
+use crate::command::align::SoloStrand;
+use crate::fileformat::new_anndata::SparseMatrixAnnDataWriter;
+use crate::fileformat::sample_sheet::SampleAnnotations;
+
+/// Bin size used to look up genes overlapping a position.
+const SOLO_GENE_BIN_SHIFT: u32 = 16;
+/// STAR reports MAPQ 255 for unique mappers (`--outSAMmapqUnique` default).
+const STAR_MAPQ_UNIQUE: u8 = 255;
+
+const SOLO_FLAG_SPLICED: u8 = 1;
+const SOLO_FLAG_UNSPLICED: u8 = 1 << 1;
+const SOLO_FLAG_AMBIGUOUS: u8 = 1 << 2;
+
+/// Strand codes in the third column of STAR's exonGeTrInfo.tab; 0 means unknown.
+const STAR_STRAND_FORWARD: &str = "1";
+const STAR_STRAND_REVERSE: &str = "2";
+
+const TAG_UMI: Tag = Tag::new(b'U', b'B');
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoloCategory {
+    Spliced,
+    Unspliced,
+    Ambiguous,
+}
+
+impl SoloCategory {
+    fn flag(self) -> u8 {
+        match self {
+            SoloCategory::Spliced => SOLO_FLAG_SPLICED,
+            SoloCategory::Unspliced => SOLO_FLAG_UNSPLICED,
+            SoloCategory::Ambiguous => SOLO_FLAG_AMBIGUOUS,
+        }
+    }
+
+    /// Position in the per-gene `[spliced, unspliced, ambiguous]` counts
+    fn index(self) -> usize {
+        match self {
+            SoloCategory::Spliced => 0,
+            SoloCategory::Unspliced => 1,
+            SoloCategory::Ambiguous => 2,
+        }
+    }
+
+    /// Collapse the categories seen for the reads of one UMI into a single category.
+    fn from_umi_flags(flags: u8) -> SoloCategory {
+        let spliced = flags & SOLO_FLAG_SPLICED != 0;
+        let unspliced = flags & SOLO_FLAG_UNSPLICED != 0;
+        match (spliced, unspliced) {
+            (true, true) => SoloCategory::Ambiguous,
+            (true, false) => SoloCategory::Spliced,
+            (false, true) => SoloCategory::Unspliced,
+            (false, false) => SoloCategory::Ambiguous,
+        }
+    }
+}
+
+struct SoloGene {
+    id: String,
+    name: String,
+    start: u64,
+    end: u64,
+    /// None if the GTF did not give a strand
+    is_reverse: Option<bool>,
+    /// Merged exon intervals, 0-based inclusive, chromosome-local
+    exons: Vec<(u64, u64)>,
+}
+
+impl SoloGene {
+    fn is_exonic(&self, start: u64, end: u64) -> bool {
+        self.exons
+            .iter()
+            .any(|&(exon_start, exon_end)| exon_start <= start && end <= exon_end)
+    }
+}
+
+///////////////////////////////
+/// Gene models loaded from a STAR genome directory
+pub struct SoloAnnotation {
+    genes: Vec<SoloGene>,
+    /// Per reference sequence (in SAM header order): bin -> genes overlapping the bin
+    bins: Vec<AHashMap<u64, Vec<u32>>>,
+    strand: SoloStrand,
+}
+
+impl SoloAnnotation {
+    /// Load the annotation STAR stores next to its index. Fails if the index was built
+    /// without a GTF, as there is nothing to quantify against.
+    pub fn from_star_genome_dir(
+        genome_dir: &Path,
+        header: &sam::Header,
+        strand: SoloStrand,
+    ) -> Result<Self> {
+        let path_exons = genome_dir.join("exonGeTrInfo.tab");
+        let path_genes = genome_dir.join("geneInfo.tab");
+        anyhow::ensure!(
+            path_exons.is_file() && path_genes.is_file(),
+            "STAR gene quantification requires a genome built with --sjdbGTFfile; missing {path_exons:?} or {path_genes:?}"
+        );
+
+        let chr_names = read_star_lines(&genome_dir.join("chrName.txt"))?;
+        let chr_starts = read_star_lines(&genome_dir.join("chrStart.txt"))?
+            .iter()
+            .map(|line| line.parse::<u64>())
+            .collect::<std::result::Result<Vec<u64>, _>>()
+            .context("failed to parse STAR chrStart.txt")?;
+        anyhow::ensure!(
+            chr_starts.len() > chr_names.len(),
+            "STAR chrStart.txt has {} entries for {} chromosomes",
+            chr_starts.len(),
+            chr_names.len()
+        );
+
+        //STAR lists chromosomes in chrName.txt order; map them onto the SAM header order
+        let chr_to_ref: Vec<Option<usize>> = chr_names
+            .iter()
+            .map(|name| header.reference_sequences().get_index_of(name.as_bytes()))
+            .collect();
+
+        let gene_lines = read_star_lines(&path_genes)?;
+        let mut genes: Vec<Option<(usize, SoloGene)>> = Vec::new();
+        let mut gene_ids = Vec::with_capacity(gene_lines.len().saturating_sub(1));
+        for line in gene_lines.iter().skip(1) {
+            let mut fields = line.split('\t');
+            let id = fields.next().unwrap_or_default().to_string();
+            let name = fields
+                .next()
+                .filter(|name| !name.is_empty())
+                .unwrap_or(&id)
+                .to_string();
+            gene_ids.push((id, name));
+            genes.push(None);
+        }
+
+        for line in read_star_lines(&path_exons)?.iter().skip(1) {
+            let fields: Vec<&str> = line.split('\t').collect();
+            anyhow::ensure!(
+                fields.len() >= 4,
+                "malformed line in STAR exonGeTrInfo.tab: {line:?}"
+            );
+            let start: u64 = fields[0]
+                .parse()
+                .context("bad exon start in exonGeTrInfo.tab")?;
+            let end: u64 = fields[1]
+                .parse()
+                .context("bad exon end in exonGeTrInfo.tab")?;
+            let is_reverse = match fields[2] {
+                STAR_STRAND_FORWARD => Some(false),
+                STAR_STRAND_REVERSE => Some(true),
+                _ => None,
+            };
+            let gene_index: usize = fields[3]
+                .parse()
+                .context("bad gene index in exonGeTrInfo.tab")?;
+            anyhow::ensure!(
+                gene_index < genes.len(),
+                "exonGeTrInfo.tab refers to gene {gene_index}, but geneInfo.tab has {} genes",
+                genes.len()
+            );
+
+            //Exon coordinates are global offsets into the concatenated genome
+            let chr = chr_starts.partition_point(|&chr_start| chr_start <= start) - 1;
+            let local_start = start - chr_starts[chr];
+            let local_end = end - chr_starts[chr];
+
+            let (id, name) = &gene_ids[gene_index];
+            let (_, gene) = genes[gene_index].get_or_insert_with(|| {
+                (
+                    chr,
+                    SoloGene {
+                        id: id.clone(),
+                        name: name.clone(),
+                        start: local_start,
+                        end: local_end,
+                        is_reverse,
+                        exons: Vec::new(),
+                    },
+                )
+            });
+            gene.start = gene.start.min(local_start);
+            gene.end = gene.end.max(local_end);
+            gene.exons.push((local_start, local_end));
+        }
+
+        let mut annotation = SoloAnnotation {
+            genes: Vec::new(),
+            bins: vec![AHashMap::new(); header.reference_sequences().len()],
+            strand,
+        };
+        for (chr, mut gene) in genes.into_iter().flatten() {
+            let Some(ref_index) = chr_to_ref.get(chr).copied().flatten() else {
+                continue;
+            };
+            gene.exons = merge_intervals(gene.exons);
+            let gene_index = annotation.genes.len() as u32;
+            for bin in (gene.start >> SOLO_GENE_BIN_SHIFT)..=(gene.end >> SOLO_GENE_BIN_SHIFT) {
+                annotation.bins[ref_index]
+                    .entry(bin)
+                    .or_default()
+                    .push(gene_index);
+            }
+            annotation.genes.push(gene);
+        }
+
+        info!(
+            genes = annotation.genes.len(),
+            "Loaded STAR gene annotation for quantification"
+        );
+        Ok(annotation)
+    }
+
+    /// Classify one aligned record. Returns the gene and category, or `None` if the record
+    /// should not be counted.
+    pub fn classify(&self, record: &RecordBuf) -> Option<(u32, SoloCategory)> {
+        let flags = record.flags();
+        if flags.is_unmapped() || flags.is_secondary() || flags.is_supplementary() {
+            return None;
+        }
+        if record.mapping_quality().map(|mapq| mapq.get()) != Some(STAR_MAPQ_UNIQUE) {
+            return None;
+        }
+        let ref_index = record.reference_sequence_id()?;
+        let start = record.alignment_start()?.get() as u64 - 1;
+
+        let ops = record
+            .cigar()
+            .as_ref()
+            .iter()
+            .map(|op| (op.kind(), op.len() as u64));
+        let (blocks, has_junction) = aligned_blocks(start, ops);
+
+        //The second mate is sequenced off the opposite strand of the fragment
+        let is_reverse =
+            flags.is_reverse_complemented() ^ (flags.is_segmented() && flags.is_last_segment());
+        self.classify_blocks(ref_index, &blocks, has_junction, is_reverse)
+    }
+
+    /// Whether a fragment on the given strand can come from `gene`
+    fn matches_strand(&self, gene: &SoloGene, is_reverse: bool) -> bool {
+        match (self.strand, gene.is_reverse) {
+            (SoloStrand::Unstranded, _) | (_, None) => true,
+            (SoloStrand::Forward, Some(gene_is_reverse)) => gene_is_reverse == is_reverse,
+            (SoloStrand::Reverse, Some(gene_is_reverse)) => gene_is_reverse != is_reverse,
+        }
+    }
+
+    fn classify_blocks(
+        &self,
+        ref_index: usize,
+        blocks: &[(u64, u64)],
+        has_junction: bool,
+        is_reverse: bool,
+    ) -> Option<(u32, SoloCategory)> {
+        let bins = self.bins.get(ref_index)?;
+        let (first, last) = (blocks.first()?.0, blocks.last()?.1);
+
+        let mut hit: Option<(u32, SoloCategory)> = None;
+        let mut seen = smallvec::SmallVec::<[u32; 4]>::new();
+        for bin in (first >> SOLO_GENE_BIN_SHIFT)..=(last >> SOLO_GENE_BIN_SHIFT) {
+            for &gene_index in bins.get(&bin).into_iter().flatten() {
+                if seen.contains(&gene_index) {
+                    continue;
+                }
+                seen.push(gene_index);
+
+                let gene = &self.genes[gene_index as usize];
+                if last < gene.start || gene.end < first || !self.matches_strand(gene, is_reverse) {
+                    continue;
+                }
+                let category = if blocks.iter().all(|&(s, e)| gene.is_exonic(s, e)) {
+                    if has_junction {
+                        SoloCategory::Spliced
+                    } else {
+                        SoloCategory::Ambiguous
+                    }
+                } else if gene.start <= first && last <= gene.end && !has_junction {
+                    SoloCategory::Unspliced
+                } else {
+                    //Partially outside of the gene, or a junction not matching the exons
+                    continue;
+                };
+
+                if hit.is_some() {
+                    //Overlaps several genes; not counted
+                    return None;
+                }
+                hit = Some((gene_index, category));
+            }
+        }
+        hit
+    }
+
+    pub fn num_genes(&self) -> usize {
+        self.genes.len()
+    }
+}
+
+///////////////////////////////
+/// Per-cell gene observations. One counter is kept per converter thread and merged at the end.
+#[derive(Default)]
+pub struct SoloCounter {
+    cells: AHashMap<Vec<u8>, SoloCellCounts>,
+    /// The last read without a UMI. It is held back so that its mate, which STAR writes right
+    /// after it, collapses onto it instead of being counted again
+    pending: Option<SoloPendingRead>,
+}
+
+#[derive(Default)]
+struct SoloCellCounts {
+    /// (gene, UMI hash) -> categories seen for that molecule
+    umis: AHashMap<(u32, u64), u8>,
+    /// gene -> read pairs without a UMI, as [spliced, unspliced, ambiguous]
+    reads: AHashMap<u32, [u32; 3]>,
+}
+
+struct SoloPendingRead {
+    cell_id: Vec<u8>,
+    read_name: Vec<u8>,
+    gene_index: u32,
+    flags: u8,
+}
+
+impl SoloCounter {
+    /// Classify and record one alignment. The cell and UMI are taken from the CB/UB tags, which
+    /// the STAR driver adds to every record; records without a cell are skipped.
+    pub fn record(&mut self, annotation: &SoloAnnotation, record: &RecordBuf) {
+        let Some((gene_index, category)) = annotation.classify(record) else {
+            return;
+        };
+        let Some(cell_id) = string_tag(record, Tag::CELL_BARCODE_ID) else {
+            return;
+        };
+        let umi = string_tag(record, TAG_UMI).filter(|umi| !umi.is_empty());
+        let read_name: &[u8] = record.name().map(|name| name.as_ref()).unwrap_or_default();
+        self.add(cell_id, umi, read_name, gene_index, category);
+    }
+
+    fn add(
+        &mut self,
+        cell_id: &[u8],
+        umi: Option<&[u8]>,
+        read_name: &[u8],
+        gene_index: u32,
+        category: SoloCategory,
+    ) {
+        let Some(umi) = umi else {
+            if let Some(pending) = &mut self.pending
+                && pending.gene_index == gene_index
+                && pending.read_name == read_name
+                && pending.cell_id == cell_id
+            {
+                pending.flags |= category.flag();
+                return;
+            }
+            self.flush_pending();
+            self.pending = Some(SoloPendingRead {
+                cell_id: cell_id.to_vec(),
+                read_name: read_name.to_vec(),
+                gene_index,
+                flags: category.flag(),
+            });
+            return;
+        };
+
+        let mut hasher = AHasher::default();
+        umi.hash(&mut hasher);
+        let umi_key = hasher.finish();
+        *self
+            .cell_counts(cell_id)
+            .umis
+            .entry((gene_index, umi_key))
+            .or_insert(0) |= category.flag();
+    }
+
+    fn cell_counts(&mut self, cell_id: &[u8]) -> &mut SoloCellCounts {
+        if !self.cells.contains_key(cell_id) {
+            self.cells
+                .insert(cell_id.to_vec(), SoloCellCounts::default());
+        }
+        self.cells.get_mut(cell_id).unwrap()
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some(pending) = self.pending.take() {
+            let category = SoloCategory::from_umi_flags(pending.flags);
+            let counts = self
+                .cell_counts(&pending.cell_id)
+                .reads
+                .entry(pending.gene_index)
+                .or_default();
+            counts[category.index()] += 1;
+        }
+    }
+
+    pub fn merge(&mut self, mut other: SoloCounter) {
+        other.flush_pending();
+        for (cell_id, other_counts) in other.cells {
+            let counts = self.cells.entry(cell_id).or_default();
+            for (key, flags) in other_counts.umis {
+                *counts.umis.entry(key).or_insert(0) |= flags;
+            }
+            for (gene_index, other_reads) in other_counts.reads {
+                let reads = counts.reads.entry(gene_index).or_default();
+                for (count, other_count) in reads.iter_mut().zip(other_reads) {
+                    *count += other_count;
+                }
+            }
+        }
+    }
+
+    /// Collapse UMIs and write gene x cell counts, with spliced/unspliced/ambiguous layers
+    pub fn save_to_anndata(
+        mut self,
+        annotation: &SoloAnnotation,
+        sample_annotations: SampleAnnotations,
+        path: &PathBuf,
+    ) -> Result<()> {
+        self.flush_pending();
+        let mut cell_ids: Vec<Vec<u8>> = self.cells.keys().cloned().collect();
+        cell_ids.sort_unstable();
+
+        let n_rows = cell_ids.len();
+        let n_cols = annotation.num_genes();
+        let mut mat_spliced = TriMat::<u32>::new((n_rows, n_cols));
+        let mut mat_unspliced = TriMat::<u32>::new((n_rows, n_cols));
+        let mut mat_ambiguous = TriMat::<u32>::new((n_rows, n_cols));
+        let mut mat_gene = TriMat::<u32>::new((n_rows, n_cols));
+
+        for (cell_index, cell_id) in cell_ids.iter().enumerate() {
+            let Some(cell_counts) = self.cells.get(cell_id) else {
+                continue;
+            };
+            let mut per_gene = cell_counts.reads.clone();
+            for (&(gene_index, _umi), &flags) in &cell_counts.umis {
+                let counts = per_gene.entry(gene_index).or_default();
+                counts[SoloCategory::from_umi_flags(flags).index()] += 1;
+            }
+            for (gene_index, [spliced, unspliced, ambiguous]) in per_gene {
+                let gene_index = gene_index as usize;
+                if spliced > 0 {
+                    mat_spliced.add_triplet(cell_index, gene_index, spliced);
+                }
+                if unspliced > 0 {
+                    mat_unspliced.add_triplet(cell_index, gene_index, unspliced);
+                }
+                if ambiguous > 0 {
+                    mat_ambiguous.add_triplet(cell_index, gene_index, ambiguous);
+                }
+                if spliced + ambiguous > 0 {
+                    mat_gene.add_triplet(cell_index, gene_index, spliced + ambiguous);
+                }
+            }
+        }
+
+        info!(
+            "Size of STAR gene count matrix: {}x{}  (cells x features)",
+            n_rows, n_cols
+        );
+        let mat_gene: CsMat<u32> = mat_gene.to_csr();
+        let mat_spliced: CsMat<u32> = mat_spliced.to_csr();
+        let mat_unspliced: CsMat<u32> = mat_unspliced.to_csr();
+        let mat_ambiguous: CsMat<u32> = mat_ambiguous.to_csr();
+
+        let mut file = SparseMatrixAnnDataWriter::create_anndata(path)?;
+        file.attach_sample_annotations(sample_annotations);
+        file.store_sparse_count_matrix(&mat_gene, n_rows as u32, n_cols as u32)?;
+        file.store_sparse_count_layers(
+            &[
+                ("spliced", &mat_spliced),
+                ("unspliced", &mat_unspliced),
+                ("ambiguous", &mat_ambiguous),
+            ],
+            n_rows as u32,
+            n_cols as u32,
+        )?;
+
+        let list_gene_ids: Vec<String> = annotation.genes.iter().map(|g| g.id.clone()).collect();
+        let list_gene_names: Vec<String> =
+            annotation.genes.iter().map(|g| g.name.clone()).collect();
+        file.store_feature_names_with_symbols(&list_gene_ids, &list_gene_names)?;
+
+        let list_cell_names: Vec<String> = cell_ids
+            .iter()
+            .map(|id| String::from_utf8_lossy(id).into_owned())
+            .collect();
+        file.store_cell_names(&list_cell_names, None)?;
+        file.close()?;
+        Ok(())
+    }
+}
+
+fn string_tag(record: &RecordBuf, tag: Tag) -> Option<&[u8]> {
+    match record.data().get(&tag)? {
+        Value::String(value) => Some(value.as_ref()),
+        _ => None,
+    }
+}
+
+/// Reference intervals covered by an alignment, 0-based inclusive. Deletions extend the
+/// current block; `N` starts a new one.
+fn aligned_blocks(
+    start: u64,
+    ops: impl Iterator<Item = (CigarKind, u64)>,
+) -> (smallvec::SmallVec<[(u64, u64); 4]>, bool) {
+    let mut blocks = smallvec::SmallVec::new();
+    let mut has_junction = false;
+    let mut pos = start;
+    let mut block_start = start;
+    for (kind, len) in ops {
+        match kind {
+            CigarKind::Match
+            | CigarKind::SequenceMatch
+            | CigarKind::SequenceMismatch
+            | CigarKind::Deletion => pos += len,
+            CigarKind::Skip => {
+                if pos > block_start {
+                    blocks.push((block_start, pos - 1));
+                }
+                has_junction = true;
+                pos += len;
+                block_start = pos;
+            }
+            CigarKind::Insertion | CigarKind::SoftClip | CigarKind::HardClip | CigarKind::Pad => {}
+        }
+    }
+    if pos > block_start {
+        blocks.push((block_start, pos - 1));
+    }
+    (blocks, has_junction)
+}
+
+fn merge_intervals(mut intervals: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+    for (start, end) in intervals {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn read_star_lines(path: &Path) -> Result<Vec<String>> {
+    let file = fs::File::open(path).with_context(|| format!("failed to open {path:?}"))?;
+    BufReader::new(file)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.is_empty()))
+        .collect::<std::io::Result<Vec<String>>>()
+        .with_context(|| format!("failed to read {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn annotation_one_gene() -> SoloAnnotation {
+        //Exons 100..=199 and 300..=399
+        let mut bins = AHashMap::new();
+        bins.insert(0, vec![0]);
+        SoloAnnotation {
+            genes: vec![SoloGene {
+                id: "G1".to_string(),
+                name: "gene1".to_string(),
+                start: 100,
+                end: 399,
+                is_reverse: Some(false),
+                exons: vec![(100, 199), (300, 399)],
+            }],
+            bins: vec![bins],
+            strand: SoloStrand::Forward,
+        }
+    }
+
+    #[test]
+    fn cigar_blocks_split_on_skip() {
+        let ops = [
+            (CigarKind::SoftClip, 5),
+            (CigarKind::Match, 50),
+            (CigarKind::Skip, 100),
+            (CigarKind::Match, 50),
+        ];
+        let (blocks, has_junction) = aligned_blocks(150, ops.into_iter());
+        assert!(has_junction);
+        assert_eq!(blocks.as_slice(), &[(150, 199), (300, 349)]);
+    }
+
+    #[test]
+    fn reads_are_classified_velocyto_style() {
+        let annotation = annotation_one_gene();
+        assert_eq!(
+            annotation.classify_blocks(0, &[(150, 199), (300, 349)], true, false),
+            Some((0, SoloCategory::Spliced))
+        );
+        assert_eq!(
+            annotation.classify_blocks(0, &[(120, 169)], false, false),
+            Some((0, SoloCategory::Ambiguous))
+        );
+        assert_eq!(
+            annotation.classify_blocks(0, &[(180, 229)], false, false),
+            Some((0, SoloCategory::Unspliced))
+        );
+        assert_eq!(
+            annotation.classify_blocks(0, &[(380, 429)], false, false),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_on_the_wrong_strand_are_not_counted() {
+        let mut annotation = annotation_one_gene();
+        assert_eq!(
+            annotation.classify_blocks(0, &[(120, 169)], false, true),
+            None
+        );
+
+        annotation.strand = SoloStrand::Reverse;
+        assert_eq!(
+            annotation.classify_blocks(0, &[(120, 169)], false, true),
+            Some((0, SoloCategory::Ambiguous))
+        );
+        assert_eq!(
+            annotation.classify_blocks(0, &[(120, 169)], false, false),
+            None
+        );
+
+        annotation.strand = SoloStrand::Unstranded;
+        assert!(
+            annotation
+                .classify_blocks(0, &[(120, 169)], false, true)
+                .is_some()
+        );
+    }
+
+    #[test]
+    fn mates_without_umi_are_counted_once_per_pair() {
+        let mut counter = SoloCounter::default();
+        counter.add(b"C1", None, b"C1::1", 0, SoloCategory::Spliced);
+        counter.add(b"C1", None, b"C1::1", 0, SoloCategory::Ambiguous);
+        counter.add(b"C1", None, b"C1::2", 0, SoloCategory::Unspliced);
+        counter.add(b"C1", Some(b"AAAA"), b"C1:AAAA:3", 0, SoloCategory::Spliced);
+        counter.add(b"C1", Some(b"AAAA"), b"C1:AAAA:4", 0, SoloCategory::Spliced);
+        counter.flush_pending();
+
+        let cell = &counter.cells[b"C1".as_slice()];
+        assert_eq!(cell.reads[&0], [1, 1, 0]);
+        assert_eq!(cell.umis.len(), 1);
+        assert!(counter.pending.is_none());
+    }
+
+    #[test]
+    fn umi_flags_collapse_to_one_category() {
+        assert_eq!(
+            SoloCategory::from_umi_flags(SOLO_FLAG_SPLICED | SOLO_FLAG_AMBIGUOUS),
+            SoloCategory::Spliced
+        );
+        assert_eq!(
+            SoloCategory::from_umi_flags(SOLO_FLAG_SPLICED | SOLO_FLAG_UNSPLICED),
+            SoloCategory::Ambiguous
+        );
+        assert_eq!(
+            SoloCategory::from_umi_flags(SOLO_FLAG_UNSPLICED),
+            SoloCategory::Unspliced
+        );
+    }
+}
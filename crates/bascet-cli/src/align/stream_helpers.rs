@@ -1,9 +1,5 @@
-//! Memory-budgeting helpers shared by the Stream-based aligners (STAR, minimap2). These were
-//! historically used by the BWA mainline path too, but BWA now drives its own pipeline-internal
-//! `ReadMemoryLimiter` inside `align_bwa_stock_driver` and no longer needs these.
-//!
-//! The whole module is feature-gated to `any(star-rs-align, minimap2-rs-align)` since neither
-//! function has a caller without those features.
+//! Memory-budgeting helpers shared by the stream-based drivers: the generic `backend` driver
+//! and STAR's pipelined one. minimap2 also sizes its batches with `aligner_batch_bases_cap`.
 
 use bytesize::ByteSize;
 use tracing::{info, warn};
@@ -0,0 +1,350 @@
+//! External-command backend: any aligner that reads FASTQ on stdin and writes SAM to stdout
+//! (`bowtie2 --interleaved -`, `bwa mem -p`, `minimap2 -a`, `hisat2 --12 -` …).
+//!
+//! The command is started once per run. Each batch is written to its stdin as FASTQ (pairs
+//! interleaved, R1 only for single-end reads) while a reader thread collects its stdout; the
+//! header is taken from the `@` lines ahead of the first record. Cell and UMI tags are recovered
+//! from the read names, so the aligner must keep them verbatim as QNAME.
+//!
+//! The input must be all paired or all single-end: an interleaving aligner would pair a lone R1
+//! with the next read.
+
+use std::{
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    process::{Child, ChildStdin, Command, Stdio},
+    thread::JoinHandle,
+};
+
+use anyhow::{Context, Result};
+use crossbeam::channel::Receiver;
+use tracing::{debug, info};
+
+use super::backend::{AlignJob, Aligner, BatchLimits, ReadBatch, ReadPair, run_aligner};
+use super::output::{SamRecordSink, TaggedBamOutput};
+
+/// Bases written to the aligner between two drains of its output. Only bounds how much SAM can
+/// pile up on our side; the aligner does its own batching.
+const PIPE_BATCH_BASES: usize = 64 * 1024 * 1024;
+/// SAM lines per message from the stdout reader thread.
+const PIPE_LINES_PER_BLOCK: usize = 4096;
+
+pub fn try_execute_external(
+    job: &AlignJob<'_>,
+    command: &str,
+    path_genome: &Path,
+    align_threads: usize,
+) -> Result<()> {
+    let aligner = SamPipeAligner::spawn(command, path_genome, align_threads)?;
+    run_aligner(aligner, job)
+}
+
+/// Split `command` into program + arguments (shell-style quoting) and substitute `{genome}` and
+/// `{threads}` in every word.
+pub fn expand_aligner_command(
+    command: &str,
+    path_genome: &Path,
+    threads: usize,
+) -> Result<Vec<String>> {
+    let words = shellwords::split(command)
+        .with_context(|| format!("failed to parse aligner command: {command:?}"))?;
+    if words.is_empty() {
+        anyhow::bail!("aligner command is empty");
+    }
+    let genome = path_genome.display().to_string();
+    let threads = threads.to_string();
+    Ok(words
+        .into_iter()
+        .map(|word| {
+            word.replace("{genome}", &genome)
+                .replace("{threads}", &threads)
+        })
+        .collect())
+}
+
+/// Runs an external aligner as a child process, speaking FASTQ in and SAM out over pipes.
+pub struct SamPipeAligner {
+    program: String,
+    child: Child,
+    stdin: Option<BufWriter<ChildStdin>>,
+    lines: Receiver<std::io::Result<Vec<String>>>,
+    reader: Option<JoinHandle<()>>,
+    /// Whether the input is paired, fixed by the first read written.
+    paired: Option<bool>,
+}
+
+impl SamPipeAligner {
+    pub fn spawn(command: &str, path_genome: &Path, threads: usize) -> Result<Self> {
+        let words = expand_aligner_command(command, path_genome, threads)?;
+        let program = words[0].clone();
+        info!(command = ?words, "Starting external aligner");
+        let mut child = Command::new(&program)
+            .args(&words[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .with_context(|| format!("failed to start external aligner {program:?}"))?;
+        let stdin = child
+            .stdin
+            .take()
+            .context("external aligner has no stdin")?;
+        let stdout = child
+            .stdout
+            .take()
+            .context("external aligner has no stdout")?;
+
+        // NOTE:    the channel is unbounded on purpose: the reader must never stop draining
+        //          stdout, or the aligner blocks on it while we block writing its stdin. What
+        //          piles up is at most what the aligner emits while one batch is written.
+        let (tx, lines) = crossbeam::channel::unbounded();
+        let reader = std::thread::Builder::new()
+            .name("AlignPipeReader".to_string())
+            .spawn(move || {
+                let mut stdout = BufReader::with_capacity(1 << 20, stdout);
+                let mut block = Vec::with_capacity(PIPE_LINES_PER_BLOCK);
+                loop {
+                    let mut line = String::new();
+                    match stdout.read_line(&mut line) {
+                        Ok(0) => break,
+                        Ok(_) => block.push(line),
+                        Err(err) => {
+                            let _ = tx.send(Err(err));
+                            return;
+                        }
+                    }
+                    if block.len() >= PIPE_LINES_PER_BLOCK
+                        && tx.send(Ok(std::mem::take(&mut block))).is_err()
+                    {
+                        return;
+                    }
+                }
+                if !block.is_empty() {
+                    let _ = tx.send(Ok(block));
+                }
+            })
+            .context("failed to spawn external aligner reader thread")?;
+
+        Ok(Self {
+            program,
+            child,
+            stdin: Some(BufWriter::with_capacity(1 << 20, stdin)),
+            lines,
+            reader: Some(reader),
+            paired: None,
+        })
+    }
+
+    fn write_lines(
+        &self,
+        block: std::io::Result<Vec<String>>,
+        output: &mut TaggedBamOutput,
+    ) -> Result<()> {
+        let block =
+            block.with_context(|| format!("failed to read SAM output of {:?}", self.program))?;
+        for line in block {
+            output.record(&line)?;
+        }
+        Ok(())
+    }
+
+    /// Turn a failed pipe write into the aligner's exit status when it has already died.
+    fn pipe_error(&mut self, err: std::io::Error) -> anyhow::Error {
+        match self.child.try_wait() {
+            Ok(Some(status)) => {
+                anyhow::anyhow!("external aligner {:?} exited early: {status}", self.program)
+            }
+            _ => anyhow::Error::new(err)
+                .context(format!("failed to write reads to {:?}", self.program)),
+        }
+    }
+}
+
+impl Aligner for SamPipeAligner {
+    fn name(&self) -> &'static str {
+        "external"
+    }
+
+    fn sam_header(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn batch_limits(&self) -> BatchLimits {
+        BatchLimits {
+            target_bases: PIPE_BATCH_BASES,
+            max_pairs: usize::MAX,
+        }
+    }
+
+    fn align_batch(&mut self, batch: &ReadBatch, output: &mut TaggedBamOutput) -> Result<()> {
+        for pair in batch.iter() {
+            check_pair_layout(&mut self.paired, &pair)?;
+        }
+        let Some(stdin) = self.stdin.as_mut() else {
+            anyhow::bail!("external aligner input is already closed");
+        };
+        let written = batch
+            .iter()
+            .try_for_each(|pair| write_fastq_pair(stdin, &pair))
+            .and_then(|()| stdin.flush());
+        if let Err(err) = written {
+            return Err(self.pipe_error(err));
+        }
+
+        for block in self.lines.try_iter() {
+            self.write_lines(block, output)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, output: &mut TaggedBamOutput) -> Result<()> {
+        if let Some(mut stdin) = self.stdin.take()
+            && let Err(err) = stdin.flush()
+        {
+            return Err(self.pipe_error(err));
+        }
+
+        for block in self.lines.iter() {
+            self.write_lines(block, output)?;
+        }
+        if let Some(reader) = self.reader.take() {
+            reader
+                .join()
+                .map_err(|_| anyhow::anyhow!("external aligner reader thread panicked"))?;
+        }
+        let status = self
+            .child
+            .wait()
+            .with_context(|| format!("failed to wait for {:?}", self.program))?;
+        if !status.success() {
+            anyhow::bail!("external aligner {:?} failed: {status}", self.program);
+        }
+        debug!(program = %self.program, "External aligner finished");
+        Ok(())
+    }
+}
+
+impl Drop for SamPipeAligner {
+    fn drop(&mut self) {
+        // Only reached with the child still running if the run failed part-way.
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+/// Fail on the first read whose layout (paired or single-end) differs from the reads before it.
+fn check_pair_layout(paired: &mut Option<bool>, pair: &ReadPair<'_>) -> Result<()> {
+    let is_paired = !pair.r2.is_empty();
+    match *paired.get_or_insert(is_paired) {
+        expected if expected == is_paired => Ok(()),
+        true => anyhow::bail!(
+            "read {} is single-end but earlier reads are paired; external aligners need one layout",
+            pair.name
+        ),
+        false => anyhow::bail!(
+            "read {} is paired but earlier reads are single-end; external aligners need one layout",
+            pair.name
+        ),
+    }
+}
+
+/// Write one pair as FASTQ: two interleaved records sharing the read name, or a single record
+/// when R2 is empty.
+fn write_fastq_pair(writer: &mut impl Write, pair: &ReadPair<'_>) -> std::io::Result<()> {
+    write_fastq_record(writer, pair.name, pair.r1, pair.q1)?;
+    if !pair.r2.is_empty() {
+        write_fastq_record(writer, pair.name, pair.r2, pair.q2)?;
+    }
+    Ok(())
+}
+
+fn write_fastq_record(
+    writer: &mut impl Write,
+    name: &str,
+    seq: &[u8],
+    qual: &[u8],
+) -> std::io::Result<()> {
+    writer.write_all(b"@")?;
+    writer.write_all(name.as_bytes())?;
+    writer.write_all(b"\n")?;
+    writer.write_all(seq)?;
+    writer.write_all(b"\n+\n")?;
+    writer.write_all(qual)?;
+    writer.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{ReadPair, check_pair_layout, expand_aligner_command, write_fastq_pair};
+
+    fn pair<'a>(r2: &'a [u8], q2: &'a [u8]) -> ReadPair<'a> {
+        ReadPair {
+            name: "CELL:UMI:0",
+            cell_id: "CELL",
+            umi: Some("UMI"),
+            r1: b"ACGT",
+            q1: b"FFFF",
+            r2,
+            q2,
+        }
+    }
+
+    #[test]
+    fn pairs_are_written_as_interleaved_fastq() {
+        let mut fastq = Vec::new();
+        write_fastq_pair(&mut fastq, &pair(b"TTG", b"III")).unwrap();
+
+        assert_eq!(
+            fastq,
+            b"@CELL:UMI:0\nACGT\n+\nFFFF\n@CELL:UMI:0\nTTG\n+\nIII\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn single_end_reads_are_written_alone() {
+        let mut fastq = Vec::new();
+        write_fastq_pair(&mut fastq, &pair(b"", b"")).unwrap();
+
+        assert_eq!(fastq, b"@CELL:UMI:0\nACGT\n+\nFFFF\n".to_vec());
+    }
+
+    #[test]
+    fn mixed_paired_and_single_end_reads_are_rejected() {
+        let mut paired = None;
+        check_pair_layout(&mut paired, &pair(b"TTG", b"III")).unwrap();
+        check_pair_layout(&mut paired, &pair(b"TTG", b"III")).unwrap();
+        assert!(check_pair_layout(&mut paired, &pair(b"", b"")).is_err());
+
+        let mut single = None;
+        check_pair_layout(&mut single, &pair(b"", b"")).unwrap();
+        assert!(check_pair_layout(&mut single, &pair(b"TTG", b"III")).is_err());
+    }
+
+    #[test]
+    fn command_placeholders_are_substituted_per_word() {
+        let words = expand_aligner_command(
+            "bowtie2 -p {threads} -x '{genome}' --interleaved -",
+            Path::new("/refs/my genome"),
+            8,
+        )
+        .unwrap();
+
+        assert_eq!(
+            words,
+            [
+                "bowtie2",
+                "-p",
+                "8",
+                "-x",
+                "/refs/my genome",
+                "--interleaved",
+                "-"
+            ]
+        );
+        assert!(expand_aligner_command("  ", Path::new("ref"), 1).is_err());
+    }
+}
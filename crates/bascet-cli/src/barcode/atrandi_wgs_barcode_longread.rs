@@ -44,6 +44,27 @@ impl DebarcodeAtrandiWGSChemistryLongread {
 
         result
     }
+
+    ///////////////////////////////
+    /// Scan `seq` for barcode windows whose linkers match with at most `max_mismatches`.
+    /// Returns the start of each window and whether a barcode could be decoded there.
+    /// Hits do not overlap.
+    pub fn scan_barcode_windows(&self, seq: &[u8], max_mismatches: usize) -> Vec<(usize, bool)> {
+        let mut hits = Vec::new();
+        let mut pos = 0;
+        while pos + LONGREAD_BC_WINDOW_LEN <= seq.len() {
+            let window = &seq[pos..pos + LONGREAD_BC_WINDOW_LEN];
+            if linker_mismatches(window) <= max_mismatches {
+                // no early abort: its partial score is not negative on failure
+                let (_, bc_score) = self.barcode.detect_barcode(window, false, 4, 1);
+                hits.push((pos, bc_score >= 0));
+                pos += LONGREAD_BC_WINDOW_LEN;
+            } else {
+                pos += 1;
+            }
+        }
+        hits
+    }
 }
 impl crate::barcode::Chemistry for DebarcodeAtrandiWGSChemistryLongread {
     ///////////////////////////////
@@ -209,6 +230,27 @@ impl crate::barcode::Chemistry for DebarcodeAtrandiWGSChemistryLongread {
     }
 }
 
+/// Barcode window of the long-read chemistry: 4x 8bp barcodes separated by 3 linkers, and 1
+/// to account for ligation
+pub const LONGREAD_BC_WINDOW_LEN: usize = 8 + 4 + 8 + 4 + 8 + 4 + 8 + 1;
+
+/// Offset and sequence of the linkers within a barcode window
+const LONGREAD_LINKERS: [(usize, &[u8; 4]); 3] = [(8, b"AGGA"), (20, b"ACTC"), (32, b"AAGG")];
+
+/// Number of mismatching linker bases (0..=12) in a barcode window
+pub fn linker_mismatches(window: &[u8]) -> usize {
+    LONGREAD_LINKERS
+        .iter()
+        .map(|(offset, linker)| {
+            window[*offset..*offset + 4]
+                .iter()
+                .zip(linker.iter())
+                .filter(|(a, b)| a != b)
+                .count()
+        })
+        .sum()
+}
+
 /// Copy a list of u8 into a u32 (almost a transmute).
 /// This function is as fast as if the size was explicitly given; size is likely added on top during inlining
 #[inline(always)]
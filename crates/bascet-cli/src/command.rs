@@ -7,17 +7,25 @@ pub mod bamsort;
 pub mod countchrom;
 pub mod countfeature;
 pub mod countsketch;
+pub mod deplete;
+pub mod doublets;
 pub mod extract;
 pub mod extract_terminal;
 pub mod exttool;
 #[cfg(feature = "fastqc")]
 pub mod fastqc;
+pub mod fastqc_summary;
+pub mod feature_barcode;
 pub mod featurise_kmc;
+pub mod filter;
 pub mod filterbam;
 #[cfg(feature = "gecco")]
 pub mod gecco;
 pub mod getraw;
+pub mod header;
+pub mod import_bam;
 pub mod import_sra;
+pub mod index;
 // Disabled until the pipe-based KMC path is replaced with a Rust KMC implementation.
 // pub mod kmc_reads;
 pub mod kraken;
@@ -29,21 +37,27 @@ pub mod detect_kmer_kmc;
 pub mod mapcell;
 pub mod minhash_fq;
 pub mod minhash_hist;
+pub mod multiref;
 pub mod ncbi_genome_download;
 pub mod qc;
 pub mod quast;
+#[cfg(feature = "minimap2-rs-align")]
+pub mod quast_reference;
+pub mod relabel_cells;
 pub mod sam_add_barcode_tag_cmd;
 pub mod samtools_rs;
 pub mod shardify;
 #[cfg(feature = "skesa")]
 pub mod skesa;
 pub mod snpcall;
+pub mod stats;
 pub mod threadcount;
 pub mod tobigwig;
 pub mod tofq;
 pub mod transform;
 pub mod transform_bam2tirp;
 pub mod transform_tirp2fq;
+pub mod validate;
 
 // BAM/SAM operations
 pub use align::AlignCMD;
@@ -56,25 +70,35 @@ pub use sam_add_barcode_tag_cmd::PipeSamAddTagsCMD;
 pub use countchrom::{CountChrom, CountChromCMD};
 pub use countfeature::{CountFeature, CountFeatureCMD};
 pub use countsketch::CountsketchCMD;
+pub use deplete::DepleteCMD;
 pub use detect_kmer_fq::{DetectKmerFq, DetectKmerFqCMD};
 pub use detect_kmer_kmc::{DetectKmerKmcCMD, QueryKmc, QueryKmcParams};
+pub use doublets::DoubletsCMD;
 pub use extract::ExtractCMD;
 pub use extract_terminal::ExtractStreamCMD;
 pub use exttool::ExttoolCMD;
 #[cfg(feature = "fastqc")]
 pub use fastqc::FastqcCMD;
+pub use fastqc_summary::FastqcSummaryCMD;
+pub use feature_barcode::FeatureBarcodeCMD;
 pub use featurise_kmc::{FeaturiseKMC, FeaturiseKmcCMD, FeaturiseParamsKMC};
+pub use filter::FilterCMD;
 pub use filterbam::FilterBamCMD;
 #[cfg(feature = "gecco")]
 pub use gecco::GeccoCMD;
 pub use getraw::GetRawCMD;
+pub use header::HeaderCMD;
+pub use import_bam::ImportBamCMD;
 pub use import_sra::ImportSraCMD;
+pub use index::IndexCMD;
 pub use mapcell::{MapCell, MapCellCMD};
 pub use minhash_fq::MinhashFqCMD;
 pub use minhash_hist::{MinhashHist, MinhashHistCMD};
+pub use multiref::MultirefCMD;
 pub use ncbi_genome_download::NcbiGenomeDownloadCMD;
 pub use qc::QcCMD;
 pub use quast::QuastCMD;
+pub use relabel_cells::RelabelCellsCMD;
 
 // Taxonomic classification
 pub use kraken::KrakenCMD;
@@ -83,11 +107,13 @@ pub use kraken::KrakenCMD;
 pub use shardify::ShardifyCMD;
 #[cfg(feature = "skesa")]
 pub use skesa::SkesaCMD;
+pub use stats::StatsCMD;
 pub use threadcount::{
     determine_thread_counts_1, determine_thread_counts_2, determine_thread_counts_3,
 };
 pub use tobigwig::ToBigWigCMD;
 pub use transform::{TransformCMD, TransformFile};
+pub use validate::ValidateCMD;
 
 use crate::command::{sysinfo::SysinfoCMD, tofq::ToFastqCMD};
 
@@ -102,33 +128,45 @@ pub enum Commands {
     Countchrom(CountChromCMD),
     Countfeature(CountFeatureCMD),
     Countsketch(CountsketchCMD),
+    Deplete(DepleteCMD),
     DetectKmerKmc(DetectKmerKmcCMD),
     DetectKmerFq(DetectKmerFqCMD),
+    Doublets(DoubletsCMD),
     Extract(ExtractCMD),
     ExtractStream(ExtractStreamCMD),
     Exttool(ExttoolCMD),
+    Filter(FilterCMD),
     Filterbam(FilterBamCMD),
     #[cfg(feature = "fastqc")]
     Fastqc(FastqcCMD),
+    FastqcSummary(FastqcSummaryCMD),
+    FeatureBarcode(FeatureBarcodeCMD),
     Featurise(FeaturiseKmcCMD),
     #[cfg(feature = "gecco")]
     Gecco(GeccoCMD),
     Debarcode(GetRawCMD),
+    Header(HeaderCMD),
+    ImportBam(ImportBamCMD),
     ImportSra(ImportSraCMD),
+    Index(IndexCMD),
     //KmcReads(KmcReadsCMD),
     Kraken(KrakenCMD),
     Mapcell(MapCellCMD),
     MinhashFq(MinhashFqCMD),
     MinhashHist(MinhashHistCMD),
+    Multiref(MultirefCMD),
     NcbiGenomeDownload(NcbiGenomeDownloadCMD),
     PipeSamAddTags(PipeSamAddTagsCMD), //Not needed for bascet anymore, but useful if anyone needs to use a non-standard aligner
     Quast(QuastCMD),
+    RelabelCells(RelabelCellsCMD),
     Shardify(ShardifyCMD),
     #[cfg(feature = "skesa")]
     Skesa(SkesaCMD),
+    Stats(StatsCMD),
     Sysinfo(SysinfoCMD),
     Tobigwig(ToBigWigCMD),
     ToFastq(ToFastqCMD),
     Transform(TransformCMD),
+    Validate(ValidateCMD),
     Qc(QcCMD),
 }
@@ -2,6 +2,7 @@
 //! dispatches to the per-aligner implementation in `crate::align::*`. All pipeline logic
 //! lives under `crate::align`.
 
+use crate::align::backend::AlignJob;
 use crate::bounded_parser;
 
 use bascet_core::*;
@@ -10,9 +11,9 @@ use bascet_derive::Budget;
 use anyhow::Result;
 use bounded_integer::BoundedU64;
 use bytesize::*;
-use clap::Args;
+use clap::{Args, ValueEnum};
 use clio::InputPath;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 #[cfg(any(
     feature = "bwa-mem2-rs-align",
     feature = "star-rs-align",
@@ -22,6 +23,7 @@ use std::sync::Arc;
 use tracing::{info, warn};
 
 #[derive(Args)]
+#[command(mut_group("AlignerArgs", |group| group.required(true)))]
 pub struct AlignCMD {
     #[arg(
         short = 'i',
@@ -39,9 +41,50 @@ pub struct AlignCMD {
     #[arg(long = "temp", help = "Temp directory; must exist already")]
     pub path_temp: PathBuf,
 
-    #[arg(short = 'g', long = "genome", help = "Genome to use")]
-    pub path_genome: PathBuf,
+    #[command(flatten)]
+    pub aligner: AlignerArgs,
+
+    #[command(flatten)]
+    pub resources: AlignResourceArgs,
+
+    #[arg(
+        long = "star-solo-out",
+        help = "With --aligner STAR: also count genes while aligning (spliced/unspliced/ambiguous layers) and write a gene x cell h5ad here. Requires a STAR genome built with a GTF"
+    )]
+    path_out_solo: Option<PathBuf>,
+
+    #[arg(
+        long = "star-solo-strand",
+        help = "With --star-solo-out: strand of the first read relative to the genes it is counted against",
+        value_enum,
+        default_value_t = SoloStrand::Forward,
+        hide_short_help = true
+    )]
+    solo_strand: SoloStrand,
 
+    #[arg(
+        long = "max-read-pairs",
+        help = "Stop after this many input read pairs [advanced/testing]",
+        hide_short_help = true,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_read_pairs: Option<u64>,
+}
+
+/// Strand of the first read of a pair relative to the transcript, as `--soloStrand` in STARsolo
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SoloStrand {
+    /// The first read has the strand of the transcript
+    Forward,
+    /// The first read has the opposite strand of the transcript
+    Reverse,
+    /// Count against genes on either strand
+    Unstranded,
+}
+
+/// Threads, memory and stream buffers of an alignment run.
+#[derive(Args)]
+pub struct AlignResourceArgs {
     #[arg(
         short = '@',
         long = "threads",
@@ -76,6 +119,34 @@ pub struct AlignCMD {
         value_parser = clap::value_parser!(ByteSize),
     )]
     sizeof_stream_arena: ByteSize,
+}
+
+impl AlignResourceArgs {
+    /// The requested thread count, or the available parallelism if none was given
+    pub fn total_threads(&self) -> BoundedU64<2, { u64::MAX }> {
+        self.total_threads.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(|p| p.get())
+                .unwrap_or_else(|e| {
+                    warn!(error = %e, "Failed to determine available parallelism, using 2 threads");
+                    2
+                })
+                .try_into()
+                .unwrap_or_else(|e| {
+                    warn!(error = %e, "Failed to convert parallelism to valid thread count, using 2 threads");
+                    2.try_into().unwrap()
+                })
+        })
+    }
+}
+
+/// Aligner and reference, shared by `align` and commands that align on the fly. The genome and
+/// aligner are required once any of these options is given.
+#[derive(Args)]
+#[group(requires_all = ["path_genome", "aligner"], multiple = true)]
+pub struct AlignerArgs {
+    #[arg(short = 'g', long = "genome", help = "Genome to use", required = false)]
+    pub path_genome: PathBuf,
 
     #[arg(
         long = "bwamem2-batch-pairs",
@@ -89,11 +160,19 @@ pub struct AlignCMD {
     #[arg(
         long = "aligner",
         help = "The command to send the data to",
-        value_parser = ["BWAMEM2", "STAR", "minimap2"],
-        hide_short_help = true
+        value_parser = ["BWAMEM2", "STAR", "minimap2", "external"],
+        hide_short_help = true,
+        required = false
     )]
     aligner: String,
 
+    #[arg(
+        long = "aligner-cmd",
+        help = "With --aligner external: command that reads FASTQ on stdin (pairs interleaved) and writes SAM to stdout. {genome} and {threads} are substituted, e.g. \"bowtie2 -p {threads} -x {genome} --interleaved -\". Read names must be kept as QNAME",
+        hide_short_help = true
+    )]
+    aligner_cmd: Option<String>,
+
     #[arg(
         long = "minimap2-preset",
         help = "minimap2 preset to use when --aligner minimap2 is selected",
@@ -101,14 +180,6 @@ pub struct AlignCMD {
         hide_short_help = true
     )]
     minimap2_preset: String,
-
-    #[arg(
-        long = "max-read-pairs",
-        help = "Stop after this many input read pairs [advanced/testing]",
-        hide_short_help = true,
-        value_parser = clap::value_parser!(u64).range(1..)
-    )]
-    max_read_pairs: Option<u64>,
 }
 
 #[derive(Budget, Debug)]
@@ -131,13 +202,11 @@ struct AlignBudget {
 }
 
 #[derive(Debug, Clone, Copy)]
-#[cfg(feature = "minimap2-rs-align")]
 struct AlignThreadAllocation {
     read: BoundedU64<1, { u64::MAX }>,
     write_bam: usize,
 }
 
-#[cfg(feature = "minimap2-rs-align")]
 impl AlignThreadAllocation {
     fn from_budget(budget: &AlignBudget) -> Self {
         let total_threads = budget.threads.get();
@@ -158,108 +227,153 @@ impl AlignThreadAllocation {
 
 impl AlignCMD {
     pub fn try_execute(&mut self) -> Result<()> {
-        let budget = AlignBudget::builder()
-            .threads(self.total_threads.unwrap_or_else(|| {
-                std::thread::available_parallelism()
-                    .map(|p| p.get())
-                    .unwrap_or_else(|e| {
-                        warn!(error = %e, "Failed to determine available parallelism, using 2 threads");
-                        2
-                    })
-                    .try_into()
-                    .unwrap_or_else(|e| {
-                        warn!(error = %e, "Failed to convert parallelism to valid thread count, using 2 threads");
-                        2.try_into().unwrap()
-                    })
-            }))
-            .memory(self.total_mem)
-            .maybe_sizeof_stream_buffer(self.sizeof_stream_buffer)
-            .build();
-
-        budget.validate();
-        #[cfg(feature = "minimap2-rs-align")]
-        let thread_allocation = AlignThreadAllocation::from_budget(&budget);
-        // Shared by aligners that can run their internal parallel regions and helper work on a
-        // common fixed-size worker pool.
-        #[cfg(any(
-            feature = "bwa-mem2-rs-align",
-            feature = "star-rs-align",
-            feature = "minimap2-rs-align"
-        ))]
-        let rayon_pool = Arc::new(
-            rayon::ThreadPoolBuilder::new()
-                .num_threads(budget.threads.get() as usize)
-                .thread_name(|idx| format!("align-rayon@{idx}"))
-                .build()?,
-        );
+        if self.path_out_solo.is_some() && self.aligner.aligner != "STAR" {
+            anyhow::bail!("--star-solo-out is only supported with --aligner STAR");
+        }
+        align_tirp(
+            &self.aligner,
+            &self.resources,
+            AlignPaths {
+                path_in: self.path_in.path().path(),
+                path_out_unsorted: &self.path_out_unsorted,
+                path_out_sorted: Some(self.path_out_sorted.as_path()),
+                path_temp: &self.path_temp,
+                report_outputs: true,
+            },
+            self.path_out_solo
+                .as_deref()
+                .map(|path| (path, self.solo_strand)),
+            self.max_read_pairs,
+        )
+    }
+}
+
+/// Input TIRP and output BAM paths of an alignment run. Without `path_out_sorted` the unsorted
+/// BAM is the only output and no sort is run. `report_outputs` is off when the BAM only feeds
+/// another command (e.g. deplete), so it stays out of the run report.
+pub struct AlignPaths<'a> {
+    pub path_in: &'a Path,
+    pub path_out_unsorted: &'a Path,
+    pub path_out_sorted: Option<&'a Path>,
+    pub path_temp: &'a Path,
+    pub report_outputs: bool,
+}
 
-        info!(
-            threads = budget.threads.get(),
-            memory = %budget.memory,
-            aligner = %self.aligner,
-            "Starting align"
+/// Budget an alignment run and dispatch it to the selected backend.
+pub fn align_tirp(
+    aligner: &AlignerArgs,
+    resources: &AlignResourceArgs,
+    paths: AlignPaths<'_>,
+    solo: Option<(&Path, SoloStrand)>,
+    max_read_pairs: Option<u64>,
+) -> Result<()> {
+    let budget = AlignBudget::builder()
+        .threads(resources.total_threads())
+        .memory(resources.total_mem)
+        .maybe_sizeof_stream_buffer(resources.sizeof_stream_buffer)
+        .build();
+
+    budget.validate();
+    if aligner.aligner_cmd.is_some() != (aligner.aligner == "external") {
+        anyhow::bail!(
+            "--aligner-cmd is required with, and only supported with, --aligner external"
         );
+    }
+    let thread_allocation = AlignThreadAllocation::from_budget(&budget);
+    let job = AlignJob {
+        path_in: paths.path_in,
+        path_out_unsorted: paths.path_out_unsorted,
+        path_out_sorted: paths.path_out_sorted,
+        path_temp: paths.path_temp,
+        report_outputs: paths.report_outputs,
+        total_memory: budget.memory,
+        total_threads: budget.threads.get(),
+        read_threads: thread_allocation.read,
+        write_bam_threads: thread_allocation.write_bam,
+        sizeof_stream_arena: resources.sizeof_stream_arena,
+        sizeof_stream_buffer: budget.sizeof_stream_buffer,
+        max_read_pairs,
+    };
+    // Shared by aligners that can run their internal parallel regions and helper work on a
+    // common fixed-size worker pool.
+    #[cfg(any(
+        feature = "bwa-mem2-rs-align",
+        feature = "star-rs-align",
+        feature = "minimap2-rs-align"
+    ))]
+    let rayon_pool = Arc::new(
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(budget.threads.get() as usize)
+            .thread_name(|idx| format!("align-rayon@{idx}"))
+            .build()?,
+    );
 
-        #[cfg(feature = "bwa-mem2-rs-align")]
-        if self.aligner == "BWAMEM2" {
-            return crate::align::bwa::try_execute_bwa_mem2(
-                self.path_in.path().path(),
-                &self.path_genome,
-                &self.path_out_unsorted,
-                &self.path_out_sorted,
-                &self.path_temp,
-                budget.threads.get() as usize,
-                budget.memory,
-                budget.threads.get(),
-                Arc::clone(&rayon_pool),
-                self.bwamem2_batch_pairs,
-            );
-        }
+    info!(
+        threads = budget.threads.get(),
+        memory = %budget.memory,
+        aligner = %aligner.aligner,
+        "Starting align"
+    );
 
-        #[cfg(feature = "star-rs-align")]
-        if self.aligner == "STAR" {
-            let star_threads = budget.threads.get() as usize;
-            let star_bam_writer_threads = star_threads.div_ceil(2).clamp(1, 16);
-            return crate::align::star::try_execute_star_rs(
-                self.path_in.path().path(),
-                &self.path_genome,
-                &self.path_out_unsorted,
-                &self.path_out_sorted,
-                &self.path_temp,
-                star_bam_writer_threads,
+    #[cfg(feature = "bwa-mem2-rs-align")]
+    if aligner.aligner == "BWAMEM2" {
+        return crate::align::bwa::try_execute_bwa_mem2(
+            &job,
+            &aligner.path_genome,
+            budget.threads.get() as usize,
+            Arc::clone(&rayon_pool),
+            aligner.bwamem2_batch_pairs,
+        );
+    }
+
+    #[cfg(not(feature = "star-rs-align"))]
+    let _ = solo;
+    #[cfg(feature = "star-rs-align")]
+    if aligner.aligner == "STAR" {
+        let star_threads = budget.threads.get() as usize;
+        // NOTE:    only STARsolo needs STAR's own pipelined driver, as it counts genes while
+        //          mapping; plain alignment goes through the shared `Aligner` driver
+        if solo.is_none() {
+            return crate::align::star::try_execute_star(
+                &job,
+                &aligner.path_genome,
                 star_threads,
-                self.sizeof_stream_arena,
-                budget.sizeof_stream_buffer,
-                budget.memory,
-                budget.threads.get(),
                 Arc::clone(&rayon_pool),
-                self.max_read_pairs,
             );
         }
+        let star_bam_writer_threads = star_threads.div_ceil(2).clamp(1, 16);
+        return crate::align::star::try_execute_star_rs(
+            &job,
+            &aligner.path_genome,
+            star_bam_writer_threads,
+            star_threads,
+            Arc::clone(&rayon_pool),
+            solo,
+        );
+    }
 
-        #[cfg(feature = "minimap2-rs-align")]
-        if self.aligner.eq_ignore_ascii_case("minimap2") {
-            return crate::align::minimap2::try_execute_minimap2(
-                self.path_in.path().path(),
-                &self.path_genome,
-                &self.path_out_unsorted,
-                &self.path_out_sorted,
-                &self.path_temp,
-                thread_allocation.write_bam,
-                budget.threads.get() as usize,
-                thread_allocation.read,
-                self.sizeof_stream_arena,
-                budget.sizeof_stream_buffer,
-                &self.minimap2_preset,
-                budget.memory,
-                budget.threads.get(),
-                Arc::clone(&rayon_pool),
-            );
-        }
+    #[cfg(feature = "minimap2-rs-align")]
+    if aligner.aligner.eq_ignore_ascii_case("minimap2") {
+        return crate::align::minimap2::try_execute_minimap2(
+            &job,
+            &aligner.path_genome,
+            budget.threads.get() as usize,
+            &aligner.minimap2_preset,
+            Arc::clone(&rayon_pool),
+        );
+    }
 
-        anyhow::bail!(
-            "aligner {} is not available; use --aligner BWAMEM2 with the Rust BWA feature, --aligner STAR with the Rust STAR feature, or --aligner minimap2 with the Rust minimap2 feature",
-            self.aligner
-        )
+    if let Some(command) = &aligner.aligner_cmd {
+        return crate::align::subprocess::try_execute_external(
+            &job,
+            command,
+            &aligner.path_genome,
+            budget.threads.get() as usize,
+        );
     }
+
+    anyhow::bail!(
+        "aligner {} is not available; use --aligner BWAMEM2 with the Rust BWA feature, --aligner STAR with the Rust STAR feature, --aligner minimap2 with the Rust minimap2 feature, or --aligner external with --aligner-cmd",
+        aligner.aligner
+    )
 }
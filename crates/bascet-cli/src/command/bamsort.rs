@@ -1,20 +1,28 @@
 //! `bam-sort` subcommand and reusable `sort_and_index_bam` API. Both delegate to the
 //! vendored `samtools_rs` module (pure-Rust port of `samtools sort` + `samtools index`).
 //!
-//! Publish flow: writes the sorted BAM and `.bai` to hidden files under `path_temp`, then
+//! The index is BAI unless a reference exceeds BAI's 512 Mbp limit, in which case it is CSI
+//! (`<bam>.csi`); `--index-format`/`--csi-*` override the choice.
+//!
+//! Publish flow: writes the sorted BAM and its index to hidden files under `path_temp`, then
 //! publishes them to their final paths on success. Spill chunks also live under `path_temp` and
 //! are deleted after a successful sort (samtools-rs handles spill-file cleanup internally).
 
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufReader, BufWriter, Cursor};
 use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, bail};
 use bytesize::ByteSize;
-use clap::Args;
+use clap::{Args, ValueEnum};
 use tracing::info;
 
 use super::determine_thread_counts_1;
+use super::samtools_rs::bam::Header;
+use super::samtools_rs::bgzf;
+use super::samtools_rs::index::{
+    BAI_MAX_REF_LEN, CSI_DEFAULT_MIN_SHIFT, csi_depth_for, max_ref_len,
+};
 use super::samtools_rs::sort::{
     EncodedBamChunk, IndexFormat, Order, ReferenceOrder, SortOptions,
     sort_encoded_record_chunk_receiver_parallel, sort_encoded_record_chunks_parallel,
@@ -29,13 +37,97 @@ pub const DEFAULT_MEMORY: &str = "8GB";
 const SORT_MEMORY_FRACTION: f64 = 0.35;
 const SORT_EXTRA_IN_FLIGHT_BUFFERS: usize = 2;
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum BamIndexKind {
+    /// BAI, or CSI if any reference is longer than 512 Mbp.
+    Auto,
+    Bai,
+    Csi,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct BamIndexArgs {
+    /// Index format written alongside the BAM (`<bam>.bai` or `<bam>.csi`).
+    #[arg(long = "index-format", value_enum, default_value_t = BamIndexKind::Auto)]
+    pub index_format: BamIndexKind,
+
+    /// CSI leaf bin size as a power of two (CSI only).
+    #[arg(
+        long = "csi-min-shift",
+        value_parser = clap::value_parser!(u8).range(1..=30),
+        default_value_t = CSI_DEFAULT_MIN_SHIFT
+    )]
+    pub csi_min_shift: u8,
+
+    /// CSI bin-tree depth (CSI only). Default: the smallest depth covering the longest reference.
+    #[arg(long = "csi-depth", value_parser = clap::value_parser!(u8).range(1..=10))]
+    pub csi_depth: Option<u8>,
+}
+
+impl Default for BamIndexArgs {
+    fn default() -> Self {
+        Self {
+            index_format: BamIndexKind::Auto,
+            csi_min_shift: CSI_DEFAULT_MIN_SHIFT,
+            csi_depth: None,
+        }
+    }
+}
+
+impl BamIndexArgs {
+    /// Pick the concrete index format for a BAM with this header.
+    pub fn resolve(&self, header: &Header) -> Result<IndexFormat> {
+        let max_len = max_ref_len(header);
+        let use_csi = match self.index_format {
+            BamIndexKind::Bai if max_len > BAI_MAX_REF_LEN => bail!(
+                "longest reference is {max_len} bp but BAI is limited to {BAI_MAX_REF_LEN} bp; \
+                use --index-format csi"
+            ),
+            BamIndexKind::Bai => false,
+            BamIndexKind::Csi => true,
+            BamIndexKind::Auto => max_len > BAI_MAX_REF_LEN,
+        };
+        if !use_csi {
+            return Ok(IndexFormat::Bai);
+        }
+
+        let min_shift = self.csi_min_shift;
+        let depth = match self.csi_depth {
+            Some(depth) => depth,
+            None => csi_depth_for(max_len, min_shift),
+        };
+        let max_addressable = (1u64 << min_shift) << (3 * depth as u32);
+        if max_len > max_addressable {
+            bail!(
+                "CSI min-shift {min_shift} with depth {depth} addresses {max_addressable} bp, \
+                but the longest reference is {max_len} bp"
+            );
+        }
+        Ok(IndexFormat::Csi { min_shift, depth })
+    }
+
+    fn resolve_for_bam(&self, path_bam: &Path) -> Result<IndexFormat> {
+        let file = File::open(path_bam)
+            .with_context(|| format!("open input BAM {}", path_bam.display()))?;
+        let header = Header::read(&mut bgzf::Reader::new(BufReader::new(file)))
+            .with_context(|| format!("read BAM header of {}", path_bam.display()))?;
+        self.resolve(&header)
+    }
+
+    fn resolve_for_header_bytes(&self, header_bytes: &[u8]) -> Result<IndexFormat> {
+        let header = Header::read(&mut Cursor::new(header_bytes))
+            .context("read BAM header of encoded chunks")?;
+        self.resolve(&header)
+    }
+}
+
 #[derive(Args)]
 pub struct BamSortCMD {
     /// Input BAM file (any sort order; will be coordinate-sorted on output).
     #[arg(short = 'i', long = "in", value_parser)]
     pub path_in: PathBuf,
 
-    /// Output BAM file (coordinate-sorted). A `.bai` index is written alongside.
+    /// Output BAM file (coordinate-sorted). A `.bai` or `.csi` index is written alongside.
     #[arg(short = 'o', long = "out", value_parser)]
     pub path_out: PathBuf,
 
@@ -54,6 +146,9 @@ pub struct BamSortCMD {
     /// Preserve the input BAM reference order instead of sorting @SQ/reference IDs by name.
     #[arg(long = "preserve-reference-order")]
     pub preserve_reference_order: bool,
+
+    #[command(flatten)]
+    pub index: BamIndexArgs,
 }
 
 impl BamSortCMD {
@@ -70,17 +165,18 @@ impl BamSortCMD {
             } else {
                 ReferenceOrder::Lexicographic
             },
+            &self.index,
         )
     }
 }
 
-/// Sort `path_in` (BAM) into `path_out_sorted` and write a BAI index alongside as
-/// `<path_out_sorted>.bai`. Both outputs are atomic-published. Used by the `bam-sort`
-/// subcommand and by every aligner's post-align flow.
+/// Sort `path_in` (BAM) into `path_out_sorted` and write an index alongside as
+/// `<path_out_sorted>.bai` or `.csi` (see `BamIndexArgs::resolve`). Both outputs are
+/// atomic-published. Used by the `bam-sort` subcommand and by every aligner's post-align flow.
 ///
 /// Backed by the vendored `samtools_rs` port. Threads + memory map directly to its
 /// `SortOptions`; the index is written by samtools-rs's `BaiBuilder` driven from
-/// `write_index = Some((bai_path_tmp, IndexFormat::Bai))`.
+/// `write_index = Some((index_path_tmp, format))`.
 pub fn sort_and_index_bam(
     path_in: &Path,
     path_out_sorted: &Path,
@@ -88,6 +184,7 @@ pub fn sort_and_index_bam(
     memory: ByteSize,
     num_threads: usize,
     reference_order: ReferenceOrder,
+    index: &BamIndexArgs,
 ) -> Result<()> {
     info!(
         input = %path_in.display(),
@@ -122,11 +219,12 @@ pub fn sort_and_index_bam(
         "BamSort: memory budget"
     );
 
-    // Publish paths for both BAM and BAI. Keep these under the job temp dir so concurrent
+    // Publish paths for both BAM and index. Keep these under the job temp dir so concurrent
     // jobs do not leave partial output in the final output directory.
     let path_out_tmp = atomic_temp_path_in_dir(path_out_sorted, path_temp);
-    let path_bai = bai_path(path_out_sorted);
-    let path_bai_tmp = atomic_temp_path_in_dir(&path_bai, path_temp);
+    let index_format = index.resolve_for_bam(path_in)?;
+    let path_index = index_path(path_out_sorted, index_format);
+    let path_index_tmp = atomic_temp_path_in_dir(&path_index, path_temp);
 
     // samtools-rs uses `<tmp_prefix>.NNNN.bam` for spill chunks; deletes them on success.
     let tmp_prefix = path_temp.join("bascet-bamsort");
@@ -140,7 +238,7 @@ pub fn sort_and_index_bam(
         max_mem: max_mem_per_chunk,
         tmp_prefix,
         threads: num_threads.max(1),
-        write_index: Some((path_bai_tmp.clone(), IndexFormat::Bai)),
+        write_index: Some((path_index_tmp.clone(), index_format)),
     };
 
     // Open input + output. Output goes to a `.tmp` and is renamed atomically.
@@ -161,10 +259,10 @@ pub fn sort_and_index_bam(
 
     // Both files exist at their `.tmp` paths now; publish atomically.
     publish_atomic_output(&path_out_tmp, &path_out_sorted.to_path_buf())?;
-    publish_atomic_output(&path_bai_tmp, &path_bai)?;
+    publish_atomic_output(&path_index_tmp, &path_index)?;
     info!(
         output = %path_out_sorted.display(),
-        index = %path_bai.display(),
+        index = %path_index.display(),
         "BamSort: complete"
     );
     Ok(())
@@ -178,6 +276,7 @@ pub fn sort_and_index_encoded_bam_chunks(
     memory: ByteSize,
     num_threads: usize,
     reference_order: ReferenceOrder,
+    index: &BamIndexArgs,
 ) -> Result<()> {
     let encoded_records: u64 = chunks.iter().map(|chunk| chunk.records).sum();
     let encoded_bytes: usize = chunks.iter().map(|chunk| chunk.data.len()).sum();
@@ -213,8 +312,9 @@ pub fn sort_and_index_encoded_bam_chunks(
     );
 
     let path_out_tmp = atomic_temp_path_in_dir(path_out_sorted, path_temp);
-    let path_bai = bai_path(path_out_sorted);
-    let path_bai_tmp = atomic_temp_path_in_dir(&path_bai, path_temp);
+    let index_format = index.resolve_for_header_bytes(&header_bytes)?;
+    let path_index = index_path(path_out_sorted, index_format);
+    let path_index_tmp = atomic_temp_path_in_dir(&path_index, path_temp);
     let tmp_prefix = path_temp.join("bascet-bamsort");
 
     let opts = SortOptions {
@@ -226,7 +326,7 @@ pub fn sort_and_index_encoded_bam_chunks(
         max_mem: max_mem_per_chunk,
         tmp_prefix,
         threads: num_threads.max(1),
-        write_index: Some((path_bai_tmp.clone(), IndexFormat::Bai)),
+        write_index: Some((path_index_tmp.clone(), index_format)),
     };
 
     let output_file = File::create(&path_out_tmp)
@@ -243,10 +343,10 @@ pub fn sort_and_index_encoded_bam_chunks(
     );
 
     publish_atomic_output(&path_out_tmp, &path_out_sorted.to_path_buf())?;
-    publish_atomic_output(&path_bai_tmp, &path_bai)?;
+    publish_atomic_output(&path_index_tmp, &path_index)?;
     info!(
         output = %path_out_sorted.display(),
-        index = %path_bai.display(),
+        index = %path_index.display(),
         "BamSort: complete"
     );
     Ok(())
@@ -260,6 +360,7 @@ pub fn sort_and_index_encoded_bam_chunk_receiver(
     memory: ByteSize,
     num_threads: usize,
     reference_order: ReferenceOrder,
+    index: &BamIndexArgs,
 ) -> Result<()> {
     info!(
         output = %path_out_sorted.display(),
@@ -290,8 +391,9 @@ pub fn sort_and_index_encoded_bam_chunk_receiver(
     );
 
     let path_out_tmp = atomic_temp_path_in_dir(path_out_sorted, path_temp);
-    let path_bai = bai_path(path_out_sorted);
-    let path_bai_tmp = atomic_temp_path_in_dir(&path_bai, path_temp);
+    let index_format = index.resolve_for_header_bytes(&header_bytes)?;
+    let path_index = index_path(path_out_sorted, index_format);
+    let path_index_tmp = atomic_temp_path_in_dir(&path_index, path_temp);
     let tmp_prefix = path_temp.join("bascet-bamsort");
 
     let opts = SortOptions {
@@ -303,7 +405,7 @@ pub fn sort_and_index_encoded_bam_chunk_receiver(
         max_mem: max_mem_per_chunk,
         tmp_prefix,
         threads: num_threads.max(1),
-        write_index: Some((path_bai_tmp.clone(), IndexFormat::Bai)),
+        write_index: Some((path_index_tmp.clone(), index_format)),
     };
 
     let output_file = File::create(&path_out_tmp)
@@ -320,17 +422,32 @@ pub fn sort_and_index_encoded_bam_chunk_receiver(
     );
 
     publish_atomic_output(&path_out_tmp, &path_out_sorted.to_path_buf())?;
-    publish_atomic_output(&path_bai_tmp, &path_bai)?;
+    publish_atomic_output(&path_index_tmp, &path_index)?;
     info!(
         output = %path_out_sorted.display(),
-        index = %path_bai.display(),
+        index = %path_index.display(),
         "BamSort: complete"
     );
     Ok(())
 }
 
-fn bai_path(bam_path: &Path) -> PathBuf {
+/// Per-chunk `SortOptions::max_mem` for a total `--memory` budget, as used by `bam-sort`.
+pub fn sort_mem_per_chunk(memory: ByteSize, num_threads: usize) -> Result<usize> {
+    let total_mem = usize::try_from(memory.as_u64())
+        .map_err(|_| anyhow::anyhow!("memory cap exceeds usize"))?;
+    let sort_mem = ((total_mem as f64) * SORT_MEMORY_FRACTION) as usize;
+    let in_flight_buffers = num_threads
+        .max(1)
+        .saturating_add(SORT_EXTRA_IN_FLIGHT_BUFFERS);
+    Ok((sort_mem / in_flight_buffers).max(64 * 1024 * 1024))
+}
+
+/// `<bam>.bai` or `<bam>.csi`.
+pub fn index_path(bam_path: &Path, format: IndexFormat) -> PathBuf {
     let mut s = bam_path.as_os_str().to_owned();
-    s.push(".bai");
+    s.push(match format {
+        IndexFormat::Bai => ".bai",
+        IndexFormat::Csi { .. } => ".csi",
+    });
     PathBuf::from(s)
 }
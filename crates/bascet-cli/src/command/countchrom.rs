@@ -1,16 +1,21 @@
 use std::fs::File;
 use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::sync::Arc;
 
 use ahash::AHashMap;
 use anyhow::Result;
+use bascet_derive::Budget;
+use bascet_runtime::budget::MemoryLimiter;
+use bounded_integer::BoundedU64;
+use bytesize::ByteSize;
 use clap::Args;
 use noodles::sam::alignment::RecordBuf as BamRecord;
 use noodles::sam::alignment::record::cigar::op::Kind as CigarKind;
 use tracing::info;
 
 use crate::fileformat::new_anndata::SparseMatrixAnnDataBuilder;
-use crate::utils::{atomic_temp_path, publish_atomic_output};
+use crate::utils::{atomic_temp_path, charge_growth, publish_atomic_output};
 
 use super::determine_thread_counts_1;
 
@@ -47,12 +52,39 @@ pub struct CountChromCMD {
     //Thread settings
     #[arg(short = '@', value_parser = clap::value_parser!(usize))]
     num_threads_total: Option<usize>,
+
+    #[arg(
+        short = 'm',
+        long = "memory",
+        help = "Total memory budget for the count matrix",
+        default_value_t = ByteSize::gib(16),
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    total_mem: ByteSize,
+}
+
+#[derive(Budget, Debug)]
+struct CountChromBudget {
+    #[threads(Total)]
+    threads: BoundedU64<1, { u64::MAX }>,
+
+    #[mem(Total)]
+    memory: ByteSize,
+
+    #[mem(MMatrix, |_, total_mem| bytesize::ByteSize(total_mem))]
+    sizeof_matrix: ByteSize,
 }
+
 impl CountChromCMD {
     /// Run the commandline option
     pub fn try_execute(&mut self) -> Result<()> {
         let num_threads_total = determine_thread_counts_1(self.num_threads_total)?;
-        info!("Using threads {}", num_threads_total);
+        let budget = CountChromBudget::builder()
+            .threads(BoundedU64::new_saturating(num_threads_total as u64))
+            .memory(self.total_mem)
+            .build();
+        budget.validate();
+        info!(using = %budget, "Using threads {}", num_threads_total);
 
         //TODO Can check that input file is sorted via header
 
@@ -63,8 +95,8 @@ impl CountChromCMD {
             min_matching: self.min_matching,
             remove_duplicates: self.remove_duplicates,
             remove_multimapper: self.remove_multimapper,
-        })
-        .unwrap();
+            matrix_limiter: budget.limiter::<MMatrix>(),
+        })?;
 
         info!("CountChrom has finished succesfully");
         Ok(())
@@ -80,11 +112,13 @@ pub struct CountChrom {
     pub min_matching: u32,
     pub remove_duplicates: bool,
     pub remove_multimapper: bool,
+    pub matrix_limiter: Arc<MemoryLimiter>,
 }
 impl CountChrom {
     /// Run the algorithm
     pub fn run(params: &CountChrom) -> anyhow::Result<()> {
         let mut cnt_mat = SparseMatrixAnnDataBuilder::new();
+        let mut matrix_permit = params.matrix_limiter.acquire(0);
 
         //Read BAM. This is a multithreaded reader already, so no need for separate threads.
         //cannot be TIRF; if we divide up reads we risk double counting
@@ -158,6 +192,11 @@ impl CountChrom {
 
                             //Clear buffers, move to the next cell
                             map_cell_count.clear();
+                            charge_growth(
+                                &mut matrix_permit,
+                                cnt_mat.sizeof_heap(),
+                                "count matrix",
+                            )?;
                         }
                     }
                     last_tid = Some(tid);
@@ -218,6 +257,7 @@ impl CountChrom {
             num_reads += 1;
             if num_reads % PROGRESS_INTERVAL_READS == 0 {
                 info!("Processed {}M reads", num_reads / 1_000_000);
+                charge_growth(&mut matrix_permit, cnt_mat.sizeof_heap(), "count matrix")?;
             }
         }
 
@@ -229,6 +269,7 @@ impl CountChrom {
             //Only empty the first loop
             let feature_index = cnt_mat.get_or_create_feature(&ref_names[tid]);
             cnt_mat.add_cell_counts_per_cell_index_ahash(feature_index, &mut map_cell_count);
+            charge_growth(&mut matrix_permit, cnt_mat.sizeof_heap(), "count matrix")?;
         }
 
         //Store unclassified counts
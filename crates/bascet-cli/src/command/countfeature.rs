@@ -1,3 +1,7 @@
+use bascet_derive::Budget;
+use bascet_runtime::budget::{MemoryLimiter, MemoryPermit};
+use bounded_integer::BoundedU64;
+use bytesize::ByteSize;
 use clap::Args;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
@@ -15,7 +19,7 @@ use noodles::gff::feature::record::Strand;
 use super::determine_thread_counts_1;
 use crate::fileformat::new_anndata::SparseMatrixAnnDataWriter;
 use crate::umi::umi_dedup::UMIcounter;
-use crate::utils::{atomic_temp_path, publish_atomic_output};
+use crate::utils::{atomic_temp_path, charge_growth, publish_atomic_output};
 
 use sprs::{CsMat, TriMat};
 
@@ -60,11 +64,38 @@ pub struct CountFeatureCMD {
     //Thread settings
     #[arg(short = '@', value_parser = clap::value_parser!(usize))]
     num_threads_total: Option<usize>,
+
+    #[arg(
+        short = 'm',
+        long = "memory",
+        help = "Total memory budget for the count matrix",
+        default_value_t = ByteSize::gib(16),
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    total_mem: ByteSize,
 }
+
+#[derive(Budget, Debug)]
+struct CountFeatureBudget {
+    #[threads(Total)]
+    threads: BoundedU64<1, { u64::MAX }>,
+
+    #[mem(Total)]
+    memory: ByteSize,
+
+    #[mem(MMatrix, |_, total_mem| bytesize::ByteSize(total_mem))]
+    sizeof_matrix: ByteSize,
+}
+
 impl CountFeatureCMD {
     pub fn try_execute(&mut self) -> anyhow::Result<()> {
         let num_threads_total = determine_thread_counts_1(self.num_threads_total)?;
-        info!("Using threads {}", num_threads_total);
+        let budget = CountFeatureBudget::builder()
+            .threads(BoundedU64::new_saturating(num_threads_total as u64))
+            .memory(self.total_mem)
+            .build();
+        budget.validate();
+        info!(using = %budget, "Using threads {}", num_threads_total);
 
         //TODO Can check that input file is sorted via header
 
@@ -80,6 +111,7 @@ impl CountFeatureCMD {
             self.path_out.clone(),
             gff_settings,
             num_threads_total,
+            budget.limiter::<MMatrix>(),
         )?;
 
         info!("CountFeature has finished succesfully");
@@ -187,6 +219,25 @@ struct CurrentCounterState {
     processed_features: u64,
     num_features: usize,
     current_cellintmapping: Arc<CellIntMapping>,
+    /// Charge for finished_genes and the cell IDs; grown as genes finish
+    matrix_permit: MemoryPermit,
+    sizeof_matrix: usize,
+    /// First failure of a counter thread; the remaining features are skipped once set
+    error: Option<anyhow::Error>,
+}
+
+/// Rough heap cost of a new cell ID beyond its bytes, which are held twice: in the map and the list
+const SIZEOF_CELL_ENTRY: usize = 48;
+
+///
+/// Approximate heap size of one entry of `finished_genes`
+///
+fn sizeof_finished_gene(meta: &GeneMeta, counts: &[(u32, u32)]) -> usize {
+    std::mem::size_of::<(GeneMeta, Vec<(u32, u32)>)>()
+        + meta.gene_chr.len()
+        + meta.gene_id.len()
+        + meta.gene_name.len()
+        + std::mem::size_of_val(counts)
 }
 
 ///
@@ -203,16 +254,23 @@ impl CountFeature {
         path_out: PathBuf,
         gff_settings: GFFparseSettings,
         num_threads: usize,
+        matrix_limiter: Arc<MemoryLimiter>,
     ) -> anyhow::Result<()> {
         //Check that the input file is present to give a nicer error message before threads start
         if !path_in.exists() {
             anyhow::bail!(format!("Input BAM does not exist: {:?}", path_in));
         }
-        let mut path_bam_index = path_in.to_string_lossy().to_string();
-        path_bam_index.push_str(".bai");
-        let path_bam_index = PathBuf::from(path_bam_index);
-        if !path_bam_index.exists() {
-            anyhow::bail!(format!("Input BAI does not exist: {:?}", path_bam_index));
+        //BAMs with references over 512 Mbp are indexed as CSI instead of BAI
+        let has_index = ["bai", "csi"].iter().any(|ext| {
+            let mut path_bam_index = path_in.as_os_str().to_owned();
+            path_bam_index.push(format!(".{ext}"));
+            PathBuf::from(path_bam_index).exists()
+        });
+        if !has_index {
+            anyhow::bail!(format!(
+                "Input BAM index (.bai or .csi) does not exist for {:?}",
+                path_in
+            ));
         }
 
         //Parse GFF-like file
@@ -226,6 +284,9 @@ impl CountFeature {
             processed_features: 0,
             num_features: gff.list_feature.len(),
             current_cellintmapping: Arc::new(CellIntMapping::new()),
+            matrix_permit: matrix_limiter.acquire(0),
+            sizeof_matrix: 0,
+            error: None,
         };
         let current_state = Arc::new(Mutex::new(current_state));
 
@@ -249,9 +310,12 @@ impl CountFeature {
                 let header = bam.read_header().unwrap();
 
                 while let Ok(Some(meta)) = rx.recv() {
-                    //Get a suitable counter
+                    //Get a suitable counter. Keep draining the queue after a failure so that the sender is not blocked
                     let current_cellintmapping = {
                         let state = current_state.lock().unwrap();
+                        if state.error.is_some() {
+                            continue;
+                        }
                         Arc::clone(&state.current_cellintmapping)
                     };
                     let mut cell_counter = CountPerCell::new(current_cellintmapping);
@@ -264,6 +328,11 @@ impl CountFeature {
                     //Put count data into matrix. To do this, we need access to the common state
                     //of the process. The operations below should thus be as fast as possible
                     let mut state = current_state.lock().unwrap();
+                    let sizeof_new_cells: usize = cell_counter
+                        .counter_other_cell
+                        .keys()
+                        .map(|cellid| 2 * cellid.len() + SIZEOF_CELL_ENTRY)
+                        .sum();
 
                     if !cell_counter.counter_other_cell.is_empty() {
                         //Need to extend common list of cells with new IDs
@@ -287,10 +356,20 @@ impl CountFeature {
                         .map(|(x, y)| (*x, *y))
                         .collect();
 
+                    let sizeof_gene = sizeof_finished_gene(&meta, &arr_counter);
                     state.finished_genes.push((meta, arr_counter));
                     state.processed_reads += cnt.processed_reads;
                     state.processed_features += 1;
 
+                    state.sizeof_matrix += sizeof_gene + sizeof_new_cells;
+                    let sizeof_matrix = state.sizeof_matrix;
+                    if let Err(e) =
+                        charge_growth(&mut state.matrix_permit, sizeof_matrix, "count matrix")
+                    {
+                        state.error = Some(e);
+                        continue;
+                    }
+
                     //Don't print too frequently as this need to lock screen I/O. Should possibly do this one main thread only
                     if state.processed_features % 1000 == 0 {
                         info!(
@@ -314,6 +393,9 @@ impl CountFeature {
             let _ = tx.send(None);
         }
         thread_pool_work.join();
+        if let Some(e) = current_state.lock().unwrap().error.take() {
+            return Err(e);
+        }
 
         info!("Writing count matrix");
         //        let current_state = current_state.lock().unwrap();
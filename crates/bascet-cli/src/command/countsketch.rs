@@ -6,7 +6,7 @@ use bascet_core::{
     *,
 };
 use bascet_derive::Budget;
-use bascet_io::{codec, parse, tirp};
+use bascet_io::{parse, tirp};
 
 use anyhow::Result;
 use bounded_integer::BoundedU64;
@@ -21,6 +21,7 @@ use polars_arrow::record_batch::RecordBatch;
 use std::{
     fs::File,
     io::BufWriter,
+    ops::Range,
     path::PathBuf,
     sync::{
         self, Arc,
@@ -29,7 +30,8 @@ use std::{
 };
 use tracing::{debug, info, warn};
 
-use crate::utils::{atomic_temp_path, publish_atomic_output};
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
+use crate::utils::{atomic_temp_path, parse_byte_range, publish_atomic_output};
 
 const COUNTSKETCH_MIN_STREAM_BUFFER: ByteSize = ByteSize::mib(64);
 const COUNTSKETCH_MIN_MEMORY_HEADROOM: ByteSize = ByteSize::mib(512);
@@ -41,10 +43,18 @@ pub struct CountsketchCMD {
         short = 'i',
         long = "in",
         value_delimiter = ',',
-        help = "List of input files (comma-separated). Assumed to be sorted by cell id in descending order."
+        help = "List of input files (comma-separated), or - for stdin. Assumed to be sorted by cell id in descending order."
     )]
     pub paths_in: Vec<InputPath>,
 
+    #[arg(
+        long = "byte-range",
+        help = "Only process cells whose first BBGZ block starts in this byte range of the input (START..END). Requires a single input file.",
+        value_name = "START..END",
+        value_parser = parse_byte_range,
+    )]
+    pub byte_range: Option<Range<u64>>,
+
     #[arg(
         short = 'o',
         long = "out",
@@ -157,6 +167,10 @@ fn default_countsketch_stream_buffer(total_mem: u64) -> ByteSize {
 
 impl CountsketchCMD {
     pub fn try_execute(&mut self) -> Result<()> {
+        if self.byte_range.is_some() && self.paths_in.len() != 1 {
+            anyhow::bail!("--byte-range requires exactly one input file");
+        }
+
         let budget = CountsketchBudget::builder()
             .threads(self.total_threads.unwrap_or_else(|| {
                 std::thread::available_parallelism()
@@ -181,6 +195,7 @@ impl CountsketchCMD {
 
         info!(
             input_files = self.paths_in.len(),
+            byte_range = ?self.byte_range,
             output_path = ?self.path_out,
             countsketch_size = self.countsketch_size,
             kmer_size = self.kmer_size,
@@ -213,10 +228,11 @@ impl CountsketchCMD {
         //For each input file
         for (input_idx, input) in self.paths_in.iter().enumerate() {
             // Create threads for streaming from the input file
-            let decoder = codec::BBGZDecoder::builder()
-                .with_path(input.path().path())
-                .countof_threads(budget.numof_threads_read)
-                .build();
+            let decoder = open_bbgz_decoder(
+                input.path().path(),
+                budget.numof_threads_read,
+                self.byte_range.clone(),
+            )?;
             let parser = parse::Tirp::builder().build();
 
             let mut stream = Stream::builder()
@@ -0,0 +1,359 @@
+//! Host depletion: split the read pairs of an aligned, cell-tagged BAM into a TIRP of pairs
+//! that did not align and/or a TIRP of pairs that did, keeping the cell order. With an aligner
+//! and genome the input TIRP is aligned first, so depletion against a host index is one step.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use tracing::info;
+
+use super::align::{AlignPaths, AlignResourceArgs, AlignerArgs, align_tirp};
+use super::filterbam::{AlignmentFilter, DEFAULT_MIN_MATCHING, DEFAULT_MIN_MATCHING_PERCENT};
+use super::transform_bam2tirp::validate_cell_order;
+use crate::fileformat::bam::{BAMStreamingReadPairReader, PairAlignment};
+use crate::fileformat::tirp::{BascetTIRPWriter, BascetTIRPWriterFactory, get_tbi_path_for_tirp};
+use crate::fileformat::{CellID, ConstructFromPath, ReadPair, ReadPairWriter};
+use crate::utils::{atomic_temp_path_in_dir, publish_atomic_output};
+
+pub const DEFAULT_PATH_TEMP: &str = "temp";
+
+#[derive(Args)]
+#[command(group(
+    clap::ArgGroup::new("outputs")
+        .args(["path_out_unmapped", "path_out_mapped"])
+        .required(true)
+        .multiple(true)
+))]
+pub struct DepleteCMD {
+    /// Cell-tagged BAM from `align` (the unsorted output, where mates are adjacent and cells
+    /// come in TIRP order). With --genome and --aligner, a TIRP to align first instead.
+    #[arg(short = 'i', long = "in", value_parser)]
+    pub path_in: PathBuf,
+
+    /// TIRP for read pairs where neither mate aligned, e.g. the non-host reads.
+    #[arg(long = "unmapped", value_parser)]
+    pub path_out_unmapped: Option<PathBuf>,
+
+    /// TIRP for read pairs where at least one mate aligned.
+    #[arg(long = "mapped", value_parser)]
+    pub path_out_mapped: Option<PathBuf>,
+
+    /// Temp directory for incomplete output and, when aligning, the intermediate BAMs.
+    #[arg(short = 't', long = "temp", value_parser, default_value = DEFAULT_PATH_TEMP)]
+    pub path_temp: PathBuf,
+
+    /// Minimum CIGAR M-bases for a BAM-mapped read to be considered aligned.
+    /// The default of 0 disables this absolute cutoff.
+    #[arg(long = "min-matching", value_parser, default_value_t = DEFAULT_MIN_MATCHING)]
+    pub min_matching: u32,
+
+    /// Minimum percent of read bases covered by CIGAR M operations for a BAM-mapped read
+    /// to be considered aligned. Use 0 to disable this fractional cutoff.
+    #[arg(
+        long = "min-matching-percent",
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        default_value_t = DEFAULT_MIN_MATCHING_PERCENT
+    )]
+    pub min_matching_percent: u8,
+
+    #[command(flatten)]
+    pub resources: AlignResourceArgs,
+
+    #[command(flatten)]
+    pub aligner: Option<AlignerArgs>,
+}
+
+impl DepleteCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        fs::create_dir_all(&self.path_temp)
+            .with_context(|| format!("failed to create temp dir {}", self.path_temp.display()))?;
+        let filter = AlignmentFilter {
+            min_matching: self.min_matching,
+            min_matching_percent: self.min_matching_percent,
+        };
+        let num_threads = self.resources.total_threads().get() as usize;
+
+        let Some(aligner) = &self.aligner else {
+            return self.split_bam(&self.path_in, filter, num_threads);
+        };
+
+        // NOTE:    only the unsorted BAM is read back, where mates are adjacent and cells are in
+        //          TIRP order, so the alignment skips the sort
+        let path_unsorted = self.path_temp.join("deplete.unsorted.bam");
+        align_tirp(
+            aligner,
+            &self.resources,
+            AlignPaths {
+                path_in: &self.path_in,
+                path_out_unsorted: &path_unsorted,
+                path_out_sorted: None,
+                path_temp: &self.path_temp,
+                report_outputs: false,
+            },
+            None,
+            None,
+        )?;
+
+        let result = self.split_bam(&path_unsorted, filter, num_threads);
+        let _ = fs::remove_file(&path_unsorted);
+        result
+    }
+
+    fn split_bam(
+        &self,
+        path_bam: &Path,
+        filter: AlignmentFilter,
+        num_threads: usize,
+    ) -> Result<()> {
+        let mut reader =
+            BAMStreamingReadPairReader::new_with_threads(&path_bam.to_path_buf(), num_threads)
+                .with_context(|| format!("failed to open BAM input {}", path_bam.display()))?;
+        let create = |path: &Option<PathBuf>| {
+            path.as_deref()
+                .map(|path| DepleteOutput::create(path, &self.path_temp))
+                .transpose()
+        };
+        // NOTE:    indexed by whether the pair aligned
+        let mut outputs = [
+            create(&self.path_out_unmapped)?,
+            create(&self.path_out_mapped)?,
+        ];
+
+        let mut last_cell_id: Option<Vec<u8>> = None;
+        let mut num_cells = 0_u64;
+        while let Some((cell_id, rp, alignment)) = reader.next_readpair_with_alignment()? {
+            if last_cell_id.as_deref() != Some(cell_id.as_slice()) {
+                validate_cell_order(&last_cell_id, &cell_id)?;
+                if let Some(last_cell_id) = &last_cell_id {
+                    write_cell(&mut outputs, last_cell_id)?;
+                }
+                last_cell_id = Some(cell_id);
+                num_cells += 1;
+            }
+
+            if let Some(out) = &mut outputs[is_pair_aligned(&alignment, filter) as usize] {
+                out.reads.push(rp);
+            }
+        }
+        if let Some(last_cell_id) = &last_cell_id {
+            write_cell(&mut outputs, last_cell_id)?;
+        }
+
+        let [out_unmapped, out_mapped] = outputs;
+        let num_unmapped = out_unmapped.map(DepleteOutput::finish).transpose()?;
+        let num_mapped = out_mapped.map(DepleteOutput::finish).transpose()?;
+        info!(
+            cells = num_cells,
+            unmapped_pairs = ?num_unmapped,
+            mapped_pairs = ?num_mapped,
+            "Deplete has finished succesfully"
+        );
+        Ok(())
+    }
+}
+
+///
+/// A pair counts as aligned if either mate passes the alignment filter, so only pairs where
+/// both mates failed to align are considered depleted
+///
+fn is_pair_aligned(alignment: &PairAlignment, filter: AlignmentFilter) -> bool {
+    std::iter::once(alignment.r1)
+        .chain(alignment.r2)
+        .any(|mate| mate.is_mapped && filter.passes(mate.matching_bases, mate.read_len))
+}
+
+fn write_cell(outputs: &mut [Option<DepleteOutput>], cell_id: &[u8]) -> Result<()> {
+    let cell_id: CellID = String::from_utf8(cell_id.to_vec()).with_context(|| {
+        format!(
+            "cell id in read name is not UTF-8: {:?}",
+            String::from_utf8_lossy(cell_id)
+        )
+    })?;
+    for out in outputs.iter_mut().flatten() {
+        out.write_cell(&cell_id);
+    }
+    Ok(())
+}
+
+///
+/// One output TIRP, written to the temp directory and published once complete. Read pairs
+/// are collected per cell and handed to the writer whenever the cell changes
+///
+struct DepleteOutput {
+    path_out: PathBuf,
+    path_tmp: PathBuf,
+    writer: BascetTIRPWriter,
+    reads: Vec<ReadPair>,
+    num_pairs: u64,
+}
+
+impl DepleteOutput {
+    fn create(path_out: &Path, path_temp: &Path) -> Result<Self> {
+        let path_tmp = atomic_temp_path_in_dir(path_out, path_temp);
+        let writer = BascetTIRPWriterFactory::new().new_from_path(&path_tmp)?;
+        Ok(Self {
+            path_out: path_out.to_path_buf(),
+            path_tmp,
+            writer,
+            reads: Vec::new(),
+            num_pairs: 0,
+        })
+    }
+
+    fn write_cell(&mut self, cell_id: &CellID) {
+        if self.reads.is_empty() {
+            return;
+        }
+        self.num_pairs += self.reads.len() as u64;
+        let reads = Arc::new(std::mem::take(&mut self.reads));
+        self.writer.write_reads_for_cell(cell_id, &reads);
+    }
+
+    fn finish(mut self) -> Result<u64> {
+        self.writer.writing_done()?;
+        publish_atomic_output(&self.path_tmp, &self.path_out)
+            .with_context(|| format!("failed to publish {}", self.path_out.display()))?;
+        let path_tbi = get_tbi_path_for_tirp(&self.path_out);
+        publish_atomic_output(get_tbi_path_for_tirp(&self.path_tmp), &path_tbi)
+            .with_context(|| format!("failed to publish {}", path_tbi.display()))?;
+        Ok(self.num_pairs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use clap::Parser;
+
+    use super::{AlignmentFilter, DepleteCMD, is_pair_aligned};
+    use crate::align::output::TaggedBamOutput;
+    use crate::fileformat::bam::{MateAlignment, PairAlignment};
+
+    const FILTER: AlignmentFilter = AlignmentFilter {
+        min_matching: 0,
+        min_matching_percent: 90,
+    };
+
+    fn mate(is_mapped: bool, matching_bases: u32) -> MateAlignment {
+        MateAlignment {
+            is_mapped,
+            matching_bases,
+            read_len: 100,
+        }
+    }
+
+    #[test]
+    fn pair_is_aligned_if_either_mate_aligned() {
+        let pair = |r1, r2| PairAlignment { r1, r2: Some(r2) };
+
+        assert!(is_pair_aligned(
+            &pair(mate(true, 100), mate(true, 95)),
+            FILTER
+        ));
+        assert!(is_pair_aligned(
+            &pair(mate(false, 0), mate(true, 95)),
+            FILTER
+        ));
+        assert!(is_pair_aligned(
+            &pair(mate(true, 100), mate(false, 0)),
+            FILTER
+        ));
+        assert!(!is_pair_aligned(
+            &pair(mate(false, 0), mate(false, 0)),
+            FILTER
+        ));
+    }
+
+    #[test]
+    fn partially_matching_mates_are_not_aligned() {
+        let clipped = PairAlignment {
+            r1: mate(true, 60),
+            r2: Some(mate(true, 89)),
+        };
+        let single_end = PairAlignment {
+            r1: mate(true, 60),
+            r2: None,
+        };
+
+        assert!(!is_pair_aligned(&clipped, FILTER));
+        assert!(!is_pair_aligned(&single_end, FILTER));
+        assert!(is_pair_aligned(
+            &single_end,
+            AlignmentFilter {
+                min_matching: 50,
+                ..FILTER
+            }
+        ));
+    }
+
+    #[derive(Parser)]
+    struct DepleteCli {
+        #[command(flatten)]
+        cmd: DepleteCMD,
+    }
+
+    fn read_tirp(path: &std::path::Path) -> String {
+        let mut lines = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(File::open(path).unwrap()),
+            &mut lines,
+        )
+        .unwrap();
+        lines
+    }
+
+    #[test]
+    fn splits_a_cell_tagged_bam_into_unmapped_and_mapped_tirps() {
+        let dir = tempfile::tempdir().unwrap();
+        let path_bam = dir.path().join("cells.bam");
+        // NOTE:    read names carry no cell:umi prefix, so cells can only come from the CB tags
+        let mut bam = TaggedBamOutput::new(&path_bam, 1, "test");
+        bam.record_with_cell_umi("@SQ\tSN:chr1\tLN:100", "", None)
+            .unwrap();
+        for (cell, line) in [
+            ("A", "p1\t77\t*\t0\t0\t*\t*\t0\t0\tACGT\tFFFF"),
+            ("A", "p1\t141\t*\t0\t0\t*\t*\t0\t0\tTTTT\tIIII"),
+            ("A", "p2\t89\tchr1\t1\t60\t4M\t*\t0\t0\tAACC\tABCD"),
+            ("A", "p2\t133\t*\t0\t0\t*\t*\t0\t0\tGGGG\tIIII"),
+            ("B", "p3\t77\t*\t0\t0\t*\t*\t0\t0\tCCCC\tFFFF"),
+            ("B", "p3\t141\t*\t0\t0\t*\t*\t0\t0\tGGGG\tIIII"),
+            ("C", "p4\t0\tchr1\t5\t60\t4M\t*\t0\t0\tGATT\tFFFF"),
+        ] {
+            bam.record_with_cell_umi(line, cell, Some("U1")).unwrap();
+        }
+        bam.finish().unwrap();
+
+        let path_unmapped = dir.path().join("unmapped.tirp.gz");
+        let path_mapped = dir.path().join("mapped.tirp.gz");
+        let path_temp = dir.path().join("temp");
+        let mut cli = DepleteCli::try_parse_from([
+            "deplete",
+            "-i",
+            path_bam.to_str().unwrap(),
+            "--unmapped",
+            path_unmapped.to_str().unwrap(),
+            "--mapped",
+            path_mapped.to_str().unwrap(),
+            "--temp",
+            path_temp.to_str().unwrap(),
+            "--threads",
+            "2",
+        ])
+        .unwrap();
+        cli.cmd.try_execute().unwrap();
+
+        assert_eq!(
+            read_tirp(&path_unmapped),
+            "A\t1\t1\tACGT\tTTTT\tFFFF\tIIII\tU1\nB\t1\t1\tCCCC\tGGGG\tFFFF\tIIII\tU1\n"
+        );
+        // NOTE:    the reverse-strand R1 is restored to its sequenced orientation
+        assert_eq!(
+            read_tirp(&path_mapped),
+            "A\t1\t1\tGGTT\tGGGG\tDCBA\tIIII\tU1\nC\t1\t1\tGATT\t\tFFFF\t\tU1\n"
+        );
+    }
+}
@@ -17,6 +17,7 @@ use std::sync::Arc;
 use std::thread;
 use tracing::info;
 
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
 use crate::fileformat::new_anndata::SparseMatrixAnnDataBuilder;
 use crate::fileformat::{DetectedFileformat, detect_shard_format};
 use crate::utils::{atomic_temp_path, publish_atomic_output};
@@ -226,10 +227,7 @@ fn reader_loop(
 ) -> Result<()> {
     let num_threads = bounded_integer::BoundedU64::new(num_threads_read.max(1) as u64)
         .context("invalid read thread count")?;
-    let decoder: bascet_io::BBGZDecoder = bascet_io::codec::BBGZDecoder::builder()
-        .with_path(&path_in)
-        .countof_threads(num_threads)
-        .build();
+    let decoder: bascet_io::BBGZDecoder = open_bbgz_decoder(&path_in, num_threads, None)?;
     let parser = bascet_io::parse::Tirp::builder().build();
     let mut stream = bascet_core::Stream::builder()
         .with_decoder(decoder)
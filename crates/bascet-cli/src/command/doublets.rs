@@ -0,0 +1,518 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use sprs::{CsMat, TriMat};
+use tracing::{info, warn};
+
+use crate::fileformat::new_anndata::{SparseMatrixAnnDataReader, SparseMatrixAnnDataWriter};
+use crate::utils::{atomic_temp_path, publish_atomic_output};
+
+const DEFAULT_GENOME_DELIMITER: &str = "_";
+
+#[derive(Args)]
+pub struct DoubletsCMD {
+    /// Input h5ad from countchrom (cells x reference sequences).
+    #[arg(short = 'i', value_parser = clap::value_parser!(PathBuf))]
+    pub path_in: PathBuf,
+
+    /// Output h5ad with cells x genomes counts in X and the classification in obs.
+    #[arg(short = 'o', value_parser = clap::value_parser!(PathBuf))]
+    pub path_out: PathBuf,
+
+    /// TSV of reference<TAB>genome, grouping the sequences of a concatenated multi-species
+    /// index. References not listed are ignored.
+    #[arg(long = "genome-map", value_parser = clap::value_parser!(PathBuf))]
+    pub path_genome_map: Option<PathBuf>,
+
+    /// Without --genome-map, the genome is the part of the reference name before this
+    /// delimiter (e.g. hg38_chr1 -> hg38). References without the delimiter are ignored.
+    #[arg(long = "genome-delimiter", default_value = DEFAULT_GENOME_DELIMITER)]
+    pub genome_delimiter: String,
+
+    /// Optional cell list (one per line) of the cells classified as singlets.
+    #[arg(long = "cells-out", value_parser = clap::value_parser!(PathBuf))]
+    pub path_cells_out: Option<PathBuf>,
+
+    /// Optional TSV with the estimated multiplet rates.
+    #[arg(long = "summary-out", value_parser = clap::value_parser!(PathBuf))]
+    pub path_summary_out: Option<PathBuf>,
+
+    /// Cells with fewer mapped reads are classified as low quality.
+    #[arg(long = "min-reads", default_value_t = 100)]
+    pub min_reads: u64,
+
+    /// Minimum fraction of reads from the top genome for a singlet.
+    #[arg(long = "singlet-fraction", default_value_t = 0.9)]
+    pub singlet_fraction: f64,
+
+    /// Minimum fraction of reads from the second genome for a mixed doublet.
+    #[arg(long = "doublet-fraction", default_value_t = 0.2)]
+    pub doublet_fraction: f64,
+}
+
+impl DoubletsCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        self.validate()?;
+        let thresholds = Thresholds {
+            min_reads: self.min_reads,
+            singlet_fraction: self.singlet_fraction,
+            doublet_fraction: self.doublet_fraction,
+        };
+
+        let reader = SparseMatrixAnnDataReader::open_anndata(&self.path_in)
+            .with_context(|| format!("failed to open {}", self.path_in.display()))?;
+        let cell_names = reader.read_cell_names()?;
+        let reference_names = reader.read_feature_names()?;
+        let counts = reader.read_sparse_count_matrix(cell_names.len(), reference_names.len())?;
+        info!(
+            "read counts for {} cells over {} reference sequences",
+            cell_names.len(),
+            reference_names.len()
+        );
+
+        let grouping = match &self.path_genome_map {
+            Some(path_genome_map) => GenomeGrouping::from_map_file(path_genome_map)?,
+            None => GenomeGrouping::Delimiter(self.genome_delimiter.clone()),
+        };
+        let (genomes, genome_counts) = group_by_genome(&counts, &reference_names, &grouping);
+        if genomes.len() < 2 {
+            bail!(
+                "found {} genome(s); species mixing needs at least 2 (check --genome-map or --genome-delimiter)",
+                genomes.len()
+            );
+        }
+        info!("grouped references into genomes {:?}", genomes);
+
+        let calls: Vec<CellCall> = genome_counts
+            .iter()
+            .map(|counts| classify_cell(counts, &thresholds))
+            .collect();
+        let rates = MultipletRates::estimate(&calls, genomes.len());
+        info!(
+            "singlets {}, mixed doublets {}, low quality {}",
+            rates.num_singlets, rates.num_mixed, rates.num_low_quality
+        );
+        info!(
+            "mixed doublet rate {:.4}, estimated multiplet rate {:.4} (of which same-species {:.4})",
+            rates.mixed_rate, rates.multiplet_rate, rates.same_species_rate
+        );
+
+        write_anndata(
+            &self.path_out,
+            &cell_names,
+            &genomes,
+            &genome_counts,
+            &calls,
+        )?;
+        if let Some(path_cells_out) = &self.path_cells_out {
+            write_singlet_list(path_cells_out, &cell_names, &calls)?;
+        }
+        if let Some(path_summary_out) = &self.path_summary_out {
+            write_summary(path_summary_out, &rates)?;
+        }
+
+        info!("Doublets has finished succesfully");
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(0.5..=1.0).contains(&self.singlet_fraction) {
+            bail!("--singlet-fraction must be between 0.5 and 1");
+        }
+        if !(0.0..=0.5).contains(&self.doublet_fraction) {
+            bail!("--doublet-fraction must be between 0 and 0.5");
+        }
+        if self.path_genome_map.is_none() && self.genome_delimiter.is_empty() {
+            bail!("--genome-delimiter must not be empty");
+        }
+        Ok(())
+    }
+}
+
+enum GenomeGrouping {
+    Map(BTreeMap<String, String>),
+    Delimiter(String),
+}
+
+impl GenomeGrouping {
+    fn from_map_file(path: &PathBuf) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open genome map {}", path.display()))?;
+        let mut map = BTreeMap::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Some((reference, genome)) = line.split_once('\t') else {
+                bail!(
+                    "{}:{}: expected reference<TAB>genome",
+                    path.display(),
+                    i + 1
+                );
+            };
+            map.insert(reference.to_string(), genome.trim().to_string());
+        }
+        Ok(GenomeGrouping::Map(map))
+    }
+
+    fn genome_of<'a>(&'a self, reference: &'a str) -> Option<&'a str> {
+        match self {
+            GenomeGrouping::Map(map) => map.get(reference).map(String::as_str),
+            GenomeGrouping::Delimiter(delimiter) => reference
+                .split_once(delimiter.as_str())
+                .map(|(genome, _)| genome),
+        }
+    }
+}
+
+///
+/// Sum counts per genome. Returns the sorted genome names and, per cell, counts per genome
+///
+fn group_by_genome(
+    counts: &CsMat<u32>,
+    reference_names: &[String],
+    grouping: &GenomeGrouping,
+) -> (Vec<String>, Vec<Vec<u64>>) {
+    let mut genome_index: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut ungrouped = Vec::new();
+    for reference in reference_names {
+        match grouping.genome_of(reference) {
+            Some(genome) => {
+                genome_index.insert(genome, 0);
+            }
+            None => ungrouped.push(reference.as_str()),
+        }
+    }
+    if !ungrouped.is_empty() {
+        let reason = match grouping {
+            GenomeGrouping::Map(_) => "are not in the genome map".to_string(),
+            GenomeGrouping::Delimiter(delimiter) => {
+                format!("have no genome delimiter {:?} in their name", delimiter)
+            }
+        };
+        warn!(
+            "{} reference sequences {} and are ignored, e.g. {:?}",
+            ungrouped.len(),
+            reason,
+            &ungrouped[..ungrouped.len().min(5)]
+        );
+    }
+    for (i, index) in genome_index.values_mut().enumerate() {
+        *index = i;
+    }
+
+    let reference_to_genome: Vec<Option<usize>> = reference_names
+        .iter()
+        .map(|reference| grouping.genome_of(reference).map(|g| genome_index[g]))
+        .collect();
+
+    let mut genome_counts = vec![vec![0u64; genome_index.len()]; counts.rows()];
+    for (cell, row) in counts.outer_iterator().enumerate() {
+        for (reference, &count) in row.iter() {
+            if let Some(genome) = reference_to_genome[reference] {
+                genome_counts[cell][genome] += count as u64;
+            }
+        }
+    }
+
+    let genomes = genome_index.into_keys().map(str::to_string).collect();
+    (genomes, genome_counts)
+}
+
+struct Thresholds {
+    min_reads: u64,
+    singlet_fraction: f64,
+    doublet_fraction: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CellClass {
+    Singlet(usize),
+    MixedDoublet(usize, usize),
+    LowQuality,
+}
+
+impl CellClass {
+    fn label(&self) -> &'static str {
+        match self {
+            CellClass::Singlet(_) => "singlet",
+            CellClass::MixedDoublet(_, _) => "mixed_doublet",
+            CellClass::LowQuality => "low_quality",
+        }
+    }
+}
+
+struct CellCall {
+    class: CellClass,
+    total: u64,
+    top_fraction: f64,
+}
+
+fn classify_cell(counts: &[u64], thresholds: &Thresholds) -> CellCall {
+    let total: u64 = counts.iter().sum();
+    let mut order: Vec<usize> = (0..counts.len()).collect();
+    order.sort_by(|a, b| counts[*b].cmp(&counts[*a]).then(a.cmp(b)));
+    let (top, second) = (order[0], order[1]);
+
+    if total == 0 {
+        return CellCall {
+            class: CellClass::LowQuality,
+            total,
+            top_fraction: 0.0,
+        };
+    }
+    let top_fraction = counts[top] as f64 / total as f64;
+    let second_fraction = counts[second] as f64 / total as f64;
+
+    let class = if total < thresholds.min_reads {
+        CellClass::LowQuality
+    } else if top_fraction >= thresholds.singlet_fraction {
+        CellClass::Singlet(top)
+    } else if second_fraction >= thresholds.doublet_fraction {
+        CellClass::MixedDoublet(top, second)
+    } else {
+        CellClass::LowQuality
+    };
+    CellCall {
+        class,
+        total,
+        top_fraction,
+    }
+}
+
+///
+/// Multiplet rate from the observed mixed doublets. A doublet of two random cells is mixed
+/// with probability 1 - sum(p_g^2), p_g being the genome proportions among singlets
+///
+#[derive(Debug)]
+struct MultipletRates {
+    num_singlets: usize,
+    num_mixed: usize,
+    num_low_quality: usize,
+    mixed_rate: f64,
+    multiplet_rate: f64,
+    same_species_rate: f64,
+}
+
+impl MultipletRates {
+    fn estimate(calls: &[CellCall], num_genomes: usize) -> Self {
+        let mut singlets_per_genome = vec![0usize; num_genomes];
+        let mut num_mixed = 0;
+        let mut num_low_quality = 0;
+        for call in calls {
+            match call.class {
+                CellClass::Singlet(genome) => singlets_per_genome[genome] += 1,
+                CellClass::MixedDoublet(_, _) => num_mixed += 1,
+                CellClass::LowQuality => num_low_quality += 1,
+            }
+        }
+        let num_singlets: usize = singlets_per_genome.iter().sum();
+        let num_called = num_singlets + num_mixed;
+
+        let mixed_rate = if num_called > 0 {
+            num_mixed as f64 / num_called as f64
+        } else {
+            f64::NAN
+        };
+        let prob_mixed = if num_singlets > 0 {
+            1.0 - singlets_per_genome
+                .iter()
+                .map(|&n| (n as f64 / num_singlets as f64).powi(2))
+                .sum::<f64>()
+        } else {
+            0.0
+        };
+        let multiplet_rate = if prob_mixed > 0.0 {
+            (mixed_rate / prob_mixed).min(1.0)
+        } else {
+            f64::NAN
+        };
+
+        MultipletRates {
+            num_singlets,
+            num_mixed,
+            num_low_quality,
+            mixed_rate,
+            multiplet_rate,
+            same_species_rate: multiplet_rate - mixed_rate,
+        }
+    }
+}
+
+fn write_anndata(
+    path_out: &PathBuf,
+    cell_names: &Vec<String>,
+    genomes: &Vec<String>,
+    genome_counts: &[Vec<u64>],
+    calls: &[CellCall],
+) -> Result<()> {
+    let n_rows = cell_names.len();
+    let n_cols = genomes.len();
+    let mut trimat = TriMat::new((n_rows, n_cols));
+    for (cell, counts) in genome_counts.iter().enumerate() {
+        for (genome, &count) in counts.iter().enumerate() {
+            if count > 0 {
+                trimat.add_triplet(cell, genome, u32::try_from(count).unwrap_or(u32::MAX));
+            }
+        }
+    }
+    let csr_mat: CsMat<u32> = trimat.to_csr();
+
+    let mut columns: Vec<(String, Vec<f64>)> = vec![
+        (
+            "mapped_reads".to_string(),
+            calls.iter().map(|call| call.total as f64).collect(),
+        ),
+        (
+            "top_genome_fraction".to_string(),
+            calls.iter().map(|call| call.top_fraction).collect(),
+        ),
+    ];
+    for (genome_idx, genome) in genomes.iter().enumerate() {
+        columns.push((
+            format!("frac_{}", genome),
+            genome_counts
+                .iter()
+                .zip(calls)
+                .map(|(counts, call)| match call.total {
+                    0 => 0.0,
+                    total => counts[genome_idx] as f64 / total as f64,
+                })
+                .collect(),
+        ));
+    }
+    let columns: Vec<(&str, Vec<f64>)> = columns
+        .iter()
+        .map(|(name, values)| (name.as_str(), values.clone()))
+        .collect();
+
+    let string_columns: [(&str, Vec<String>); 2] = [
+        (
+            "doublet_class",
+            calls
+                .iter()
+                .map(|call| call.class.label().to_string())
+                .collect(),
+        ),
+        (
+            "genome",
+            calls
+                .iter()
+                .map(|call| match call.class {
+                    CellClass::Singlet(g) => genomes[g].clone(),
+                    CellClass::MixedDoublet(a, b) => format!("{}+{}", genomes[a], genomes[b]),
+                    CellClass::LowQuality => String::new(),
+                })
+                .collect(),
+        ),
+    ];
+
+    let path_tmp = atomic_temp_path(path_out);
+    let mut file = SparseMatrixAnnDataWriter::create_anndata(&path_tmp)?;
+    file.store_sparse_count_matrix(&csr_mat, n_rows as u32, n_cols as u32)?;
+    file.store_feature_names(genomes)?;
+    file.store_cell_obs(cell_names, &columns, &string_columns)?;
+    file.close()?;
+    publish_atomic_output(&path_tmp, path_out)?;
+    info!("wrote doublet calls for {} cells", n_rows);
+    Ok(())
+}
+
+fn write_singlet_list(path_out: &PathBuf, cell_names: &[String], calls: &[CellCall]) -> Result<()> {
+    let path_tmp = atomic_temp_path(path_out);
+    let mut writer = BufWriter::new(File::create(&path_tmp)?);
+    for (cell, call) in cell_names.iter().zip(calls) {
+        if let CellClass::Singlet(_) = call.class {
+            writeln!(writer, "{}", cell)?;
+        }
+    }
+    writer.flush()?;
+    drop(writer);
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
+
+fn write_summary(path_out: &PathBuf, rates: &MultipletRates) -> Result<()> {
+    let path_tmp = atomic_temp_path(path_out);
+    let mut writer = BufWriter::new(File::create(&path_tmp)?);
+    writeln!(writer, "metric\tvalue")?;
+    writeln!(writer, "singlets\t{}", rates.num_singlets)?;
+    writeln!(writer, "mixed_doublets\t{}", rates.num_mixed)?;
+    writeln!(writer, "low_quality\t{}", rates.num_low_quality)?;
+    writeln!(writer, "mixed_doublet_rate\t{}", rates.mixed_rate)?;
+    writeln!(writer, "multiplet_rate\t{}", rates.multiplet_rate)?;
+    writeln!(
+        writer,
+        "same_species_multiplet_rate\t{}",
+        rates.same_species_rate
+    )?;
+    writer.flush()?;
+    drop(writer);
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn thresholds() -> Thresholds {
+        Thresholds {
+            min_reads: 100,
+            singlet_fraction: 0.9,
+            doublet_fraction: 0.2,
+        }
+    }
+
+    #[test]
+    fn classifies_singlets_doublets_and_low_quality() {
+        let t = thresholds();
+        assert_eq!(classify_cell(&[950, 50], &t).class, CellClass::Singlet(0));
+        assert_eq!(classify_cell(&[40, 960], &t).class, CellClass::Singlet(1));
+        assert_eq!(
+            classify_cell(&[400, 600], &t).class,
+            CellClass::MixedDoublet(1, 0)
+        );
+        assert_eq!(classify_cell(&[85, 15, 0], &t).class, CellClass::LowQuality);
+        assert_eq!(
+            classify_cell(&[850, 100, 50], &t).class,
+            CellClass::LowQuality
+        );
+        let empty = classify_cell(&[0, 0], &t);
+        assert_eq!(empty.class, CellClass::LowQuality);
+        assert_eq!(empty.top_fraction, 0.0);
+    }
+
+    #[test]
+    fn multiplet_rate_accounts_for_same_species_doublets() {
+        let t = thresholds();
+        //Equal mix of two species: half of all doublets are mixed
+        let mut calls = Vec::new();
+        for _ in 0..45 {
+            calls.push(classify_cell(&[1000, 0], &t));
+            calls.push(classify_cell(&[0, 1000], &t));
+        }
+        for _ in 0..10 {
+            calls.push(classify_cell(&[500, 500], &t));
+        }
+        let rates = MultipletRates::estimate(&calls, 2);
+        assert_eq!(rates.num_singlets, 90);
+        assert_eq!(rates.num_mixed, 10);
+        assert!((rates.mixed_rate - 0.1).abs() < 1e-9);
+        assert!((rates.multiplet_rate - 0.2).abs() < 1e-9);
+        assert!((rates.same_species_rate - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn genome_from_reference_prefix() {
+        let grouping = GenomeGrouping::Delimiter("_".to_string());
+        assert_eq!(grouping.genome_of("hg38_chr1"), Some("hg38"));
+        assert_eq!(grouping.genome_of("mm10_chrX_random"), Some("mm10"));
+        assert_eq!(grouping.genome_of("phix"), None);
+    }
+}
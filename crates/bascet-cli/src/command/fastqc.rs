@@ -16,8 +16,9 @@ use fastqc_rs::{config::FastQCConfig, sequence::Sequence};
 use tracing::{info, warn};
 use zip::ZipWriter;
 
+use super::fastqc_summary::{CellFastqcSummary, FastqcReportSummary, write_summary};
 use crate::{
-    fileformat::ReadPair,
+    fileformat::{ReadPair, bbgz_input::open_bbgz_decoder},
     utils::{atomic_temp_path, publish_atomic_output},
 };
 
@@ -33,6 +34,11 @@ pub struct FastqcCMD {
     #[arg(short = 'o', value_parser = clap::value_parser!(PathBuf))]
     pub path_out: PathBuf,
 
+    /// Also write a per-cell summary of module statuses and key metrics: TSV, or h5ad obs if
+    /// it ends in `.h5ad` (see `fastqc-summary`).
+    #[arg(long = "summary", value_parser = clap::value_parser!(PathBuf))]
+    pub path_summary: Option<PathBuf>,
+
     /// Total thread budget. One or more threads are used for TIRP reading; the rest process cells.
     #[arg(short = 't', long = "threads")]
     pub threads: Option<usize>,
@@ -105,6 +111,7 @@ impl FastqcCMD {
         run_fastqc_cells(
             self.path_in.clone(),
             self.path_out.clone(),
+            self.path_summary.clone(),
             self.num_threads_read,
             fastqc_workers,
             self.max_reads_per_cell,
@@ -189,6 +196,7 @@ struct CellFastqc {
 fn run_fastqc_cells(
     path_in: PathBuf,
     path_out: PathBuf,
+    path_summary: Option<PathBuf>,
     num_threads_read: usize,
     fastqc_workers: usize,
     max_reads_per_cell: usize,
@@ -234,7 +242,7 @@ fn run_fastqc_cells(
     drop(rx_cells);
     drop(tx_reports);
 
-    let writer = thread::spawn(move || write_zip(path_out, rx_reports));
+    let writer = thread::spawn(move || write_zip(path_out, path_summary, rx_reports));
 
     reader
         .join()
@@ -264,10 +272,7 @@ fn stream_tirp_cells(
     info!("Streaming TIRP input {}", path_in.display());
     let num_threads = bounded_integer::BoundedU64::new(num_threads_read as u64)
         .context("invalid read thread count")?;
-    let decoder: bascet_io::BBGZDecoder = bascet_io::codec::BBGZDecoder::builder()
-        .with_path(&path_in)
-        .countof_threads(num_threads)
-        .build();
+    let decoder: bascet_io::BBGZDecoder = open_bbgz_decoder(&path_in, num_threads, None)?;
     let parser = bascet_io::parse::Tirp::builder().build();
 
     let mut stream = bascet_core::Stream::builder()
@@ -437,7 +442,11 @@ fn fastqc_data_report(
     ))
 }
 
-fn write_zip(path_out: PathBuf, rx_reports: Receiver<Result<CellFastqc>>) -> Result<()> {
+fn write_zip(
+    path_out: PathBuf,
+    path_summary: Option<PathBuf>,
+    rx_reports: Receiver<Result<CellFastqc>>,
+) -> Result<()> {
     let path_tmp = atomic_temp_path(&path_out);
     let file = File::create(&path_tmp)
         .with_context(|| format!("failed to create output zip {}", path_tmp.display()))?;
@@ -446,8 +455,12 @@ fn write_zip(path_out: PathBuf, rx_reports: Receiver<Result<CellFastqc>>) -> Res
         zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
 
     let mut num_cells = 0_u64;
+    let mut summaries = Vec::new();
     for report in rx_reports {
         let report = report?;
+        if path_summary.is_some() {
+            summaries.push(summarise_cell(&report)?);
+        }
         let entry_name = format!("{}/r1_fastqc_data.txt", report.cell_id);
         zip_writer.start_file(entry_name, options)?;
         let mut r1_report = report.r1_report.as_slice();
@@ -467,9 +480,29 @@ fn write_zip(path_out: PathBuf, rx_reports: Receiver<Result<CellFastqc>>) -> Res
     zip_writer.finish()?;
     publish_atomic_output(&path_tmp, &path_out)?;
     info!("wrote fastqc output for final total of {} cells", num_cells);
+
+    if let Some(path_summary) = path_summary {
+        summaries.sort_unstable_by(|a, b| a.cell_id.cmp(&b.cell_id));
+        write_summary(&path_summary, &summaries)?;
+        info!("wrote fastqc summary to {}", path_summary.display());
+    }
     Ok(())
 }
 
+fn summarise_cell(report: &CellFastqc) -> Result<CellFastqcSummary> {
+    let parse = |text: &[u8]| {
+        FastqcReportSummary::parse(&String::from_utf8_lossy(text))
+            .with_context(|| format!("failed to summarise fastqc report of {}", report.cell_id))
+    };
+    Ok(CellFastqcSummary {
+        cell_id: report.cell_id.clone(),
+        mates: [
+            Some(parse(&report.r1_report)?),
+            Some(parse(&report.r2_report)?),
+        ],
+    })
+}
+
 fn validate_zip_cell_id(cell_id: &str) -> Result<()> {
     if cell_id.is_empty() {
         bail!("empty cell id is not supported");
@@ -0,0 +1,421 @@
+//! `fastqc-summary` subcommand: condense the per-cell reports of a `fastqc` zip into one table.
+//!
+//! Each `<cell>/r{1,2}_fastqc_data.txt` entry is reduced to its module PASS/WARN/FAIL statuses
+//! and a few numeric metrics (total sequences, %GC, per-base quality medians, GC deviation from
+//! a fitted normal, overrepresented sequence fraction and duplication level), one row per cell.
+//! Output is a TSV, or an h5ad with empty X and the summary in obs when the output path ends in
+//! `.h5ad`. `fastqc --summary` writes the same table directly.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use tracing::info;
+use zip::ZipArchive;
+
+use crate::fileformat::new_anndata::SparseMatrixAnnDataWriter;
+use crate::utils::{atomic_temp_path, publish_atomic_output};
+
+/// Mates as named in the zip entries of `fastqc`
+pub const FASTQC_MATES: [&str; 2] = ["r1", "r2"];
+
+// Indices into `FastqcReportSummary::metrics`
+const TOTAL_SEQUENCES: usize = 0;
+const GC_PERCENT: usize = 1;
+const QUALITY_MEDIAN_MIN: usize = 2;
+const QUALITY_MEDIAN_MEAN: usize = 3;
+const GC_DEVIATION: usize = 4;
+const OVERREPRESENTED: usize = 5;
+const DUPLICATION: usize = 6;
+
+const NUMERIC_METRICS: [&str; 7] = [
+    "total_sequences",
+    "gc_percent",
+    "per_base_quality_median_min",
+    "per_base_quality_median_mean",
+    "gc_deviation_percent",
+    "overrepresented_fraction",
+    "duplication_percent",
+];
+
+#[derive(Args)]
+pub struct FastqcSummaryCMD {
+    /// Input zip written by `bascet fastqc`.
+    #[arg(short = 'i', value_parser = clap::value_parser!(PathBuf))]
+    pub path_in: PathBuf,
+
+    /// Output file: TSV, or h5ad (empty X, summary in obs) if it ends in `.h5ad`.
+    #[arg(short = 'o', value_parser = clap::value_parser!(PathBuf))]
+    pub path_out: PathBuf,
+}
+
+impl FastqcSummaryCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        let file = File::open(&self.path_in)
+            .with_context(|| format!("failed to open {}", self.path_in.display()))?;
+        let mut archive = ZipArchive::new(BufReader::new(file))
+            .with_context(|| format!("{} is not a zip file", self.path_in.display()))?;
+
+        let mut cells: BTreeMap<String, CellFastqcSummary> = BTreeMap::new();
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let entry_name = entry.name().to_string();
+            let Some((cell_id, file_name)) = entry_name.split_once('/') else {
+                continue;
+            };
+            let Some(mate) = FASTQC_MATES
+                .iter()
+                .position(|mate| file_name == format!("{mate}_fastqc_data.txt"))
+            else {
+                continue;
+            };
+            let cell_id = cell_id.to_string();
+            let mut text = String::new();
+            entry
+                .read_to_string(&mut text)
+                .with_context(|| format!("failed to read {entry_name}"))?;
+            let summary = FastqcReportSummary::parse(&text)
+                .with_context(|| format!("failed to parse {entry_name}"))?;
+            cells
+                .entry(cell_id.clone())
+                .or_insert_with(|| CellFastqcSummary {
+                    cell_id,
+                    mates: Default::default(),
+                })
+                .mates[mate] = Some(summary);
+        }
+        if cells.is_empty() {
+            bail!("no FastQC reports found in {}", self.path_in.display());
+        }
+
+        let cells: Vec<CellFastqcSummary> = cells.into_values().collect();
+        write_summary(&self.path_out, &cells)?;
+        info!(
+            "wrote FastQC summary for {} cells to {}",
+            cells.len(),
+            self.path_out.display()
+        );
+        Ok(())
+    }
+}
+
+/// Summary of the R1 and R2 reports of one cell
+pub struct CellFastqcSummary {
+    pub cell_id: String,
+    pub mates: [Option<FastqcReportSummary>; 2],
+}
+
+/// Module statuses and key metrics of one `fastqc_data.txt`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct FastqcReportSummary {
+    /// `(module name, status)` in report order
+    pub statuses: Vec<(String, String)>,
+    pub metrics: [f64; NUMERIC_METRICS.len()],
+}
+
+impl FastqcReportSummary {
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut summary = Self {
+            statuses: Vec::new(),
+            metrics: [f64::NAN; NUMERIC_METRICS.len()],
+        };
+        let mut module: Option<&str> = None;
+        let mut quality_medians: Vec<f64> = Vec::new();
+        let mut gc_counts: Vec<(f64, f64)> = Vec::new();
+        let mut overrepresented = 0.0;
+
+        for line in text.lines() {
+            if let Some(header) = line.strip_prefix(">>") {
+                if header == "END_MODULE" {
+                    module = None;
+                    continue;
+                }
+                let Some((name, status)) = header.split_once('\t') else {
+                    bail!("malformed module header {line:?}");
+                };
+                summary
+                    .statuses
+                    .push((name.to_string(), status.trim().to_lowercase()));
+                module = Some(name);
+                if name == "Overrepresented sequences" {
+                    summary.metrics[OVERREPRESENTED] = 0.0;
+                }
+                continue;
+            }
+
+            let Some(module) = module else {
+                continue;
+            };
+            if let Some(comment) = line.strip_prefix('#') {
+                if let Some(value) = comment.strip_prefix("Total Deduplicated Percentage\t") {
+                    summary.metrics[DUPLICATION] = 100.0 - parse_f64(value)?;
+                }
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            match (module, fields.as_slice()) {
+                ("Basic Statistics", ["Total Sequences", value]) => {
+                    summary.metrics[TOTAL_SEQUENCES] = parse_f64(value)?;
+                }
+                ("Basic Statistics", ["%GC", value]) => {
+                    summary.metrics[GC_PERCENT] = parse_f64(value)?
+                }
+                ("Per base sequence quality", [_, _, median, ..]) => {
+                    quality_medians.push(parse_f64(median)?);
+                }
+                ("Per sequence GC content", [gc, count, ..]) => {
+                    gc_counts.push((parse_f64(gc)?, parse_f64(count)?));
+                }
+                ("Overrepresented sequences", [_, _, percentage, ..]) => {
+                    overrepresented += parse_f64(percentage)? / 100.0;
+                    summary.metrics[OVERREPRESENTED] = overrepresented;
+                }
+                _ => {}
+            }
+        }
+
+        if !quality_medians.is_empty() {
+            summary.metrics[QUALITY_MEDIAN_MIN] = quality_medians
+                .iter()
+                .copied()
+                .fold(f64::INFINITY, f64::min);
+            summary.metrics[QUALITY_MEDIAN_MEAN] =
+                quality_medians.iter().sum::<f64>() / quality_medians.len() as f64;
+        }
+        if let Some(deviation) = gc_deviation_percent(&gc_counts) {
+            summary.metrics[GC_DEVIATION] = deviation;
+        }
+        Ok(summary)
+    }
+}
+
+fn parse_f64(value: &str) -> Result<f64> {
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("{value:?} is not a number"))
+}
+
+/// Percentage of reads deviating from a normal distribution fitted to the per-sequence GC
+/// distribution, the statistic behind FastQC's "Per sequence GC content" module
+fn gc_deviation_percent(gc_counts: &[(f64, f64)]) -> Option<f64> {
+    let total: f64 = gc_counts.iter().map(|(_, count)| count).sum();
+    if total <= 0.0 {
+        return None;
+    }
+    let mean = gc_counts.iter().map(|(gc, count)| gc * count).sum::<f64>() / total;
+    let variance = gc_counts
+        .iter()
+        .map(|(gc, count)| (gc - mean).powi(2) * count)
+        .sum::<f64>()
+        / total;
+    if variance <= 0.0 {
+        return Some(0.0);
+    }
+    let density = |gc: f64| {
+        (-(gc - mean).powi(2) / (2.0 * variance)).exp()
+            / (2.0 * std::f64::consts::PI * variance).sqrt()
+    };
+    let fitted_total: f64 = gc_counts.iter().map(|(gc, _)| density(*gc)).sum();
+    let deviation: f64 = gc_counts
+        .iter()
+        .map(|(gc, count)| (count - density(*gc) / fitted_total * total).abs())
+        .sum();
+    Some(100.0 * deviation / total)
+}
+
+/// Module names in first-seen order across all reports
+fn module_names(cells: &[CellFastqcSummary]) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for summary in cells.iter().flat_map(|cell| cell.mates.iter().flatten()) {
+        for (name, _) in &summary.statuses {
+            if !names.contains(name) {
+                names.push(name.clone());
+            }
+        }
+    }
+    names
+}
+
+fn module_column(mate: &str, module: &str) -> String {
+    let slug: String = module
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("{mate}_{slug}")
+}
+
+/// Write one row per cell, as h5ad obs if `path_out` ends in `.h5ad` and as TSV otherwise
+pub fn write_summary(path_out: &Path, cells: &[CellFastqcSummary]) -> Result<()> {
+    let modules = module_names(cells);
+    let mut numeric_columns: Vec<(String, Vec<f64>)> = Vec::new();
+    let mut string_columns: Vec<(String, Vec<String>)> = Vec::new();
+    for (mate_index, mate) in FASTQC_MATES.iter().enumerate() {
+        for (metric_index, metric) in NUMERIC_METRICS.iter().enumerate() {
+            let values = cells
+                .iter()
+                .map(|cell| {
+                    cell.mates[mate_index]
+                        .as_ref()
+                        .map_or(f64::NAN, |summary| summary.metrics[metric_index])
+                })
+                .collect();
+            numeric_columns.push((format!("{mate}_{metric}"), values));
+        }
+        for module in &modules {
+            let values = cells
+                .iter()
+                .map(|cell| {
+                    cell.mates[mate_index]
+                        .as_ref()
+                        .and_then(|summary| {
+                            summary.statuses.iter().find(|(name, _)| name == module)
+                        })
+                        .map_or_else(String::new, |(_, status)| status.clone())
+                })
+                .collect();
+            string_columns.push((module_column(mate, module), values));
+        }
+    }
+
+    let path_tmp = atomic_temp_path(path_out);
+    let is_h5ad = path_out
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("h5ad"));
+    if is_h5ad {
+        let mut file = SparseMatrixAnnDataWriter::create_anndata(&path_tmp)?;
+        let n_rows = cells.len();
+        let empty_matrix =
+            sprs::CsMat::<u32>::new((n_rows, 0), vec![0; n_rows + 1], Vec::new(), Vec::new());
+        let cell_names: Vec<String> = cells.iter().map(|cell| cell.cell_id.clone()).collect();
+        let numeric: Vec<(&str, Vec<f64>)> = numeric_columns
+            .iter()
+            .map(|(name, values)| (name.as_str(), values.clone()))
+            .collect();
+        let strings: Vec<(&str, Vec<String>)> = string_columns
+            .iter()
+            .map(|(name, values)| (name.as_str(), values.clone()))
+            .collect();
+        file.store_feature_names(&Vec::new())?;
+        file.store_cell_obs(&cell_names, &numeric, &strings)?;
+        file.store_sparse_count_matrix(&empty_matrix, n_rows as u32, 0)?;
+        file.close()?;
+    } else {
+        let mut writer = BufWriter::new(
+            File::create(&path_tmp)
+                .with_context(|| format!("failed to create {}", path_tmp.display()))?,
+        );
+        write!(writer, "cell")?;
+        for (name, _) in &numeric_columns {
+            write!(writer, "\t{name}")?;
+        }
+        for (name, _) in &string_columns {
+            write!(writer, "\t{name}")?;
+        }
+        writeln!(writer)?;
+        for (row, cell) in cells.iter().enumerate() {
+            write!(writer, "{}", cell.cell_id)?;
+            for (_, values) in &numeric_columns {
+                match values[row] {
+                    value if value.is_nan() => write!(writer, "\tNA")?,
+                    value => write!(writer, "\t{}", value)?,
+                }
+            }
+            for (_, values) in &string_columns {
+                write!(writer, "\t{}", values[row])?;
+            }
+            writeln!(writer)?;
+        }
+        writer.flush()?;
+    }
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPORT: &str = "##FastQC\t0.12.1
+>>Basic Statistics\tpass
+#Measure\tValue
+Filename\tA1_R1.fastq
+Total Sequences\t200
+%GC\t48
+>>END_MODULE
+>>Per base sequence quality\twarn
+#Base\tMean\tMedian\tLower Quartile\tUpper Quartile\t10th Percentile\t90th Percentile
+1\t30.0\t32.0\t28.0\t34.0\t26.0\t36.0
+2-3\t28.0\t26.0\t24.0\t30.0\t22.0\t32.0
+>>END_MODULE
+>>Per sequence GC content\tfail
+#GC Content\tCount
+40\t50.0
+50\t100.0
+60\t50.0
+>>END_MODULE
+>>Sequence Duplication Levels\tpass
+#Total Deduplicated Percentage\t80.0
+#Duplication Level\tPercentage of deduplicated\tPercentage of total
+1\t90.0\t70.0
+>>END_MODULE
+>>Overrepresented sequences\twarn
+#Sequence\tCount\tPercentage\tPossible Source
+AAAAAAAAAA\t4\t2.0\tNo Hit
+CCCCCCCCCC\t2\t1.0\tNo Hit
+>>END_MODULE
+";
+
+    #[test]
+    fn parses_statuses_and_metrics() {
+        let summary = FastqcReportSummary::parse(REPORT).unwrap();
+        assert_eq!(
+            summary.statuses,
+            [
+                ("Basic Statistics", "pass"),
+                ("Per base sequence quality", "warn"),
+                ("Per sequence GC content", "fail"),
+                ("Sequence Duplication Levels", "pass"),
+                ("Overrepresented sequences", "warn"),
+            ]
+            .map(|(name, status)| (name.to_string(), status.to_string()))
+        );
+        assert_eq!(summary.metrics[TOTAL_SEQUENCES], 200.0);
+        assert_eq!(summary.metrics[GC_PERCENT], 48.0);
+        assert_eq!(summary.metrics[QUALITY_MEDIAN_MIN], 26.0);
+        assert_eq!(summary.metrics[QUALITY_MEDIAN_MEAN], 29.0);
+        assert!(summary.metrics[GC_DEVIATION] >= 0.0 && summary.metrics[GC_DEVIATION] < 100.0);
+        assert!((summary.metrics[OVERREPRESENTED] - 0.03).abs() < 1e-9);
+        assert_eq!(summary.metrics[DUPLICATION], 20.0);
+    }
+
+    #[test]
+    fn writes_tsv_with_missing_mates() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("summary.tsv");
+        let cells = [CellFastqcSummary {
+            cell_id: "A1".to_string(),
+            mates: [Some(FastqcReportSummary::parse(REPORT).unwrap()), None],
+        }];
+        write_summary(&path, &cells).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        let mut lines = text.lines();
+        let header: Vec<&str> = lines.next().unwrap().split('\t').collect();
+        let row: Vec<&str> = lines.next().unwrap().split('\t').collect();
+        assert_eq!(header.len(), row.len());
+        let column = |name: &str| row[header.iter().position(|h| *h == name).unwrap()];
+        assert_eq!(column("r1_total_sequences"), "200");
+        assert_eq!(column("r2_total_sequences"), "NA");
+        assert_eq!(column("r1_per_sequence_gc_content"), "fail");
+        assert_eq!(column("r2_per_sequence_gc_content"), "");
+    }
+}
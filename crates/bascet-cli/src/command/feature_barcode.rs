@@ -0,0 +1,999 @@
+//! `feature-barcode` subcommand: count antibody-derived tags, hashtag oligos or CRISPR guides
+//! from a feature-barcode library, and optionally demultiplex hashtagged cells.
+//!
+//! The tag library shares its cell barcodes with the main library, so it is debarcoded with
+//! the same `Chemistry` (and `--library` prefix) as `debarcode`. The feature sequence is then
+//! looked up in the trimmed read, at a fixed offset or anywhere, allowing a few mismatches
+//! against a reference CSV. Counts are distinct UMIs per cell and feature, or reads for
+//! chemistries without UMIs, written as a cell x feature h5ad.
+//!
+//! With `--demux`, cells are classified HTODemux-style: per feature, CLR values are split into
+//! a background and a signal cluster, a negative binomial is fitted to the background counts,
+//! and cells above its `--positive-quantile` are positive. One positive feature makes a
+//! singlet of that feature's sample, several a doublet, none a negative.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+use ahash::{AHashMap, AHashSet};
+use anyhow::{Context, Result, bail};
+use bascet_core::DEFAULT_SIZEOF_ARENA;
+use bascet_core::{
+    attr::{quality::*, sequence::*},
+    *,
+};
+use bascet_io::fastq::fastq;
+use bascet_io::parse;
+use bytesize::ByteSize;
+use clap::{Args, ValueEnum};
+use clio::InputPath;
+use crossbeam::channel::{Receiver, Sender};
+use sprs::{CsMat, TriMat};
+use tracing::{info, warn};
+
+use crate::barcode::Chemistry;
+use crate::command::determine_thread_counts_1;
+use crate::command::getraw::{
+    GetRawChemistry, GetRawChemistryCMD, open_fastq_decoder, sample_reads,
+};
+use crate::fileformat::bbgz_input::input_progress;
+use crate::fileformat::new_anndata::SparseMatrixAnnDataWriter;
+use crate::fileformat::read_cell_list_file;
+use crate::utils::{atomic_temp_path, publish_atomic_output};
+
+const DEFAULT_SIZEOF_STREAM_BUFFER: ByteSize = ByteSize::gib(1);
+const SIZEOF_READ_PAIR_BATCH: usize = 4096;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum FeatureRead {
+    /// Feature sequence is in read 1, after barcode trimming
+    R1,
+    /// Feature sequence is in read 2, after barcode trimming
+    R2,
+}
+
+#[derive(Args)]
+pub struct FeatureBarcodeCMD {
+    #[arg(
+        short = '1',
+        long = "r1",
+        value_delimiter = ',',
+        help = "R1 files of the feature-barcode library (comma-separated)"
+    )]
+    pub paths_r1: Vec<InputPath>,
+
+    #[arg(
+        short = '2',
+        long = "r2",
+        value_delimiter = ',',
+        help = "R2 files of the feature-barcode library (comma-separated)"
+    )]
+    pub paths_r2: Vec<InputPath>,
+
+    /// Feature reference CSV with columns id, sequence and optionally name (e.g. the sample of
+    /// a hashtag). Other columns, such as those of a 10x feature reference, are ignored.
+    #[arg(short = 'f', long = "features")]
+    pub path_features: PathBuf,
+
+    /// Output h5ad: cells x features counts, with demultiplexing results in obs.
+    #[arg(short = 'o', long = "out")]
+    pub path_out: PathBuf,
+
+    /// Library name to prefix cell barcodes with; use the one of the main library.
+    #[arg(long = "library")]
+    pub library: Option<String>,
+
+    /// Only keep these cells, e.g. the cells called in the main library. One per line.
+    #[arg(long = "cells")]
+    pub path_cells: Option<PathBuf>,
+
+    /// Read holding the feature sequence.
+    #[arg(long = "feature-read", value_enum, default_value_t = FeatureRead::R2)]
+    pub feature_read: FeatureRead,
+
+    /// Start of the feature sequence in the trimmed read. Searched anywhere if not given.
+    #[arg(long = "feature-offset")]
+    pub feature_offset: Option<usize>,
+
+    /// Mismatches allowed against a reference feature sequence.
+    #[arg(
+        long = "max-mismatches",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(0..=3),
+    )]
+    pub max_mismatches: u8,
+
+    /// Drop cells with fewer feature counts than this.
+    #[arg(long = "min-counts", default_value_t = 1)]
+    pub min_counts: u64,
+
+    /// Classify cells as singlet of a sample, doublet or negative.
+    #[arg(long = "demux")]
+    pub demux: bool,
+
+    /// Quantile of the fitted background above which a cell is positive for a feature.
+    #[arg(long = "positive-quantile", default_value_t = 0.99, requires = "demux")]
+    pub positive_quantile: f64,
+
+    /// Also write the per-cell classification as TSV.
+    #[arg(long = "assignments", requires = "demux")]
+    pub path_assignments: Option<PathBuf>,
+
+    #[arg(short = '@', long = "threads")]
+    pub threads: Option<usize>,
+
+    #[arg(
+        long = "sizeof-stream-buffer",
+        help = "Total stream buffer size.",
+        default_value_t = DEFAULT_SIZEOF_STREAM_BUFFER,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_buffer: ByteSize,
+
+    #[arg(
+        long = "sizeof-stream-arena",
+        help = "Stream arena buffer size [Advanced: changing this will impact performance and stability]",
+        hide_short_help = true,
+        default_value_t = DEFAULT_SIZEOF_ARENA,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_arena: ByteSize,
+
+    #[command(subcommand)]
+    pub chemistry: GetRawChemistryCMD,
+}
+
+impl FeatureBarcodeCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        if self.paths_r1.is_empty() {
+            bail!("No R1 input files specified");
+        }
+        if !self.paths_r2.is_empty() && self.paths_r1.len() != self.paths_r2.len() {
+            bail!(
+                "{} R1 files but {} R2 files",
+                self.paths_r1.len(),
+                self.paths_r2.len()
+            );
+        }
+        if self.paths_r2.is_empty() && self.feature_read == FeatureRead::R2 {
+            bail!("--feature-read r2 needs R2 files; use --feature-read r1 for single-end input");
+        }
+        if !(0.0..1.0).contains(&self.positive_quantile) {
+            bail!("--positive-quantile must be in [0, 1)");
+        }
+
+        let features = read_feature_reference(&self.path_features)?;
+        let matcher = Arc::new(FeatureMatcher::new(&features, self.max_mismatches)?);
+        info!(
+            features = features.len(),
+            max_mismatches = self.max_mismatches,
+            "Loaded feature reference {}",
+            self.path_features.display()
+        );
+
+        let mut chemistry = self.chemistry.to_chemistry();
+        {
+            info!("Preparing chemistry...");
+            let b1 = sample_reads(&self.paths_r1[0], self.sizeof_stream_arena, "R1")?;
+            let b2 = match self.paths_r2.first() {
+                Some(path_r2) => sample_reads(path_r2, self.sizeof_stream_arena, "R2")?,
+                None => (0..b1.len()).map(|_| fastq::OwnedRecord::empty()).collect(),
+            };
+            chemistry.prepare_using_rp_vecs(b1, b2)?;
+        }
+
+        let num_workers = determine_thread_counts_1(self.threads)?
+            .saturating_sub(1)
+            .max(1);
+        let (tx_pairs, rx_pairs) = crossbeam::channel::bounded::<ReadPairBatch>(num_workers * 2);
+        let mut workers = Vec::with_capacity(num_workers);
+        for _ in 0..num_workers {
+            let rx_pairs = rx_pairs.clone();
+            let chemistry = chemistry.clone();
+            let matcher = Arc::clone(&matcher);
+            let feature_read = self.feature_read;
+            let feature_offset = self.feature_offset;
+            workers.push(thread::spawn(move || {
+                count_worker(rx_pairs, chemistry, &matcher, feature_read, feature_offset)
+            }));
+        }
+        drop(rx_pairs);
+
+        let result = self.stream_read_pairs(tx_pairs);
+        let mut counts = FeatureCounts::default();
+        for worker in workers {
+            let worker_counts = worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("feature-barcode worker thread panicked"))?;
+            counts.merge(worker_counts);
+        }
+        result?;
+        counts.log_summary();
+
+        let library_prefix = match self.library.as_deref() {
+            Some(library) if !library.is_empty() => format!("{library}_"),
+            _ => String::new(),
+        };
+        let keep_cells: Option<HashSet<String>> = self
+            .path_cells
+            .as_ref()
+            .map(|path| read_cell_list_file(path).into_iter().collect());
+        let matrix = counts.into_matrix(features.len(), |bc_index| {
+            let barcode = chemistry.bcindexu32_to_bcu8(&bc_index);
+            format!("{library_prefix}{}", String::from_utf8_lossy(&barcode))
+        });
+        let matrix: Vec<(String, Vec<u32>)> = matrix
+            .into_iter()
+            .filter(|(cell, row)| {
+                row.iter().map(|&c| c as u64).sum::<u64>() >= self.min_counts
+                    && keep_cells.as_ref().is_none_or(|keep| keep.contains(cell))
+            })
+            .collect();
+        info!(cells = matrix.len(), "Cells with feature counts");
+
+        let assignments = if self.demux {
+            let assignments = demultiplex(&matrix, &features, self.positive_quantile);
+            log_assignments(&assignments);
+            Some(assignments)
+        } else {
+            None
+        };
+
+        write_anndata(&self.path_out, &features, &matrix, assignments.as_deref())?;
+        if let (Some(path), Some(assignments)) = (&self.path_assignments, &assignments) {
+            write_assignments(path, &features, &matrix, assignments)?;
+        }
+        Ok(())
+    }
+
+    fn stream_read_pairs(&self, tx_pairs: Sender<ReadPairBatch>) -> Result<()> {
+        let sizeof_stream_buffer = ByteSize(self.sizeof_stream_buffer.as_u64() / 2);
+        let open = |path: &InputPath| -> Result<_> {
+            let path = path.path().path();
+            let decoder = open_fastq_decoder(path, None, Some(input_progress(path, None)))?;
+            Ok(Stream::builder()
+                .with_decoder(decoder)
+                .with_parser(parse::Fastq::builder().build())
+                .sizeof_decode_arena(self.sizeof_stream_arena)
+                .sizeof_decode_buffer(sizeof_stream_buffer)
+                .build())
+        };
+
+        let mut num_pairs = 0_u64;
+        for (i, path_r1) in self.paths_r1.iter().enumerate() {
+            info!("Reading {path_r1}");
+            let mut stream_r1 = open(path_r1)?;
+            let mut query_r1 = stream_r1.query::<fastq::Record>();
+            let mut stream_r2 = self.paths_r2.get(i).map(open).transpose()?;
+            let mut query_r2 = stream_r2
+                .as_mut()
+                .map(|stream| stream.query::<fastq::Record>());
+
+            let mut batch = Vec::with_capacity(SIZEOF_READ_PAIR_BATCH);
+            loop {
+                let r1 = query_r1.next().context("failed to read R1")?;
+                let r2 = match query_r2.as_mut() {
+                    Some(query) => query.next().context("failed to read R2")?,
+                    None => None,
+                };
+                let Some(r1) = r1 else {
+                    if r2.is_some() {
+                        bail!("{path_r1} has fewer reads than its R2 file");
+                    }
+                    break;
+                };
+                if query_r2.is_some() && r2.is_none() {
+                    bail!("{path_r1} has more reads than its R2 file");
+                }
+
+                batch.push((r1, r2));
+                num_pairs += 1;
+                if batch.len() == SIZEOF_READ_PAIR_BATCH {
+                    let full =
+                        std::mem::replace(&mut batch, Vec::with_capacity(SIZEOF_READ_PAIR_BATCH));
+                    if tx_pairs.send(full).is_err() {
+                        bail!("feature-barcode workers stopped early");
+                    }
+                }
+                if num_pairs % 10_000_000 == 0 {
+                    info!(read_pairs = num_pairs, "Processing");
+                }
+            }
+            if !batch.is_empty() && tx_pairs.send(batch).is_err() {
+                bail!("feature-barcode workers stopped early");
+            }
+        }
+        Ok(())
+    }
+}
+
+type ReadPairBatch = Vec<(fastq::Record, Option<fastq::Record>)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureReference {
+    pub id: String,
+    pub name: String,
+    pub sequence: Vec<u8>,
+}
+
+pub fn read_feature_reference(path: &Path) -> Result<Vec<FeatureReference>> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open feature reference {}", path.display()))?;
+    parse_feature_reference(file)
+        .with_context(|| format!("invalid feature reference {}", path.display()))
+}
+
+fn parse_feature_reference(src: impl std::io::Read) -> Result<Vec<FeatureReference>> {
+    let mut reader = csv::ReaderBuilder::new()
+        .delimiter(b',')
+        .comment(Some(b'#'))
+        .trim(csv::Trim::All)
+        .from_reader(src);
+    let header: Vec<String> = reader
+        .headers()?
+        .iter()
+        .map(|h| h.to_ascii_lowercase())
+        .collect();
+    let column = |name: &str| header.iter().position(|h| h == name);
+    let (Some(col_id), Some(col_sequence)) = (column("id"), column("sequence")) else {
+        bail!("feature reference needs columns 'id' and 'sequence'");
+    };
+    let col_name = column("name");
+
+    let mut features: Vec<FeatureReference> = Vec::new();
+    for record in reader.records() {
+        let record = record?;
+        let id = record.get(col_id).unwrap_or("").to_string();
+        if id.is_empty() {
+            bail!("feature with empty id");
+        }
+        let sequence = record
+            .get(col_sequence)
+            .unwrap_or("")
+            .to_ascii_uppercase()
+            .into_bytes();
+        if sequence.is_empty() || !sequence.iter().all(|b| b"ACGT".contains(b)) {
+            bail!("feature {id}: sequence must be non-empty ACGT");
+        }
+        let name = col_name
+            .and_then(|c| record.get(c))
+            .filter(|n| !n.is_empty())
+            .unwrap_or(&id)
+            .to_string();
+        if let Some(other) = features
+            .iter()
+            .find(|f| f.id == id || f.sequence == sequence)
+        {
+            bail!("features {} and {id} share an id or sequence", other.id);
+        }
+        features.push(FeatureReference { id, name, sequence });
+    }
+    if features.is_empty() {
+        bail!("no features listed");
+    }
+    Ok(features)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeatureHit {
+    Unique { feature: u32, mismatches: u8 },
+    Ambiguous { mismatches: u8 },
+}
+
+impl FeatureHit {
+    fn mismatches(&self) -> u8 {
+        match self {
+            FeatureHit::Unique { mismatches, .. } | FeatureHit::Ambiguous { mismatches } => {
+                *mismatches
+            }
+        }
+    }
+
+    /// The better of two hits; equally good hits to different features are ambiguous
+    fn best(self, other: FeatureHit) -> FeatureHit {
+        match self.mismatches().cmp(&other.mismatches()) {
+            std::cmp::Ordering::Less => self,
+            std::cmp::Ordering::Greater => other,
+            std::cmp::Ordering::Equal if self == other => self,
+            std::cmp::Ordering::Equal => FeatureHit::Ambiguous {
+                mismatches: self.mismatches(),
+            },
+        }
+    }
+}
+
+/// Every sequence within `max_mismatches` substitutions of a reference feature, mapped to the
+/// closest feature
+pub struct FeatureMatcher {
+    lengths: Vec<usize>,
+    variants: AHashMap<Vec<u8>, FeatureHit>,
+}
+
+impl FeatureMatcher {
+    pub fn new(features: &[FeatureReference], max_mismatches: u8) -> Result<FeatureMatcher> {
+        let mut variants: AHashMap<Vec<u8>, FeatureHit> = AHashMap::new();
+        let mut lengths: Vec<usize> = Vec::new();
+        for (index, feature) in features.iter().enumerate() {
+            if !lengths.contains(&feature.sequence.len()) {
+                lengths.push(feature.sequence.len());
+            }
+            let mut sequence = feature.sequence.clone();
+            push_variants(
+                &mut variants,
+                &mut sequence,
+                0,
+                max_mismatches,
+                index as u32,
+                0,
+            );
+        }
+        let num_ambiguous = variants
+            .values()
+            .filter(|hit| matches!(hit, FeatureHit::Ambiguous { .. }))
+            .count();
+        if num_ambiguous > 0 {
+            warn!(
+                ambiguous = num_ambiguous,
+                "Some sequences are equally close to several features and are not counted"
+            );
+        }
+        lengths.sort_unstable();
+        Ok(FeatureMatcher { lengths, variants })
+    }
+
+    /// Best feature in `seq`, at `offset` or anywhere
+    pub fn find(&self, seq: &[u8], offset: Option<usize>) -> Option<FeatureHit> {
+        let mut best: Option<FeatureHit> = None;
+        for &len in &self.lengths {
+            if seq.len() < len {
+                continue;
+            }
+            let starts = match offset {
+                Some(offset) if offset + len <= seq.len() => offset..offset + 1,
+                Some(_) => continue,
+                None => 0..seq.len() - len + 1,
+            };
+            for start in starts {
+                let Some(hit) = self.variants.get(&seq[start..start + len]) else {
+                    continue;
+                };
+                best = Some(best.map_or(*hit, |best| best.best(*hit)));
+            }
+        }
+        best
+    }
+}
+
+fn push_variants(
+    variants: &mut AHashMap<Vec<u8>, FeatureHit>,
+    sequence: &mut Vec<u8>,
+    from: usize,
+    max_mismatches: u8,
+    feature: u32,
+    mismatches: u8,
+) {
+    let hit = FeatureHit::Unique {
+        feature,
+        mismatches,
+    };
+    variants
+        .entry(sequence.clone())
+        .and_modify(|existing| *existing = existing.best(hit))
+        .or_insert(hit);
+    if mismatches == max_mismatches {
+        return;
+    }
+    for pos in from..sequence.len() {
+        let original = sequence[pos];
+        for &base in b"ACGTN" {
+            if base == original {
+                continue;
+            }
+            sequence[pos] = base;
+            push_variants(
+                variants,
+                sequence,
+                pos + 1,
+                max_mismatches,
+                feature,
+                mismatches + 1,
+            );
+        }
+        sequence[pos] = original;
+    }
+}
+
+#[derive(Default)]
+struct CellFeatureCount {
+    reads: u64,
+    umis: AHashSet<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct FeatureCounts {
+    counts: AHashMap<(u32, u32), CellFeatureCount>,
+    read_pairs: u64,
+    with_barcode: u64,
+    with_feature: u64,
+    ambiguous: u64,
+}
+
+impl FeatureCounts {
+    fn merge(&mut self, other: FeatureCounts) {
+        for (key, count) in other.counts {
+            let entry = self.counts.entry(key).or_default();
+            entry.reads += count.reads;
+            entry.umis.extend(count.umis);
+        }
+        self.read_pairs += other.read_pairs;
+        self.with_barcode += other.with_barcode;
+        self.with_feature += other.with_feature;
+        self.ambiguous += other.ambiguous;
+    }
+
+    fn log_summary(&self) {
+        let fraction = |n: u64| n as f64 / self.read_pairs.max(1) as f64;
+        info!(
+            read_pairs = self.read_pairs,
+            with_barcode = %format!("{:.1}%", 100.0 * fraction(self.with_barcode)),
+            with_feature = %format!("{:.1}%", 100.0 * fraction(self.with_feature)),
+            ambiguous = %format!("{:.1}%", 100.0 * fraction(self.ambiguous)),
+            "Feature barcode summary"
+        );
+    }
+
+    /// Dense count rows per cell, sorted by cell name. Counts are distinct UMIs, or reads for
+    /// chemistries without UMIs
+    fn into_matrix(
+        self,
+        num_features: usize,
+        cell_name: impl Fn(u32) -> String,
+    ) -> Vec<(String, Vec<u32>)> {
+        let mut rows: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for ((bc_index, feature), count) in self.counts {
+            let value = if count.umis.is_empty() {
+                count.reads
+            } else {
+                count.umis.len() as u64
+            };
+            rows.entry(bc_index)
+                .or_insert_with(|| vec![0; num_features])[feature as usize] =
+                value.min(u32::MAX as u64) as u32;
+        }
+        let mut matrix: Vec<(String, Vec<u32>)> = rows
+            .into_iter()
+            .map(|(bc_index, row)| (cell_name(bc_index), row))
+            .collect();
+        matrix.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        matrix
+    }
+}
+
+fn count_worker(
+    rx_pairs: Receiver<ReadPairBatch>,
+    mut chemistry: GetRawChemistry,
+    matcher: &FeatureMatcher,
+    feature_read: FeatureRead,
+    feature_offset: Option<usize>,
+) -> FeatureCounts {
+    let mut counts = FeatureCounts::default();
+    while let Ok(batch) = rx_pairs.recv() {
+        for (r1, r2) in batch {
+            counts.read_pairs += 1;
+            let (r2_seq, r2_qual): (&[u8], &[u8]) = match &r2 {
+                Some(r2) => (*r2.get_ref::<R0>(), *r2.get_ref::<Q0>()),
+                None => (&[], &[]),
+            };
+            let (bc_index, rp) = chemistry.detect_barcode_and_trim(
+                r1.get_ref::<R0>(),
+                r1.get_ref::<Q0>(),
+                r2_seq,
+                r2_qual,
+            );
+            if bc_index == u32::MAX {
+                continue;
+            }
+            counts.with_barcode += 1;
+
+            let seq = match feature_read {
+                FeatureRead::R1 => rp.r1,
+                FeatureRead::R2 => rp.r2,
+            };
+            match matcher.find(seq, feature_offset) {
+                Some(FeatureHit::Unique { feature, .. }) => {
+                    counts.with_feature += 1;
+                    let entry = counts.counts.entry((bc_index, feature)).or_default();
+                    entry.reads += 1;
+                    if !rp.umi.is_empty() && !entry.umis.contains(rp.umi) {
+                        entry.umis.insert(rp.umi.to_vec());
+                    }
+                }
+                Some(FeatureHit::Ambiguous { .. }) => counts.ambiguous += 1,
+                None => {}
+            }
+        }
+    }
+    counts
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HashAssignment {
+    /// Sample name of the positive feature, `Doublet` or `Negative`
+    pub classification: String,
+    /// `Singlet`, `Doublet` or `Negative`
+    pub global: &'static str,
+    pub max_feature: usize,
+    pub second_feature: Option<usize>,
+    /// CLR difference between the strongest and second strongest feature
+    pub margin: f64,
+}
+
+///
+/// HTODemux-like classification of cells from their feature counts
+///
+pub fn demultiplex(
+    matrix: &[(String, Vec<u32>)],
+    features: &[FeatureReference],
+    positive_quantile: f64,
+) -> Vec<HashAssignment> {
+    let num_cells = matrix.len();
+    let num_features = features.len();
+
+    let clr: Vec<Vec<f64>> = (0..num_features)
+        .map(|f| {
+            let counts: Vec<f64> = matrix.iter().map(|(_, row)| row[f] as f64).collect();
+            clr_normalise(&counts)
+        })
+        .collect();
+
+    let mut positive = vec![vec![false; num_features]; num_cells];
+    for (f, feature_clr) in clr.iter().enumerate() {
+        let background = background_cells(feature_clr);
+        let background_counts: Vec<f64> = background
+            .iter()
+            .map(|&cell| matrix[cell].1[f] as f64)
+            .collect();
+        let threshold = negative_binomial_quantile(&background_counts, positive_quantile);
+        info!(
+            feature = %features[f].id,
+            background_cells = background.len(),
+            threshold,
+            "Positive threshold"
+        );
+        for (cell, (_, row)) in matrix.iter().enumerate() {
+            positive[cell][f] = row[f] as f64 > threshold;
+        }
+    }
+
+    (0..num_cells)
+        .map(|cell| {
+            let mut order: Vec<usize> = (0..num_features).collect();
+            order.sort_by(|&a, &b| clr[b][cell].total_cmp(&clr[a][cell]));
+            let max_feature = order[0];
+            let second_feature = order.get(1).copied();
+            let margin = clr[max_feature][cell] - second_feature.map_or(0.0, |s| clr[s][cell]);
+
+            let positives: Vec<usize> = (0..num_features).filter(|&f| positive[cell][f]).collect();
+            let (classification, global) = match positives.as_slice() {
+                [] => ("Negative".to_string(), "Negative"),
+                [f] => (features[*f].name.clone(), "Singlet"),
+                _ => ("Doublet".to_string(), "Doublet"),
+            };
+            HashAssignment {
+                classification,
+                global,
+                max_feature,
+                second_feature,
+                margin,
+            }
+        })
+        .collect()
+}
+
+/// Centred log-ratio across cells, as Seurat's CLR
+fn clr_normalise(counts: &[f64]) -> Vec<f64> {
+    if counts.is_empty() {
+        return Vec::new();
+    }
+    let geometric = (counts.iter().map(|c| c.ln_1p()).sum::<f64>() / counts.len() as f64).exp();
+    counts.iter().map(|c| (c / geometric).ln_1p()).collect()
+}
+
+/// Cells in the lower of two 1-D k-means clusters
+fn background_cells(values: &[f64]) -> Vec<usize> {
+    let (min, max) = values
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+            (lo.min(v), hi.max(v))
+        });
+    if max <= min {
+        return (0..values.len()).collect();
+    }
+
+    let (mut low, mut high) = (min, max);
+    let mut in_low: Vec<bool> = Vec::new();
+    for _ in 0..100 {
+        let assignment: Vec<bool> = values
+            .iter()
+            .map(|&v| (v - low).abs() <= (v - high).abs())
+            .collect();
+        if assignment == in_low {
+            break;
+        }
+        in_low = assignment;
+        let mean = |want: bool| {
+            let (sum, n) = values
+                .iter()
+                .zip(&in_low)
+                .filter(|(_, l)| **l == want)
+                .fold((0.0, 0usize), |(s, n), (v, _)| (s + v, n + 1));
+            (n > 0).then(|| sum / n as f64)
+        };
+        low = mean(true).unwrap_or(low);
+        high = mean(false).unwrap_or(high);
+    }
+    (0..values.len()).filter(|&i| in_low[i]).collect()
+}
+
+/// Smallest count whose CDF under a method-of-moments negative binomial (Poisson if not
+/// overdispersed) fit to `counts` reaches `quantile`
+fn negative_binomial_quantile(counts: &[f64], quantile: f64) -> f64 {
+    if counts.is_empty() {
+        return f64::INFINITY;
+    }
+    let n = counts.len() as f64;
+    let mean = counts.iter().sum::<f64>() / n;
+    if mean <= 0.0 {
+        return 0.0;
+    }
+    let variance = if counts.len() > 1 {
+        counts.iter().map(|c| (c - mean).powi(2)).sum::<f64>() / (n - 1.0)
+    } else {
+        0.0
+    };
+
+    // log pmf(0), and log of pmf(k+1)/pmf(k) = ln((k+r)/(k+1)) + ln_step
+    let (log_pmf0, size, ln_step) = if variance > mean {
+        let size = mean * mean / (variance - mean);
+        let p = size / (size + mean);
+        (size * p.ln(), Some(size), (1.0 - p).ln())
+    } else {
+        (-mean, None, mean.ln())
+    };
+
+    let limit = (mean + 100.0 * variance.max(mean).sqrt() + 100.0) as u64;
+    let mut log_pmf = log_pmf0;
+    let mut cdf = log_pmf.exp();
+    let mut k = 0u64;
+    while cdf < quantile && k < limit {
+        let kf = k as f64;
+        log_pmf += match size {
+            Some(size) => ((kf + size) / (kf + 1.0)).ln() + ln_step,
+            None => ln_step - (kf + 1.0).ln(),
+        };
+        cdf += log_pmf.exp();
+        k += 1;
+    }
+    k as f64
+}
+
+fn log_assignments(assignments: &[HashAssignment]) {
+    let mut by_class: BTreeMap<&str, usize> = BTreeMap::new();
+    for assignment in assignments {
+        *by_class
+            .entry(assignment.classification.as_str())
+            .or_default() += 1;
+    }
+    for (classification, cells) in by_class {
+        info!(classification, cells, "Demultiplexing");
+    }
+}
+
+fn write_anndata(
+    path_out: &Path,
+    features: &[FeatureReference],
+    matrix: &[(String, Vec<u32>)],
+    assignments: Option<&[HashAssignment]>,
+) -> Result<()> {
+    let num_cells = matrix.len();
+    let num_features = features.len();
+    let mut trimat = TriMat::new((num_cells, num_features));
+    for (cell, (_, row)) in matrix.iter().enumerate() {
+        for (feature, &count) in row.iter().enumerate() {
+            if count > 0 {
+                trimat.add_triplet(cell, feature, count);
+            }
+        }
+    }
+    let counts: CsMat<u32> = trimat.to_csr();
+
+    let cell_names: Vec<String> = matrix.iter().map(|(cell, _)| cell.clone()).collect();
+    let total_counts: Vec<f64> = matrix
+        .iter()
+        .map(|(_, row)| row.iter().map(|&c| c as f64).sum())
+        .collect();
+    let mut numeric = vec![("total_counts", total_counts)];
+    let mut strings = Vec::new();
+    if let Some(assignments) = assignments {
+        let feature_id = |f: Option<usize>| f.map_or(String::new(), |f| features[f].id.clone());
+        numeric.push((
+            "hash_margin",
+            assignments.iter().map(|a| a.margin).collect(),
+        ));
+        strings.push((
+            "hash_classification",
+            assignments
+                .iter()
+                .map(|a| a.classification.clone())
+                .collect(),
+        ));
+        strings.push((
+            "hash_classification_global",
+            assignments.iter().map(|a| a.global.to_string()).collect(),
+        ));
+        strings.push((
+            "hash_max_id",
+            assignments
+                .iter()
+                .map(|a| feature_id(Some(a.max_feature)))
+                .collect(),
+        ));
+        strings.push((
+            "hash_second_id",
+            assignments
+                .iter()
+                .map(|a| feature_id(a.second_feature))
+                .collect(),
+        ));
+    }
+
+    let path_tmp = atomic_temp_path(path_out);
+    let mut file = SparseMatrixAnnDataWriter::create_anndata(&path_tmp)?;
+    file.store_feature_names_with_symbols(
+        &features.iter().map(|f| f.id.clone()).collect(),
+        &features.iter().map(|f| f.name.clone()).collect(),
+    )?;
+    file.store_cell_obs(&cell_names, &numeric, &strings)?;
+    file.store_sparse_count_matrix(&counts, num_cells as u32, num_features as u32)?;
+    file.close()?;
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
+
+fn write_assignments(
+    path_out: &Path,
+    features: &[FeatureReference],
+    matrix: &[(String, Vec<u32>)],
+    assignments: &[HashAssignment],
+) -> Result<()> {
+    let path_tmp = atomic_temp_path(path_out);
+    let mut writer = BufWriter::new(File::create(&path_tmp)?);
+    writeln!(
+        writer,
+        "cell\tclassification\tclassification_global\tmax_id\tsecond_id\tmargin\ttotal_counts"
+    )?;
+    for ((cell, row), assignment) in matrix.iter().zip(assignments) {
+        writeln!(
+            writer,
+            "{cell}\t{}\t{}\t{}\t{}\t{:.4}\t{}",
+            assignment.classification,
+            assignment.global,
+            features[assignment.max_feature].id,
+            assignment
+                .second_feature
+                .map_or("", |f| features[f].id.as_str()),
+            assignment.margin,
+            row.iter().map(|&c| c as u64).sum::<u64>(),
+        )?;
+    }
+    writer.flush()?;
+    drop(writer);
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference() -> Vec<FeatureReference> {
+        let csv = "id,name,read,pattern,sequence,feature_type\n\
+                   HTO1,donorA,R2,^(BC),ACGTACGTAC,Antibody Capture\n\
+                   HTO2,donorB,R2,^(BC),TTGCAAGGCT,Antibody Capture\n\
+                   HTO3,,R2,^(BC),GGATCCTTAA,Antibody Capture\n";
+        parse_feature_reference(csv.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn parses_reference_and_matches_with_mismatches() {
+        let features = reference();
+        assert_eq!(features[2].name, "HTO3");
+        assert!(parse_feature_reference("id,sequence\na,ACGT\nb,ACGT\n".as_bytes()).is_err());
+
+        let matcher = FeatureMatcher::new(&features, 1).unwrap();
+        let unique = |feature, mismatches| {
+            Some(FeatureHit::Unique {
+                feature,
+                mismatches,
+            })
+        };
+        assert_eq!(matcher.find(b"ACGTACGTACNNNN", Some(0)), unique(0, 0));
+        assert_eq!(matcher.find(b"ACGTACCTACNNNN", Some(0)), unique(0, 1));
+        assert_eq!(matcher.find(b"ACGTAGCTACNNNN", Some(0)), None);
+        assert_eq!(matcher.find(b"NNNTTGCAAGGCTNN", None), unique(1, 0));
+        assert_eq!(matcher.find(b"NNNTTGCAAGGCTNN", Some(0)), None);
+        assert_eq!(
+            matcher.find(b"ACGTACGTACGGATCCTTAA", None),
+            Some(FeatureHit::Ambiguous { mismatches: 0 })
+        );
+
+        let close = vec![
+            FeatureReference {
+                id: "a".into(),
+                name: "a".into(),
+                sequence: b"AAAA".to_vec(),
+            },
+            FeatureReference {
+                id: "b".into(),
+                name: "b".into(),
+                sequence: b"AAAC".to_vec(),
+            },
+        ];
+        let matcher = FeatureMatcher::new(&close, 1).unwrap();
+        assert_eq!(matcher.find(b"AAAA", Some(0)), unique(0, 0));
+        assert_eq!(
+            matcher.find(b"AAAG", Some(0)),
+            Some(FeatureHit::Ambiguous { mismatches: 1 })
+        );
+    }
+
+    #[test]
+    fn negative_binomial_quantile_is_monotone() {
+        let poisson: Vec<f64> = [2.0, 3.0, 1.0, 2.0, 2.0, 3.0, 1.0, 2.0].to_vec();
+        let q90 = negative_binomial_quantile(&poisson, 0.9);
+        let q99 = negative_binomial_quantile(&poisson, 0.99);
+        assert!(q90 >= 2.0 && q99 >= q90 && q99 < 10.0);
+
+        let overdispersed: Vec<f64> = [0.0, 1.0, 0.0, 8.0, 2.0, 0.0, 15.0, 1.0].to_vec();
+        assert!(negative_binomial_quantile(&overdispersed, 0.99) > q99);
+        assert_eq!(negative_binomial_quantile(&[0.0, 0.0], 0.99), 0.0);
+    }
+
+    #[test]
+    fn demultiplexes_singlets_doublets_and_negatives() {
+        let features = reference();
+        let mut matrix = Vec::new();
+        for i in 0..30u32 {
+            let noise = [i % 3, (i + 1) % 4, (i + 2) % 3];
+            let mut row = noise.to_vec();
+            match i % 10 {
+                0..=3 => row[0] += 200 + i,
+                4..=7 => row[1] += 150 + i,
+                8 => {
+                    row[0] += 180;
+                    row[2] += 170;
+                }
+                _ => {}
+            }
+            matrix.push((format!("cell{i:02}"), row));
+        }
+
+        let assignments = demultiplex(&matrix, &features, 0.99);
+        for (i, assignment) in assignments.iter().enumerate() {
+            let expected = match i % 10 {
+                0..=3 => "donorA",
+                4..=7 => "donorB",
+                8 => "Doublet",
+                _ => "Negative",
+            };
+            assert_eq!(assignment.classification, expected, "cell {i}");
+        }
+        assert_eq!(assignments[0].global, "Singlet");
+        assert_eq!(assignments[4].max_feature, 1);
+        assert!(assignments[0].margin > 1.0);
+    }
+}
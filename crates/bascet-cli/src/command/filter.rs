@@ -0,0 +1,578 @@
+//! `filter` subcommand: keep the reads (and cells) of a TIRP or FASTQ that match an expression.
+//!
+//! The expression (see `filter::expr` for the grammar) is split at its top-level `&&`. Read
+//! terms such as `len(r1) >= 50 && mean_q(r1) > 20 && cell in cells.txt` become a `Filter` on
+//! the record stream. Cell terms such as `reads >= 100` switch to cell mode: the stream is
+//! grouped by cell id, the read terms drop reads as the cell accumulates, and the cell terms are
+//! evaluated on the accumulated cell. The output has the format of the input: a cell-sorted
+//! TIRP with its histogram, or a BGZF FASTQ for FASTQ input (read terms only). TIRP input can
+//! also be exported to an Arrow IPC or Parquet table with one row per read pair, holding the
+//! TIRP fields and the read metrics; without an expression every read is exported.
+
+mod expr;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use bascet_core::DEFAULT_SIZEOF_ARENA;
+use bascet_core::{
+    attr::{meta::*, quality::*, sequence::*},
+    *,
+};
+use bascet_io::fastq::fastq;
+use bascet_io::{
+    ArrowIpcWriter, BBGZHeader, BBGZMetadata, BBGZWriter, METADATA_SUBFIELD, ParquetWriter, parse,
+    tirp,
+};
+use bounded_integer::BoundedU64;
+use bytesize::ByteSize;
+use clap::Args;
+use serde::Serialize;
+use tracing::info;
+
+use self::expr::{CellView, FilterProgram, Metric, RecordView};
+use super::determine_thread_counts_1;
+use super::getraw::open_fastq_decoder;
+use crate::fileformat::bbgz_input::{input_progress, is_stdin_path, open_bbgz_decoder};
+use crate::fileformat::tirp::get_histogram_path_for_tirp;
+use crate::utils::{ColumnarFormat, atomic_temp_path, publish_atomic_output, write_histogram};
+
+const DEFAULT_SIZEOF_STREAM_BUFFER: ByteSize = ByteSize::gib(1);
+
+/// Id, R1, R2, Q1 and Q2 of a TIRP record, as handed to a query filter
+type ReadFields<'a> = (
+    &'a &'static [u8],
+    &'a &'static [u8],
+    &'a &'static [u8],
+    &'a &'static [u8],
+    &'a &'static [u8],
+);
+
+/// Columns of a TIRP record in columnar output, ahead of the `ReadMetrics`
+type TirpColumns = (Id, R1, R2, Q1, Q2, Umi);
+
+#[derive(Args)]
+pub struct FilterCMD {
+    /// Input cell-sorted TIRP, or FASTQ (any compression). `-` reads a TIRP from stdin.
+    #[arg(short = 'i', long = "in", value_parser)]
+    pub path_in: PathBuf,
+
+    /// Output in the format of the input: TIRP (with a `<out>.hist` histogram), or BGZF FASTQ.
+    /// For TIRP input, a path ending in `.arrow`/`.feather`/`.parquet` writes a table instead.
+    #[arg(short = 'o', long = "out", value_parser)]
+    pub path_out: PathBuf,
+
+    /// Filter expression, e.g. `len(r1) >= 50 && mean_q(r1) > 20 && cell in cells.txt &&
+    /// gc(r2) < 0.7 && reads >= 100`. Read metrics: len, mean_q, gc, n_frac of r1 or r2;
+    /// `reads` counts the passing read pairs of a cell. Combine with &&, ||, ! and parentheses.
+    /// Without an expression every read is kept.
+    #[arg(short = 'e', long = "expr")]
+    pub expr: Option<String>,
+
+    /// Total threads.
+    #[arg(short = '@', long = "threads", value_parser = clap::value_parser!(usize))]
+    pub num_threads: Option<usize>,
+
+    #[arg(
+        long = "sizeof-stream-buffer",
+        help = "Total stream buffer size.",
+        default_value_t = DEFAULT_SIZEOF_STREAM_BUFFER,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_buffer: ByteSize,
+
+    #[arg(
+        long = "sizeof-stream-arena",
+        help = "Stream arena buffer size [Advanced: changing this will impact performance and stability]",
+        hide_short_help = true,
+        default_value_t = DEFAULT_SIZEOF_ARENA,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_arena: ByteSize,
+}
+
+#[derive(Debug, Default)]
+struct FilterStats {
+    records_in: u64,
+    records_out: u64,
+    cells_dropped: u64,
+}
+
+impl FilterCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        let num_threads = determine_thread_counts_1(self.num_threads)?;
+        let is_tirp = is_stdin_path(&self.path_in) || is_tirp_path(&self.path_in);
+        let program = match &self.expr {
+            Some(expr) => FilterProgram::compile(expr, is_tirp)?,
+            None => FilterProgram::default(),
+        };
+        if !is_tirp && program.has_cell_terms() {
+            bail!("Cell terms (`reads`) need a cell-sorted TIRP input, not FASTQ");
+        }
+        if !is_tirp && ColumnarFormat::from_path(&self.path_out).is_some() {
+            bail!("Arrow IPC and Parquet output need a TIRP input, not FASTQ");
+        }
+        info!(
+            input = %self.path_in.display(),
+            output = %self.path_out.display(),
+            expr = self.expr.as_deref().unwrap_or(""),
+            cell_mode = program.has_cell_terms(),
+            "Filter: starting"
+        );
+
+        let stats = if is_tirp {
+            self.filter_tirp(&program, num_threads)?
+        } else {
+            self.filter_fastq(&program, num_threads)?
+        };
+        info!(
+            records_in = stats.records_in,
+            records_out = stats.records_out,
+            cells_dropped = stats.cells_dropped,
+            "Filter: complete"
+        );
+        Ok(())
+    }
+
+    fn filter_tirp(&self, program: &FilterProgram, num_threads: usize) -> Result<FilterStats> {
+        let decoder = open_bbgz_decoder(
+            &self.path_in,
+            BoundedU64::new_saturating(num_threads as u64),
+            None,
+        )?;
+        let parser = parse::Tirp::builder().build();
+        let mut stream = Stream::builder()
+            .with_decoder(decoder)
+            .with_parser(parser)
+            .sizeof_decode_arena(self.sizeof_stream_arena)
+            .sizeof_decode_buffer(self.sizeof_stream_buffer)
+            .build();
+
+        let mut metadata = BBGZMetadata::derive_from_paths(
+            std::iter::once(&self.path_in).filter(|path| !is_stdin_path(path)),
+        );
+        if let Some(expr) = &self.expr {
+            metadata.push("filter", expr.clone());
+        }
+
+        let path_out_tmp = atomic_temp_path(&self.path_out);
+        let file = File::create(&path_out_tmp)
+            .with_context(|| format!("failed to create output {}", path_out_tmp.display()))?;
+        let mut writer = match ColumnarFormat::from_path(&self.path_out) {
+            Some(ColumnarFormat::ArrowIpc) => {
+                TirpOutput::ArrowIpc(ArrowIpcWriter::with::<TirpColumns>(BufWriter::new(file)))
+            }
+            Some(ColumnarFormat::Parquet) => {
+                TirpOutput::Parquet(ParquetWriter::with::<TirpColumns>(BufWriter::new(file)))
+            }
+            None => {
+                let bbgzwriter = BBGZWriter::builder()
+                    .countof_threads(BoundedU64::new_saturating(num_threads as u64))
+                    .with_writer(file)
+                    .build();
+                TirpOutput::Tirp(TirpCellWriter::new(bbgzwriter, metadata.to_bytes()?))
+            }
+        };
+
+        // NOTE:    the stream drops failing records inside the query; count them as they pass by
+        let records_in = std::cell::Cell::new(0u64);
+        let keep_record = |id: &[u8], r1: &[u8], r2: &[u8], q1: &[u8], q2: &[u8]| {
+            records_in.set(records_in.get() + 1);
+            program.keep_record(&RecordView {
+                cell: id,
+                r1,
+                q1,
+                r2,
+                q2,
+            })
+        };
+
+        let mut stats = FilterStats::default();
+        if program.has_cell_terms() {
+            let mut query = stream
+                .query::<tirp::Cell>()
+                .group_relaxed_with_context::<Id, Id, _>(
+                    |id_current: &&'static [u8], id_context: &&'static [u8]| {
+                        if id_current == id_context {
+                            QueryResult::Keep
+                        } else {
+                            QueryResult::Emit
+                        }
+                    },
+                )
+                .filter_with_context::<(Id, R1, R2, Q1, Q2), (Id,), _>(
+                    |(id, r1, r2, q1, q2): ReadFields<'_>, _: (&&'static [u8],)| {
+                        keep_record(*id, *r1, *r2, *q1, *q2)
+                    },
+                );
+
+            while let Some(cell) = query.next().context("failed to read TIRP cell")? {
+                let id = *cell.get_ref::<Id>();
+                let r1 = cell.get_ref::<R1>();
+                let cell_view = CellView {
+                    cell: id,
+                    reads: r1.len() as u64,
+                };
+                if !program.keep_cell(&cell_view) {
+                    stats.cells_dropped += 1;
+                    continue;
+                }
+                for (((r1, r2), (q1, q2)), umi) in r1
+                    .iter()
+                    .zip(cell.get_ref::<R2>())
+                    .zip(cell.get_ref::<Q1>().iter().zip(cell.get_ref::<Q2>()))
+                    .zip(cell.get_ref::<Umi>())
+                {
+                    writer.write(id, [*r1, *r2, *q1, *q2, *umi])?;
+                    stats.records_out += 1;
+                }
+            }
+        } else {
+            let mut query = stream
+                .query::<tirp::Record>()
+                .filter::<(Id, R1, R2, Q1, Q2), _>(|(id, r1, r2, q1, q2): ReadFields<'_>| {
+                    keep_record(*id, *r1, *r2, *q1, *q2)
+                });
+
+            while let Some(record) = query.next().context("failed to read TIRP record")? {
+                writer.write(
+                    *record.get_ref::<Id>(),
+                    [
+                        *record.get_ref::<R1>(),
+                        *record.get_ref::<R2>(),
+                        *record.get_ref::<Q1>(),
+                        *record.get_ref::<Q2>(),
+                        *record.get_ref::<Umi>(),
+                    ],
+                )?;
+                stats.records_out += 1;
+            }
+        }
+
+        writer.finish(&path_out_tmp, &self.path_out)?;
+        stats.records_in = records_in.get();
+        Ok(stats)
+    }
+
+    fn filter_fastq(&self, program: &FilterProgram, num_threads: usize) -> Result<FilterStats> {
+        let decoder = open_fastq_decoder(
+            &self.path_in,
+            None,
+            Some(input_progress(&self.path_in, None)),
+        )?;
+        let parser = parse::Fastq::builder().build();
+        let mut stream = Stream::builder()
+            .with_decoder(decoder)
+            .with_parser(parser)
+            .sizeof_decode_arena(self.sizeof_stream_arena)
+            .sizeof_decode_buffer(self.sizeof_stream_buffer)
+            .build();
+
+        let path_out_tmp = atomic_temp_path(&self.path_out);
+        let file = File::create(&path_out_tmp)
+            .with_context(|| format!("failed to create output {}", path_out_tmp.display()))?;
+        let mut writer = noodles::bgzf::io::MultithreadedWriter::with_worker_count(
+            NonZeroUsize::new(num_threads).unwrap_or(NonZeroUsize::MIN),
+            file,
+        );
+
+        let records_in = std::cell::Cell::new(0u64);
+        let mut query = stream.query::<fastq::Record>().filter::<(Id, R0, Q0), _>(
+            |(id, r0, q0): (&&'static [u8], &&'static [u8], &&'static [u8])| {
+                records_in.set(records_in.get() + 1);
+                program.keep_record(&RecordView {
+                    cell: fastq_cell_id(*id),
+                    r1: r0,
+                    q1: q0,
+                    r2: b"",
+                    q2: b"",
+                })
+            },
+        );
+
+        let mut stats = FilterStats::default();
+        while let Some(record) = query.next().context("failed to read FASTQ record")? {
+            // NOTE:    the parsed id keeps its leading '@', so the header is written back as-is
+            for field in [
+                *record.get_ref::<Id>(),
+                b"\n",
+                *record.get_ref::<R0>(),
+                b"\n+\n",
+                *record.get_ref::<Q0>(),
+                b"\n",
+            ] {
+                writer.write_all(field)?;
+            }
+            stats.records_out += 1;
+        }
+
+        writer.finish()?;
+        publish_atomic_output(&path_out_tmp, &self.path_out)?;
+        stats.records_in = records_in.get();
+        Ok(stats)
+    }
+}
+
+/// Where the TIRP filter writes the records it keeps
+enum TirpOutput {
+    Tirp(TirpCellWriter),
+    ArrowIpc(ArrowIpcWriter<BufWriter<File>, TirpColumns>),
+    Parquet(ParquetWriter<BufWriter<File>, TirpColumns>),
+}
+
+impl TirpOutput {
+    fn write(&mut self, id: &[u8], [r1, r2, q1, q2, umi]: [&[u8]; 5]) -> Result<()> {
+        let row = (id, r1, r2, q1, q2, umi);
+        match self {
+            TirpOutput::Tirp(writer) => writer.write(id, [r1, r2, q1, q2, umi])?,
+            TirpOutput::ArrowIpc(writer) => {
+                writer.write_row_with(&row, &ReadMetrics::new(r1, q1, r2, q2))?
+            }
+            TirpOutput::Parquet(writer) => {
+                writer.write_row_with(&row, &ReadMetrics::new(r1, q1, r2, q2))?
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish the file at `path_tmp` and publish it as `path_out`, with its histogram for TIRP
+    fn finish(self, path_tmp: &Path, path_out: &Path) -> Result<()> {
+        let file = match self {
+            TirpOutput::Tirp(writer) => {
+                let histogram = writer.finish()?;
+                publish_atomic_output(path_tmp, path_out)?;
+                write_histogram(
+                    &get_histogram_path_for_tirp(&path_out.to_path_buf()),
+                    &histogram,
+                )?;
+                return Ok(());
+            }
+            TirpOutput::ArrowIpc(writer) => writer.finish()?,
+            TirpOutput::Parquet(writer) => writer.finish()?,
+        };
+        file.into_inner().map_err(|e| e.into_error())?;
+        publish_atomic_output(path_tmp, path_out)?;
+        Ok(())
+    }
+}
+
+/// Read metrics of a read pair, written after its TIRP fields in columnar output. Named as in
+/// the filter expression
+#[derive(Serialize)]
+struct ReadMetrics {
+    len_r1: u64,
+    len_r2: u64,
+    mean_q_r1: f64,
+    mean_q_r2: f64,
+    gc_r1: f64,
+    gc_r2: f64,
+    n_frac_r1: f64,
+    n_frac_r2: f64,
+}
+
+impl ReadMetrics {
+    fn new(r1: &[u8], q1: &[u8], r2: &[u8], q2: &[u8]) -> Self {
+        Self {
+            len_r1: r1.len() as u64,
+            len_r2: r2.len() as u64,
+            mean_q_r1: Metric::MeanQ.eval(r1, q1),
+            mean_q_r2: Metric::MeanQ.eval(r2, q2),
+            gc_r1: Metric::Gc.eval(r1, q1),
+            gc_r2: Metric::Gc.eval(r2, q2),
+            n_frac_r1: Metric::NFrac.eval(r1, q1),
+            n_frac_r2: Metric::NFrac.eval(r2, q2),
+        }
+    }
+}
+
+/// Writes TIRP records as one block run per cell, like the other cell-sorted writers. Lines are
+/// buffered per cell and written out when the cell ends or the buffer fills
+struct TirpCellWriter {
+    bbgzwriter: BBGZWriter,
+    metadata: Option<Vec<u8>>,
+    current_id: Vec<u8>,
+    buffer: Vec<u8>,
+    line_ends: Vec<usize>,
+    histogram: BTreeMap<Vec<u8>, u64>,
+}
+
+impl TirpCellWriter {
+    const SIZEOF_FLUSH: usize = 4 * 1024 * 1024;
+
+    fn new(bbgzwriter: BBGZWriter, metadata: Vec<u8>) -> Self {
+        Self {
+            bbgzwriter,
+            metadata: Some(metadata),
+            current_id: Vec::new(),
+            buffer: Vec::new(),
+            line_ends: Vec::new(),
+            histogram: BTreeMap::new(),
+        }
+    }
+
+    fn write(&mut self, id: &[u8], [r1, r2, q1, q2, umi]: [&[u8]; 5]) -> Result<()> {
+        if id != self.current_id.as_slice() {
+            self.flush()?;
+            self.current_id = id.to_vec();
+        }
+
+        self.buffer.extend_from_slice(id);
+        for field in [
+            b"\t1\t1\t".as_slice(),
+            r1,
+            b"\t",
+            r2,
+            b"\t",
+            q1,
+            b"\t",
+            q2,
+            b"\t",
+            umi,
+        ] {
+            self.buffer.extend_from_slice(field);
+        }
+        self.buffer.push(b'\n');
+        self.line_ends.push(self.buffer.len());
+        *self.histogram.entry(self.current_id.clone()).or_insert(0) += 1;
+
+        if self.buffer.len() >= Self::SIZEOF_FLUSH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.line_ends.is_empty() {
+            return Ok(());
+        }
+        let mut bbgzheader = BBGZHeader::new();
+        unsafe {
+            bbgzheader.add_extra_unchecked(b"ID", self.current_id.clone());
+        }
+        if let Some(metadata) = self.metadata.take() {
+            bbgzheader
+                .add_extra(&METADATA_SUBFIELD, metadata)
+                .map_err(|_| anyhow::anyhow!("duplicate BBGZ metadata subfield"))?;
+        }
+        let mut blockwriter = self.bbgzwriter.begin(bbgzheader);
+        let mut start = 0;
+        for &end in &self.line_ends {
+            // Reserve space for the entire record to prevent splitting across blocks
+            blockwriter.reserve(end - start);
+            blockwriter.write_all(&self.buffer[start..end])?;
+            start = end;
+        }
+        blockwriter.flush()?;
+        self.buffer.clear();
+        self.line_ends.clear();
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<BTreeMap<Vec<u8>, u64>> {
+        self.flush()?;
+        self.bbgzwriter
+            .finish_async()
+            .join()
+            .map_err(|_| anyhow::anyhow!("BBGZ writer panicked while finishing"))?;
+        Ok(self.histogram)
+    }
+}
+
+fn is_tirp_path(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().contains(".tirp"))
+}
+
+/// Cell id of a bascet FASTQ header `@cell:umi:n`
+fn fastq_cell_id(id: &[u8]) -> &[u8] {
+    let id = id.strip_prefix(b"@").unwrap_or(id);
+    let end = id
+        .iter()
+        .position(|&b| b == b':' || b.is_ascii_whitespace())
+        .unwrap_or(id.len());
+    &id[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_input_format_and_fastq_cell_ids() {
+        assert!(is_tirp_path(Path::new("out/filtered.tirp.gz")));
+        assert!(is_tirp_path(Path::new("chunk_0.tirp.bbgz")));
+        assert!(!is_tirp_path(Path::new("tirp/reads.R1.fq.gz")));
+
+        assert_eq!(fastq_cell_id(b"@A1_B2:ACGT:1"), b"A1_B2");
+        assert_eq!(fastq_cell_id(b"@A1_B2 comment"), b"A1_B2");
+        assert_eq!(fastq_cell_id(b"A1_B2"), b"A1_B2");
+    }
+
+    fn filter_cmd(path_in: &Path, path_out: &Path, expr: &str) -> FilterCMD {
+        FilterCMD {
+            path_in: path_in.to_path_buf(),
+            path_out: path_out.to_path_buf(),
+            expr: Some(expr.to_string()),
+            num_threads: Some(1),
+            sizeof_stream_buffer: ByteSize::mib(16),
+            sizeof_stream_arena: DEFAULT_SIZEOF_ARENA,
+        }
+    }
+
+    #[test]
+    fn filters_reads_and_cells_of_a_real_tirp() {
+        let dir = tempfile::tempdir().unwrap();
+        let path_in = dir.path().join("in.tirp.gz");
+        let bbgzwriter = BBGZWriter::builder()
+            .countof_threads(BoundedU64::new_saturating(1))
+            .with_writer(File::create(&path_in).unwrap())
+            .build();
+        let mut metadata = BBGZMetadata::new();
+        metadata.push("chemistry", "test");
+        let mut writer = TirpCellWriter::new(bbgzwriter, metadata.to_bytes().unwrap());
+        for (id, r1) in [
+            (b"A1".as_slice(), b"ACGT".as_slice()),
+            (b"A1", b"AC"),
+            (b"B2", b"GATTACA"),
+            (b"B2", b"GG"),
+            (b"B2", b"TTTT"),
+            (b"C3", b"CCCC"),
+        ] {
+            let q1 = vec![b'I'; r1.len()];
+            writer.write(id, [r1, b"TT", &q1, b"II", b"U1"]).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let path_out = dir.path().join("out.tirp.gz");
+        filter_cmd(&path_in, &path_out, "len(r1) >= 4 && reads >= 2")
+            .try_execute()
+            .unwrap();
+
+        let mut lines = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::MultiGzDecoder::new(File::open(&path_out).unwrap()),
+            &mut lines,
+        )
+        .unwrap();
+        assert_eq!(
+            lines,
+            "B2\t1\t1\tGATTACA\tTT\tIIIIIII\tII\tU1\nB2\t1\t1\tTTTT\tTT\tIIII\tII\tU1\n"
+        );
+        let histogram = std::fs::read_to_string(get_histogram_path_for_tirp(&path_out)).unwrap();
+        assert_eq!(histogram, "B2\t2\n");
+        let metadata = BBGZMetadata::read_from_path(&path_out).unwrap().unwrap();
+        assert_eq!(metadata.get("chemistry"), Some("test"));
+        assert_eq!(metadata.get("filter"), Some("len(r1) >= 4 && reads >= 2"));
+
+        // NOTE:    a cell term on FASTQ input is rejected before anything is written
+        let path_fastq = dir.path().join("in.fq.gz");
+        let err = filter_cmd(&path_fastq, &dir.path().join("out.fq.gz"), "reads >= 2")
+            .try_execute()
+            .unwrap_err();
+        assert!(err.to_string().contains("cell-sorted TIRP"), "{err:#}");
+    }
+}
@@ -0,0 +1,633 @@
+//! Expression language of `bascet filter`.
+//!
+//! ```text
+//! expr       := and ("||" and)*
+//! and        := unary ("&&" unary)*
+//! unary      := "!" unary | "(" expr ")" | "cell" "in" PATH | value CMP value
+//! value      := NUMBER | "reads" | METRIC "(" ("r1" | "r2") ")"
+//! METRIC     := "len" | "mean_q" | "gc" | "n_frac"
+//! CMP        := "<" | "<=" | ">" | ">=" | "==" | "!="
+//! ```
+//!
+//! Metrics are per read: `mean_q` is the mean Phred quality, `gc` the G+C fraction of the
+//! called (ACGT) bases and `n_frac` the fraction of uncalled bases. `reads` is the number of read
+//! pairs of a cell that pass the record terms, which makes a term cell-level. `cell in PATH`
+//! tests the cell id against a file of ids, one per line.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, Result, bail};
+
+const PHRED_OFFSET: u8 = 33;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mate {
+    R1,
+    R2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    Len,
+    MeanQ,
+    Gc,
+    NFrac,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Number(f64),
+    Metric(Metric, Mate),
+    /// Read pairs of the cell; cell-level
+    Reads,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CmpOp {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Cmp(Value, CmpOp, Value),
+    CellIn(Arc<HashSet<Vec<u8>>>),
+}
+
+/// Where a term can be evaluated: on each record, only on a whole cell, or on either
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Level {
+    Any,
+    Record,
+    Cell,
+}
+
+impl Level {
+    fn join(self, other: Level) -> Option<Level> {
+        match (self, other) {
+            (Level::Any, level) | (level, Level::Any) => Some(level),
+            (a, b) if a == b => Some(a),
+            _ => None,
+        }
+    }
+}
+
+/// One read pair (or single read, for FASTQ) as seen by the record terms
+pub struct RecordView<'a> {
+    pub cell: &'a [u8],
+    pub r1: &'a [u8],
+    pub q1: &'a [u8],
+    pub r2: &'a [u8],
+    pub q2: &'a [u8],
+}
+
+/// One cell as seen by the cell terms, after the record terms dropped its failing reads
+pub struct CellView<'a> {
+    pub cell: &'a [u8],
+    pub reads: u64,
+}
+
+trait Scope {
+    fn cell(&self) -> &[u8];
+    fn value(&self, value: &Value) -> f64;
+}
+
+impl Scope for RecordView<'_> {
+    fn cell(&self) -> &[u8] {
+        self.cell
+    }
+
+    fn value(&self, value: &Value) -> f64 {
+        match value {
+            Value::Number(number) => *number,
+            Value::Metric(metric, Mate::R1) => metric.eval(self.r1, self.q1),
+            Value::Metric(metric, Mate::R2) => metric.eval(self.r2, self.q2),
+            // NOTE:    compile() never puts a cell-level term in the record filter
+            Value::Reads => f64::NAN,
+        }
+    }
+}
+
+impl Scope for CellView<'_> {
+    fn cell(&self) -> &[u8] {
+        self.cell
+    }
+
+    fn value(&self, value: &Value) -> f64 {
+        match value {
+            Value::Number(number) => *number,
+            Value::Reads => self.reads as f64,
+            // NOTE:    compile() never puts a record-level term in the cell filter
+            Value::Metric(..) => f64::NAN,
+        }
+    }
+}
+
+impl Metric {
+    pub fn eval(self, seq: &[u8], qual: &[u8]) -> f64 {
+        match self {
+            Metric::Len => seq.len() as f64,
+            Metric::MeanQ => {
+                if qual.is_empty() {
+                    return 0.0;
+                }
+                let sum: u64 = qual
+                    .iter()
+                    .map(|q| q.saturating_sub(PHRED_OFFSET) as u64)
+                    .sum();
+                sum as f64 / qual.len() as f64
+            }
+            Metric::Gc => {
+                let (gc, acgt) = seq.iter().fold((0u64, 0u64), |(gc, acgt), base| {
+                    match base.to_ascii_uppercase() {
+                        b'G' | b'C' => (gc + 1, acgt + 1),
+                        b'A' | b'T' => (gc, acgt + 1),
+                        _ => (gc, acgt),
+                    }
+                });
+                if acgt == 0 {
+                    0.0
+                } else {
+                    gc as f64 / acgt as f64
+                }
+            }
+            Metric::NFrac => {
+                if seq.is_empty() {
+                    return 0.0;
+                }
+                let ns = seq
+                    .iter()
+                    .filter(|base| !matches!(base.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T'))
+                    .count();
+                ns as f64 / seq.len() as f64
+            }
+        }
+    }
+}
+
+impl CmpOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            CmpOp::Lt => "<",
+            CmpOp::Le => "<=",
+            CmpOp::Gt => ">",
+            CmpOp::Ge => ">=",
+            CmpOp::Eq => "==",
+            CmpOp::Ne => "!=",
+        }
+    }
+
+    fn eval(self, lhs: f64, rhs: f64) -> bool {
+        match self {
+            CmpOp::Lt => lhs < rhs,
+            CmpOp::Le => lhs <= rhs,
+            CmpOp::Gt => lhs > rhs,
+            CmpOp::Ge => lhs >= rhs,
+            CmpOp::Eq => lhs == rhs,
+            CmpOp::Ne => lhs != rhs,
+        }
+    }
+}
+
+impl Value {
+    fn level(&self) -> Level {
+        match self {
+            Value::Number(_) => Level::Any,
+            Value::Metric(..) => Level::Record,
+            Value::Reads => Level::Cell,
+        }
+    }
+
+    fn uses_r2(&self) -> bool {
+        matches!(self, Value::Metric(_, Mate::R2))
+    }
+}
+
+impl Expr {
+    fn eval(&self, scope: &impl Scope) -> bool {
+        match self {
+            Expr::And(lhs, rhs) => lhs.eval(scope) && rhs.eval(scope),
+            Expr::Or(lhs, rhs) => lhs.eval(scope) || rhs.eval(scope),
+            Expr::Not(inner) => !inner.eval(scope),
+            Expr::Cmp(lhs, op, rhs) => op.eval(scope.value(lhs), scope.value(rhs)),
+            Expr::CellIn(cells) => cells.contains(scope.cell()),
+        }
+    }
+
+    /// None if the expression mixes record and cell terms below a `||` or `!`
+    fn level(&self) -> Option<Level> {
+        match self {
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => lhs.level()?.join(rhs.level()?),
+            Expr::Not(inner) => inner.level(),
+            Expr::Cmp(lhs, _, rhs) => lhs.level().join(rhs.level()),
+            Expr::CellIn(_) => Some(Level::Any),
+        }
+    }
+
+    fn uses_r2(&self) -> bool {
+        match self {
+            Expr::And(lhs, rhs) | Expr::Or(lhs, rhs) => lhs.uses_r2() || rhs.uses_r2(),
+            Expr::Not(inner) => inner.uses_r2(),
+            Expr::Cmp(lhs, _, rhs) => lhs.uses_r2() || rhs.uses_r2(),
+            Expr::CellIn(_) => false,
+        }
+    }
+
+    fn into_conjuncts(self, conjuncts: &mut Vec<Expr>) {
+        match self {
+            Expr::And(lhs, rhs) => {
+                lhs.into_conjuncts(conjuncts);
+                rhs.into_conjuncts(conjuncts);
+            }
+            expr => conjuncts.push(expr),
+        }
+    }
+}
+
+///////////////////////////////
+/// A filter expression split at its top-level `&&` into the terms applied to every record and
+/// the terms applied to every cell once its records are filtered
+#[derive(Debug, Default)]
+pub struct FilterProgram {
+    record: Vec<Expr>,
+    cell: Vec<Expr>,
+}
+
+impl FilterProgram {
+    /// Parse `text`. `paired` is false for single-end input, where `r2` is an error
+    pub fn compile(text: &str, paired: bool) -> Result<Self> {
+        let expr = Parser::new(text)?.parse()?;
+        if !paired && expr.uses_r2() {
+            bail!("The input has no R2; `r2` cannot be used in the filter expression");
+        }
+
+        let mut conjuncts = Vec::new();
+        expr.into_conjuncts(&mut conjuncts);
+        let mut program = Self::default();
+        for conjunct in conjuncts {
+            match conjunct.level() {
+                Some(Level::Cell) => program.cell.push(conjunct),
+                Some(Level::Any | Level::Record) => program.record.push(conjunct),
+                None => bail!(
+                    "Read terms and cell terms (`reads`) cannot be combined with `||` or `!`; \
+                    join them with a top-level `&&` instead"
+                ),
+            }
+        }
+        Ok(program)
+    }
+
+    pub fn has_record_terms(&self) -> bool {
+        !self.record.is_empty()
+    }
+
+    pub fn has_cell_terms(&self) -> bool {
+        !self.cell.is_empty()
+    }
+
+    pub fn keep_record(&self, record: &RecordView<'_>) -> bool {
+        self.record.iter().all(|expr| expr.eval(record))
+    }
+
+    pub fn keep_cell(&self, cell: &CellView<'_>) -> bool {
+        self.cell.iter().all(|expr| expr.eval(cell))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    LParen,
+    RParen,
+    Not,
+    And,
+    Or,
+    Cmp(CmpOp),
+    Word(String),
+    Quoted(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::LParen => write!(f, "`(`"),
+            Token::RParen => write!(f, "`)`"),
+            Token::Not => write!(f, "`!`"),
+            Token::And => write!(f, "`&&`"),
+            Token::Or => write!(f, "`||`"),
+            Token::Cmp(op) => write!(f, "`{}`", op.symbol()),
+            Token::Word(word) => write!(f, "`{word}`"),
+            Token::Quoted(text) => write!(f, "\"{text}\""),
+        }
+    }
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        chars.next();
+        let token = match (c, chars.peek().copied()) {
+            ('(', _) => Token::LParen,
+            (')', _) => Token::RParen,
+            ('&', Some('&')) => {
+                chars.next();
+                Token::And
+            }
+            ('|', Some('|')) => {
+                chars.next();
+                Token::Or
+            }
+            ('!', Some('=')) | ('=', Some('=')) | ('<', Some('=')) | ('>', Some('=')) => {
+                chars.next();
+                Token::Cmp(match c {
+                    '!' => CmpOp::Ne,
+                    '=' => CmpOp::Eq,
+                    '<' => CmpOp::Le,
+                    _ => CmpOp::Ge,
+                })
+            }
+            ('!', _) => Token::Not,
+            ('<', _) => Token::Cmp(CmpOp::Lt),
+            ('>', _) => Token::Cmp(CmpOp::Gt),
+            ('"' | '\'', _) => {
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some(end) if end == c => break,
+                        Some(other) => quoted.push(other),
+                        None => bail!("Unterminated quote in filter expression"),
+                    }
+                }
+                Token::Quoted(quoted)
+            }
+            ('&' | '|' | '=', _) => bail!("Unexpected `{c}` in filter expression"),
+            _ => {
+                let mut word = String::from(c);
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || "()!<>=&|\"'".contains(next) {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(text: &str) -> Result<Self> {
+        Ok(Self {
+            tokens: tokenize(text)?,
+            pos: 0,
+        })
+    }
+
+    fn parse(mut self) -> Result<Expr> {
+        if self.tokens.is_empty() {
+            bail!("The filter expression is empty");
+        }
+        let expr = self.parse_or()?;
+        if let Some(token) = self.next() {
+            bail!("Unexpected {token} after the end of the filter expression");
+        }
+        Ok(expr)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => bail!("Expected {expected} in filter expression, found {token}"),
+            None => bail!("Expected {expected} at the end of the filter expression"),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.pos += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.parse_unary()?)))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let expr = self.parse_or()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            }
+            Some(Token::Word(word))
+                if word == "cell"
+                    && matches!(self.tokens.get(self.pos + 1), Some(Token::Word(w)) if w == "in") =>
+            {
+                self.pos += 2;
+                match self.next() {
+                    Some(Token::Word(path) | Token::Quoted(path)) => {
+                        Ok(Expr::CellIn(Arc::new(load_cell_list(Path::new(&path))?)))
+                    }
+                    Some(token) => {
+                        bail!("Expected a cell list file after `cell in`, found {token}")
+                    }
+                    None => bail!("Expected a cell list file after `cell in`"),
+                }
+            }
+            _ => {
+                let lhs = self.parse_value()?;
+                let op = match self.next() {
+                    Some(Token::Cmp(op)) => op,
+                    Some(token) => bail!("Expected a comparison operator, found {token}"),
+                    None => {
+                        bail!("Expected a comparison operator at the end of the filter expression")
+                    }
+                };
+                let rhs = self.parse_value()?;
+                Ok(Expr::Cmp(lhs, op, rhs))
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        let word = match self.next() {
+            Some(Token::Word(word)) => word,
+            Some(token) => bail!("Expected a number, `reads` or a read metric, found {token}"),
+            None => bail!(
+                "Expected a number, `reads` or a read metric at the end of the filter expression"
+            ),
+        };
+        let metric = match word.as_str() {
+            "reads" => return Ok(Value::Reads),
+            "len" => Metric::Len,
+            "mean_q" => Metric::MeanQ,
+            "gc" => Metric::Gc,
+            "n_frac" => Metric::NFrac,
+            _ => {
+                return word.parse::<f64>().map(Value::Number).map_err(|_| {
+                    anyhow::anyhow!(
+                        "Unknown term `{word}` in filter expression; expected a number, `reads`, \
+                        `cell in <file>` or one of len, mean_q, gc, n_frac"
+                    )
+                });
+            }
+        };
+        self.expect(Token::LParen)?;
+        let mate = match self.next() {
+            Some(Token::Word(mate)) if mate == "r1" => Mate::R1,
+            Some(Token::Word(mate)) if mate == "r2" => Mate::R2,
+            Some(token) => bail!("Expected `r1` or `r2` as argument of `{word}`, found {token}"),
+            None => bail!("Expected `r1` or `r2` as argument of `{word}`"),
+        };
+        self.expect(Token::RParen)?;
+        Ok(Value::Metric(metric, mate))
+    }
+}
+
+/// Cell ids, one per line. Blank lines are ignored
+fn load_cell_list(path: &Path) -> Result<HashSet<Vec<u8>>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read cell list {}", path.display()))?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| line.as_bytes().to_vec())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record<'a>(cell: &'a [u8], r1: &'a [u8], q1: &'a [u8]) -> RecordView<'a> {
+        RecordView {
+            cell,
+            r1,
+            q1,
+            r2: b"",
+            q2: b"",
+        }
+    }
+
+    #[test]
+    fn record_terms_evaluate_read_metrics() {
+        let program =
+            FilterProgram::compile("len(r1) >= 4 && mean_q(r1) > 20 && gc(r1) < 0.7", true)
+                .unwrap();
+        assert!(!program.has_cell_terms());
+
+        // Phred 40 and GC 0.5
+        assert!(program.keep_record(&record(b"A", b"ACGT", b"IIII")));
+        // too short
+        assert!(!program.keep_record(&record(b"A", b"ACG", b"III")));
+        // Phred 0
+        assert!(!program.keep_record(&record(b"A", b"ACGT", b"!!!!")));
+        // GC 1.0
+        assert!(!program.keep_record(&record(b"A", b"GCGC", b"IIII")));
+    }
+
+    #[test]
+    fn precedence_negation_and_parentheses() {
+        let program = FilterProgram::compile("!(len(r1) < 2) || len(r1) == 0", true).unwrap();
+        assert!(program.keep_record(&record(b"A", b"AC", b"II")));
+        assert!(program.keep_record(&record(b"A", b"", b"")));
+        assert!(!program.keep_record(&record(b"A", b"A", b"I")));
+
+        // && binds tighter than ||
+        let program =
+            FilterProgram::compile("len(r1) > 5 || len(r1) > 1 && len(r1) < 3", true).unwrap();
+        assert!(program.keep_record(&record(b"A", b"AC", b"II")));
+        assert!(!program.keep_record(&record(b"A", b"ACGT", b"IIII")));
+    }
+
+    #[test]
+    fn cell_terms_split_from_record_terms() {
+        let dir = tempfile::tempdir().unwrap();
+        let path_cells = dir.path().join("cells.txt");
+        std::fs::write(&path_cells, "A\n\nB\n").unwrap();
+
+        let text = format!(
+            "cell in {} && reads >= 2 && reads <= 3 && n_frac(r2) < 0.5",
+            path_cells.display()
+        );
+        let program = FilterProgram::compile(&text, true).unwrap();
+        assert!(program.has_record_terms());
+        assert!(program.has_cell_terms());
+
+        assert!(program.keep_record(&record(b"B", b"", b"")));
+        assert!(!program.keep_record(&record(b"C", b"", b"")));
+        assert!(program.keep_cell(&CellView {
+            cell: b"A",
+            reads: 2
+        }));
+        assert!(!program.keep_cell(&CellView {
+            cell: b"A",
+            reads: 4
+        }));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for text in [
+            "",
+            "len(r1)",
+            "len(r3) > 1",
+            "foo > 1",
+            "len(r1) > 1 &&",
+            "(len(r1) > 1",
+            "len(r1) > 1 )",
+            "len(r1) = 1",
+            "reads > 1 || len(r1) > 1",
+        ] {
+            assert!(FilterProgram::compile(text, true).is_err(), "{text}");
+        }
+        assert!(FilterProgram::compile("len(r2) > 1", false).is_err());
+        assert!(FilterProgram::compile("cell in /nonexistent/cells.txt", true).is_err());
+    }
+}
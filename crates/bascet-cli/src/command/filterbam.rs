@@ -21,8 +21,8 @@ const BAM_FLAG_SEGMENTED: u16 = 0x1;
 const BAM_FLAG_UNMAPPED: u16 = 0x4;
 const BAM_FLAG_FIRST_SEGMENT: u16 = 0x40;
 const BAM_FLAG_LAST_SEGMENT: u16 = 0x80;
-const DEFAULT_MIN_MATCHING: u32 = 0;
-const DEFAULT_MIN_MATCHING_PERCENT: u8 = 90;
+pub const DEFAULT_MIN_MATCHING: u32 = 0;
+pub const DEFAULT_MIN_MATCHING_PERCENT: u8 = 90;
 
 #[derive(Clone, Copy, Debug)]
 pub struct AlignmentFilter {
@@ -30,6 +30,23 @@ pub struct AlignmentFilter {
     pub min_matching_percent: u8,
 }
 
+impl AlignmentFilter {
+    /// Whether a mapped read with `matching_bases` CIGAR M bases out of `read_len` counts as
+    /// aligned: at least `min_matching` bases (when non-zero) or `min_matching_percent` of the read.
+    pub fn passes(&self, matching_bases: u32, read_len: u32) -> bool {
+        let passes_absolute = self.min_matching > 0 && matching_bases >= self.min_matching;
+        let passes_percent = if self.min_matching_percent == 0 {
+            true
+        } else {
+            let required =
+                (read_len as u64 * self.min_matching_percent as u64).div_ceil(100) as u32;
+            matching_bases >= required
+        };
+
+        passes_absolute || passes_percent
+    }
+}
+
 #[derive(Clone, Copy, Debug)]
 struct FilterBamMemoryPlan {
     output_buffer_size: usize,
@@ -309,17 +326,7 @@ fn is_record_aligned(record: &bam::Record, filter: AlignmentFilter) -> bool {
         return false;
     }
 
-    let matching_bases = count_matching_bases(record);
-    let passes_absolute = filter.min_matching > 0 && matching_bases >= filter.min_matching;
-    let passes_percent = if filter.min_matching_percent == 0 {
-        true
-    } else {
-        let read_len = record.l_seq().max(0) as u32;
-        let required = (read_len as u64 * filter.min_matching_percent as u64).div_ceil(100) as u32;
-        matching_bases >= required
-    };
-
-    passes_absolute || passes_percent
+    filter.passes(count_matching_bases(record), record.l_seq().max(0) as u32)
 }
 
 fn count_matching_bases(record: &bam::Record) -> u32 {
@@ -1,5 +1,5 @@
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Cursor, Read};
+use std::io::{BufReader, BufWriter};
 use std::path::PathBuf;
 use std::sync::{
     Arc, Mutex,
@@ -9,12 +9,18 @@ use std::thread;
 use std::time::{Duration, Instant};
 
 use anyhow::{Context, Result, bail};
+use bascet_core::{
+    Composite,
+    attr::{meta::Id, sequence::R0},
+};
+use bascet_io::parse::fasta;
 use clap::Args;
 use crossbeam::channel::{Receiver, RecvTimeoutError, Sender};
 use gecco::{Gecco, orf::SeqRecord};
 use tracing::info;
 use zip::{ZipWriter, read::ZipArchive};
 
+use crate::fileformat::zip::{new_fasta_arena_pool, stream_fasta_entry};
 use crate::utils::{atomic_temp_path, publish_atomic_output};
 
 #[derive(Args)]
@@ -118,7 +124,7 @@ fn available_threads() -> usize {
 
 struct CellContigs {
     cell_id: String,
-    contigs: Vec<u8>,
+    records: Vec<SeqRecord>,
 }
 
 struct CellContigEntry {
@@ -176,7 +182,7 @@ fn run_gecco_zip(
                 }
                 let result = cell.and_then(|cell| {
                     info!("gecco worker {} processing {}", worker_id, cell.cell_id);
-                    let records = read_fasta_records(&cell.contigs, &cell.cell_id)?;
+                    let records = cell.records;
                     let record_count = records.len();
                     let base_count = records.iter().map(|record| record.seq.len()).sum();
                     {
@@ -338,18 +344,23 @@ fn read_zip_cells(
     });
 
     let num_cells = entries.len();
+    let arena_pool = new_fasta_arena_pool();
     for entry in entries {
         if cancel.load(Ordering::Acquire) {
             bail!("stopping GECCO input reader because a worker failed");
         }
-        let mut file = archive.by_index(entry.index)?;
-        let mut contigs = Vec::new();
-        file.read_to_end(&mut contigs)
-            .with_context(|| format!("failed to read {}/contigs.fa", entry.cell_id))?;
+        let reader = File::open(&path_in)
+            .with_context(|| format!("failed to open input zip {}", path_in.display()))?;
+        let mut records = Vec::new();
+        stream_fasta_entry(&mut archive, reader, &arena_pool, entry.index, |record| {
+            records.push(seq_record(record));
+            Ok(())
+        })
+        .with_context(|| format!("failed to parse FASTA for cell {}", entry.cell_id))?;
         tx_cells
             .send(Ok(CellContigs {
                 cell_id: entry.cell_id,
-                contigs,
+                records,
             }))
             .context("failed to send contigs to gecco workers")?;
     }
@@ -362,18 +373,16 @@ fn contigs_cell_id(path: &str) -> Option<&str> {
     path.strip_suffix("/contigs.fa")
 }
 
-fn read_fasta_records(contigs: &[u8], cell_id: &str) -> Result<Vec<SeqRecord>> {
-    let reader = bio::io::fasta::Reader::new(Cursor::new(contigs));
-    let mut records = Vec::new();
-    for record in reader.records() {
-        let record =
-            record.with_context(|| format!("failed to parse FASTA for cell {}", cell_id))?;
-        records.push(SeqRecord {
-            id: record.id().to_string(),
-            seq: String::from_utf8_lossy(record.seq()).into_owned(),
-        });
+fn seq_record(record: &fasta::Record) -> SeqRecord {
+    let id = record
+        .get_ref::<Id>()
+        .split(|b| b.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default();
+    SeqRecord {
+        id: String::from_utf8_lossy(id).into_owned(),
+        seq: String::from_utf8_lossy(record.get_ref::<R0>()).into_owned(),
     }
-    Ok(records)
 }
 
 struct GeccoReports {
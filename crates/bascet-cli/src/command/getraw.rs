@@ -73,18 +73,18 @@
 // blocking and I/O progress.
 
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::{BufWriter, Read, Write};
+use std::io::Write;
 use std::mem::MaybeUninit;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
 #[cfg(all(target_os = "linux", target_env = "gnu"))]
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use anyhow::Context;
 use bascet_io::fastq::fastq;
 use bascet_io::tirp::tirp;
 use bascet_io::{BBGZFinishHandle, BBGZWriteBlock, Compression};
@@ -99,30 +99,32 @@ use bascet_core::attr::{meta::*, quality::*, sequence::*};
 use bascet_core::*;
 use bascet_derive::Budget;
 use bascet_io::{
-    BBGZCompressionLimiter, BBGZHeader, BBGZWriter,
+    BBGZCompressionLimiter, BBGZHeader, BBGZMetadata, BBGZWriter, BlockFormat, METADATA_SUBFIELD,
     codec::{self, bbgz},
     parse,
 };
+use bascet_runtime::budget::{InFlightLimiter, MemoryLimiter, MemoryPermit};
+use bascet_runtime::report::{Progress, RunReport};
 use serde::Serialize;
 use smallvec::{SmallVec, ToSmallVec};
 
 use crate::barcode::atrandi_wgs_barcode_illumina::DebarcodeAtrandiWGSChemistryIllumina;
 use crate::barcode::atrandi_wgs_barcode_longread::DebarcodeAtrandiWGSChemistryLongread;
 use crate::barcode::{Chemistry, ParseBioChemistry3, TenxRNAChemistry};
-use crate::command::shardify::ShardifyCMD;
-use crate::utils::{atomic_temp_path, publish_atomic_output, rename_or_copy_across_filesystems};
+use crate::command::shardify::{ShardifyCMD, shard_output_format};
+use crate::fileformat::fastq_input::{FastqInputDecoder, FastqInputFormat};
+use crate::fileformat::sample_sheet::{SampleSheet, SampleSheetRow};
+use crate::utils::{rename_or_copy_across_filesystems, write_histogram_or_table};
 use crate::{bbgz_compression_parser, bounded_parser};
 use tracing::{debug, error, info, warn};
 
-const FASTQ_INPUT_EXTENSIONS: &[&str] = &[".fastq.gz", ".fq.gz"];
-
 #[derive(Args)]
 pub struct GetRawCMD {
     #[arg(
         short = '1',
         long = "r1",
         value_delimiter = ',',
-        help = "List of input R1 FASTQ files (comma-separated)"
+        help = "List of input R1 files (comma-separated). FASTQ (plain, gzip, bgzip, zstd, bzip2) or unaligned BAM, detected from content"
     )]
     pub paths_r1: Vec<InputPath>,
 
@@ -130,22 +132,28 @@ pub struct GetRawCMD {
         short = '2',
         long = "r2",
         value_delimiter = ',',
-        help = "List of input R2 FASTQ files (comma-separated)"
+        help = "List of input R2 files (comma-separated), same formats as R1"
     )]
     pub paths_r2: Vec<InputPath>,
 
+    #[arg(
+        long = "interleaved",
+        help = "R1 inputs hold both mates, alternating R1/R2 (interleaved FASTQ or paired unaligned BAM)"
+    )]
+    pub interleaved: bool,
+
     #[arg(
         short = 'o',
         long = "out",
         value_delimiter = ',',
-        help = "List of output file paths (comma-separated)"
+        help = "List of output file paths (comma-separated). Outputs ending in .zst are written as seekable zstd instead of BBGZ"
     )]
     pub paths_out: Vec<OutputPath>,
 
     #[arg(
         long = "hist",
         value_delimiter = ',',
-        help = "Histogram file paths. Defaults to <path_out>.hist"
+        help = "Histogram file paths. Defaults to <path_out>.hist; .arrow/.feather/.parquet paths are written as cell/count tables"
     )]
     pub paths_hist: Option<Vec<OutputPath>>,
 
@@ -251,11 +259,26 @@ pub struct GetRawCMD {
     )]
     countof_merge_streams: Option<BoundedU64<2, { u64::MAX }>>,
 
+    #[arg(
+        long = "sample-sheet",
+        conflicts_with_all = ["paths_r1", "paths_r2", "library", "skip_debarcode"],
+        help = "CSV with columns sample, r1 and optionally library, chemistry, subchemistry, r2, plus free metadata columns. Debarcodes every library in one run; the chemistry subcommand applies to rows without a chemistry"
+    )]
+    pub path_sample_sheet: Option<PathBuf>,
+
+    #[arg(
+        long = "sample-out-dir",
+        requires = "path_sample_sheet",
+        conflicts_with = "paths_out",
+        help = "With --sample-sheet, write <dir>/<sample>.tirp.gz per sample instead of one combined output"
+    )]
+    pub path_sample_out_dir: Option<PathBuf>,
+
     #[command(subcommand)]
     pub chemistry: GetRawChemistryCMD,
 }
 
-#[derive(Subcommand)]
+#[derive(Subcommand, Clone)]
 pub enum GetRawChemistryCMD {
     /// AtrandiWGS chemistry, uses combinatorial 8bp barcodes for debarcoding -- short read for illumina, paired end
     AtrandiWGS,
@@ -274,6 +297,45 @@ pub enum GetRawChemistryCMD {
     Tenx {},
 }
 
+impl GetRawChemistryCMD {
+    /// Name as given on the command line and in sample sheets
+    pub fn name(&self) -> &'static str {
+        match self {
+            GetRawChemistryCMD::AtrandiWGS => "atrandi-wgs",
+            GetRawChemistryCMD::AtrandiWGSLR => "atrandi-wgslr",
+            GetRawChemistryCMD::ParseBio { .. } => "parse-bio",
+            GetRawChemistryCMD::Tenx {} => "tenx",
+        }
+    }
+
+    pub fn to_chemistry(&self) -> GetRawChemistry {
+        match self {
+            GetRawChemistryCMD::AtrandiWGS { .. } => {
+                GetRawChemistry::AtrandiWGS(DebarcodeAtrandiWGSChemistryIllumina::new())
+            }
+            GetRawChemistryCMD::AtrandiWGSLR { .. } => {
+                GetRawChemistry::AtrandiWGSLR(DebarcodeAtrandiWGSChemistryLongread::new())
+            }
+            GetRawChemistryCMD::ParseBio { subchemistry, .. } => {
+                GetRawChemistry::ParseBio(ParseBioChemistry3::new(&subchemistry))
+            }
+            GetRawChemistryCMD::Tenx { .. } => GetRawChemistry::Tenx(TenxRNAChemistry::new()),
+        }
+    }
+
+    pub fn from_name(name: &str, subchemistry: Option<&str>) -> Option<GetRawChemistryCMD> {
+        match name.to_ascii_lowercase().as_str() {
+            "atrandi-wgs" => Some(GetRawChemistryCMD::AtrandiWGS),
+            "atrandi-wgslr" => Some(GetRawChemistryCMD::AtrandiWGSLR),
+            "parse-bio" => Some(GetRawChemistryCMD::ParseBio {
+                subchemistry: subchemistry.unwrap_or("").to_string(),
+            }),
+            "tenx" => Some(GetRawChemistryCMD::Tenx {}),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 #[enum_dispatch::enum_dispatch(Chemistry)]
 pub enum GetRawChemistry {
@@ -324,170 +386,6 @@ where
         .unwrap()
 }
 
-struct ReadMemoryLimiter {
-    cap: usize,
-    used: Mutex<usize>,
-    available: Condvar,
-    wait_count: AtomicUsize,
-    max_used: AtomicUsize,
-}
-
-impl ReadMemoryLimiter {
-    fn new(cap: usize) -> Self {
-        Self {
-            cap,
-            used: Mutex::new(0),
-            available: Condvar::new(),
-            wait_count: AtomicUsize::new(0),
-            max_used: AtomicUsize::new(0),
-        }
-    }
-
-    fn acquire(self: &Arc<Self>, bytes: usize) -> ReadMemoryPermit {
-        if bytes == 0 {
-            return ReadMemoryPermit::new(bytes, Arc::clone(self));
-        }
-
-        let charge = bytes.min(self.cap);
-        let mut used = self.used.lock().unwrap();
-        while *used + charge > self.cap {
-            self.wait_count.fetch_add(1, Ordering::Relaxed);
-            used = self.available.wait(used).unwrap();
-        }
-        *used += charge;
-        self.max_used.fetch_max(*used, Ordering::Relaxed);
-
-        ReadMemoryPermit::new(charge, Arc::clone(self))
-    }
-
-    fn release(&self, bytes: usize) {
-        if bytes == 0 {
-            return;
-        }
-
-        let mut used = self.used.lock().unwrap();
-        *used = used.saturating_sub(bytes);
-        self.available.notify_all();
-    }
-
-    fn stats(&self) -> (usize, usize, usize) {
-        let used = *self.used.lock().unwrap();
-        let max_used = self.max_used.load(Ordering::Relaxed);
-        let wait_count = self.wait_count.load(Ordering::Relaxed);
-        (used, max_used, wait_count)
-    }
-}
-
-struct InFlightLimiter {
-    cap: usize,
-    available: Mutex<usize>,
-    ready: Condvar,
-}
-
-impl InFlightLimiter {
-    fn new(cap: usize) -> Self {
-        let cap = cap.max(1);
-        Self {
-            cap,
-            available: Mutex::new(cap),
-            ready: Condvar::new(),
-        }
-    }
-
-    fn acquire(self: &Arc<Self>) -> InFlightPermit {
-        let mut available = self.available.lock().unwrap();
-        while *available == 0 {
-            available = self.ready.wait(available).unwrap();
-        }
-        *available -= 1;
-
-        InFlightPermit {
-            limiter: Arc::clone(self),
-        }
-    }
-
-    fn release(&self) {
-        let mut available = self.available.lock().unwrap();
-        *available += 1;
-        self.ready.notify_one();
-    }
-
-    fn stats(&self) -> (usize, usize) {
-        let available = *self.available.lock().unwrap();
-        (self.cap.saturating_sub(available), self.cap)
-    }
-}
-
-struct InFlightPermit {
-    limiter: Arc<InFlightLimiter>,
-}
-
-impl Drop for InFlightPermit {
-    fn drop(&mut self) {
-        self.limiter.release();
-    }
-}
-
-struct ByteLimiter {
-    cap: usize,
-    used: Mutex<usize>,
-    available: Condvar,
-    wait_count: AtomicUsize,
-    max_used: AtomicUsize,
-}
-
-impl ByteLimiter {
-    fn new(cap: usize) -> Self {
-        Self {
-            cap: cap.max(1),
-            used: Mutex::new(0),
-            available: Condvar::new(),
-            wait_count: AtomicUsize::new(0),
-            max_used: AtomicUsize::new(0),
-        }
-    }
-
-    fn acquire(self: &Arc<Self>, bytes: usize) -> BytePermit {
-        let charge = bytes.max(1).min(self.cap);
-        let mut used = self.used.lock().unwrap();
-        while *used + charge > self.cap {
-            self.wait_count.fetch_add(1, Ordering::Relaxed);
-            used = self.available.wait(used).unwrap();
-        }
-        *used += charge;
-        self.max_used.fetch_max(*used, Ordering::Relaxed);
-
-        BytePermit {
-            bytes: charge,
-            limiter: Arc::clone(self),
-        }
-    }
-
-    fn release(&self, bytes: usize) {
-        let mut used = self.used.lock().unwrap();
-        *used = used.saturating_sub(bytes);
-        self.available.notify_all();
-    }
-
-    fn stats(&self) -> (usize, usize, usize) {
-        let used = *self.used.lock().unwrap();
-        let max_used = self.max_used.load(Ordering::Relaxed);
-        let wait_count = self.wait_count.load(Ordering::Relaxed);
-        (used, max_used, wait_count)
-    }
-}
-
-struct BytePermit {
-    bytes: usize,
-    limiter: Arc<ByteLimiter>,
-}
-
-impl Drop for BytePermit {
-    fn drop(&mut self) {
-        self.limiter.release(self.bytes);
-    }
-}
-
 struct PipelineTicker {
     last: Instant,
     interval: Duration,
@@ -567,26 +465,17 @@ impl MallocTrimGuard {
     }
 }
 
-struct ReadMemoryPermitInner {
-    bytes: usize,
-    limiter: Arc<ReadMemoryLimiter>,
-}
-
-impl Drop for ReadMemoryPermitInner {
-    fn drop(&mut self) {
-        self.limiter.release(self.bytes);
-    }
-}
-
+/// Read memory held by a record, shared by the records of one reader batch and merged when
+/// the R1 and R2 records are paired
 #[derive(Clone)]
 struct ReadMemoryPermit {
-    permits: SmallVec<[Arc<ReadMemoryPermitInner>; 2]>,
+    permits: SmallVec<[Arc<MemoryPermit>; 2]>,
 }
 
 impl ReadMemoryPermit {
-    fn new(bytes: usize, limiter: Arc<ReadMemoryLimiter>) -> Self {
+    fn new(permit: MemoryPermit) -> Self {
         Self {
-            permits: smallvec::smallvec![Arc::new(ReadMemoryPermitInner { bytes, limiter })],
+            permits: smallvec::smallvec![Arc::new(permit)],
         }
     }
 
@@ -647,12 +536,12 @@ type SortedRecordBatch = Vec<DebarcodedSortRecord>;
 
 struct SortChunk {
     records: SortRecordBatch,
-    permit: BytePermit,
+    permit: MemoryPermit,
 }
 
 struct SortedChunk {
     records: SortedRecordBatch,
-    permit: BytePermit,
+    permit: MemoryPermit,
 }
 
 type HistogramCounts = BTreeMap<Vec<u8>, u64>;
@@ -779,6 +668,20 @@ impl GetRawStageTimings {
     }
 
     fn log_summary(&self) {
+        // NOTE:    read, debarcode and collect are summed over their worker threads
+        for (stage, counter) in [
+            ("read", &self.read_nanos),
+            ("debarcode", &self.debarcode_nanos),
+            ("collect", &self.collect_nanos),
+            ("sort", &self.sort_nanos),
+            ("write", &self.write_nanos),
+            ("merge", &self.merge_nanos),
+            ("publish", &self.publish_nanos),
+            ("histogram", &self.histogram_nanos),
+        ] {
+            RunReport::add_stage_time(stage, Self::load_duration(counter));
+        }
+
         info!(
             read = ?Self::load_duration(&self.read_nanos),
             debarcode = ?Self::load_duration(&self.debarcode_nanos),
@@ -799,7 +702,7 @@ fn estimate_fastq_record_bytes(record: &fastq::Record) -> usize {
 
 fn budget_fastq_record_batch(
     records: Vec<(fastq::Record, usize)>,
-    read_memory_limiter: &Arc<ReadMemoryLimiter>,
+    read_memory_limiter: &Arc<MemoryLimiter>,
 ) -> BudgetedFastqRecordBatch {
     let mut current_permit = None;
     let mut batch = Vec::with_capacity(records.len());
@@ -811,7 +714,7 @@ fn budget_fastq_record_batch(
             } else {
                 retained_bytes
             };
-            current_permit = Some(read_memory_limiter.acquire(bytes));
+            current_permit = Some(ReadMemoryPermit::new(read_memory_limiter.acquire(bytes)));
         }
         let permit = current_permit
             .as_ref()
@@ -935,10 +838,270 @@ fn radix_sort_debarcoded_records(records: &mut Vec<DebarcodedSortRecord>) {
     );
 }
 
+/// State shared by every library debarcoded in one invocation: they run one after another on
+/// the same budget, Rayon pool and limiters
+struct GetRawRun {
+    budget: GetrawBudget,
+    timestamp_temp_files: String,
+    read_memory_limiter: Arc<MemoryLimiter>,
+    sort_memory_limiter: Arc<MemoryLimiter>,
+    batch_stats: Arc<GetRawBatchStats>,
+    stage_timings: Arc<GetRawStageTimings>,
+    progress: Arc<Progress>,
+    rayon_pool: Arc<rayon::ThreadPool>,
+    compression_limiter: Arc<BBGZCompressionLimiter>,
+    debarcode_inflight_limiter: Arc<InFlightLimiter>,
+    _malloc_trim_guard: MallocTrimGuard,
+}
+
+/// Chemistry, R1 and R2 inputs of one sample sheet library
+type SampleSheetLibrary = (GetRawChemistryCMD, Vec<InputPath>, Vec<InputPath>);
+
+/// Libraries going into one output set, with its outputs and histogram paths
+type SampleSheetOutput<'a> = (
+    Vec<&'a SampleSheetRow>,
+    Vec<OutputPath>,
+    Option<Vec<OutputPath>>,
+);
+
+impl GetRawRun {
+    fn log_limiter_summary(&self) {
+        let read_memory = self.read_memory_limiter.stats();
+        info!(
+            read_memory_used = %ByteSize(read_memory.used as u64),
+            read_memory_max_used = %ByteSize(read_memory.max_used as u64),
+            read_memory_wait_count = read_memory.wait_count,
+            "Read memory limiter summary"
+        );
+        let sort_memory = self.sort_memory_limiter.stats();
+        info!(
+            sort_memory_used = %ByteSize(sort_memory.used as u64),
+            sort_memory_max_used = %ByteSize(sort_memory.max_used as u64),
+            sort_memory_wait_count = sort_memory.wait_count,
+            "Sort memory limiter summary"
+        );
+    }
+}
+
 impl GetRawCMD {
     pub fn try_execute(&mut self) -> anyhow::Result<()> {
+        if let Some(path_sample_sheet) = self.path_sample_sheet.clone() {
+            return self.try_execute_sample_sheet(&path_sample_sheet);
+        }
         self.validate_fastq_inputs()?;
 
+        let total_bytes_in = match self.skip_debarcode {
+            Some(_) => None,
+            None => total_input_bytes(self.paths_r1.iter().chain(&self.paths_r2)),
+        };
+        let run = self.prepare_run(total_bytes_in)?;
+
+        let mut vec_input_debarcode_merge = self.skip_debarcode.clone().unwrap_or(Vec::new());
+        let mut histogram_counts = if self.skip_debarcode.is_none() && self.paths_out.len() == 1 {
+            Some(HistogramCounts::new())
+        } else {
+            None
+        };
+
+        if self.paths_out.is_empty() {
+            error!("No valid output file paths specified. All output paths failed verification.");
+            panic!("No valid output file paths specified");
+        }
+
+        if self.paths_hist.is_some()
+            && self.paths_hist.as_ref().unwrap().len() != self.paths_out.len()
+        {
+            let n_hist = self.paths_hist.as_ref().unwrap().len();
+            let n_out = self.paths_out.len();
+            error!(
+                "Number of histogram paths ({n_hist}) does not match number of output paths ({n_out})"
+            );
+            panic!("Histogram paths count mismatch");
+        }
+
+        let library = self.library.clone().unwrap_or(String::from(""));
+        let metadata = self.provenance();
+        let metadata_bytes = metadata.to_bytes()?;
+        let path_temp_dir = self.temp_dir_for(&self.paths_out);
+        let format = shard_output_format(&vec_input_debarcode_merge, &self.paths_out)?;
+
+        //Only perform debarcoding if skipping is disabled
+        if vec_input_debarcode_merge.is_empty() {
+            let (chunks, chunk_histogram_counts) = self.debarcode_library(
+                &run,
+                &self.chemistry,
+                self.paths_r1.clone(),
+                self.paths_r2.clone(),
+                &library,
+                &metadata_bytes,
+                &path_temp_dir,
+                &run.timestamp_temp_files,
+                format,
+            )?;
+            vec_input_debarcode_merge.extend(chunks);
+            if let Some(ref mut histogram_counts) = histogram_counts {
+                merge_histogram_counts(histogram_counts, chunk_histogram_counts);
+            }
+        }
+
+        run.log_limiter_summary();
+
+        do_merging(
+            &self,
+            &run.budget,
+            &path_temp_dir,
+            &run.timestamp_temp_files,
+            &vec_input_debarcode_merge,
+            histogram_counts,
+            &metadata,
+            &self.paths_out,
+            self.paths_hist.as_ref(),
+            format,
+            Arc::clone(&run.rayon_pool),
+            Arc::clone(&run.stage_timings),
+        )?;
+        run.progress.finish();
+        run.stage_timings.log_summary();
+
+        Ok(())
+    }
+
+    ///
+    /// Debarcode every library of a sample sheet in one run, into one combined output or one
+    /// output per sample
+    ///
+    fn try_execute_sample_sheet(&mut self, path_sample_sheet: &Path) -> anyhow::Result<()> {
+        let sheet = SampleSheet::read(path_sample_sheet)?;
+
+        //Check every library before spending hours on the first one
+        let mut libraries = BTreeMap::new();
+        for row in &sheet.rows {
+            let chemistry = self
+                .sample_sheet_chemistry(row)
+                .with_context(|| format!("sample {:?}, library {:?}", row.sample, row.library))?;
+            let paths_r1 = sample_sheet_inputs(&row.paths_r1)?;
+            let paths_r2 = sample_sheet_inputs(&row.paths_r2)?;
+            validate_fastq_input_paths(&paths_r1, "R1")?;
+            validate_fastq_input_paths(&paths_r2, "R2")?;
+            if self.interleaved && !paths_r2.is_empty() {
+                anyhow::bail!(
+                    "--interleaved takes both mates from R1, but library {:?} also lists R2 files",
+                    row.library
+                );
+            }
+            libraries.insert(row.library.as_str(), (chemistry, paths_r1, paths_r2));
+        }
+
+        let outputs: Vec<SampleSheetOutput<'_>> = match &self.path_sample_out_dir {
+            Some(dir) => {
+                std::fs::create_dir_all(dir).with_context(|| {
+                    format!("failed to create output directory {}", dir.display())
+                })?;
+                let mut outputs = Vec::new();
+                for (sample, rows) in sheet.rows_by_sample() {
+                    if sample.contains(std::path::is_separator) {
+                        anyhow::bail!("sample name {sample:?} cannot be used as a file name");
+                    }
+                    let path_out = dir.join(format!("{sample}.tirp.gz"));
+                    let path_out = OutputPath::try_from(&path_out)
+                        .with_context(|| format!("invalid output {}", path_out.display()))?;
+                    outputs.push((rows, vec![path_out], None));
+                }
+                outputs
+            }
+            None => {
+                if self.paths_out.is_empty() {
+                    anyhow::bail!("--sample-sheet needs either -o or --sample-out-dir");
+                }
+                if let Some(paths_hist) = &self.paths_hist
+                    && paths_hist.len() != self.paths_out.len()
+                {
+                    anyhow::bail!(
+                        "Number of histogram paths ({}) does not match number of output paths ({})",
+                        paths_hist.len(),
+                        self.paths_out.len()
+                    );
+                }
+                vec![(
+                    sheet.rows.iter().collect(),
+                    self.paths_out.clone(),
+                    self.paths_hist.clone(),
+                )]
+            }
+        };
+
+        let total_bytes_in = total_input_bytes(
+            libraries
+                .values()
+                .flat_map(|(_, paths_r1, paths_r2)| paths_r1.iter().chain(paths_r2)),
+        );
+        let run = self.prepare_run(total_bytes_in)?;
+        info!(
+            samples = sheet.rows_by_sample().len(),
+            libraries = sheet.rows.len(),
+            outputs = outputs.iter().map(|(_, out, _)| out.len()).sum::<usize>(),
+            "Debarcoding from sample sheet {}",
+            path_sample_sheet.display()
+        );
+
+        let mut library_index = 0;
+        for (output_index, (rows, paths_out, paths_hist)) in outputs.iter().enumerate() {
+            let metadata = self.sample_sheet_provenance(path_sample_sheet, rows, &libraries);
+            let metadata_bytes = metadata.to_bytes()?;
+            let path_temp_dir = self.temp_dir_for(paths_out);
+            let format = shard_output_format(&[], paths_out)?;
+
+            let mut vec_input_debarcode_merge = Vec::new();
+            let mut histogram_counts = HistogramCounts::new();
+            for row in rows {
+                let (chemistry, paths_r1, paths_r2) = &libraries[row.library.as_str()];
+                info!(
+                    sample = %row.sample,
+                    library = %row.library,
+                    chemistry = chemistry.name(),
+                    "Debarcoding library"
+                );
+                let (chunks, chunk_histogram_counts) = self.debarcode_library(
+                    &run,
+                    chemistry,
+                    paths_r1.clone(),
+                    paths_r2.clone(),
+                    &row.library,
+                    &metadata_bytes,
+                    &path_temp_dir,
+                    &format!("{}_lib{library_index}", run.timestamp_temp_files),
+                    format,
+                )?;
+                vec_input_debarcode_merge.extend(chunks);
+                merge_histogram_counts(&mut histogram_counts, chunk_histogram_counts);
+                library_index += 1;
+            }
+
+            do_merging(
+                &self,
+                &run.budget,
+                &path_temp_dir,
+                &format!("{}_out{output_index}", run.timestamp_temp_files),
+                &vec_input_debarcode_merge,
+                (paths_out.len() == 1).then_some(histogram_counts),
+                &metadata,
+                paths_out,
+                paths_hist.as_ref(),
+                format,
+                Arc::clone(&run.rayon_pool),
+                Arc::clone(&run.stage_timings),
+            )?;
+        }
+
+        run.log_limiter_summary();
+        run.progress.finish();
+        run.stage_timings.log_summary();
+
+        Ok(())
+    }
+
+    /// `total_bytes_in` is the size of every read input, if known, to give the progress an ETA
+    fn prepare_run(&self, total_bytes_in: Option<u64>) -> anyhow::Result<GetRawRun> {
         let total_threads = self.total_threads.unwrap_or_else(|| {
             std::thread::available_parallelism()
                 .map(|p| p.get())
@@ -983,14 +1146,10 @@ impl GetRawCMD {
         if self.compression_level.level() == 0 {
             warn!("Compression level is 0 (uncompressed)")
         }
-        let _malloc_trim_guard = MallocTrimGuard::new();
-        let read_memory_limiter = Arc::new(ReadMemoryLimiter::new(
-            budget.mem::<MStreamBuffer>().as_u64() as usize,
-        ));
-        let batch_stats = Arc::new(GetRawBatchStats::default());
-        let stage_timings = Arc::new(GetRawStageTimings::default());
+        let malloc_trim_guard = MallocTrimGuard::new();
+        let read_memory_limiter = budget.limiter::<MStreamBuffer>();
         info!(
-            read_memory_cap = %ByteSize(read_memory_limiter.cap as u64),
+            read_memory_cap = %ByteSize(read_memory_limiter.cap() as u64),
             "Read memory limiter enabled"
         );
         let rayon_pool = Arc::new(
@@ -1004,46 +1163,43 @@ impl GetRawCMD {
         ));
         let debarcode_inflight_limiter =
             Arc::new(InFlightLimiter::new(rayon_pool.current_num_threads()));
-        let sort_memory_limiter = Arc::new(ByteLimiter::new(
-            budget.mem::<MSortBuffer>().as_u64() as usize
-        ));
-
-        let mut vec_input_debarcode_merge = self.skip_debarcode.clone().unwrap_or(Vec::new());
-        let mut histogram_counts = if self.skip_debarcode.is_none() && self.paths_out.len() == 1 {
-            Some(HistogramCounts::new())
-        } else {
-            None
-        };
-
-        if self.paths_out.is_empty() {
-            error!("No valid output file paths specified. All output paths failed verification.");
-            panic!("No valid output file paths specified");
-        }
-
-        if self.paths_hist.is_some()
-            && self.paths_hist.as_ref().unwrap().len() != self.paths_out.len()
-        {
-            let n_hist = self.paths_hist.as_ref().unwrap().len();
-            let n_out = self.paths_out.len();
-            error!(
-                "Number of histogram paths ({n_hist}) does not match number of output paths ({n_out})"
-            );
-            panic!("Histogram paths count mismatch");
-        }
+        let sort_memory_limiter = budget.limiter::<MSortBuffer>();
 
         let timestamp_temp_files = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_secs();
-
-        let timestamp_temp_files = timestamp_temp_files.to_string();
-
-        let library = self.library.clone().unwrap_or(String::from(""));
+            .as_secs()
+            .to_string();
+
+        Ok(GetRawRun {
+            budget,
+            timestamp_temp_files,
+            read_memory_limiter,
+            sort_memory_limiter,
+            batch_stats: Arc::new(GetRawBatchStats::default()),
+            stage_timings: Arc::new(GetRawStageTimings::default()),
+            // NOTE:    --max-read-pairs usually stops well before the end of the input, so
+            //          the ETA follows the read pairs then rather than the input bytes
+            progress: match self.max_read_pairs {
+                Some(max_read_pairs) => Progress::new("debarcode")
+                    .with_total_records(Some(max_read_pairs))
+                    .start(),
+                None => Progress::new("debarcode")
+                    .with_total_bytes_in(total_bytes_in)
+                    .start(),
+            },
+            rayon_pool,
+            compression_limiter,
+            debarcode_inflight_limiter,
+            _malloc_trim_guard: malloc_trim_guard,
+        })
+    }
 
-        let path_temp_dir = if let Some(temp_path) = self.path_temp.clone() {
+    fn temp_dir_for(&self, paths_out: &[OutputPath]) -> PathBuf {
+        if let Some(temp_path) = self.path_temp.clone() {
             temp_path
         } else {
-            self.paths_out
+            paths_out
                 .first()
                 .unwrap()
                 .path()
@@ -1053,230 +1209,327 @@ impl GetRawCMD {
                     panic!("No valid output parent directory found");
                 })
                 .to_path_buf()
-        };
+        }
+    }
 
-        //Only perform debarcoding if skipping is disabled
-        if vec_input_debarcode_merge.is_empty() {
-            //Provide further settings to the chosen chemistry
-            let mut chemistry = match &self.chemistry {
-                GetRawChemistryCMD::AtrandiWGS { .. } => {
-                    GetRawChemistry::AtrandiWGS(DebarcodeAtrandiWGSChemistryIllumina::new())
-                }
-                GetRawChemistryCMD::AtrandiWGSLR { .. } => {
-                    GetRawChemistry::AtrandiWGSLR(DebarcodeAtrandiWGSChemistryLongread::new())
+    ///
+    /// Debarcode one library into sorted first-round chunks, returning the chunk paths and
+    /// the read count of every cell. The metadata comes serialised, so an oversized one fails
+    /// before any thread is spawned
+    ///
+    fn debarcode_library(
+        &self,
+        run: &GetRawRun,
+        chemistry: &GetRawChemistryCMD,
+        paths_r1: Vec<InputPath>,
+        paths_r2: Vec<InputPath>,
+        library: &str,
+        metadata_bytes: &[u8],
+        path_temp_dir: &PathBuf,
+        timestamp_temp_files: &str,
+        format: BlockFormat,
+    ) -> anyhow::Result<(Vec<InputPath>, HistogramCounts)> {
+        let budget = &run.budget;
+
+        //Provide further settings to the chosen chemistry
+        let mut chemistry = chemistry.to_chemistry();
+
+        //Check if we have single-end or paired-end data
+        if paths_r1.is_empty() {
+            error!("No valid input files found. All input files failed to open or do not exist.");
+            panic!("No valid input files found");
+        }
+
+        let ((r1_rx, r2_rx), (r1_handle, r2_handle)) = if self.interleaved {
+            //Both mates in the R1 files, alternating
+
+            //////////// For the given chemistry, check the read content (interleaved version)
+            {
+                info!("Preparing chemistry...");
+                let input_r1 = paths_r1.first().unwrap();
+                let sampled = sample_reads(input_r1, self.sizeof_stream_arena, "R1+R2")?;
+                let (mut b1, mut b2) = (Vec::new(), Vec::new());
+                for (i, record) in sampled.into_iter().enumerate() {
+                    if i % 2 == 0 {
+                        b1.push(record);
+                    } else {
+                        b2.push(record);
+                    }
                 }
-                GetRawChemistryCMD::ParseBio { subchemistry, .. } => {
-                    GetRawChemistry::ParseBio(ParseBioChemistry3::new(&subchemistry))
+                b1.truncate(b2.len());
+                chemistry.prepare_using_rp_vecs(b1, b2)?;
+            }
+            info!("Finished preparing chemistry...");
+
+            //////////// Prepare readers to process the full file (interleaved version)
+            spawn_interleaved_readers(
+                paths_r1,
+                budget,
+                self.sizeof_stream_arena,
+                Arc::clone(&run.read_memory_limiter),
+                Arc::clone(&run.rayon_pool),
+                Arc::clone(&run.stage_timings),
+                Arc::clone(&run.progress),
+                self.max_read_pairs,
+            )
+        } else if paths_r2.len() == 0 {
+            //No R2 files given ==> this must be single-end input
+
+            //////////// For the given chemistry, check the read content (single-end version)
+            {
+                info!("Preparing chemistry...");
+                let input_r1 = paths_r1.first().unwrap();
+                let b1 = sample_reads(input_r1, self.sizeof_stream_arena, "R1")?;
+                let mut b2 = Vec::new();
+                for _i in 0..b1.len() {
+                    b2.push(bascet_io::parse::fastq::OwnedRecord::empty());
                 }
-                GetRawChemistryCMD::Tenx { .. } => GetRawChemistry::Tenx(TenxRNAChemistry::new()),
-            };
-
-            //Check if we have single-end or paired-end data
-            let paths_r1 = self.paths_r1.clone();
-            let paths_r2 = self.paths_r2.clone();
-            if paths_r1.is_empty() {
-                error!(
-                    "No valid input files found. All input files failed to open or do not exist."
-                );
-                panic!("No valid input files found");
+                chemistry.prepare_using_rp_vecs(b1, b2)?;
+            }
+            info!("Finished preparing chemistry...");
+
+            //////////// Prepare readers to process the full file (single-end version)
+            spawn_single_readers(
+                paths_r1,
+                budget,
+                self.sizeof_stream_arena,
+                Arc::clone(&run.read_memory_limiter),
+                Arc::clone(&run.rayon_pool),
+                Arc::clone(&run.stage_timings),
+                Arc::clone(&run.progress),
+                self.max_read_pairs,
+            )
+        } else {
+            //Both R1 and R2 ==> this must be paired-end input
+            if paths_r1.len() != paths_r2.len() {
+                panic!("Both R1 and R2 specified but lists are of different length")
             }
+            let vec_input: Vec<(InputPath, InputPath)> = izip!(paths_r1, paths_r2).collect();
+
+            //////////// For the given chemistry, check the read content (paired-end version)
+            {
+                info!("Preparing chemistry...");
+                let (input_r1, input_r2) = &vec_input.first().unwrap();
+                let b1 = sample_reads(input_r1, self.sizeof_stream_arena, "R1")?;
+                let b2 = sample_reads(input_r2, self.sizeof_stream_arena, "R2")?;
+                chemistry.prepare_using_rp_vecs(b1, b2)?;
+            }
+            info!("Finished preparing chemistry...");
+
+            //////////// Prepare readers to process the full file (paired-end version)
+            spawn_paired_readers(
+                vec_input,
+                budget,
+                self.sizeof_stream_arena,
+                Arc::clone(&run.read_memory_limiter),
+                Arc::clone(&run.rayon_pool),
+                Arc::clone(&run.stage_timings),
+                Arc::clone(&run.progress),
+                self.max_read_pairs,
+            )
+        };
 
-            let ((r1_rx, r2_rx), (r1_handle, r2_handle)) = if paths_r2.len() == 0 {
-                //No R2 files given ==> this must be single-end input
+        let (rp_rx, rt_handle) = spawn_debarcode_router(
+            r1_rx,
+            r2_rx,
+            budget,
+            Arc::clone(&run.batch_stats),
+            Arc::clone(&run.progress),
+        );
+        let first_round_sort_chunk_target =
+            Arc::new(AtomicU64::new(first_round_sort_chunk_size(budget).as_u64()));
+        let (db_rx, db_handles, chemistry) = spawn_debarcode_workers(
+            rp_rx,
+            chemistry,
+            budget,
+            Arc::clone(&run.rayon_pool),
+            Arc::clone(&run.debarcode_inflight_limiter),
+            Arc::clone(&run.batch_stats),
+            Arc::clone(&run.stage_timings),
+            Arc::clone(&first_round_sort_chunk_target),
+        );
 
-                //////////// For the given chemistry, check the read content (single-end version)
-                {
-                    info!("Preparing chemistry...");
-                    let input_r1 = paths_r1.first().unwrap();
-                    let b1 = sample_reads(input_r1, &self, &budget, "R1");
-                    let mut b2 = Vec::new();
-                    for _i in 0..b1.len() {
-                        b2.push(bascet_io::parse::fastq::OwnedRecord::empty());
-                    }
-                    chemistry.prepare_using_rp_vecs(b1, b2)?;
-                }
-                info!("Finished preparing chemistry...");
-
-                //////////// Prepare readers to process the full file (single-end version)
-                spawn_single_readers(
-                    paths_r1,
-                    &budget,
-                    self.sizeof_stream_arena,
-                    Arc::clone(&read_memory_limiter),
-                    Arc::clone(&rayon_pool),
-                    Arc::clone(&stage_timings),
-                    self.max_read_pairs,
-                )
-            } else {
-                //Both R1 and R2 ==> this must be paired-end input
-                if paths_r1.len() != paths_r2.len() {
-                    panic!("Both R1 and R2 specified but lists are of different length")
-                }
-                let vec_input: Vec<(InputPath, InputPath)> = izip!(paths_r1, paths_r2).collect();
+        let (ct_rx, ct_handle) = spawn_collector(
+            db_rx,
+            budget,
+            Arc::clone(&run.read_memory_limiter),
+            Arc::clone(&run.sort_memory_limiter),
+            Arc::clone(&run.batch_stats),
+            Arc::clone(&run.stage_timings),
+            Arc::clone(&first_round_sort_chunk_target),
+        );
+        let writer_chemistry = chemistry.clone();
+        let (st_rx, st_handles) = spawn_sort_workers(
+            ct_rx,
+            budget,
+            Arc::clone(&run.rayon_pool),
+            Arc::clone(&run.stage_timings),
+        );
 
-                //////////// For the given chemistry, check the read content (paired-end version)
-                {
-                    info!("Preparing chemistry...");
-                    let (input_r1, input_r2) = &vec_input.first().unwrap();
-                    let b1 = sample_reads(input_r1, &self, &budget, "R1");
-                    let b2 = sample_reads(input_r2, &self, &budget, "R2");
-                    chemistry.prepare_using_rp_vecs(b1, b2)?;
-                }
-                info!("Finished preparing chemistry...");
-
-                //////////// Prepare readers to process the full file (paired-end version)
-                spawn_paired_readers(
-                    vec_input,
-                    &budget,
-                    self.sizeof_stream_arena,
-                    Arc::clone(&read_memory_limiter),
-                    Arc::clone(&rayon_pool),
-                    Arc::clone(&stage_timings),
-                    self.max_read_pairs,
-                )
-            };
+        let wt_handles = spawn_chunk_writers(
+            st_rx,
+            timestamp_temp_files.to_string(),
+            path_temp_dir.clone(),
+            budget,
+            self.compression_level,
+            format,
+            writer_chemistry,
+            library,
+            metadata_bytes,
+            Arc::clone(&run.rayon_pool),
+            Arc::clone(&run.compression_limiter),
+            Arc::clone(&run.stage_timings),
+        );
 
-            let (rp_rx, rt_handle) =
-                spawn_debarcode_router(r1_rx, r2_rx, &budget, Arc::clone(&batch_stats));
-            let first_round_sort_chunk_target = Arc::new(AtomicU64::new(
-                first_round_sort_chunk_size(&budget).as_u64(),
-            ));
-            let (db_rx, db_handles, chemistry) = spawn_debarcode_workers(
-                rp_rx,
-                chemistry,
-                &budget,
-                Arc::clone(&rayon_pool),
-                Arc::clone(&debarcode_inflight_limiter),
-                Arc::clone(&batch_stats),
-                Arc::clone(&stage_timings),
-                Arc::clone(&first_round_sort_chunk_target),
-            );
+        info!("Waiting for R1 and R2 reader threads to finish...");
+        // NOTE:    a reader error is returned once the rest of the pipeline has drained, so no
+        //          thread is left running on a truncated input
+        let r1_result = r1_handle.join().expect("R1 reader thread panicked");
+        let r2_result = r2_handle.join().expect("R2 reader thread panicked");
+        info!("R1 and R2 reader threads finished");
 
-            let (ct_rx, ct_handle) = spawn_collector(
-                db_rx,
-                &budget,
-                Arc::clone(&read_memory_limiter),
-                Arc::clone(&sort_memory_limiter),
-                Arc::clone(&batch_stats),
-                Arc::clone(&stage_timings),
-                Arc::clone(&first_round_sort_chunk_target),
-            );
-            let writer_chemistry = chemistry.clone();
-            let (st_rx, st_handles) = spawn_sort_workers(
-                ct_rx,
-                &budget,
-                Arc::clone(&rayon_pool),
-                Arc::clone(&stage_timings),
-            );
+        ////////////////// The rest here is in common
 
-            let wt_handles = spawn_chunk_writers(
-                st_rx,
-                timestamp_temp_files.clone(),
-                path_temp_dir.clone(),
-                &budget,
-                self.compression_level,
-                writer_chemistry,
-                &library,
-                Arc::clone(&rayon_pool),
-                Arc::clone(&compression_limiter),
-                Arc::clone(&stage_timings),
-            );
+        info!("Waiting for router thread to finish...");
+        rt_handle.join().expect("Router thread panicked");
+        info!("Router thread finished");
+
+        debug!(
+            "Waiting for {} debarcode worker threads to finish...",
+            db_handles.len()
+        );
+        for (i, handle) in IntoIterator::into_iter(db_handles).enumerate() {
+            handle
+                .join()
+                .expect(&format!("Worker thread {} panicked", i));
+        }
+        debug!("All debarcode worker threads finished");
 
-            info!("Waiting for R1 and R2 reader threads to finish...");
-            r1_handle.join().expect("R1 reader thread panicked");
-            r2_handle.join().expect("R2 reader thread panicked");
-            info!("R1 and R2 reader threads finished");
+        debug!("Waiting for collector thread to finish...");
+        ct_handle.join().expect("Collector thread panicked");
+        debug!("Collector thread finished");
+        run.batch_stats.log_summary();
 
-            ////////////////// The rest here is in common
+        debug!(
+            "Waiting for {} sort worker threads to finish...",
+            st_handles.len()
+        );
+        for (i, handle) in IntoIterator::into_iter(st_handles).enumerate() {
+            handle
+                .join()
+                .expect(&format!("Sort worker thread {} panicked", i));
+        }
+        debug!("All sort worker threads finished");
 
-            info!("Waiting for router thread to finish...");
-            rt_handle.join().expect("Router thread panicked");
-            info!("Router thread finished");
+        debug!(
+            "Waiting for {} chunk writer threads to finish...",
+            wt_handles.len()
+        );
+        let mut chunks = Vec::new();
+        let mut histogram_counts = HistogramCounts::new();
+        for (i, handle) in wt_handles.into_iter().enumerate() {
+            let output: ChunkWriterOutput = handle
+                .join()
+                .expect(&format!("Writer thread {} panicked", i));
 
-            debug!(
-                "Waiting for {} debarcode worker threads to finish...",
-                db_handles.len()
-            );
-            for (i, handle) in IntoIterator::into_iter(db_handles).enumerate() {
-                handle
+            for (j, finish_handle) in output.finish_handles.into_iter().enumerate() {
+                let finish_started = Instant::now();
+                finish_handle
                     .join()
-                    .expect(&format!("Worker thread {} panicked", i));
+                    .expect(&format!("BBGZ finish thread {j} from writer {i} panicked"));
+                run.stage_timings.add_write(finish_started.elapsed());
             }
-            debug!("All debarcode worker threads finished");
+            chunks.extend(output.paths);
+            merge_histogram_counts(&mut histogram_counts, output.histogram_counts);
+        }
+        debug!(
+            "All chunk writer threads finished. Total chunks: {}",
+            chunks.len()
+        );
+        r1_result?;
+        r2_result?;
 
-            debug!("Waiting for collector thread to finish...");
-            ct_handle.join().expect("Collector thread panicked");
-            debug!("Collector thread finished");
-            batch_stats.log_summary();
+        Ok((chunks, histogram_counts))
+    }
 
-            debug!(
-                "Waiting for {} sort worker threads to finish...",
-                st_handles.len()
-            );
-            for (i, handle) in IntoIterator::into_iter(st_handles).enumerate() {
-                handle
-                    .join()
-                    .expect(&format!("Sort worker thread {} panicked", i));
-            }
-            debug!("All sort worker threads finished");
+    /// Provenance stored in the metadata of every debarcoded output
+    fn provenance(&self) -> BBGZMetadata {
+        let mut metadata = match &self.skip_debarcode {
+            Some(paths) => BBGZMetadata::derive_from_paths(paths.iter().map(|p| p.path().path())),
+            None => BBGZMetadata::for_current_process(),
+        };
 
-            debug!(
-                "Waiting for {} chunk writer threads to finish...",
-                wt_handles.len()
-            );
-            for (i, handle) in wt_handles.into_iter().enumerate() {
-                let output: ChunkWriterOutput = handle
-                    .join()
-                    .expect(&format!("Writer thread {} panicked", i));
-
-                for (j, finish_handle) in output.finish_handles.into_iter().enumerate() {
-                    let finish_started = Instant::now();
-                    finish_handle
-                        .join()
-                        .expect(&format!("BBGZ finish thread {j} from writer {i} panicked"));
-                    stage_timings.add_write(finish_started.elapsed());
-                }
-                vec_input_debarcode_merge.extend(output.paths);
-                if let Some(ref mut histogram_counts) = histogram_counts {
-                    merge_histogram_counts(histogram_counts, output.histogram_counts);
-                }
+        if let GetRawChemistryCMD::ParseBio { subchemistry } = &self.chemistry
+            && !subchemistry.is_empty()
+        {
+            metadata.set("subchemistry", subchemistry.as_str());
+        }
+        push_lineage(&mut metadata, "chemistry", self.chemistry.name());
+        if let Some(library) = self.library.as_ref().filter(|l| !l.is_empty()) {
+            push_lineage(&mut metadata, "library", library);
+        }
+        for path in &self.paths_r1 {
+            metadata.push("source.input_r1", path.to_string());
+        }
+        for path in &self.paths_r2 {
+            metadata.push("source.input_r2", path.to_string());
+        }
+        metadata
+    }
+
+    /// Provenance of an output built from some libraries of a sample sheet: their chemistries,
+    /// libraries and sample columns, then their inputs as `source.*` entries so that a long
+    /// sheet drops them rather than exceeding the metadata cap
+    fn sample_sheet_provenance(
+        &self,
+        path_sample_sheet: &Path,
+        rows: &[&SampleSheetRow],
+        libraries: &BTreeMap<&str, SampleSheetLibrary>,
+    ) -> BBGZMetadata {
+        let mut metadata = BBGZMetadata::for_current_process();
+        metadata.push("sample_sheet", path_sample_sheet.display().to_string());
+        for row in rows {
+            let (chemistry, _, _) = &libraries[row.library.as_str()];
+            push_lineage(&mut metadata, "chemistry", chemistry.name());
+            if let GetRawChemistryCMD::ParseBio { subchemistry } = chemistry
+                && !subchemistry.is_empty()
+            {
+                push_lineage(&mut metadata, "subchemistry", subchemistry);
             }
-            debug!(
-                "All chunk writer threads finished. Total chunks: {}",
-                vec_input_debarcode_merge.len()
-            );
+            push_lineage(&mut metadata, "library", &row.library);
         }
+        for row in rows {
+            row.push_metadata(&mut metadata);
+        }
+        for row in rows {
+            let (_, paths_r1, paths_r2) = &libraries[row.library.as_str()];
+            for path in paths_r1 {
+                metadata.push("source.input_r1", path.to_string());
+            }
+            for path in paths_r2 {
+                metadata.push("source.input_r2", path.to_string());
+            }
+        }
+        metadata
+    }
 
-        let (read_memory_used, read_memory_max_used, read_memory_wait_count) =
-            read_memory_limiter.stats();
-        info!(
-            read_memory_used = %ByteSize(read_memory_used as u64),
-            read_memory_max_used = %ByteSize(read_memory_max_used as u64),
-            read_memory_wait_count,
-            "Read memory limiter summary"
-        );
-        let (sort_memory_used, sort_memory_max_used, sort_memory_wait_count) =
-            sort_memory_limiter.stats();
-        info!(
-            sort_memory_used = %ByteSize(sort_memory_used as u64),
-            sort_memory_max_used = %ByteSize(sort_memory_max_used as u64),
-            sort_memory_wait_count,
-            "Sort memory limiter summary"
-        );
-
-        do_merging(
-            &self,
-            &budget,
-            &path_temp_dir,
-            &timestamp_temp_files,
-            &vec_input_debarcode_merge,
-            histogram_counts,
-            Arc::clone(&rayon_pool),
-            Arc::clone(&stage_timings),
-        )?;
-        stage_timings.log_summary();
-
-        Ok(())
+    /// Chemistry of a sample sheet row, falling back to the chemistry subcommand
+    fn sample_sheet_chemistry(&self, row: &SampleSheetRow) -> anyhow::Result<GetRawChemistryCMD> {
+        let Some(name) = row.chemistry.as_deref() else {
+            return Ok(match (&self.chemistry, &row.subchemistry) {
+                (GetRawChemistryCMD::ParseBio { .. }, Some(subchemistry)) => {
+                    GetRawChemistryCMD::ParseBio {
+                        subchemistry: subchemistry.clone(),
+                    }
+                }
+                (chemistry, _) => chemistry.clone(),
+            });
+        };
+        GetRawChemistryCMD::from_name(name, row.subchemistry.as_deref()).with_context(|| {
+            format!(
+                "unknown chemistry {name:?}; expected atrandi-wgs, atrandi-wgslr, parse-bio or tenx"
+            )
+        })
     }
 
     fn validate_fastq_inputs(&self) -> anyhow::Result<()> {
@@ -1291,6 +1544,10 @@ impl GetRawCMD {
         validate_fastq_input_paths(&self.paths_r1, "R1")?;
         validate_fastq_input_paths(&self.paths_r2, "R2")?;
 
+        if self.interleaved && !self.paths_r2.is_empty() {
+            anyhow::bail!("--interleaved takes both mates from R1; do not also give R2 files");
+        }
+
         if !self.paths_r2.is_empty() && self.paths_r1.len() != self.paths_r2.len() {
             anyhow::bail!(
                 "Both R1 and R2 specified but lists are of different length: {} R1 files, {} R2 files",
@@ -1303,6 +1560,22 @@ impl GetRawCMD {
     }
 }
 
+fn push_lineage(metadata: &mut BBGZMetadata, key: &str, value: &str) {
+    if metadata.get_all(key).all(|v| v != value) {
+        metadata.push(key, value);
+    }
+}
+
+fn sample_sheet_inputs(paths: &[PathBuf]) -> anyhow::Result<Vec<InputPath>> {
+    paths
+        .iter()
+        .map(|path| {
+            InputPath::try_from(path)
+                .map_err(|e| anyhow::anyhow!("cannot read input {}: {e}", path.display()))
+        })
+        .collect()
+}
+
 fn validate_fastq_input_paths(paths: &[InputPath], read_name: &str) -> anyhow::Result<()> {
     for path in paths {
         validate_fastq_input_path(path.path().path(), read_name)?;
@@ -1311,93 +1584,35 @@ fn validate_fastq_input_paths(paths: &[InputPath], read_name: &str) -> anyhow::R
 }
 
 fn validate_fastq_input_path(path: &Path, read_name: &str) -> anyhow::Result<()> {
-    if !has_fastq_extension(path) {
-        anyhow::bail!(
-            "{read_name} input '{}' must be a block-zipped FASTQ file with one of these extensions: {}",
-            path.display(),
-            FASTQ_INPUT_EXTENSIONS.join(", ")
-        );
-    }
-
-    let mut file = File::open(path).map_err(|err| {
-        anyhow::anyhow!("Cannot open {read_name} input '{}': {err}", path.display())
-    })?;
-    let metadata = file.metadata().map_err(|err| {
-        anyhow::anyhow!(
-            "Cannot read metadata for {read_name} input '{}': {err}",
-            path.display()
-        )
-    })?;
-    if metadata.len() == 0 {
-        anyhow::bail!("{read_name} input '{}' is empty", path.display());
-    }
-
-    let mut base = [0u8; 12];
-    file.read_exact(&mut base).map_err(|err| {
-        anyhow::anyhow!(
-            "Cannot read gzip header from {read_name} input '{}': {err}",
-            path.display()
-        )
-    })?;
-
-    if base[0] != 0x1f || base[1] != 0x8b || base[2] != 8 {
-        anyhow::bail!(
-            "{read_name} input '{}' must be block-zipped FASTQ, but does not start with a gzip deflate header",
-            path.display()
-        );
-    }
-    if base[3] & 0x04 == 0 {
-        anyhow::bail!(
-            "{read_name} input '{}' must be block-zipped FASTQ, but the gzip header has no FEXTRA field",
-            path.display()
-        );
-    }
-
-    let xlen = u16::from_le_bytes([base[10], base[11]]) as usize;
-    let mut extra = vec![0u8; xlen];
-    file.read_exact(&mut extra).map_err(|err| {
-        anyhow::anyhow!(
-            "Cannot read gzip extra field from {read_name} input '{}': {err}",
-            path.display()
-        )
-    })?;
-    if !has_bgzf_bsize_extra(&extra) {
-        anyhow::bail!(
-            "{read_name} input '{}' must be block-zipped FASTQ, but the gzip extra field has no BGZF/BBGZ BC block-size entry",
-            path.display()
-        );
-    }
-
+    let format = FastqInputFormat::detect(path)
+        .map_err(|err| anyhow::anyhow!("Invalid {read_name} input: {err}"))?;
+    debug!(path = %path.display(), ?format, "Detected {read_name} input format");
     Ok(())
 }
 
-fn has_fastq_extension(path: &Path) -> bool {
-    let Some(file_name) = path.file_name() else {
-        return false;
-    };
-    let file_name = file_name.to_string_lossy().to_ascii_lowercase();
-    FASTQ_INPUT_EXTENSIONS
-        .iter()
-        .any(|extension| file_name.ends_with(extension))
+///////////////////////////////
+/// Open a read input of any supported format as FASTQ text
+pub(crate) fn open_fastq_decoder(
+    path: &Path,
+    rayon_pool: Option<Arc<rayon::ThreadPool>>,
+    progress: Option<Arc<Progress>>,
+) -> anyhow::Result<FastqInputDecoder> {
+    let format = FastqInputFormat::detect(path)?;
+    FastqInputDecoder::open(path, format, rayon_pool, progress)
 }
 
-fn has_bgzf_bsize_extra(extra: &[u8]) -> bool {
-    let mut cursor = 0usize;
-    while cursor + 4 <= extra.len() {
-        let si1 = extra[cursor];
-        let si2 = extra[cursor + 1];
-        let slen = u16::from_le_bytes([extra[cursor + 2], extra[cursor + 3]]) as usize;
-        cursor += 4;
-
-        if cursor + slen > extra.len() {
-            return false;
-        }
-        if si1 == b'B' && si2 == b'C' && slen == 2 {
-            return true;
-        }
-        cursor += slen;
-    }
-    false
+///////////////////////////////
+/// Total size of the read inputs, or None if any of them is not a regular file (e.g. stdin)
+fn total_input_bytes<'a>(paths: impl IntoIterator<Item = &'a InputPath>) -> Option<u64> {
+    paths
+        .into_iter()
+        .map(|path| {
+            std::fs::metadata(path.path().path())
+                .ok()
+                .filter(|metadata| metadata.is_file())
+                .map(|metadata| metadata.len())
+        })
+        .sum()
 }
 
 ///
@@ -1410,6 +1625,10 @@ fn do_merging(
     timestamp_temp_files: &String,
     vec_input_debarcode_merge: &Vec<InputPath>,
     histogram_counts: Option<HistogramCounts>,
+    metadata: &BBGZMetadata,
+    paths_out: &[OutputPath],
+    paths_hist: Option<&Vec<OutputPath>>,
+    format: BlockFormat,
     rayon_pool: Arc<rayon::ThreadPool>,
     stage_timings: Arc<GetRawStageTimings>,
 ) -> anyhow::Result<()> {
@@ -1421,7 +1640,7 @@ fn do_merging(
         .max(2);
     let vec_input_debarcode_merge = vec_input_debarcode_merge.clone();
 
-    let mergeround_target_count = s.paths_out.len();
+    let mergeround_target_count = paths_out.len();
     let mut mergeround_counter = 1;
     let mut mergeround_merge_next = vec_input_debarcode_merge;
 
@@ -1460,7 +1679,9 @@ fn do_merging(
             }
 
             let temp_fname = format!("{}_{mergeround_counter}_{batch_idx}", timestamp_temp_files);
-            let temp_pathbuf = path_temp_dir.join(temp_fname).with_extension("tirp.bbgz");
+            let temp_pathbuf = path_temp_dir
+                .join(temp_fname)
+                .with_extension(format.tirp_extension());
 
             let temp_output_path = match OutputPath::try_from(&temp_pathbuf) {
                 Ok(path) => path,
@@ -1481,6 +1702,7 @@ fn do_merging(
                 path_temp_dir.clone(),
                 &budget,
                 s.sizeof_stream_arena,
+                metadata.clone(),
             );
             stage_timings.add_merge(merge_started.elapsed());
 
@@ -1511,11 +1733,12 @@ fn do_merging(
     }
 
     let mut output_paths = Vec::new();
-    for (final_path, output_path) in izip!(&mergeround_merge_next, &s.paths_out) {
+    for (final_path, output_path) in izip!(&mergeround_merge_next, paths_out) {
         let publish_started = Instant::now();
         match rename_or_copy_across_filesystems(&**final_path.path(), &**output_path.path()) {
             Ok(_) => {
                 debug!("Moved {final_path} -> {output_path}");
+                RunReport::add_output(output_path.path().path());
                 output_paths.push(output_path.clone());
             }
             Err(e) => {
@@ -1534,7 +1757,7 @@ fn do_merging(
         .into_iter()
         .enumerate()
         .map(|(i, output_path)| {
-            let hist_path = if let Some(ref hist_paths) = s.paths_hist {
+            let hist_path = if let Some(hist_paths) = paths_hist {
                 hist_paths[i].clone()
             } else {
                 match OutputPath::try_from(&format!("{}.hist", output_path.path().path().display()))
@@ -1585,21 +1808,7 @@ fn write_histogram_counts(
     hist_path: &OutputPath,
     histogram_counts: &HistogramCounts,
 ) -> anyhow::Result<()> {
-    let hist_final_path = hist_path.path().path().to_path_buf();
-    let hist_tmp_path = atomic_temp_path(&hist_final_path);
-    let hist_file = File::create(&hist_tmp_path)?;
-    let mut bufwriter = BufWriter::new(hist_file);
-
-    for (cell_id, count) in histogram_counts {
-        bufwriter.write_all(cell_id)?;
-        bufwriter.write_all(b"\t")?;
-        bufwriter.write_all(count.to_string().as_bytes())?;
-        bufwriter.write_all(b"\n")?;
-    }
-
-    bufwriter.flush()?;
-    drop(bufwriter);
-    publish_atomic_output(&hist_tmp_path, &hist_final_path)?;
+    write_histogram_or_table(hist_path.path().path(), histogram_counts)?;
     debug!("Wrote histogram at {}", hist_path);
     Ok(())
 }
@@ -1611,16 +1820,20 @@ fn spawn_paired_readers(
     vec_input: Vec<(InputPath, InputPath)>,
     budget: &GetrawBudget,
     stream_arena: ByteSize,
-    read_memory_limiter: Arc<ReadMemoryLimiter>,
+    read_memory_limiter: Arc<MemoryLimiter>,
     rayon_pool: Arc<rayon::ThreadPool>,
     stage_timings: Arc<GetRawStageTimings>,
+    progress: Arc<Progress>,
     max_read_pairs: Option<u64>,
 ) -> (
     (
         Receiver<BudgetedFastqRecordBatch>,
         Receiver<BudgetedFastqRecordBatch>,
     ),
-    (JoinHandle<()>, JoinHandle<()>),
+    (
+        JoinHandle<anyhow::Result<()>>,
+        JoinHandle<anyhow::Result<()>>,
+    ),
 ) {
     let batch_capacity = read_pair_batch_capacity(budget);
     let queue_capacity = record_queue_capacity(budget).div_ceil(batch_capacity);
@@ -1635,6 +1848,7 @@ fn spawn_paired_readers(
     let r1_rayon_pool = Arc::clone(&rayon_pool);
     let r1_read_memory_limiter = Arc::clone(&read_memory_limiter);
     let r1_stage_timings = Arc::clone(&stage_timings);
+    let r1_progress = Arc::clone(&progress);
     let handle_r1 = spawn_coordinator("getraw-read-r1", 0, move || {
         let read_started = Instant::now();
         let thread = std::thread::current();
@@ -1647,10 +1861,19 @@ fn spawn_paired_readers(
             if max_read_pairs.is_some_and(|limit| records_read >= limit) {
                 break;
             }
-            let d1 = codec::BBGZDecoder::builder()
-                .with_path(&**input_r1.path())
-                .with_opt_rayon_pool(Arc::clone(&r1_rayon_pool))
-                .build();
+            let d1 = match open_fastq_decoder(
+                input_r1.path().path(),
+                Some(Arc::clone(&r1_rayon_pool)),
+                Some(Arc::clone(&r1_progress)),
+            ) {
+                Ok(decoder) => decoder,
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "R1 reader failed to open {}",
+                        input_r1.path().display()
+                    )));
+                }
+            };
             let p1 = parse::Fastq::builder().build();
 
             let mut s1 = Stream::builder()
@@ -1661,6 +1884,7 @@ fn spawn_paired_readers(
 
             let mut q1 = s1.query::<fastq::Record>();
             let mut stop_reading = false;
+            let mut read_error = None;
 
             while !stop_reading {
                 let remaining_capacity = max_read_pairs
@@ -1674,7 +1898,7 @@ fn spawn_paired_readers(
                 let records = match q1.next_batch_with_retained_bytes(remaining_capacity) {
                     Ok(records) => records,
                     Err(err) => {
-                        error!(error = ?err, "R1 reader failed");
+                        read_error = Some(err);
                         stop_reading = true;
                         break;
                     }
@@ -1690,15 +1914,15 @@ fn spawn_paired_readers(
                     break;
                 }
                 if ticker.tick() {
-                    let (read_used, read_max_used, read_waits) = r1_read_memory_limiter.stats();
+                    let read_memory = r1_read_memory_limiter.stats();
                     debug!(
                         stage = "reader-r1",
                         r1_queue_len = r1_tx.len(),
                         r1_queue_cap = r1_tx.capacity().unwrap_or(0),
                         records_read,
-                        read_memory_used = %ByteSize(read_used as u64),
-                        read_memory_max_used = %ByteSize(read_max_used as u64),
-                        read_memory_wait_count = read_waits,
+                        read_memory_used = %ByteSize(read_memory.used as u64),
+                        read_memory_max_used = %ByteSize(read_memory.max_used as u64),
+                        read_memory_wait_count = read_memory.wait_count,
                         "getraw pipeline telemetry"
                     );
                 }
@@ -1707,17 +1931,24 @@ fn spawn_paired_readers(
                 unsafe {
                     s1.shutdown();
                 }
+                if let Some(err) = read_error {
+                    return Err(
+                        err.context(format!("R1 reader failed on {}", input_r1.path().display()))
+                    );
+                }
                 break;
             }
             debug!("R1 finished reading");
         }
         r1_stage_timings.add_read(read_started.elapsed());
+        Ok(())
     });
 
     let input_r2 = Arc::clone(&arc_vec_input);
     let r2_rayon_pool = Arc::clone(&rayon_pool);
     let r2_read_memory_limiter = Arc::clone(&read_memory_limiter);
     let r2_stage_timings = Arc::clone(&stage_timings);
+    let r2_progress = Arc::clone(&progress);
     let handle_r2 = spawn_coordinator("getraw-read-r2", 1, move || {
         let read_started = Instant::now();
         let thread = std::thread::current();
@@ -1730,10 +1961,19 @@ fn spawn_paired_readers(
             if max_read_pairs.is_some_and(|limit| records_read >= limit) {
                 break;
             }
-            let d2 = codec::BBGZDecoder::builder()
-                .with_path(&**input_r2.path())
-                .with_opt_rayon_pool(Arc::clone(&r2_rayon_pool))
-                .build();
+            let d2 = match open_fastq_decoder(
+                input_r2.path().path(),
+                Some(Arc::clone(&r2_rayon_pool)),
+                Some(Arc::clone(&r2_progress)),
+            ) {
+                Ok(decoder) => decoder,
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "R2 reader failed to open {}",
+                        input_r2.path().display()
+                    )));
+                }
+            };
             let p2 = parse::Fastq::builder().build();
 
             let mut s2 = Stream::builder()
@@ -1744,6 +1984,7 @@ fn spawn_paired_readers(
 
             let mut q2 = s2.query::<fastq::Record>();
             let mut stop_reading = false;
+            let mut read_error = None;
 
             while !stop_reading {
                 let remaining_capacity = max_read_pairs
@@ -1757,7 +1998,7 @@ fn spawn_paired_readers(
                 let records = match q2.next_batch_with_retained_bytes(remaining_capacity) {
                     Ok(records) => records,
                     Err(err) => {
-                        error!(error = ?err, "R2 reader failed");
+                        read_error = Some(err);
                         stop_reading = true;
                         break;
                     }
@@ -1773,15 +2014,15 @@ fn spawn_paired_readers(
                     break;
                 }
                 if ticker.tick() {
-                    let (read_used, read_max_used, read_waits) = r2_read_memory_limiter.stats();
+                    let read_memory = r2_read_memory_limiter.stats();
                     debug!(
                         stage = "reader-r2",
                         r2_queue_len = r2_tx.len(),
                         r2_queue_cap = r2_tx.capacity().unwrap_or(0),
                         records_read,
-                        read_memory_used = %ByteSize(read_used as u64),
-                        read_memory_max_used = %ByteSize(read_max_used as u64),
-                        read_memory_wait_count = read_waits,
+                        read_memory_used = %ByteSize(read_memory.used as u64),
+                        read_memory_max_used = %ByteSize(read_memory.max_used as u64),
+                        read_memory_wait_count = read_memory.wait_count,
                         "getraw pipeline telemetry"
                     );
                 }
@@ -1790,11 +2031,17 @@ fn spawn_paired_readers(
                 unsafe {
                     s2.shutdown();
                 }
+                if let Some(err) = read_error {
+                    return Err(
+                        err.context(format!("R2 reader failed on {}", input_r2.path().display()))
+                    );
+                }
                 break;
             }
             debug!("R2 finished reading");
         }
         r2_stage_timings.add_read(read_started.elapsed());
+        Ok(())
     });
 
     return ((r1_rx, r2_rx), (handle_r1, handle_r2));
@@ -1809,16 +2056,20 @@ fn spawn_single_readers(
     vec_input: Vec<InputPath>,
     budget: &GetrawBudget,
     stream_arena: ByteSize,
-    read_memory_limiter: Arc<ReadMemoryLimiter>,
+    read_memory_limiter: Arc<MemoryLimiter>,
     rayon_pool: Arc<rayon::ThreadPool>,
     stage_timings: Arc<GetRawStageTimings>,
+    progress: Arc<Progress>,
     max_read_pairs: Option<u64>,
 ) -> (
     (
         Receiver<BudgetedFastqRecordBatch>,
         Receiver<BudgetedFastqRecordBatch>,
     ),
-    (JoinHandle<()>, JoinHandle<()>),
+    (
+        JoinHandle<anyhow::Result<()>>,
+        JoinHandle<anyhow::Result<()>>,
+    ),
 ) {
     let batch_capacity = read_pair_batch_capacity(budget);
     let queue_capacity = record_queue_capacity(budget).div_ceil(batch_capacity);
@@ -1833,6 +2084,7 @@ fn spawn_single_readers(
     let r1_rayon_pool = Arc::clone(&rayon_pool);
     let r1_read_memory_limiter = Arc::clone(&read_memory_limiter);
     let r1_stage_timings = Arc::clone(&stage_timings);
+    let r1_progress = Arc::clone(&progress);
     let handle_r1 = spawn_coordinator("getraw-read-r1", 0, move || {
         let read_started = Instant::now();
         let thread = std::thread::current();
@@ -1845,10 +2097,19 @@ fn spawn_single_readers(
             if max_read_pairs.is_some_and(|limit| records_read >= limit) {
                 break;
             }
-            let d1 = codec::BBGZDecoder::builder()
-                .with_path(&**input_r1.path())
-                .with_opt_rayon_pool(Arc::clone(&r1_rayon_pool))
-                .build();
+            let d1 = match open_fastq_decoder(
+                input_r1.path().path(),
+                Some(Arc::clone(&r1_rayon_pool)),
+                Some(Arc::clone(&r1_progress)),
+            ) {
+                Ok(decoder) => decoder,
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "R1 reader failed to open {}",
+                        input_r1.path().display()
+                    )));
+                }
+            };
             let p1 = parse::Fastq::builder().build();
 
             let mut s1 = Stream::builder()
@@ -1861,17 +2122,29 @@ fn spawn_single_readers(
             let mut r1_batch = Vec::with_capacity(batch_capacity);
             let mut r2_batch = Vec::with_capacity(batch_capacity);
             let mut stop_reading = false;
+            let mut read_error = None;
 
-            while let Ok(Some(record)) = q1.next() {
+            loop {
+                let record = match q1.next() {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(err) => {
+                        read_error = Some(err);
+                        stop_reading = true;
+                        break;
+                    }
+                };
                 if max_read_pairs.is_some_and(|limit| records_read >= limit) {
                     stop_reading = true;
                     break;
                 }
-                let permit = r1_read_memory_limiter.acquire(estimate_fastq_record_bytes(&record));
+                let permit = ReadMemoryPermit::new(
+                    r1_read_memory_limiter.acquire(estimate_fastq_record_bytes(&record)),
+                );
                 r1_batch.push(Budgeted::new(record, permit));
                 records_read += 1;
                 let dummy_record_r2 = bascet_io::parse::fastq::Record::empty();
-                let dummy_permit = r1_read_memory_limiter.acquire(0);
+                let dummy_permit = ReadMemoryPermit::new(r1_read_memory_limiter.acquire(0));
                 r2_batch.push(Budgeted::new(dummy_record_r2, dummy_permit));
 
                 if r1_batch.len() >= batch_capacity {
@@ -1884,7 +2157,7 @@ fn spawn_single_readers(
                         break;
                     }
                     if ticker.tick() {
-                        let (read_used, read_max_used, read_waits) = r1_read_memory_limiter.stats();
+                        let read_memory = r1_read_memory_limiter.stats();
                         debug!(
                             stage = "reader-single",
                             r1_queue_len = r1_tx.len(),
@@ -1892,9 +2165,9 @@ fn spawn_single_readers(
                             r2_queue_len = r2_tx.len(),
                             r2_queue_cap = r2_tx.capacity().unwrap_or(0),
                             records_read,
-                            read_memory_used = %ByteSize(read_used as u64),
-                            read_memory_max_used = %ByteSize(read_max_used as u64),
-                            read_memory_wait_count = read_waits,
+                            read_memory_used = %ByteSize(read_memory.used as u64),
+                            read_memory_max_used = %ByteSize(read_memory.max_used as u64),
+                            read_memory_wait_count = read_memory.wait_count,
                             "getraw pipeline telemetry"
                         );
                     }
@@ -1908,18 +2181,202 @@ fn spawn_single_readers(
                 unsafe {
                     s1.shutdown();
                 }
+                if let Some(err) = read_error {
+                    return Err(
+                        err.context(format!("R1 reader failed on {}", input_r1.path().display()))
+                    );
+                }
                 break;
             }
             debug!("R1 finished reading");
         }
         r1_stage_timings.add_read(read_started.elapsed());
+        Ok(())
+    });
+
+    let handle_r2 = spawn_coordinator("getraw-read-r2", 1, move || Ok(()));
+
+    return ((r1_rx, r2_rx), (handle_r1, handle_r2));
+}
+
+///
+/// Given R1 input paths holding alternating mates, spawn a reader splitting them into R1/R2
+///
+fn spawn_interleaved_readers(
+    vec_input: Vec<InputPath>,
+    budget: &GetrawBudget,
+    stream_arena: ByteSize,
+    read_memory_limiter: Arc<MemoryLimiter>,
+    rayon_pool: Arc<rayon::ThreadPool>,
+    stage_timings: Arc<GetRawStageTimings>,
+    progress: Arc<Progress>,
+    max_read_pairs: Option<u64>,
+) -> (
+    (
+        Receiver<BudgetedFastqRecordBatch>,
+        Receiver<BudgetedFastqRecordBatch>,
+    ),
+    (
+        JoinHandle<anyhow::Result<()>>,
+        JoinHandle<anyhow::Result<()>>,
+    ),
+) {
+    let batch_capacity = read_pair_batch_capacity(budget);
+    let queue_capacity = record_queue_capacity(budget).div_ceil(batch_capacity);
+    let (r1_tx, r1_rx) = crossbeam::channel::bounded(queue_capacity);
+    let (r2_tx, r2_rx) = crossbeam::channel::bounded(queue_capacity);
+    let sizeof_stream_each_buffer = ByteSize(budget.mem::<MStreamBuffer>().as_u64() / 2);
+    let shared_alloc = Arc::new(ArenaPool::new(sizeof_stream_each_buffer, stream_arena));
+
+    let handle_r1 = spawn_coordinator("getraw-read-interleaved", 0, move || {
+        let read_started = Instant::now();
+        let thread = std::thread::current();
+        let thread_name = thread.name().unwrap_or("unknown thread");
+        debug!(thread = thread_name, "Starting interleaved reader");
+
+        let mut ticker = PipelineTicker::new(telemetry_interval());
+        let mut pairs_read = 0u64;
+        for input in &vec_input {
+            if max_read_pairs.is_some_and(|limit| pairs_read >= limit) {
+                break;
+            }
+            let d1 = match open_fastq_decoder(
+                input.path().path(),
+                Some(Arc::clone(&rayon_pool)),
+                Some(Arc::clone(&progress)),
+            ) {
+                Ok(decoder) => decoder,
+                Err(err) => {
+                    return Err(err.context(format!(
+                        "Interleaved reader failed to open {}",
+                        input.path().display()
+                    )));
+                }
+            };
+            let p1 = parse::Fastq::builder().build();
+
+            let mut s1 = Stream::builder()
+                .with_decoder(d1)
+                .with_parser(p1)
+                .with_opt_decode_arena_pool(Arc::clone(&shared_alloc))
+                .build();
+
+            let mut q1 = s1.query::<fastq::Record>();
+            let mut r1_batch: BudgetedFastqRecordBatch = Vec::with_capacity(batch_capacity);
+            let mut r2_batch = Vec::with_capacity(batch_capacity);
+            let mut stop_reading = false;
+            let mut read_error = None;
+
+            loop {
+                let record = match q1.next() {
+                    Ok(Some(record)) => record,
+                    Ok(None) => break,
+                    Err(err) => {
+                        read_error = Some(err);
+                        stop_reading = true;
+                        break;
+                    }
+                };
+                let permit = ReadMemoryPermit::new(
+                    read_memory_limiter.acquire(estimate_fastq_record_bytes(&record)),
+                );
+                if r1_batch.len() == r2_batch.len() {
+                    if max_read_pairs.is_some_and(|limit| pairs_read >= limit) {
+                        stop_reading = true;
+                        break;
+                    }
+                    r1_batch.push(Budgeted::new(record, permit));
+                    continue;
+                }
+                // NOTE:    r1_batch is one ahead here, so its last record is the mate of this one
+                let name_r1 = mate_name(r1_batch[r1_batch.len() - 1].get_ref::<Id>());
+                let name_r2 = mate_name(record.get_ref::<Id>());
+                if name_r1 != name_r2 {
+                    read_error = Some(anyhow::anyhow!(
+                        "mates {:?} and {:?} have different names; is the input interleaved?",
+                        String::from_utf8_lossy(name_r1),
+                        String::from_utf8_lossy(name_r2)
+                    ));
+                    stop_reading = true;
+                    break;
+                }
+                r2_batch.push(Budgeted::new(record, permit));
+                pairs_read += 1;
+
+                if r2_batch.len() >= batch_capacity {
+                    let send_r1 =
+                        std::mem::replace(&mut r1_batch, Vec::with_capacity(batch_capacity));
+                    let send_r2 =
+                        std::mem::replace(&mut r2_batch, Vec::with_capacity(batch_capacity));
+                    if r1_tx.send(send_r1).is_err() || r2_tx.send(send_r2).is_err() {
+                        stop_reading = true;
+                        break;
+                    }
+                    if ticker.tick() {
+                        let read_memory = read_memory_limiter.stats();
+                        debug!(
+                            stage = "reader-interleaved",
+                            r1_queue_len = r1_tx.len(),
+                            r1_queue_cap = r1_tx.capacity().unwrap_or(0),
+                            r2_queue_len = r2_tx.len(),
+                            r2_queue_cap = r2_tx.capacity().unwrap_or(0),
+                            pairs_read,
+                            read_memory_used = %ByteSize(read_memory.used as u64),
+                            read_memory_max_used = %ByteSize(read_memory.max_used as u64),
+                            read_memory_wait_count = read_memory.wait_count,
+                            "getraw pipeline telemetry"
+                        );
+                    }
+                }
+            }
+            if read_error.is_none() && r1_batch.len() > r2_batch.len() {
+                warn!(
+                    path = %input.path().display(),
+                    "Interleaved input has an odd number of records; dropping the unpaired last read"
+                );
+                r1_batch.pop();
+            }
+            if !r2_batch.is_empty() {
+                let _ = r1_tx.send(r1_batch);
+                let _ = r2_tx.send(r2_batch);
+            }
+            if stop_reading {
+                unsafe {
+                    s1.shutdown();
+                }
+                if let Some(err) = read_error {
+                    return Err(err.context(format!(
+                        "Interleaved reader failed on {}",
+                        input.path().display()
+                    )));
+                }
+                break;
+            }
+            debug!("Interleaved input finished reading");
+        }
+        stage_timings.add_read(read_started.elapsed());
+        Ok(())
     });
 
-    let handle_r2 = spawn_coordinator("getraw-read-r2", 1, move || {});
+    let handle_r2 = spawn_coordinator("getraw-read-r2", 1, move || Ok(()));
 
     return ((r1_rx, r2_rx), (handle_r1, handle_r2));
 }
 
+///
+/// Read name up to the first whitespace and without a /1 or /2 mate suffix, which is what
+/// the two mates of an interleaved pair share
+///
+fn mate_name(id: &[u8]) -> &[u8] {
+    let name = id
+        .split(|b| b.is_ascii_whitespace())
+        .next()
+        .unwrap_or_default();
+    name.strip_suffix(b"/1")
+        .or_else(|| name.strip_suffix(b"/2"))
+        .unwrap_or(name)
+}
+
 ///
 /// Route inputs from two readers into a stream of paired end
 ///
@@ -1928,6 +2385,7 @@ fn spawn_debarcode_router(
     r2_rx: Receiver<BudgetedFastqRecordBatch>,
     budget: &GetrawBudget,
     batch_stats: Arc<GetRawBatchStats>,
+    progress: Arc<Progress>,
 ) -> (Receiver<BudgetedReadPairBatch>, JoinHandle<()>) {
     let queue_capacity = chunk_queue_capacity(budget);
     let (rp_tx, rp_rx) = crossbeam::channel::bounded(queue_capacity);
@@ -1955,6 +2413,7 @@ fn spawn_debarcode_router(
 
                     if !batch.is_empty() {
                         batch_stats.record_read_pair_batch(batch.len());
+                        progress.add(batch.len() as u64, 0, 0);
                         if rp_tx.send(batch).is_err() {
                             break;
                         }
@@ -1996,24 +2455,19 @@ fn spawn_debarcode_router(
 ///
 /// Sample a couple of reads for the purpose of analyzing the content
 ///
-fn sample_reads(
+pub(crate) fn sample_reads(
     input_path: &InputPath,
-    s: &GetRawCMD,
-    budget: &GetrawBudget,
+    sizeof_stream_arena: ByteSize,
     readname: &str,
-) -> Vec<fastq::OwnedRecord> {
-    let _ = budget;
-    let decoder = codec::BBGZDecoder::builder()
-        .with_path(input_path.path().path())
-        .countof_threads(BoundedU64::new_saturating(1))
-        .build();
+) -> anyhow::Result<Vec<fastq::OwnedRecord>> {
+    let decoder = open_fastq_decoder(input_path.path().path(), None, None)?;
 
     let p1 = parse::Fastq::builder().build();
 
     let mut streamer = Stream::builder()
         .with_decoder(decoder)
         .with_parser(p1)
-        .sizeof_decode_arena(s.sizeof_stream_arena)
+        .sizeof_decode_arena(sizeof_stream_arena)
         .sizeof_decode_buffer(ByteSize::mib(64))
         .countof_buffers(BoundedUsize::new_saturating(2))
         .build();
@@ -2033,7 +2487,7 @@ fn sample_reads(
     unsafe {
         streamer.shutdown();
     }
-    list_reads
+    Ok(list_reads)
 }
 
 ///
@@ -2224,8 +2678,8 @@ fn spawn_debarcode_workers(
 fn spawn_collector(
     db_rx: Receiver<BudgetedDebarcodedRecordBatch>,
     budget: &GetrawBudget,
-    read_memory_limiter: Arc<ReadMemoryLimiter>,
-    sort_memory_limiter: Arc<ByteLimiter>,
+    read_memory_limiter: Arc<MemoryLimiter>,
+    sort_memory_limiter: Arc<MemoryLimiter>,
     batch_stats: Arc<GetRawBatchStats>,
     stage_timings: Arc<GetRawStageTimings>,
     first_round_sort_chunk_target: Arc<AtomicU64>,
@@ -2235,7 +2689,7 @@ fn spawn_collector(
     let min_sort_chunk_size = first_round_sort_chunk_min_size(budget);
     let max_sort_chunk_size = first_round_sort_chunk_max_size(budget);
     let mut countof_each_sort_alloc = 0;
-    let (_, _, mut last_read_memory_wait_count) = read_memory_limiter.stats();
+    let mut last_read_memory_wait_count = read_memory_limiter.stats().wait_count;
     first_round_sort_chunk_target.store(sizeof_each_sort_alloc.as_u64(), Ordering::Relaxed);
 
     info!(
@@ -2285,7 +2739,9 @@ fn spawn_collector(
                 return false;
             }
 
-            let (read_memory_used, _, read_memory_wait_count) = read_memory_limiter.stats();
+            let read_memory = read_memory_limiter.stats();
+            let (read_memory_used, read_memory_wait_count) =
+                (read_memory.used, read_memory.wait_count);
             let read_wait_delta =
                 read_memory_wait_count.saturating_sub(*last_read_memory_wait_count);
             *last_read_memory_wait_count = read_memory_wait_count;
@@ -2293,7 +2749,7 @@ fn spawn_collector(
             let old_sort_chunk_size = sizeof_each_sort_alloc.as_u64();
             let mut new_sort_chunk_size = old_sort_chunk_size;
             let read_memory_high =
-                read_memory_used.saturating_mul(5) >= read_memory_limiter.cap.saturating_mul(4);
+                read_memory_used.saturating_mul(5) >= read_memory_limiter.cap().saturating_mul(4);
             if read_wait_delta >= 16 && read_memory_high {
                 new_sort_chunk_size = old_sort_chunk_size.saturating_mul(85) / 100;
             } else if read_wait_delta == 0 && !read_memory_high {
@@ -2395,7 +2851,7 @@ fn spawn_collector(
                     }
                     stage_timings.add_collect(collect_started.elapsed());
                     if ticker.tick() {
-                        let (sort_used, sort_max_used, sort_waits) = sort_memory_limiter.stats();
+                        let sort_memory = sort_memory_limiter.stats();
                         debug!(
                             stage = "collector",
                             debarcode_queue_len = db_rx.len(),
@@ -2405,9 +2861,9 @@ fn spawn_collector(
                             collection_records = collection_buffer.len(),
                             collection_bytes = %sizeof_sort_alloc,
                             target_first_round_sort_chunk_size = %sizeof_each_sort_alloc,
-                            sort_memory_used = %ByteSize(sort_used as u64),
-                            sort_memory_max_used = %ByteSize(sort_max_used as u64),
-                            sort_memory_wait_count = sort_waits,
+                            sort_memory_used = %ByteSize(sort_memory.used as u64),
+                            sort_memory_max_used = %ByteSize(sort_memory.max_used as u64),
+                            sort_memory_wait_count = sort_memory.wait_count,
                             "getraw pipeline telemetry"
                         );
                     }
@@ -2529,8 +2985,10 @@ fn spawn_chunk_writers(
     path_temp_dir: PathBuf,
     budget: &GetrawBudget,
     compression_level: Compression,
+    format: BlockFormat,
     chemistry: GetRawChemistry,
     library: &str,
+    metadata: &[u8],
     rayon_pool: Arc<rayon::ThreadPool>,
     compression_limiter: Arc<BBGZCompressionLimiter>,
     stage_timings: Arc<GetRawStageTimings>,
@@ -2551,6 +3009,7 @@ fn spawn_chunk_writers(
     ));
     let timestamp_temp_files = Arc::new(timestamp_temp_files);
     let library: Arc<str> = Arc::from(library);
+    let metadata: Arc<[u8]> = Arc::from(metadata);
     let chemistry = Arc::new(chemistry);
 
     let thread_handle = spawn_coordinator("getraw-write-dispatch", 0, move || {
@@ -2572,6 +3031,7 @@ fn spawn_chunk_writers(
             let task_timestamp_temp_files = Arc::clone(&timestamp_temp_files);
             let task_path_temp_dir = path_temp_dir.clone();
             let task_library = Arc::clone(&library);
+            let task_metadata = Arc::clone(&metadata);
             let task_chemistry = Arc::clone(&chemistry);
             let task_raw_arena = Arc::clone(&shared_raw_arena);
             let task_compression_arena = Arc::clone(&shared_compression_arena);
@@ -2592,7 +3052,7 @@ fn spawn_chunk_writers(
                         format!("{}_merge_0_{chunk_index}", *task_timestamp_temp_files);
                     let temp_pathbuf = task_path_temp_dir
                         .join(temp_fname)
-                        .with_extension("tirp.bbgz");
+                        .with_extension(format.tirp_extension());
 
                     let temp_output_path = match OutputPath::try_from(&temp_pathbuf) {
                         Ok(path) => path,
@@ -2618,6 +3078,7 @@ fn spawn_chunk_writers(
                     );
                     let mut bbgzwriter = BBGZWriter::builder()
                         .compression_level(compression_level)
+                        .format(format)
                         .with_opt_raw_arena_pool(Arc::clone(&task_raw_arena))
                         .with_opt_compression_arena_pool(Arc::clone(&task_compression_arena))
                         .with_opt_compression_limiter(Arc::clone(&task_compression_limiter))
@@ -2632,6 +3093,7 @@ fn spawn_chunk_writers(
                     let mut current_hist_count = 0u64;
                     let mut chunk_histogram_counts = HistogramCounts::new();
                     let mut blockwriter_opt: Option<BBGZWriteBlock<'_>> = None;
+                    let mut metadata_pending = true;
 
                     let library_bytes = task_library.as_bytes();
                     let library_sep = if task_library.is_empty() { "" } else { "_" };
@@ -2664,6 +3126,18 @@ fn spawn_chunk_writers(
                             unsafe {
                                 bbgzheader.add_extra_unchecked(b"ID", prefixed_id);
                             }
+                            // every chunk carries the metadata, as any of them may be
+                            // published as an output without being merged
+                            if metadata_pending {
+                                metadata_pending = false;
+                                // SAFETY: the header only carries the ID subfield so far
+                                unsafe {
+                                    bbgzheader.add_extra_unchecked(
+                                        &METADATA_SUBFIELD,
+                                        task_metadata.to_vec(),
+                                    );
+                                }
+                            }
                             blockwriter_opt = Some(bbgzwriter.begin(bbgzheader));
                         }
 
@@ -2781,6 +3255,7 @@ fn spawn_mergesort_workers(
     path_temp: PathBuf,
     budget: &GetrawBudget,
     sizeof_stream_arena: ByteSize,
+    metadata: BBGZMetadata,
 ) {
     let mut shardify_cmd = ShardifyCMD {
         paths_in,
@@ -2795,6 +3270,8 @@ fn spawn_mergesort_workers(
 
         show_filter_warning: false,
         show_startup_message: true,
+        metadata,
+        report_outputs: false,
     };
 
     if let Err(e) = shardify_cmd.try_execute() {
@@ -2831,10 +3308,17 @@ fn spawn_histogram_workers(
             let thread_name = thread.name().unwrap_or("unknown thread");
             debug!(thread = thread_name, processing_histogram_for = %output_path, "Starting histogram worker");
 
-            let decoder = codec::BBGZDecoder::builder()
+            let decoder = match codec::BBGZDecoder::builder()
                 .with_path(&**output_path.path())
                 .with_opt_rayon_pool(thread_rayon_pool)
-                .build();
+                .build()
+            {
+                Ok(decoder) => decoder,
+                Err(e) => {
+                    error!(path = %output_path, error = %e, "Failed to open output for histogram");
+                    panic!("Failed to open output for histogram");
+                }
+            };
             let parser = parse::Tirp::builder().build();
 
             let mut stream = Stream::builder()
@@ -2852,17 +3336,8 @@ fn spawn_histogram_workers(
                     "id_current < id_context",
                 );
 
-            let hist_final_path = hist_path.path().path().to_path_buf();
-            let hist_tmp_path = atomic_temp_path(&hist_final_path);
-            let hist_file = match File::create(&hist_tmp_path) {
-                Ok(file) => file,
-                Err(e) => {
-                    error!(path = ?hist_tmp_path, error = %e, "Failed to create output file");
-                    panic!("Failed to create output file");
-                }
-            };
-            let mut bufwriter = BufWriter::new(hist_file);
-
+            // NOTE:    the output is cell-sorted, so each cell is one run of records
+            let mut histogram_counts = HistogramCounts::new();
             let mut current_id: SmallVec<[u8; 16]> = SmallVec::new();
             let mut current_count: u64 = 0;
 
@@ -2872,30 +3347,20 @@ fn spawn_histogram_workers(
                     current_count += 1;
                 } else {
                     if !current_id.is_empty() {
-                        bufwriter.write_all(&current_id).unwrap();
-                        bufwriter.write_all(b"\t").unwrap();
-                        bufwriter
-                            .write_all(current_count.to_string().as_bytes())
-                            .unwrap();
-                        bufwriter.write_all(b"\n").unwrap();
+                        histogram_counts.insert(current_id.to_vec(), current_count);
                     }
                     current_id = id.to_smallvec();
                     current_count = 1;
                 }
             }
             if !current_id.is_empty() {
-                bufwriter.write_all(&current_id).unwrap();
-                bufwriter.write_all(b"\t").unwrap();
-                bufwriter
-                    .write_all(current_count.to_string().as_bytes())
-                    .unwrap();
-                bufwriter.write_all(b"\n").unwrap();
+                histogram_counts.insert(current_id.to_vec(), current_count);
             }
 
-            bufwriter.flush().unwrap();
-            drop(bufwriter);
-            publish_atomic_output(&hist_tmp_path, &hist_final_path).unwrap();
-            debug!("Wrote histogram at {}", hist_path);
+            if let Err(e) = write_histogram_counts(&hist_path, &histogram_counts) {
+                error!(path = %hist_path, error = %e, "Failed to write histogram");
+                panic!("Failed to write histogram");
+            }
         });
         thread_handles.push(worker_handle);
     }
@@ -2918,3 +3383,134 @@ pub struct DebarcodedRecord {
     #[serde(skip)]
     arena_backing: smallvec::SmallVec<[ArenaView<u8>; 2]>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bascet_io::MAX_SIZEOF_METADATA;
+    use bascet_runtime::report::RunResources;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct GetRawCli {
+        #[command(flatten)]
+        cmd: GetRawCMD,
+    }
+
+    #[test]
+    fn sample_sheet_provenance_drops_inputs_over_cap() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sheet = String::from("sample,library,r1\n");
+        for library in ["lib1", "lib2"] {
+            let mut paths_r1 = Vec::new();
+            for lane in 0..80 {
+                let name = format!("{library}_a_rather_long_run_name_L{lane:03}_R1_001.fastq.gz");
+                std::fs::write(dir.path().join(&name), b"").unwrap();
+                paths_r1.push(name);
+            }
+            sheet.push_str(&format!("s1,{library},{}\n", paths_r1.join(";")));
+        }
+        let path_sheet = dir.path().join("sheet.csv");
+        let sheet = SampleSheet::parse(sheet.as_bytes(), dir.path()).unwrap();
+
+        let cli = GetRawCli::try_parse_from([
+            "getraw",
+            "--sample-sheet",
+            path_sheet.to_str().unwrap(),
+            "-o",
+            dir.path().join("out.tirp.gz").to_str().unwrap(),
+            "atrandi-wgs",
+        ])
+        .unwrap();
+        let mut libraries = BTreeMap::new();
+        for row in &sheet.rows {
+            let paths_r1 = sample_sheet_inputs(&row.paths_r1).unwrap();
+            libraries.insert(
+                row.library.as_str(),
+                (GetRawChemistryCMD::AtrandiWGS, paths_r1, Vec::new()),
+            );
+        }
+        let rows: Vec<_> = sheet.rows.iter().collect();
+        let metadata = cli
+            .cmd
+            .sample_sheet_provenance(&path_sheet, &rows, &libraries);
+        assert_eq!(metadata.get_all("source.input_r1").count(), 160);
+
+        let bytes = metadata.to_bytes().unwrap();
+        assert!(bytes.len() <= MAX_SIZEOF_METADATA);
+        let parsed = BBGZMetadata::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            parsed.get_all("library").collect::<Vec<_>>(),
+            ["lib1", "lib2"]
+        );
+        assert_eq!(
+            parsed.get_all("sample.sample").collect::<Vec<_>>(),
+            ["lib1=s1", "lib2=s1"]
+        );
+        assert!(parsed.get_all("source.input_r1").count() < 160);
+    }
+
+    fn write_tirp(path: &Path, ids: &[&[u8]]) {
+        let mut bbgzwriter = BBGZWriter::builder()
+            .countof_threads(BoundedU64::new_saturating(1))
+            .with_writer(std::fs::File::create(path).unwrap())
+            .build();
+        for id in ids {
+            let mut bbgzheader = BBGZHeader::new();
+            unsafe {
+                bbgzheader.add_extra_unchecked(b"ID", id.to_vec());
+            }
+            let mut blockwriter = bbgzwriter.begin(bbgzheader);
+            blockwriter
+                .write_all(&[*id, b"\t1\t1\tACGT\tTT\tIIII\tII\tU1\n"].concat())
+                .unwrap();
+            blockwriter.flush().unwrap();
+        }
+        bbgzwriter.finish_async().join().unwrap();
+    }
+
+    #[test]
+    fn merged_debarcode_reports_only_its_published_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths_in = Vec::new();
+        for (i, ids) in [[b"A1", b"C3"], [b"B2", b"D4"], [b"A1", b"E5"]]
+            .iter()
+            .enumerate()
+        {
+            let path = dir.path().join(format!("chunk_{i}.tirp.gz"));
+            write_tirp(&path, &ids.map(|id| id.as_slice()));
+            paths_in.push(path.display().to_string());
+        }
+        let path_out = dir.path().join("out.tirp.gz");
+
+        let mut cli = GetRawCli::try_parse_from([
+            "getraw",
+            "--skip-debarcode",
+            paths_in.join(",").as_str(),
+            "-o",
+            path_out.to_str().unwrap(),
+            "--threads",
+            "6",
+            "--memory",
+            "1GiB",
+            "atrandi-wgs",
+        ])
+        .unwrap();
+        cli.cmd.try_execute().unwrap();
+
+        // NOTE:    the merge round writes through shardify into the output directory, and its
+        //          temp file must not be listed
+        let outputs: Vec<_> = RunReport::summary(true, None, RunResources::default())
+            .outputs
+            .into_iter()
+            .filter(|path| path.starts_with(dir.path()))
+            .collect();
+        assert_eq!(
+            outputs,
+            [
+                path_out.clone(),
+                PathBuf::from(format!("{}.hist", path_out.display()))
+            ]
+        );
+    }
+}
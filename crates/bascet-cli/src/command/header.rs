@@ -0,0 +1,71 @@
+//! `header` subcommand: inspect the provenance metadata stored in TIRP/BBGZ files.
+//!
+//! `header show` prints the `key<TAB>value` entries of each input. With several inputs, each
+//! is preceded by a `# <path>` line and the inputs are checked for compatible lineage
+//! (e.g. the same chemistry), failing if they would not merge cleanly.
+
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+use bascet_io::BBGZMetadata;
+use clap::{Args, Subcommand};
+
+#[derive(Args)]
+pub struct HeaderCMD {
+    #[command(subcommand)]
+    pub action: HeaderAction,
+}
+
+#[derive(Subcommand)]
+pub enum HeaderAction {
+    /// Print the metadata of one or more TIRP/BBGZ files.
+    Show {
+        /// TIRP/BBGZ files (comma-separated).
+        #[arg(short = 'i', long = "in", value_delimiter = ',', required = true)]
+        paths_in: Vec<PathBuf>,
+    },
+}
+
+impl HeaderCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        match &self.action {
+            HeaderAction::Show { paths_in } => show(paths_in),
+        }
+    }
+}
+
+fn show(paths_in: &[PathBuf]) -> Result<()> {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let mut all_metadata: Vec<(&PathBuf, BBGZMetadata)> = Vec::new();
+
+    for path in paths_in {
+        let metadata = BBGZMetadata::read_from_path(path)
+            .with_context(|| format!("read metadata from {}", path.display()))?;
+        if paths_in.len() > 1 {
+            writeln!(out, "# {}", path.display())?;
+        }
+        match metadata {
+            Some(metadata) => {
+                metadata.write_text(&mut out)?;
+                all_metadata.push((path, metadata));
+            }
+            None => writeln!(out, "# no metadata")?,
+        }
+    }
+    out.flush()?;
+
+    if let Some(((first_path, first), rest)) = all_metadata.split_first() {
+        for (path, metadata) in rest {
+            if let Err(e) = first.check_compatible(metadata) {
+                bail!(
+                    "{} and {} are incompatible: {e}",
+                    first_path.display(),
+                    path.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,433 @@
+//! `import-bam` subcommand: turn a CB/UB-tagged BAM (Cell Ranger, STARsolo; usually
+//! coordinate-sorted) into a cell-sorted BBGZ TIRP that `shardify` can consume.
+//!
+//! Records are externally sorted by (cell tag, read name, READ1/READ2) with the `samtools_rs`
+//! spill + merge machinery, so memory stays within `--memory` whatever the input size. Mates
+//! then arrive adjacent and are rebuilt into read pairs in sequenced orientation. Secondary and
+//! supplementary alignments and reads without a cell tag are dropped.
+//!
+//! Output layout matches `getraw`: one BBGZ block run per cell with the cell ID in the `ID`
+//! header subfield, plus a `<out>.hist` read-count histogram.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use bascet_io::{BBGZHeader, BBGZMetadata, BBGZWriteBlock, BBGZWriter, METADATA_SUBFIELD};
+use bounded_integer::BoundedU64;
+use bytesize::ByteSize;
+use clap::Args;
+use crossbeam_channel::{Receiver, bounded};
+use tracing::info;
+
+use super::bamsort::{DEFAULT_MEMORY, DEFAULT_PATH_TEMP, sort_mem_per_chunk};
+use super::determine_thread_counts_1;
+use super::samtools_rs::bam::{Record, decode_qual, decode_seq};
+use super::samtools_rs::sort::{Order, ReferenceOrder, SortOptions, sort_streaming_parallel_into};
+use crate::fileformat::shard::ReadPair;
+use crate::fileformat::tirp::get_histogram_path_for_tirp;
+use crate::utils::{atomic_temp_path, publish_atomic_output, write_histogram};
+
+/// Read pairs handed from the sort sink to the TIRP writer thread per message.
+const PAIR_BATCH_SIZE: usize = 4096;
+
+const BAM_FPAIRED: u16 = 0x1;
+const BAM_FREVERSE: u16 = 0x10;
+const BAM_FREAD1: u16 = 0x40;
+const BAM_FREAD2: u16 = 0x80;
+const BAM_FSECONDARY_OR_SUPPLEMENTARY: u16 = 0x900;
+
+#[derive(Args)]
+pub struct ImportBamCMD {
+    /// Input BAM with cell barcode (and optionally UMI) tags, in any sort order.
+    #[arg(short = 'i', long = "in", value_parser)]
+    pub path_in: PathBuf,
+
+    /// Output cell-sorted BBGZ TIRP. A `<out>.hist` histogram is written alongside.
+    #[arg(short = 'o', long = "out", value_parser)]
+    pub path_out: PathBuf,
+
+    /// Tag holding the corrected cell barcode.
+    #[arg(long = "cell-tag", value_parser = parse_tag, default_value = "CB")]
+    pub cell_tag: [u8; 2],
+
+    /// UMI tags, comma-separated; the first one present on a read is used.
+    #[arg(long = "umi-tag", value_parser = parse_tag, value_delimiter = ',', default_value = "UB")]
+    pub umi_tags: Vec<[u8; 2]>,
+
+    /// Directory for spill chunks. Cleaned up after a successful import.
+    #[arg(short = 't', long = "temp", value_parser, default_value = DEFAULT_PATH_TEMP)]
+    pub path_temp: PathBuf,
+
+    /// Total in-memory budget for the sort phase, split across threads.
+    #[arg(short = 'm', long = "memory", value_parser, default_value = DEFAULT_MEMORY)]
+    pub memory: ByteSize,
+
+    /// Total threads.
+    #[arg(short = '@', long = "threads", value_parser = clap::value_parser!(usize))]
+    pub num_threads: Option<usize>,
+}
+
+fn parse_tag(s: &str) -> Result<[u8; 2], String> {
+    match s.as_bytes() {
+        [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphanumeric() => Ok([*a, *b]),
+        _ => Err(format!("{s:?} is not a two-character SAM tag")),
+    }
+}
+
+impl ImportBamCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        let num_threads = determine_thread_counts_1(self.num_threads)?;
+        std::fs::create_dir_all(&self.path_temp)
+            .with_context(|| format!("failed to create temp dir {}", self.path_temp.display()))?;
+
+        let opts = SortOptions {
+            order: Order::TagName(self.cell_tag),
+            reference_order: ReferenceOrder::Preserve,
+            level: 1,
+            arg_list: None,
+            no_pg: true,
+            max_mem: sort_mem_per_chunk(self.memory, num_threads)?,
+            tmp_prefix: self.path_temp.join("bascet-import-bam"),
+            threads: num_threads,
+            write_index: None,
+        };
+        info!(
+            input = %self.path_in.display(),
+            output = %self.path_out.display(),
+            cell_tag = %String::from_utf8_lossy(&self.cell_tag),
+            max_mem_per_chunk = opts.max_mem,
+            threads = num_threads,
+            "ImportBam: starting"
+        );
+
+        let path_out_tmp = atomic_temp_path(&self.path_out);
+        let output_file = File::create(&path_out_tmp)
+            .with_context(|| format!("create output TIRP tmp {}", path_out_tmp.display()))?;
+        let mut metadata = BBGZMetadata::for_current_process();
+        metadata
+            .push("input", self.path_in.display().to_string())
+            .push("cell_tag", String::from_utf8_lossy(&self.cell_tag));
+        let (tx, rx) = bounded::<Vec<(Vec<u8>, ReadPair)>>(num_threads.max(2));
+        let writer = std::thread::Builder::new()
+            .name("ImportBamWriter".to_string())
+            .spawn(move || write_tirp(output_file, num_threads, &metadata, rx))
+            .context("failed to spawn import-bam TIRP writer")?;
+
+        let mut assembler = PairAssembler::new(self.cell_tag, self.umi_tags.clone());
+        let input_file = File::open(&self.path_in)
+            .with_context(|| format!("open input BAM {}", self.path_in.display()))?;
+        let sorted = sort_streaming_parallel_into(input_file, &opts, |record| {
+            assembler.push(record);
+            if assembler.pending_pairs() >= PAIR_BATCH_SIZE {
+                tx.send(assembler.take_pairs())
+                    .map_err(|_| std::io::Error::other("import-bam TIRP writer stopped early"))?;
+            }
+            Ok(())
+        });
+        assembler.finish();
+        let stats = assembler.stats;
+        if sorted.is_ok() {
+            let _ = tx.send(assembler.take_pairs());
+        }
+        drop(tx);
+
+        let written = writer
+            .join()
+            .map_err(|_| anyhow::anyhow!("import-bam TIRP writer panicked"))?;
+        sorted.with_context(|| format!("failed to sort {} by cell", self.path_in.display()))?;
+        let histogram = written?;
+
+        let path_hist = get_histogram_path_for_tirp(&self.path_out);
+        write_histogram(&path_hist, &histogram)?;
+        publish_atomic_output(&path_out_tmp, &self.path_out)?;
+        info!(
+            cells = histogram.len(),
+            pairs = stats.pairs,
+            single = stats.single,
+            skipped_secondary = stats.skipped_secondary,
+            skipped_no_cell = stats.skipped_no_cell,
+            "ImportBam: complete"
+        );
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct ImportStats {
+    pairs: u64,
+    single: u64,
+    skipped_secondary: u64,
+    skipped_no_cell: u64,
+}
+
+/// One decoded BAM record, back in sequenced orientation.
+struct Mate {
+    cell: Vec<u8>,
+    name: Vec<u8>,
+    umi: Vec<u8>,
+    seq: Vec<u8>,
+    qual: Vec<u8>,
+}
+
+/// Rebuilds read pairs from records sorted by `(cell, name, READ1/READ2)`. A READ1 is held
+/// until the next record; if that is its READ2 they form a pair, otherwise it is emitted as a
+/// single-end read (R2/Q2 empty), as are unpaired reads and READ2s whose mate was dropped.
+struct PairAssembler {
+    cell_tag: [u8; 2],
+    umi_tags: Vec<[u8; 2]>,
+    pending_r1: Option<Mate>,
+    pairs: Vec<(Vec<u8>, ReadPair)>,
+    stats: ImportStats,
+}
+
+impl PairAssembler {
+    fn new(cell_tag: [u8; 2], umi_tags: Vec<[u8; 2]>) -> Self {
+        Self {
+            cell_tag,
+            umi_tags,
+            pending_r1: None,
+            pairs: Vec::with_capacity(PAIR_BATCH_SIZE),
+            stats: ImportStats::default(),
+        }
+    }
+
+    fn push(&mut self, record: &Record) {
+        let flag = record.flag();
+        if flag & BAM_FSECONDARY_OR_SUPPLEMENTARY != 0 {
+            self.stats.skipped_secondary += 1;
+            return;
+        }
+        let Some(cell) = record.aux_str(self.cell_tag) else {
+            self.stats.skipped_no_cell += 1;
+            return;
+        };
+        let umi = self
+            .umi_tags
+            .iter()
+            .find_map(|tag| record.aux_str(*tag))
+            .unwrap_or_default();
+        let mate = Mate {
+            cell: cell.to_vec(),
+            name: record.read_name().to_vec(),
+            umi: umi.to_vec(),
+            seq: decode_seq(
+                record.seq_raw(),
+                record.l_seq() as usize,
+                flag & BAM_FREVERSE != 0,
+            ),
+            qual: decode_qual(record.qual(), flag & BAM_FREVERSE != 0),
+        };
+
+        let paired = flag & BAM_FPAIRED != 0;
+        if paired && flag & BAM_FREAD1 != 0 {
+            self.flush_pending();
+            self.pending_r1 = Some(mate);
+        } else if paired && flag & BAM_FREAD2 != 0 {
+            match self.pending_r1.take() {
+                Some(r1) if r1.name == mate.name && r1.cell == mate.cell => {
+                    self.emit(r1, Some(mate));
+                }
+                other => {
+                    self.pending_r1 = other;
+                    self.flush_pending();
+                    self.emit(mate, None);
+                }
+            }
+        } else {
+            self.flush_pending();
+            self.emit(mate, None);
+        }
+    }
+
+    fn finish(&mut self) {
+        self.flush_pending();
+    }
+
+    fn flush_pending(&mut self) {
+        if let Some(r1) = self.pending_r1.take() {
+            self.emit(r1, None);
+        }
+    }
+
+    fn emit(&mut self, r1: Mate, r2: Option<Mate>) {
+        let (r2_seq, q2) = match r2 {
+            Some(r2) => {
+                self.stats.pairs += 1;
+                (r2.seq, r2.qual)
+            }
+            None => {
+                self.stats.single += 1;
+                (Vec::new(), Vec::new())
+            }
+        };
+        self.pairs.push((
+            r1.cell,
+            ReadPair {
+                r1: r1.seq,
+                r2: r2_seq,
+                q1: r1.qual,
+                q2,
+                umi: r1.umi,
+            },
+        ));
+    }
+
+    fn pending_pairs(&self) -> usize {
+        self.pairs.len()
+    }
+
+    fn take_pairs(&mut self) -> Vec<(Vec<u8>, ReadPair)> {
+        std::mem::replace(&mut self.pairs, Vec::with_capacity(PAIR_BATCH_SIZE))
+    }
+}
+
+/// Write cell-sorted read pairs as BBGZ TIRP, one block run per cell; returns reads per cell.
+fn write_tirp(
+    output_file: File,
+    num_threads: usize,
+    metadata: &BBGZMetadata,
+    rx: Receiver<Vec<(Vec<u8>, ReadPair)>>,
+) -> Result<BTreeMap<Vec<u8>, u64>> {
+    let mut bbgzwriter = BBGZWriter::builder()
+        .countof_threads(BoundedU64::new_saturating(num_threads as u64))
+        .with_writer(output_file)
+        .build();
+    let mut histogram: BTreeMap<Vec<u8>, u64> = BTreeMap::new();
+    let mut current_cell: Option<Vec<u8>> = None;
+    let mut blockwriter_opt: Option<BBGZWriteBlock<'_>> = None;
+    let mut metadata_pending = Some(metadata.to_bytes()?);
+
+    for batch in rx {
+        for (cell, rp) in batch {
+            if current_cell.as_deref() != Some(cell.as_slice()) {
+                if let Some(mut blockwriter) = blockwriter_opt.take() {
+                    blockwriter.flush()?;
+                }
+                let mut bbgzheader = BBGZHeader::new();
+                unsafe {
+                    bbgzheader.add_extra_unchecked(b"ID", cell.clone());
+                }
+                if let Some(metadata) = metadata_pending.take() {
+                    bbgzheader
+                        .add_extra(&METADATA_SUBFIELD, metadata)
+                        .map_err(|_| anyhow::anyhow!("duplicate BBGZ metadata subfield"))?;
+                }
+                blockwriter_opt = Some(bbgzwriter.begin(bbgzheader));
+                current_cell = Some(cell);
+            }
+            let (Some(blockwriter), Some(cell)) = (blockwriter_opt.as_mut(), &current_cell) else {
+                unreachable!("a block is open for the current cell");
+            };
+
+            // Reserve space for the entire record to prevent splitting across blocks
+            let record_size = 11 // 8x '\t' + '1' + '1' + '\n'
+                + cell.len()
+                + rp.r1.len()
+                + rp.r2.len()
+                + rp.q1.len()
+                + rp.q2.len()
+                + rp.umi.len();
+            blockwriter.reserve(record_size);
+            for field in [
+                cell.as_slice(),
+                b"\t1\t1\t",
+                &rp.r1,
+                b"\t",
+                &rp.r2,
+                b"\t",
+                &rp.q1,
+                b"\t",
+                &rp.q2,
+                b"\t",
+                &rp.umi,
+                b"\n",
+            ] {
+                blockwriter.write_all(field)?;
+            }
+            *histogram.entry(cell.clone()).or_insert(0) += 1;
+        }
+    }
+
+    if let Some(mut blockwriter) = blockwriter_opt.take() {
+        blockwriter.flush()?;
+    }
+    bbgzwriter
+        .finish_async()
+        .join()
+        .map_err(|_| anyhow::anyhow!("BBGZ writer panicked while finishing"))?;
+    Ok(histogram)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(name: &[u8], flag: u16, cb: Option<&[u8]>, seq: &[u8], qual: &[u8]) -> Record {
+        let mut tags: Vec<(&[u8; 2], &[u8])> = cb.map(|cb| (b"CB", cb)).into_iter().collect();
+        tags.push((b"UB", b"GGT"));
+        Record::test_unmapped(name, flag, seq, qual, &tags)
+    }
+
+    #[test]
+    fn decode_seq_restores_sequenced_orientation() {
+        let r = rec(b"q", 0x10, Some(b"C1"), b"AACGN", b"ABCDE");
+        assert_eq!(decode_seq(r.seq_raw(), 5, false), b"AACGN");
+        assert_eq!(decode_seq(r.seq_raw(), 5, true), b"NCGTT");
+        assert_eq!(decode_qual(r.qual(), true), b"EDCBA");
+        assert_eq!(decode_qual(&[0xff, 0xff], false), b"\"\"");
+    }
+
+    #[test]
+    fn pair_assembler_rebuilds_pairs_and_singles() {
+        let mut assembler = PairAssembler::new(*b"CB", vec![*b"UB"]);
+        for r in [
+            rec(b"q9", 0x4, None, b"AC", b"II"),
+            rec(b"q1", 0x41, Some(b"C1"), b"AC", b"II"),
+            rec(b"q1", 0x91, Some(b"C1"), b"GG", b"AB"),
+            rec(b"q2", 0x41, Some(b"C2"), b"TT", b"II"),
+            rec(b"q3", 0x81, Some(b"C2"), b"CA", b"II"),
+            rec(b"q4", 0x141, Some(b"C2"), b"CA", b"II"),
+            rec(b"q5", 0x0, Some(b"C3"), b"GA", b"II"),
+        ] {
+            assembler.push(&r);
+        }
+        assembler.finish();
+
+        let pairs = assembler.take_pairs();
+        let summary: Vec<(&[u8], &[u8], &[u8], &[u8])> = pairs
+            .iter()
+            .map(|(cell, rp)| {
+                (
+                    cell.as_slice(),
+                    rp.r1.as_slice(),
+                    rp.r2.as_slice(),
+                    rp.q2.as_slice(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (&b"C1"[..], &b"AC"[..], &b"CC"[..], &b"BA"[..]),
+                (&b"C2"[..], &b"TT"[..], &b""[..], &b""[..]),
+                (&b"C2"[..], &b"CA"[..], &b""[..], &b""[..]),
+                (&b"C3"[..], &b"GA"[..], &b""[..], &b""[..]),
+            ]
+        );
+        assert!(pairs.iter().all(|(_, rp)| rp.umi == b"GGT"));
+        assert_eq!(assembler.stats.pairs, 1);
+        assert_eq!(assembler.stats.single, 3);
+        assert_eq!(assembler.stats.skipped_secondary, 1);
+        assert_eq!(assembler.stats.skipped_no_cell, 1);
+    }
+
+    #[test]
+    fn parse_tag_accepts_two_character_tags() {
+        assert_eq!(parse_tag("CB"), Ok(*b"CB"));
+        assert!(parse_tag("C").is_err());
+        assert!(parse_tag("CBX").is_err());
+    }
+}
@@ -0,0 +1,269 @@
+//! `index` subcommand: build the index of an existing BGZF file without rewriting it.
+//!
+//! * BAM (coordinate-sorted) → `<bam>.bai`, or `<bam>.csi` when a reference exceeds the BAI
+//!   limit. Same format choice and `--index-format`/`--csi-*` options as `bam-sort`.
+//! * Fragments file or TIRP → `<file>.tbi`. Both are BED-like text with the reference (or cell)
+//!   name and start/end in the first three columns, so they share one tabix path.
+//!
+//! Blocks are walked with `samtools_rs::bgzf::VirtualReader`, which also accepts Bascet's own
+//! BBGZ output (extra gzip subfields before BC).
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use noodles::bgzf::VirtualPosition;
+use noodles::csi::binning_index::index::reference_sequence::bin::Chunk;
+use tracing::info;
+
+use super::bamsort::{BamIndexArgs, index_path};
+use super::samtools_rs::bam::{Header, Record};
+use super::samtools_rs::bgzf::VirtualReader;
+use super::samtools_rs::index::BaiBuilder;
+use crate::utils::{BedTabixIndexer, atomic_temp_path, publish_atomic_output};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum IndexInputType {
+    /// BAM if the file starts with the BAM magic, otherwise BED-like text.
+    Auto,
+    /// Coordinate-sorted BAM.
+    Bam,
+    /// Fragments file or TIRP: name, start, end in the first three columns.
+    Bed,
+}
+
+#[derive(Args)]
+pub struct IndexCMD {
+    /// BGZF-compressed BAM, fragments file or TIRP to index.
+    #[arg(short = 'i', long = "in", value_parser)]
+    pub path_in: PathBuf,
+
+    /// Input type.
+    #[arg(long = "type", value_enum, default_value_t = IndexInputType::Auto)]
+    pub input_type: IndexInputType,
+
+    #[command(flatten)]
+    pub index: BamIndexArgs,
+}
+
+impl IndexCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        let input_type = match self.input_type {
+            IndexInputType::Auto => detect_input_type(&self.path_in)?,
+            other => other,
+        };
+        let path_index = match input_type {
+            IndexInputType::Bam => index_bam(&self.path_in, &self.index)?,
+            _ => index_bed(&self.path_in)?,
+        };
+        info!(
+            input = %self.path_in.display(),
+            index = %path_index.display(),
+            "Index: complete"
+        );
+        Ok(())
+    }
+}
+
+fn open_virtual_reader(path: &Path) -> Result<VirtualReader<BufReader<File>>> {
+    let file = File::open(path).with_context(|| format!("open input {}", path.display()))?;
+    Ok(VirtualReader::new(BufReader::new(file)))
+}
+
+fn detect_input_type(path: &Path) -> Result<IndexInputType> {
+    let mut reader = open_virtual_reader(path)?;
+    let mut magic = [0u8; 4];
+    let n = reader
+        .read(&mut magic)
+        .with_context(|| format!("{} is not BGZF-compressed", path.display()))?;
+    Ok(if &magic[..n] == b"BAM\x01" {
+        IndexInputType::Bam
+    } else {
+        IndexInputType::Bed
+    })
+}
+
+/// Index a coordinate-sorted BAM as BAI or CSI; returns the index path.
+pub fn index_bam(path_bam: &Path, index: &BamIndexArgs) -> Result<PathBuf> {
+    let mut reader = open_virtual_reader(path_bam)?;
+    let header = Header::read(&mut reader)
+        .with_context(|| format!("read BAM header of {}", path_bam.display()))?;
+    let format = index.resolve(&header)?;
+    let mut builder = BaiBuilder::with_format(&header, format);
+
+    // Unplaced reads (ref_id -1) sort last, which `as u32` gives for free.
+    let mut last_key = (0u32, i32::MIN);
+    let mut n_records: u64 = 0;
+    let mut buf = Vec::new();
+    loop {
+        let voffset_start = reader.virtual_position()?;
+        let Some(record) = Record::read_into(&mut reader, buf)
+            .with_context(|| format!("read BAM record from {}", path_bam.display()))?
+        else {
+            break;
+        };
+        let voffset_end = reader.virtual_position()?;
+
+        let key = (record.ref_id() as u32, record.pos());
+        if key < last_key {
+            bail!(
+                "{} is not coordinate-sorted (record {} is out of order); run bam-sort first",
+                path_bam.display(),
+                n_records
+            );
+        }
+        last_key = key;
+        builder.add_record(&record, voffset_start, voffset_end);
+        n_records += 1;
+        buf = record.data;
+    }
+
+    let path_index = index_path(path_bam, format);
+    let path_index_tmp = atomic_temp_path(&path_index);
+    let mut out = BufWriter::new(
+        File::create(&path_index_tmp)
+            .with_context(|| format!("create index tmp {}", path_index_tmp.display()))?,
+    );
+    builder.write_virtual(&mut out)?;
+    out.flush()?;
+    drop(out);
+    publish_atomic_output(&path_index_tmp, &path_index)?;
+    info!(records = n_records, ?format, "Index: indexed BAM");
+    Ok(path_index)
+}
+
+/// Tabix-index a BGZF fragments file or TIRP; returns the `.tbi` path. Lines starting with
+/// `#` are headers and are skipped.
+pub fn index_bed(path_in: &Path) -> Result<PathBuf> {
+    let mut reader = open_virtual_reader(path_in)?;
+    let mut indexer = BedTabixIndexer::new();
+
+    let mut seen_names: HashSet<Vec<u8>> = HashSet::new();
+    let mut current_name: Vec<u8> = Vec::new();
+    let mut last_start = 0usize;
+    let mut line = Vec::new();
+    let mut line_no: u64 = 0;
+    loop {
+        line.clear();
+        let voffset_start = reader.virtual_position()?;
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        let voffset_end = reader.virtual_position()?;
+        line_no += 1;
+
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        if content.is_empty() || content.starts_with(b"#") {
+            continue;
+        }
+
+        let mut fields = content.split(|&b| b == b'\t');
+        let (Some(name), Some(start), Some(end)) = (fields.next(), fields.next(), fields.next())
+        else {
+            bail!(
+                "{}:{line_no}: expected at least 3 tab-separated columns",
+                path_in.display()
+            );
+        };
+        let parse_pos = |field: &[u8]| -> Result<usize> {
+            std::str::from_utf8(field)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .with_context(|| {
+                    format!(
+                        "{}:{line_no}: invalid position {:?}",
+                        path_in.display(),
+                        String::from_utf8_lossy(field)
+                    )
+                })
+        };
+        let (start, end) = (parse_pos(start)?, parse_pos(end)?);
+
+        if name != current_name.as_slice() {
+            if !seen_names.insert(name.to_vec()) {
+                bail!(
+                    "{}:{line_no}: {} appears in more than one block; the file is not sorted",
+                    path_in.display(),
+                    String::from_utf8_lossy(name)
+                );
+            }
+            current_name = name.to_vec();
+            last_start = 0;
+        } else if start < last_start {
+            bail!(
+                "{}:{line_no}: start {start} is before the previous start {last_start}; \
+                the file is not sorted",
+                path_in.display()
+            );
+        }
+        last_start = start;
+
+        let name = std::str::from_utf8(name).with_context(|| {
+            format!(
+                "{}:{line_no}: name is not UTF-8 and cannot be tabix-indexed",
+                path_in.display()
+            )
+        })?;
+        indexer.add_record(
+            name,
+            start,
+            end,
+            Chunk::new(
+                VirtualPosition::from(voffset_start),
+                VirtualPosition::from(voffset_end),
+            ),
+        )?;
+    }
+
+    let mut path_index = path_in.as_os_str().to_owned();
+    path_index.push(".tbi");
+    let path_index = PathBuf::from(path_index);
+    let path_index_tmp = atomic_temp_path(&path_index);
+    indexer
+        .write_to_path(&path_index_tmp)
+        .with_context(|| format!("failed to write tabix index {}", path_index_tmp.display()))?;
+    publish_atomic_output(&path_index_tmp, &path_index)?;
+    info!(names = seen_names.len(), "Index: indexed BED-like file");
+    Ok(path_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::samtools_rs::bgzf::Writer;
+
+    fn write_bgzf(path: &Path, payload: &[u8]) {
+        let mut w = Writer::new(File::create(path).unwrap(), 6);
+        w.write_all(payload).unwrap();
+        w.finish().unwrap();
+    }
+
+    #[test]
+    fn index_bed_writes_tbi_and_rejects_unsorted() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let path_sorted = dir.path().join("fragments.tsv.gz");
+        write_bgzf(
+            &path_sorted,
+            b"#CHR\tFROM\tTO\tCELLID\tCNT\tUMI\nchr1\t10\t20\tA\t1\t\nchr1\t15\t30\tB\t1\t\nchr2\t5\t9\tA\t1\t\n",
+        );
+        assert_eq!(
+            detect_input_type(&path_sorted).unwrap(),
+            IndexInputType::Bed
+        );
+        let path_tbi = index_bed(&path_sorted).unwrap();
+        assert!(path_tbi.ends_with("fragments.tsv.gz.tbi"));
+        assert!(path_tbi.exists());
+
+        let path_unsorted = dir.path().join("unsorted.tsv.gz");
+        write_bgzf(
+            &path_unsorted,
+            b"chr1\t10\t20\tA\t1\t\nchr2\t5\t9\tA\t1\t\nchr1\t15\t30\tB\t1\t\n",
+        );
+        assert!(index_bed(&path_unsorted).is_err());
+    }
+}
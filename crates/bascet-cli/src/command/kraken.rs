@@ -1,6 +1,9 @@
 use crate::{
     bounded_parser,
-    utils::{atomic_temp_path, publish_atomic_output},
+    utils::{
+        ColumnarFormat, atomic_temp_path, charge_growth, parse_byte_range, publish_atomic_output,
+        write_columnar_table,
+    },
 };
 
 use bascet_core::{
@@ -8,9 +11,10 @@ use bascet_core::{
     *,
 };
 use bascet_derive::Budget;
+use bascet_runtime::budget::MemoryLimiter;
 
 use anyhow::Result;
-use bascet_io::{codec, parse, tirp};
+use bascet_io::{parse, tirp};
 use bounded_integer::BoundedU64;
 use bytesize::*;
 use clap::Args;
@@ -22,8 +26,10 @@ use kraken2_pure_rs::{
     types::{Sequence, SequenceFormat},
 };
 use rayon::prelude::*;
+use serde::Serialize;
 use std::{
     io::{BufWriter, Write},
+    ops::Range,
     path::{Path, PathBuf},
     thread::JoinHandle,
     time::Instant,
@@ -31,6 +37,7 @@ use std::{
 use tracing::{info, warn};
 
 use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
@@ -42,7 +49,9 @@ const KRAKEN_OUTPUT_FLUSH_INTERVAL: u64 = 1_000_000;
 const KRAKEN_MIN_STREAM_BUFFER: ByteSize = ByteSize::mib(256);
 const KRAKEN_MEMORY_HEADROOM: ByteSize = ByteSize::mib(512);
 
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
 use crate::fileformat::new_anndata::SparseMatrixAnnDataBuilder;
+use crate::fileformat::sample_sheet::SampleAnnotations;
 
 struct KrakenReadPair {
     cell_id: Arc<[u8]>,
@@ -75,35 +84,95 @@ struct KrakenCellCounts {
     unclassified_counter: u32,
 }
 
+/// Rough heap cost of a new cell in the accumulator: map node plus the cell ID it keeps alive
+const SIZEOF_KRAKEN_CELL_ENTRY: usize = 96;
+/// Rough heap cost of a new taxid of a cell
+const SIZEOF_KRAKEN_TAXID_ENTRY: usize = 16;
+
+/// Row of the long count table written instead of an h5ad matrix
+#[derive(Serialize)]
+struct KrakenCountRow<'a> {
+    cell: &'a [u8],
+    taxid: Option<u32>,
+    count: u32,
+}
+
 #[derive(Default)]
 struct KrakenMatrixAccumulator {
     cell_counts: BTreeMap<Arc<[u8]>, KrakenCellCounts>,
+    /// Approximate heap size, kept up to date as entries are added
+    sizeof_heap: usize,
 }
 
 impl KrakenMatrixAccumulator {
     fn add_call(&mut self, cell_id: &Arc<[u8]>, external_taxid: Option<u32>) {
-        let cell_counts = self.cell_counts.entry(Arc::clone(cell_id)).or_default();
+        let cell_counts = match self.cell_counts.entry(Arc::clone(cell_id)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                self.sizeof_heap += SIZEOF_KRAKEN_CELL_ENTRY + cell_id.len();
+                entry.insert(KrakenCellCounts::default())
+            }
+        };
         if let Some(taxid) = external_taxid {
-            *cell_counts.taxid_counter.entry(taxid + 1).or_insert(0) += 1;
+            Self::add_taxid_count(&mut self.sizeof_heap, cell_counts, taxid + 1, 1);
         } else {
             cell_counts.unclassified_counter += 1;
         }
     }
 
+    fn add_taxid_count(
+        sizeof_heap: &mut usize,
+        cell_counts: &mut KrakenCellCounts,
+        taxid: u32,
+        count: u32,
+    ) {
+        let counter = cell_counts.taxid_counter.entry(taxid).or_insert_with(|| {
+            *sizeof_heap += SIZEOF_KRAKEN_TAXID_ENTRY;
+            0
+        });
+        *counter += count;
+    }
+
     fn add(&mut self, pair: &KrakenReadPair, classification: &KrakenClassification) {
         self.add_call(&pair.cell_id, classification.external_taxid);
     }
 
     fn merge(&mut self, other: KrakenMatrixAccumulator) {
         for (cell_id, other_counts) in other.cell_counts {
-            let counts = self.cell_counts.entry(cell_id).or_default();
+            let counts = match self.cell_counts.entry(cell_id) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    self.sizeof_heap += SIZEOF_KRAKEN_CELL_ENTRY + entry.key().len();
+                    entry.insert(KrakenCellCounts::default())
+                }
+            };
             counts.unclassified_counter += other_counts.unclassified_counter;
             for (taxid, count) in other_counts.taxid_counter {
-                *counts.taxid_counter.entry(taxid).or_insert(0) += count;
+                Self::add_taxid_count(&mut self.sizeof_heap, counts, taxid, count);
             }
         }
     }
 
+    /// One row per cell and taxid, plus a row with a null taxid for the unclassified reads
+    fn table_rows(&self) -> impl Iterator<Item = KrakenCountRow<'_>> {
+        self.cell_counts.iter().flat_map(|(cell_id, counts)| {
+            // NOTE:    taxids are counted off by one, as taxid 0 is in use; undo it here
+            let classified = counts
+                .taxid_counter
+                .iter()
+                .map(|(taxid, count)| (Some(taxid - 1), *count));
+            let unclassified =
+                (counts.unclassified_counter > 0).then_some((None, counts.unclassified_counter));
+            classified
+                .chain(unclassified)
+                .map(move |(taxid, count)| KrakenCountRow {
+                    cell: cell_id,
+                    taxid,
+                    count,
+                })
+        })
+    }
+
     fn into_anndata_builder(self) -> Result<SparseMatrixAnnDataBuilder> {
         let mut matrix = SparseMatrixAnnDataBuilder::new();
 
@@ -123,10 +192,18 @@ pub struct KrakenCMD {
     #[arg(
         short = 'i',
         long = "in",
-        help = "List of input files (comma-separated). Assumed to be sorted by cell id in descending order."
+        help = "List of input files (comma-separated), or - for stdin. Assumed to be sorted by cell id in descending order."
     )]
     pub path_in: InputPath,
 
+    #[arg(
+        long = "byte-range",
+        help = "Only classify cells whose first BBGZ block starts in this byte range of the input (START..END)",
+        value_name = "START..END",
+        value_parser = parse_byte_range,
+    )]
+    pub byte_range: Option<Range<u64>>,
+
     #[arg(long = "out-raw", help = "Raw KRAKEN2 output file")]
     pub path_out_raw: Option<PathBuf>,
 
@@ -136,7 +213,10 @@ pub struct KrakenCMD {
     )]
     pub enable_raw_output: bool,
 
-    #[arg(long = "out-matrix", help = "Output count matrix")]
+    #[arg(
+        long = "out-matrix",
+        help = "Output count matrix (h5ad), or a long cell/taxid/count table for .arrow/.feather/.parquet"
+    )]
     pub path_out_matrix: PathBuf,
 
     #[arg(long = "temp", help = "Temp directory; must exist already")]
@@ -239,6 +319,7 @@ impl KrakenCMD {
             read_threads = thread_allocation.read_threads.get(),
             classify_threads = thread_allocation.classify_threads,
             input_path = ?self.path_in,
+            byte_range = ?self.byte_range,
             path_out_raw = ?self.path_out_raw,
             "Starting KRAKEN2"
         );
@@ -257,12 +338,14 @@ impl KrakenCMD {
         // Stream read pairs directly into the Rust Kraken library.
         let matrix = Self::write_tirp_to_kraken(
             self.path_in.path().path(),
+            self.byte_range.clone(),
             &self.path_db,
             path_out_raw_tmp.as_deref(),
             thread_allocation.classify_threads,
             thread_allocation.read_threads,
             self.sizeof_stream_arena,
             budget.sizeof_stream_buffer,
+            budget.limiter::<Total>(),
         )?;
 
         if let Some(path_out_raw_tmp) = path_out_raw_tmp {
@@ -274,12 +357,18 @@ impl KrakenCMD {
         }
 
         info!("Storing count table to {}", self.path_out_matrix.display());
-        let path_matrix_tmp = atomic_temp_path(&self.path_out_matrix);
-        matrix
-            .into_anndata_builder()?
-            .save_to_anndata(&path_matrix_tmp)
-            .expect("Failed to save to HDF5 file");
-        publish_atomic_output(path_matrix_tmp, &self.path_out_matrix)?;
+        if let Some(format) = ColumnarFormat::from_path(&self.path_out_matrix) {
+            write_columnar_table(&self.path_out_matrix, format, matrix.table_rows())?;
+        } else {
+            let path_matrix_tmp = atomic_temp_path(&self.path_out_matrix);
+            let sample_annotations = SampleAnnotations::from_paths([self.path_in.path().path()]);
+            let mut matrix = matrix.into_anndata_builder()?;
+            matrix.attach_sample_annotations(sample_annotations);
+            matrix
+                .save_to_anndata(&path_matrix_tmp)
+                .expect("Failed to save to HDF5 file");
+            publish_atomic_output(path_matrix_tmp, &self.path_out_matrix)?;
+        }
 
         info!("All KRAKEN2 steps complete");
 
@@ -302,12 +391,14 @@ impl KrakenCMD {
     ///
     fn write_tirp_to_kraken(
         path_in: impl AsRef<Path>,
+        byte_range: Option<Range<u64>>,
         path_db: impl AsRef<Path>,
         path_out_raw: Option<&Path>,
         classify_threads: usize,
         read_threads: BoundedU64<1, { u64::MAX }>,
         sizeof_stream_arena: ByteSize,
         sizeof_stream_buffer: ByteSize,
+        matrix_limiter: Arc<MemoryLimiter>,
     ) -> Result<KrakenMatrixAccumulator> {
         let db = Self::load_kraken_db(path_db)?;
         let sizeof_stream_buffer = Self::kraken_stream_buffer_after_db_load(sizeof_stream_buffer);
@@ -329,6 +420,7 @@ impl KrakenCMD {
 
         let (batch_rx, reader_handle) = Self::spawn_kraken_batch_reader(
             path_in.as_ref().to_path_buf(),
+            byte_range,
             read_threads,
             sizeof_stream_arena,
             sizeof_stream_buffer,
@@ -338,6 +430,7 @@ impl KrakenCMD {
         info!("Classifying read pairs");
         let mut num_read: u64 = 0;
         let mut matrix = KrakenMatrixAccumulator::default();
+        let mut matrix_permit = matrix_limiter.acquire(0);
         let mut total_read_time = std::time::Duration::ZERO;
         let mut total_classify_time = std::time::Duration::ZERO;
         let mut total_accumulate_time = std::time::Duration::ZERO;
@@ -404,6 +497,11 @@ impl KrakenCMD {
                     matrix.add(pair, classification);
                 }
             }
+            charge_growth(
+                &mut matrix_permit,
+                matrix.sizeof_heap,
+                "KRAKEN count matrix",
+            )?;
             total_accumulate_time += accumulate_started.elapsed();
             if num_read % KRAKEN_OUTPUT_FLUSH_INTERVAL == 0 {
                 if let Some(writer) = writer.as_mut() {
@@ -438,6 +536,7 @@ impl KrakenCMD {
 
     fn spawn_kraken_batch_reader(
         path_in: PathBuf,
+        byte_range: Option<Range<u64>>,
         read_threads: BoundedU64<1, { u64::MAX }>,
         sizeof_stream_arena: ByteSize,
         sizeof_stream_buffer: ByteSize,
@@ -450,10 +549,7 @@ impl KrakenCMD {
         let reader_handle = std::thread::Builder::new()
             .name("KrakenRead@0".to_string())
             .spawn(move || -> Result<()> {
-                let decoder = codec::BBGZDecoder::builder()
-                    .with_path(path_in)
-                    .countof_threads(read_threads)
-                    .build();
+                let decoder = open_bbgz_decoder(&path_in, read_threads, byte_range)?;
                 let parser = parse::Tirp::builder().build();
 
                 let mut stream = Stream::builder()
@@ -513,10 +609,7 @@ impl KrakenCMD {
     where
         P: AsRef<Path>,
     {
-        let decoder = codec::BBGZDecoder::builder()
-            .with_path(path_in)
-            .countof_threads(num_threads)
-            .build();
+        let decoder = open_bbgz_decoder(path_in.as_ref(), num_threads, None)?;
         let parser = parse::Tirp::builder().build();
 
         let mut stream = Stream::builder()
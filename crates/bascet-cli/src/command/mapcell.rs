@@ -1,5 +1,8 @@
 use anyhow::Result;
 use bascet_core::DEFAULT_SIZEOF_ARENA;
+use bascet_derive::Budget;
+use bascet_runtime::budget::MemoryLimiter;
+use bounded_integer::BoundedU64;
 use bytesize::ByteSize;
 use clap::Args;
 use std::fs;
@@ -67,6 +70,15 @@ pub struct MapCellCMD {
     #[arg(long, value_parser = clap::value_parser!(usize))]
     num_threads_mapcell: Option<usize>,
 
+    #[arg(
+        short = 'm',
+        long = "memory",
+        help = "Total memory budget; half of it bounds the streamed cells being extracted",
+        default_value_t = ByteSize::gib(8),
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    total_mem: ByteSize,
+
     #[arg(
         long = "sizeof-stream-buffer",
         help = "Total stream buffer size.",
@@ -84,6 +96,19 @@ pub struct MapCellCMD {
     )]
     sizeof_stream_arena: ByteSize,
 }
+
+#[derive(Budget, Debug)]
+struct MapCellBudget {
+    #[threads(Total)]
+    threads: BoundedU64<1, { u64::MAX }>,
+
+    #[mem(Total)]
+    memory: ByteSize,
+
+    #[mem(MCells, |_, total_mem| bytesize::ByteSize(total_mem / 2))]
+    sizeof_cells: ByteSize,
+}
+
 impl MapCellCMD {
     /// Run the map-cell commandline option
     pub fn try_execute(&mut self) -> Result<()> {
@@ -132,6 +157,14 @@ impl MapCellCMD {
             "Using threads, readers: {}, writers: {}, mapcell: {}",
             num_threads_read, num_threads_write, num_threads_mapcell
         );
+        let budget = MapCellBudget::builder()
+            .threads(BoundedU64::new_saturating(
+                (num_threads_read + num_threads_write * num_threads_mapcell) as u64,
+            ))
+            .memory(self.total_mem)
+            .build();
+        budget.validate();
+        info!(using = %budget, "Using memory budget");
 
         let params = mapcell::MapCell {
             path_in: self
@@ -153,6 +186,7 @@ impl MapCellCMD {
 
             sizeof_stream_buffer: self.sizeof_stream_buffer,
             sizeof_stream_arena: self.sizeof_stream_arena,
+            cell_memory_limiter: budget.limiter::<MCells>(),
 
             show_script_output: self.show_script_output,
             keep_files: self.keep_files,
@@ -182,6 +216,8 @@ pub struct MapCell {
 
     sizeof_stream_buffer: ByteSize,
     sizeof_stream_arena: ByteSize,
+    //Bounds the bytes of streamed cells while they are extracted
+    cell_memory_limiter: Arc<MemoryLimiter>,
 
     pub show_script_output: bool,
     pub keep_files: bool,
@@ -254,6 +290,7 @@ impl MapCell {
             params.sizeof_stream_arena,
             params.sizeof_stream_buffer,
             params.threads_read,
+            Some(Arc::clone(&params.cell_memory_limiter)),
             &process_cell_fn,
         )?;
 
@@ -1,5 +1,6 @@
 use std::fs::File;
 use std::io::{BufWriter, Write};
+use std::ops::Range;
 use std::path::PathBuf;
 use std::thread;
 
@@ -16,9 +17,10 @@ use std::collections::HashMap;
 use tracing::info;
 use zip::ZipWriter;
 
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
 use crate::kmer::minhash::{MinhashCodec, MinhashKMER};
 use crate::kmer::{BoundedHeap, BoundedMinHeap};
-use crate::utils::{atomic_temp_path, publish_atomic_output};
+use crate::utils::{atomic_temp_path, parse_byte_range, publish_atomic_output};
 
 /// Default stream decode buffer. Small on purpose: the minhash consumer is fast and
 /// parallel, so there is no need for the large buffer the mapcell path used.
@@ -32,10 +34,14 @@ const WORKER_QUEUE_DEPTH: usize = 4;
 
 #[derive(Args)]
 pub struct MinhashFqCMD {
-    /// Input TIRP file (records must be sorted by cell, as Bascet TIRP always is).
+    /// Input TIRP file, or - for stdin (records must be sorted by cell, as Bascet TIRP always is).
     #[arg(short = 'i', value_parser = clap::value_parser!(PathBuf))]
     pub path_in: PathBuf,
 
+    /// Only sketch cells whose first BBGZ block starts in this byte range of the input.
+    #[arg(long = "byte-range", value_name = "START..END", value_parser = parse_byte_range)]
+    pub byte_range: Option<Range<u64>>,
+
     /// Output zip file. One entry `<cell>/minhash.txt` per cell.
     #[arg(short = 'o', value_parser = clap::value_parser!(PathBuf))]
     pub path_out: PathBuf,
@@ -79,6 +85,7 @@ impl MinhashFqCMD {
         self.validate()?;
         info!(
             path_in = %self.path_in.display(),
+            byte_range = ?self.byte_range,
             workers = self.workers,
             kmer = self.kmer,
             num_minhash = self.num_minhash,
@@ -87,6 +94,7 @@ impl MinhashFqCMD {
         );
         run_minhash_fq(
             self.path_in.clone(),
+            self.byte_range.clone(),
             self.path_out.clone(),
             self.workers,
             self.num_threads_read,
@@ -126,6 +134,7 @@ type Partial = (u32, String, BoundedMinHeap<MinhashKMER>);
 
 fn run_minhash_fq(
     path_in: PathBuf,
+    byte_range: Option<Range<u64>>,
     path_out: PathBuf,
     workers: usize,
     num_threads_read: usize,
@@ -156,6 +165,7 @@ fn run_minhash_fq(
     let reader = thread::spawn(move || {
         reader_loop(
             path_in,
+            byte_range,
             num_threads_read,
             sizeof_stream_arena,
             sizeof_stream_buffer,
@@ -182,6 +192,7 @@ fn run_minhash_fq(
 /// and mark cell boundaries. Holds at most one batch in memory — never a whole cell.
 fn reader_loop(
     path_in: PathBuf,
+    byte_range: Option<Range<u64>>,
     num_threads_read: usize,
     sizeof_stream_arena: ByteSize,
     sizeof_stream_buffer: ByteSize,
@@ -189,10 +200,7 @@ fn reader_loop(
 ) -> Result<()> {
     let num_threads = bounded_integer::BoundedU64::new(num_threads_read as u64)
         .context("invalid read thread count")?;
-    let decoder: bascet_io::BBGZDecoder = bascet_io::codec::BBGZDecoder::builder()
-        .with_path(&path_in)
-        .countof_threads(num_threads)
-        .build();
+    let decoder = open_bbgz_decoder(&path_in, num_threads, byte_range)?;
     let parser = bascet_io::parse::Tirp::builder().build();
     let mut stream = bascet_core::Stream::builder()
         .with_decoder(decoder)
@@ -0,0 +1,35 @@
+//! Competitive alignment against several references at once.
+//!
+//! `multiref build` concatenates a directory of reference FASTAs into one reference whose
+//! contigs are named `<group>|<contig>`; after `align` against it, `multiref assign` counts the
+//! reads of every cell per reference group and writes the most likely reference(s) per cell.
+
+pub mod assign;
+pub mod build;
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+
+/// Separates the reference group from the original contig name in a combined reference.
+pub const GROUP_DELIMITER: &str = "|";
+
+#[derive(Args)]
+pub struct MultirefCMD {
+    #[command(subcommand)]
+    pub subcommand: MultirefSubcommand,
+}
+
+#[derive(Subcommand)]
+pub enum MultirefSubcommand {
+    Build(build::MultirefBuildCMD),
+    Assign(assign::MultirefAssignCMD),
+}
+
+impl MultirefCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        match &mut self.subcommand {
+            MultirefSubcommand::Build(cmd) => cmd.try_execute(),
+            MultirefSubcommand::Assign(cmd) => cmd.try_execute(),
+        }
+    }
+}
@@ -0,0 +1,713 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use clap::{Args, ValueEnum};
+use sprs::{CsMat, TriMat};
+use tracing::{info, warn};
+
+use super::GROUP_DELIMITER;
+use crate::command::determine_thread_counts_1;
+use crate::command::samtools_rs::{bam, bgzf};
+use crate::fileformat::new_anndata::SparseMatrixAnnDataWriter;
+use crate::utils::{atomic_temp_path, publish_atomic_output};
+
+const BAM_FUNMAP: u16 = 0x4;
+const PROGRESS_INTERVAL_READS: u64 = 10_000_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MultimapStrategy {
+    /// Split reads aligning to several references by per-cell expectation maximisation.
+    Em,
+    /// Count only reads whose alignments all fall in one reference.
+    Unique,
+}
+
+#[derive(Args)]
+pub struct MultirefAssignCMD {
+    /// Unsorted BAM from `align` against a `multiref build` reference. All alignments of a read
+    /// must be adjacent, as the aligner wrote them.
+    #[arg(short = 'i', value_parser = clap::value_parser!(PathBuf))]
+    pub path_in: PathBuf,
+
+    /// Output h5ad with cells x reference groups counts in X and the assignment in obs.
+    #[arg(short = 'o', value_parser = clap::value_parser!(PathBuf))]
+    pub path_out: PathBuf,
+
+    /// Optional TSV of cell<TAB>reference with the most likely reference of every assigned
+    /// cell, usable as `quast --reference-map`.
+    #[arg(long = "reference-map-out", value_parser = clap::value_parser!(PathBuf))]
+    pub path_reference_map_out: Option<PathBuf>,
+
+    /// TSV of contig<TAB>group, for references not built by `multiref build`. Contigs not
+    /// listed are ignored.
+    #[arg(long = "group-map", value_parser = clap::value_parser!(PathBuf))]
+    pub path_group_map: Option<PathBuf>,
+
+    /// Without --group-map, the group is the part of the contig name before this delimiter.
+    /// Contigs without the delimiter are ignored.
+    #[arg(long = "group-delimiter", default_value = GROUP_DELIMITER)]
+    pub group_delimiter: String,
+
+    /// How reads aligning to several references are counted.
+    #[arg(long = "strategy", value_enum, default_value_t = MultimapStrategy::Em)]
+    pub strategy: MultimapStrategy,
+
+    /// Cells with fewer assigned reads get no reference.
+    #[arg(long = "min-reads", default_value_t = 10)]
+    pub min_reads: u64,
+
+    /// Minimum fraction of a cell's reads for a reference to be listed as likely.
+    #[arg(long = "min-fraction", default_value_t = 0.1)]
+    pub min_fraction: f64,
+
+    /// Maximum EM iterations per cell.
+    #[arg(long = "em-max-iterations", default_value_t = 1000)]
+    pub em_max_iterations: usize,
+
+    /// EM stops once no reference abundance changes by more than this.
+    #[arg(long = "em-tolerance", default_value_t = 1e-6)]
+    pub em_tolerance: f64,
+
+    //Thread settings
+    #[arg(short = '@', value_parser = clap::value_parser!(usize))]
+    num_threads_total: Option<usize>,
+}
+
+impl MultirefAssignCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        self.validate()?;
+        let num_threads = determine_thread_counts_1(self.num_threads_total)?;
+        info!("Using threads {}", num_threads);
+
+        let grouping = match &self.path_group_map {
+            Some(path_group_map) => Grouping::from_map_file(path_group_map)?,
+            None => Grouping::Delimiter(self.group_delimiter.clone()),
+        };
+        let (groups, cells) = collect_hits(&self.path_in, &grouping, num_threads)?;
+        if groups.names.is_empty() {
+            bail!("no reference groups found (check --group-map or --group-delimiter)");
+        }
+        info!(
+            "read alignments of {} cells over reference groups {:?}",
+            cells.len(),
+            groups.names
+        );
+
+        let em = EmSettings {
+            max_iterations: self.em_max_iterations,
+            tolerance: self.em_tolerance,
+        };
+        let assignments: Vec<CellAssignment> = cells
+            .iter()
+            .map(|(_, hits)| {
+                let counts = match self.strategy {
+                    MultimapStrategy::Em => em_counts(&hits.classes, groups.names.len(), &em),
+                    MultimapStrategy::Unique => unique_counts(&hits.classes, groups.names.len()),
+                };
+                CellAssignment::new(hits, counts, self.min_reads, self.min_fraction)
+            })
+            .collect();
+        let num_assigned = assignments.iter().filter(|a| !a.likely.is_empty()).count();
+        info!(
+            "assigned a reference to {} of {} cells",
+            num_assigned,
+            cells.len()
+        );
+
+        let cell_names: Vec<String> = cells
+            .iter()
+            .map(|(name, _)| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        write_anndata(&self.path_out, &cell_names, &groups.names, &assignments)?;
+        if let Some(path_reference_map_out) = &self.path_reference_map_out {
+            write_reference_map(
+                path_reference_map_out,
+                &cell_names,
+                &groups.names,
+                &assignments,
+            )?;
+        }
+
+        info!("Multiref assign has finished succesfully");
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if !(0.0..=1.0).contains(&self.min_fraction) {
+            bail!("--min-fraction must be between 0 and 1");
+        }
+        if self.em_max_iterations == 0 {
+            bail!("--em-max-iterations must be at least 1");
+        }
+        if self.path_group_map.is_none() && self.group_delimiter.is_empty() {
+            bail!("--group-delimiter must not be empty");
+        }
+        Ok(())
+    }
+}
+
+enum Grouping {
+    Map(HashMap<String, String>),
+    Delimiter(String),
+}
+
+impl Grouping {
+    fn from_map_file(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open group map {}", path.display()))?;
+        let mut map = HashMap::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let Some((contig, group)) = line.split_once('\t') else {
+                bail!("{}:{}: expected contig<TAB>group", path.display(), i + 1);
+            };
+            map.insert(contig.to_string(), group.trim().to_string());
+        }
+        Ok(Grouping::Map(map))
+    }
+
+    fn group_of<'a>(&'a self, contig: &'a str) -> Option<&'a str> {
+        match self {
+            Grouping::Map(map) => map.get(contig).map(String::as_str),
+            Grouping::Delimiter(delimiter) => contig
+                .split_once(delimiter.as_str())
+                .map(|(group, _)| group),
+        }
+    }
+}
+
+///
+/// Reference group of every contig in the BAM header, by index and by name
+///
+struct ReferenceGroups {
+    names: Vec<String>,
+    by_tid: Vec<Option<u32>>,
+    by_contig: HashMap<Vec<u8>, u32>,
+}
+
+impl ReferenceGroups {
+    fn new(contigs: &[Vec<u8>], grouping: &Grouping) -> Self {
+        let contig_groups: Vec<Option<String>> = contigs
+            .iter()
+            .map(|contig| {
+                grouping
+                    .group_of(&String::from_utf8_lossy(contig))
+                    .map(str::to_string)
+            })
+            .collect();
+        let ungrouped: Vec<String> = contigs
+            .iter()
+            .zip(&contig_groups)
+            .filter(|(_, group)| group.is_none())
+            .map(|(contig, _)| String::from_utf8_lossy(contig).into_owned())
+            .collect();
+        if !ungrouped.is_empty() {
+            let reason = match grouping {
+                Grouping::Map(_) => "are not in the group map".to_string(),
+                Grouping::Delimiter(delimiter) => {
+                    format!("have no group delimiter {:?} in their name", delimiter)
+                }
+            };
+            warn!(
+                "{} reference sequences {} and are ignored, e.g. {:?}",
+                ungrouped.len(),
+                reason,
+                &ungrouped[..ungrouped.len().min(5)]
+            );
+        }
+
+        let mut names: Vec<String> = contig_groups.iter().flatten().cloned().collect();
+        names.sort();
+        names.dedup();
+        let by_tid: Vec<Option<u32>> = contig_groups
+            .iter()
+            .map(|group| {
+                group
+                    .as_ref()
+                    .and_then(|g| names.binary_search(g).ok())
+                    .map(|i| i as u32)
+            })
+            .collect();
+        let by_contig = contigs
+            .iter()
+            .zip(&by_tid)
+            .filter_map(|(contig, group)| group.map(|g| (contig.clone(), g)))
+            .collect();
+        ReferenceGroups {
+            names,
+            by_tid,
+            by_contig,
+        }
+    }
+
+    /// Groups of the record's own alignment and of the alternative hits in its XA tag
+    fn push_record_groups(&self, record: &bam::Record, out: &mut Vec<u32>) {
+        if record.flag() & BAM_FUNMAP == 0
+            && let Ok(tid) = usize::try_from(record.ref_id())
+            && let Some(Some(group)) = self.by_tid.get(tid)
+        {
+            out.push(*group);
+        }
+        if let Some(xa) = record.aux_str(*b"XA") {
+            out.extend(xa_contigs(xa).filter_map(|contig| self.by_contig.get(contig).copied()));
+        }
+    }
+}
+
+///
+/// Contig of every alternative hit in a bwa XA tag (`contig,±pos,CIGAR,NM;` repeated)
+///
+fn xa_contigs(xa: &[u8]) -> impl Iterator<Item = &[u8]> {
+    xa.split(|&b| b == b';')
+        .filter(|hit| !hit.is_empty())
+        .filter_map(|hit| hit.split(|&b| b == b',').next())
+}
+
+///
+/// Reads of one cell, by the set of reference groups they aligned to
+///
+#[derive(Default)]
+struct CellHits {
+    classes: HashMap<Vec<u32>, u64>,
+    unassigned: u64,
+}
+
+/// Cells by name, with the reads of each
+type CellHitList = Vec<(Vec<u8>, CellHits)>;
+
+///
+/// Gathers the alignments of each read (adjacent records with one name) into the read's set of
+/// reference groups
+///
+#[derive(Default)]
+struct HitCollector {
+    cell_index: HashMap<Vec<u8>, usize>,
+    cells: CellHitList,
+    current_name: Vec<u8>,
+    current_cell: Option<usize>,
+    current_groups: Vec<u32>,
+    num_reads: u64,
+}
+
+impl HitCollector {
+    fn push(&mut self, name: &[u8], cell: &[u8], groups: &[u32]) {
+        if self.current_cell.is_none() || name != self.current_name.as_slice() {
+            self.flush();
+            self.current_name.clear();
+            self.current_name.extend_from_slice(name);
+            let next_index = self.cells.len();
+            let index = *self.cell_index.entry(cell.to_vec()).or_insert(next_index);
+            if index == next_index {
+                self.cells.push((cell.to_vec(), CellHits::default()));
+            }
+            self.current_cell = Some(index);
+        }
+        self.current_groups.extend_from_slice(groups);
+    }
+
+    fn flush(&mut self) {
+        let Some(cell) = self.current_cell.take() else {
+            return;
+        };
+        let hits = &mut self.cells[cell].1;
+        if self.current_groups.is_empty() {
+            hits.unassigned += 1;
+        } else {
+            self.current_groups.sort_unstable();
+            self.current_groups.dedup();
+            *hits.classes.entry(self.current_groups.clone()).or_insert(0) += 1;
+            self.current_groups.clear();
+        }
+        self.num_reads += 1;
+        if self.num_reads.is_multiple_of(PROGRESS_INTERVAL_READS) {
+            info!("Processed {}M reads", self.num_reads / 1_000_000);
+        }
+    }
+
+    /// Cells sorted by name
+    fn finish(mut self) -> CellHitList {
+        self.flush();
+        let mut cells = self.cells;
+        cells.sort_by(|a, b| a.0.cmp(&b.0));
+        cells
+    }
+}
+
+fn collect_hits(
+    path_in: &Path,
+    grouping: &Grouping,
+    num_threads: usize,
+) -> Result<(ReferenceGroups, CellHitList)> {
+    let input =
+        File::open(path_in).with_context(|| format!("open input BAM {}", path_in.display()))?;
+    let mut reader = bgzf::ParallelReader::new(input, num_threads);
+    let header = bam::Header::read(&mut reader)
+        .with_context(|| format!("read BAM header {}", path_in.display()))?;
+    if String::from_utf8_lossy(&header.text).contains("SO:coordinate") {
+        bail!(
+            "{} is sorted by coordinate; multiref assign needs the alignments of a read next to each other (the unsorted BAM of align)",
+            path_in.display()
+        );
+    }
+    let contigs: Vec<Vec<u8>> = header.refs.iter().map(|r| r.name.clone()).collect();
+    let groups = ReferenceGroups::new(&contigs, grouping);
+
+    let mut collector = HitCollector::default();
+    let mut record_groups = Vec::new();
+    let mut scratch = Vec::new();
+    while let Some(record) = bam::Record::read_into(&mut reader, scratch)
+        .with_context(|| format!("read BAM record {}", path_in.display()))?
+    {
+        // NOTE:    the cell is the CB tag written by align, or for other BAMs the read name up
+        //          to the first ':' as in countchrom.
+        let name = record.read_name();
+        let cell = record
+            .aux_str(*b"CB")
+            .unwrap_or_else(|| name.split(|&b| b == b':').next().unwrap_or(name));
+        record_groups.clear();
+        groups.push_record_groups(&record, &mut record_groups);
+        collector.push(name, cell, &record_groups);
+        scratch = record.data;
+    }
+    Ok((groups, collector.finish()))
+}
+
+struct EmSettings {
+    max_iterations: usize,
+    tolerance: f64,
+}
+
+///
+/// Reads per group, counting only reads that hit a single group
+///
+fn unique_counts(classes: &HashMap<Vec<u32>, u64>, num_groups: usize) -> Vec<f64> {
+    let mut counts = vec![0.0; num_groups];
+    for (groups, &n) in classes {
+        if let [group] = groups.as_slice() {
+            counts[*group as usize] += n as f64;
+        }
+    }
+    counts
+}
+
+///
+/// Expected reads per group. Every read is split between the groups it hit in proportion to
+/// their abundance in the cell, and the abundances are re-estimated from the split until they
+/// converge
+///
+fn em_counts(classes: &HashMap<Vec<u32>, u64>, num_groups: usize, em: &EmSettings) -> Vec<f64> {
+    let total: u64 = classes.values().sum();
+    let mut expected = vec![0.0; num_groups];
+    if total == 0 {
+        return expected;
+    }
+    let total = total as f64;
+    let mut abundance = vec![1.0 / num_groups as f64; num_groups];
+    for _ in 0..em.max_iterations {
+        split_reads(classes, &abundance, &mut expected);
+        let mut change: f64 = 0.0;
+        for (abundance, &expected) in abundance.iter_mut().zip(&expected) {
+            let updated = expected / total;
+            change = change.max((updated - *abundance).abs());
+            *abundance = updated;
+        }
+        if change < em.tolerance {
+            break;
+        }
+    }
+
+    // NOTE:    groups only supported by shared reads approach zero slowly and would keep a
+    //          residual of a fraction of a read. Drop them and split once more.
+    for abundance in abundance.iter_mut() {
+        if *abundance < em.tolerance {
+            *abundance = 0.0;
+        }
+    }
+    split_reads(classes, &abundance, &mut expected);
+    expected
+}
+
+/// E-step: reads of each class split by group abundance, evenly if all its groups are at zero
+fn split_reads(classes: &HashMap<Vec<u32>, u64>, abundance: &[f64], expected: &mut [f64]) {
+    expected.fill(0.0);
+    for (groups, &n) in classes {
+        let norm: f64 = groups.iter().map(|&g| abundance[g as usize]).sum();
+        for &g in groups {
+            expected[g as usize] += if norm > 0.0 {
+                n as f64 * abundance[g as usize] / norm
+            } else {
+                n as f64 / groups.len() as f64
+            };
+        }
+    }
+}
+
+struct CellAssignment {
+    counts: Vec<f64>,
+    mapped: u64,
+    multimapped: u64,
+    unassigned: u64,
+    top_fraction: f64,
+    /// Groups holding at least the minimum fraction of the reads, most abundant first
+    likely: Vec<usize>,
+}
+
+impl CellAssignment {
+    fn new(hits: &CellHits, counts: Vec<f64>, min_reads: u64, min_fraction: f64) -> Self {
+        let mapped = hits.classes.values().sum();
+        let multimapped = hits
+            .classes
+            .iter()
+            .filter(|(groups, _)| groups.len() > 1)
+            .map(|(_, &n)| n)
+            .sum();
+
+        let total: f64 = counts.iter().sum();
+        let mut order: Vec<usize> = (0..counts.len()).collect();
+        order.sort_by(|&a, &b| counts[b].total_cmp(&counts[a]).then(a.cmp(&b)));
+        let top_fraction = match order.first() {
+            Some(&top) if total > 0.0 => counts[top] / total,
+            _ => 0.0,
+        };
+        let likely = if total > 0.0 && total >= min_reads as f64 {
+            order
+                .into_iter()
+                .take_while(|&g| counts[g] / total >= min_fraction && counts[g] > 0.0)
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        CellAssignment {
+            counts,
+            mapped,
+            multimapped,
+            unassigned: hits.unassigned,
+            top_fraction,
+            likely,
+        }
+    }
+
+    fn likely_names(&self, groups: &[String]) -> String {
+        self.likely
+            .iter()
+            .map(|&g| groups[g].as_str())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+fn write_anndata(
+    path_out: &PathBuf,
+    cell_names: &Vec<String>,
+    groups: &Vec<String>,
+    assignments: &[CellAssignment],
+) -> Result<()> {
+    let n_rows = cell_names.len();
+    let n_cols = groups.len();
+    let mut trimat = TriMat::new((n_rows, n_cols));
+    for (cell, assignment) in assignments.iter().enumerate() {
+        for (group, &count) in assignment.counts.iter().enumerate() {
+            if count > 0.0 {
+                trimat.add_triplet(cell, group, count);
+            }
+        }
+    }
+    let csr_mat: CsMat<f64> = trimat.to_csr();
+
+    let columns: [(&str, Vec<f64>); 4] = [
+        (
+            "mapped_reads",
+            assignments.iter().map(|a| a.mapped as f64).collect(),
+        ),
+        (
+            "multimapped_reads",
+            assignments.iter().map(|a| a.multimapped as f64).collect(),
+        ),
+        (
+            "unassigned_reads",
+            assignments.iter().map(|a| a.unassigned as f64).collect(),
+        ),
+        (
+            "top_reference_fraction",
+            assignments.iter().map(|a| a.top_fraction).collect(),
+        ),
+    ];
+    let string_columns: [(&str, Vec<String>); 2] = [
+        (
+            "reference",
+            assignments
+                .iter()
+                .map(|a| {
+                    a.likely
+                        .first()
+                        .map_or(String::new(), |&g| groups[g].clone())
+                })
+                .collect(),
+        ),
+        (
+            "references",
+            assignments.iter().map(|a| a.likely_names(groups)).collect(),
+        ),
+    ];
+
+    let path_tmp = atomic_temp_path(path_out);
+    let mut file = SparseMatrixAnnDataWriter::create_anndata(&path_tmp)?;
+    file.store_sparse_count_matrix(&csr_mat, n_rows as u32, n_cols as u32)?;
+    file.store_feature_names(groups)?;
+    file.store_cell_obs(cell_names, &columns, &string_columns)?;
+    file.close()?;
+    publish_atomic_output(&path_tmp, path_out)?;
+    info!("wrote reference counts for {} cells", n_rows);
+    Ok(())
+}
+
+///
+/// One line per assigned cell: the most likely reference first, as `quast --reference-map`
+/// reads it, then its fraction and all likely references
+///
+fn write_reference_map(
+    path_out: &PathBuf,
+    cell_names: &[String],
+    groups: &[String],
+    assignments: &[CellAssignment],
+) -> Result<()> {
+    let path_tmp = atomic_temp_path(path_out);
+    let mut writer = BufWriter::new(File::create(&path_tmp)?);
+    writeln!(
+        writer,
+        "cell\treference\tfraction\treferences\tmapped_reads"
+    )?;
+    for (cell, assignment) in cell_names.iter().zip(assignments) {
+        let Some(&top) = assignment.likely.first() else {
+            continue;
+        };
+        writeln!(
+            writer,
+            "{}\t{}\t{:.4}\t{}\t{}",
+            cell,
+            groups[top],
+            assignment.top_fraction,
+            assignment.likely_names(groups),
+            assignment.mapped
+        )?;
+    }
+    writer.flush()?;
+    drop(writer);
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{
+        CellAssignment, CellHits, EmSettings, Grouping, HitCollector, ReferenceGroups, em_counts,
+        unique_counts, xa_contigs,
+    };
+
+    const EM: EmSettings = EmSettings {
+        max_iterations: 1000,
+        tolerance: 1e-9,
+    };
+
+    fn classes(entries: &[(&[u32], u64)]) -> HashMap<Vec<u32>, u64> {
+        entries.iter().map(|(g, n)| (g.to_vec(), *n)).collect()
+    }
+
+    #[test]
+    fn contigs_are_grouped_by_delimiter_or_map() {
+        let contigs = [
+            b"ecoli|chr".to_vec(),
+            b"ecoli|plasmid".to_vec(),
+            b"bsub|chr".to_vec(),
+        ];
+        let groups = ReferenceGroups::new(&contigs, &Grouping::Delimiter("|".to_string()));
+        assert_eq!(groups.names, ["bsub", "ecoli"]);
+        assert_eq!(groups.by_tid, [Some(1), Some(1), Some(0)]);
+
+        let with_phix = [contigs[0].clone(), b"phix".to_vec()];
+        let groups = ReferenceGroups::new(&with_phix, &Grouping::Delimiter("|".to_string()));
+        assert_eq!(groups.names, ["ecoli"]);
+        assert_eq!(groups.by_tid, [Some(0), None]);
+        assert!(groups.by_contig.get(&b"phix"[..]).is_none());
+
+        let map = HashMap::from([("bsub|chr".to_string(), "B".to_string())]);
+        let groups = ReferenceGroups::new(&contigs, &Grouping::Map(map));
+        assert_eq!(groups.names, ["B"]);
+        assert_eq!(groups.by_tid, [None, None, Some(0)]);
+        assert_eq!(groups.by_contig.get(&b"bsub|chr"[..]), Some(&0));
+    }
+
+    #[test]
+    fn xa_tag_lists_alternative_contigs() {
+        let contigs: Vec<&[u8]> = xa_contigs(b"ecoli|chr,+100,50M,0;bsub|chr,-7,50M,1;").collect();
+        assert_eq!(contigs, [&b"ecoli|chr"[..], &b"bsub|chr"[..]]);
+    }
+
+    #[test]
+    fn alignments_of_a_read_are_counted_once() {
+        let mut collector = HitCollector::default();
+        collector.push(b"r1", b"C1", &[0]);
+        collector.push(b"r1", b"C1", &[1, 0]);
+        collector.push(b"r2", b"C2", &[]);
+        collector.push(b"r3", b"C1", &[1]);
+        collector.push(b"r3", b"C1", &[]);
+
+        let cells = collector.finish();
+        assert_eq!(cells.len(), 2);
+        assert_eq!(cells[0].0, b"C1");
+        assert_eq!(cells[0].1.classes, classes(&[(&[0, 1], 1), (&[1], 1)]));
+        assert_eq!(cells[1].1.unassigned, 1);
+        assert!(cells[1].1.classes.is_empty());
+    }
+
+    #[test]
+    fn unique_strategy_drops_multimappers() {
+        let counts = unique_counts(&classes(&[(&[0], 6), (&[0, 1], 10), (&[1], 2)]), 3);
+        assert_eq!(counts, [6.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn em_splits_multimappers_by_unique_evidence() {
+        // 30 reads only fit group 0, 10 fit both: the shared reads all go to group 0
+        let counts = em_counts(&classes(&[(&[0], 30), (&[0, 1], 10)]), 2, &EM);
+        assert_eq!(counts, [40.0, 0.0]);
+
+        // With evidence for both, shared reads follow the 3:1 unique ratio
+        let counts = em_counts(&classes(&[(&[0], 30), (&[1], 10), (&[0, 1], 40)]), 2, &EM);
+        assert!((counts[0] - 60.0).abs() < 1e-3);
+        assert!((counts[1] - 20.0).abs() < 1e-3);
+        assert!((counts.iter().sum::<f64>() - 80.0).abs() < 1e-9);
+
+        assert_eq!(em_counts(&HashMap::new(), 2, &EM), [0.0, 0.0]);
+    }
+
+    #[test]
+    fn likely_references_need_reads_and_fraction() {
+        let hits = CellHits {
+            classes: classes(&[(&[0], 5), (&[1], 80), (&[2], 15), (&[1, 2], 4)]),
+            unassigned: 3,
+        };
+        let assignment = CellAssignment::new(&hits, vec![5.0, 80.0, 15.0], 10, 0.1);
+        assert_eq!(assignment.likely, [1, 2]);
+        assert_eq!(assignment.mapped, 104);
+        assert_eq!(assignment.multimapped, 4);
+        assert!((assignment.top_fraction - 0.8).abs() < 1e-9);
+        let groups = ["a".to_string(), "b".to_string(), "c".to_string()];
+        assert_eq!(assignment.likely_names(&groups), "b,c");
+
+        let assignment = CellAssignment::new(&hits, vec![5.0, 80.0, 15.0], 1000, 0.1);
+        assert!(assignment.likely.is_empty());
+        let assignment = CellAssignment::new(&CellHits::default(), vec![0.0; 3], 0, 0.1);
+        assert!(assignment.likely.is_empty());
+        assert_eq!(assignment.top_fraction, 0.0);
+    }
+}
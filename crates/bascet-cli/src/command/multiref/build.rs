@@ -0,0 +1,162 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result, bail};
+use clap::Args;
+use flate2::read::MultiGzDecoder;
+use tracing::{info, warn};
+
+use super::GROUP_DELIMITER;
+use crate::align::subprocess::expand_aligner_command;
+use crate::utils::{atomic_temp_path, list_fasta_files, publish_atomic_output};
+
+#[derive(Args)]
+pub struct MultirefBuildCMD {
+    /// Directory of reference FASTAs (.fa, .fasta, .fna, optionally gzipped). The file name
+    /// without extension becomes the reference group, as for `quast --reference <dir>`.
+    #[arg(short = 'i', value_parser = clap::value_parser!(PathBuf))]
+    pub path_in: PathBuf,
+
+    /// Combined FASTA with contigs renamed to <group>|<contig>.
+    #[arg(short = 'o', value_parser = clap::value_parser!(PathBuf))]
+    pub path_out: PathBuf,
+
+    /// Command indexing the combined FASTA once written, e.g. "bwa-mem2 index {genome}" or
+    /// "minimap2 -d {genome}.mmi {genome}". {genome} is the combined FASTA.
+    #[arg(long = "index-cmd")]
+    pub index_cmd: Option<String>,
+
+    /// Threads substituted for {threads} in --index-cmd.
+    #[arg(short = '@', default_value_t = 1)]
+    pub num_threads: usize,
+}
+
+impl MultirefBuildCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        let references = list_fasta_files(&self.path_in)?;
+        if references.len() < 2 {
+            bail!(
+                "found {} FASTA file(s) in {}; competitive alignment needs at least 2",
+                references.len(),
+                self.path_in.display()
+            );
+        }
+        for group in references.keys() {
+            validate_group_name(group)?;
+        }
+
+        let path_tmp = atomic_temp_path(&self.path_out);
+        let mut writer = BufWriter::new(
+            File::create(&path_tmp)
+                .with_context(|| format!("failed to create {}", path_tmp.display()))?,
+        );
+        for (group, path) in &references {
+            let num_contigs = write_renamed_fasta(open_fasta(path)?, group, &mut writer)
+                .with_context(|| format!("failed to copy {}", path.display()))?;
+            if num_contigs == 0 {
+                warn!(reference = %group, "Reference has no sequences");
+            }
+            info!(reference = %group, contigs = num_contigs, "Added reference");
+        }
+        writer.flush()?;
+        drop(writer);
+        publish_atomic_output(&path_tmp, &self.path_out)?;
+        info!(
+            references = references.len(),
+            output = %self.path_out.display(),
+            "Wrote combined reference"
+        );
+
+        if let Some(index_cmd) = &self.index_cmd {
+            run_index_command(index_cmd, &self.path_out, self.num_threads)?;
+        }
+
+        info!("Multiref build has finished succesfully");
+        Ok(())
+    }
+}
+
+/// Group names end up before the first delimiter of every contig name, so they cannot contain
+/// it themselves.
+fn validate_group_name(group: &str) -> Result<()> {
+    if group.is_empty() || group.contains(GROUP_DELIMITER) || group.contains(char::is_whitespace) {
+        bail!(
+            "reference name {:?} must be non-empty and contain neither {:?} nor whitespace",
+            group,
+            GROUP_DELIMITER
+        );
+    }
+    Ok(())
+}
+
+fn open_fasta(path: &Path) -> Result<Box<dyn BufRead>> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    if path.extension().is_some_and(|ext| ext == "gz") {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(file))))
+    } else {
+        Ok(Box::new(BufReader::new(file)))
+    }
+}
+
+///
+/// Copy a FASTA, prefixing every header with `<group>|`. Returns the number of sequences
+///
+fn write_renamed_fasta(reader: impl BufRead, group: &str, writer: &mut impl Write) -> Result<u64> {
+    let mut num_contigs = 0;
+    for line in reader.lines() {
+        let line = line?;
+        if let Some(header) = line.strip_prefix('>') {
+            writeln!(writer, ">{group}{GROUP_DELIMITER}{}", header.trim_start())?;
+            num_contigs += 1;
+        } else if !line.is_empty() {
+            if num_contigs == 0 {
+                bail!("sequence before the first FASTA header");
+            }
+            writeln!(writer, "{line}")?;
+        }
+    }
+    Ok(num_contigs)
+}
+
+fn run_index_command(index_cmd: &str, path_fasta: &Path, num_threads: usize) -> Result<()> {
+    let words = expand_aligner_command(index_cmd, path_fasta, num_threads)?;
+    info!(command = ?words, "Indexing combined reference");
+    let status = Command::new(&words[0])
+        .args(&words[1..])
+        .status()
+        .with_context(|| format!("failed to start index command {:?}", words[0]))?;
+    if !status.success() {
+        bail!("index command {:?} failed: {status}", words[0]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{validate_group_name, write_renamed_fasta};
+
+    #[test]
+    fn contigs_are_prefixed_with_their_group() {
+        let fasta = b">chr1 some description\nACGT\n\nTTGA\n>plasmid\nGG\n";
+        let mut out = Vec::new();
+
+        let num_contigs = write_renamed_fasta(&fasta[..], "ecoli", &mut out).unwrap();
+
+        assert_eq!(num_contigs, 2);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            ">ecoli|chr1 some description\nACGT\nTTGA\n>ecoli|plasmid\nGG\n"
+        );
+        assert!(write_renamed_fasta(&b"ACGT\n>chr1\n"[..], "ecoli", &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn group_names_cannot_hold_the_delimiter() {
+        assert!(validate_group_name("GCF_000005845.2").is_ok());
+        assert!(validate_group_name("strain|A").is_err());
+        assert!(validate_group_name("strain A").is_err());
+        assert!(validate_group_name("").is_err());
+    }
+}
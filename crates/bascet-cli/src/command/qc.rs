@@ -1,4 +1,5 @@
 pub mod aligned_coverage;
+pub mod long_read;
 pub mod ref_composition;
 
 use anyhow::Result;
@@ -13,6 +14,7 @@ pub struct QcCMD {
 #[derive(Subcommand)]
 pub enum QcSubcommand {
     AlignedCoverage(aligned_coverage::QcAlignedCoverageCMD),
+    LongRead(long_read::QcLongReadCMD),
     RefComposition(ref_composition::QcRefCompositionCMD),
 }
 
@@ -20,6 +22,7 @@ impl QcCMD {
     pub fn try_execute(&mut self) -> Result<()> {
         match &mut self.subcommand {
             QcSubcommand::AlignedCoverage(cmd) => cmd.try_execute(),
+            QcSubcommand::LongRead(cmd) => cmd.try_execute(),
             QcSubcommand::RefComposition(cmd) => cmd.try_execute(),
         }
     }
@@ -0,0 +1,578 @@
+//! `qc long-read` subcommand: per-cell QC for long-read TIRPs (e.g. `AtrandiWGSLR`).
+//!
+//! Reports, per cell, read-length N50, a read-length histogram, the mean read Q-score and the
+//! fraction of reads with an internal barcode window. Debarcoding already trimmed the leading
+//! barcode and the trailing adapter, so a window found inside a read (either strand) is left
+//! over from ligation: it is counted as a chimera if a barcode can be decoded there and as an
+//! internal adapter otherwise. Optionally writes the reads passing `--min-length`,
+//! `--min-qscore` (and `--drop-chimeras`) to a new TIRP.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::{Context, Result, bail};
+use bascet_core::{
+    attr::{meta::*, quality::*, sequence::*},
+    channel::ordered_dense,
+    *,
+};
+use bascet_io::{BBGZHeader, BBGZMetadata, BBGZWriteBlock, BBGZWriter, METADATA_SUBFIELD, tirp};
+use bounded_integer::BoundedU64;
+use bytesize::ByteSize;
+use clap::Args;
+use tracing::info;
+
+use crate::barcode::atrandi_wgs_barcode_longread::DebarcodeAtrandiWGSChemistryLongread;
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
+use crate::fileformat::tirp::get_histogram_path_for_tirp;
+use crate::utils::{atomic_temp_path, publish_atomic_output, write_histogram};
+
+const DEFAULT_SIZEOF_STREAM_BUFFER: ByteSize = ByteSize::gib(1);
+const PHRED_OFFSET: u8 = 33;
+
+/// Upper bounds of the read-length histogram bins; the last bin is open-ended
+const LENGTH_BIN_UPPER: [usize; 7] = [1_000, 2_000, 5_000, 10_000, 20_000, 50_000, 100_000];
+const LENGTH_BIN_NAMES: [&str; 8] = [
+    "len_lt_1k",
+    "len_1k_2k",
+    "len_2k_5k",
+    "len_5k_10k",
+    "len_10k_20k",
+    "len_20k_50k",
+    "len_50k_100k",
+    "len_ge_100k",
+];
+
+#[derive(Args)]
+pub struct QcLongReadCMD {
+    /// Input long-read TIRP.
+    #[arg(short = 'i', long = "in", value_parser)]
+    pub path_in: PathBuf,
+
+    /// Output TSV with one row per cell.
+    #[arg(short = 'o', long = "out", value_parser)]
+    pub path_out: PathBuf,
+
+    /// Also write the reads passing the filters to this TIRP (with a `<out>.hist`).
+    #[arg(long = "out-tirp", value_parser)]
+    pub path_out_tirp: Option<PathBuf>,
+
+    /// Minimum read length kept in --out-tirp.
+    #[arg(long = "min-length", default_value_t = 0)]
+    pub min_length: usize,
+
+    /// Minimum mean read Q-score kept in --out-tirp.
+    #[arg(long = "min-qscore", default_value_t = 0.0)]
+    pub min_qscore: f64,
+
+    /// Drop chimeric reads from --out-tirp.
+    #[arg(long = "drop-chimeras")]
+    pub drop_chimeras: bool,
+
+    /// Maximum mismatching linker bases (of 12) for an internal barcode window.
+    #[arg(long = "max-linker-mismatches", default_value_t = 1)]
+    pub max_linker_mismatches: usize,
+
+    /// Number of cells to process concurrently.
+    #[arg(short = '@', long = "threads")]
+    pub num_threads: Option<usize>,
+
+    /// Threads used by the TIRP BGZF decoder.
+    #[arg(long = "num-threads-read", default_value_t = 1)]
+    pub num_threads_read: usize,
+
+    #[arg(
+        long = "sizeof-stream-buffer",
+        help = "Total stream buffer size.",
+        default_value_t = DEFAULT_SIZEOF_STREAM_BUFFER,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_buffer: ByteSize,
+
+    #[arg(
+        long = "sizeof-stream-arena",
+        help = "Stream arena buffer size [Advanced: changing this will impact performance and stability]",
+        hide_short_help = true,
+        default_value_t = DEFAULT_SIZEOF_ARENA,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_arena: ByteSize,
+}
+
+#[derive(Clone, Copy)]
+struct ReadFilter {
+    min_length: usize,
+    min_qscore: f64,
+    drop_chimeras: bool,
+}
+
+impl QcLongReadCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        if self.num_threads == Some(0) {
+            bail!("--threads must be > 0");
+        }
+        if self.num_threads_read == 0 {
+            bail!("--num-threads-read must be > 0");
+        }
+        if self.max_linker_mismatches >= 12 {
+            bail!("--max-linker-mismatches must be < 12");
+        }
+        let num_workers = self.num_threads.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1)
+                .saturating_sub(self.num_threads_read)
+                .max(1)
+        });
+        let filter = ReadFilter {
+            min_length: self.min_length,
+            min_qscore: self.min_qscore,
+            drop_chimeras: self.drop_chimeras,
+        };
+        let write_tirp = self.path_out_tirp.is_some();
+        info!(
+            input = %self.path_in.display(),
+            output = %self.path_out.display(),
+            workers = num_workers,
+            "QC long-read: starting"
+        );
+
+        let (tx_cells, rx_cells) =
+            crossbeam::channel::bounded::<(usize, tirp::Cell)>(num_workers * 2);
+        let (tx_results, mut rx_results) = ordered_dense::<CellResult, 1024>();
+        let chemistry = DebarcodeAtrandiWGSChemistryLongread::new();
+        let max_mismatches = self.max_linker_mismatches;
+
+        let mut workers = Vec::with_capacity(num_workers);
+        for _ in 0..num_workers {
+            let rx_cells = rx_cells.clone();
+            let tx_results = tx_results.clone();
+            let chemistry = chemistry.clone();
+            workers.push(thread::spawn(move || {
+                while let Ok((index, cell)) = rx_cells.recv() {
+                    let (stats, keep) = LongReadStats::compute(
+                        cell.get_ref::<R1>(),
+                        cell.get_ref::<R2>(),
+                        cell.get_ref::<Q1>(),
+                        cell.get_ref::<Q2>(),
+                        &chemistry,
+                        max_mismatches,
+                        filter,
+                    );
+                    let result = CellResult {
+                        cell_id: cell.get_ref::<Id>().to_vec(),
+                        stats,
+                        kept: write_tirp.then(|| (cell, keep)),
+                    };
+                    tx_results.send(index, result);
+                }
+            }));
+        }
+        drop(rx_cells);
+        drop(tx_results);
+
+        let path_in = self.path_in.clone();
+        let num_threads_read = self.num_threads_read;
+        let sizeof_stream_arena = self.sizeof_stream_arena;
+        let sizeof_stream_buffer = self.sizeof_stream_buffer;
+        let reader = thread::spawn(move || {
+            stream_tirp_cells(
+                &path_in,
+                num_threads_read,
+                sizeof_stream_arena,
+                sizeof_stream_buffer,
+                tx_cells,
+            )
+        });
+
+        let path_tsv_tmp = atomic_temp_path(&self.path_out);
+        let mut tsv = BufWriter::new(
+            File::create(&path_tsv_tmp)
+                .with_context(|| format!("failed to create {}", path_tsv_tmp.display()))?,
+        );
+        writeln!(
+            tsv,
+            "cell\treads\tbases\tn50\tmean_length\tmax_length\tmean_qscore\tchimera_rate\tadapter_internal_rate\treads_pass\t{}",
+            LENGTH_BIN_NAMES.join("\t")
+        )?;
+        let mut tirp_writer = match &self.path_out_tirp {
+            Some(path_out_tirp) => Some(FilteredTirpWriter::new(
+                path_out_tirp,
+                num_threads_read,
+                self.filter_metadata(),
+            )?),
+            None => None,
+        };
+
+        let mut num_cells = 0u64;
+        while let Ok(result) = rx_results.recv() {
+            result.stats.write_row(&mut tsv, &result.cell_id)?;
+            if let (Some(tirp_writer), Some((cell, keep))) = (tirp_writer.as_mut(), &result.kept) {
+                tirp_writer.write_cell(cell, keep)?;
+            }
+            num_cells += 1;
+        }
+
+        reader
+            .join()
+            .map_err(|_| anyhow::anyhow!("TIRP reader thread panicked"))??;
+        for worker in workers {
+            worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("long-read QC worker thread panicked"))?;
+        }
+
+        tsv.flush()?;
+        drop(tsv);
+        publish_atomic_output(&path_tsv_tmp, &self.path_out)?;
+        if let (Some(tirp_writer), Some(path_out_tirp)) = (tirp_writer, &self.path_out_tirp) {
+            tirp_writer.finish(path_out_tirp)?;
+        }
+        info!(cells = num_cells, "QC long-read: complete");
+        Ok(())
+    }
+
+    fn filter_metadata(&self) -> BBGZMetadata {
+        let mut metadata = BBGZMetadata::derive_from_paths([&self.path_in]);
+        metadata
+            .push("min_length", self.min_length.to_string())
+            .push("min_qscore", self.min_qscore.to_string())
+            .push("drop_chimeras", self.drop_chimeras.to_string());
+        metadata
+    }
+}
+
+fn stream_tirp_cells(
+    path_in: &Path,
+    num_threads_read: usize,
+    sizeof_stream_arena: ByteSize,
+    sizeof_stream_buffer: ByteSize,
+    tx_cells: crossbeam::channel::Sender<(usize, tirp::Cell)>,
+) -> Result<()> {
+    let decoder = open_bbgz_decoder(
+        path_in,
+        BoundedU64::new_saturating(num_threads_read as u64),
+        None,
+    )?;
+    let parser = bascet_io::parse::Tirp::builder().build();
+
+    let mut stream = Stream::builder()
+        .with_decoder(decoder)
+        .with_parser(parser)
+        .sizeof_decode_arena(sizeof_stream_arena)
+        .sizeof_decode_buffer(sizeof_stream_buffer)
+        .build();
+
+    let mut query = stream
+        .query::<tirp::Cell>()
+        .group_relaxed_with_context::<Id, Id, _>(
+            |id_current: &&'static [u8], id_context: &&'static [u8]| {
+                if id_current == id_context {
+                    QueryResult::Keep
+                } else {
+                    QueryResult::Emit
+                }
+            },
+        );
+
+    let mut index = 0;
+    while let Some(cell) = query.next().context("failed to read TIRP cell")? {
+        if tx_cells.send((index, cell)).is_err() {
+            bail!("long-read QC workers stopped early");
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+struct CellResult {
+    cell_id: Vec<u8>,
+    stats: LongReadStats,
+    /// The cell and which of its reads pass the filters, when writing a filtered TIRP
+    kept: Option<(tirp::Cell, Vec<bool>)>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct LongReadStats {
+    reads: u64,
+    bases: u64,
+    n50: u64,
+    max_length: u64,
+    /// Mean over reads of the per-read Q-score
+    mean_qscore: f64,
+    chimeras: u64,
+    adapters_internal: u64,
+    reads_pass: u64,
+    length_hist: [u64; LENGTH_BIN_NAMES.len()],
+}
+
+impl LongReadStats {
+    /// Statistics of one cell, and which reads pass `filter`. R2 is empty for long-read
+    /// chemistries; if present it is counted as part of the same read
+    fn compute(
+        r1: &[&[u8]],
+        r2: &[&[u8]],
+        q1: &[&[u8]],
+        q2: &[&[u8]],
+        chemistry: &DebarcodeAtrandiWGSChemistryLongread,
+        max_mismatches: usize,
+        filter: ReadFilter,
+    ) -> (Self, Vec<bool>) {
+        let mut stats = Self::default();
+        let mut lengths = Vec::with_capacity(r1.len());
+        let mut qscore_sum = 0.0;
+        let mut keep = Vec::with_capacity(r1.len());
+
+        for (i, seq1) in r1.iter().enumerate() {
+            let seq2 = r2.get(i).copied().unwrap_or_default();
+            let length = seq1.len() + seq2.len();
+            let qscore = read_qscore(
+                q1.get(i).copied().unwrap_or_default(),
+                q2.get(i).copied().unwrap_or_default(),
+            );
+
+            let (chimera, adapter) = [*seq1, seq2]
+                .iter()
+                .filter(|seq| !seq.is_empty())
+                .flat_map(|seq| {
+                    let mut hits = chemistry.scan_barcode_windows(seq, max_mismatches);
+                    hits.extend(chemistry.scan_barcode_windows(&revcomp(seq), max_mismatches));
+                    hits
+                })
+                .fold((false, false), |(chimera, adapter), (_, decoded)| {
+                    (chimera || decoded, adapter || !decoded)
+                });
+
+            stats.reads += 1;
+            stats.bases += length as u64;
+            qscore_sum += qscore;
+            lengths.push(length as u64);
+            stats.length_hist[LENGTH_BIN_UPPER.partition_point(|upper| *upper <= length)] += 1;
+            if chimera {
+                stats.chimeras += 1;
+            } else if adapter {
+                stats.adapters_internal += 1;
+            }
+
+            let pass = length >= filter.min_length
+                && qscore >= filter.min_qscore
+                && !(filter.drop_chimeras && chimera);
+            stats.reads_pass += pass as u64;
+            keep.push(pass);
+        }
+
+        stats.max_length = lengths.iter().copied().max().unwrap_or(0);
+        stats.n50 = n50(&mut lengths);
+        if stats.reads > 0 {
+            stats.mean_qscore = qscore_sum / stats.reads as f64;
+        }
+        (stats, keep)
+    }
+
+    fn write_row<W: Write>(&self, writer: &mut W, cell_id: &[u8]) -> std::io::Result<()> {
+        let rate = |count: u64| {
+            if self.reads == 0 {
+                0.0
+            } else {
+                count as f64 / self.reads as f64
+            }
+        };
+        writer.write_all(cell_id)?;
+        write!(
+            writer,
+            "\t{}\t{}\t{}\t{:.1}\t{}\t{:.2}\t{:.4}\t{:.4}\t{}",
+            self.reads,
+            self.bases,
+            self.n50,
+            if self.reads == 0 {
+                0.0
+            } else {
+                self.bases as f64 / self.reads as f64
+            },
+            self.max_length,
+            self.mean_qscore,
+            rate(self.chimeras),
+            rate(self.adapters_internal),
+            self.reads_pass,
+        )?;
+        for count in self.length_hist {
+            write!(writer, "\t{count}")?;
+        }
+        writeln!(writer)
+    }
+}
+
+/// Q-score of a read from its mean per-base error probability, as reported by the basecaller
+fn read_qscore(q1: &[u8], q2: &[u8]) -> f64 {
+    let num_bases = q1.len() + q2.len();
+    if num_bases == 0 {
+        return 0.0;
+    }
+    let error_sum: f64 = q1
+        .iter()
+        .chain(q2)
+        .map(|q| 10f64.powf(-(q.saturating_sub(PHRED_OFFSET) as f64) / 10.0))
+        .sum();
+    -10.0 * (error_sum / num_bases as f64).log10()
+}
+
+/// Length such that reads at least this long hold half of the bases
+fn n50(lengths: &mut [u64]) -> u64 {
+    lengths.sort_unstable_by(|a, b| b.cmp(a));
+    let total: u64 = lengths.iter().sum();
+    let mut cumulative = 0;
+    for length in lengths.iter() {
+        cumulative += length;
+        if cumulative * 2 >= total {
+            return *length;
+        }
+    }
+    0
+}
+
+fn revcomp(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' => b'A',
+            other => *other,
+        })
+        .collect()
+}
+
+struct FilteredTirpWriter {
+    path_tmp: PathBuf,
+    bbgzwriter: BBGZWriter,
+    metadata: Option<Vec<u8>>,
+    histogram: BTreeMap<Vec<u8>, u64>,
+}
+
+impl FilteredTirpWriter {
+    fn new(path_out: &Path, num_threads: usize, metadata: BBGZMetadata) -> Result<Self> {
+        let path_tmp = atomic_temp_path(path_out);
+        let file = File::create(&path_tmp)
+            .with_context(|| format!("failed to create {}", path_tmp.display()))?;
+        Ok(Self {
+            path_tmp,
+            bbgzwriter: BBGZWriter::builder()
+                .countof_threads(BoundedU64::new_saturating(num_threads as u64))
+                .with_writer(file)
+                .build(),
+            metadata: Some(metadata.to_bytes()?),
+            histogram: BTreeMap::new(),
+        })
+    }
+
+    fn write_cell(&mut self, cell: &tirp::Cell, keep: &[bool]) -> Result<()> {
+        let num_kept = keep.iter().filter(|k| **k).count();
+        if num_kept == 0 {
+            return Ok(());
+        }
+        let id = *cell.get_ref::<Id>();
+        let mut bbgzheader = BBGZHeader::new();
+        unsafe {
+            bbgzheader.add_extra_unchecked(b"ID", id.to_vec());
+        }
+        if let Some(metadata) = self.metadata.take() {
+            bbgzheader
+                .add_extra(&METADATA_SUBFIELD, metadata)
+                .map_err(|_| anyhow::anyhow!("duplicate BBGZ metadata subfield"))?;
+        }
+        let mut blockwriter: BBGZWriteBlock<'_> = self.bbgzwriter.begin(bbgzheader);
+
+        let (r1, r2) = (cell.get_ref::<R1>(), cell.get_ref::<R2>());
+        let (q1, q2) = (cell.get_ref::<Q1>(), cell.get_ref::<Q2>());
+        let umis = cell.get_ref::<Umi>();
+        for i in (0..r1.len()).filter(|i| keep[*i]) {
+            let fields: [&[u8]; 12] = [
+                id,
+                b"\t1\t1\t",
+                r1[i],
+                b"\t",
+                r2[i],
+                b"\t",
+                q1[i],
+                b"\t",
+                q2[i],
+                b"\t",
+                umis[i],
+                b"\n",
+            ];
+            // Reserve space for the entire record to prevent splitting across blocks; only
+            // records larger than a block are split
+            blockwriter.reserve(fields.iter().map(|f| f.len()).sum());
+            for field in fields {
+                blockwriter.write_all(field)?;
+            }
+        }
+        blockwriter.flush()?;
+        self.histogram.insert(id.to_vec(), num_kept as u64);
+        Ok(())
+    }
+
+    fn finish(self, path_out: &Path) -> Result<()> {
+        self.bbgzwriter
+            .finish_async()
+            .join()
+            .map_err(|_| anyhow::anyhow!("BBGZ writer panicked while finishing"))?;
+        write_histogram(
+            &get_histogram_path_for_tirp(&path_out.to_path_buf()),
+            &self.histogram,
+        )?;
+        publish_atomic_output(&self.path_tmp, path_out)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn n50_and_qscore() {
+        assert_eq!(n50(&mut [2, 3, 4, 5, 6, 7, 8, 9, 10]), 8);
+        assert_eq!(n50(&mut []), 0);
+        assert!((read_qscore(b"++++", b"") - 10.0).abs() < 1e-9);
+        // Q10 and Q30 average to an error of 0.0505, not Q20
+        let q = read_qscore(b"+", b"?");
+        assert!((q - (-10.0 * 0.0505f64.log10())).abs() < 1e-9);
+    }
+
+    #[test]
+    fn flags_internal_barcode_windows() {
+        let chemistry = DebarcodeAtrandiWGSChemistryLongread::new();
+        let filter = ReadFilter {
+            min_length: 50,
+            min_qscore: 0.0,
+            drop_chimeras: false,
+        };
+        let plain: Vec<u8> = b"ACGTTGCA".repeat(20);
+        let mut adapter = plain.clone();
+        adapter.splice(
+            40..40,
+            b"NNNNNNNNAGGANNNNNNNNACTCNNNNNNNNAAGGNNNNNNNNN"
+                .iter()
+                .copied(),
+        );
+        let adapter_rc = revcomp(&adapter);
+        let reads: Vec<&[u8]> = vec![&plain, &adapter, &adapter_rc, b"ACGT"];
+        let empty: Vec<&[u8]> = vec![b""; reads.len()];
+        let quals: Vec<Vec<u8>> = reads.iter().map(|r| vec![b'5'; r.len()]).collect();
+        let quals: Vec<&[u8]> = quals.iter().map(|q| q.as_slice()).collect();
+
+        let (stats, keep) =
+            LongReadStats::compute(&reads, &empty, &quals, &empty, &chemistry, 1, filter);
+        assert_eq!(stats.reads, 4);
+        assert_eq!(stats.adapters_internal, 2);
+        assert_eq!(stats.chimeras, 0);
+        assert_eq!(stats.length_hist[0], 4);
+        assert_eq!(keep, [true, true, true, false]);
+        assert!((stats.mean_qscore - 20.0).abs() < 1e-9);
+    }
+}
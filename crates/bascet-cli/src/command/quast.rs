@@ -1,18 +1,30 @@
 use std::collections::BTreeSet;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::thread;
 
 use anyhow::{Context, Result, bail};
+use bascet_core::{
+    ArenaPool, Composite,
+    attr::{meta::Id, sequence::R0},
+};
 use clap::Args;
+use serde::Serialize;
+use serde::ser::SerializeMap;
 use tracing::{info, warn};
 use zip::read::ZipArchive;
 
+#[cfg(feature = "minimap2-rs-align")]
+use super::quast_reference::{ReferenceAligner, ReferenceSource, ReferenceStats};
 use crate::fileformat::new_anndata::SparseMatrixAnnDataWriter;
-use crate::utils::{atomic_temp_path, publish_atomic_output};
+use crate::fileformat::zip::{new_fasta_arena_pool, stream_fasta_entry};
+use crate::utils::{ColumnarFormat, atomic_temp_path, publish_atomic_output, write_columnar_table};
 
 const DEFAULT_CONTIG_NAME: &str = "contigs.fa";
+const DEFAULT_REFERENCE_PRESET: &str = "asm5";
+const DEFAULT_MAX_LOADED_REFERENCES: usize = 8;
 
 #[derive(Args)]
 pub struct QuastCMD {
@@ -20,7 +32,8 @@ pub struct QuastCMD {
     #[arg(short = 'i', value_parser = clap::value_parser!(PathBuf))]
     pub path_in: PathBuf,
 
-    /// Output h5ad file with empty X and QUAST metrics in obs.
+    /// Output h5ad file with empty X and QUAST metrics in obs, or an Arrow IPC/Parquet table of
+    /// the same metrics if it ends in `.arrow`/`.feather`/`.parquet`.
     #[arg(short = 'o', value_parser = clap::value_parser!(PathBuf))]
     pub path_out: PathBuf,
 
@@ -35,17 +48,50 @@ pub struct QuastCMD {
     /// Cell-local contig file name.
     #[arg(long = "contig-name", default_value = DEFAULT_CONTIG_NAME)]
     pub contig_name: String,
+
+    /// Reference FASTA, or a directory of FASTAs (one per reference) used together with
+    /// --reference-map. Enables reference-based metrics (genome fraction, NGA50, misassemblies).
+    #[arg(long = "reference", value_parser = clap::value_parser!(PathBuf))]
+    pub path_reference: Option<PathBuf>,
+
+    /// TSV of cell<TAB>reference, naming a FASTA in the --reference directory (without extension).
+    /// E.g. the best hit per cell from kraken or minhash.
+    #[arg(long = "reference-map", value_parser = clap::value_parser!(PathBuf))]
+    pub path_reference_map: Option<PathBuf>,
+
+    /// minimap2 preset used to align contigs to the reference.
+    #[arg(long = "reference-preset", default_value = DEFAULT_REFERENCE_PRESET)]
+    pub reference_preset: String,
+
+    /// Maximum number of reference indexes kept in memory at once with --reference-map. The
+    /// least recently used one is dropped, and indexed again if a later cell needs it.
+    #[arg(long = "max-loaded-references", default_value_t = DEFAULT_MAX_LOADED_REFERENCES)]
+    pub max_loaded_references: usize,
 }
 
 impl QuastCMD {
     pub fn try_execute(&mut self) -> Result<()> {
         self.validate()?;
+
+        #[cfg(feature = "minimap2-rs-align")]
+        let reference = match &self.path_reference {
+            Some(path_reference) => Some(Arc::new(ReferenceAligner::new(
+                ReferenceSource::new(path_reference, self.path_reference_map.as_deref())?,
+                self.reference_preset.clone(),
+                self.max_loaded_references,
+            ))),
+            None => None,
+        };
+        #[cfg(not(feature = "minimap2-rs-align"))]
+        let reference = None;
+
         run_quast_cells(
             self.path_in.clone(),
             self.path_out.clone(),
             self.effective_quast_workers(),
             self.min_contig,
             self.contig_name.clone(),
+            reference,
         )
     }
 
@@ -59,6 +105,16 @@ impl QuastCMD {
         {
             bail!("--contig-name must be a cell-local file name");
         }
+        if self.max_loaded_references == 0 {
+            bail!("--max-loaded-references must be > 0");
+        }
+        if self.path_reference_map.is_some() && self.path_reference.is_none() {
+            bail!("--reference-map requires --reference");
+        }
+        #[cfg(not(feature = "minimap2-rs-align"))]
+        if self.path_reference.is_some() {
+            bail!("--reference requires bascet to be built with the minimap2-rs-align feature");
+        }
         Ok(())
     }
 
@@ -78,9 +134,14 @@ struct CellInput {
     entry_name: String,
 }
 
+#[cfg(not(feature = "minimap2-rs-align"))]
+enum ReferenceAligner {}
+
 struct CellQuast {
     cell_id: String,
     stats: AssemblyStats,
+    #[cfg(feature = "minimap2-rs-align")]
+    reference: Option<ReferenceStats>,
 }
 
 #[derive(Debug, Default)]
@@ -98,6 +159,7 @@ fn run_quast_cells(
     quast_workers: usize,
     min_contig: usize,
     contig_name: String,
+    reference: Option<Arc<ReferenceAligner>>,
 ) -> Result<()> {
     let cells = list_contig_cells(&path_in, &contig_name)?;
     info!("queued {} cells with {}", cells.len(), contig_name);
@@ -119,10 +181,18 @@ fn run_quast_cells(
         let path_in = path_in.clone();
         let rx_cells = rx_cells.clone();
         let tx_reports = tx_reports.clone();
+        let reference = reference.clone();
         workers.push(thread::spawn(move || {
+            let arena_pool = new_fasta_arena_pool();
             while let Ok(cell) = rx_cells.recv() {
                 info!("quast worker {} processing {}", worker_id, cell.cell_id);
-                let report = process_cell(&path_in, cell, min_contig);
+                let report = process_cell(
+                    &path_in,
+                    cell,
+                    min_contig,
+                    reference.as_deref(),
+                    &arena_pool,
+                );
                 if tx_reports.send(report).is_err() {
                     break;
                 }
@@ -145,7 +215,7 @@ fn run_quast_cells(
             .join()
             .map_err(|_| anyhow::anyhow!("quast worker thread panicked"))?;
     }
-    write_anndata(path_out, reports)
+    write_reports(path_out, reports, reference.is_some())
 }
 
 fn list_contig_cells(path_in: &Path, contig_name: &str) -> Result<Vec<CellInput>> {
@@ -194,89 +264,108 @@ fn list_contig_cells(path_in: &Path, contig_name: &str) -> Result<Vec<CellInput>
     Ok(cells)
 }
 
-fn process_cell(path_in: &Path, cell: CellInput, min_contig: usize) -> Result<CellQuast> {
-    let file = File::open(path_in)
-        .with_context(|| format!("failed to open input zip {}", path_in.display()))?;
-    let mut zip = ZipArchive::new(BufReader::new(file))
+fn process_cell(
+    path_in: &Path,
+    cell: CellInput,
+    min_contig: usize,
+    reference: Option<&ReferenceAligner>,
+    arena_pool: &Arc<ArenaPool<u8>>,
+) -> Result<CellQuast> {
+    let open = || {
+        File::open(path_in)
+            .with_context(|| format!("failed to open input zip {}", path_in.display()))
+    };
+    let mut zip = ZipArchive::new(BufReader::new(open()?))
         .with_context(|| format!("failed to read input zip {}", path_in.display()))?;
-    let mut entry = zip
-        .by_name(&cell.entry_name)
+    let index = zip
+        .index_for_name(&cell.entry_name)
         .with_context(|| format!("missing zip entry {}", cell.entry_name))?;
 
-    let stats = read_fasta_stats(&mut entry, min_contig)
-        .with_context(|| format!("failed to parse {}", cell.entry_name))?;
-    Ok(CellQuast {
-        cell_id: cell.cell_id,
-        stats,
-    })
+    //Contigs are only kept when they are to be aligned to a reference
+    let mut contigs = reference.map(|_| Vec::new());
+    let stats = read_fasta_entry(
+        &mut zip,
+        open()?,
+        arena_pool,
+        index,
+        min_contig,
+        contigs.as_mut(),
+    )
+    .with_context(|| format!("failed to parse {}", cell.entry_name))?;
+
+    let Some(reference) = reference else {
+        return Ok(CellQuast {
+            cell_id: cell.cell_id,
+            stats,
+            #[cfg(feature = "minimap2-rs-align")]
+            reference: None,
+        });
+    };
+
+    #[cfg(not(feature = "minimap2-rs-align"))]
+    match *reference {}
+
+    #[cfg(feature = "minimap2-rs-align")]
+    {
+        let reference = reference
+            .evaluate(&cell.cell_id, &contigs.unwrap_or_default())
+            .with_context(|| format!("failed to align contigs of cell {}", cell.cell_id))?;
+        Ok(CellQuast {
+            cell_id: cell.cell_id,
+            stats,
+            reference,
+        })
+    }
 }
 
-fn read_fasta_stats<R: Read>(reader: R, min_contig: usize) -> Result<AssemblyStats> {
+fn read_fasta_entry<R: Read + Seek, S: Read + Seek + Send + 'static>(
+    zip: &mut ZipArchive<R>,
+    reader: S,
+    arena_pool: &Arc<ArenaPool<u8>>,
+    index: usize,
+    min_contig: usize,
+    mut contigs: Option<&mut Vec<(String, Vec<u8>)>>,
+) -> Result<AssemblyStats> {
     let mut stats = AssemblyStats::default();
-    let mut reader = BufReader::new(reader);
-    let mut buf = Vec::new();
-    let mut current_len = 0usize;
-    let mut current_gc = 0usize;
-    let mut current_acgt = 0usize;
-    let mut current_ns = 0usize;
-    let mut saw_record = false;
-
-    loop {
-        buf.clear();
-        let bytes = std::io::BufRead::read_until(&mut reader, b'\n', &mut buf)?;
-        if bytes == 0 {
-            break;
-        }
-        while matches!(buf.last(), Some(b'\n' | b'\r')) {
-            buf.pop();
-        }
-        if buf.starts_with(b">") {
-            if saw_record {
-                add_contig(
-                    &mut stats,
-                    current_len,
-                    current_gc,
-                    current_acgt,
-                    current_ns,
-                    min_contig,
-                );
-            }
-            saw_record = true;
-            current_len = 0;
-            current_gc = 0;
-            current_acgt = 0;
-            current_ns = 0;
-            continue;
-        }
-        for b in &buf {
-            current_len += 1;
-            match b.to_ascii_uppercase() {
-                b'G' | b'C' => {
-                    current_gc += 1;
-                    current_acgt += 1;
-                }
-                b'A' | b'T' => current_acgt += 1,
-                b'N' => current_ns += 1,
-                _ => {}
-            }
+    stream_fasta_entry(zip, reader, arena_pool, index, |record| {
+        let seq: &[u8] = record.get_ref::<R0>();
+        add_sequence(&mut stats, seq, min_contig);
+        if let Some(contigs) = contigs.as_mut()
+            && seq.len() >= min_contig
+        {
+            let name = record
+                .get_ref::<Id>()
+                .split(|b| b.is_ascii_whitespace())
+                .next()
+                .unwrap_or_default();
+            contigs.push((String::from_utf8_lossy(name).into_owned(), seq.to_vec()));
         }
-    }
+        Ok(())
+    })?;
 
-    if saw_record {
-        add_contig(
-            &mut stats,
-            current_len,
-            current_gc,
-            current_acgt,
-            current_ns,
-            min_contig,
-        );
-    }
     stats.all_lengths.sort_unstable_by(|a, b| b.cmp(a));
     stats.lengths.sort_unstable_by(|a, b| b.cmp(a));
     Ok(stats)
 }
 
+fn add_sequence(stats: &mut AssemblyStats, seq: &[u8], min_contig: usize) {
+    let mut gc = 0usize;
+    let mut acgt = 0usize;
+    let mut ns = 0usize;
+    for b in seq {
+        match b.to_ascii_uppercase() {
+            b'G' | b'C' => {
+                gc += 1;
+                acgt += 1;
+            }
+            b'A' | b'T' => acgt += 1,
+            b'N' => ns += 1,
+            _ => {}
+        }
+    }
+    add_contig(stats, seq.len(), gc, acgt, ns, min_contig);
+}
+
 fn add_contig(
     stats: &mut AssemblyStats,
     len: usize,
@@ -294,11 +383,49 @@ fn add_contig(
     }
 }
 
-fn write_anndata(path_out: PathBuf, mut reports: Vec<CellQuast>) -> Result<()> {
+fn write_reports(
+    path_out: PathBuf,
+    mut reports: Vec<CellQuast>,
+    with_reference: bool,
+) -> Result<()> {
     reports.sort_unstable_by(|a, b| a.cell_id.cmp(&b.cell_id));
-    let path_tmp = atomic_temp_path(&path_out);
+    let cell_names: Vec<String> = reports
+        .iter()
+        .map(|report| report.cell_id.clone())
+        .collect();
+    let mut columns = obs_columns(&reports);
+    #[cfg(feature = "minimap2-rs-align")]
+    if with_reference {
+        columns.extend(reference_obs_columns(&reports));
+    }
+    #[cfg(not(feature = "minimap2-rs-align"))]
+    let _ = with_reference;
+
+    if let Some(format) = ColumnarFormat::from_path(&path_out) {
+        let rows = (0..cell_names.len()).map(|row| ObsRow {
+            cell_names: &cell_names,
+            columns: &columns,
+            row,
+        });
+        write_columnar_table(&path_out, format, rows)?;
+    } else {
+        write_anndata(&path_out, &cell_names, &columns)?;
+    }
+    info!(
+        "wrote quast output for final total of {} cells",
+        cell_names.len()
+    );
+    Ok(())
+}
+
+fn write_anndata(
+    path_out: &Path,
+    cell_names: &Vec<String>,
+    columns: &[(&'static str, Vec<f64>)],
+) -> Result<()> {
+    let path_tmp = atomic_temp_path(path_out);
     let mut file = SparseMatrixAnnDataWriter::create_anndata(&path_tmp)?;
-    let n_rows = reports.len() as u32;
+    let n_rows = cell_names.len() as u32;
     let n_cols = 0;
     let empty_matrix = sprs::CsMat::<u32>::new(
         (n_rows as usize, n_cols as usize),
@@ -308,19 +435,32 @@ fn write_anndata(path_out: PathBuf, mut reports: Vec<CellQuast>) -> Result<()> {
     );
 
     let empty_features = Vec::new();
-    let cell_names: Vec<String> = reports
-        .iter()
-        .map(|report| report.cell_id.clone())
-        .collect();
     file.store_feature_names(&empty_features)?;
-    file.store_cell_obs_f64(&cell_names, &obs_columns(&reports))?;
+    file.store_cell_obs_f64(cell_names, columns)?;
     file.store_sparse_count_matrix(&empty_matrix, n_rows, n_cols)?;
     file.close()?;
-    publish_atomic_output(&path_tmp, &path_out)?;
-    info!("wrote quast output for final total of {} cells", n_rows);
+    publish_atomic_output(&path_tmp, path_out)?;
     Ok(())
 }
 
+/// One cell of the obs columns, as a table row: the cell name followed by its metrics
+struct ObsRow<'a> {
+    cell_names: &'a [String],
+    columns: &'a [(&'static str, Vec<f64>)],
+    row: usize,
+}
+
+impl Serialize for ObsRow<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len() + 1))?;
+        map.serialize_entry("cell", &self.cell_names[self.row])?;
+        for (name, values) in self.columns {
+            map.serialize_entry(name, &values[self.row])?;
+        }
+        map.end()
+    }
+}
+
 fn obs_columns(reports: &[CellQuast]) -> Vec<(&'static str, Vec<f64>)> {
     let mut contigs_ge_0bp = Vec::with_capacity(reports.len());
     let mut contigs_ge_1000bp = Vec::with_capacity(reports.len());
@@ -405,6 +545,36 @@ fn obs_columns(reports: &[CellQuast]) -> Vec<(&'static str, Vec<f64>)> {
     ]
 }
 
+/// Reference-based columns. Cells without an assigned reference get NaN.
+#[cfg(feature = "minimap2-rs-align")]
+fn reference_obs_columns(reports: &[CellQuast]) -> Vec<(&'static str, Vec<f64>)> {
+    let column = |f: fn(&ReferenceStats) -> f64| -> Vec<f64> {
+        reports
+            .iter()
+            .map(|report| report.reference.as_ref().map_or(f64::NAN, f))
+            .collect()
+    };
+    vec![
+        ("reference_length", column(|r| r.reference_length as f64)),
+        ("genome_fraction", column(|r| r.genome_fraction)),
+        ("duplication_ratio", column(|r| r.duplication_ratio)),
+        ("largest_alignment", column(|r| r.largest_alignment as f64)),
+        (
+            "total_aligned_length",
+            column(|r| r.total_aligned_length as f64),
+        ),
+        ("nga50", column(|r| r.nga50 as f64)),
+        ("mismatches_per_100kbp", column(|r| r.mismatches_per_100kbp)),
+        ("indels_per_100kbp", column(|r| r.indels_per_100kbp)),
+        ("misassemblies", column(|r| r.misassemblies as f64)),
+        (
+            "misassembled_contigs",
+            column(|r| r.misassembled_contigs as f64),
+        ),
+        ("unaligned_contigs", column(|r| r.unaligned_contigs as f64)),
+    ]
+}
+
 fn validate_zip_cell_id(cell_id: &str) -> Result<()> {
     if cell_id.is_empty() {
         bail!("empty cell id is not supported");
@@ -481,6 +651,7 @@ fn gc_percent(stats: &AssemblyStats) -> Option<f64> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::io::{Cursor, Write};
 
     #[test]
     fn n_and_l_metrics_match_quast_formula() {
@@ -491,10 +662,27 @@ mod tests {
         assert_eq!(l_metric(&lengths, 90), Some(3));
     }
 
+    fn contigs_zip(fasta: &[u8]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options: zip::write::FileOptions<()> = zip::write::FileOptions::default();
+        writer.start_file("cell/contigs.fa", options).unwrap();
+        writer.write_all(fasta).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
     #[test]
     fn default_min_contig_keeps_short_contigs() {
-        let fasta = b">a\nACGT\n>b\nAC\n";
-        let stats = read_fasta_stats(fasta.as_slice(), 0).unwrap();
+        let bytes = contigs_zip(b">a\nACGT\n>b\nAC\n");
+        let mut zip = ZipArchive::new(Cursor::new(bytes.clone())).unwrap();
+        let stats = read_fasta_entry(
+            &mut zip,
+            Cursor::new(bytes),
+            &new_fasta_arena_pool(),
+            0,
+            0,
+            None,
+        )
+        .unwrap();
 
         assert_eq!(stats.lengths, vec![4, 2]);
         assert_eq!(total_len(&stats.lengths), 6);
@@ -0,0 +1,600 @@
+//! Reference-based assembly metrics for `quast --reference`.
+//!
+//! Contigs are aligned to the reference with the bundled minimap2 backend and the alignments
+//! are summarised the way QUAST does: genome fraction, duplication ratio, largest alignment,
+//! NGA50, mismatches/indels per 100 kbp and misassemblies (relocations, translocations and
+//! inversions between consecutive alignments of one contig).
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{Context, Result, bail};
+use minimap2::{aligner::Aligner, flags::MapFlags, format::sam as minimap_sam, map};
+use tracing::{info, warn};
+
+use crate::utils::list_fasta_files;
+
+/// Reference gap/overlap between two alignments of one contig above which QUAST calls a
+/// relocation.
+const MISASSEMBLY_RELOCATION_BP: i64 = 1000;
+
+///////////////////////////////
+/// Where the reference for each cell comes from
+pub enum ReferenceSource {
+    /// One reference for all cells
+    Single(PathBuf),
+    /// A directory of references, and cell -> reference name (file name without extension)
+    PerCell {
+        references: HashMap<String, PathBuf>,
+        cell_to_reference: HashMap<String, String>,
+    },
+}
+
+impl ReferenceSource {
+    pub fn new(path_reference: &Path, path_reference_map: Option<&Path>) -> Result<Self> {
+        if path_reference.is_file() {
+            if path_reference_map.is_some() {
+                warn!("--reference is a single file; ignoring the cell -> reference map");
+            }
+            return Ok(ReferenceSource::Single(path_reference.to_path_buf()));
+        }
+        if !path_reference.is_dir() {
+            bail!("reference {} does not exist", path_reference.display());
+        }
+        let Some(path_reference_map) = path_reference_map else {
+            bail!("--reference is a directory; --reference-map is needed to pick one per cell");
+        };
+
+        let references: HashMap<String, PathBuf> =
+            list_fasta_files(path_reference)?.into_iter().collect();
+        if references.is_empty() {
+            bail!("no FASTA files found in {}", path_reference.display());
+        }
+
+        let cell_to_reference = read_reference_map(path_reference_map)?;
+        info!(
+            references = references.len(),
+            cells = cell_to_reference.len(),
+            "Loaded per-cell reference assignment"
+        );
+        Ok(ReferenceSource::PerCell {
+            references,
+            cell_to_reference,
+        })
+    }
+
+    fn reference_for_cell(&self, cell_id: &str) -> Option<&Path> {
+        match self {
+            ReferenceSource::Single(path) => Some(path),
+            ReferenceSource::PerCell {
+                references,
+                cell_to_reference,
+            } => cell_to_reference
+                .get(cell_id)
+                .and_then(|name| references.get(name))
+                .map(PathBuf::as_path),
+        }
+    }
+}
+
+/// Read a `cell<TAB>reference` table. Lines starting with `#` and an optional header line
+/// (`cell ...`) are skipped. Extra columns, e.g. scores from kraken or minhash, are ignored.
+fn read_reference_map(path: &Path) -> Result<HashMap<String, String>> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open reference map {}", path.display()))?;
+    let mut map = HashMap::new();
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let (Some(cell_id), Some(reference)) = (fields.next(), fields.next()) else {
+            bail!(
+                "line {} of {} is not cell<TAB>reference",
+                line_no + 1,
+                path.display()
+            );
+        };
+        if line_no == 0 && cell_id.eq_ignore_ascii_case("cell") {
+            continue;
+        }
+        map.insert(cell_id.to_string(), reference.to_string());
+    }
+    Ok(map)
+}
+
+struct LoadedReference {
+    aligner: Aligner,
+    /// (name, length) per reference sequence
+    sequences: HashMap<String, u64>,
+    total_length: u64,
+}
+
+///////////////////////////////
+/// Aligns cell contigs to their reference. Indexes are built on first use and shared by all
+/// workers; at most `max_loaded` are kept, dropping the least recently used one beyond that.
+pub struct ReferenceAligner {
+    source: ReferenceSource,
+    preset: String,
+    max_loaded: usize,
+    cache: Mutex<ReferenceCache>,
+}
+
+#[derive(Default)]
+struct ReferenceCache {
+    slots: HashMap<PathBuf, CachedReference>,
+    clock: u64,
+}
+
+struct CachedReference {
+    /// Locked while the index is built, so only workers waiting on this reference block
+    slot: Arc<Mutex<Option<Arc<LoadedReference>>>>,
+    last_used: u64,
+}
+
+impl ReferenceAligner {
+    pub fn new(source: ReferenceSource, preset: String, max_loaded: usize) -> Self {
+        Self {
+            source,
+            preset,
+            max_loaded: max_loaded.max(1),
+            cache: Mutex::new(ReferenceCache::default()),
+        }
+    }
+
+    fn load(&self, path: &Path) -> Result<Arc<LoadedReference>> {
+        let slot = self.slot(path)?;
+        let mut slot = slot
+            .lock()
+            .map_err(|_| anyhow::anyhow!("reference slot lock poisoned"))?;
+        if let Some(reference) = slot.as_ref() {
+            return Ok(Arc::clone(reference));
+        }
+
+        info!("indexing reference {}", path.display());
+        let path_str = path
+            .to_str()
+            .with_context(|| format!("reference path is not UTF-8: {path:?}"))?;
+        let mut aligner = Aligner::builder()
+            .preset(&self.preset)
+            .index(path_str)
+            .with_cigar()
+            .build()
+            .map_err(anyhow::Error::msg)?;
+        aligner.map_opt.flag |= MapFlags::OUT_SAM | MapFlags::CIGAR;
+
+        let sam_header = minimap_sam::write_sam_hdr(&aligner.idx, None, &[]);
+        let sequences = parse_sam_header_lengths(&sam_header);
+        let total_length = sequences.values().sum();
+        let reference = Arc::new(LoadedReference {
+            aligner,
+            sequences,
+            total_length,
+        });
+        *slot = Some(Arc::clone(&reference));
+        Ok(reference)
+    }
+
+    /// The cache slot of a reference, creating it if needed. The cache lock is only held for
+    /// the lookup; evicted references stay alive until the workers using them are done.
+    fn slot(&self, path: &Path) -> Result<Arc<Mutex<Option<Arc<LoadedReference>>>>> {
+        let mut cache = self
+            .cache
+            .lock()
+            .map_err(|_| anyhow::anyhow!("reference cache lock poisoned"))?;
+        cache.clock += 1;
+        let clock = cache.clock;
+        let entry = cache
+            .slots
+            .entry(path.to_path_buf())
+            .or_insert_with(|| CachedReference {
+                slot: Arc::default(),
+                last_used: 0,
+            });
+        entry.last_used = clock;
+        let slot = Arc::clone(&entry.slot);
+
+        while cache.slots.len() > self.max_loaded {
+            let Some(evict) = cache
+                .slots
+                .iter()
+                .min_by_key(|(_, cached)| cached.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            info!("dropping reference index {}", evict.display());
+            cache.slots.remove(&evict);
+        }
+        Ok(slot)
+    }
+
+    /// Align the contigs of one cell. Returns `None` if no reference is assigned to the cell.
+    pub fn evaluate(
+        &self,
+        cell_id: &str,
+        contigs: &[(String, Vec<u8>)],
+    ) -> Result<Option<ReferenceStats>> {
+        let Some(path) = self.source.reference_for_cell(cell_id) else {
+            return Ok(None);
+        };
+        let reference = self.load(path)?;
+
+        let mut contig_alignments = Vec::with_capacity(contigs.len());
+        for (name, seq) in contigs {
+            let result = map::map_query(
+                &reference.aligner.idx,
+                &reference.aligner.map_opt,
+                name,
+                seq,
+            );
+            let mut alignments = Vec::with_capacity(result.regs.len());
+            for region in &result.regs {
+                let line = minimap_sam::write_sam_record(
+                    &reference.aligner.idx,
+                    name,
+                    seq,
+                    &[],
+                    Some(region),
+                    result.regs.len(),
+                    &result.regs,
+                    reference.aligner.map_opt.flag,
+                    result.rep_len,
+                );
+                if let Some(alignment) = ContigAlignment::from_sam_line(&line, seq.len() as u64)? {
+                    alignments.push(alignment);
+                }
+            }
+            contig_alignments.push(alignments);
+        }
+
+        Ok(Some(ReferenceStats::from_alignments(
+            &contig_alignments,
+            &reference.sequences,
+            reference.total_length,
+        )))
+    }
+}
+
+fn parse_sam_header_lengths(sam_header: &str) -> HashMap<String, u64> {
+    let mut sequences = HashMap::new();
+    for line in sam_header.lines().filter(|line| line.starts_with("@SQ")) {
+        let mut name = None;
+        let mut length = None;
+        for field in line.split('\t') {
+            if let Some(value) = field.strip_prefix("SN:") {
+                name = Some(value.to_string());
+            } else if let Some(value) = field.strip_prefix("LN:") {
+                length = value.parse::<u64>().ok();
+            }
+        }
+        if let (Some(name), Some(length)) = (name, length) {
+            sequences.insert(name, length);
+        }
+    }
+    sequences
+}
+
+///////////////////////////////
+/// One primary or supplementary alignment of a contig
+#[derive(Debug, Clone)]
+struct ContigAlignment {
+    reference: String,
+    /// 0-based, half-open
+    ref_start: u64,
+    ref_end: u64,
+    /// 0-based, half-open, in the forward orientation of the contig
+    query_start: u64,
+    query_end: u64,
+    reverse: bool,
+    mismatches: u64,
+    indels: u64,
+}
+
+impl ContigAlignment {
+    /// Parse the fields needed from a SAM line. Unmapped and secondary records yield `None`.
+    fn from_sam_line(line: &str, contig_len: u64) -> Result<Option<Self>> {
+        let fields: Vec<&str> = line.trim_end().split('\t').collect();
+        if fields.len() < 11 {
+            bail!("malformed minimap2 SAM record: {line}");
+        }
+        let flags: u16 = fields[1].parse().context("bad SAM flag")?;
+        if flags & 0x4 != 0 || flags & 0x100 != 0 {
+            return Ok(None);
+        }
+        let reverse = flags & 0x10 != 0;
+        let ref_start = fields[3].parse::<u64>().context("bad SAM position")? - 1;
+
+        let mut ref_len = 0_u64;
+        let mut query_aligned = 0_u64;
+        let mut clip_left = 0_u64;
+        let mut clip_right = 0_u64;
+        let mut indel_events = 0_u64;
+        let mut indel_bases = 0_u64;
+        let mut seen_aligned = false;
+        let mut num = 0_u64;
+        for b in fields[5].bytes() {
+            if b.is_ascii_digit() {
+                num = num * 10 + (b - b'0') as u64;
+                continue;
+            }
+            match b {
+                b'M' | b'=' | b'X' => {
+                    ref_len += num;
+                    query_aligned += num;
+                    seen_aligned = true;
+                }
+                b'I' => {
+                    query_aligned += num;
+                    indel_events += 1;
+                    indel_bases += num;
+                }
+                b'D' => {
+                    ref_len += num;
+                    indel_events += 1;
+                    indel_bases += num;
+                }
+                b'N' => ref_len += num,
+                b'S' | b'H' => {
+                    if seen_aligned {
+                        clip_right += num;
+                    } else {
+                        clip_left += num;
+                    }
+                }
+                _ => {}
+            }
+            num = 0;
+        }
+
+        let edit_distance = fields[11..]
+            .iter()
+            .find_map(|tag| tag.strip_prefix("NM:i:"))
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(indel_bases);
+
+        //Clipping is reported in alignment orientation; flip it back for reverse alignments
+        let (query_start, query_end) = if reverse {
+            (clip_right, contig_len.saturating_sub(clip_left))
+        } else {
+            (clip_left, clip_left + query_aligned)
+        };
+
+        Ok(Some(ContigAlignment {
+            reference: fields[2].to_string(),
+            ref_start,
+            ref_end: ref_start + ref_len,
+            query_start,
+            query_end,
+            reverse,
+            mismatches: edit_distance.saturating_sub(indel_bases),
+            indels: indel_events,
+        }))
+    }
+
+    fn ref_len(&self) -> u64 {
+        self.ref_end - self.ref_start
+    }
+
+    fn query_len(&self) -> u64 {
+        self.query_end.saturating_sub(self.query_start)
+    }
+}
+
+///////////////////////////////
+/// Reference-based metrics of one cell
+#[derive(Debug, Default, Clone)]
+pub struct ReferenceStats {
+    pub reference_length: u64,
+    pub genome_fraction: f64,
+    pub duplication_ratio: f64,
+    pub largest_alignment: u64,
+    pub total_aligned_length: u64,
+    pub nga50: u64,
+    pub mismatches_per_100kbp: f64,
+    pub indels_per_100kbp: f64,
+    pub misassemblies: u64,
+    pub misassembled_contigs: u64,
+    pub unaligned_contigs: u64,
+}
+
+impl ReferenceStats {
+    fn from_alignments(
+        contig_alignments: &[Vec<ContigAlignment>],
+        sequences: &HashMap<String, u64>,
+        reference_length: u64,
+    ) -> Self {
+        let mut stats = ReferenceStats {
+            reference_length,
+            ..Default::default()
+        };
+
+        let mut covered: HashMap<&str, Vec<(u64, u64)>> = HashMap::new();
+        let mut aligned_ref_bases = 0_u64;
+        let mut aligned_block_lengths = Vec::new();
+        let mut mismatches = 0_u64;
+        let mut indels = 0_u64;
+
+        for alignments in contig_alignments {
+            if alignments.is_empty() {
+                stats.unaligned_contigs += 1;
+                continue;
+            }
+            for alignment in alignments {
+                if !sequences.contains_key(&alignment.reference) {
+                    continue;
+                }
+                covered
+                    .entry(alignment.reference.as_str())
+                    .or_default()
+                    .push((alignment.ref_start, alignment.ref_end));
+                aligned_ref_bases += alignment.ref_len();
+                aligned_block_lengths.push(alignment.query_len());
+                mismatches += alignment.mismatches;
+                indels += alignment.indels;
+            }
+
+            let num_misassemblies = count_misassemblies(alignments);
+            stats.misassemblies += num_misassemblies;
+            if num_misassemblies > 0 {
+                stats.misassembled_contigs += 1;
+            }
+        }
+
+        let covered_bases: u64 = covered
+            .into_values()
+            .map(|intervals| union_length(intervals))
+            .sum();
+        aligned_block_lengths.sort_unstable_by(|a, b| b.cmp(a));
+
+        stats.largest_alignment = aligned_block_lengths.first().copied().unwrap_or(0);
+        stats.total_aligned_length = aligned_block_lengths.iter().sum();
+        stats.nga50 = ng_metric(&aligned_block_lengths, reference_length, 50);
+        if reference_length > 0 {
+            stats.genome_fraction = covered_bases as f64 * 100.0 / reference_length as f64;
+        }
+        if covered_bases > 0 {
+            stats.duplication_ratio = aligned_ref_bases as f64 / covered_bases as f64;
+        }
+        if stats.total_aligned_length > 0 {
+            stats.mismatches_per_100kbp =
+                mismatches as f64 * 100_000.0 / stats.total_aligned_length as f64;
+            stats.indels_per_100kbp = indels as f64 * 100_000.0 / stats.total_aligned_length as f64;
+        }
+        stats
+    }
+}
+
+/// Count breakpoints between consecutive alignments of one contig, in contig order
+fn count_misassemblies(alignments: &[ContigAlignment]) -> u64 {
+    let mut ordered: Vec<&ContigAlignment> = alignments.iter().collect();
+    ordered.sort_unstable_by_key(|alignment| alignment.query_start);
+
+    let mut misassemblies = 0;
+    for pair in ordered.windows(2) {
+        let (prev, next) = (pair[0], pair[1]);
+        if prev.reference != next.reference || prev.reverse != next.reverse {
+            //Translocation or inversion
+            misassemblies += 1;
+            continue;
+        }
+        let query_gap = next.query_start as i64 - prev.query_end as i64;
+        let ref_gap = if prev.reverse {
+            prev.ref_start as i64 - next.ref_end as i64
+        } else {
+            next.ref_start as i64 - prev.ref_end as i64
+        };
+        if (ref_gap - query_gap).abs() > MISASSEMBLY_RELOCATION_BP {
+            misassemblies += 1;
+        }
+    }
+    misassemblies
+}
+
+fn union_length(mut intervals: Vec<(u64, u64)>) -> u64 {
+    intervals.sort_unstable();
+    let mut total = 0;
+    let mut current: Option<(u64, u64)> = None;
+    for (start, end) in intervals {
+        current = match current {
+            Some((cur_start, cur_end)) if start <= cur_end => Some((cur_start, cur_end.max(end))),
+            Some((cur_start, cur_end)) => {
+                total += cur_end - cur_start;
+                Some((start, end))
+            }
+            None => Some((start, end)),
+        };
+    }
+    if let Some((cur_start, cur_end)) = current {
+        total += cur_end - cur_start;
+    }
+    total
+}
+
+/// NGx over lengths sorted in descending order, relative to the reference length
+fn ng_metric(lengths: &[u64], reference_length: u64, percentage: u64) -> u64 {
+    let target = reference_length as f64 * percentage as f64 / 100.0;
+    let mut sum = 0_u64;
+    for &len in lengths {
+        sum += len;
+        if sum as f64 >= target {
+            return len;
+        }
+    }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alignment(
+        reference: &str,
+        ref_start: u64,
+        query_start: u64,
+        len: u64,
+        reverse: bool,
+    ) -> ContigAlignment {
+        ContigAlignment {
+            reference: reference.to_string(),
+            ref_start,
+            ref_end: ref_start + len,
+            query_start,
+            query_end: query_start + len,
+            reverse,
+            mismatches: 0,
+            indels: 0,
+        }
+    }
+
+    #[test]
+    fn sam_line_yields_query_and_reference_span() {
+        let line = "ctg1\t16\tchr1\t101\t60\t5S90M2D5M10S\t*\t0\t0\t*\t*\tNM:i:4";
+        let alignment = ContigAlignment::from_sam_line(line, 110).unwrap().unwrap();
+        assert_eq!((alignment.ref_start, alignment.ref_end), (100, 197));
+        assert_eq!((alignment.query_start, alignment.query_end), (10, 105));
+        assert_eq!(alignment.mismatches, 2);
+        assert_eq!(alignment.indels, 1);
+    }
+
+    #[test]
+    fn breakpoints_are_counted_as_misassemblies() {
+        let collinear = vec![
+            alignment("a", 0, 0, 5000, false),
+            alignment("a", 5100, 5100, 5000, false),
+        ];
+        assert_eq!(count_misassemblies(&collinear), 0);
+
+        let relocated = vec![
+            alignment("a", 0, 0, 5000, false),
+            alignment("a", 50_000, 5000, 5000, false),
+        ];
+        assert_eq!(count_misassemblies(&relocated), 1);
+
+        let translocated = vec![
+            alignment("a", 0, 0, 5000, false),
+            alignment("b", 0, 5000, 5000, false),
+        ];
+        assert_eq!(count_misassemblies(&translocated), 1);
+    }
+
+    #[test]
+    fn genome_fraction_and_duplication_use_union_of_alignments() {
+        let mut sequences = HashMap::new();
+        sequences.insert("a".to_string(), 1000);
+        let contigs = vec![
+            vec![alignment("a", 0, 0, 400, false)],
+            vec![alignment("a", 200, 0, 400, false)],
+            vec![],
+        ];
+        let stats = ReferenceStats::from_alignments(&contigs, &sequences, 1000);
+        assert_eq!(format!("{:.1}", stats.genome_fraction), "60.0");
+        assert_eq!(format!("{:.3}", stats.duplication_ratio), "1.333");
+        assert_eq!(stats.nga50, 400);
+        assert_eq!(stats.unaligned_contigs, 1);
+    }
+}
@@ -0,0 +1,596 @@
+//! `relabel-cells` subcommand: rename and merge cells of a TIRP from an `old_id<TAB>new_id`
+//! mapping table.
+//!
+//! Several old ids may map to the same new id (barcode collisions, bead doublets, joined
+//! libraries); their reads end up in a single cell. Records are relabelled and sorted by new id
+//! in memory-bounded chunks, keeping the input order of reads within a cell. While records
+//! arrive sorted by new id (e.g. a mapping that keeps the cell order), chunks need no sort and
+//! are appended to the same file. A single chunk is published as-is, several are merged with
+//! `shardify`. Cells missing from the table keep their id unless `--drop-unmapped` is given. A
+//! `<out>.hist` histogram is regenerated.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use bascet_core::{
+    attr::{meta::*, quality::*, sequence::*},
+    *,
+};
+use bascet_io::{
+    BBGZHeader, BBGZMetadata, BBGZWriteBlock, BBGZWriter, METADATA_SUBFIELD, parse, tirp,
+};
+use bounded_integer::BoundedU64;
+use bytesize::ByteSize;
+use clap::Args;
+use clio::{InputPath, OutputPath};
+use tracing::{debug, info, warn};
+
+use super::determine_thread_counts_1;
+use super::shardify::ShardifyCMD;
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
+use crate::fileformat::tirp::get_histogram_path_for_tirp;
+use crate::utils::{atomic_temp_path, publish_atomic_output};
+
+const DEFAULT_MEMORY: ByteSize = ByteSize::gib(4);
+const DEFAULT_SIZEOF_STREAM_BUFFER: ByteSize = ByteSize::gib(1);
+
+#[derive(Args)]
+pub struct RelabelCellsCMD {
+    /// Input cell-sorted TIRP.
+    #[arg(short = 'i', long = "in", value_parser)]
+    pub path_in: PathBuf,
+
+    /// Output cell-sorted TIRP. A `<out>.hist` histogram is written alongside.
+    #[arg(short = 'o', long = "out", value_parser)]
+    pub path_out: PathBuf,
+
+    /// TSV of old_id<TAB>new_id. Lines starting with '#' are ignored.
+    #[arg(long = "map", value_parser)]
+    pub path_map: PathBuf,
+
+    /// Drop cells that are not listed in the mapping table instead of keeping their id.
+    #[arg(long = "drop-unmapped")]
+    pub drop_unmapped: bool,
+
+    /// Directory for sorted chunks. Defaults to the directory of the output.
+    #[arg(short = 't', long = "temp", value_parser)]
+    pub path_temp: Option<PathBuf>,
+
+    /// Memory used to sort relabelled records before spilling a chunk.
+    #[arg(short = 'm', long = "memory", value_parser, default_value_t = DEFAULT_MEMORY)]
+    pub memory: ByteSize,
+
+    /// Total threads.
+    #[arg(short = '@', long = "threads", value_parser = clap::value_parser!(usize))]
+    pub num_threads: Option<usize>,
+
+    #[arg(
+        long = "sizeof-stream-buffer",
+        help = "Total stream buffer size.",
+        default_value_t = DEFAULT_SIZEOF_STREAM_BUFFER,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_buffer: ByteSize,
+
+    #[arg(
+        long = "sizeof-stream-arena",
+        help = "Stream arena buffer size [Advanced: changing this will impact performance and stability]",
+        hide_short_help = true,
+        default_value_t = DEFAULT_SIZEOF_ARENA,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_arena: ByteSize,
+}
+
+impl RelabelCellsCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        let num_threads = determine_thread_counts_1(self.num_threads)?;
+        let mapping = read_mapping(&self.path_map)?;
+        let path_temp = match &self.path_temp {
+            Some(path_temp) => path_temp.clone(),
+            None => self
+                .path_out
+                .parent()
+                .filter(|p| !p.as_os_str().is_empty())
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| PathBuf::from(".")),
+        };
+        std::fs::create_dir_all(&path_temp)
+            .with_context(|| format!("failed to create temp dir {}", path_temp.display()))?;
+        info!(
+            input = %self.path_in.display(),
+            output = %self.path_out.display(),
+            mapped_ids = mapping.len(),
+            drop_unmapped = self.drop_unmapped,
+            "RelabelCells: starting"
+        );
+
+        let mut metadata = BBGZMetadata::derive_from_paths([&self.path_in]);
+        metadata.push("relabel_map", self.path_map.display().to_string());
+
+        let mut spiller = ChunkSpiller {
+            path_temp,
+            prefix: format!("bascet-relabel-{}", std::process::id()),
+            num_threads,
+            metadata: metadata.to_bytes()?,
+            metadata_pending: false,
+            open_chunk: None,
+            last_id: None,
+            chunks: ChunkFiles(Vec::new()),
+            histogram: BTreeMap::new(),
+        };
+        let stats = self.relabel_into_chunks(&mapping, num_threads, &mut spiller)?;
+        spiller.finish_chunk()?;
+        let ChunkSpiller {
+            chunks, histogram, ..
+        } = spiller;
+        let chunks = &chunks.0;
+
+        if chunks.len() == 1 {
+            publish_atomic_output(&chunks[0], &self.path_out)
+                .with_context(|| format!("failed to move {} to output", chunks[0].display()))?;
+        } else {
+            info!(chunks = chunks.len(), "RelabelCells: merging sorted chunks");
+            let path_out_tmp = atomic_temp_path(&self.path_out);
+            let mut shardify_cmd = ShardifyCMD {
+                paths_in: chunks
+                    .iter()
+                    .map(InputPath::try_from)
+                    .collect::<Result<_, _>>()?,
+                paths_out: vec![OutputPath::try_from(&path_out_tmp)?],
+                path_include: None,
+                path_temp: self.path_temp.clone(),
+                total_threads: Some(BoundedU64::new_saturating(num_threads.max(2) as u64)),
+                numof_threads_write: None,
+                total_mem: self.memory,
+                sizeof_stream_buffer: None,
+                sizeof_stream_arena: self.sizeof_stream_arena,
+
+                show_filter_warning: false,
+                show_startup_message: false,
+                metadata,
+                report_outputs: false,
+            };
+            shardify_cmd
+                .try_execute()
+                .context("failed to merge sorted chunks")?;
+            publish_atomic_output(&path_out_tmp, &self.path_out)?;
+        }
+
+        write_histogram(&get_histogram_path_for_tirp(&self.path_out), &histogram)?;
+        info!(
+            cells_in = stats.cells_in,
+            cells_out = histogram.len(),
+            cells_renamed = stats.cells_renamed,
+            cells_kept = stats.cells_kept,
+            cells_dropped = stats.cells_dropped,
+            records = stats.records,
+            "RelabelCells: complete"
+        );
+        Ok(())
+    }
+
+    fn relabel_into_chunks(
+        &self,
+        mapping: &HashMap<Vec<u8>, Vec<u8>>,
+        num_threads: usize,
+        spiller: &mut ChunkSpiller,
+    ) -> Result<RelabelStats> {
+        let decoder = open_bbgz_decoder(
+            &self.path_in,
+            BoundedU64::new_saturating(num_threads as u64),
+            None,
+        )?;
+        let parser = parse::Tirp::builder().build();
+        let mut stream = Stream::builder()
+            .with_decoder(decoder)
+            .with_parser(parser)
+            .sizeof_decode_arena(self.sizeof_stream_arena)
+            .sizeof_decode_buffer(self.sizeof_stream_buffer)
+            .build();
+        let mut query = stream.query::<tirp::Record>();
+
+        let mut stats = RelabelStats::default();
+        let mut chunk = SortChunk::default();
+        let mut current_old_id: Vec<u8> = Vec::new();
+        let mut current_new_id: Option<Vec<u8>> = None;
+
+        while let Some(record) = query
+            .next_into::<tirp::Record>()
+            .context("failed to read TIRP record")?
+        {
+            let old_id = *record.get_ref::<Id>();
+            if stats.records == 0 || old_id != current_old_id.as_slice() {
+                stats.cells_in += 1;
+                current_old_id = old_id.to_vec();
+                current_new_id = match mapping.get(old_id) {
+                    Some(new_id) => {
+                        stats.cells_renamed += 1;
+                        Some(new_id.clone())
+                    }
+                    None if self.drop_unmapped => {
+                        stats.cells_dropped += 1;
+                        None
+                    }
+                    None => {
+                        stats.cells_kept += 1;
+                        Some(old_id.to_vec())
+                    }
+                };
+            }
+            stats.records += 1;
+
+            let Some(new_id) = &current_new_id else {
+                continue;
+            };
+            chunk.push(
+                new_id,
+                [
+                    *record.get_ref::<R1>(),
+                    *record.get_ref::<R2>(),
+                    *record.get_ref::<Q1>(),
+                    *record.get_ref::<Q2>(),
+                    *record.get_ref::<Umi>(),
+                ],
+            );
+            if chunk.sizeof() >= self.memory.as_u64() as usize {
+                spiller.spill(std::mem::take(&mut chunk))?;
+            }
+        }
+        if !chunk.is_empty() || spiller.chunks.0.is_empty() {
+            spiller.spill(chunk)?;
+        }
+        Ok(stats)
+    }
+}
+
+#[derive(Debug, Default)]
+struct RelabelStats {
+    cells_in: u64,
+    cells_renamed: u64,
+    cells_kept: u64,
+    cells_dropped: u64,
+    records: u64,
+}
+
+/// Parse an `old_id<TAB>new_id` table. An old id listed twice must map to the same new id.
+fn read_mapping(path: &Path) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let file = File::open(path)
+        .with_context(|| format!("failed to open mapping table {}", path.display()))?;
+    let mut mapping: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    for (line_no, line) in BufReader::new(file).split(b'\n').enumerate() {
+        let line = line?;
+        let line = line.strip_suffix(b"\r").unwrap_or(&line);
+        if line.is_empty() || line.starts_with(b"#") {
+            continue;
+        }
+        let fields: Vec<&[u8]> = line.split(|b| *b == b'\t').collect();
+        let [old_id, new_id] = fields[..] else {
+            bail!(
+                "{}:{}: expected old_id<TAB>new_id, found {} columns",
+                path.display(),
+                line_no + 1,
+                fields.len()
+            );
+        };
+        if old_id.is_empty() || new_id.is_empty() {
+            bail!("{}:{}: empty cell id", path.display(), line_no + 1);
+        }
+        if let Some(previous) = mapping.insert(old_id.to_vec(), new_id.to_vec()) {
+            if previous != new_id {
+                bail!(
+                    "{}:{}: {} is mapped to both {} and {}",
+                    path.display(),
+                    line_no + 1,
+                    String::from_utf8_lossy(old_id),
+                    String::from_utf8_lossy(&previous),
+                    String::from_utf8_lossy(new_id)
+                );
+            }
+        }
+    }
+    Ok(mapping)
+}
+
+/// Relabelled records of one chunk: `data` holds `new_id` followed by the remainder of the
+/// TIRP line for each record, `entries` the `(start, id_end, end)` of each record in `data`
+#[derive(Default)]
+struct SortChunk {
+    data: Vec<u8>,
+    entries: Vec<(usize, usize, usize)>,
+}
+
+impl SortChunk {
+    fn push(&mut self, id: &[u8], [r1, r2, q1, q2, umi]: [&[u8]; 5]) {
+        let start = self.data.len();
+        self.data.extend_from_slice(id);
+        let id_end = self.data.len();
+        for field in [
+            b"\t1\t1\t".as_slice(),
+            r1,
+            b"\t",
+            r2,
+            b"\t",
+            q1,
+            b"\t",
+            q2,
+            b"\t",
+            umi,
+        ] {
+            self.data.extend_from_slice(field);
+        }
+        self.data.push(b'\n');
+        self.entries.push((start, id_end, self.data.len()));
+    }
+
+    fn id(&self, (start, id_end, _): (usize, usize, usize)) -> &[u8] {
+        &self.data[start..id_end]
+    }
+
+    fn sizeof(&self) -> usize {
+        self.data.len() + self.entries.len() * std::mem::size_of::<(usize, usize, usize)>()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Stable sort by id, keeping the input order of records within a cell. Already sorted
+    /// records are left as they are
+    fn sort(&mut self) {
+        if self.entries.is_sorted_by(|a, b| self.id(*a) <= self.id(*b)) {
+            return;
+        }
+        let mut entries = std::mem::take(&mut self.entries);
+        entries.sort_by(|a, b| self.id(*a).cmp(self.id(*b)));
+        self.entries = entries;
+    }
+}
+
+/// Sorted chunk files, deleted when dropped unless they were moved away. This cleans up
+/// after a failed run as well as after a merge
+struct ChunkFiles(Vec<PathBuf>);
+
+impl Drop for ChunkFiles {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            match std::fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(path = %path.display(), error = %e, "Failed to delete sorted chunk")
+                }
+            }
+        }
+    }
+}
+
+struct ChunkSpiller {
+    path_temp: PathBuf,
+    prefix: String,
+    num_threads: usize,
+    metadata: Vec<u8>,
+    metadata_pending: bool,
+    // NOTE:    dropped before `chunks`, so the file is closed before it is deleted
+    open_chunk: Option<BBGZWriter>,
+    last_id: Option<Vec<u8>>,
+    chunks: ChunkFiles,
+    histogram: BTreeMap<Vec<u8>, u64>,
+}
+
+impl ChunkSpiller {
+    /// Sort `chunk` and write it as a BBGZ TIRP with one block run per cell. A chunk whose
+    /// first id does not sort before the last id written is appended to the open chunk file
+    fn spill(&mut self, mut chunk: SortChunk) -> Result<()> {
+        chunk.sort();
+        let in_order = match (&self.last_id, chunk.entries.first()) {
+            (Some(last_id), Some(&first)) => last_id.as_slice() <= chunk.id(first),
+            _ => true,
+        };
+        if !in_order {
+            self.finish_chunk()?;
+        }
+        if self.open_chunk.is_none() {
+            let path_chunk =
+                self.path_temp
+                    .join(format!("{}_{}.tirp.bbgz", self.prefix, self.chunks.0.len()));
+            debug!(path = %path_chunk.display(), "Starting sorted chunk");
+            let file = File::create(&path_chunk)
+                .with_context(|| format!("failed to create chunk {}", path_chunk.display()))?;
+            self.chunks.0.push(path_chunk);
+            self.open_chunk = Some(
+                BBGZWriter::builder()
+                    .countof_threads(BoundedU64::new_saturating(self.num_threads as u64))
+                    .with_writer(file)
+                    .build(),
+            );
+            self.metadata_pending = true;
+        }
+        let Some(bbgzwriter) = self.open_chunk.as_mut() else {
+            unreachable!("a chunk file is open");
+        };
+        debug!(
+            records = chunk.entries.len(),
+            "Writing sorted records to chunk"
+        );
+
+        let mut blockwriter_opt: Option<BBGZWriteBlock<'_>> = None;
+        let mut current_id: Option<&[u8]> = None;
+
+        for &entry in &chunk.entries {
+            let id = chunk.id(entry);
+            if current_id != Some(id) {
+                if let Some(mut blockwriter) = blockwriter_opt.take() {
+                    blockwriter.flush()?;
+                }
+                let mut bbgzheader = BBGZHeader::new();
+                unsafe {
+                    bbgzheader.add_extra_unchecked(b"ID", id.to_vec());
+                }
+                // every chunk carries the metadata, as a single chunk is published as-is
+                if std::mem::take(&mut self.metadata_pending) {
+                    bbgzheader
+                        .add_extra(&METADATA_SUBFIELD, self.metadata.clone())
+                        .map_err(|_| anyhow::anyhow!("duplicate BBGZ metadata subfield"))?;
+                }
+                blockwriter_opt = Some(bbgzwriter.begin(bbgzheader));
+                current_id = Some(id);
+            }
+            let Some(blockwriter) = blockwriter_opt.as_mut() else {
+                unreachable!("a block is open for the current cell");
+            };
+            let (start, _, end) = entry;
+            // Reserve space for the entire record to prevent splitting across blocks
+            blockwriter.reserve(end - start);
+            blockwriter.write_all(&chunk.data[start..end])?;
+            *self.histogram.entry(id.to_vec()).or_insert(0) += 1;
+        }
+
+        if let Some(mut blockwriter) = blockwriter_opt.take() {
+            blockwriter.flush()?;
+        }
+        if let Some(&last) = chunk.entries.last() {
+            self.last_id = Some(chunk.id(last).to_vec());
+        }
+        Ok(())
+    }
+
+    /// Finish the open chunk file, so the next spill starts a new one
+    fn finish_chunk(&mut self) -> Result<()> {
+        if let Some(bbgzwriter) = self.open_chunk.take() {
+            bbgzwriter
+                .finish_async()
+                .join()
+                .map_err(|_| anyhow::anyhow!("BBGZ writer panicked while finishing"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bascet_runtime::report::{RunReport, RunResources};
+
+    #[test]
+    fn reads_mapping_and_rejects_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("map.tsv");
+        let mut file = File::create(&path).unwrap();
+        writeln!(file, "# old\tnew\nA1\tS1_A1\nB2\tS1_A1\r\nA1\tS1_A1").unwrap();
+        drop(file);
+        let mapping = read_mapping(&path).unwrap();
+        assert_eq!(mapping.len(), 2);
+        assert_eq!(mapping[b"B2".as_slice()], b"S1_A1");
+
+        std::fs::write(&path, "A1\tX\nA1\tY\n").unwrap();
+        assert!(read_mapping(&path).is_err());
+        std::fs::write(&path, "A1\tX\tZ\n").unwrap();
+        assert!(read_mapping(&path).is_err());
+    }
+
+    #[test]
+    fn sorts_chunk_stably_by_new_id() {
+        let mut chunk = SortChunk::default();
+        chunk.push(b"C", [b"AA", b"CC", b"II", b"II", b"u1"]);
+        chunk.push(b"A", [b"GG", b"TT", b"II", b"II", b"u2"]);
+        chunk.push(b"C", [b"TT", b"GG", b"II", b"II", b"u3"]);
+        chunk.sort();
+        let lines: Vec<&[u8]> = chunk
+            .entries
+            .iter()
+            .map(|&(start, _, end)| &chunk.data[start..end])
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                b"A\t1\t1\tGG\tTT\tII\tII\tu2\n".as_slice(),
+                b"C\t1\t1\tAA\tCC\tII\tII\tu1\n".as_slice(),
+                b"C\t1\t1\tTT\tGG\tII\tII\tu3\n".as_slice(),
+            ]
+        );
+    }
+
+    fn spiller(path_temp: &Path) -> ChunkSpiller {
+        ChunkSpiller {
+            path_temp: path_temp.to_path_buf(),
+            prefix: "relabel-test".to_string(),
+            num_threads: 1,
+            metadata: b"relabel_map\tmap.tsv\n".to_vec(),
+            metadata_pending: false,
+            open_chunk: None,
+            last_id: None,
+            chunks: ChunkFiles(Vec::new()),
+            histogram: BTreeMap::new(),
+        }
+    }
+
+    fn chunk(ids: &[&[u8]]) -> SortChunk {
+        let mut chunk = SortChunk::default();
+        for id in ids {
+            chunk.push(id, [b"AC", b"GT", b"II", b"II", b""]);
+        }
+        chunk
+    }
+
+    #[test]
+    fn appends_sorted_spills_to_one_chunk_and_deletes_chunks_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut spiller = spiller(dir.path());
+        spiller.spill(chunk(&[b"A", b"B"])).unwrap();
+        spiller.spill(chunk(&[b"B", b"C"])).unwrap();
+        assert_eq!(spiller.chunks.0.len(), 1);
+
+        spiller.spill(chunk(&[b"D", b"A"])).unwrap();
+        spiller.finish_chunk().unwrap();
+        assert_eq!(spiller.chunks.0.len(), 2);
+        assert_eq!(spiller.histogram[b"B".as_slice()], 2);
+        let chunks = spiller.chunks.0.clone();
+        assert!(chunks.iter().all(|path| path.exists()));
+
+        drop(spiller);
+        assert!(chunks.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn reports_the_published_output_and_histogram() {
+        let dir = tempfile::tempdir().unwrap();
+        let path_in = dir.path().join("in.tirp.bbgz");
+        {
+            let mut spiller = spiller(dir.path());
+            spiller.spill(chunk(&[b"A1", b"B2"])).unwrap();
+            spiller.finish_chunk().unwrap();
+            std::fs::copy(&spiller.chunks.0[0], &path_in).unwrap();
+        }
+        let path_map = dir.path().join("map.tsv");
+        std::fs::write(&path_map, "A1\tS1_A1\nB2\tS1_B2\n").unwrap();
+
+        // NOTE:    the records fit in one chunk, which is published as the output directly
+        let path_out = dir.path().join("out.tirp.bbgz");
+        let mut cmd = RelabelCellsCMD {
+            path_in,
+            path_out: path_out.clone(),
+            path_map,
+            drop_unmapped: false,
+            path_temp: None,
+            memory: DEFAULT_MEMORY,
+            num_threads: Some(1),
+            sizeof_stream_buffer: ByteSize::mib(16),
+            sizeof_stream_arena: DEFAULT_SIZEOF_ARENA,
+        };
+        cmd.try_execute().unwrap();
+
+        let outputs: Vec<_> = RunReport::summary(true, None, RunResources::default())
+            .outputs
+            .into_iter()
+            .filter(|path| path.starts_with(dir.path()))
+            .collect();
+        assert_eq!(
+            outputs,
+            [path_out.clone(), get_histogram_path_for_tirp(&path_out)]
+        );
+    }
+}
@@ -21,7 +21,7 @@ impl PipeSamAddTagsCMD {
                 writeln!(writer, "{}", line).unwrap();
             } else {
                 //This is a read that need to be mangled
-                let (cell_id, umi) = crate::fileformat::bam::readname_to_cell_umi(line.as_bytes());
+                let (cell_id, umi) = crate::fileformat::bam::readname_to_cell_umi(line.as_bytes())?;
 
                 writer.write_all(line.as_bytes())?;
                 writer.write_all(b"\tCB:Z:")?;
@@ -242,6 +242,77 @@ impl Record {
     pub fn aux(&self) -> &[u8] {
         &self.data[self.aux_off()..]
     }
+
+    /// Value of a string (`Z`) aux tag, without its NUL. `None` if the tag
+    /// is absent, has another type, or the aux section is malformed.
+    pub fn aux_str(&self, tag: [u8; 2]) -> Option<&[u8]> {
+        let mut aux = self.aux();
+        while aux.len() >= 3 {
+            let (key, ty, rest) = ([aux[0], aux[1]], aux[2], &aux[3..]);
+            let len = match ty {
+                b'A' | b'c' | b'C' => 1,
+                b's' | b'S' => 2,
+                b'i' | b'I' | b'f' => 4,
+                b'Z' | b'H' => rest.iter().position(|&b| b == 0)? + 1,
+                b'B' => {
+                    let width = match *rest.first()? {
+                        b'c' | b'C' => 1,
+                        b's' | b'S' => 2,
+                        b'i' | b'I' | b'f' => 4,
+                        _ => return None,
+                    };
+                    let count = u32::from_le_bytes(rest.get(1..5)?.try_into().ok()?) as usize;
+                    5 + count * width
+                }
+                _ => return None,
+            };
+            if key == tag {
+                return (ty == b'Z').then(|| &rest[..len - 1]);
+            }
+            aux = rest.get(len..)?;
+        }
+        None
+    }
+}
+
+/// Quality written when a record has no QUAL (0xff), as `samtools fastq -v` defaults to 1.
+pub const MISSING_QUAL: u8 = b'!' + 1;
+
+/// Decode 4-bit SEQ to ASCII; `reverse` reverse-complements it back to sequenced orientation.
+pub fn decode_seq(seq_raw: &[u8], l_seq: usize, reverse: bool) -> Vec<u8> {
+    const BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+    let mut seq: Vec<u8> = (0..l_seq)
+        .map(|i| {
+            let byte = seq_raw[i / 2];
+            BASES[if i % 2 == 0 { byte >> 4 } else { byte & 0xf } as usize]
+        })
+        .collect();
+    if reverse {
+        seq.reverse();
+        for base in seq.iter_mut() {
+            *base = match *base {
+                b'A' => b'T',
+                b'C' => b'G',
+                b'G' => b'C',
+                b'T' => b'A',
+                other => other,
+            };
+        }
+    }
+    seq
+}
+
+/// Phred+33 encode QUAL, reversed along with `decode_seq` when `reverse` is set.
+pub fn decode_qual(qual: &[u8], reverse: bool) -> Vec<u8> {
+    let mut out: Vec<u8> = if qual.first() == Some(&0xff) {
+        vec![MISSING_QUAL; qual.len()]
+    } else {
+        qual.iter().map(|q| q.saturating_add(33)).collect()
+    };
+    if reverse {
+        out.reverse();
+    }
+    out
 }
 
 fn read_i32<R: Read>(r: &mut R) -> io::Result<i32> {
@@ -254,6 +325,42 @@ fn write_i32<W: Write>(w: &mut W, v: i32) -> io::Result<()> {
     w.write_all(&v.to_le_bytes())
 }
 
+#[cfg(test)]
+impl Record {
+    /// Unmapped record with the given sequence, Phred+33 qualities and `Z`-typed aux tags.
+    /// Shared fixture of the BAM reading tests.
+    pub(crate) fn test_unmapped(
+        name: &[u8],
+        flag: u16,
+        seq: &[u8],
+        qual: &[u8],
+        tags: &[(&[u8; 2], &[u8])],
+    ) -> Record {
+        let mut data = vec![0u8; 32];
+        data[0..4].copy_from_slice(&(-1i32).to_le_bytes());
+        data[4..8].copy_from_slice(&(-1i32).to_le_bytes());
+        data[8] = (name.len() + 1) as u8;
+        data[14..16].copy_from_slice(&flag.to_le_bytes());
+        data[16..20].copy_from_slice(&(seq.len() as i32).to_le_bytes());
+        data[20..24].copy_from_slice(&(-1i32).to_le_bytes());
+        data[24..28].copy_from_slice(&(-1i32).to_le_bytes());
+        data.extend_from_slice(name);
+        data.push(0);
+        let code = |b: u8| b"=ACMGRSVTWYHKDBN".iter().position(|&c| c == b).unwrap() as u8;
+        for pair in seq.chunks(2) {
+            data.push(code(pair[0]) << 4 | pair.get(1).map_or(0, |&b| code(b)));
+        }
+        data.extend(qual.iter().map(|q| q - 33));
+        for (tag, value) in tags {
+            data.extend_from_slice(*tag);
+            data.push(b'Z');
+            data.extend_from_slice(value);
+            data.push(0);
+        }
+        Record { data }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -323,6 +430,21 @@ mod tests {
         assert_eq!(r7.pos(), -1);
     }
 
+    #[test]
+    fn aux_str_skips_other_tag_types() {
+        let mut data = vec![0u8; 32 + 1];
+        data[8] = 1; // l_read_name = 1 (just the NUL)
+        data.extend_from_slice(b"NMC\x02");
+        data.extend_from_slice(b"XBBC\x02\x00\x00\x00\x01\x02");
+        data.extend_from_slice(b"CBZAACGT-1\x00");
+        data.extend_from_slice(b"UBZTTGG\x00");
+        let r = Record { data };
+        assert_eq!(r.aux_str(*b"CB"), Some(&b"AACGT-1"[..]));
+        assert_eq!(r.aux_str(*b"UB"), Some(&b"TTGG"[..]));
+        assert_eq!(r.aux_str(*b"NM"), None);
+        assert_eq!(r.aux_str(*b"CR"), None);
+    }
+
     #[test]
     fn header_and_records_roundtrip_to_identical_bytes() {
         let bytes = read_test_bam_decompressed();
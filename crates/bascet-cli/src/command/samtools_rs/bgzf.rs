@@ -140,6 +140,153 @@ impl<R: Read> Read for Reader<R> {
     }
 }
 
+/// Single-threaded BGZF reader that reports virtual offsets, for indexing
+/// files we didn't write. Unlike `Reader` it accepts any gzip member with a
+/// BC subfield — extra subfields before/after BC and the optional FNAME /
+/// FCOMMENT / FHCRC fields are skipped — so BGZF produced by other writers
+/// (including Bascet's own BBGZ output) can be indexed.
+pub struct VirtualReader<R: Read> {
+    inner: BufReader<R>,
+    block: Vec<u8>,
+    pos: usize,
+    /// Compressed offset of the current block and of the one after it.
+    block_coffset: u64,
+    next_coffset: u64,
+    exhausted: bool,
+}
+
+impl<R: Read> VirtualReader<R> {
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            block: Vec::with_capacity(BLOCK_SIZE),
+            pos: 0,
+            block_coffset: 0,
+            next_coffset: 0,
+            exhausted: false,
+        }
+    }
+
+    /// Virtual offset `(coffset << 16) | inblock` of the next byte `read`
+    /// would return. At a block boundary this points at the next non-empty
+    /// block, matching what htslib records as a chunk start.
+    pub fn virtual_position(&mut self) -> io::Result<u64> {
+        io::BufRead::fill_buf(self)?;
+        Ok((self.block_coffset << 16) | self.pos as u64)
+    }
+
+    fn fill_block(&mut self) -> io::Result<()> {
+        self.block.clear();
+        self.pos = 0;
+        self.block_coffset = self.next_coffset;
+
+        let mut fixed = [0u8; 12];
+        match self.inner.read_exact(&mut fixed[..1]) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                self.exhausted = true;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+        self.inner.read_exact(&mut fixed[1..])?;
+        let flg = fixed[3];
+        if fixed[0] != 0x1f || fixed[1] != 0x8b || fixed[2] != 0x08 || flg & 0x04 == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a BGZF block (bad gzip magic / flags)",
+            ));
+        }
+        let xlen = u16::from_le_bytes([fixed[10], fixed[11]]) as usize;
+        let mut extra = vec![0u8; xlen];
+        self.inner.read_exact(&mut extra)?;
+        let mut bsize = None;
+        let mut sub = extra.as_slice();
+        while sub.len() >= 4 {
+            let slen = u16::from_le_bytes([sub[2], sub[3]]) as usize;
+            let body = sub.get(4..4 + slen).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "truncated gzip extra subfield")
+            })?;
+            if sub[0] == b'B' && sub[1] == b'C' && slen == 2 {
+                bsize = Some(u16::from_le_bytes([body[0], body[1]]) as usize);
+            }
+            sub = &sub[4 + slen..];
+        }
+        let block_size = bsize.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "missing BGZF BC extra subfield")
+        })? + 1;
+
+        let mut header_len = 12 + xlen;
+        for flag in [0x08u8, 0x10] {
+            // FNAME / FCOMMENT: NUL-terminated.
+            if flg & flag != 0 {
+                let mut field = Vec::new();
+                io::BufRead::read_until(&mut self.inner, 0, &mut field)?;
+                header_len += field.len();
+            }
+        }
+        if flg & 0x02 != 0 {
+            let mut hcrc = [0u8; 2];
+            self.inner.read_exact(&mut hcrc)?;
+            header_len += 2;
+        }
+        if block_size < header_len + TRAILER_LEN || block_size > MAX_BLOCK_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "BGZF block size out of range",
+            ));
+        }
+
+        let mut cdata = vec![0u8; block_size - header_len - TRAILER_LEN];
+        self.inner.read_exact(&mut cdata)?;
+        let mut trailer = [0u8; TRAILER_LEN];
+        self.inner.read_exact(&mut trailer)?;
+        self.next_coffset += block_size as u64;
+
+        let expected_isize = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+        if expected_isize == 0 {
+            return Ok(());
+        }
+        if expected_isize as usize > MAX_BLOCK_SIZE {
+            // The in-block half of a virtual offset is 16 bits.
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "BGZF block inflates past 64 KiB and cannot be addressed by virtual offsets",
+            ));
+        }
+        let raw = RawBlock {
+            cdata,
+            expected_crc: u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]),
+            expected_isize,
+        };
+        self.block = inflate_block(&raw)?;
+        Ok(())
+    }
+}
+
+impl<R: Read> io::BufRead for VirtualReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos >= self.block.len() && !self.exhausted {
+            self.fill_block()?;
+        }
+        Ok(&self.block[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.block.len());
+    }
+}
+
+impl<R: Read> Read for VirtualReader<R> {
+    fn read(&mut self, dst: &mut [u8]) -> io::Result<usize> {
+        let available = io::BufRead::fill_buf(self)?;
+        let n = available.len().min(dst.len());
+        dst[..n].copy_from_slice(&available[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
 pub struct Writer<W: Write> {
     inner: W,
     buf: Vec<u8>,
@@ -791,6 +938,26 @@ mod tests {
         assert_eq!(decompressed, p);
     }
 
+    #[test]
+    fn virtual_reader_tracks_block_offsets() {
+        let p = vec![0x5a; BLOCK_SIZE + 10];
+        let mut w = Writer::new(Vec::new(), 6);
+        w.write_all(&p).unwrap();
+        let (compressed, offsets) = w.finish_with_offsets().unwrap();
+
+        let mut r = VirtualReader::new(Cursor::new(compressed));
+        assert_eq!(r.virtual_position().unwrap(), 0);
+        let mut buf = vec![0u8; BLOCK_SIZE - 1];
+        r.read_exact(&mut buf).unwrap();
+        assert_eq!(r.virtual_position().unwrap(), (BLOCK_SIZE - 1) as u64);
+        r.read_exact(&mut buf[..1]).unwrap();
+        // Exhausting a block moves the position to the start of the next one.
+        assert_eq!(r.virtual_position().unwrap(), offsets[1] << 16);
+        let mut rest = Vec::new();
+        r.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest.len(), 10);
+    }
+
     #[test]
     fn read_test_bam_then_reencode_roundtrip() {
         // Read the canned test BAM with our Reader, get uncompressed bytes A.
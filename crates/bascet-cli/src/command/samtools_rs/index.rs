@@ -2,7 +2,8 @@
 // Spec: https://samtools.github.io/hts-specs/SAMv1.pdf §5
 //
 // We build the index incrementally as records are written:
-//   * For each record, look up its bin (already in the BAM record) and
+//   * For each record, compute its bin under the configured binning scheme
+//     (BAI's fixed min_shift=14/depth=5, or a CSI min_shift/depth) and
 //     extend the bin's chunk list with [uoffset_start, uoffset_end). Adjacent
 //     ranges from the same bin are merged on the fly to keep the index
 //     compact (matches what htslib does).
@@ -14,22 +15,36 @@
 // Virtual offsets are stored as raw uncompressed offsets during the build.
 // Once the BGZF writer's per-block compressed offsets are known (after
 // finish), we transform every uoffset → voffset = (coffset << 16) | inblock.
+// When indexing an existing file the caller already knows the virtual
+// offsets; those are stored as-is and written with `write_virtual`.
 
 use super::bam::{Header, Record};
 use super::bgzf::BLOCK_SIZE;
+use super::sort::IndexFormat;
 use std::collections::BTreeMap;
 use std::io::{self, Write};
 
-/// Bin number reserved for per-reference metadata (n_mapped/n_unmapped +
-/// the virtual-offset range of mapped reads). Defined by the BAM spec.
-const BIN_META: u32 = 37450;
-
 /// Width of one linear-index window (samtools uses 16 KiB).
 const LINEAR_WINDOW_SHIFT: i32 = 14;
 
+/// BAI's fixed binning scheme. CSI with these parameters numbers bins
+/// identically.
+pub const BAI_MIN_SHIFT: u8 = 14;
+pub const BAI_DEPTH: u8 = 5;
+
+/// Longest reference BAI can address: `1 << (min_shift + 3 * depth)`.
+pub const BAI_MAX_REF_LEN: u64 = 1 << 29;
+
+/// Default CSI min_shift, same as `samtools index -c`.
+pub const CSI_DEFAULT_MIN_SHIFT: u8 = 14;
+
 pub struct BaiBuilder {
     refs: Vec<RefIndex>,
     n_no_coor: u64,
+    format: IndexFormat,
+    min_shift: u8,
+    depth: u8,
+    max_ref_len: u64,
 }
 
 #[derive(Default)]
@@ -77,10 +92,27 @@ struct Chunk {
 
 impl BaiBuilder {
     pub fn new(header: &Header) -> Self {
+        Self::with_format(header, IndexFormat::Bai)
+    }
+
+    /// Builder for `format`. The bin scheme is fixed at construction because
+    /// bin ids are assigned as records are added.
+    pub fn with_format(header: &Header, format: IndexFormat) -> Self {
         let refs = (0..header.refs.len())
             .map(|_| RefIndex::default())
             .collect();
-        Self { refs, n_no_coor: 0 }
+        let (min_shift, depth) = match format {
+            IndexFormat::Bai => (BAI_MIN_SHIFT, BAI_DEPTH),
+            IndexFormat::Csi { min_shift, depth } => (min_shift, depth),
+        };
+        Self {
+            refs,
+            n_no_coor: 0,
+            format,
+            min_shift,
+            depth,
+            max_ref_len: max_ref_len(header),
+        }
     }
 
     /// Add a record to the index. `uoffset_start`/`uoffset_end` are the
@@ -108,7 +140,11 @@ impl BaiBuilder {
             r.mapped_last_end = uoffset_end;
         }
 
-        let bin = record.bin() as u32;
+        // Recompute the bin rather than trusting `record.bin()`: htslib does
+        // the same, and the stored bin is only valid for BAI's scheme.
+        let pos = record.pos().max(0);
+        let end_pos = pos.saturating_add(record_reference_span(record).max(1) as i32);
+        let bin = reg2bin(pos as i64, end_pos as i64, self.min_shift, self.depth);
         let entry = r.bins.entry(bin).or_insert_with(BinEntry::new);
         push_chunk(&mut entry.chunks, uoffset_start, uoffset_end);
         if uoffset_start < entry.loffset {
@@ -116,8 +152,6 @@ impl BaiBuilder {
         }
 
         // Update linear index over windows overlapped by the record.
-        let pos = record.pos().max(0);
-        let end_pos = pos + record_reference_span(record).max(1) as i32;
         let first_w = (pos >> LINEAR_WINDOW_SHIFT) as usize;
         let last_w = ((end_pos - 1) >> LINEAR_WINDOW_SHIFT) as usize;
         if r.linear.len() <= last_w {
@@ -133,6 +167,45 @@ impl BaiBuilder {
     /// Serialize the BAI to `out`. `block_offsets[i]` must give the
     /// compressed file offset of the i-th BGZF block of the indexed BAM.
     pub fn write<W: Write>(&self, out: &mut W, block_offsets: &[u64]) -> io::Result<()> {
+        self.write_bai_with(out, |u| uoffset_to_voffset(u, block_offsets))
+    }
+
+    /// Serialize the CSI to `out` using the builder's min_shift/depth.
+    pub fn write_csi<W: Write>(&self, out: &mut W, block_offsets: &[u64]) -> io::Result<()> {
+        self.write_csi_with(out, |u| uoffset_to_voffset(u, block_offsets))
+    }
+
+    /// Serialize in the builder's format when records were added with
+    /// virtual offsets rather than uncompressed ones (indexing an existing
+    /// BGZF file whose block layout we didn't produce).
+    pub fn write_virtual<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self.format {
+            IndexFormat::Bai => self.write_bai_with(out, |v| v),
+            IndexFormat::Csi { .. } => self.write_csi_with(out, |v| v),
+        }
+    }
+
+    fn write_bai_with<W: Write>(
+        &self,
+        out: &mut W,
+        to_voffset: impl Fn(u64) -> u64,
+    ) -> io::Result<()> {
+        if (self.min_shift, self.depth) != (BAI_MIN_SHIFT, BAI_DEPTH) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "BAI requires min_shift=14/depth=5; use CSI for other bin schemes",
+            ));
+        }
+        if self.max_ref_len > BAI_MAX_REF_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "reference of {} bp exceeds the BAI limit of {} bp; use CSI",
+                    self.max_ref_len, BAI_MAX_REF_LEN
+                ),
+            ));
+        }
+        let meta_bin = meta_bin(self.depth);
         out.write_all(b"BAI\x01")?;
         write_i32_le(out, self.refs.len() as i32)?;
         for r in &self.refs {
@@ -144,19 +217,19 @@ impl BaiBuilder {
                 write_u32_le(out, bin_id)?;
                 write_i32_le(out, entry.chunks.len() as i32)?;
                 for c in &entry.chunks {
-                    let beg_voff = uoffset_to_voffset(c.beg, block_offsets);
-                    let end_voff = uoffset_to_voffset(c.end, block_offsets);
+                    let beg_voff = to_voffset(c.beg);
+                    let end_voff = to_voffset(c.end);
                     write_u64_le(out, beg_voff)?;
                     write_u64_le(out, end_voff)?;
                 }
             }
             if has_meta {
-                write_u32_le(out, BIN_META)?;
+                write_u32_le(out, meta_bin)?;
                 write_i32_le(out, 2)?; // metadata bin always has 2 chunks
                 let beg = r.mapped_first.unwrap_or(0);
                 let end = r.mapped_last_end;
-                let beg_v = uoffset_to_voffset(beg, block_offsets);
-                let end_v = uoffset_to_voffset(end, block_offsets);
+                let beg_v = to_voffset(beg);
+                let end_v = to_voffset(end);
                 write_u64_le(out, beg_v)?;
                 write_u64_le(out, end_v)?;
                 // Second "chunk" is repurposed: (n_mapped, n_unmapped).
@@ -172,7 +245,7 @@ impl BaiBuilder {
             for &raw in &r.linear {
                 let u = if raw == u64::MAX { last } else { raw };
                 last = u;
-                let v = uoffset_to_voffset(u, block_offsets);
+                let v = to_voffset(u);
                 write_u64_le(out, v)?;
             }
         }
@@ -183,18 +256,25 @@ impl BaiBuilder {
         Ok(())
     }
 
-    /// Serialize the CSI to `out`. Uses the BAI-equivalent bin scheme
-    /// (`min_shift = 14`, `depth = 5`) so the bin numbering is identical
-    /// — this means the same data we built for BAI can be re-emitted as
-    /// CSI without rebuilding. (Bigger contigs would need a deeper
-    /// bin tree, which we can configure later if needed.)
-    pub fn write_csi<W: Write>(&self, out: &mut W, block_offsets: &[u64]) -> io::Result<()> {
-        let min_shift: i32 = LINEAR_WINDOW_SHIFT;
-        let depth: i32 = 5;
-
+    fn write_csi_with<W: Write>(
+        &self,
+        out: &mut W,
+        to_voffset: impl Fn(u64) -> u64,
+    ) -> io::Result<()> {
+        let max_len = (1u64 << self.min_shift) << (3 * self.depth as u32);
+        if self.max_ref_len > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "reference of {} bp exceeds the CSI limit of {} bp for min_shift={} depth={}",
+                    self.max_ref_len, max_len, self.min_shift, self.depth
+                ),
+            ));
+        }
+        let meta_bin = meta_bin(self.depth);
         out.write_all(b"CSI\x01")?;
-        write_i32_le(out, min_shift)?;
-        write_i32_le(out, depth)?;
+        write_i32_le(out, self.min_shift as i32)?;
+        write_i32_le(out, self.depth as i32)?;
         write_i32_le(out, 0)?; // l_aux = 0; no aux data
         write_i32_le(out, self.refs.len() as i32)?;
         for r in &self.refs {
@@ -203,26 +283,26 @@ impl BaiBuilder {
             write_i32_le(out, n_bin as i32)?;
             for (&bin_id, entry) in &r.bins {
                 write_u32_le(out, bin_id)?;
-                let loffset_v = uoffset_to_voffset(entry.loffset, block_offsets);
+                let loffset_v = to_voffset(entry.loffset);
                 write_u64_le(out, loffset_v)?;
                 write_i32_le(out, entry.chunks.len() as i32)?;
                 for c in &entry.chunks {
-                    let beg_voff = uoffset_to_voffset(c.beg, block_offsets);
-                    let end_voff = uoffset_to_voffset(c.end, block_offsets);
+                    let beg_voff = to_voffset(c.beg);
+                    let end_voff = to_voffset(c.end);
                     write_u64_le(out, beg_voff)?;
                     write_u64_le(out, end_voff)?;
                 }
             }
             if has_meta {
-                write_u32_le(out, BIN_META)?;
+                write_u32_le(out, meta_bin)?;
                 // Metadata bin's loffset is conventionally 0 — htslib does
                 // the same. It isn't used by region queries.
                 write_u64_le(out, 0)?;
                 write_i32_le(out, 2)?;
                 let beg = r.mapped_first.unwrap_or(0);
                 let end = r.mapped_last_end;
-                let beg_v = uoffset_to_voffset(beg, block_offsets);
-                let end_v = uoffset_to_voffset(end, block_offsets);
+                let beg_v = to_voffset(beg);
+                let end_v = to_voffset(end);
                 write_u64_le(out, beg_v)?;
                 write_u64_le(out, end_v)?;
                 write_u64_le(out, r.n_mapped)?;
@@ -249,6 +329,56 @@ fn push_chunk(chunks: &mut Vec<Chunk>, start: u64, end: u64) {
     chunks.push(Chunk { beg: start, end });
 }
 
+/// Longest reference in `header`, in bp.
+pub fn max_ref_len(header: &Header) -> u64 {
+    header
+        .refs
+        .iter()
+        .map(|r| r.length.max(0) as u64)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Smallest CSI depth whose bin tree covers `max_ref_len` at `min_shift`.
+/// Mirrors the level computation in htslib's `bam_index`, including its
+/// 256 bp slack at the end of the longest reference.
+pub fn csi_depth_for(max_ref_len: u64, min_shift: u8) -> u8 {
+    let max_len = max_ref_len + 256;
+    let mut depth = 0u8;
+    let mut span = 1u64 << min_shift;
+    while max_len > span {
+        depth += 1;
+        span <<= 3;
+    }
+    depth
+}
+
+/// Bin of the 0-based half-open interval `[beg, end)` under a
+/// min_shift/depth scheme. Port of htslib's `hts_reg2bin`; with
+/// `min_shift = 14, depth = 5` it matches the BAM spec's `reg2bin`.
+pub fn reg2bin(beg: i64, end: i64, min_shift: u8, depth: u8) -> u32 {
+    let end = end - 1;
+    let mut s = min_shift as u32;
+    let mut t = ((1i64 << (depth as u32 * 3)) - 1) / 7;
+    let mut l = depth as i32;
+    while l > 0 {
+        if beg >> s == end >> s {
+            return (t + (beg >> s)) as u32;
+        }
+        l -= 1;
+        s += 3;
+        t -= 1 << (l * 3);
+    }
+    0
+}
+
+/// Pseudo-bin holding per-reference metadata (n_mapped/n_unmapped + the
+/// virtual-offset range of mapped reads): one past the last real bin.
+/// 37450 for BAI's depth of 5.
+fn meta_bin(depth: u8) -> u32 {
+    (((1u64 << (3 * depth as u32 + 3)) - 1) / 7 + 1) as u32
+}
+
 fn uoffset_to_voffset(uoffset: u64, block_offsets: &[u64]) -> u64 {
     let block_idx = (uoffset / BLOCK_SIZE as u64) as usize;
     let offset_in_block = uoffset % BLOCK_SIZE as u64;
@@ -342,4 +472,46 @@ mod tests {
         // Total = 4 (magic) + 4 (n_ref) + 2 * 8 = 24.
         assert_eq!(out.len(), 24);
     }
+
+    #[test]
+    fn reg2bin_matches_bai_scheme() {
+        assert_eq!(meta_bin(BAI_DEPTH), 37450);
+        // Within one 16 kbp window → leaf bin 4681 + window.
+        assert_eq!(reg2bin(0, 1, 14, 5), 4681);
+        assert_eq!(reg2bin(1 << 14, (1 << 14) + 100, 14, 5), 4682);
+        // Crossing a 16 kbp boundary moves up one level.
+        assert_eq!(reg2bin((1 << 14) - 1, (1 << 14) + 1, 14, 5), 585);
+        // Spanning the whole addressable range → root bin.
+        assert_eq!(reg2bin(0, 1 << 29, 14, 5), 0);
+    }
+
+    #[test]
+    fn csi_depth_covers_long_references() {
+        assert_eq!(csi_depth_for(1_000_000, 14), 2);
+        // Just over the BAI limit needs one extra level.
+        assert_eq!(csi_depth_for(BAI_MAX_REF_LEN + 1, 14), 6);
+        assert_eq!(csi_depth_for(i32::MAX as u64, 14), 6);
+        let depth = csi_depth_for(i32::MAX as u64, 14);
+        assert!((1u64 << 14) << (3 * depth as u32) > i32::MAX as u64);
+    }
+
+    #[test]
+    fn bai_rejects_long_reference() {
+        let mut h = header_with(1);
+        h.refs[0].length = (BAI_MAX_REF_LEN + 1) as i32;
+        let mut out = Vec::new();
+        assert!(BaiBuilder::new(&h).write(&mut out, &[0]).is_err());
+
+        let csi = BaiBuilder::with_format(
+            &h,
+            IndexFormat::Csi {
+                min_shift: 14,
+                depth: 6,
+            },
+        );
+        out.clear();
+        csi.write_csi(&mut out, &[0]).unwrap();
+        assert_eq!(&out[..4], b"CSI\x01");
+        assert_eq!(i32::from_le_bytes(out[8..12].try_into().unwrap()), 6);
+    }
 }
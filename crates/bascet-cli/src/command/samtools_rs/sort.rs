@@ -32,6 +32,10 @@ fn fmt_count(n: u64) -> String {
 #[derive(Clone, Copy)]
 pub enum Order {
     Coordinate,
+    /// By the value of a string aux tag (e.g. `CB`), then read name, then
+    /// READ1 before READ2 — `samtools sort -t TAG -n`-like. Groups reads by
+    /// cell with mates adjacent. Records without the tag sort first.
+    TagName([u8; 2]),
 }
 
 #[derive(Clone, Copy)]
@@ -59,6 +63,22 @@ pub fn coord_cmp(a: &Record, b: &Record) -> Ordering {
     a_rev.cmp(&b_rev)
 }
 
+/// Comparator for `Order::TagName`. Read names compare bytewise, not with
+/// samtools' natural-number ordering; only grouping matters to callers.
+pub fn tag_name_cmp(tag: [u8; 2], a: &Record, b: &Record) -> Ordering {
+    a.aux_str(tag)
+        .cmp(&b.aux_str(tag))
+        .then_with(|| a.read_name().cmp(b.read_name()))
+        .then_with(|| (a.flag() & 0xc0).cmp(&(b.flag() & 0xc0)))
+}
+
+fn record_cmp(order: Order, a: &Record, b: &Record) -> Ordering {
+    match order {
+        Order::Coordinate => coord_cmp(a, b),
+        Order::TagName(tag) => tag_name_cmp(tag, a, b),
+    }
+}
+
 pub struct SortOptions<'a> {
     pub order: Order,
     pub reference_order: ReferenceOrder,
@@ -86,10 +106,12 @@ pub struct SortOptions<'a> {
     pub write_index: Option<(PathBuf, IndexFormat)>,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum IndexFormat {
+    /// Fixed min_shift=14/depth=5; references up to 512 Mbp.
     Bai,
-    Csi,
+    /// Bins of `1 << min_shift` bp at the leaves, `depth` levels above.
+    Csi { min_shift: u8, depth: u8 },
 }
 
 pub struct EncodedBamChunk {
@@ -151,6 +173,7 @@ fn sort_records(records: &mut [Record], order: Order) {
     // via the diff harness; replace with a ksort port if it bites.
     match order {
         Order::Coordinate => records.sort_by(coord_cmp),
+        Order::TagName(tag) => records.sort_by(|a, b| tag_name_cmp(tag, a, b)),
     }
 }
 
@@ -198,6 +221,42 @@ pub fn sort_streaming_parallel<R: Read + Send + 'static, W: Write + Send + 'stat
     Ok(())
 }
 
+/// Same external sort as `sort_streaming_parallel`, but sorted records are
+/// handed to `sink` in order instead of being written as a BAM. For
+/// importers that need the spill + merge machinery but emit another format.
+/// `opts.write_index`, `level` and the @PG/@HD edits are ignored.
+pub fn sort_streaming_parallel_into<R, F>(
+    input: R,
+    opts: &SortOptions<'_>,
+    mut sink: F,
+) -> io::Result<()>
+where
+    R: Read + Send + 'static,
+    F: FnMut(&Record) -> io::Result<()>,
+{
+    info!("BamSort: phase 1/2 — reading input and spilling sorted chunks");
+    let staged = collect_and_spill_parallel(input, opts)?;
+    match staged.chunks {
+        ChunkSource::InMemory(records) => {
+            for r in &records {
+                sink(r)?;
+            }
+            Ok(())
+        }
+        ChunkSource::Files(paths) => {
+            info!(
+                chunks = paths.len(),
+                "BamSort: phase 2/2 — k-way merge into caller"
+            );
+            let result = merge_chunks_with(&paths, opts.order, &mut sink);
+            for p in &paths {
+                let _ = std::fs::remove_file(p);
+            }
+            result
+        }
+    }
+}
+
 pub fn sort_encoded_record_chunks_parallel<W: Write + Send + 'static>(
     header_bytes: Vec<u8>,
     chunks: Vec<EncodedBamChunk>,
@@ -895,9 +954,15 @@ fn write_with_single_threaded_writer<W: Write>(
     let mut builder = opts
         .write_index
         .as_ref()
-        .map(|_| super::index::BaiBuilder::new(&out_header));
+        .map(|(_, fmt)| super::index::BaiBuilder::with_format(&out_header, *fmt));
     let header_uoffset = out_header.serialized_len() as u64;
-    drain_chunks(&mut bgz, chunks, builder.as_mut(), header_uoffset)?;
+    drain_chunks(
+        &mut bgz,
+        chunks,
+        opts.order,
+        builder.as_mut(),
+        header_uoffset,
+    )?;
     let (_inner, block_offsets) = bgz.finish_with_offsets()?;
     if let (Some(b), Some((path, fmt))) = (builder, opts.write_index.as_ref()) {
         write_index_file(path, *fmt, &b, &block_offsets)?;
@@ -916,9 +981,15 @@ fn write_with_parallel_writer<W: Write + Send + 'static>(
     let mut builder = opts
         .write_index
         .as_ref()
-        .map(|_| super::index::BaiBuilder::new(&out_header));
+        .map(|(_, fmt)| super::index::BaiBuilder::with_format(&out_header, *fmt));
     let header_uoffset = out_header.serialized_len() as u64;
-    drain_chunks(&mut bgz, chunks, builder.as_mut(), header_uoffset)?;
+    drain_chunks(
+        &mut bgz,
+        chunks,
+        opts.order,
+        builder.as_mut(),
+        header_uoffset,
+    )?;
     let block_offsets = bgz.finish_with_offsets()?;
     if let (Some(b), Some((path, fmt))) = (builder, opts.write_index.as_ref()) {
         write_index_file(path, *fmt, &b, &block_offsets)?;
@@ -935,7 +1006,7 @@ fn write_index_file(
     let mut f = BufWriter::new(File::create(path)?);
     match fmt {
         IndexFormat::Bai => builder.write(&mut f, block_offsets)?,
-        IndexFormat::Csi => builder.write_csi(&mut f, block_offsets)?,
+        IndexFormat::Csi { .. } => builder.write_csi(&mut f, block_offsets)?,
     }
     f.flush()
 }
@@ -943,6 +1014,7 @@ fn write_index_file(
 fn drain_chunks<W: Write>(
     out: &mut W,
     chunks: ChunkSource,
+    order: Order,
     mut builder: Option<&mut super::index::BaiBuilder>,
     starting_uoffset: u64,
 ) -> io::Result<u64> {
@@ -960,7 +1032,7 @@ fn drain_chunks<W: Write>(
             Ok(uoffset)
         }
         ChunkSource::Files(paths) => {
-            let result = merge_chunks_into(out, &paths, builder, uoffset);
+            let result = merge_chunks_into(out, &paths, order, builder, uoffset);
             for p in &paths {
                 let _ = std::fs::remove_file(p);
             }
@@ -1001,13 +1073,14 @@ fn spill_chunk(
 /// eliminated for a 9 GB BAM workload.
 struct ChunkReader {
     bgz: bgzf::ParallelReader,
+    order: Order,
     file_idx: usize,
     next_record_idx: u64,
     scratch: Vec<u8>,
 }
 
 impl ChunkReader {
-    fn open(path: &std::path::Path, file_idx: usize) -> io::Result<Self> {
+    fn open(path: &std::path::Path, order: Order, file_idx: usize) -> io::Result<Self> {
         let f = BufReader::new(File::open(path)?);
         // 1 inflate worker per chunk: with N chunks open during merge, we
         // get N decompression streams running concurrently up to the
@@ -1018,6 +1091,7 @@ impl ChunkReader {
         let _ = Header::read(&mut bgz)?;
         Ok(Self {
             bgz,
+            order,
             file_idx,
             next_record_idx: 0,
             scratch: Vec::new(),
@@ -1033,6 +1107,7 @@ impl ChunkReader {
                 self.next_record_idx += 1;
                 Ok(Some(HeapEntry {
                     record,
+                    order: self.order,
                     file_idx: self.file_idx,
                     record_idx: idx,
                 }))
@@ -1050,6 +1125,7 @@ impl ChunkReader {
 
 struct HeapEntry {
     record: Record,
+    order: Order,
     /// Matches samtools' `heap1_t.i` — input file index, used as primary
     /// tie-break after the sort key.
     file_idx: usize,
@@ -1060,7 +1136,7 @@ struct HeapEntry {
 
 impl HeapEntry {
     fn key_cmp(&self, other: &Self) -> Ordering {
-        match coord_cmp(&self.record, &other.record) {
+        match record_cmp(self.order, &self.record, &other.record) {
             Ordering::Equal => match self.file_idx.cmp(&other.file_idx) {
                 Ordering::Equal => self.record_idx.cmp(&other.record_idx),
                 ord => ord,
@@ -1094,9 +1170,29 @@ impl PartialOrd for HeapEntry {
 fn merge_chunks_into<W: Write>(
     out: &mut W,
     chunk_paths: &[PathBuf],
+    order: Order,
     mut builder: Option<&mut super::index::BaiBuilder>,
     starting_uoffset: u64,
 ) -> io::Result<u64> {
+    let mut uoffset = starting_uoffset;
+    merge_chunks_with(chunk_paths, order, |record| {
+        let start = uoffset;
+        record.write(out)?;
+        uoffset += 4 + record.data.len() as u64;
+        if let Some(b) = builder.as_deref_mut() {
+            b.add_record(record, start, uoffset);
+        }
+        Ok(())
+    })?;
+    Ok(uoffset)
+}
+
+/// K-way merge of pre-sorted chunk BAMs, handing each record to `sink` in
+/// order.
+fn merge_chunks_with<F>(chunk_paths: &[PathBuf], order: Order, mut sink: F) -> io::Result<()>
+where
+    F: FnMut(&Record) -> io::Result<()>,
+{
     info!(
         n_chunks = chunk_paths.len(),
         "BamSort: opening chunks for k-way merge"
@@ -1104,7 +1200,7 @@ fn merge_chunks_into<W: Write>(
     let mut readers: Vec<ChunkReader> = chunk_paths
         .iter()
         .enumerate()
-        .map(|(i, p)| ChunkReader::open(p, i))
+        .map(|(i, p)| ChunkReader::open(p, order, i))
         .collect::<io::Result<_>>()?;
 
     let mut heap: BinaryHeap<HeapEntry> = BinaryHeap::with_capacity(readers.len());
@@ -1121,17 +1217,11 @@ fn merge_chunks_into<W: Write>(
     let mut next_log_at: u64 = MERGE_LOG_EVERY;
     let merge_start = Instant::now();
 
-    let mut uoffset = starting_uoffset;
     while let Some(top) = heap.pop() {
         let HeapEntry {
             record, file_idx, ..
         } = top;
-        let start = uoffset;
-        record.write(out)?;
-        uoffset += 4 + record.data.len() as u64;
-        if let Some(b) = builder.as_deref_mut() {
-            b.add_record(&record, start, uoffset);
-        }
+        sink(&record)?;
         // Hand the Vec back to the chunk reader for the next read — mirrors
         // htslib's bam1_t.data realloc-and-keep pattern.
         readers[file_idx].return_buf(record.data);
@@ -1153,12 +1243,13 @@ fn merge_chunks_into<W: Write>(
         elapsed_secs = merge_start.elapsed().as_secs(),
         "BamSort: merge done"
     );
-    Ok(uoffset)
+    Ok(())
 }
 
 fn update_header(h: &mut Header, opts: &SortOptions<'_>) {
     let so = match opts.order {
         Order::Coordinate => "coordinate",
+        Order::TagName(_) => "unknown",
     };
     h.text = update_or_add_hd_so(&h.text, so);
     if !opts.no_pg {
@@ -1492,6 +1583,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn tag_name_cmp_groups_by_tag_with_mates_adjacent() {
+        fn rec(name: &[u8], flag: u16, cb: Option<&[u8]>) -> Record {
+            let tags: &[(&[u8; 2], &[u8])] = match cb {
+                Some(cb) => &[(b"CB", cb)],
+                None => &[],
+            };
+            Record::test_unmapped(name, flag, b"", b"", tags)
+        }
+
+        let mut v = vec![
+            rec(b"q2", 0x81, Some(b"BBB")),
+            rec(b"q1", 0x81, Some(b"AAA")),
+            rec(b"q9", 0x4, None),
+            rec(b"q2", 0x41, Some(b"BBB")),
+            rec(b"q1", 0x41, Some(b"AAA")),
+            rec(b"q0", 0x0, Some(b"BBB")),
+        ];
+        v.sort_by(|a, b| tag_name_cmp(*b"CB", a, b));
+        let order: Vec<(Vec<u8>, u16)> = v
+            .iter()
+            .map(|r| (r.read_name().to_vec(), r.flag()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (b"q9".to_vec(), 0x4),
+                (b"q1".to_vec(), 0x41),
+                (b"q1".to_vec(), 0x81),
+                (b"q0".to_vec(), 0x0),
+                (b"q2".to_vec(), 0x41),
+                (b"q2".to_vec(), 0x81),
+            ]
+        );
+    }
+
     #[test]
     fn end_to_end_sort_test_bam() {
         let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/small_unsorted.bam");
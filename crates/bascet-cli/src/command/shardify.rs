@@ -1,4 +1,4 @@
-use anyhow::Result;
+use anyhow::{Context, Result, bail};
 use bascet_core::{
     attr::{block::*, meta::*},
     channel::PeekableReceiver,
@@ -6,7 +6,11 @@ use bascet_core::{
     *,
 };
 use bascet_derive::Budget;
-use bascet_io::{BBGZHeader, BBGZTrailer, MAX_SIZEOF_BLOCKusize, codec, parse};
+use bascet_io::{
+    BBGZExtra, BBGZHeader, BBGZMetadata, BBGZTrailer, BlockFormat, MAX_SIZEOF_BLOCKusize,
+    METADATA_SUBFIELD, ZstBlockHeader, ZstSeekTable, codec, parse,
+};
+use bascet_runtime::report::Progress;
 use bounded_integer::{BoundedU64, BoundedUsize};
 use bytesize::ByteSize;
 use clap::Args;
@@ -22,7 +26,7 @@ use std::{
 };
 use tracing::{debug, error, info, warn};
 
-use crate::utils::{atomic_temp_path, publish_atomic_output};
+use crate::utils::{atomic_temp_path, publish_atomic_output, rename_or_copy_across_filesystems};
 
 use crate::bounded_parser;
 
@@ -105,6 +109,16 @@ pub struct ShardifyCMD {
 
     #[arg(long = "show-startup-message", default_value_t = true, hide = true)]
     pub show_startup_message: bool,
+
+    /// Metadata for every output, set by commands driving intermediate merges (e.g. debarcode).
+    /// If empty, it is derived from the inputs
+    #[arg(skip)]
+    pub metadata: BBGZMetadata,
+
+    /// List the outputs in the run report. Off for intermediate merges, whose outputs are
+    /// temp files that the driving command publishes or deletes itself
+    #[arg(skip = true)]
+    pub report_outputs: bool,
 }
 
 #[derive(Budget, Debug)]
@@ -148,6 +162,8 @@ impl ShardifyCMD {
             .build();
         budget.log();
 
+        let output_format = shard_output_format(&self.paths_in, &self.paths_out)?;
+
         let arc_filter = match &self.path_include {
             Some(path) => read_filter(&**path.path(), self.show_filter_warning),
             None => Arc::new(None),
@@ -187,6 +203,20 @@ impl ShardifyCMD {
 
         let global_cells_processed = Arc::new(std::sync::atomic::AtomicU64::new(0));
         let global_cells_kept = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        // NOTE:    records are blocks written, bytes_in are block bytes read
+        let progress = Progress::new("shardify")
+            .with_total_bytes_in(
+                self.paths_in
+                    .iter()
+                    .map(|path| {
+                        std::fs::metadata(path.path().path())
+                            .ok()
+                            .filter(|metadata| metadata.is_file())
+                            .map(|metadata| metadata.len())
+                    })
+                    .sum(),
+            )
+            .start();
 
         // bounds given by rtrb, this is only a notifier
         let (notify_tx, notify_rx) = channel::unbounded::<()>();
@@ -202,16 +232,17 @@ impl ShardifyCMD {
 
             let global_processed_counter = Arc::clone(&global_cells_processed);
             let global_kept_counter = Arc::clone(&global_cells_kept);
+            let thread_progress = Arc::clone(&progress);
 
-            vec_reader_handles.push(budget.spawn::<TRead, _, _>(thread_idx as u64, move || {
+            vec_reader_handles.push(budget.spawn::<TRead, _, _>(thread_idx as u64, move || -> Result<()> {
                 let thread = std::thread::current();
                 let thread_name = thread.name().unwrap_or("unknown thread");
                 debug!(thread = thread_name, path = %thread_input, "Starting stream");
 
-                let thread_file = match thread_input.clone().open() {
-                    Ok(file) => file,
-                    Err(e) => panic!("{e}"),
-                };
+                let thread_file = thread_input
+                    .clone()
+                    .open()
+                    .with_context(|| format!("failed to open input {thread_input}"))?;
 
                 let thread_decoder = codec::plain::PlaintextDecoder::builder()
                     .with_reader(thread_file)
@@ -236,6 +267,7 @@ impl ShardifyCMD {
                         "id_current < id_context",
                     );
 
+                let mut format_checked = false;
                 loop {
                     let block = match query.next() {
                         Ok(Some(block)) => block,
@@ -246,6 +278,30 @@ impl ShardifyCMD {
                         }
                     };
 
+                    // NOTE:    stdin and pipes cannot be sniffed up front, so check their
+                    //          first block instead
+                    if !format_checked {
+                        if let Some(input_format) = BlockFormat::detect(block.as_bytes::<Header>())
+                            && input_format != output_format
+                        {
+                            bail!(
+                                "Input '{}' is {:?} but the outputs are {:?}; shardify cannot \
+                                 convert between block formats, use transform instead",
+                                thread_input,
+                                input_format,
+                                output_format
+                            );
+                        }
+                        format_checked = true;
+                    }
+
+                    thread_progress.add(
+                        0,
+                        (block.as_bytes::<Header>().len()
+                            + block.as_bytes::<Compressed>().len()
+                            + block.as_bytes::<Trailer>().len()) as u64,
+                        0,
+                    );
                     let global_processed = global_processed_counter
                         .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
                         + 1;
@@ -280,6 +336,7 @@ impl ShardifyCMD {
                 drop(thread_notify_tx);
                 drop(thread_cell_tx);
                 debug!(thread = thread_name, "Reader thread exiting");
+                Ok(())
             }));
         }
         drop(notify_tx);
@@ -294,6 +351,13 @@ impl ShardifyCMD {
 
         let global_cells_written = Arc::new(std::sync::atomic::AtomicU64::new(0));
 
+        let output_metadata_bytes = if self.metadata.is_empty() {
+            BBGZMetadata::derive_from_paths(self.paths_in.iter().map(|p| p.path().path()))
+                .to_bytes()?
+        } else {
+            self.metadata.to_bytes()?
+        };
+
         let final_output_paths: Vec<PathBuf> = self
             .paths_out
             .iter()
@@ -323,7 +387,9 @@ impl ShardifyCMD {
                 BufWriter::with_capacity(ByteSize::mib(8).as_u64() as usize, thread_file);
 
             let global_counter = Arc::clone(&global_cells_written);
-            vec_writer_handles.push(budget.spawn::<TWrite, _, _>(thread_idx as u64, move || {
+            let thread_progress = Arc::clone(&progress);
+            let thread_metadata = output_metadata_bytes.clone();
+            vec_writer_handles.push(budget.spawn::<TWrite, _, _>(thread_idx as u64, move || -> Result<()> {
                 let thread = std::thread::current();
                 let thread_name = thread.name().unwrap_or("unknown thread");
                 debug!(thread = thread_name, path = ?thread_output_tmp, "Starting writer");
@@ -331,6 +397,9 @@ impl ShardifyCMD {
                 let mut merge_blocks: SmallVec<[parse::BBGZBlock; 32]> = SmallVec::new();
                 let mut merge_csize;
                 let mut merge_hsize;
+                let mut seek_table = ZstSeekTable::new();
+                // metadata is attached to the first block written to this shard
+                let mut pending_metadata = Some(thread_metadata);
 
                 while let Ok(vec_blocks) = thread_write_rx.recv() {
                     let n = vec_blocks.len() as u64;
@@ -346,62 +415,24 @@ impl ShardifyCMD {
 
                         let csize = compressed_bytes.len();
                         let hsize = header_bytes.len() + csize;
+                        let msize = pending_metadata
+                            .as_ref()
+                            .map_or(0, |md| BBGZExtra::SSIZE + md.len());
 
-                        if merge_hsize + hsize + BBGZTrailer::SSIZE > MAX_SIZEOF_BLOCKusize {
+                        if merge_hsize + hsize + msize + BBGZTrailer::SSIZE > MAX_SIZEOF_BLOCKusize
+                        {
                             if merge_blocks.len() > 0 {
-                                let (new_header_bytes, new_trailer_bytes) = unsafe {
-                                    let merge_first = merge_blocks.get_unchecked(0);
-                                    (
-                                        merge_first.as_bytes::<Header>(),
-                                        merge_first.as_bytes::<Trailer>(),
-                                    )
-                                };
-
-                                let mut new_header =
-                                    BBGZHeader::from_bytes(new_header_bytes).unwrap();
-                                let mut new_trailer =
-                                    BBGZTrailer::from_bytes(new_trailer_bytes).unwrap();
-
-                                for merge_block in merge_blocks.iter().skip(1) {
-                                    let merge_header_bytes = merge_block.as_bytes::<Header>();
-                                    let merge_trailer_bytes = merge_block.as_bytes::<Trailer>();
-
-                                    let merge_header =
-                                        BBGZHeader::from_bytes(merge_header_bytes).unwrap();
-                                    let merge_trailer =
-                                        BBGZTrailer::from_bytes(merge_trailer_bytes).unwrap();
-
-                                    new_header.merge(merge_header).unwrap();
-                                    new_trailer.merge(merge_trailer).unwrap();
-                                }
-
-                                new_header
-                                    .write_with_csize(&mut thread_buf_writer, merge_csize)
-                                    .unwrap();
-                                // BBGZ compressed payloads end with 03 00: an
-                                // empty final fixed-Huffman deflate block added
-                                // by BBGZWriter after SyncFlush. Merging keeps
-                                // the byte-aligned SyncFlush boundaries, strips
-                                // those per-block final markers, and appends one
-                                // final 03 00 for the combined deflate stream.
-                                let last_idx = merge_blocks.len() - 1;
-                                for i in 0..last_idx {
-                                    let merge_raw_bytes = unsafe { merge_blocks.get_unchecked(i) }
-                                        .as_bytes::<Compressed>();
-                                    let merge_raw_bytes_len = merge_raw_bytes.len();
-                                    thread_buf_writer
-                                        .write_all(&merge_raw_bytes[..(merge_raw_bytes_len - 2)])
-                                        .unwrap();
-                                }
-                                let last_raw_bytes =
-                                    unsafe { merge_blocks.get_unchecked(last_idx) }
-                                        .as_bytes::<Compressed>();
-                                let last_raw_bytes_len = last_raw_bytes.len();
-                                thread_buf_writer
-                                    .write_all(&last_raw_bytes[..(last_raw_bytes_len - 2)])
-                                    .unwrap();
-                                thread_buf_writer.write_all(&[0x03, 0x00]).unwrap();
-                                new_trailer.write_with(&mut thread_buf_writer).unwrap();
+                                write_merged_blocks(
+                                    output_format,
+                                    &mut thread_buf_writer,
+                                    &merge_blocks,
+                                    merge_csize,
+                                    &mut pending_metadata,
+                                    &mut seek_table,
+                                )
+                                .with_context(|| {
+                                    format!("failed to write {}", thread_output_tmp.display())
+                                })?;
 
                                 merge_blocks.clear();
                                 merge_csize = 0;
@@ -426,62 +457,30 @@ impl ShardifyCMD {
                                     String::from_utf8_lossy(merge_id)
                                 );
                                 merge_blocks.push(block);
-                                merge_csize += csize - 2;
+                                merge_csize += match output_format {
+                                    BlockFormat::Bbgz => csize - 2,
+                                    BlockFormat::Zst => csize,
+                                };
                                 merge_hsize += hsize;
                             }
                         }
                     }
 
                     if merge_blocks.len() > 0 {
-                        let (new_header_bytes, new_trailer_bytes) = unsafe {
-                            let merge_first = merge_blocks.get_unchecked(0);
-                            (
-                                merge_first.as_bytes::<Header>(),
-                                merge_first.as_bytes::<Trailer>(),
-                            )
-                        };
-
-                        let mut new_header = BBGZHeader::from_bytes(new_header_bytes).unwrap();
-                        let mut new_trailer = BBGZTrailer::from_bytes(new_trailer_bytes).unwrap();
-
-                        for merge_block in merge_blocks.iter().skip(1) {
-                            let merge_header_bytes = merge_block.as_bytes::<Header>();
-                            let merge_trailer_bytes = merge_block.as_bytes::<Trailer>();
-
-                            let merge_header = BBGZHeader::from_bytes(merge_header_bytes).unwrap();
-                            let merge_trailer =
-                                BBGZTrailer::from_bytes(merge_trailer_bytes).unwrap();
-
-                            new_header.merge(merge_header).unwrap();
-                            new_trailer.merge(merge_trailer).unwrap();
-                        }
-
-                        new_header
-                            .write_with_csize(&mut thread_buf_writer, merge_csize)
-                            .unwrap();
-                        // See the identical merge block above: each input
-                        // payload is SyncFlush output plus a removable 03 00
-                        // final empty fixed-Huffman block. The merged payload
-                        // gets exactly one final 03 00.
-                        let last_idx = merge_blocks.len() - 1;
-                        for i in 0..last_idx {
-                            let merge_raw_bytes =
-                                unsafe { merge_blocks.get_unchecked(i) }.as_bytes::<Compressed>();
-                            let merge_raw_bytes_len = merge_raw_bytes.len();
-                            thread_buf_writer
-                                .write_all(&merge_raw_bytes[..(merge_raw_bytes_len - 2)])
-                                .unwrap();
-                        }
-                        let last_raw_bytes = unsafe { merge_blocks.get_unchecked(last_idx) }
-                            .as_bytes::<Compressed>();
-                        let last_raw_bytes_len = last_raw_bytes.len();
-                        thread_buf_writer
-                            .write_all(&last_raw_bytes[..(last_raw_bytes_len - 2)])
-                            .unwrap();
-                        thread_buf_writer.write_all(&[0x03, 0x00]).unwrap();
-                        new_trailer.write_with(&mut thread_buf_writer).unwrap();
+                        write_merged_blocks(
+                            output_format,
+                            &mut thread_buf_writer,
+                            &merge_blocks,
+                            merge_csize,
+                            &mut pending_metadata,
+                            &mut seek_table,
+                        )
+                        .with_context(|| {
+                            format!("failed to write {}", thread_output_tmp.display())
+                        })?;
                     }
 
+                    thread_progress.add(n, 0, 0);
                     let last_counter =
                         global_counter.fetch_add(n, std::sync::atomic::Ordering::Relaxed);
                     let new_counter = last_counter + n;
@@ -490,11 +489,15 @@ impl ShardifyCMD {
                     }
                 }
 
-                thread_buf_writer
-                    .write_all(&codec::bbgz::MARKER_EOF)
-                    .unwrap();
-                thread_buf_writer.flush().unwrap();
+                match output_format {
+                    BlockFormat::Bbgz => {
+                        thread_buf_writer.write_all(&codec::bbgz::MARKER_EOF)?
+                    }
+                    BlockFormat::Zst => seek_table.write_with(&mut thread_buf_writer)?,
+                }
+                thread_buf_writer.flush()?;
                 debug!("Exiting writer {thread_idx}");
+                Ok(())
             }));
         }
 
@@ -625,17 +628,24 @@ impl ShardifyCMD {
 
         drop(vec_write_tx);
         for handle in vec_writer_handles {
-            handle.join().expect("Writer thread panicked");
-        }
-        for (path_tmp, path_final) in izip!(temp_output_paths, final_output_paths) {
-            publish_atomic_output(path_tmp, path_final)?;
+            handle.join().expect("Writer thread panicked")?;
         }
         debug!("Write handles closed");
 
+        // NOTE:    join the readers before publishing, a rejected input must not leave
+        //          partial shards behind
         for handle in vec_reader_handles {
-            handle.join().expect("Reader thread panicked");
+            handle.join().expect("Reader thread panicked")?;
         }
         debug!("Reader handles closed");
+        for (path_tmp, path_final) in izip!(temp_output_paths, final_output_paths) {
+            if self.report_outputs {
+                publish_atomic_output(path_tmp, path_final)?;
+            } else {
+                rename_or_copy_across_filesystems(path_tmp, path_final)?;
+            }
+        }
+        progress.finish();
 
         info!(
             input_files_processed = self.paths_in.len(),
@@ -647,6 +657,160 @@ impl ShardifyCMD {
     }
 }
 
+/// Block format of the outputs, taken from their extension. Merging copies compressed
+/// payloads as they are, so every input must already be in that format
+pub(crate) fn shard_output_format(
+    paths_in: &[InputPath],
+    paths_out: &[OutputPath],
+) -> Result<BlockFormat> {
+    let mut formats = paths_out
+        .iter()
+        .map(|path| BlockFormat::from_path(path.path().path()));
+    let output_format = formats.next().unwrap_or_default();
+    if formats.any(|format| format != output_format) {
+        bail!("Outputs mix .zst and BBGZ extensions; all shards must use one block format");
+    }
+
+    // NOTE:    only regular files are sniffed here; reading the magic of stdin or a pipe
+    //          would consume it, so their readers check the first block instead
+    for path in paths_in.iter().filter(|path| path.path().path().is_file()) {
+        if let Ok(Some(input_format)) = BlockFormat::detect_path(path.path().path()) {
+            if input_format != output_format {
+                bail!(
+                    "Input '{}' is {:?} but the outputs are {:?}; shardify cannot convert between \
+                     block formats, use transform instead",
+                    path,
+                    input_format,
+                    output_format
+                );
+            }
+        }
+    }
+    Ok(output_format)
+}
+
+/// Write same-ID blocks as a single block of `format`
+fn write_merged_blocks<W: Write>(
+    format: BlockFormat,
+    writer: &mut W,
+    merge_blocks: &[parse::BBGZBlock],
+    merge_csize: usize,
+    metadata: &mut Option<Vec<u8>>,
+    seek_table: &mut ZstSeekTable,
+) -> std::io::Result<()> {
+    match format {
+        BlockFormat::Bbgz => write_merged_bbgz_blocks(writer, merge_blocks, merge_csize, metadata),
+        BlockFormat::Zst => {
+            write_merged_zst_blocks(writer, merge_blocks, merge_csize, metadata, seek_table)
+        }
+    }
+}
+
+/// Write same-ID blocks as a single BBGZ block. Input metadata is dropped from the merged
+/// header; `metadata` is attached instead and taken. Err if it does not fit within the block
+/// size.
+fn write_merged_bbgz_blocks<W: Write>(
+    writer: &mut W,
+    merge_blocks: &[parse::BBGZBlock],
+    merge_csize: usize,
+    metadata: &mut Option<Vec<u8>>,
+) -> std::io::Result<()> {
+    let invalid =
+        |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string());
+
+    let merge_first = &merge_blocks[0];
+    let mut new_header = BBGZHeader::from_bytes(merge_first.as_bytes::<Header>())
+        .map_err(|_| invalid("malformed BBGZ header"))?;
+    let mut new_trailer = BBGZTrailer::from_bytes(merge_first.as_bytes::<Trailer>())
+        .map_err(|_| invalid("malformed BBGZ trailer"))?;
+    new_header.remove_extra(&METADATA_SUBFIELD);
+
+    for merge_block in merge_blocks.iter().skip(1) {
+        let mut merge_header = BBGZHeader::from_bytes(merge_block.as_bytes::<Header>())
+            .map_err(|_| invalid("malformed BBGZ header"))?;
+        let merge_trailer = BBGZTrailer::from_bytes(merge_block.as_bytes::<Trailer>())
+            .map_err(|_| invalid("malformed BBGZ trailer"))?;
+        merge_header.remove_extra(&METADATA_SUBFIELD);
+
+        new_header
+            .merge(merge_header)
+            .map_err(|_| invalid("conflicting BBGZ header extras"))?;
+        new_trailer
+            .merge(merge_trailer)
+            .map_err(|_| invalid("conflicting BBGZ trailers"))?;
+    }
+
+    if let Some(md) = metadata.take() {
+        let msize = BBGZExtra::SSIZE + md.len();
+        if new_header.size() + msize + merge_csize + BBGZTrailer::SSIZE <= MAX_SIZEOF_BLOCKusize {
+            new_header
+                .add_extra(&METADATA_SUBFIELD, md)
+                .map_err(|_| invalid("duplicate BBGZ metadata subfield"))?;
+        } else {
+            return Err(invalid("first shard block too large to carry the metadata"));
+        }
+    }
+
+    new_header.write_with_csize(writer, merge_csize)?;
+    // BBGZ compressed payloads end with 03 00: an empty final fixed-Huffman
+    // deflate block added by BBGZWriter after SyncFlush. Merging keeps the
+    // byte-aligned SyncFlush boundaries, strips those per-block final markers,
+    // and appends one final 03 00 for the combined deflate stream.
+    for merge_block in merge_blocks {
+        let merge_raw_bytes = merge_block.as_bytes::<Compressed>();
+        writer.write_all(&merge_raw_bytes[..(merge_raw_bytes.len() - 2)])?;
+    }
+    writer.write_all(&[0x03, 0x00])?;
+    new_trailer.write_with(writer)?;
+    Ok(())
+}
+
+/// Write same-ID zst blocks as a single zst block. The zstd frames are concatenated as they
+/// are and the block is recorded in `seek_table`; metadata is handled as for BBGZ.
+fn write_merged_zst_blocks<W: Write>(
+    writer: &mut W,
+    merge_blocks: &[parse::BBGZBlock],
+    merge_csize: usize,
+    metadata: &mut Option<Vec<u8>>,
+    seek_table: &mut ZstSeekTable,
+) -> std::io::Result<()> {
+    let invalid =
+        |what: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, what.to_string());
+
+    let mut new_header = BBGZHeader::new();
+    let mut new_isize = 0;
+    for merge_block in merge_blocks {
+        let mut merge_header = ZstBlockHeader::from_bytes(merge_block.as_bytes::<Header>())
+            .map_err(|_| invalid("malformed zst block header"))?;
+        merge_header.header.remove_extra(&METADATA_SUBFIELD);
+        new_isize += merge_header.isize as usize;
+
+        new_header
+            .merge(merge_header.header)
+            .map_err(|_| invalid("conflicting zst header extras"))?;
+    }
+
+    let mut new_header = ZstBlockHeader::new(new_header, merge_csize, new_isize);
+    if let Some(md) = metadata.take() {
+        let msize = BBGZExtra::SSIZE + md.len();
+        if new_header.sizeof_block() + msize <= MAX_SIZEOF_BLOCKusize {
+            new_header
+                .header
+                .add_extra(&METADATA_SUBFIELD, md)
+                .map_err(|_| invalid("duplicate zst metadata subfield"))?;
+        } else {
+            return Err(invalid("first shard block too large to carry the metadata"));
+        }
+    }
+
+    new_header.write_with(writer)?;
+    for merge_block in merge_blocks {
+        writer.write_all(merge_block.as_bytes::<Compressed>())?;
+    }
+    seek_table.push(new_header.sizeof_block(), new_isize);
+    Ok(())
+}
+
 fn read_filter<P: AsRef<Path>>(
     input: P,
     show_warning: bool,
@@ -15,6 +15,7 @@ use crossbeam::channel::{Receiver, Sender};
 use tracing::{info, warn};
 use zip::ZipWriter;
 
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
 use crate::utils::{atomic_temp_path, publish_atomic_output};
 
 const DEFAULT_SIZEOF_STREAM_BUFFER: ByteSize = ByteSize::gib(4);
@@ -356,10 +357,7 @@ fn stream_tirp_cells(
     info!("Streaming TIRP input {}", path_in.display());
     let num_threads = bounded_integer::BoundedU64::new(num_threads_read as u64)
         .context("invalid read thread count")?;
-    let decoder: bascet_io::BBGZDecoder = bascet_io::codec::BBGZDecoder::builder()
-        .with_path(&path_in)
-        .countof_threads(num_threads)
-        .build();
+    let decoder: bascet_io::BBGZDecoder = open_bbgz_decoder(&path_in, num_threads, None)?;
     let parser = bascet_io::parse::Tirp::builder().build();
 
     let mut stream = bascet_core::Stream::builder()
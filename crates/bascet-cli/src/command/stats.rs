@@ -0,0 +1,515 @@
+//! `stats` subcommand: fast per-cell summary statistics for a TIRP.
+//!
+//! Streams the TIRP cell by cell (`AsCell` mode) and computes, for every cell, the number of
+//! read pairs and bases, mean/median base quality, GC content, a read-length summary, the
+//! number of distinct UMIs and the duplicate rate. Cheap enough to run on every shard right
+//! after `debarcode`. Output is a TSV, or an h5ad with empty X and the statistics in obs
+//! when the output path ends in `.h5ad`; the h5ad also carries the sample sheet columns of each
+//! cell's library when the TIRP was debarcoded from a sample sheet. `.arrow`/`.feather` and
+//! `.parquet` outputs hold the same table as the TSV, typed, for columnar readers.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use anyhow::{Context, Result, bail};
+use bascet_core::DEFAULT_SIZEOF_ARENA;
+use bascet_core::{
+    attr::{meta::*, quality::*, sequence::*},
+    *,
+};
+use bytesize::ByteSize;
+use clap::Args;
+use crossbeam::channel::{Receiver, Sender};
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
+use crate::fileformat::new_anndata::SparseMatrixAnnDataWriter;
+use crate::fileformat::sample_sheet::SampleAnnotations;
+use crate::utils::{ColumnarFormat, atomic_temp_path, publish_atomic_output, write_columnar_table};
+
+const DEFAULT_SIZEOF_STREAM_BUFFER: ByteSize = ByteSize::gib(1);
+const PHRED_OFFSET: u8 = 33;
+
+const COLUMNS: [&str; 12] = [
+    "read_pairs",
+    "bases",
+    "mean_quality",
+    "median_quality",
+    "gc_content",
+    "read_length_min",
+    "read_length_median",
+    "read_length_mean",
+    "read_length_max",
+    "distinct_umis",
+    "duplicate_rate",
+    "n_content",
+];
+
+#[derive(Args)]
+pub struct StatsCMD {
+    /// Input TIRP file.
+    #[arg(short = 'i', value_parser = clap::value_parser!(PathBuf))]
+    pub path_in: PathBuf,
+
+    /// Output file: TSV, h5ad (empty X, statistics in obs) if it ends in `.h5ad`, Arrow IPC if it
+    /// ends in `.arrow`/`.feather`, Parquet if it ends in `.parquet`.
+    #[arg(short = 'o', value_parser = clap::value_parser!(PathBuf))]
+    pub path_out: PathBuf,
+
+    /// Number of cells to process concurrently.
+    #[arg(short = '@', long = "stats-workers")]
+    pub stats_workers: Option<usize>,
+
+    /// Threads used by the TIRP BGZF decoder.
+    #[arg(long = "num-threads-read", default_value_t = 1)]
+    pub num_threads_read: usize,
+
+    #[arg(
+        long = "sizeof-stream-buffer",
+        help = "Total stream buffer size.",
+        default_value_t = DEFAULT_SIZEOF_STREAM_BUFFER,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_buffer: ByteSize,
+
+    #[arg(
+        long = "sizeof-stream-arena",
+        help = "Stream arena buffer size [Advanced: changing this will impact performance and stability]",
+        hide_short_help = true,
+        default_value_t = DEFAULT_SIZEOF_ARENA,
+        value_parser = clap::value_parser!(ByteSize),
+    )]
+    sizeof_stream_arena: ByteSize,
+}
+
+impl StatsCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        if self.stats_workers == Some(0) {
+            bail!("--stats-workers must be > 0");
+        }
+        if self.num_threads_read == 0 {
+            bail!("--num-threads-read must be > 0");
+        }
+        let stats_workers = self.stats_workers.unwrap_or_else(|| {
+            std::thread::available_parallelism()
+                .map(usize::from)
+                .unwrap_or(1)
+                .saturating_sub(self.num_threads_read)
+                .max(1)
+        });
+
+        let (tx_cells, rx_cells) = crossbeam::channel::bounded::<tirp::Cell>(stats_workers * 4);
+        let (tx_stats, rx_stats) = crossbeam::channel::unbounded::<CellStats>();
+
+        let mut workers = Vec::with_capacity(stats_workers);
+        for _ in 0..stats_workers {
+            let rx_cells = rx_cells.clone();
+            let tx_stats = tx_stats.clone();
+            workers.push(thread::spawn(move || stats_worker(rx_cells, tx_stats)));
+        }
+        drop(rx_cells);
+        drop(tx_stats);
+
+        let result = stream_tirp_cells(
+            &self.path_in,
+            self.num_threads_read,
+            self.sizeof_stream_arena,
+            self.sizeof_stream_buffer,
+            tx_cells,
+        );
+        for worker in workers {
+            worker
+                .join()
+                .map_err(|_| anyhow::anyhow!("stats worker thread panicked"))?;
+        }
+        result?;
+
+        let mut all_stats: Vec<CellStats> = rx_stats.into_iter().collect();
+        all_stats.sort_unstable_by(|a, b| a.cell_id.cmp(&b.cell_id));
+        if let Some(pair) = all_stats.windows(2).find(|w| w[0].cell_id == w[1].cell_id) {
+            warn!(
+                cell = pair[0].cell_id,
+                "Cell appears in several non-contiguous runs; the input is not cell-sorted"
+            );
+        }
+
+        let is_h5ad = self
+            .path_out
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("h5ad"));
+        if is_h5ad {
+            write_anndata(&self.path_out, &all_stats, &self.path_in)?;
+        } else if let Some(format) = ColumnarFormat::from_path(&self.path_out) {
+            write_columnar_table(&self.path_out, format, &all_stats)?;
+        } else {
+            write_tsv(&self.path_out, &all_stats)?;
+        }
+        info!(
+            "Wrote statistics for {} cells to {}",
+            all_stats.len(),
+            self.path_out.display()
+        );
+        Ok(())
+    }
+}
+
+fn stream_tirp_cells(
+    path_in: &Path,
+    num_threads_read: usize,
+    sizeof_stream_arena: ByteSize,
+    sizeof_stream_buffer: ByteSize,
+    tx_cells: Sender<tirp::Cell>,
+) -> Result<()> {
+    info!("Streaming TIRP input {}", path_in.display());
+    let num_threads = bounded_integer::BoundedU64::new(num_threads_read as u64)
+        .context("invalid read thread count")?;
+    let decoder = open_bbgz_decoder(path_in, num_threads, None)?;
+    let parser = bascet_io::parse::Tirp::builder().build();
+
+    let mut stream = Stream::builder()
+        .with_decoder(decoder)
+        .with_parser(parser)
+        .sizeof_decode_arena(sizeof_stream_arena)
+        .sizeof_decode_buffer(sizeof_stream_buffer)
+        .build();
+
+    let mut query = stream
+        .query::<tirp::Cell>()
+        .group_relaxed_with_context::<Id, Id, _>(
+            |id_current: &&'static [u8], id_context: &&'static [u8]| {
+                if id_current == id_context {
+                    QueryResult::Keep
+                } else {
+                    QueryResult::Emit
+                }
+            },
+        );
+
+    let mut num_cells = 0_u64;
+    while let Some(cell) = query.next().context("failed to read TIRP cell")? {
+        if tx_cells.send(cell).is_err() {
+            bail!("stats workers stopped early");
+        }
+        num_cells += 1;
+        if num_cells % 100_000 == 0 {
+            info!(cells_processed = num_cells, "Processing");
+        }
+    }
+    Ok(())
+}
+
+fn stats_worker(rx_cells: Receiver<tirp::Cell>, tx_stats: Sender<CellStats>) {
+    while let Ok(cell) = rx_cells.recv() {
+        let stats = CellStats::compute(
+            String::from_utf8_lossy(cell.get_ref::<Id>()).into_owned(),
+            cell.get_ref::<R1>(),
+            cell.get_ref::<R2>(),
+            cell.get_ref::<Q1>(),
+            cell.get_ref::<Q2>(),
+            cell.get_ref::<Umi>(),
+        );
+        if tx_stats.send(stats).is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct CellStats {
+    #[serde(rename = "cell")]
+    cell_id: String,
+    read_pairs: u64,
+    bases: u64,
+    mean_quality: f64,
+    median_quality: f64,
+    /// G+C over called (ACGT) bases
+    gc_content: f64,
+    /// Read lengths are over the non-empty mates, so single-end reads count once
+    read_length_min: u64,
+    read_length_median: f64,
+    read_length_mean: f64,
+    read_length_max: u64,
+    /// Distinct non-empty UMIs
+    distinct_umis: u64,
+    /// 1 - distinct fragments / read pairs. A fragment is its UMI, or the R1+R2 sequence for
+    /// read pairs without one
+    duplicate_rate: f64,
+    /// N over all bases
+    n_content: f64,
+}
+
+impl CellStats {
+    fn compute(
+        cell_id: String,
+        r1: &[&[u8]],
+        r2: &[&[u8]],
+        q1: &[&[u8]],
+        q2: &[&[u8]],
+        umis: &[&[u8]],
+    ) -> Self {
+        let mut quality_hist = [0u64; 128];
+        let mut gc = 0u64;
+        let mut acgt = 0u64;
+        let mut ns = 0u64;
+        let mut read_lengths: Vec<u64> = Vec::with_capacity(r1.len() + r2.len());
+
+        // NOTE:    single-end reads carry an empty R2, which is no read of length 0
+        for seq in r1.iter().chain(r2).filter(|seq| !seq.is_empty()) {
+            read_lengths.push(seq.len() as u64);
+            for base in seq.iter() {
+                match base.to_ascii_uppercase() {
+                    b'G' | b'C' => {
+                        gc += 1;
+                        acgt += 1;
+                    }
+                    b'A' | b'T' => acgt += 1,
+                    _ => ns += 1,
+                }
+            }
+        }
+        for qual in q1.iter().chain(q2) {
+            for q in qual.iter() {
+                quality_hist[q.saturating_sub(PHRED_OFFSET).min(127) as usize] += 1;
+            }
+        }
+
+        let mut fragments: HashSet<(&[u8], &[u8])> = HashSet::with_capacity(r1.len());
+        let mut distinct_umis: HashSet<&[u8]> = HashSet::with_capacity(umis.len());
+        for (i, (seq1, seq2)) in r1.iter().zip(r2).enumerate() {
+            match umis.get(i).copied().filter(|umi| !umi.is_empty()) {
+                Some(umi) => {
+                    distinct_umis.insert(umi);
+                    fragments.insert((umi, &[]));
+                }
+                None => {
+                    fragments.insert((*seq1, *seq2));
+                }
+            }
+        }
+
+        let read_pairs = r1.len() as u64;
+        let bases: u64 = read_lengths.iter().sum();
+        read_lengths.sort_unstable();
+        let quality_total: u64 = quality_hist.iter().sum();
+        let quality_sum: u64 = quality_hist
+            .iter()
+            .enumerate()
+            .map(|(q, count)| q as u64 * count)
+            .sum();
+
+        Self {
+            cell_id,
+            read_pairs,
+            bases,
+            mean_quality: ratio(quality_sum, quality_total),
+            median_quality: median_from_histogram(&quality_hist),
+            gc_content: ratio(gc, acgt),
+            n_content: ratio(ns, bases),
+            read_length_min: read_lengths.first().copied().unwrap_or(0),
+            read_length_median: median_sorted(&read_lengths),
+            read_length_mean: ratio(bases, read_lengths.len() as u64),
+            read_length_max: read_lengths.last().copied().unwrap_or(0),
+            distinct_umis: distinct_umis.len() as u64,
+            duplicate_rate: if read_pairs == 0 {
+                0.0
+            } else {
+                1.0 - fragments.len() as f64 / read_pairs as f64
+            },
+        }
+    }
+
+    fn values(&self) -> [f64; COLUMNS.len()] {
+        [
+            self.read_pairs as f64,
+            self.bases as f64,
+            self.mean_quality,
+            self.median_quality,
+            self.gc_content,
+            self.read_length_min as f64,
+            self.read_length_median,
+            self.read_length_mean,
+            self.read_length_max as f64,
+            self.distinct_umis as f64,
+            self.duplicate_rate,
+            self.n_content,
+        ]
+    }
+}
+
+fn ratio(numerator: u64, denominator: u64) -> f64 {
+    if denominator == 0 {
+        0.0
+    } else {
+        numerator as f64 / denominator as f64
+    }
+}
+
+fn median_sorted(values: &[u64]) -> f64 {
+    match values.len() {
+        0 => 0.0,
+        n if n % 2 == 1 => values[n / 2] as f64,
+        n => (values[n / 2 - 1] + values[n / 2]) as f64 / 2.0,
+    }
+}
+
+fn median_from_histogram(hist: &[u64]) -> f64 {
+    let total: u64 = hist.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    // 0-based ranks of the two middle elements (equal for an odd total)
+    let lo_rank = (total - 1) / 2;
+    let hi_rank = total / 2;
+    let mut seen = 0u64;
+    let mut lo = None;
+    for (value, count) in hist.iter().enumerate() {
+        seen += count;
+        if lo.is_none() && seen > lo_rank {
+            lo = Some(value);
+        }
+        if seen > hi_rank {
+            return (lo.unwrap_or(value) + value) as f64 / 2.0;
+        }
+    }
+    unreachable!("histogram total covers every rank")
+}
+
+fn write_tsv(path_out: &Path, all_stats: &[CellStats]) -> Result<()> {
+    let path_tmp = atomic_temp_path(path_out);
+    let mut writer = BufWriter::new(
+        File::create(&path_tmp)
+            .with_context(|| format!("failed to create {}", path_tmp.display()))?,
+    );
+    writeln!(writer, "cell\t{}", COLUMNS.join("\t"))?;
+    for stats in all_stats {
+        writeln!(
+            writer,
+            "{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{}\t{:.4}\t{:.4}\t{}\t{}\t{:.4}\t{:.4}",
+            stats.cell_id,
+            stats.read_pairs,
+            stats.bases,
+            stats.mean_quality,
+            stats.median_quality,
+            stats.gc_content,
+            stats.read_length_min,
+            stats.read_length_median,
+            stats.read_length_mean,
+            stats.read_length_max,
+            stats.distinct_umis,
+            stats.duplicate_rate,
+            stats.n_content,
+        )?;
+    }
+    writer.flush()?;
+    drop(writer);
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
+
+fn write_anndata(path_out: &Path, all_stats: &[CellStats], path_in: &Path) -> Result<()> {
+    let path_tmp = atomic_temp_path(path_out);
+    let mut file = SparseMatrixAnnDataWriter::create_anndata(&path_tmp)?;
+    file.attach_sample_annotations(SampleAnnotations::from_paths([path_in]));
+    let n_rows = all_stats.len();
+    let empty_matrix =
+        sprs::CsMat::<u32>::new((n_rows, 0), vec![0; n_rows + 1], Vec::new(), Vec::new());
+
+    let cell_names: Vec<String> = all_stats.iter().map(|s| s.cell_id.clone()).collect();
+    let rows: Vec<_> = all_stats.iter().map(CellStats::values).collect();
+    let columns: Vec<(&str, Vec<f64>)> = COLUMNS
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (*name, rows.iter().map(|row| row[i]).collect()))
+        .collect();
+
+    file.store_feature_names(&Vec::new())?;
+    file.store_cell_obs_f64(&cell_names, &columns)?;
+    file.store_sparse_count_matrix(&empty_matrix, n_rows as u32, 0)?;
+    file.close()?;
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seqs(list: &[&'static str]) -> Vec<&'static [u8]> {
+        list.iter().map(|s| s.as_bytes()).collect()
+    }
+
+    #[test]
+    fn computes_cell_stats() {
+        let stats = CellStats::compute(
+            "A1".to_string(),
+            &seqs(&["GGCC", "GGCC", "ATNN"]),
+            &seqs(&["AT", "AT", "ATGCAA"]),
+            &seqs(&["IIII", "IIII", "####"]),
+            &seqs(&["II", "II", "######"]),
+            &seqs(&["", "", ""]),
+        );
+        assert_eq!(stats.read_pairs, 3);
+        assert_eq!(stats.bases, 18);
+        // 12 bases at Q40 and 10 at Q2
+        assert!((stats.mean_quality - 500.0 / 22.0).abs() < 1e-9);
+        assert_eq!(stats.median_quality, 40.0);
+        assert_eq!(stats.gc_content, 0.5);
+        assert!((stats.n_content - 2.0 / 18.0).abs() < 1e-9);
+        assert_eq!(stats.read_length_min, 2);
+        assert_eq!(stats.read_length_median, 4.0);
+        assert_eq!(stats.read_length_mean, 3.0);
+        assert_eq!(stats.read_length_max, 6);
+        assert_eq!(stats.distinct_umis, 0);
+        // without UMIs the first two pairs are duplicates by sequence
+        assert!((stats.duplicate_rate - 1.0 / 3.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn uses_umis_for_duplicates() {
+        let stats = CellStats::compute(
+            "A1".to_string(),
+            &seqs(&["AAAA", "CCCC", "GGGG", "TTTT"]),
+            &seqs(&["AAAA", "CCCC", "GGGG", "TTTT"]),
+            &seqs(&["IIII"; 4]),
+            &seqs(&["IIII"; 4]),
+            &seqs(&["UMI1", "UMI1", "UMI2", ""]),
+        );
+        assert_eq!(stats.distinct_umis, 2);
+        assert!((stats.duplicate_rate - 0.25).abs() < 1e-9);
+        assert_eq!(stats.mean_quality, 40.0);
+        assert_eq!(stats.median_quality, 40.0);
+    }
+
+    #[test]
+    fn skips_empty_mates_in_read_lengths() {
+        let stats = CellStats::compute(
+            "A1".to_string(),
+            &seqs(&["ACGT", "ACGTAC"]),
+            &seqs(&["", ""]),
+            &seqs(&["IIII", "IIIIII"]),
+            &seqs(&["", ""]),
+            &seqs(&["", ""]),
+        );
+        assert_eq!(stats.bases, 10);
+        assert_eq!(stats.read_length_min, 4);
+        assert_eq!(stats.read_length_median, 5.0);
+        assert_eq!(stats.read_length_mean, 5.0);
+    }
+
+    #[test]
+    fn medians_even_and_odd() {
+        assert_eq!(median_sorted(&[1, 2, 3]), 2.0);
+        assert_eq!(median_sorted(&[1, 2, 3, 10]), 2.5);
+        assert_eq!(median_sorted(&[]), 0.0);
+        let mut hist = [0u64; 8];
+        hist[2] = 1;
+        hist[5] = 1;
+        assert_eq!(median_from_histogram(&hist), 3.5);
+        hist[5] = 2;
+        assert_eq!(median_from_histogram(&hist), 5.0);
+    }
+}
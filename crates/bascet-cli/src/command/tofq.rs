@@ -21,7 +21,7 @@ pub struct ToFastqCMD {
     #[arg(
         short = 'i',
         long = "in",
-        help = "List of input files (comma-separated). Assumed to be sorted by cell id in descending order."
+        help = "List of input files (comma-separated), or - for stdin. Assumed to be sorted by cell id in descending order."
     )]
     pub path_in: InputPath,
 
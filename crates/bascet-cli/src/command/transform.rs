@@ -3,6 +3,7 @@ use crate::fileformat::read_cell_list_file;
 use crate::fileformat::zip::ZipStreamingReadPairReaderFactory;
 use anyhow::{Context, Result};
 use bascet_core::DEFAULT_SIZEOF_ARENA;
+use bascet_io::{BBGZMetadata, BlockFormat};
 use bytesize::ByteSize;
 use clap::Args;
 use crossbeam::channel::Receiver;
@@ -24,9 +25,11 @@ use crate::fileformat::ReadPairWriter;
 use crate::fileformat::ShardCellDictionary;
 use crate::fileformat::StreamingReadPairReader;
 use crate::fileformat::bam::BAMStreamingReadPairReaderFactory;
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
 use crate::fileformat::paired_fastq::BascetPairedFastqWriterFactory;
 use crate::fileformat::single_fastq::BascetSingleFastqWriterFactory;
 use crate::fileformat::tirp::BascetTIRPWriterFactory;
+use crate::fileformat::tirp::BascetTIRPZstWriterFactory;
 use crate::fileformat::tirp::TirpBascetShardReaderFactory;
 use crate::fileformat::tirp::get_tbi_path_for_tirp;
 use crate::fileformat::try_get_cells_in_file;
@@ -135,6 +138,7 @@ impl TransformFile {
                 crate::fileformat::detect_shard_format(&params.path_out[0]),
                 DetectedFileformat::TIRP
             )
+            && BlockFormat::from_path(&params.path_out[0]) == BlockFormat::Bbgz
         {
             return super::transform_bam2tirp::try_bam_to_tirp_fast_path(
                 &params.path_in[0],
@@ -196,12 +200,29 @@ impl TransformFile {
                         "Storing reads in ZipBascet not implemented. Consider TIRP format instead as it is a more relevant option"
                     )
                 }
+                DetectedFileformat::TIRP
+                    if BlockFormat::from_path(&p_final) == BlockFormat::Zst =>
+                {
+                    create_writer_thread(
+                        &p_tmp,
+                        &thread_pool_write,
+                        &rx_data,
+                        &tx_writer_result,
+                        &Arc::new(BascetTIRPZstWriterFactory::with_metadata(
+                            BBGZMetadata::derive_from_paths(&params.path_in),
+                            bounded_integer::BoundedU64::new_saturating(params.num_threads as u64),
+                        )),
+                    )
+                    .unwrap()
+                }
                 DetectedFileformat::TIRP => create_writer_thread(
                     &p_tmp,
                     &thread_pool_write,
                     &rx_data,
                     &tx_writer_result,
-                    &Arc::new(BascetTIRPWriterFactory::new()),
+                    &Arc::new(BascetTIRPWriterFactory::with_metadata(
+                        BBGZMetadata::derive_from_paths(&params.path_in),
+                    )),
                 )
                 .unwrap(),
                 DetectedFileformat::SingleFASTQ => create_writer_thread(
@@ -261,6 +282,9 @@ impl TransformFile {
                     let p_final_r2 = paired_r2_by_last_r1(&p_final)?;
                     publish_atomic_output(p_tmp_r2, p_final_r2)?;
                 }
+                // NOTE:    zstd TIRPs are indexed by their seek table, there is no .tbi
+                DetectedFileformat::TIRP
+                    if BlockFormat::from_path(&p_final) == BlockFormat::Zst => {}
                 DetectedFileformat::TIRP => {
                     publish_atomic_output(
                         get_tbi_path_for_tirp(&p_tmp),
@@ -298,11 +322,17 @@ pub fn create_random_readers(
     let (tx_readcell, rx_readcell) = crossbeam::channel::bounded::<Option<CellID>>(n_input);
 
     //Get full list of cells, or use provided list. possibly subset to cells present to avoid calls later?
-    let include_cells = get_list_of_all_cells(&params);
+    let include_cells = get_list_of_all_cells(&params)?;
 
     // Start reader threads -- for reading subset of cells
     for p in &params.path_in {
         let read_thread = match crate::fileformat::detect_shard_format(&p) {
+            DetectedFileformat::TIRP if BlockFormat::from_path(p) == BlockFormat::Zst => {
+                anyhow::bail!(
+                    "Reading specific cells needs a tabix-indexed TIRP, but {} is zstd",
+                    p.display()
+                )
+            }
             DetectedFileformat::TIRP => create_random_reader_thread(
                 &p,
                 &thread_pool_read,
@@ -400,7 +430,7 @@ pub fn create_stream_readers_with_options(
                 )
             }
         };
-        read_thread.expect("Failed to open input file");
+        read_thread.with_context(|| format!("Failed to open input file {}", p.display()))?;
     }
 
     //Wait for all readers to finish
@@ -458,23 +488,23 @@ where
 ////////////////
 /// Get the list of all files to process, given by user or by getting names from the files.
 /// The latter case is only relevant for compatibility with non-streaming APIs (which likely are slower than streaming APIs)
-fn get_list_of_all_cells(params: &Arc<TransformFile>) -> Vec<CellID> {
+fn get_list_of_all_cells(params: &Arc<TransformFile>) -> Result<Vec<CellID>> {
     //Get full list of cells, or use provided list. possibly subset to cells present to avoid calls later?
     if let Some(p) = &params.include_cells {
-        p.clone()
+        Ok(p.clone())
     } else {
         let mut all_cells: HashSet<CellID> = HashSet::new();
         for p in &params.path_in {
-            if let Some(cells) = try_get_cells_in_file(&p).expect("Failed to parse input file") {
+            if let Some(cells) = try_get_cells_in_file(&p)? {
                 all_cells.extend(cells);
             } else {
-                panic!(
+                anyhow::bail!(
                     "Cannot obtain list of cell names from this input file format. Try streaming the content instead"
                 );
             }
         }
         let all_cells: Vec<CellID> = all_cells.iter().cloned().collect();
-        all_cells
+        Ok(all_cells)
     }
 }
 
@@ -581,15 +611,13 @@ pub fn create_stream_reader_thread_newtirp(
     let infile = infile.clone();
     let tx_data = tx_data.clone();
 
+    let num_threads = bounded_integer::BoundedU64::new(num_threads.max(1) as u64).unwrap();
+    let decoder: bascet_io::BBGZDecoder = open_bbgz_decoder(&infile, num_threads, None)?;
+
     thread_pool.execute(move || {
         // Streamer from input TIRP
-        let num_threads = bounded_integer::BoundedU64::new(num_threads.max(1) as u64).unwrap();
         let sizeof_stream_arena = DEFAULT_SIZEOF_ARENA;
         let sizeof_stream_buffer: ByteSize = ByteSize::gib(4); //////////////////////////// parameter is made up TODO
-        let decoder: bascet_io::BBGZDecoder = bascet_io::codec::BBGZDecoder::builder()
-            .with_path(&infile)
-            .countof_threads(num_threads)
-            .build();
         let parser = bascet_io::parse::Tirp::builder().build();
 
         let mut stream = bascet_core::Stream::builder()
@@ -283,7 +283,7 @@ fn send_final_chunk(raw_tx: &Sender<RawChunk>, chunk: RawChunk) -> Result<()> {
         .map_err(|_| anyhow::anyhow!("BAM->TIRP compression workers stopped unexpectedly"))
 }
 
-fn validate_cell_order(last_cell_id: &Option<Vec<u8>>, cell_id: &[u8]) -> Result<()> {
+pub fn validate_cell_order(last_cell_id: &Option<Vec<u8>>, cell_id: &[u8]) -> Result<()> {
     let Some(last_cell_id) = last_cell_id else {
         return Ok(());
     };
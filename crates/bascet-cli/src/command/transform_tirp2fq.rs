@@ -10,14 +10,14 @@ use bascet_core::{
     Composite, Stream,
     attr::{meta::*, quality::*, sequence::*},
 };
-use bascet_io::{codec, parse, tirp};
+use bascet_io::{parse, tirp};
 use bounded_integer::BoundedU64;
 use bytesize::ByteSize;
 use noodles::bgzf::io::MultithreadedWriter;
 use tracing::{debug, info};
 
 use crate::{
-    fileformat::{DetectedFileformat, detect_shard_format},
+    fileformat::{DetectedFileformat, bbgz_input::open_bbgz_decoder, detect_shard_format},
     utils::{atomic_temp_path, publish_atomic_output},
 };
 
@@ -182,10 +182,7 @@ fn stream_tirp_to_fastq(
     stream_buffer: ByteSize,
     mut write_record: impl FnMut(&tirp::Record, u64) -> Result<()>,
 ) -> Result<()> {
-    let decoder = codec::BBGZDecoder::builder()
-        .with_path(path_in)
-        .countof_threads(read_threads)
-        .build();
+    let decoder = open_bbgz_decoder(path_in, read_threads, None)?;
     let parser = parse::Tirp::builder().build();
 
     let mut stream = Stream::builder()
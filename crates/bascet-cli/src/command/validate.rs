@@ -0,0 +1,864 @@
+//! `validate` subcommand: integrity and sort-order checks for TIRP, BBGZ and Bascet ZIP files.
+//!
+//! * TIRP / BBGZ: every block's gzip framing, CRC32 and ISIZE trailer (inflated in parallel),
+//!   the BGZF EOF marker, and that cells are contiguous and sorted bytewise, the order
+//!   `shardify` asserts on merge. For TIRP, each line must have the 8 TIRP columns with
+//!   matching sequence/quality lengths, and must belong to the cell named by its block's `ID`
+//!   extra if it has one.
+//! * ZIP: every entry is named `<cell>/<file>` and reads back with a matching CRC.
+//!
+//! The report is a TSV with one row per file and check (`path`, `check`, `status`, `count`,
+//! `detail`), where `count` is the number of failures and `detail` describes the first. The
+//! command fails if any check fails, so its exit code can gate later jobs.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use bascet_io::MARKER_EOF;
+use bascet_runtime::report::RunReport;
+use clap::{Args, ValueEnum};
+use rayon::prelude::*;
+use tracing::info;
+
+use super::determine_thread_counts_1;
+
+/// Blocks inflated per parallel batch; bounds memory to roughly this many 64 KiB blocks
+const BLOCKS_PER_BATCH: usize = 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub enum ValidateInputType {
+    /// ZIP by magic bytes, otherwise TIRP if the name contains `.tirp`, otherwise BBGZ.
+    Auto,
+    /// Block-gzipped TIRP.
+    Tirp,
+    /// BBGZ/BGZF blocks, checking framing, trailers and `ID` order only.
+    Bbgz,
+    /// Bascet ZIP archive.
+    Zip,
+}
+
+#[derive(Args)]
+pub struct ValidateCMD {
+    /// Files to validate (comma-separated).
+    #[arg(short = 'i', long = "in", value_delimiter = ',', required = true)]
+    pub paths_in: Vec<PathBuf>,
+
+    /// Report TSV. Written to stdout if omitted.
+    #[arg(short = 'o', long = "out")]
+    pub path_out: Option<PathBuf>,
+
+    /// Input type.
+    #[arg(long = "type", value_enum, default_value_t = ValidateInputType::Auto)]
+    pub input_type: ValidateInputType,
+
+    /// Total threads.
+    #[arg(short = '@', long = "threads", value_parser = clap::value_parser!(usize))]
+    pub num_threads: Option<usize>,
+}
+
+impl ValidateCMD {
+    pub fn try_execute(&mut self) -> Result<()> {
+        let num_threads = determine_thread_counts_1(self.num_threads)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .build()
+            .context("failed to build validate thread pool")?;
+
+        let mut reports = Vec::with_capacity(self.paths_in.len());
+        for path in &self.paths_in {
+            let input_type = match self.input_type {
+                ValidateInputType::Auto => detect_input_type(path)?,
+                other => other,
+            };
+            let report = pool.install(|| match input_type {
+                ValidateInputType::Zip => validate_zip(path),
+                other => validate_blocks(path, other == ValidateInputType::Tirp),
+            })?;
+            info!(
+                path = %path.display(),
+                failures = report.countof_failures(),
+                "Validate: checked file"
+            );
+            reports.push(report);
+        }
+
+        match &self.path_out {
+            Some(path_out) => {
+                let file = File::create(path_out)
+                    .with_context(|| format!("create report {}", path_out.display()))?;
+                write_reports(&mut BufWriter::new(file), &reports)?;
+                RunReport::add_output(path_out);
+            }
+            None => write_reports(&mut std::io::stdout().lock(), &reports)?,
+        }
+
+        let countof_invalid = reports.iter().filter(|r| r.countof_failures() > 0).count();
+        if countof_invalid > 0 {
+            bail!(
+                "{countof_invalid} of {} files failed validation",
+                reports.len()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd)]
+enum Check {
+    Framing,
+    Crc,
+    Isize,
+    EofMarker,
+    Record,
+    IdExtra,
+    CellOrder,
+    ZipLayout,
+    ZipCrc,
+}
+
+impl Check {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Check::Framing => "framing",
+            Check::Crc => "crc32",
+            Check::Isize => "isize",
+            Check::EofMarker => "eof_marker",
+            Check::Record => "tirp_record",
+            Check::IdExtra => "id_extra",
+            Check::CellOrder => "cell_order",
+            Check::ZipLayout => "zip_layout",
+            Check::ZipCrc => "zip_crc",
+        }
+    }
+}
+
+struct FileReport {
+    path: PathBuf,
+    checks: BTreeMap<Check, (u64, Option<String>)>,
+    summary: String,
+}
+
+impl FileReport {
+    fn new(path: &Path, checks: &[Check]) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            checks: checks.iter().map(|c| (*c, (0, None))).collect(),
+            summary: String::new(),
+        }
+    }
+
+    fn fail(&mut self, check: Check, detail: impl FnOnce() -> String) {
+        let (count, first) = self.checks.entry(check).or_insert((0, None));
+        *count += 1;
+        if first.is_none() {
+            *first = Some(detail());
+        }
+    }
+
+    fn countof_failures(&self) -> u64 {
+        self.checks.values().map(|(count, _)| count).sum()
+    }
+}
+
+fn write_reports<W: Write>(writer: &mut W, reports: &[FileReport]) -> Result<()> {
+    writeln!(writer, "path\tcheck\tstatus\tcount\tdetail")?;
+    for report in reports {
+        let path = report.path.display();
+        for (check, (count, first)) in &report.checks {
+            let status = if *count == 0 { "ok" } else { "fail" };
+            let detail = first.as_deref().unwrap_or("").replace(['\t', '\n'], " ");
+            writeln!(
+                writer,
+                "{path}\t{}\t{status}\t{count}\t{detail}",
+                check.as_str()
+            )?;
+        }
+        let status = if report.countof_failures() == 0 {
+            "ok"
+        } else {
+            "fail"
+        };
+        writeln!(
+            writer,
+            "{path}\tsummary\t{status}\t{}\t{}",
+            report.countof_failures(),
+            report.summary
+        )?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn detect_input_type(path: &Path) -> Result<ValidateInputType> {
+    let mut magic = [0u8; 4];
+    let mut file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let n = file.read(&mut magic)?;
+    if n == 4 && magic == *b"PK\x03\x04" {
+        return Ok(ValidateInputType::Zip);
+    }
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_ascii_lowercase())
+        .unwrap_or_default();
+    if name.contains(".tirp") {
+        Ok(ValidateInputType::Tirp)
+    } else {
+        Ok(ValidateInputType::Bbgz)
+    }
+}
+
+/// A block as read from disk, before inflation
+struct RawBlock {
+    offset: u64,
+    id: Option<Vec<u8>>,
+    cdata: Vec<u8>,
+    crc32: u32,
+    isize: u32,
+}
+
+/// What a block contributes to the sequential checks
+#[derive(Default)]
+struct BlockText {
+    /// Bytes before the first newline: the end of a line started in earlier blocks, or a
+    /// whole line if the block starts on a record boundary
+    leading: Vec<u8>,
+    /// Runs of equal cells over the complete lines after the first newline
+    runs: Vec<(Vec<u8>, u64)>,
+    /// Bytes after the last newline
+    trailing: Vec<u8>,
+    has_newline: bool,
+    countof_records: u64,
+}
+
+struct BlockResult {
+    failures: Vec<(Check, String)>,
+    text: Option<BlockText>,
+}
+
+/// Cells must form contiguous runs in non-decreasing bytewise order
+#[derive(Default)]
+struct CellOrder {
+    last: Option<Vec<u8>>,
+    finished: HashSet<Vec<u8>>,
+    countof_cells: u64,
+}
+
+impl CellOrder {
+    fn push(&mut self, cell: &[u8], location: impl Fn() -> String, report: &mut FileReport) {
+        if let Some(last) = &self.last {
+            if last.as_slice() == cell {
+                return;
+            }
+            if cell < last.as_slice() {
+                report.fail(Check::CellOrder, || {
+                    format!(
+                        "{}: cell {} sorts before preceding cell {}",
+                        location(),
+                        String::from_utf8_lossy(cell),
+                        String::from_utf8_lossy(last)
+                    )
+                });
+            }
+            let last = self.last.take().unwrap();
+            self.finished.insert(last);
+        }
+        if self.finished.contains(cell) {
+            report.fail(Check::CellOrder, || {
+                format!(
+                    "{}: cell {} is not contiguous",
+                    location(),
+                    String::from_utf8_lossy(cell)
+                )
+            });
+        } else {
+            self.countof_cells += 1;
+        }
+        self.last = Some(cell.to_vec());
+    }
+}
+
+fn validate_blocks(path: &Path, is_tirp: bool) -> Result<FileReport> {
+    let mut checks = vec![Check::Framing, Check::Crc, Check::Isize, Check::EofMarker];
+    if is_tirp {
+        checks.extend([Check::Record, Check::IdExtra]);
+    }
+    checks.push(Check::CellOrder);
+    let mut report = FileReport::new(path, &checks);
+
+    let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::with_capacity(1 << 20, file);
+
+    let mut order = CellOrder::default();
+    let mut countof_blocks = 0u64;
+    let mut countof_records = 0u64;
+    // partial line spanning blocks, with the ID extra of the block it started in
+    let mut carry: Vec<u8> = Vec::new();
+    let mut carry_block: Option<(u64, Option<Vec<u8>>)> = None;
+
+    let mut offset = 0u64;
+    let mut framing_ok = true;
+    while framing_ok {
+        let mut batch = Vec::with_capacity(BLOCKS_PER_BATCH);
+        while batch.len() < BLOCKS_PER_BATCH {
+            match read_raw_block(&mut reader, offset) {
+                Ok(Some((block, size))) => {
+                    offset += size;
+                    batch.push(block);
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    report.fail(Check::Framing, || format!("offset {offset}: {e}"));
+                    framing_ok = false;
+                    break;
+                }
+            }
+        }
+        if batch.is_empty() {
+            break;
+        }
+
+        let results: Vec<BlockResult> = batch
+            .par_iter()
+            .map_init(libdeflater::Decompressor::new, |decompressor, block| {
+                check_block(decompressor, block, is_tirp)
+            })
+            .collect();
+
+        for (block, result) in batch.iter().zip(results) {
+            countof_blocks += 1;
+            for (check, detail) in result.failures {
+                report.fail(check, || detail);
+            }
+
+            if !is_tirp {
+                if let Some(id) = &block.id {
+                    order.push(
+                        id,
+                        || format!("block at offset {}", block.offset),
+                        &mut report,
+                    );
+                }
+                continue;
+            }
+            let Some(text) = result.text else {
+                continue;
+            };
+
+            if carry.is_empty() {
+                carry_block = Some((block.offset, block.id.clone()));
+            }
+            carry.extend_from_slice(&text.leading);
+            if !text.has_newline {
+                continue;
+            }
+
+            let (line_offset, line_id) = carry_block.take().unwrap_or((block.offset, None));
+            if !carry.is_empty() {
+                countof_records += 1;
+                let location = || format!("line starting in block at offset {line_offset}");
+                match check_tirp_line(&carry) {
+                    Ok(cell) => {
+                        if let Some(id) = line_id.as_deref().filter(|id| *id != cell) {
+                            report.fail(Check::IdExtra, || {
+                                format!(
+                                    "{}: cell {} in block with ID {}",
+                                    location(),
+                                    String::from_utf8_lossy(cell),
+                                    String::from_utf8_lossy(id)
+                                )
+                            });
+                        }
+                        order.push(cell, location, &mut report);
+                    }
+                    Err(e) => report.fail(Check::Record, || format!("{}: {e}", location())),
+                }
+            }
+
+            countof_records += text.countof_records;
+            for (cell, _) in &text.runs {
+                order.push(
+                    cell,
+                    || format!("block at offset {}", block.offset),
+                    &mut report,
+                );
+            }
+            carry = text.trailing;
+            if !carry.is_empty() {
+                carry_block = Some((block.offset, block.id.clone()));
+            }
+        }
+    }
+
+    if framing_ok && !carry.is_empty() {
+        report.fail(Check::Record, || {
+            format!(
+                "file ends inside a record ({} bytes without newline)",
+                carry.len()
+            )
+        });
+    }
+    if !has_eof_marker(path, len)? {
+        report.fail(Check::EofMarker, || {
+            "file does not end with the BGZF EOF marker (truncated?)".to_string()
+        });
+    }
+
+    report.summary = format!(
+        "blocks={countof_blocks} records={countof_records} cells={}",
+        order.countof_cells
+    );
+    Ok(report)
+}
+
+/// Read one block at `offset`. `Ok(None)` at a clean end of file
+fn read_raw_block<R: Read>(reader: &mut R, offset: u64) -> Result<Option<(RawBlock, u64)>> {
+    let mut base = [0u8; 12];
+    let n = read_up_to(reader, &mut base)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n < base.len() {
+        bail!("truncated gzip header");
+    }
+    if base[..3] != [0x1f, 0x8b, 0x08] {
+        bail!("not a gzip deflate member");
+    }
+    if base[3] & 0x04 == 0 {
+        bail!("gzip header has no extra field, so no BGZF block size");
+    }
+
+    let xlen = u16::from_le_bytes([base[10], base[11]]) as usize;
+    let mut extra = vec![0u8; xlen];
+    if read_up_to(reader, &mut extra)? < xlen {
+        bail!("truncated gzip extra field");
+    }
+
+    let mut bsize = None;
+    let mut id = None;
+    let mut cursor = 0;
+    while cursor + 4 <= xlen {
+        let slen = u16::from_le_bytes([extra[cursor + 2], extra[cursor + 3]]) as usize;
+        let data = extra
+            .get(cursor + 4..cursor + 4 + slen)
+            .context("gzip extra subfield overruns the extra field")?;
+        match &extra[cursor..cursor + 2] {
+            b"BC" if slen == 2 => bsize = Some(u16::from_le_bytes([data[0], data[1]]) as usize),
+            b"ID" => id = Some(data.to_vec()),
+            _ => {}
+        }
+        cursor += 4 + slen;
+    }
+    let Some(bsize) = bsize else {
+        bail!("gzip extra field has no BGZF BC block size");
+    };
+
+    let size = bsize + 1;
+    let Some(remaining) = size.checked_sub(base.len() + xlen + 8) else {
+        bail!("block size {size} is smaller than its header and trailer");
+    };
+    let mut cdata = vec![0u8; remaining];
+    let mut trailer = [0u8; 8];
+    if read_up_to(reader, &mut cdata)? < remaining || read_up_to(reader, &mut trailer)? < 8 {
+        bail!("truncated block (block size {size})");
+    }
+
+    Ok(Some((
+        RawBlock {
+            offset,
+            id,
+            cdata,
+            crc32: u32::from_le_bytes(trailer[..4].try_into().unwrap()),
+            isize: u32::from_le_bytes(trailer[4..].try_into().unwrap()),
+        },
+        size as u64,
+    )))
+}
+
+fn read_up_to<R: Read>(reader: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn check_block(
+    decompressor: &mut libdeflater::Decompressor,
+    block: &RawBlock,
+    is_tirp: bool,
+) -> BlockResult {
+    let location = format!("block at offset {}", block.offset);
+    let mut failures = Vec::new();
+
+    if block.isize as usize > bascet_io::MAX_SIZEOF_BLOCKusize {
+        failures.push((
+            Check::Isize,
+            format!("{location}: ISIZE {} exceeds 64 KiB", block.isize),
+        ));
+        return BlockResult {
+            failures,
+            text: None,
+        };
+    }
+
+    let mut data = vec![0u8; block.isize as usize];
+    match decompressor.deflate_decompress(&block.cdata, &mut data) {
+        Ok(n) if n == data.len() => {}
+        Ok(n) => {
+            failures.push((
+                Check::Isize,
+                format!("{location}: inflated {n} bytes, ISIZE says {}", block.isize),
+            ));
+            return BlockResult {
+                failures,
+                text: None,
+            };
+        }
+        Err(e) => {
+            failures.push((
+                Check::Isize,
+                format!("{location}: cannot inflate to ISIZE {}: {e}", block.isize),
+            ));
+            return BlockResult {
+                failures,
+                text: None,
+            };
+        }
+    }
+    let crc32 = crc32fast::hash(&data);
+    if crc32 != block.crc32 {
+        failures.push((
+            Check::Crc,
+            format!(
+                "{location}: CRC32 {crc32:08x} does not match trailer {:08x}",
+                block.crc32
+            ),
+        ));
+    }
+
+    if !is_tirp {
+        return BlockResult {
+            failures,
+            text: None,
+        };
+    }
+
+    let mut text = BlockText::default();
+    let Some(first_nl) = memchr::memchr(b'\n', &data) else {
+        text.leading = data;
+        return BlockResult {
+            failures,
+            text: Some(text),
+        };
+    };
+    let last_nl = memchr::memrchr(b'\n', &data).unwrap();
+    text.has_newline = true;
+    text.leading = data[..first_nl].to_vec();
+    text.trailing = data[last_nl + 1..].to_vec();
+
+    if first_nl < last_nl {
+        for line in data[first_nl + 1..last_nl].split(|b| *b == b'\n') {
+            text.countof_records += 1;
+            match check_tirp_line(line) {
+                Ok(cell) => {
+                    if let Some(id) = block.id.as_deref().filter(|id| *id != cell) {
+                        failures.push((
+                            Check::IdExtra,
+                            format!(
+                                "{location}: cell {} in block with ID {}",
+                                String::from_utf8_lossy(cell),
+                                String::from_utf8_lossy(id)
+                            ),
+                        ));
+                    }
+                    match text.runs.last_mut() {
+                        Some((last, count)) if last.as_slice() == cell => *count += 1,
+                        _ => text.runs.push((cell.to_vec(), 1)),
+                    }
+                }
+                Err(e) => failures.push((Check::Record, format!("{location}: {e}"))),
+            }
+        }
+    }
+
+    BlockResult {
+        failures,
+        text: Some(text),
+    }
+}
+
+/// Check a TIRP line (without newline): `cell 1 1 r1 r2 q1 q2 umi`. Returns the cell
+fn check_tirp_line(line: &[u8]) -> std::result::Result<&[u8], String> {
+    let fields: Vec<&[u8]> = line.split(|b| *b == b'\t').collect();
+    if fields.len() != 8 {
+        return Err(format!(
+            "expected 8 tab-separated columns, found {}",
+            fields.len()
+        ));
+    }
+    let cell = fields[0];
+    if cell.is_empty() {
+        return Err("empty cell ID".to_string());
+    }
+    if fields[3].len() != fields[5].len() {
+        return Err(format!(
+            "cell {}: R1 length {} does not match Q1 length {}",
+            String::from_utf8_lossy(cell),
+            fields[3].len(),
+            fields[5].len()
+        ));
+    }
+    if fields[4].len() != fields[6].len() {
+        return Err(format!(
+            "cell {}: R2 length {} does not match Q2 length {}",
+            String::from_utf8_lossy(cell),
+            fields[4].len(),
+            fields[6].len()
+        ));
+    }
+    Ok(cell)
+}
+
+fn has_eof_marker(path: &Path, len: u64) -> Result<bool> {
+    let marker_len = MARKER_EOF.len() as u64;
+    if len < marker_len {
+        return Ok(false);
+    }
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(len - marker_len))?;
+    let mut tail = [0u8; MARKER_EOF.len()];
+    file.read_exact(&mut tail)?;
+    Ok(tail == MARKER_EOF)
+}
+
+fn validate_zip(path: &Path) -> Result<FileReport> {
+    let mut report = FileReport::new(path, &[Check::ZipLayout, Check::ZipCrc]);
+
+    let names: Vec<String> = {
+        let file = File::open(path).with_context(|| format!("open {}", path.display()))?;
+        match zip::ZipArchive::new(BufReader::new(file)) {
+            Ok(archive) => archive.file_names().map(str::to_string).collect(),
+            Err(e) => {
+                report.fail(Check::ZipLayout, || {
+                    format!("cannot read zip directory: {e}")
+                });
+                return Ok(report);
+            }
+        }
+    };
+
+    let mut cells: HashSet<&str> = HashSet::new();
+    for name in &names {
+        match check_zip_entry_name(name) {
+            Ok(Some(cell)) => {
+                cells.insert(cell);
+            }
+            Ok(None) => {}
+            Err(e) => report.fail(Check::ZipLayout, || format!("entry {name:?}: {e}")),
+        }
+    }
+
+    let failures: Vec<String> = (0..names.len())
+        .into_par_iter()
+        .map_init(
+            || {
+                File::open(path)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|f| Ok(zip::ZipArchive::new(BufReader::new(f))?))
+            },
+            |archive, index| -> Option<String> {
+                let archive = match archive {
+                    Ok(archive) => archive,
+                    Err(e) => return Some(format!("cannot reopen archive: {e}")),
+                };
+                let mut entry = match archive.by_index(index) {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(format!("entry {:?}: {e}", names[index])),
+                };
+                std::io::copy(&mut entry, &mut std::io::sink())
+                    .err()
+                    .map(|e| format!("entry {:?}: {e}", names[index]))
+            },
+        )
+        .flatten()
+        .collect();
+    for failure in failures {
+        report.fail(Check::ZipCrc, || failure);
+    }
+
+    report.summary = format!("entries={} cells={}", names.len(), cells.len());
+    Ok(report)
+}
+
+/// `Ok(Some(cell))` for a `<cell>/<file>` entry, `Ok(None)` for a `<cell>/` directory entry
+fn check_zip_entry_name(name: &str) -> std::result::Result<Option<&str>, String> {
+    let Some((cell, file)) = name.split_once('/') else {
+        return Err("not inside a <cell>/ directory".to_string());
+    };
+    if cell.is_empty() {
+        return Err("empty cell name".to_string());
+    }
+    if file.split('/').any(|part| part == "..") || cell == ".." {
+        return Err("path escapes the cell directory".to_string());
+    }
+    if file.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(cell))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_tirp_lines() {
+        assert_eq!(
+            check_tirp_line(b"A1_B2\t1\t1\tACGT\tTT\tIIII\tII\tUMI").unwrap(),
+            b"A1_B2"
+        );
+        assert!(check_tirp_line(b"A1_B2\t1\t1\tACGT\tTT\tIII\tII\tUMI").is_err());
+        assert!(check_tirp_line(b"A1_B2\t1\t1\tACGT").is_err());
+    }
+
+    #[test]
+    fn flags_unsorted_and_split_cells() {
+        let mut report = FileReport::new(Path::new("x"), &[Check::CellOrder]);
+        let mut order = CellOrder::default();
+        for cell in [b"a", b"b", b"b", b"c"] {
+            order.push(cell, String::new, &mut report);
+        }
+        assert_eq!(report.countof_failures(), 0);
+
+        order.push(b"a", String::new, &mut report);
+        // out of order and already finished
+        assert_eq!(report.checks[&Check::CellOrder].0, 2);
+        assert_eq!(order.countof_cells, 3);
+    }
+
+    #[test]
+    fn checks_zip_entry_names() {
+        assert_eq!(check_zip_entry_name("cell1/contigs.fa"), Ok(Some("cell1")));
+        assert_eq!(check_zip_entry_name("cell1/"), Ok(None));
+        assert!(check_zip_entry_name("contigs.fa").is_err());
+        assert!(check_zip_entry_name("/contigs.fa").is_err());
+        assert!(check_zip_entry_name("cell1/../x").is_err());
+    }
+
+    /// BGZF block with an `ID` extra, deflated without compression so the records can be
+    /// found and corrupted in the file bytes
+    fn bgzf_block(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::none());
+        encoder.write_all(data).unwrap();
+        let cdata = encoder.finish().unwrap();
+
+        let xlen = 6 + 4 + id.len();
+        let bsize = 12 + xlen + cdata.len() + 8;
+        let mut block = vec![0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff];
+        block.extend_from_slice(&(xlen as u16).to_le_bytes());
+        block.extend_from_slice(b"BC\x02\x00");
+        block.extend_from_slice(&((bsize - 1) as u16).to_le_bytes());
+        block.extend_from_slice(b"ID");
+        block.extend_from_slice(&(id.len() as u16).to_le_bytes());
+        block.extend_from_slice(id);
+        block.extend_from_slice(&cdata);
+        block.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block
+    }
+
+    fn tirp_fixture() -> Vec<u8> {
+        let mut file = bgzf_block(
+            b"A1",
+            b"A1\t1\t1\tACGT\tTT\tIIII\tII\tU1\nA1\t1\t1\tGG\tCC\tII\tII\tU2\n",
+        );
+        file.extend(bgzf_block(
+            b"B2",
+            b"B2\t1\t1\tGATTACA\tTTG\tIIIIIII\tIII\tU3\n",
+        ));
+        file.extend_from_slice(&MARKER_EOF);
+        file
+    }
+
+    /// Report rows of `path` as `check -> (status, count)`
+    fn report_rows<'a>(report: &'a str, path: &Path) -> BTreeMap<&'a str, (&'a str, &'a str)> {
+        let path = path.display().to_string();
+        report
+            .lines()
+            .skip(1)
+            .map(|line| line.split('\t').collect::<Vec<_>>())
+            .filter(|row| row[0] == path)
+            .map(|row| (row[1], (row[2], row[3])))
+            .collect()
+    }
+
+    #[test]
+    fn validates_real_truncated_and_corrupted_tirps() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = tirp_fixture();
+
+        let path_valid = dir.path().join("valid.tirp.gz");
+        std::fs::write(&path_valid, &fixture).unwrap();
+
+        // NOTE:    cut inside the second block, so both its framing and the EOF marker are lost
+        let path_truncated = dir.path().join("truncated.tirp.gz");
+        std::fs::write(
+            &path_truncated,
+            &fixture[..fixture.len() - MARKER_EOF.len() - 5],
+        )
+        .unwrap();
+
+        // NOTE:    a base flipped in the stored payload still inflates, but no longer matches
+        //          the CRC32 in the trailer
+        let path_corrupted = dir.path().join("corrupted.tirp.gz");
+        let mut corrupted = fixture.clone();
+        let at = memchr::memmem::find(&corrupted, b"GATTACA").unwrap();
+        corrupted[at] = b'C';
+        std::fs::write(&path_corrupted, &corrupted).unwrap();
+
+        let path_report = dir.path().join("report.tsv");
+        let mut cmd = ValidateCMD {
+            paths_in: vec![
+                path_valid.clone(),
+                path_truncated.clone(),
+                path_corrupted.clone(),
+            ],
+            path_out: Some(path_report.clone()),
+            input_type: ValidateInputType::Auto,
+            num_threads: Some(1),
+        };
+        let err = cmd.try_execute().unwrap_err();
+        assert_eq!(err.to_string(), "2 of 3 files failed validation");
+        let report = std::fs::read_to_string(&path_report).unwrap();
+
+        let valid = report_rows(&report, &path_valid);
+        assert!(
+            valid.values().all(|(status, _)| *status == "ok"),
+            "{valid:?}"
+        );
+        let summary = report
+            .lines()
+            .find(|line| line.starts_with(&format!("{}\tsummary", path_valid.display())))
+            .unwrap();
+        assert!(summary.ends_with("blocks=3 records=3 cells=2"), "{summary}");
+
+        let truncated = report_rows(&report, &path_truncated);
+        assert_eq!(truncated["framing"], ("fail", "1"));
+        assert_eq!(truncated["eof_marker"], ("fail", "1"));
+        assert_eq!(truncated["crc32"], ("ok", "0"));
+
+        let corrupted = report_rows(&report, &path_corrupted);
+        assert_eq!(corrupted["crc32"], ("fail", "1"));
+        assert_eq!(corrupted["framing"], ("ok", "0"));
+        assert_eq!(corrupted["tirp_record"], ("ok", "0"));
+        assert_eq!(corrupted["eof_marker"], ("ok", "0"));
+    }
+}
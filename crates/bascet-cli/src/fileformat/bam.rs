@@ -8,6 +8,9 @@ use crate::fileformat::shard::ReadPair;
 
 use super::CellID;
 use noodles::sam::alignment::RecordBuf as BamRecord;
+use noodles::sam::alignment::record::cigar::op::Kind as CigarKind;
+use noodles::sam::alignment::record::data::field::Tag;
+use noodles::sam::alignment::record_buf::data::field::Value;
 
 type ListReadWithBarcode = Arc<(CellID, Arc<Vec<ReadPair>>)>;
 
@@ -23,6 +26,25 @@ struct BamHalf {
     is_segmented: bool,
     is_first: bool,
     is_last: bool,
+    alignment: MateAlignment,
+}
+
+///////////////////////////////
+/// How one mate aligned, as recorded in its BAM record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MateAlignment {
+    pub is_mapped: bool,
+    /// Read bases covered by CIGAR M operations
+    pub matching_bases: u32,
+    pub read_len: u32,
+}
+
+///////////////////////////////
+/// How a read pair aligned. `r2` is None for single-end reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PairAlignment {
+    pub r1: MateAlignment,
+    pub r2: Option<MateAlignment>,
 }
 
 ///////////////////////////////
@@ -39,7 +61,7 @@ pub struct BAMStreamingReadPairReader {
     /// One-record lookahead, used when an expected mate turns out not to be one.
     pushback: Option<BamHalf>,
     /// One-readpair lookahead, used to detect cell boundaries.
-    last_rp: Option<(Vec<u8>, ReadPair)>,
+    last_rp: Option<(Vec<u8>, ReadPair, PairAlignment)>,
 }
 impl BAMStreamingReadPairReader {
     /// Create a new reader from a BAM file
@@ -84,6 +106,15 @@ impl BAMStreamingReadPairReader {
     }
 
     pub fn next_readpair_for_transform(&mut self) -> anyhow::Result<Option<(Vec<u8>, ReadPair)>> {
+        Ok(self
+            .next_readpair_with_alignment()?
+            .map(|(cell_id, rp, _)| (cell_id, rp)))
+    }
+
+    /// Like `next_readpair_for_transform`, but also reports how each mate aligned
+    pub fn next_readpair_with_alignment(
+        &mut self,
+    ) -> anyhow::Result<Option<(Vec<u8>, ReadPair, PairAlignment)>> {
         if let Some(rp) = self.last_rp.take() {
             Ok(Some(rp))
         } else {
@@ -104,7 +135,7 @@ impl BAMStreamingReadPairReader {
                 if flags.is_secondary() || flags.is_supplementary() {
                     continue;
                 }
-                return Ok(Some(read_to_half(&record)));
+                return read_to_half(&record).map(Some);
             } else {
                 return Ok(None);
             }
@@ -115,7 +146,7 @@ impl BAMStreamingReadPairReader {
     /// (paired-end). For a paired R1 (segmented + first-segment) the next record is verified to
     /// be its mate (segmented + last-segment, same cell+UMI); if not, the pairing assumption is
     /// violated and we error out rather than silently emit corrupt data.
-    fn next_readpair(&mut self) -> anyhow::Result<Option<(Vec<u8>, ReadPair)>> {
+    fn next_readpair(&mut self) -> anyhow::Result<Option<(Vec<u8>, ReadPair, PairAlignment)>> {
         let first = match self.next_half()? {
             Some(h) => h,
             None => return Ok(None),
@@ -145,6 +176,10 @@ impl BAMStreamingReadPairReader {
                 );
             }
 
+            let alignment = PairAlignment {
+                r1: first.alignment,
+                r2: Some(second.alignment),
+            };
             let rp = ReadPair {
                 r1: first.seq,
                 r2: second.seq,
@@ -152,7 +187,7 @@ impl BAMStreamingReadPairReader {
                 q2: second.qual,
                 umi: first.umi,
             };
-            return Ok(Some((first.cell_id, rp)));
+            return Ok(Some((first.cell_id, rp, alignment)));
         }
 
         //Single-end (non-segmented), or a lone R2 in a filtered file: emit as R1 only
@@ -163,25 +198,29 @@ impl BAMStreamingReadPairReader {
             q2: Vec::new(),
             umi: first.umi,
         };
-        Ok(Some((first.cell_id, rp)))
+        let alignment = PairAlignment {
+            r1: first.alignment,
+            r2: None,
+        };
+        Ok(Some((first.cell_id, rp, alignment)))
     }
 }
 impl StreamingReadPairReader for BAMStreamingReadPairReader {
     fn get_reads_for_next_cell(&mut self) -> anyhow::Result<Option<ListReadWithBarcode>> {
         //Check if we arrived at the end already
-        if let Some((current_cell, last_rp)) = self.last_rp.take() {
+        if let Some((current_cell, last_rp, _)) = self.last_rp.take() {
             //First push the last read pair we had
             let mut reads: Vec<ReadPair> = Vec::new();
             reads.push(last_rp);
 
             //Keep reading read pairs until we reach the next cell or the end
-            while let Some((cell_id, rp)) = self.next_readpair()? {
+            while let Some((cell_id, rp, alignment)) = self.next_readpair()? {
                 if cell_id == current_cell {
                     //This read belongs to this cell, so add to the list and continue
                     reads.push(rp);
                 } else {
                     //This read belongs to the next cell, so stop reading for now
-                    self.last_rp = Some((cell_id, rp));
+                    self.last_rp = Some((cell_id, rp, alignment));
                     break;
                 }
             }
@@ -200,14 +239,42 @@ impl StreamingReadPairReader for BAMStreamingReadPairReader {
 
 ///////////////////////////////
 /// Given the name of a read, divide into cell ID and UMI
-pub fn readname_to_cell_umi(read_name: &[u8]) -> (&[u8], &[u8]) {
+pub fn readname_to_cell_umi(read_name: &[u8]) -> anyhow::Result<(&[u8], &[u8])> {
     let mut splitter = read_name.split(|b| *b == b':');
-    let cell_id = splitter
-        .next()
-        .expect("Could not parse cellID from read name");
-    let umi = splitter.next().expect("Could not parse UMI from read name");
+    match (splitter.next(), splitter.next()) {
+        (Some(cell_id), Some(umi)) => Ok((cell_id, umi)),
+        _ => anyhow::bail!(
+            "Could not parse cell ID and UMI from read name {}",
+            String::from_utf8_lossy(read_name)
+        ),
+    }
+}
+
+const TAG_UMI: Tag = Tag::new(b'U', b'B');
 
-    (cell_id, umi)
+///////////////////////////////
+/// String value of an aux tag, if the record has it as a string
+fn string_tag(record: &BamRecord, tag: Tag) -> Option<&[u8]> {
+    match record.data().get(&tag)? {
+        Value::String(value) => Some(value.as_ref()),
+        _ => None,
+    }
+}
+
+///////////////////////////////
+/// Cell ID and UMI of a record: the CB/UB tags written by `align`, or for BAMs without a CB tag
+/// the `cell:umi` prefix of the read name
+fn record_to_cell_umi(record: &BamRecord) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    if let Some(cell_id) = string_tag(record, Tag::CELL_BARCODE_ID) {
+        let umi = string_tag(record, TAG_UMI).unwrap_or_default();
+        return Ok((cell_id.to_vec(), umi.to_vec()));
+    }
+    let read_name: &[u8] = record
+        .name()
+        .ok_or_else(|| anyhow::anyhow!("BAM record has neither a CB tag nor a read name"))?
+        .as_ref();
+    let (cell_id, umi) = readname_to_cell_umi(read_name)?;
+    Ok((cell_id.to_vec(), umi.to_vec()))
 }
 
 ///////////////////////////////
@@ -228,9 +295,8 @@ fn complement_base(b: u8) -> u8 {
 
 ///////////////////////////////
 /// Parse one BAM entry into a `BamHalf`, restoring original sequenced orientation
-fn read_to_half(record: &BamRecord) -> BamHalf {
-    let read_name: &[u8] = record.name().expect("missing read name").as_ref();
-    let (cell_id, umi) = readname_to_cell_umi(read_name);
+fn read_to_half(record: &BamRecord) -> anyhow::Result<BamHalf> {
+    let (cell_id, umi) = record_to_cell_umi(record)?;
 
     let flags = record.flags();
     let is_reverse = flags.is_reverse_complemented();
@@ -257,15 +323,29 @@ fn read_to_half(record: &BamRecord) -> BamHalf {
         qual_iter.collect()
     };
 
-    BamHalf {
-        cell_id: cell_id.to_vec(),
-        umi: umi.to_vec(),
+    let matching_bases = record
+        .cigar()
+        .as_ref()
+        .iter()
+        .filter(|op| op.kind() == CigarKind::Match)
+        .map(|op| op.len() as u32)
+        .sum();
+    let alignment = MateAlignment {
+        is_mapped: !flags.is_unmapped(),
+        matching_bases,
+        read_len: seq.len() as u32,
+    };
+
+    Ok(BamHalf {
+        cell_id,
+        umi,
         seq,
         qual,
         is_segmented: flags.is_segmented(),
         is_first: flags.is_first_segment(),
         is_last: flags.is_last_segment(),
-    }
+        alignment,
+    })
 }
 
 #[derive(Debug, Clone)]
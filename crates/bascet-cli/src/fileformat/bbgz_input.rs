@@ -0,0 +1,79 @@
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, bail};
+use bascet_io::codec::{BBGZDecoder, BBGZOnBlockRead};
+use bascet_runtime::report::Progress;
+use bounded_integer::BoundedU64;
+
+///////////////////////////////
+/// Path that stands for stdin, following the usual command line convention (and `clio`)
+pub const STDIN_PATH: &str = "-";
+
+///////////////////////////////
+/// True if the input path means stdin rather than a file
+pub fn is_stdin_path(path: &Path) -> bool {
+    path.as_os_str() == STDIN_PATH
+}
+
+///////////////////////////////
+/// Progress of reading one input. Files are sized up front, so the progress events carry an
+/// ETA; stdin and pipes only report the bytes read so far
+pub fn input_progress(path: &Path, byte_range: Option<&Range<u64>>) -> Arc<Progress> {
+    let total_bytes_in = match byte_range {
+        Some(range) => Some(range.end.saturating_sub(range.start)),
+        None => std::fs::metadata(path)
+            .ok()
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len()),
+    };
+    Progress::new(format!("read {}", path.display()))
+        .with_total_bytes_in(total_bytes_in)
+        .start()
+}
+
+///////////////////////////////
+/// Count the compressed bytes of every block read into `progress`
+pub fn on_block_read_progress(progress: Arc<Progress>) -> BBGZOnBlockRead {
+    Arc::new(move |sizeof_block| progress.add(0, sizeof_block, 0))
+}
+
+///////////////////////////////
+/// Open a BBGZ input for streaming, reporting its progress. `-` reads stdin; named pipes and
+/// process substitutions are opened like files but only read front to back, so they cannot take
+/// a byte range
+pub fn open_bbgz_decoder(
+    path: &Path,
+    countof_threads: BoundedU64<1, { u64::MAX }>,
+    byte_range: Option<Range<u64>>,
+) -> anyhow::Result<BBGZDecoder> {
+    if is_stdin_path(path) {
+        if byte_range.is_some() {
+            bail!("A byte range needs a seekable input file, not stdin");
+        }
+        return Ok(BBGZDecoder::from_reader()
+            .with_reader(std::io::stdin())
+            .countof_threads(countof_threads)
+            .with_opt_on_block_read(on_block_read_progress(input_progress(path, None)))
+            .build());
+    }
+
+    // NOTE:    stat rather than open, as opening a FIFO twice would race its writer
+    let metadata = std::fs::metadata(path)
+        .with_context(|| format!("Cannot open input '{}'", path.display()))?;
+    if byte_range.is_some() && !metadata.is_file() {
+        bail!(
+            "A byte range needs a seekable input file, but '{}' is a pipe or device",
+            path.display()
+        );
+    }
+
+    let progress = input_progress(path, byte_range.as_ref());
+    BBGZDecoder::builder()
+        .with_path(path)
+        .countof_threads(countof_threads)
+        .maybe_with_opt_byte_range(byte_range)
+        .with_opt_on_block_read(on_block_read_progress(progress))
+        .build()
+}
@@ -1,4 +1,5 @@
 use anyhow::bail;
+use bascet_io::BlockFormat;
 use std::fs::File;
 use std::path::PathBuf;
 use tracing::info;
@@ -25,7 +26,7 @@ pub fn detect_shard_format(p: &PathBuf) -> DetectedFileformat {
         .expect("cannot convert OS string when detecting file format")
         .to_string_lossy();
 
-    if p_string.ends_with(".tirp.gz") {
+    if p_string.ends_with(".tirp.gz") || p_string.ends_with(".tirp.zst") {
         DetectedFileformat::TIRP
     } else if p_string.ends_with(".zip") {
         DetectedFileformat::ZIP
@@ -56,6 +57,9 @@ pub fn get_suitable_file_extractor(
     format: &DetectedFileformat,
 ) -> Box<dyn ShardRandomFileExtractor> {
     match format {
+        DetectedFileformat::TIRP if BlockFormat::from_path(p) == BlockFormat::Zst => {
+            panic!("zstd TIRP cannot be used for file extraction currently, it has no tabix index")
+        }
         DetectedFileformat::TIRP => {
             Box::new(TirpBascetShardReader::new(&p).expect("Failed to create TIRP reader"))
         }
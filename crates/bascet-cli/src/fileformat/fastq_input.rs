@@ -0,0 +1,342 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{Context, bail};
+use bascet_core::{Decode, DecodeResult};
+use bascet_io::codec::{BBGZDecoder, PlaintextDecoder};
+use bascet_runtime::report::Progress;
+
+use crate::command::samtools_rs::bam::{Header, Record, decode_qual, decode_seq};
+use crate::command::samtools_rs::bgzf;
+use crate::fileformat::bbgz_input::on_block_read_progress;
+
+const BAM_FREVERSE: u16 = 0x10;
+const BAM_FSECONDARY_OR_SUPPLEMENTARY: u16 = 0x900;
+
+///////////////////////////////
+/// Container of a read input, detected from its magic bytes rather than its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastqInputFormat {
+    /// Block-gzipped FASTQ (BGZF/BBGZ); decoded in parallel
+    Bgzf,
+    /// Ordinary (multi-member) gzip FASTQ
+    Gzip,
+    Zstd,
+    Bzip2,
+    /// Uncompressed FASTQ
+    Plain,
+    /// Unaligned BAM as written by ONT/PacBio basecallers or Picard FastqToSam
+    UnalignedBam,
+}
+
+impl FastqInputFormat {
+    ///////////////////////////////
+    /// Sniff the format of a read input
+    pub fn detect(path: &Path) -> anyhow::Result<Self> {
+        let mut file =
+            File::open(path).with_context(|| format!("Cannot open input '{}'", path.display()))?;
+        let mut magic = Vec::with_capacity(4);
+        (&mut file).take(4).read_to_end(&mut magic)?;
+        if magic.is_empty() {
+            bail!("Input '{}' is empty", path.display());
+        }
+
+        let format = match magic.as_slice() {
+            [0x1f, 0x8b, 0x08, flags, ..] if flags & 0x04 != 0 && has_bgzf_header(path)? => {
+                let mut reader = bgzf::Reader::new(BufReader::new(File::open(path)?));
+                let mut payload_magic = [0u8; 4];
+                match reader.read_exact(&mut payload_magic) {
+                    Ok(()) if &payload_magic == b"BAM\x01" => Self::UnalignedBam,
+                    _ => Self::Bgzf,
+                }
+            }
+            [0x1f, 0x8b, ..] => Self::Gzip,
+            [0x28, 0xb5, 0x2f, 0xfd] => Self::Zstd,
+            [b'B', b'Z', b'h', ..] => Self::Bzip2,
+            [b'@', ..] => Self::Plain,
+            _ => bail!(
+                "Input '{}' is not FASTQ (plain, gzip, bgzip, zstd or bzip2) or unaligned BAM",
+                path.display()
+            ),
+        };
+        Ok(format)
+    }
+}
+
+///////////////////////////////
+/// True if the first gzip member carries a BGZF/BBGZ `BC` block-size subfield
+fn has_bgzf_header(path: &Path) -> anyhow::Result<bool> {
+    let mut file = File::open(path)?;
+    let mut base = [0u8; 12];
+    if file.read_exact(&mut base).is_err() {
+        return Ok(false);
+    }
+    let xlen = u16::from_le_bytes([base[10], base[11]]) as usize;
+    let mut extra = vec![0u8; xlen];
+    if file.read_exact(&mut extra).is_err() {
+        return Ok(false);
+    }
+    Ok(has_bgzf_bsize_extra(&extra))
+}
+
+pub fn has_bgzf_bsize_extra(extra: &[u8]) -> bool {
+    let mut cursor = 0usize;
+    while cursor + 4 <= extra.len() {
+        let si1 = extra[cursor];
+        let si2 = extra[cursor + 1];
+        let slen = u16::from_le_bytes([extra[cursor + 2], extra[cursor + 3]]) as usize;
+        cursor += 4;
+
+        if cursor + slen > extra.len() {
+            return false;
+        }
+        if si1 == b'B' && si2 == b'C' && slen == 2 {
+            return true;
+        }
+        cursor += slen;
+    }
+    false
+}
+
+///////////////////////////////
+/// Decoder yielding FASTQ text from any `FastqInputFormat`. Block-gzipped input goes through
+/// the parallel `BBGZDecoder`; everything else is a sequential stream
+pub enum FastqInputDecoder {
+    Bbgz(BBGZDecoder),
+    Stream(PlaintextDecoder<Box<dyn Read + Send>>),
+}
+
+impl FastqInputDecoder {
+    /// Open `path`, counting the bytes read from disk into `progress` if given
+    pub fn open(
+        path: &Path,
+        format: FastqInputFormat,
+        rayon_pool: Option<Arc<rayon::ThreadPool>>,
+        progress: Option<Arc<Progress>>,
+    ) -> anyhow::Result<Self> {
+        let reader: Box<dyn Read + Send> = match format {
+            FastqInputFormat::Bgzf => {
+                return Ok(Self::Bbgz(
+                    BBGZDecoder::builder()
+                        .with_path(path)
+                        .maybe_with_opt_rayon_pool(rayon_pool)
+                        .maybe_with_opt_on_block_read(progress.map(on_block_read_progress))
+                        .build()?,
+                ));
+            }
+            FastqInputFormat::UnalignedBam => {
+                let threads = rayon_pool
+                    .as_ref()
+                    .map(|pool| pool.current_num_threads())
+                    .unwrap_or(1);
+                let file = File::open(path)
+                    .with_context(|| format!("Cannot open input '{}'", path.display()))?;
+                Box::new(UnalignedBamFastqReader::new(bgzf::ParallelReader::new(
+                    ProgressReader::new(file, progress),
+                    threads,
+                ))?)
+            }
+            FastqInputFormat::Gzip
+            | FastqInputFormat::Zstd
+            | FastqInputFormat::Bzip2
+            | FastqInputFormat::Plain => {
+                let file = File::open(path)
+                    .with_context(|| format!("Cannot open input '{}'", path.display()))?;
+                let file = ProgressReader::new(file, progress);
+                let (reader, _) = niffler::send::get_reader(Box::new(BufReader::new(file)))
+                    .with_context(|| format!("Cannot decompress input '{}'", path.display()))?;
+                reader
+            }
+        };
+        Ok(Self::Stream(
+            PlaintextDecoder::builder().with_reader(reader).build(),
+        ))
+    }
+}
+
+impl Decode for FastqInputDecoder {
+    fn sizeof_target_alloc(&self) -> usize {
+        match self {
+            Self::Bbgz(decoder) => decoder.sizeof_target_alloc(),
+            Self::Stream(decoder) => decoder.sizeof_target_alloc(),
+        }
+    }
+
+    fn decode_into<B: AsMut<[u8]>>(&mut self, buf: B) -> DecodeResult {
+        match self {
+            Self::Bbgz(decoder) => decoder.decode_into(buf),
+            Self::Stream(decoder) => decoder.decode_into(buf),
+        }
+    }
+}
+
+///////////////////////////////
+/// Counts the bytes read through it into a `Progress`
+struct ProgressReader<R: Read> {
+    inner: R,
+    progress: Option<Arc<Progress>>,
+}
+
+impl<R: Read> ProgressReader<R> {
+    fn new(inner: R, progress: Option<Arc<Progress>>) -> Self {
+        Self { inner, progress }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(progress) = &self.progress {
+            progress.add(0, n as u64, 0);
+        }
+        Ok(n)
+    }
+}
+
+///////////////////////////////
+/// Renders an unaligned BAM as FASTQ text. Secondary/supplementary records are dropped and
+/// reverse-strand records are restored to sequenced orientation; records are otherwise emitted
+/// in file order, so a paired uBAM reads as interleaved FASTQ
+pub struct UnalignedBamFastqReader<R: Read> {
+    inner: R,
+    pending: Vec<u8>,
+    pending_pos: usize,
+    record_buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: Read> UnalignedBamFastqReader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        Header::read(&mut inner)?;
+        Ok(Self {
+            inner,
+            pending: Vec::with_capacity(64 * 1024),
+            pending_pos: 0,
+            record_buf: Vec::new(),
+            eof: false,
+        })
+    }
+
+    fn refill(&mut self) -> io::Result<()> {
+        self.pending.clear();
+        self.pending_pos = 0;
+        while !self.eof && self.pending.len() < 64 * 1024 {
+            let buf = std::mem::take(&mut self.record_buf);
+            let Some(record) = Record::read_into(&mut self.inner, buf)? else {
+                self.eof = true;
+                break;
+            };
+            let flag = record.flag();
+            if flag & BAM_FSECONDARY_OR_SUPPLEMENTARY == 0 {
+                let reverse = flag & BAM_FREVERSE != 0;
+                self.pending.push(b'@');
+                self.pending.extend_from_slice(record.read_name());
+                self.pending.push(b'\n');
+                self.pending.extend_from_slice(&decode_seq(
+                    record.seq_raw(),
+                    record.l_seq() as usize,
+                    reverse,
+                ));
+                self.pending.extend_from_slice(b"\n+\n");
+                self.pending
+                    .extend_from_slice(&decode_qual(record.qual(), reverse));
+                self.pending.push(b'\n');
+            }
+            self.record_buf = record.data;
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for UnalignedBamFastqReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending_pos == self.pending.len() {
+            self.refill()?;
+        }
+        let remaining = &self.pending[self.pending_pos..];
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.pending_pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn unaligned_bam(records: &[(&[u8], u16, &[u8], &[u8])]) -> Vec<u8> {
+        let mut bam = Vec::new();
+        bam.extend_from_slice(b"BAM\x01");
+        bam.extend_from_slice(&0i32.to_le_bytes());
+        bam.extend_from_slice(&0i32.to_le_bytes());
+        for (name, flag, seq, qual) in records {
+            Record::test_unmapped(name, *flag, seq, qual, &[])
+                .write(&mut bam)
+                .unwrap();
+        }
+        bam
+    }
+
+    #[test]
+    fn unaligned_bam_renders_as_fastq() {
+        let bam = unaligned_bam(&[
+            (b"read1", 0x4, b"ACGTA", b"IIIII"),
+            (b"read1", 0x904, b"AAAAA", b"IIIII"),
+            (b"read2", 0x14, b"AACG", b"ABCD"),
+        ]);
+        let mut fastq = String::new();
+        UnalignedBamFastqReader::new(io::Cursor::new(bam))
+            .unwrap()
+            .read_to_string(&mut fastq)
+            .unwrap();
+        assert_eq!(fastq, "@read1\nACGTA\n+\nIIIII\n@read2\nCGTT\n+\nDCBA\n");
+    }
+
+    #[test]
+    fn detect_uses_magic_bytes_not_extensions() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, bytes: &[u8]| {
+            let path = dir.path().join(name);
+            File::create(&path).unwrap().write_all(bytes).unwrap();
+            path
+        };
+
+        let plain = write("reads.fq.gz", b"@r\nACGT\n+\nIIII\n");
+        assert_eq!(
+            FastqInputFormat::detect(&plain).unwrap(),
+            FastqInputFormat::Plain
+        );
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gz.write_all(b"@r\nACGT\n+\nIIII\n").unwrap();
+        let gzip = write("reads.fastq", &gz.finish().unwrap());
+        assert_eq!(
+            FastqInputFormat::detect(&gzip).unwrap(),
+            FastqInputFormat::Gzip
+        );
+
+        let zstd = write("reads.zst", &[0x28, 0xb5, 0x2f, 0xfd, 0, 0]);
+        assert_eq!(
+            FastqInputFormat::detect(&zstd).unwrap(),
+            FastqInputFormat::Zstd
+        );
+
+        let mut bgzf_bytes = Vec::new();
+        let mut w = bgzf::Writer::new(&mut bgzf_bytes, 6);
+        w.write_all(&unaligned_bam(&[(b"r", 0x4, b"AC", b"II")]))
+            .unwrap();
+        w.finish().unwrap();
+        let ubam = write("reads.fq.gz", &bgzf_bytes);
+        assert_eq!(
+            FastqInputFormat::detect(&ubam).unwrap(),
+            FastqInputFormat::UnalignedBam
+        );
+
+        assert!(FastqInputFormat::detect(&write("empty.fq", b"")).is_err());
+        assert!(FastqInputFormat::detect(&write("junk.fq", b">fasta\n")).is_err());
+    }
+}
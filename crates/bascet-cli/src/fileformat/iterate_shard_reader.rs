@@ -2,11 +2,13 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::Mutex;
 
-use anyhow::bail;
+use anyhow::{Context, bail};
+use bascet_runtime::budget::MemoryLimiter;
 use bytesize::ByteSize;
 use tracing::info;
 
 use crate::fileformat::ReadPair;
+use crate::fileformat::bbgz_input::open_bbgz_decoder;
 use crate::fileformat::inmem_readpairs::ShardFileExtractorInmem;
 use crate::fileformat::tirp::TirpBascetShardReaderFactory;
 use crate::fileformat::zip::ZipBascetShardReaderFactory;
@@ -24,7 +26,8 @@ use bascet_core::{
 };
 
 ///
-/// General interface to all types of readers, enabling iteration over shard-type files
+/// General interface to all types of readers, enabling iteration over shard-type files.
+/// If given, streamed cells are charged against `cell_memory_limiter` while `run_func` holds them
 ///
 pub fn iterate_shard_reader_multithreaded(
     threads_read: usize,
@@ -33,6 +36,7 @@ pub fn iterate_shard_reader_multithreaded(
     sizeof_stream_arena: ByteSize,
     sizeof_stream_buffer: ByteSize,
     num_threads: usize, //BoundedU64<1, { u64::MAX }>,
+    cell_memory_limiter: Option<Arc<MemoryLimiter>>,
 
     run_func: &Arc<
         impl Fn((String, &mut Box<&mut dyn ShardFileExtractor>)) + Sync + Send + 'static,
@@ -56,15 +60,16 @@ pub fn iterate_shard_reader_multithreaded(
             info!("Detected input as TIRP");
             for _tidx in 0..threads_read {
                 /////////// option #2: keep list of files separately from list of readers
-                _ = create_streaming_tirp_reader(
+                create_streaming_tirp_reader(
                     ///////////////////////////////////////////////////// Note: Use Bascet 2.x TIRP-specific streamer here
                     &path_in,
                     &thread_pool_readers,
                     sizeof_stream_arena,
                     sizeof_stream_buffer,
                     num_threads,
+                    cell_memory_limiter.clone(),
                     &run_func,
-                );
+                )?;
             }
         } else {
             bail!("Cannot tell the type of the input format"); /////////////////////////// TODO add support for BAM etc as a shardreader
@@ -80,7 +85,7 @@ pub fn iterate_shard_reader_multithreaded(
 
         //Figure out what cells there are to process - get all of them by default
         let list_cells = fileformat::try_get_cells_in_file(&path_in)
-            .expect("Could not get list of cells from input file");
+            .context("Could not get list of cells from input file")?;
         let list_cells = if let Some(list_cells) = list_cells {
             list_cells
         } else {
@@ -261,6 +266,7 @@ fn create_streaming_tirp_reader(
     sizeof_stream_arena: ByteSize,
     sizeof_stream_buffer: ByteSize,
     num_threads: usize, //BoundedU64<1, { u64::MAX }>,
+    cell_memory_limiter: Option<Arc<MemoryLimiter>>,
     run_func: &Arc<
         impl Fn((String, &mut Box<&mut dyn ShardFileExtractor>)) + Sync + Send + 'static,
     >,
@@ -268,13 +274,10 @@ fn create_streaming_tirp_reader(
     let path_in = path_in.clone();
     let run_func = Arc::clone(run_func);
     let num_threads = bounded_integer::BoundedU64::new(num_threads as u64).unwrap();
+    let decoder: bascet_io::BBGZDecoder = open_bbgz_decoder(&path_in, num_threads, None)?;
 
     thread_pool.execute(move || {
         // Streamer from input TIRP
-        let decoder: bascet_io::BBGZDecoder = bascet_io::codec::BBGZDecoder::builder()
-            .with_path(&path_in)
-            .countof_threads(num_threads)
-            .build();
         let parser = bascet_io::parse::Tirp::builder().build();
 
         let mut stream = bascet_core::Stream::builder()
@@ -290,6 +293,7 @@ fn create_streaming_tirp_reader(
         let mut num_proc_cell: u64 = 0;
         let mut last_cellid = Vec::new();
         let mut cur_rps: Vec<ReadPair> = Vec::new();
+        let mut sizeof_cur_rps: usize = 0;
 
         loop {
             match query.next_into::<bascet_io::tirp::Record>() {
@@ -308,6 +312,12 @@ fn create_streaming_tirp_reader(
                         q2: record_q2.to_vec(),
                         umi: record_umi.to_vec(),
                     };
+                    let sizeof_rp = std::mem::size_of::<ReadPair>()
+                        + rp.r1.len()
+                        + rp.r2.len()
+                        + rp.q1.len()
+                        + rp.q2.len()
+                        + rp.umi.len();
 
                     //Send records to process if we got them all
                     if record_id != last_cellid.as_slice() {
@@ -316,6 +326,12 @@ fn create_streaming_tirp_reader(
                             cur_rps = Vec::new();
                             let cellid = String::from_utf8_lossy(last_cellid.as_slice());
 
+                            //Wait for room in the budget before handing over another cell
+                            let _permit = cell_memory_limiter
+                                .as_ref()
+                                .map(|limiter| limiter.acquire(sizeof_cur_rps));
+                            sizeof_cur_rps = 0;
+
                             let mut dat = ShardFileExtractorInmem {
                                 cellid: cellid.to_string(),
                                 rp: prev_cur_rps,
@@ -330,6 +346,7 @@ fn create_streaming_tirp_reader(
                         last_cellid = record_id.to_vec();
                     }
                     cur_rps.push(rp);
+                    sizeof_cur_rps += sizeof_rp;
                 }
                 Ok(None) => {
                     break;
@@ -343,6 +360,9 @@ fn create_streaming_tirp_reader(
         //Send final records to process
         if cur_rps.len() > 0 {
             let cellid = String::from_utf8_lossy(last_cellid.as_slice());
+            let _permit = cell_memory_limiter
+                .as_ref()
+                .map(|limiter| limiter.acquire(sizeof_cur_rps));
             let mut dat = ShardFileExtractorInmem {
                 cellid: cellid.to_string(),
                 rp: cur_rps,
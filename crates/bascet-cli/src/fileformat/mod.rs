@@ -1,5 +1,7 @@
 ////// File formats
+pub mod bbgz_input;
 pub mod cram;
+pub mod fastq_input;
 pub mod list_fastq;
 pub mod paired_fastq;
 pub mod shard;
@@ -19,6 +21,7 @@ mod detect_fileformat;
 pub mod uuencode;
 
 pub mod new_anndata;
+pub mod sample_sheet;
 
 pub mod iterate_shard_reader;
 
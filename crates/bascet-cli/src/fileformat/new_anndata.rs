@@ -5,6 +5,9 @@ use std::path::PathBuf;
 
 use ahash::AHashMap;
 use tracing::info;
+use tracing::warn;
+
+use crate::fileformat::sample_sheet::SampleAnnotations;
 
 use hdf5::WritableFile as H5File;
 use hdf5::hl::writable_file::WritableGroup;
@@ -12,6 +15,9 @@ use hdf5::hl::writable_file::WritableGroup;
 type Cellid = Vec<u8>;
 type Featureid = Vec<u8>;
 
+/// Rough heap cost of one map entry beyond its key bytes: node slot, `Vec` header and index
+const SIZEOF_MAP_ENTRY: usize = 48;
+
 use sprs::{CsMat, TriMat};
 
 ///
@@ -42,6 +48,9 @@ pub struct SparseMatrixAnnDataBuilder {
     /// this could easily be a hashset instead TODO
     cur_num_cell: u32,
     cur_num_feature: u32,
+    /// Heap bytes of the cell and feature names, for `sizeof_heap`
+    sizeof_ids: usize,
+    sample_annotations: SampleAnnotations,
 }
 impl SparseMatrixAnnDataBuilder {
     pub fn new() -> Self {
@@ -52,9 +61,27 @@ impl SparseMatrixAnnDataBuilder {
             map_cell_unclassified_count: BTreeMap::new(),
             cur_num_cell: 0,
             cur_num_feature: 0,
+            sizeof_ids: 0,
+            sample_annotations: SampleAnnotations::default(),
         }
     }
 
+    ///
+    /// Add the sample sheet columns of each cell's library to the saved obs
+    ///
+    pub fn attach_sample_annotations(&mut self, sample_annotations: SampleAnnotations) {
+        self.sample_annotations = sample_annotations;
+    }
+
+    ///
+    /// Approximate heap size of the matrix so far, to charge it against a memory budget
+    ///
+    pub fn sizeof_heap(&self) -> usize {
+        self.entries.capacity() * std::mem::size_of::<(u32, u32, u32)>()
+            + self.sizeof_ids
+            + self.map_cell_unclassified_count.len() * SIZEOF_MAP_ENTRY
+    }
+
     ///
     /// Features may have been added before. Try to recover index of cell, or create it
     ///
@@ -65,6 +92,7 @@ impl SparseMatrixAnnDataBuilder {
             let i = self.cur_num_feature;
             self.feature_to_index.insert(id.to_vec(), i);
             self.cur_num_feature += 1;
+            self.sizeof_ids += id.len() + SIZEOF_MAP_ENTRY;
             i
         }
     }
@@ -79,6 +107,7 @@ impl SparseMatrixAnnDataBuilder {
             let i = self.cur_num_cell;
             self.cell_to_index.insert(id.to_vec(), i);
             self.cur_num_cell += 1;
+            self.sizeof_ids += id.len() + SIZEOF_MAP_ENTRY;
             i
         }
     }
@@ -242,6 +271,7 @@ impl SparseMatrixAnnDataBuilder {
         info!("Saving count matrix");
 
         let mut file = SparseMatrixAnnDataWriter::create_anndata(p)?;
+        file.attach_sample_annotations(self.sample_annotations.clone());
 
         file.store_sparse_count_matrix(&csr_mat, n_rows, n_cols)?;
 
@@ -285,6 +315,7 @@ impl SparseMatrixAnnDataBuilder {
 ///
 pub struct SparseMatrixAnnDataWriter {
     file: hdf5::WritableFile,
+    sample_annotations: Option<SampleAnnotations>,
 }
 impl SparseMatrixAnnDataWriter {
     ///
@@ -298,7 +329,43 @@ impl SparseMatrixAnnDataWriter {
         file.add_fixed_utf8_attr("encoding-type", "anndata", "anndata".len())?;
         file.add_fixed_utf8_attr("encoding-version", "0.1.0", "0.1.0".len())?;
 
-        Ok(SparseMatrixAnnDataWriter { file: file })
+        Ok(SparseMatrixAnnDataWriter {
+            file: file,
+            sample_annotations: None,
+        })
+    }
+
+    ///
+    /// Add the sample sheet columns of each cell's library to every obs written afterwards
+    ///
+    pub fn attach_sample_annotations(&mut self, sample_annotations: SampleAnnotations) {
+        if !sample_annotations.is_empty() {
+            self.sample_annotations = Some(sample_annotations);
+        }
+    }
+
+    ///
+    /// Sample columns for these cells, leaving out any whose name is already taken
+    ///
+    fn sample_obs_columns(
+        &self,
+        list_cell_names: &[String],
+        taken: &[&str],
+    ) -> Vec<(String, Vec<String>)> {
+        let Some(sample_annotations) = &self.sample_annotations else {
+            return Vec::new();
+        };
+        sample_annotations
+            .obs_columns(list_cell_names)
+            .into_iter()
+            .filter(|(name, _)| {
+                let clash = taken.contains(&name.as_str());
+                if clash {
+                    warn!(column = %name, "Sample column clashes with an obs column; not stored");
+                }
+                !clash
+            })
+            .collect()
     }
 
     pub fn close(self) -> anyhow::Result<()> {
@@ -321,6 +388,31 @@ impl SparseMatrixAnnDataWriter {
         Ok(())
     }
 
+    ///
+    /// Store feature IDs as the index, with a human-readable symbol column (e.g. gene names)
+    ///
+    pub fn store_feature_names_with_symbols(
+        &mut self,
+        list_feature_ids: &Vec<String>,
+        list_feature_symbols: &Vec<String>,
+    ) -> anyhow::Result<()> {
+        let mut group = self.file.create_group("var")?;
+        Self::add_dataframe_attrs(&mut group, &["symbol"])?;
+        let list_feature_ids = strings_as_strs(list_feature_ids);
+        group
+            .new_dataset_builder("_index")
+            .fixed_utf8_attr("encoding-type", "string-array", "string-array".len())?
+            .fixed_utf8_attr("encoding-version", "0.2.0", "0.2.0".len())?
+            .write_vlen_utf8_strings(&list_feature_ids)?;
+        let list_feature_symbols = strings_as_strs(list_feature_symbols);
+        group
+            .new_dataset_builder("symbol")
+            .fixed_utf8_attr("encoding-type", "string-array", "string-array".len())?
+            .fixed_utf8_attr("encoding-version", "0.2.0", "0.2.0".len())?
+            .write_vlen_utf8_strings(&list_feature_symbols)?;
+        Ok(())
+    }
+
     ///
     /// x
     ///
@@ -329,13 +421,19 @@ impl SparseMatrixAnnDataWriter {
         list_cell_names: &Vec<String>,
         list_cell_unmapped: Option<&Vec<u32>>,
     ) -> anyhow::Result<()> {
-        let mut group = self.file.create_group("obs")?;
         let columns = if list_cell_unmapped.is_some() {
             ["_unmapped"].as_slice()
         } else {
             [].as_slice()
         };
-        Self::add_dataframe_attrs(&mut group, columns)?;
+        let sample_columns = self.sample_obs_columns(list_cell_names, columns);
+        let column_order: Vec<&str> = columns
+            .iter()
+            .copied()
+            .chain(sample_columns.iter().map(|(name, _)| name.as_str()))
+            .collect();
+        let mut group = self.file.create_group("obs")?;
+        Self::add_dataframe_attrs(&mut group, &column_order)?;
 
         //Store names of cells
         let list_cell_names = strings_as_strs(list_cell_names);
@@ -354,6 +452,14 @@ impl SparseMatrixAnnDataWriter {
                 .write(list_cell_unmapped.as_slice())?;
         }
 
+        for (name, values) in &sample_columns {
+            group
+                .new_dataset_builder(name)
+                .fixed_utf8_attr("encoding-type", "string-array", "string-array".len())?
+                .fixed_utf8_attr("encoding-version", "0.2.0", "0.2.0".len())?
+                .write_vlen_utf8_strings(&strings_as_strs(values))?;
+        }
+
         Ok(())
     }
 
@@ -362,8 +468,35 @@ impl SparseMatrixAnnDataWriter {
         list_cell_names: &Vec<String>,
         columns: &[(&str, Vec<f64>)],
     ) -> anyhow::Result<()> {
+        self.store_cell_obs(list_cell_names, columns, &[])
+    }
+
+    ///
+    /// Store obs as numeric columns followed by string columns
+    ///
+    pub fn store_cell_obs(
+        &mut self,
+        list_cell_names: &Vec<String>,
+        columns: &[(&str, Vec<f64>)],
+        string_columns: &[(&str, Vec<String>)],
+    ) -> anyhow::Result<()> {
+        let mut column_order: Vec<&str> = columns
+            .iter()
+            .map(|(name, _)| *name)
+            .chain(string_columns.iter().map(|(name, _)| *name))
+            .collect();
+        let sample_columns = self.sample_obs_columns(list_cell_names, &column_order);
+        column_order.extend(sample_columns.iter().map(|(name, _)| name.as_str()));
+        let string_columns: Vec<(&str, &Vec<String>)> = string_columns
+            .iter()
+            .map(|(name, values)| (*name, values))
+            .chain(
+                sample_columns
+                    .iter()
+                    .map(|(name, values)| (name.as_str(), values)),
+            )
+            .collect();
         let mut group = self.file.create_group("obs")?;
-        let column_order: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
         Self::add_dataframe_attrs(&mut group, &column_order)?;
 
         let list_cell_names = strings_as_strs(list_cell_names);
@@ -389,6 +522,22 @@ impl SparseMatrixAnnDataWriter {
                 .write(values.as_slice())?;
         }
 
+        for (name, values) in string_columns {
+            if values.len() != list_cell_names.len() {
+                anyhow::bail!(
+                    "obs column {} has {} values for {} cells",
+                    name,
+                    values.len(),
+                    list_cell_names.len()
+                );
+            }
+            group
+                .new_dataset_builder(name)
+                .fixed_utf8_attr("encoding-type", "string-array", "string-array".len())?
+                .fixed_utf8_attr("encoding-version", "0.2.0", "0.2.0".len())?
+                .write_vlen_utf8_strings(&strings_as_strs(values))?;
+        }
+
         Ok(())
     }
 
@@ -473,6 +622,42 @@ impl SparseMatrixAnnDataWriter {
         n_rows: u32,
         n_cols: u32,
     ) -> anyhow::Result<()>
+    where
+        X: hdf5::H5Type,
+    {
+        //Store the sparse matrix here
+        let mut group = self.file.create_group("X")?;
+        Self::write_csr_group(&mut group, csr_mat, n_rows, n_cols)
+    }
+
+    ///
+    /// Store additional matrices of the same shape as X, under layers/<name>
+    ///
+    pub fn store_sparse_count_layers<X>(
+        &mut self,
+        layers: &[(&str, &CsMat<X>)],
+        n_rows: u32,
+        n_cols: u32,
+    ) -> anyhow::Result<()>
+    where
+        X: hdf5::H5Type,
+    {
+        let mut group = self.file.create_group("layers")?;
+        group.add_fixed_utf8_attr("encoding-type", "dict", "dict".len())?;
+        group.add_fixed_utf8_attr("encoding-version", "0.1.0", "0.1.0".len())?;
+        for (name, csr_mat) in layers {
+            let mut layer = group.create_group(name)?;
+            Self::write_csr_group(&mut layer, csr_mat, n_rows, n_cols)?;
+        }
+        Ok(())
+    }
+
+    fn write_csr_group<X>(
+        group: &mut WritableGroup<'_>,
+        csr_mat: &CsMat<X>,
+        n_rows: u32,
+        n_cols: u32,
+    ) -> anyhow::Result<()>
     where
         X: hdf5::H5Type,
     {
@@ -487,8 +672,6 @@ impl SparseMatrixAnnDataWriter {
             .map(|x| *x as u64)
             .collect();
 
-        //Store the sparse matrix here
-        let mut group = self.file.create_group("X")?;
         group.add_fixed_utf8_attr("encoding-type", "csr_matrix", "csr_matrix".len())?;
         group.add_fixed_utf8_attr("encoding-version", "0.1.0", "0.1.0".len())?;
         group.add_attr_array("shape", &[i64::from(n_rows), i64::from(n_cols)])?;
@@ -537,6 +720,54 @@ impl SparseMatrixAnnDataWriter {
     }
 }
 
+///
+/// Reader for AnnData files with a CSR count matrix, as written by SparseMatrixAnnDataBuilder
+///
+pub struct SparseMatrixAnnDataReader {
+    file: hdf5::File,
+}
+impl SparseMatrixAnnDataReader {
+    pub fn open_anndata(p: &PathBuf) -> anyhow::Result<SparseMatrixAnnDataReader> {
+        let file = hdf5::File::open(p)?;
+        Ok(SparseMatrixAnnDataReader { file: file })
+    }
+
+    ///
+    /// Names of cells (obs index)
+    ///
+    pub fn read_cell_names(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.file.dataset("obs/_index")?.read_strings()?)
+    }
+
+    ///
+    /// Names of features (var index)
+    ///
+    pub fn read_feature_names(&self) -> anyhow::Result<Vec<String>> {
+        Ok(self.file.dataset("var/_index")?.read_strings()?)
+    }
+
+    ///
+    /// Count matrix X, cells x features
+    ///
+    pub fn read_sparse_count_matrix(
+        &self,
+        n_rows: usize,
+        n_cols: usize,
+    ) -> anyhow::Result<CsMat<u32>> {
+        let mat_data: Vec<u32> = self.file.dataset("X/data")?.read::<u32>()?;
+        let mat_indices: Vec<u64> = self.file.dataset("X/indices")?.read::<u64>()?;
+        let mat_indptr: Vec<u64> = self.file.dataset("X/indptr")?.read::<u64>()?;
+
+        CsMat::try_new(
+            (n_rows, n_cols),
+            mat_indptr.into_iter().map(|x| x as usize).collect(),
+            mat_indices.into_iter().map(|x| x as usize).collect(),
+            mat_data,
+        )
+        .map_err(|(_, _, _, e)| anyhow::anyhow!("invalid count matrix X: {}", e))
+    }
+}
+
 fn strings_as_strs(values: &[String]) -> Vec<&str> {
     values.iter().map(String::as_str).collect()
 }
@@ -164,7 +164,7 @@ impl PairedFastqStreamingReadPairReader {
             let r1 = r1.as_ref().expect("Error reading record r1");
             let r2 = r2.unwrap().expect("Error reading record r2");
 
-            let (cell_id, umi) = bam::readname_to_cell_umi(r1.head());
+            let (cell_id, umi) = bam::readname_to_cell_umi(r1.head())?;
             Some((
                 cell_id.to_vec(),
                 ReadPair {
@@ -211,7 +211,7 @@ impl StreamingReadPairReader for PairedFastqStreamingReadPairReader {
 
                 let r2 = some_r2.unwrap().expect("Error reading record r2");
 
-                let (cell_id, umi) = bam::readname_to_cell_umi(r1.head());
+                let (cell_id, umi) = bam::readname_to_cell_umi(r1.head())?;
 
                 let rp = ReadPair {
                     r1: r1.seq().to_vec(),
@@ -0,0 +1,349 @@
+//! Sample sheets for multi-library debarcoding, and the per-cell sample annotations they leave
+//! in the metadata of the debarcoded TIRP.
+//!
+//! A sample sheet is a CSV with a header row. `sample` and `r1` are required; `library`
+//! (defaults to the sample name), `chemistry`, `subchemistry` and `r2` are optional. Several
+//! FASTQ files for one mate are separated by `;`, and relative paths are taken relative to the
+//! sheet. Every other column is free metadata attached to the cells of that library.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use bascet_io::{BBGZMetadata, METADATA_SAMPLE_PREFIX};
+
+const RESERVED_COLUMNS: &[&str] = &["sample", "library", "chemistry", "subchemistry", "r1", "r2"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleSheetRow {
+    pub sample: String,
+    pub library: String,
+    pub chemistry: Option<String>,
+    pub subchemistry: Option<String>,
+    pub paths_r1: Vec<PathBuf>,
+    pub paths_r2: Vec<PathBuf>,
+    /// Free columns, in sheet order
+    pub metadata: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleSheet {
+    pub rows: Vec<SampleSheetRow>,
+}
+
+impl SampleSheet {
+    pub fn read(path: &Path) -> anyhow::Result<SampleSheet> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("failed to open sample sheet {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        Self::parse(file, base_dir)
+            .with_context(|| format!("invalid sample sheet {}", path.display()))
+    }
+
+    pub fn parse(src: impl Read, base_dir: &Path) -> anyhow::Result<SampleSheet> {
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(b',')
+            .comment(Some(b'#'))
+            .trim(csv::Trim::All)
+            .from_reader(src);
+
+        let header: Vec<String> = reader
+            .headers()?
+            .iter()
+            .map(|h| h.to_ascii_lowercase())
+            .collect();
+        let column = |name: &str| header.iter().position(|h| h == name);
+        let Some(col_sample) = column("sample") else {
+            anyhow::bail!("missing required column 'sample'");
+        };
+        let Some(col_r1) = column("r1") else {
+            anyhow::bail!("missing required column 'r1'");
+        };
+        let (col_library, col_chemistry, col_subchemistry, col_r2) = (
+            column("library"),
+            column("chemistry"),
+            column("subchemistry"),
+            column("r2"),
+        );
+
+        let mut seen_columns = HashSet::new();
+        for name in &header {
+            if !seen_columns.insert(name.as_str()) {
+                anyhow::bail!("column '{name}' appears more than once");
+            }
+        }
+
+        let field = |record: &csv::StringRecord, col: Option<usize>| {
+            col.and_then(|c| record.get(c))
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+        };
+        let paths = |value: Option<String>| -> Vec<PathBuf> {
+            value
+                .iter()
+                .flat_map(|v| v.split(';'))
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(|p| base_dir.join(p))
+                .collect()
+        };
+
+        let mut rows = Vec::new();
+        let mut libraries = HashSet::new();
+        for (i, record) in reader.records().enumerate() {
+            let record = record?;
+            let line = record.position().map(|p| p.line()).unwrap_or(i as u64 + 2);
+
+            let Some(sample) = field(&record, Some(col_sample)) else {
+                anyhow::bail!("line {line}: empty sample name");
+            };
+            let library = field(&record, col_library).unwrap_or_else(|| sample.clone());
+            if library.contains('=') {
+                anyhow::bail!("line {line}: library {library:?} must not contain '='");
+            }
+            if !libraries.insert(library.clone()) {
+                anyhow::bail!("line {line}: library {library:?} is listed more than once");
+            }
+
+            let paths_r1 = paths(field(&record, Some(col_r1)));
+            if paths_r1.is_empty() {
+                anyhow::bail!("line {line}: no R1 files for sample {sample:?}");
+            }
+            let paths_r2 = paths(field(&record, col_r2));
+            if !paths_r2.is_empty() && paths_r1.len() != paths_r2.len() {
+                anyhow::bail!(
+                    "line {line}: {} R1 files but {} R2 files",
+                    paths_r1.len(),
+                    paths_r2.len()
+                );
+            }
+
+            let metadata = header
+                .iter()
+                .enumerate()
+                .filter(|(_, name)| !RESERVED_COLUMNS.contains(&name.as_str()))
+                .map(|(c, name)| (name.clone(), record.get(c).unwrap_or("").to_string()))
+                .collect();
+
+            rows.push(SampleSheetRow {
+                sample,
+                library,
+                chemistry: field(&record, col_chemistry),
+                subchemistry: field(&record, col_subchemistry),
+                paths_r1,
+                paths_r2,
+                metadata,
+            });
+        }
+
+        if rows.is_empty() {
+            anyhow::bail!("no samples listed");
+        }
+        Ok(SampleSheet { rows })
+    }
+
+    /// Rows grouped by sample name, in order of first appearance
+    pub fn rows_by_sample(&self) -> Vec<(&str, Vec<&SampleSheetRow>)> {
+        let mut groups: Vec<(&str, Vec<&SampleSheetRow>)> = Vec::new();
+        for row in &self.rows {
+            match groups.iter_mut().find(|(sample, _)| *sample == row.sample) {
+                Some((_, rows)) => rows.push(row),
+                None => groups.push((row.sample.as_str(), vec![row])),
+            }
+        }
+        groups
+    }
+}
+
+impl SampleSheetRow {
+    /// Record the sample name and free columns of this row under its library
+    pub fn push_metadata(&self, metadata: &mut BBGZMetadata) {
+        let columns = [("sample", self.sample.as_str())]
+            .into_iter()
+            .chain(self.metadata.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+        for (column, value) in columns {
+            metadata.push(
+                format!("{METADATA_SAMPLE_PREFIX}{column}"),
+                format!("{}={value}", self.library),
+            );
+        }
+    }
+}
+
+/// Sample columns per library, recovered from `sample.*` metadata entries. Cells are matched to
+/// a library by their `<library>_` barcode prefix
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SampleAnnotations {
+    columns: Vec<String>,
+    libraries: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl SampleAnnotations {
+    pub fn from_metadata(metadata: &BBGZMetadata) -> SampleAnnotations {
+        let mut annotations = SampleAnnotations::default();
+        for (key, value) in metadata.entries() {
+            let Some(column) = key.strip_prefix(METADATA_SAMPLE_PREFIX) else {
+                continue;
+            };
+            let Some((library, value)) = value.split_once('=') else {
+                continue;
+            };
+            if !annotations.columns.iter().any(|c| c == column) {
+                annotations.columns.push(column.to_string());
+            }
+            annotations
+                .libraries
+                .entry(library.to_string())
+                .or_default()
+                .insert(column.to_string(), value.to_string());
+        }
+        annotations
+    }
+
+    /// Annotations of every input carrying metadata; inputs without any are skipped
+    pub fn from_paths<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> SampleAnnotations {
+        let mut metadata = BBGZMetadata::new();
+        for path in paths {
+            match BBGZMetadata::read_from_path(path.as_ref()) {
+                Ok(Some(source)) => {
+                    for (key, value) in source.entries() {
+                        metadata.push(key.as_str(), value.as_str());
+                    }
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::debug!(path = %path.as_ref().display(), error = %e, "No sample annotations")
+                }
+            }
+        }
+        Self::from_metadata(&metadata)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.libraries.is_empty()
+    }
+
+    /// Library a cell belongs to; the longest matching prefix wins
+    pub fn library_of(&self, cell: &str) -> Option<&str> {
+        self.libraries
+            .keys()
+            .filter(|library| {
+                cell.strip_prefix(library.as_str())
+                    .is_some_and(|rest| rest.starts_with('_'))
+            })
+            .max_by_key(|library| library.len())
+            .map(String::as_str)
+    }
+
+    /// `library` followed by every sample column, one value per cell. Cells of unknown
+    /// libraries get empty strings
+    pub fn obs_columns(&self, cell_names: &[String]) -> Vec<(String, Vec<String>)> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let cell_libraries: Vec<Option<&str>> =
+            cell_names.iter().map(|c| self.library_of(c)).collect();
+
+        let mut out = Vec::with_capacity(self.columns.len() + 1);
+        out.push((
+            "library".to_string(),
+            cell_libraries
+                .iter()
+                .map(|l| l.unwrap_or("").to_string())
+                .collect(),
+        ));
+        for column in &self.columns {
+            let values = cell_libraries
+                .iter()
+                .map(|library| {
+                    library
+                        .and_then(|l| self.libraries.get(l))
+                        .and_then(|values| values.get(column))
+                        .cloned()
+                        .unwrap_or_default()
+                })
+                .collect();
+            out.push((column.clone(), values));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHEET: &str = "\
+Sample,Library,Chemistry,R1,R2,donor,condition
+# pilot run
+s1,lib1,atrandi-wgs,a_R1.fq.gz;b_R1.fq.gz,a_R2.fq.gz;b_R2.fq.gz,d1,ctrl
+s1,lib1_x,,c_R1.fq.gz,c_R2.fq.gz,d1,ctrl
+s2,,tenx,/abs/d_R1.fq.gz,,d2,treated
+";
+
+    #[test]
+    fn parses_rows_and_free_columns() {
+        let sheet = SampleSheet::parse(SHEET.as_bytes(), Path::new("/runs")).unwrap();
+        assert_eq!(sheet.rows.len(), 3);
+
+        let row = &sheet.rows[0];
+        assert_eq!(row.library, "lib1");
+        assert_eq!(row.chemistry.as_deref(), Some("atrandi-wgs"));
+        assert_eq!(
+            row.paths_r1,
+            [PathBuf::from("/runs/a_R1.fq.gz"), "/runs/b_R1.fq.gz".into()]
+        );
+        assert_eq!(
+            row.metadata,
+            [
+                ("donor".to_string(), "d1".to_string()),
+                ("condition".to_string(), "ctrl".to_string())
+            ]
+        );
+
+        assert_eq!(sheet.rows[1].chemistry, None);
+        assert_eq!(sheet.rows[2].library, "s2");
+        assert_eq!(sheet.rows[2].paths_r1, [PathBuf::from("/abs/d_R1.fq.gz")]);
+        assert!(sheet.rows[2].paths_r2.is_empty());
+
+        let groups = sheet.rows_by_sample();
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].1.len(), 2);
+    }
+
+    #[test]
+    fn rejects_duplicate_libraries_and_mismatched_mates() {
+        let duplicate = "sample,library,r1\na,lib,x.fq\nb,lib,y.fq\n";
+        assert!(SampleSheet::parse(duplicate.as_bytes(), Path::new("")).is_err());
+
+        let mismatched = "sample,r1,r2\na,x.fq;y.fq,z.fq\n";
+        assert!(SampleSheet::parse(mismatched.as_bytes(), Path::new("")).is_err());
+
+        let missing = "library,r1\nlib,x.fq\n";
+        assert!(SampleSheet::parse(missing.as_bytes(), Path::new("")).is_err());
+    }
+
+    #[test]
+    fn annotates_cells_from_metadata() {
+        let sheet = SampleSheet::parse(SHEET.as_bytes(), Path::new("")).unwrap();
+        let mut metadata = BBGZMetadata::new();
+        for row in &sheet.rows {
+            row.push_metadata(&mut metadata);
+        }
+        let metadata = BBGZMetadata::from_bytes(&metadata.to_bytes().unwrap()).unwrap();
+        let annotations = SampleAnnotations::from_metadata(&metadata);
+
+        let cells: Vec<String> = ["lib1_A1_B2", "lib1_x_C3_D4", "s2_AAAC", "other_A1"]
+            .map(String::from)
+            .to_vec();
+        assert_eq!(annotations.library_of("lib1_x_C3_D4"), Some("lib1_x"));
+
+        let columns = annotations.obs_columns(&cells);
+        let names: Vec<&str> = columns.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["library", "sample", "donor", "condition"]);
+        assert_eq!(columns[0].1, ["lib1", "lib1_x", "s2", ""]);
+        assert_eq!(columns[1].1, ["s1", "s1", "s2", ""]);
+        assert_eq!(columns[3].1, ["ctrl", "ctrl", "treated", ""]);
+    }
+}
@@ -5,6 +5,9 @@ use std::io::BufReader;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use anyhow::Context;
+use bascet_io::BlockFormat;
+
 use super::DetectedFileformat;
 use super::TirpBascetShardReader;
 use super::ZipBascetShardReader;
@@ -198,12 +201,16 @@ impl ShardFileExtractor for DynShardReader {
 /// Given a path, get a suitable shard reader
 pub fn get_shard_reader_for_path(p: &PathBuf) -> anyhow::Result<DynShardReader> {
     match crate::fileformat::detect_shard_format(&p) {
+        DetectedFileformat::TIRP if BlockFormat::from_path(p) == BlockFormat::Zst => {
+            bail_zst_tirp(p)
+        }
         DetectedFileformat::TIRP => Ok(DynShardReader::TirpBascetShardReader(
             TirpBascetShardReader::new(p)
-                .expect(format!("Failed to read {}", p.display()).as_str()),
+                .with_context(|| format!("Failed to read {}", p.display()))?,
         )),
         DetectedFileformat::ZIP => Ok(DynShardReader::ZipBascetShardReader(
-            ZipBascetShardReader::new(p).expect(format!("Failed to read {}", p.display()).as_str()),
+            ZipBascetShardReader::new(p)
+                .with_context(|| format!("Failed to read {}", p.display()))?,
         )),
         _ => {
             anyhow::bail!(
@@ -218,13 +225,16 @@ pub fn get_shard_reader_for_path(p: &PathBuf) -> anyhow::Result<DynShardReader>
 /// Given a path to a shard file, get a dictionary that can return which cells are in it
 pub fn get_dyn_celldict(p: &PathBuf) -> anyhow::Result<Box<dyn ShardCellDictionary>> {
     match crate::fileformat::detect_shard_format(&p) {
+        DetectedFileformat::TIRP if BlockFormat::from_path(p) == BlockFormat::Zst => {
+            bail_zst_tirp(p)
+        }
         DetectedFileformat::TIRP => Ok(Box::new(
             TirpBascetShardReader::new(p)
-                .expect(format!("Unable to read cell list for {}", p.display()).as_str()),
+                .with_context(|| format!("Unable to read cell list for {}", p.display()))?,
         )),
         DetectedFileformat::ZIP => Ok(Box::new(
             ZipBascetShardReader::new(p)
-                .expect(format!("Unable to read cell list for {}", p.display()).as_str()),
+                .with_context(|| format!("Unable to read cell list for {}", p.display()))?,
         )),
         _ => {
             anyhow::bail!(
@@ -235,13 +245,21 @@ pub fn get_dyn_celldict(p: &PathBuf) -> anyhow::Result<Box<dyn ShardCellDictiona
     }
 }
 
+///////////////////////////////
+/// zstd TIRPs are indexed by their seek table, not tabix, so they can only be streamed
+fn bail_zst_tirp<T>(p: &PathBuf) -> anyhow::Result<T> {
+    anyhow::bail!(
+        "Listing cell IDs needs a tabix-indexed TIRP, but {} is zstd; stream it instead",
+        p.display()
+    )
+}
+
 ///////////////////////////////
 /// Try to figure out what cells are present in an input file.           
 /// If we cannot list the cells for this file then it will have to stream all the content
 pub fn try_get_cells_in_file(p: &PathBuf) -> anyhow::Result<Option<Vec<CellID>>> {
-    let mut cell_dict = get_dyn_celldict(p)
-        .expect(format!("Unable to read cell list for {}", p.display()).as_str());
-    Ok(Some(cell_dict.get_cell_ids().unwrap()))
+    let mut cell_dict = get_dyn_celldict(p)?;
+    Ok(Some(cell_dict.get_cell_ids()?))
 }
 
 ///////////////////////////////
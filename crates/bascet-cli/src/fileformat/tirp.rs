@@ -1,12 +1,19 @@
 // HTSlib version - does not work with our version of TIRP so taken out of the pipeline for now
 
 use anyhow::{Context, bail};
+use bascet_io::{
+    BBGZExtra, BBGZHeader, BBGZMetadata, BBGZWriter, BlockFormat, MARKER_EOF,
+    MAX_SIZEOF_BLOCKusize, MAX_SIZEOF_RAW_BLOCKusize, METADATA_SUBFIELD,
+};
+use bounded_integer::BoundedU64;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
+use std::io::Read;
 use std::io::Write;
+use std::io::{Seek, SeekFrom};
 use std::num::NonZeroUsize;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -24,6 +31,7 @@ use super::ShardStreamingFileExtractor;
 use super::shard::StreamingReadPairReader;
 use crate::utils::BedTabixIndexer;
 
+use noodles::bgzf::VirtualPosition;
 use noodles::csi::binning_index::index::reference_sequence::bin::Chunk;
 use noodles::fastq::Record as FastqRecord;
 use noodles::fastq::Writer as FastqWriter;
@@ -570,16 +578,25 @@ impl ConstructFromPath<TirpStreamingShardExtractor> for TirpStreamingShardReader
 ///////////////////
 
 #[derive(Debug, Clone)]
-pub struct BascetTIRPWriterFactory {}
+pub struct BascetTIRPWriterFactory {
+    metadata: Option<BBGZMetadata>,
+}
 impl BascetTIRPWriterFactory {
     pub fn new() -> BascetTIRPWriterFactory {
-        BascetTIRPWriterFactory {}
+        BascetTIRPWriterFactory { metadata: None }
+    }
+
+    /// Embed `metadata` in the first block of every TIRP written
+    pub fn with_metadata(metadata: BBGZMetadata) -> BascetTIRPWriterFactory {
+        BascetTIRPWriterFactory {
+            metadata: Some(metadata),
+        }
     }
 }
 impl ConstructFromPath<BascetTIRPWriter> for BascetTIRPWriterFactory {
     fn new_from_path(&self, fname: &PathBuf) -> anyhow::Result<BascetTIRPWriter> {
         ///////// maybe anyhow prevents spec of reader?
-        BascetTIRPWriter::new(fname)
+        BascetTIRPWriter::new(fname, self.metadata.as_ref())
     }
 }
 
@@ -588,14 +605,29 @@ pub struct BascetTIRPWriter {
     pub writer: Option<noodles::bgzf::io::Writer<BufWriter<File>>>,
     indexer: Option<BedTabixIndexer>,
     write_error: Option<anyhow::Error>,
+
+    // Metadata goes into the header of the first BGZF block, which noodles cannot write. We
+    // reserve its size at the start of the file, let noodles write block 0 behind it and
+    // rewrite that header in place once finished. Block 0 is closed early enough for the
+    // metadata to always fit, so blocks after it sit exactly `metadata_reserved` bytes later
+    // than noodles believes.
+    metadata: Option<Vec<u8>>,
+    metadata_reserved: u64,
+    sizeof_block0_remaining: usize,
 }
 impl BascetTIRPWriter {
-    fn new(path: &PathBuf) -> anyhow::Result<BascetTIRPWriter> {
+    fn new(path: &PathBuf, metadata: Option<&BBGZMetadata>) -> anyhow::Result<BascetTIRPWriter> {
         info!("starting writer for TIRP {}", path.display());
 
         let f = File::create(path)
             .with_context(|| format!("failed to create TIRP output {}", path.display()))?;
-        let bw = BufWriter::new(f); //TODO  put in a buffered writer in loop. no need to do twice
+        let mut bw = BufWriter::new(f); //TODO  put in a buffered writer in loop. no need to do twice
+
+        let metadata = metadata.map(|m| m.to_bytes()).transpose()?;
+        let metadata_reserved = metadata
+            .as_ref()
+            .map_or(0, |md| (BBGZExtra::SSIZE + md.len()) as u64);
+        bw.write_all(&vec![0u8; metadata_reserved as usize])?;
         // Keep this on noodles BGZF: the older bgzip crate writer emits TIRP
         // files that noodles' BGZF/tabix reader rejects, and the tabix indexer
         // needs noodles' exact virtual positions while records are written.
@@ -606,33 +638,54 @@ impl BascetTIRPWriter {
             writer: Some(writer),
             indexer: Some(BedTabixIndexer::new()),
             write_error: None,
+            metadata,
+            metadata_reserved,
+            sizeof_block0_remaining: match metadata_reserved {
+                0 => 0,
+                reserved => MAX_SIZEOF_RAW_BLOCKusize - reserved as usize,
+            },
         })
     }
 
+    fn add_index_record(&mut self, cell_id: &CellID, chunk: Chunk) -> anyhow::Result<()> {
+        let indexer = self
+            .indexer
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("TIRP tabix indexer already finalized"))?;
+        // TIRP uses fixed dummy BED coordinates for cell-based lookup. Preserve
+        // the same raw columns that `tabix -p bed` indexed previously.
+        indexer.add_record(cell_id, 1, 1, shift_chunk(chunk, self.metadata_reserved)?)?;
+        Ok(())
+    }
+
     fn write_read_pair(&mut self, cell_id: &CellID, read: &ReadPair) -> anyhow::Result<()> {
         let writer = self
             .writer
             .as_mut()
             .ok_or_else(|| anyhow::anyhow!("TIRP writer already finalized"))?;
-        let indexer = self
-            .indexer
-            .as_mut()
-            .ok_or_else(|| anyhow::anyhow!("TIRP tabix indexer already finalized"))?;
 
         let record_start_position = writer.virtual_position();
-        write_records_pair_to_tirp(writer, cell_id, read)?;
+        if self.sizeof_block0_remaining == 0 {
+            write_records_pair_to_tirp(writer, cell_id, read)?;
+        } else {
+            // NOTE:    the record is split where block 0 has to end; noodles continues it in
+            //          block 1 like any record spanning blocks
+            let mut record = Vec::new();
+            write_records_pair_to_tirp(&mut record, cell_id, read)?;
+            let n = record.len().min(self.sizeof_block0_remaining);
+            writer.write_all(&record[..n])?;
+            self.sizeof_block0_remaining -= n;
+            if self.sizeof_block0_remaining == 0 {
+                writer.flush()?;
+            }
+            writer.write_all(&record[n..])?;
+        }
         let record_end_position = writer.virtual_position();
 
-        // TIRP uses fixed dummy BED coordinates for cell-based lookup. Preserve
-        // the same raw columns that `tabix -p bed` indexed previously.
-        indexer.add_record(
+        self.add_index_record(
             cell_id,
-            1,
-            1,
             Chunk::new(record_start_position, record_end_position),
-        )?;
-
-        Ok(())
+        )
     }
 }
 impl ReadPairWriter for BascetTIRPWriter {
@@ -658,9 +711,16 @@ impl ReadPairWriter for BascetTIRPWriter {
             .writer
             .take()
             .ok_or_else(|| anyhow::anyhow!("TIRP writer already finalized"))?;
-        writer
+        let mut inner = writer
             .finish()
             .with_context(|| format!("failed to finish TIRP output {}", self.path.display()))?;
+        inner.flush()?;
+        drop(inner);
+
+        if let Some(metadata) = self.metadata.take() {
+            embed_tirp_metadata(&self.path, &metadata)
+                .with_context(|| format!("failed to embed metadata in {}", self.path.display()))?;
+        }
 
         info!("Indexing final TIRP output file");
         let indexer = self
@@ -676,3 +736,203 @@ impl ReadPairWriter for BascetTIRPWriter {
         Ok(())
     }
 }
+
+///////////////////
+/////////////////// Writer for seekable zstd TIRPs
+///////////////////
+
+/// Writes `.tirp.zst` files: the blocks of each cell carry its id like BBGZ does, and the
+/// zstd seek table takes the place of the tabix index
+#[derive(Debug, Clone)]
+pub struct BascetTIRPZstWriterFactory {
+    metadata: Option<BBGZMetadata>,
+    countof_threads: BoundedU64<1, { u64::MAX }>,
+}
+impl BascetTIRPZstWriterFactory {
+    /// Embed `metadata` in the first block of every TIRP written, compressing on
+    /// `countof_threads` threads per output
+    pub fn with_metadata(
+        metadata: BBGZMetadata,
+        countof_threads: BoundedU64<1, { u64::MAX }>,
+    ) -> BascetTIRPZstWriterFactory {
+        BascetTIRPZstWriterFactory {
+            metadata: Some(metadata),
+            countof_threads,
+        }
+    }
+}
+impl ConstructFromPath<BascetTIRPZstWriter> for BascetTIRPZstWriterFactory {
+    fn new_from_path(&self, fname: &PathBuf) -> anyhow::Result<BascetTIRPZstWriter> {
+        BascetTIRPZstWriter::new(fname, self.metadata.as_ref(), self.countof_threads)
+    }
+}
+
+pub struct BascetTIRPZstWriter {
+    pub path: PathBuf,
+    writer: Option<BBGZWriter>,
+    metadata: Option<Vec<u8>>,
+    write_error: Option<anyhow::Error>,
+}
+impl BascetTIRPZstWriter {
+    fn new(
+        path: &PathBuf,
+        metadata: Option<&BBGZMetadata>,
+        countof_threads: BoundedU64<1, { u64::MAX }>,
+    ) -> anyhow::Result<BascetTIRPZstWriter> {
+        info!("starting writer for zstd TIRP {}", path.display());
+
+        let f = File::create(path)
+            .with_context(|| format!("failed to create TIRP output {}", path.display()))?;
+        let writer = BBGZWriter::builder()
+            .with_writer(BufWriter::new(f))
+            .countof_threads(countof_threads)
+            .format(BlockFormat::Zst)
+            .build();
+
+        Ok(BascetTIRPZstWriter {
+            path: path.clone(),
+            writer: Some(writer),
+            metadata: metadata.map(|m| m.to_bytes()).transpose()?,
+            write_error: None,
+        })
+    }
+
+    fn write_cell(&mut self, cell_id: &CellID, list_reads: &[ReadPair]) -> anyhow::Result<()> {
+        let writer = self
+            .writer
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("TIRP writer already finalized"))?;
+
+        let mut header = BBGZHeader::new();
+        header
+            .add_extra(b"ID", cell_id.as_bytes().to_vec())
+            .map_err(|_| anyhow::anyhow!("duplicate BBGZ ID subfield"))?;
+        if let Some(metadata) = self.metadata.take() {
+            header
+                .add_extra(&METADATA_SUBFIELD, metadata)
+                .map_err(|_| anyhow::anyhow!("duplicate BBGZ metadata subfield"))?;
+        }
+
+        let mut blockwriter = writer.begin(header);
+        for rp in list_reads {
+            // Reserve space for the entire record to prevent splitting across blocks
+            let record_size = 11 // 8x '\t' + '1' + '1' + '\n'
+                + cell_id.len()
+                + rp.r1.len()
+                + rp.r2.len()
+                + rp.q1.len()
+                + rp.q2.len()
+                + rp.umi.len();
+            blockwriter.reserve(record_size);
+            write_records_pair_to_tirp(&mut blockwriter, cell_id, rp)?;
+        }
+        blockwriter.flush()?;
+        Ok(())
+    }
+}
+impl ReadPairWriter for BascetTIRPZstWriter {
+    fn write_reads_for_cell(&mut self, cell_id: &CellID, list_reads: &Arc<Vec<ReadPair>>) {
+        if self.write_error.is_some() {
+            return;
+        }
+        if let Err(e) = self.write_cell(cell_id, list_reads) {
+            self.write_error = Some(e);
+        }
+    }
+
+    fn writing_done(&mut self) -> anyhow::Result<()> {
+        if let Some(e) = self.write_error.take() {
+            return Err(e);
+        }
+
+        let writer = self
+            .writer
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("TIRP writer already finalized"))?;
+        writer
+            .finish_async()
+            .join()
+            .map_err(|_| anyhow::anyhow!("zstd TIRP writer panicked while finishing"))?;
+
+        info!("Finished writing TIRP {}", self.path.display());
+        Ok(())
+    }
+}
+
+fn shift_chunk(chunk: Chunk, shift: u64) -> anyhow::Result<Chunk> {
+    let shift_position = |position: VirtualPosition| -> anyhow::Result<VirtualPosition> {
+        if shift == 0 || position.compressed() == 0 {
+            return Ok(position);
+        }
+        VirtualPosition::try_from((position.compressed() + shift, position.uncompressed()))
+            .map_err(|e| anyhow::anyhow!("TIRP virtual position out of range: {e}"))
+    };
+    Ok(Chunk::new(
+        shift_position(chunk.start())?,
+        shift_position(chunk.end())?,
+    ))
+}
+
+/// Fill the bytes reserved at the start of a finished TIRP by rewriting the header of its
+/// first BGZF block with an added metadata subfield. A TIRP without records instead gets an
+/// empty block carrying the metadata in front of its EOF marker, as the marker has to stay
+/// byte-identical for htslib to recognise it.
+fn embed_tirp_metadata(path: &PathBuf, metadata: &[u8]) -> anyhow::Result<()> {
+    const SIZEOF_BGZF_HEADER: usize = 18;
+
+    let reserved = (BBGZExtra::SSIZE + metadata.len()) as u64;
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut header = [0u8; SIZEOF_BGZF_HEADER];
+    file.seek(SeekFrom::Start(reserved))?;
+    file.read_exact(&mut header)?;
+    if header[..2] != [0x1f, 0x8b] || header[10..14] != [6, 0, b'B', b'C'] {
+        bail!("first block is not a canonical BGZF block");
+    }
+
+    if len - reserved <= MARKER_EOF.len() as u64 {
+        let sizeof_block = (MARKER_EOF.len() as u64) + reserved;
+        let mut blocks = metadata_block_header(&MARKER_EOF[..10], metadata, sizeof_block);
+        blocks.extend_from_slice(&MARKER_EOF[SIZEOF_BGZF_HEADER..]);
+        blocks.extend_from_slice(&MARKER_EOF);
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&blocks)?;
+        file.set_len(blocks.len() as u64)?;
+        file.flush()?;
+        return Ok(());
+    }
+
+    let sizeof_block0 = u16::from_le_bytes([header[16], header[17]]) as u64 + 1;
+    if sizeof_block0 + reserved > MAX_SIZEOF_BLOCKusize as u64 {
+        bail!(
+            "first block of {sizeof_block0} bytes has no room for {} bytes of metadata",
+            metadata.len()
+        );
+    }
+
+    let new_header = metadata_block_header(&header[..10], metadata, sizeof_block0 + reserved);
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&new_header)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// BGZF block header with `base` (the fixed gzip fields), a metadata subfield and the BC
+/// subfield for a block of `sizeof_block` bytes
+fn metadata_block_header(base: &[u8], metadata: &[u8], sizeof_block: u64) -> Vec<u8> {
+    let xlen = 6 + BBGZExtra::SSIZE + metadata.len();
+    let mut header = Vec::with_capacity(base.len() + 2 + xlen);
+    header.extend_from_slice(base);
+    header.extend_from_slice(&(xlen as u16).to_le_bytes());
+    header.extend_from_slice(&METADATA_SUBFIELD);
+    header.extend_from_slice(&(metadata.len() as u16).to_le_bytes());
+    header.extend_from_slice(metadata);
+    header.extend_from_slice(b"BC");
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&((sizeof_block - 1) as u16).to_le_bytes());
+    header
+}
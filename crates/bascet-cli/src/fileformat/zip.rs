@@ -1,10 +1,13 @@
-use anyhow::bail;
+use anyhow::{Context, bail};
+use bascet_core::{ArenaPool, Composite, Stream, attr::sequence::R0};
+use bascet_io::{codec, parse};
+use bytesize::ByteSize;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
-use std::path::PathBuf;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use tracing::debug;
 use tracing::info;
@@ -40,9 +43,11 @@ impl ConstructFromPath<ZipBascetShardReader> for ZipBascetShardReaderFactory {
 /// A reader of ZIP-files as shards
 pub struct ZipBascetShardReader {
     pub files_for_cell: HashMap<CellID, Vec<String>>,
+    path_shard: PathBuf,
     zip_shard: ZipArchive<BufReader<File>>,
     current_cell_index: usize, //CellID,
     list_cells: Vec<CellID>,
+    fasta_arena_pool: Arc<ArenaPool<u8>>,
 }
 impl ZipBascetShardReader {
     ///
@@ -83,9 +88,11 @@ impl ZipBascetShardReader {
 
         Ok(ZipBascetShardReader {
             files_for_cell: files_for_cell,
+            path_shard: fname.clone(),
             zip_shard: zip_shard,
             current_cell_index: 0, //"".to_string(),
             list_cells: list_cells,
+            fasta_arena_pool: new_fasta_arena_pool(),
         })
     }
 }
@@ -245,14 +252,19 @@ impl StreamingReadPairReader for ZipBascetShardReader {
             let zip_fname_contigs = format!("{cell_id}/contigs.fa");
             let zip_fname_r1 = format!("{cell_id}/r1.fa");
             let zip_fname_r2 = format!("{cell_id}/r2.fa");
-            if let Some(dat) = parse_fasta_to_strings(&mut self.zip_shard, &zip_fname_contigs) {
+            if let Some(dat) = parse_fasta_to_strings(
+                &mut self.zip_shard,
+                &self.path_shard,
+                &self.fasta_arena_pool,
+                &zip_fname_contigs,
+            )? {
                 // A single contigs.fa; empty R2
                 let list_rp: Vec<ReadPair> = dat
                     .iter()
                     .map(|r| {
-                        let qs = make_good_q_for_seq(r.as_bytes());
+                        let qs = make_good_q_for_seq(r);
                         ReadPair {
-                            r1: r.clone().into_bytes(),
+                            r1: r.clone(),
                             r2: Vec::new(),
                             q1: qs,
                             q2: Vec::new(),
@@ -262,19 +274,30 @@ impl StreamingReadPairReader for ZipBascetShardReader {
                     .collect();
                 let list_rp = Arc::new(list_rp);
                 anyhow::Ok(Some(Arc::new((cell_id.clone(), list_rp))))
-            } else if let Some(dat_r1) = parse_fasta_to_strings(&mut self.zip_shard, &zip_fname_r1)
-            {
+            } else if let Some(dat_r1) = parse_fasta_to_strings(
+                &mut self.zip_shard,
+                &self.path_shard,
+                &self.fasta_arena_pool,
+                &zip_fname_r1,
+            )? {
                 // R1 and R2 expected
-                let dat_r2 = parse_fasta_to_strings(&mut self.zip_shard, &zip_fname_r2)
-                    .expect("Found r1.fa, but not r2.fa");
+                let Some(dat_r2) = parse_fasta_to_strings(
+                    &mut self.zip_shard,
+                    &self.path_shard,
+                    &self.fasta_arena_pool,
+                    &zip_fname_r2,
+                )?
+                else {
+                    bail!("Found {zip_fname_r1}, but not {zip_fname_r2}");
+                };
                 let twodat = dat_r1.iter().zip(dat_r2.iter());
                 let list_rp: Vec<ReadPair> = twodat
                     .map(|(r1, r2)| {
-                        let q1 = make_good_q_for_seq(r1.as_bytes());
-                        let q2 = make_good_q_for_seq(r2.as_bytes());
+                        let q1 = make_good_q_for_seq(r1);
+                        let q2 = make_good_q_for_seq(r2);
                         ReadPair {
-                            r1: r1.clone().into_bytes(),
-                            r2: r2.clone().into_bytes(),
+                            r1: r1.clone(),
+                            r2: r2.clone(),
                             q1: q1,
                             q2: q2,
                             umi: Vec::new(),
@@ -284,7 +307,7 @@ impl StreamingReadPairReader for ZipBascetShardReader {
                 let list_rp = Arc::new(list_rp);
                 anyhow::Ok(Some(Arc::new((cell_id.clone(), list_rp))))
             } else {
-                panic!("No FASTA content for this cell; FASTQ not supported yet");
+                bail!("No FASTA content for cell {cell_id}; FASTQ not supported yet");
             }
             //anyhow::Ok(None)
         } else {
@@ -302,32 +325,68 @@ fn make_good_q_for_seq(r: &[u8]) -> Vec<u8> {
 }
 
 ///
-/// Attempt to parse a FASTA to list of strings (one per record)
+/// Arena pool shared by the FASTA streams of one reader
+///
+pub fn new_fasta_arena_pool() -> Arc<ArenaPool<u8>> {
+    Arc::new(ArenaPool::new(ByteSize::mib(64), ByteSize::mib(8)))
+}
+
+///
+/// Stream the FASTA records of a zip entry through the arena-backed parser. The entry is read
+/// through `reader`, a separate handle on the archive, see `codec::ZipEntryDecoder`
+///
+pub fn stream_fasta_entry<R, S, F>(
+    zip_shard: &mut ZipArchive<R>,
+    reader: S,
+    arena_pool: &Arc<ArenaPool<u8>>,
+    index: usize,
+    mut f: F,
+) -> anyhow::Result<()>
+where
+    R: Read + Seek,
+    S: Read + Seek + Send + 'static,
+    F: FnMut(&parse::fasta::Record) -> anyhow::Result<()>,
+{
+    let decoder = codec::ZipEntryDecoder::builder()
+        .with_archive(zip_shard)
+        .with_entry(index)
+        .with_reader(reader)
+        .with_trailer(parse::fasta::FASTA_TRAILER)
+        .build()?;
+    let mut stream = Stream::builder()
+        .with_decoder(decoder)
+        .with_parser(parse::Fasta::builder().build())
+        .with_opt_decode_arena_pool(Arc::clone(arena_pool))
+        .build();
+    let mut query = stream.query::<parse::fasta::Record>();
+    while let Some(record) = query.next_into::<parse::fasta::Record>()? {
+        f(&record)?;
+    }
+    Ok(())
+}
+
+///
+/// Attempt to parse a FASTA to list of sequences (one per record). Returns `None` if the
+/// shard has no such file
 ///
 fn parse_fasta_to_strings(
     zip_shard: &mut ZipArchive<BufReader<File>>,
+    path_shard: &Path,
+    arena_pool: &Arc<ArenaPool<u8>>,
     zip_fname: &String,
-) -> Option<Vec<String>> {
-    let mut entry = zip_shard.by_name(&zip_fname);
-    if let Ok(entry) = &mut entry {
-        let bufreader_found = BufReader::new(entry);
-        let mut list_read = Vec::new();
-        let mut lines = bufreader_found.lines();
-
-        while let Some(v1) = lines.next() {
-            let v1 = v1.unwrap();
-            let v2 = lines.next().expect("No sequence line").unwrap();
-            let v2 = v2.trim();
-            if !v1.starts_with(">") {
-                panic!("Expected >; is this FASTA content?")
-            }
-
-            list_read.push(v2.to_string());
-        }
-        Some(list_read)
-    } else {
-        None
-    }
+) -> anyhow::Result<Option<Vec<Vec<u8>>>> {
+    let Some(index) = zip_shard.index_for_name(zip_fname) else {
+        return Ok(None);
+    };
+    let reader = File::open(path_shard)
+        .with_context(|| format!("Failed to open bascet shard {}", path_shard.display()))?;
+    let mut list_read = Vec::new();
+    stream_fasta_entry(zip_shard, reader, arena_pool, index, |record| {
+        list_read.push(record.get_ref::<R0>().to_vec());
+        Ok(())
+    })
+    .with_context(|| format!("Failed to parse FASTA {zip_fname}"))?;
+    Ok(Some(list_read))
 }
 
 /*
@@ -368,3 +427,102 @@ fn parse_fastq_to_strings(
     }
 }
     */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bascet_core::attr::meta::Id;
+    use std::io::{Cursor, Write};
+
+    fn fasta_zip(fasta: &[u8], compression: zip::CompressionMethod) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options: zip::write::FileOptions<()> =
+            zip::write::FileOptions::default().compression_method(compression);
+        writer.start_file("cell/contigs.fa", options).unwrap();
+        writer.write_all(fasta).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    fn read_zip_records(
+        bytes: Vec<u8>,
+        sizeof_target_alloc: ByteSize,
+    ) -> anyhow::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut zip = ZipArchive::new(Cursor::new(bytes.clone()))?;
+        let decoder = codec::ZipEntryDecoder::builder()
+            .with_archive(&mut zip)
+            .with_entry(0)
+            .with_reader(Cursor::new(bytes))
+            .with_trailer(parse::fasta::FASTA_TRAILER)
+            .sizeof_target_alloc(sizeof_target_alloc)
+            .build()?;
+        let mut stream = Stream::builder()
+            .with_decoder(decoder)
+            .with_parser(parse::Fasta::builder().build())
+            .with_opt_decode_arena_pool(new_fasta_arena_pool())
+            .build();
+        let mut query = stream.query::<parse::fasta::Record>();
+        let mut records = Vec::new();
+        while let Some(record) = query.next_into::<parse::fasta::Record>()? {
+            records.push((
+                record.get_ref::<Id>().to_vec(),
+                record.get_ref::<R0>().to_vec(),
+            ));
+        }
+        Ok(records)
+    }
+
+    fn read_records(fasta: &[u8], sizeof_target_alloc: ByteSize) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let bytes = fasta_zip(fasta, zip::CompressionMethod::Deflated);
+        read_zip_records(bytes, sizeof_target_alloc).unwrap()
+    }
+
+    #[test]
+    fn fasta_records_handle_multiline_crlf_and_missing_newline() {
+        let fasta = b">a desc\r\nAC\r\nGT\r\n\n>b\nTTT\n>c\n>d\nGG";
+        let expected = vec![
+            (b"a desc".to_vec(), b"ACGT".to_vec()),
+            (b"b".to_vec(), b"TTT".to_vec()),
+            (b"c".to_vec(), b"".to_vec()),
+            (b"d".to_vec(), b"GG".to_vec()),
+        ];
+        assert_eq!(read_records(fasta, ByteSize::mib(4)), expected);
+        //Small decode buffers force records to span two or more buffers
+        for sizeof_target_alloc in 1..32 {
+            assert_eq!(
+                read_records(fasta, ByteSize::b(sizeof_target_alloc)),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn contigs_larger_than_the_decode_buffer_are_parsed() {
+        let contig: Vec<u8> = b"ACGT".iter().copied().cycle().take(10_000).collect();
+        let mut fasta = b">small\nGG\n>large\r\n".to_vec();
+        for line in contig.chunks(61) {
+            fasta.extend_from_slice(line);
+            fasta.extend_from_slice(b"\r\n");
+        }
+        fasta.extend_from_slice(b">last\nTT\n");
+
+        let records = read_records(&fasta, ByteSize::b(100));
+        assert_eq!(
+            records,
+            vec![
+                (b"small".to_vec(), b"GG".to_vec()),
+                (b"large".to_vec(), contig),
+                (b"last".to_vec(), b"TT".to_vec()),
+            ]
+        );
+    }
+
+    #[test]
+    fn corrupt_entry_is_an_error() {
+        let fasta = b">a\nACGTACGTACGT\n";
+        let mut bytes = fasta_zip(fasta, zip::CompressionMethod::Stored);
+        let pos = bytes.windows(fasta.len()).position(|w| w == fasta).unwrap();
+        bytes[pos + 5] = b'T';
+
+        assert!(read_zip_records(bytes, ByteSize::mib(4)).is_err());
+    }
+}
@@ -2,13 +2,17 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
 
 use bascet_cli::command::{self, Commands};
+use bascet_cli::utils::{max_rss_bytes, process_cpu_seconds};
+use bascet_runtime::budget::{MemoryEnforcement, MemoryWatch};
 use bascet_runtime::logging::{
     LogConfig, LogGuard, LogLevel, LogMode, LogOrdered, LogStrictness, LogStrictnessLayer,
     log_filter_parser, log_mode_parser, log_ordered_parser, log_strictness_parser,
 };
+use bascet_runtime::report::{PROGRESS_TARGET, RunReport, RunResources, RunSummary};
 use clap::Parser;
 use tracing::{error, info};
 
@@ -37,7 +41,8 @@ struct Cli {
     #[arg(
         long = "log-mode",
         default_value = "./latest.log",
-        value_parser = log_mode_parser!(LogMode)
+        value_parser = log_mode_parser!(LogMode),
+        help = "Comma separated: terminal, json or discard for the terminal, and/or a log file path"
     )]
     log_mode: LogMode,
 
@@ -47,6 +52,39 @@ struct Cli {
         value_parser = log_ordered_parser!(LogOrdered)
     )]
     log_ordered: LogOrdered,
+
+    #[arg(
+        long = "report-json",
+        help = "Write a JSON summary of the run here at exit: resource usage, stage timings, budget and outputs"
+    )]
+    report_json: Option<PathBuf>,
+
+    #[arg(
+        long = "memory-enforcement",
+        default_value = "warn",
+        help = "What to do when the process RSS exceeds the command's --memory budget; fail stops the run with exit code 3"
+    )]
+    memory_enforcement: MemoryEnforcement,
+}
+
+///////////////////////////////
+/// Summarise the run, and write the summary to the --report-json path if one was given
+fn finish_run_report(path: Option<&Path>, success: bool, error: Option<String>) -> RunSummary {
+    let summary = RunReport::summary(
+        success,
+        error,
+        RunResources {
+            peak_rss_bytes: max_rss_bytes(),
+            cpu_seconds: process_cpu_seconds(),
+        },
+    );
+    let Some(path) = path else {
+        return summary;
+    };
+    if let Err(err) = summary.write_json(path) {
+        eprintln!("Error: cannot write run report '{}': {err}", path.display());
+    }
+    summary
 }
 
 ///////////////////////////////
@@ -54,12 +92,20 @@ struct Cli {
 fn main() -> std::process::ExitCode {
     let start = std::time::Instant::now();
     let cli = Cli::parse();
+    RunReport::start(cli.command.to_string(), start);
+    MemoryWatch::set_enforcement(cli.memory_enforcement);
 
     //Output from these commands need to get out without any log text. The commands are responsible for some type of error handing
     //as Zorn must be able to parse the output
     match &cli.command {
         Commands::Sysinfo(cmd) => {
-            return match cmd.try_execute() {
+            let result = cmd.try_execute();
+            finish_run_report(
+                cli.report_json.as_deref(),
+                result.is_ok(),
+                result.as_ref().err().map(|err| format!("{err:#}")),
+            );
+            return match result {
                 Ok(()) => std::process::ExitCode::SUCCESS,
                 Err(err) => {
                     eprintln!("Error: {err:#}");
@@ -68,7 +114,13 @@ fn main() -> std::process::ExitCode {
             };
         }
         Commands::ExtractStream(cmd) => {
-            return match cmd.try_execute() {
+            let result = cmd.try_execute();
+            finish_run_report(
+                cli.report_json.as_deref(),
+                result.is_ok(),
+                result.as_ref().err().map(|err| format!("{err:#}")),
+            );
+            return match result {
                 Ok(()) => std::process::ExitCode::SUCCESS,
                 Err(err) => {
                     if !err.to_string().is_empty() {
@@ -79,7 +131,13 @@ fn main() -> std::process::ExitCode {
             };
         }
         Commands::Exttool(cmd) => {
-            return match cmd.try_execute() {
+            let result = cmd.try_execute();
+            finish_run_report(
+                cli.report_json.as_deref(),
+                result.is_ok(),
+                result.as_ref().err().map(|err| format!("{err:#}")),
+            );
+            return match result {
                 Ok(code) => code,
                 Err(err) => {
                     eprintln!("Error: {err:#}");
@@ -90,6 +148,7 @@ fn main() -> std::process::ExitCode {
         _ => (),
     };
 
+    let path_report_json = cli.report_json.clone();
     LogGuard::with_config(LogConfig {
         level: cli.log_level,
         mode: cli.log_mode,
@@ -128,14 +187,24 @@ fn main() -> std::process::ExitCode {
         }
 
         error!(elapsed = ?start.elapsed(), "Failure!");
+        finish_run_report(path_report_json.as_deref(), false, Some(message));
         LogGuard::flush();
         std::process::abort();
     }));
 
+    let path_report_json = cli.report_json.clone();
+    MemoryWatch::on_exceeded(move |message| {
+        MemoryWatch::log_summary();
+        error!(elapsed = ?start.elapsed(), "Failure!");
+        finish_run_report(path_report_json.as_deref(), false, Some(message.to_string()));
+        LogGuard::flush();
+    });
+
     info!("*=========================================================================*");
     info!(version = env!("CARGO_PKG_VERSION"), command = %cli.command, "Running Bascet");
     info!("---------------------------------------------------------------------------");
 
+    let stage = RunReport::stage(cli.command.to_string());
     let result = match cli.command {
         Commands::Align(mut cmd) => cmd.try_execute(),
         Commands::Bam2fragments(mut cmd) => cmd.try_execute(),
@@ -143,12 +212,16 @@ fn main() -> std::process::ExitCode {
         Commands::Countchrom(mut cmd) => cmd.try_execute(),
         Commands::Countfeature(mut cmd) => cmd.try_execute(),
         Commands::Countsketch(mut cmd) => cmd.try_execute(),
+        Commands::Deplete(mut cmd) => cmd.try_execute(),
         Commands::Extract(mut cmd) => cmd.try_execute(),
         Commands::ExtractStream(_cmd) => panic!("Command handled in the wrong place"),
         Commands::Exttool(_cmd) => panic!("Command handled in the wrong place"),
+        Commands::Filter(mut cmd) => cmd.try_execute(),
         Commands::Filterbam(mut cmd) => cmd.try_execute(),
         #[cfg(feature = "fastqc")]
         Commands::Fastqc(mut cmd) => cmd.try_execute(),
+        Commands::FastqcSummary(mut cmd) => cmd.try_execute(),
+        Commands::FeatureBarcode(mut cmd) => cmd.try_execute(),
         Commands::Featurise(mut cmd) => cmd.try_execute(),
         #[cfg(feature = "gecco")]
         Commands::Gecco(mut cmd) => cmd.try_execute(),
@@ -164,26 +237,51 @@ fn main() -> std::process::ExitCode {
             // }
             cmd.try_execute()
         }
+        Commands::Header(mut cmd) => cmd.try_execute(),
+        Commands::ImportBam(mut cmd) => cmd.try_execute(),
         Commands::ImportSra(mut cmd) => cmd.try_execute(),
+        Commands::Index(mut cmd) => cmd.try_execute(),
         Commands::Mapcell(mut cmd) => cmd.try_execute(),
         Commands::MinhashFq(mut cmd) => cmd.try_execute(),
         Commands::MinhashHist(mut cmd) => cmd.try_execute(),
+        Commands::Multiref(mut cmd) => cmd.try_execute(),
         Commands::NcbiGenomeDownload(mut cmd) => cmd.try_execute(),
         //Commands::KmcReads(mut cmd) => cmd.try_execute(),
         Commands::Kraken(mut cmd) => cmd.try_execute(),
         Commands::PipeSamAddTags(mut _cmd) => _cmd.try_execute(), // no longer needed?
         Commands::Qc(mut cmd) => cmd.try_execute(),
         Commands::Quast(mut cmd) => cmd.try_execute(),
+        Commands::RelabelCells(mut cmd) => cmd.try_execute(),
         Commands::Shardify(mut cmd) => cmd.try_execute(),
         #[cfg(feature = "skesa")]
         Commands::Skesa(mut cmd) => cmd.try_execute(),
+        Commands::Stats(mut cmd) => cmd.try_execute(),
         Commands::Sysinfo(_cmd) => panic!("Command handled in the wrong place"),
         Commands::Tobigwig(mut cmd) => cmd.try_execute(),
         Commands::ToFastq(mut cmd) => cmd.try_execute(),
         Commands::Transform(mut cmd) => cmd.try_execute(),
+        Commands::Validate(mut cmd) => cmd.try_execute(),
         Commands::DetectKmerKmc(mut cmd) => cmd.try_execute(),
         Commands::DetectKmerFq(mut cmd) => cmd.try_execute(),
+        Commands::Doublets(mut cmd) => cmd.try_execute(),
     };
+    drop(stage);
+
+    MemoryWatch::log_summary();
+    let summary = finish_run_report(
+        cli.report_json.as_deref(),
+        result.is_ok(),
+        result.as_ref().err().map(|err| format!("{err:#}")),
+    );
+    info!(
+        target: PROGRESS_TARGET,
+        status = "summary",
+        wall_seconds = summary.wall_seconds,
+        cpu_seconds = summary.cpu_seconds,
+        peak_rss_bytes = summary.peak_rss_bytes,
+        outputs = summary.outputs.len(),
+        "Run summary"
+    );
 
     if let Err(e) = result {
         error!("Error occurred: {:#}", e);
@@ -308,4 +406,162 @@ mod tests {
         };
         assert_eq!(err.kind(), clap::error::ErrorKind::UnknownArgument);
     }
+
+    #[test]
+    fn debarcode_sample_sheet_excludes_fastq_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let sheet = fastq_path(&dir, "samples.csv");
+        let r1 = fastq_path(&dir, "reads_R1.fastq.gz");
+
+        let cmd = debarcode_command(vec![
+            "bascet".into(),
+            "debarcode".into(),
+            "--sample-sheet".into(),
+            sheet.clone(),
+            "--sample-out-dir".into(),
+            dir.path().to_string_lossy().into_owned(),
+            "atrandi-wgs".into(),
+        ]);
+        assert!(cmd.path_sample_sheet.is_some());
+        assert!(cmd.path_sample_out_dir.is_some());
+
+        let result = Cli::try_parse_from(vec![
+            "bascet".into(),
+            "debarcode".into(),
+            "--sample-sheet".into(),
+            sheet,
+            "-1".into(),
+            r1,
+            "atrandi-wgs".into(),
+        ]);
+        let err = match result {
+            Ok(_) => panic!("expected parser error"),
+            Err(err) => err,
+        };
+        assert_eq!(err.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn json_log_mode_and_report_path_parse() {
+        let cli = Cli::try_parse_from([
+            "bascet",
+            "--log-mode",
+            "json",
+            "--report-json",
+            "run.json",
+            "sysinfo",
+            "--info",
+            "cpu",
+        ])
+        .unwrap();
+        assert!(cli.log_mode.terminal && cli.log_mode.json);
+        assert!(cli.log_mode.file.is_none());
+        assert_eq!(cli.report_json, Some(PathBuf::from("run.json")));
+
+        let cli = Cli::try_parse_from([
+            "bascet",
+            "--log-mode",
+            "json,run.log",
+            "sysinfo",
+            "--info",
+            "cpu",
+        ])
+        .unwrap();
+        assert!(cli.log_mode.terminal && cli.log_mode.json);
+        assert!(cli.log_mode.file.is_some());
+
+        let cli = Cli::try_parse_from(["bascet", "sysinfo", "--info", "cpu"]).unwrap();
+        assert!(!cli.log_mode.json);
+        assert!(cli.report_json.is_none());
+        assert_eq!(cli.memory_enforcement, MemoryEnforcement::Warn);
+    }
+
+    #[test]
+    fn memory_enforcement_parses() {
+        let parse = |value: &str| {
+            Cli::try_parse_from([
+                "bascet",
+                "--memory-enforcement",
+                value,
+                "sysinfo",
+                "--info",
+                "cpu",
+            ])
+        };
+        assert_eq!(parse("fail").unwrap().memory_enforcement, MemoryEnforcement::Fail);
+        assert_eq!(parse("off").unwrap().memory_enforcement, MemoryEnforcement::Off);
+        assert!(parse("abort").is_err());
+    }
+
+    #[test]
+    fn minhash_fq_parses_byte_range() {
+        let parse = |range: &str| {
+            Cli::try_parse_from([
+                "bascet",
+                "minhash-fq",
+                "-i",
+                "cells.tirp.bbgz",
+                "-o",
+                "minhash.zip",
+                "--byte-range",
+                range,
+            ])
+        };
+
+        match parse("1024..4096").unwrap().command {
+            Commands::MinhashFq(cmd) => assert_eq!(cmd.byte_range, Some(1024..4096)),
+            _ => panic!("expected minhash-fq command"),
+        }
+        match parse("4096..").unwrap().command {
+            Commands::MinhashFq(cmd) => assert_eq!(cmd.byte_range, Some(4096..u64::MAX)),
+            _ => panic!("expected minhash-fq command"),
+        }
+        assert!(parse("4096..1024").is_err());
+        assert!(parse("4096").is_err());
+    }
+
+    #[test]
+    fn aligner_options_are_required_together_and_optional_for_deplete() {
+        let deplete = |extra: &[&str]| {
+            let mut argv = vec![
+                "bascet",
+                "deplete",
+                "-i",
+                "cells.bam",
+                "--unmapped",
+                "u.tirp",
+            ];
+            argv.extend_from_slice(extra);
+            Cli::try_parse_from(argv)
+        };
+
+        match deplete(&[]).unwrap().command {
+            Commands::Deplete(cmd) => assert!(cmd.aligner.is_none()),
+            _ => panic!("expected deplete command"),
+        }
+        match deplete(&["-g", "host.fa", "--aligner", "STAR"])
+            .unwrap()
+            .command
+        {
+            Commands::Deplete(cmd) => assert!(cmd.aligner.is_some()),
+            _ => panic!("expected deplete command"),
+        }
+        assert!(deplete(&["-g", "host.fa"]).is_err());
+        assert!(Cli::try_parse_from(["bascet", "deplete", "-i", "cells.bam"]).is_err());
+    }
+
+    #[test]
+    fn align_requires_aligner_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let tirp = fastq_path(&dir, "cells.tirp.gz");
+        let tirp = tirp.as_str();
+        let align = |extra: &[&str]| {
+            let mut argv = vec!["bascet", "align", "-i", tirp, "-u", "u.bam", "-s", "s.bam"];
+            argv.extend_from_slice(&["--temp", "temp"]);
+            argv.extend_from_slice(extra);
+            Cli::try_parse_from(argv)
+        };
+        assert!(align(&["-g", "host.fa", "--aligner", "BWAMEM2"]).is_ok());
+        assert!(align(&[]).is_err());
+    }
 }
@@ -24,3 +24,24 @@ macro_rules! bbgz_compression_parser {
         })
     };
 }
+
+/// Parse a `START..END` byte range; an omitted end reads to the end of the file.
+pub fn parse_byte_range(s: &str) -> Result<std::ops::Range<u64>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected START..END, got '{s}'"))?;
+    let start = start
+        .trim()
+        .parse::<u64>()
+        .map_err(|e| format!("invalid range start '{start}': {e}"))?;
+    let end = match end.trim() {
+        "" => u64::MAX,
+        end => end
+            .parse::<u64>()
+            .map_err(|e| format!("invalid range end '{end}': {e}"))?,
+    };
+    if start >= end {
+        return Err(format!("empty byte range {start}..{end}"));
+    }
+    Ok(start..end)
+}
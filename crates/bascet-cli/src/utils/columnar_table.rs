@@ -0,0 +1,62 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bascet_io::{ArrowIpcWriter, ParquetWriter};
+use serde::Serialize;
+
+use super::{atomic_temp_path, publish_atomic_output};
+
+/// Columnar table formats, for per-cell tables read back with zero-copy tools (pyarrow, polars,
+/// the R arrow package)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnarFormat {
+    ArrowIpc,
+    Parquet,
+}
+
+impl ColumnarFormat {
+    /// `.arrow`, `.feather` and `.ipc` are Arrow IPC files, `.parquet` is Parquet
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "arrow" | "feather" | "ipc" => Some(Self::ArrowIpc),
+            "parquet" => Some(Self::Parquet),
+            _ => None,
+        }
+    }
+}
+
+/// Write one row per item of `rows` (structs, or maps with string keys) and publish the file
+/// atomically
+pub fn write_columnar_table<R: Serialize>(
+    path_out: &Path,
+    format: ColumnarFormat,
+    rows: impl IntoIterator<Item = R>,
+) -> Result<()> {
+    let path_tmp = atomic_temp_path(path_out);
+    let file = BufWriter::new(
+        File::create(&path_tmp)
+            .with_context(|| format!("failed to create {}", path_tmp.display()))?,
+    );
+    let file = match format {
+        ColumnarFormat::ArrowIpc => {
+            let mut writer = ArrowIpcWriter::new(file);
+            for row in rows {
+                writer.write_row(&row)?;
+            }
+            writer.finish()?
+        }
+        ColumnarFormat::Parquet => {
+            let mut writer = ParquetWriter::new(file);
+            for row in rows {
+                writer.write_row(&row)?;
+            }
+            writer.finish()?
+        }
+    };
+    file.into_inner().map_err(|e| e.into_error())?;
+    publish_atomic_output(&path_tmp, path_out)?;
+    Ok(())
+}
@@ -4,6 +4,8 @@ use std::{
     time::{SystemTime, UNIX_EPOCH},
 };
 
+use bascet_runtime::report::RunReport;
+
 /// Return a hidden sibling temp path for writing a final output.
 ///
 /// Writing in the destination directory keeps the final publish step atomic on
@@ -41,8 +43,11 @@ pub fn atomic_temp_path_in_dir(
     ))
 }
 
+/// Move a completed output into place and list it in the run report
 pub fn publish_atomic_output(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
-    rename_or_copy_across_filesystems(from, to)
+    rename_or_copy_across_filesystems(from, &to)?;
+    RunReport::add_output(to);
+    Ok(())
 }
 
 /// Move a completed file into place.
@@ -0,0 +1,60 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use super::{ColumnarFormat, atomic_temp_path, publish_atomic_output, write_columnar_table};
+
+/// Write a `cell<TAB>count` histogram, sorted by cell, and publish it atomically
+pub fn write_histogram(
+    path_hist: impl AsRef<Path>,
+    histogram: &BTreeMap<Vec<u8>, u64>,
+) -> anyhow::Result<()> {
+    let path_hist = path_hist.as_ref();
+    let path_hist_tmp = atomic_temp_path(path_hist);
+    let mut bufwriter = BufWriter::new(
+        File::create(&path_hist_tmp)
+            .with_context(|| format!("create histogram tmp {}", path_hist_tmp.display()))?,
+    );
+    for (cell_id, count) in histogram {
+        bufwriter.write_all(cell_id)?;
+        bufwriter.write_all(b"\t")?;
+        bufwriter.write_all(count.to_string().as_bytes())?;
+        bufwriter.write_all(b"\n")?;
+    }
+    bufwriter.flush()?;
+    drop(bufwriter);
+    publish_atomic_output(&path_hist_tmp, path_hist)
+        .with_context(|| format!("publish histogram {}", path_hist.display()))
+}
+
+/// Row of a histogram written as a table
+#[derive(Serialize)]
+struct HistogramRow<'a> {
+    cell: &'a [u8],
+    count: u64,
+}
+
+/// Write a histogram as a `cell`/`count` table when `path_hist` ends in an Arrow IPC or Parquet
+/// extension, and as `write_histogram` does otherwise
+pub fn write_histogram_or_table(
+    path_hist: impl AsRef<Path>,
+    histogram: &BTreeMap<Vec<u8>, u64>,
+) -> anyhow::Result<()> {
+    let path_hist = path_hist.as_ref();
+    match ColumnarFormat::from_path(path_hist) {
+        Some(format) => write_columnar_table(
+            path_hist,
+            format,
+            histogram
+                .iter()
+                .map(|(cell, &count)| HistogramRow { cell, count }),
+        ),
+        None => write_histogram(path_hist, histogram),
+    }
+}
@@ -0,0 +1,17 @@
+use bascet_runtime::budget::MemoryPermit;
+use bytesize::ByteSize;
+
+/// Resize `permit` to the `bytes` now held by a structure that grows over the run, e.g. a count
+/// matrix. Fails rather than waits once it outgrows its budget, as nothing would free the bytes
+pub fn charge_growth(permit: &mut MemoryPermit, bytes: usize, what: &str) -> anyhow::Result<()> {
+    if !permit.try_resize(bytes) {
+        let limiter = permit.limiter();
+        anyhow::bail!(
+            "The {what} has grown to {} and no longer fits the memory budget {} of {}; raise --memory",
+            ByteSize(bytes as u64),
+            limiter.name(),
+            ByteSize(limiter.cap() as u64)
+        );
+    }
+    Ok(())
+}
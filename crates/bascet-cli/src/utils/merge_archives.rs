@@ -8,7 +8,7 @@ use std::ffi::OsString;
 use tracing::info;
 use zip::{ZipArchive, ZipWriter};
 
-use crate::utils::rename_or_copy_across_filesystems;
+use crate::utils::publish_atomic_output;
 
 ///////////////////////////////
 /// Merge a list of ZIP archives
@@ -76,7 +76,7 @@ where
     zipwriter_destination.finish().unwrap();
 
     //Move file from temp-file to real file, signifying that the file is now complete
-    rename_or_copy_across_filesystems(destination_temp, destination)?;
+    publish_atomic_output(destination_temp, destination)?;
 
     Ok(())
 }
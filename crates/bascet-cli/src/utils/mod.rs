@@ -1,12 +1,17 @@
 mod clap_utils;
+mod columnar_table;
 mod command_to_string;
 mod detect_software;
 mod fs_utils;
+mod histogram;
+mod memory_charge;
 mod merge_archives;
 mod path_utils;
 mod resource_usage;
 mod tabix_bed;
 
+pub use clap_utils::parse_byte_range;
+pub use columnar_table::{ColumnarFormat, write_columnar_table};
 pub use merge_archives::merge_archives;
 pub use merge_archives::merge_archives_and_delete;
 
@@ -16,7 +21,9 @@ pub use fs_utils::{
     atomic_temp_path, atomic_temp_path_in_dir, publish_atomic_output,
     rename_or_copy_across_filesystems,
 };
-pub use path_utils::expand_and_resolve;
+pub use histogram::{write_histogram, write_histogram_or_table};
+pub use memory_charge::charge_growth;
+pub use path_utils::{FASTA_EXTENSIONS, expand_and_resolve, list_fasta_files};
 pub use resource_usage::{
     current_rss_bytes, current_rss_display, max_rss_bytes, max_rss_display, process_cpu_seconds,
     thread_cpu_seconds,
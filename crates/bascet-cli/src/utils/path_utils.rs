@@ -1,9 +1,13 @@
 use anyhow::{Context, Result};
 use shellexpand;
+use std::collections::BTreeMap;
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 
+/// File extensions recognised as FASTA when a directory of references is given.
+pub const FASTA_EXTENSIONS: [&str; 5] = [".fa", ".fasta", ".fna", ".fa.gz", ".fasta.gz"];
+
 /// Expands ~ and env vars if possible (only for UTF-8 paths), and always returns an absolute PathBuf.
 /// Prints a warning if the path is not valid UTF-8 or expansion fails, but still makes the path absolute.
 /// Does NOT fail if the file does not exist.
@@ -43,3 +47,22 @@ pub fn expand_and_resolve<P: AsRef<Path>>(input: P) -> Result<PathBuf> {
     };
     Ok(abs)
 }
+
+/// Lists the FASTA files in `dir`, keyed by file name without the FASTA extension. This name
+/// identifies a reference wherever a directory of references is given (`quast --reference`,
+/// `multiref build`).
+pub fn list_fasta_files(dir: &Path) -> Result<BTreeMap<String, PathBuf>> {
+    let mut references = BTreeMap::new();
+    for entry in fs::read_dir(dir).with_context(|| format!("failed to list {}", dir.display()))? {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(ext) = FASTA_EXTENSIONS
+            .iter()
+            .find(|ext| file_name.ends_with(*ext))
+        {
+            let name = file_name[..file_name.len() - ext.len()].to_string();
+            references.insert(name, path.clone());
+        }
+    }
+    Ok(references)
+}
@@ -2,6 +2,32 @@ pub trait Attr {
     const NAME: &'static str;
 }
 
+/// Column names of a tuple of attributes, in order
+pub trait AttrNames {
+    fn names() -> Vec<&'static str>;
+}
+
+impl AttrNames for () {
+    fn names() -> Vec<&'static str> {
+        Vec::new()
+    }
+}
+
+impl<A0: Attr> AttrNames for (A0,) {
+    fn names() -> Vec<&'static str> {
+        vec![A0::NAME]
+    }
+}
+
+bascet_variadic::variadic! {
+    #[expand(n = 2..=16)]
+    impl<@n[A~#: crate::Attr](sep=",")> crate::AttrNames for (@n[A~#](sep=",")) {
+        fn names() -> Vec<&'static str> {
+            vec![@n[A~#::NAME](sep=",")]
+        }
+    }
+}
+
 pub struct Tagged<A, T> {
     pub value: T,
     _marker: std::marker::PhantomData<A>,
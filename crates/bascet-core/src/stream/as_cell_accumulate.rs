@@ -28,6 +28,7 @@ where
                         self.inner_decoder_flag_stop.load(Ordering::Relaxed) == true,
                     ) {
                         self.inner_state = StreamState::Aligned;
+                        self.take_decoder_error()?;
                         return Ok(self.inner_context.take());
                     }
 
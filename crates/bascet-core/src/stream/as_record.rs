@@ -23,6 +23,7 @@ where
                         self.inner_decoder_flag_stop.load(Ordering::Relaxed) == true,
                     ) {
                         self.inner_state = StreamState::Aligned;
+                        self.take_decoder_error()?;
                         return Ok(self.inner_context.take());
                     }
 
@@ -117,6 +118,7 @@ where
                         self.inner_decoder_flag_stop.load(Ordering::Relaxed) == true,
                     ) {
                         self.inner_state = StreamState::Aligned;
+                        self.take_decoder_error()?;
                         if let Some(record) = self.inner_context.take() {
                             batch.push((record, 0));
                         }
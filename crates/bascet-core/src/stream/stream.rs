@@ -1,6 +1,6 @@
 use std::mem::ManuallyDrop;
-use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread::JoinHandle;
 
 use bounded_integer::BoundedUsize;
@@ -22,6 +22,7 @@ pub struct Stream<P, D, C, M> {
     pub(crate) inner_decoder_buffer_rx: rtrb::Consumer<ArenaSlice<u8>>,
     pub(crate) inner_decoder_thread: ManuallyDrop<JoinHandle<D>>,
     pub(crate) inner_decoder_flag_stop: Arc<AtomicBool>,
+    pub(crate) inner_decoder_error: Arc<Mutex<Option<anyhow::Error>>>,
 
     pub(crate) inner_parser: P,
 
@@ -54,10 +55,12 @@ where
         };
 
         let arc_decoder_stop_flag = Arc::new(AtomicBool::new(false));
+        let arc_decoder_error = Arc::new(Mutex::new(None));
         let (handle, rx) = Self::spawn_decode_worker(
             with_decoder,
             countof_buffers,
             Arc::clone(&arc_decoder_stop_flag),
+            Arc::clone(&arc_decoder_error),
             Arc::clone(&arc_decoder_arena_pool),
         );
         Self {
@@ -65,6 +68,7 @@ where
             inner_decoder_buffer_rx: rx,
             inner_decoder_thread: ManuallyDrop::new(handle),
             inner_decoder_flag_stop: arc_decoder_stop_flag,
+            inner_decoder_error: arc_decoder_error,
             inner_parser: with_parser,
 
             inner_state: StreamState::Aligned,
@@ -77,6 +81,7 @@ where
         mut decoder: D,
         n_buffers: BoundedUsize<2, { usize::MAX }>,
         flag_shutdown: Arc<AtomicBool>,
+        decode_error: Arc<Mutex<Option<anyhow::Error>>>,
         arena_pool: Arc<ArenaPool<u8>>,
     ) -> (JoinHandle<D>, rtrb::Consumer<ArenaSlice<u8>>) {
         let (mut tx, rx) = rtrb::RingBuffer::new(n_buffers.get());
@@ -99,8 +104,11 @@ where
                             continue;
                         }
                         DecodeResult::Error(e) => {
-                            match e {
-                                _ => error!(error = ?e, "Unhandled error"),
+                            // NOTE:    stored before the stop flag is raised, so the consumer
+                            //          sees it once it runs out of buffers
+                            error!(error = ?e, "Decoding failed");
+                            if let Ok(mut slot) = decode_error.lock() {
+                                slot.get_or_insert(e);
                             }
                             flag_shutdown.store(true, Ordering::Relaxed);
                             continue;
@@ -142,6 +150,17 @@ where
         (handle, rx)
     }
 
+    ///
+    /// Error the decoder stopped on, if any. Checked once the stream has run out of buffers,
+    /// so a failed decode is not mistaken for the end of the input
+    ///
+    pub(crate) fn take_decoder_error(&self) -> anyhow::Result<()> {
+        match self.inner_decoder_error.lock() {
+            Ok(mut slot) => slot.take().map_or(Ok(()), Err),
+            Err(_) => Err(anyhow::anyhow!("stream decoder error lock poisoned")),
+        }
+    }
+
     pub unsafe fn shutdown(mut self) {
         self.inner_decoder_flag_stop.store(true, Ordering::Relaxed);
         // HACK: make sure stop flag is read
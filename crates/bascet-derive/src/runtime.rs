@@ -195,6 +195,13 @@ pub fn derive_budget(item: TokenStream) -> TokenStream {
                         fn mem(&self) -> &Self::Value {
                             &self.#field_ident
                         }
+
+                        fn limiter(&self) -> std::sync::Arc<bascet_runtime::budget::MemoryLimiter> {
+                            bascet_runtime::budget::MemoryLimiter::register(
+                                concat!(stringify!(#name), "::", stringify!(#marker)),
+                                self.#field_ident.as_u64() as usize,
+                            )
+                        }
                     }
                 })
             }
@@ -205,6 +212,13 @@ pub fn derive_budget(item: TokenStream) -> TokenStream {
                     fn mem(&self) -> &Self::Value {
                         &self.#field_ident
                     }
+
+                    fn limiter(&self) -> std::sync::Arc<bascet_runtime::budget::MemoryLimiter> {
+                        bascet_runtime::budget::MemoryLimiter::register(
+                            concat!(stringify!(#name), "::Total"),
+                            self.#field_ident.as_u64() as usize,
+                        )
+                    }
                 }
             }),
             BudgetKind::Thread(_) => None,
@@ -333,6 +347,13 @@ pub fn derive_budget(item: TokenStream) -> TokenStream {
                 {
                     bascet_runtime::budget::Memory::<M>::mem(self)
                 }
+
+                pub fn limiter<M>(&self) -> std::sync::Arc<bascet_runtime::budget::MemoryLimiter>
+                where
+                    Self: bascet_runtime::budget::Memory<M>,
+                {
+                    bascet_runtime::budget::Memory::<M>::limiter(self)
+                }
             }
         } else {
             quote! {}
@@ -366,12 +387,23 @@ pub fn derive_budget(item: TokenStream) -> TokenStream {
             quote! { tracing::info!("Budget (memory)"); }
         };
 
+        let report_budget = match (&total_threads_field, &total_mem_field) {
+            (Some(total_threads), Some(total_mem)) => quote! {
+                bascet_runtime::report::RunReport::set_budget(
+                    self.#total_threads.get(),
+                    self.#total_mem.as_u64(),
+                );
+            },
+            _ => quote! {},
+        };
+
         let log_method = quote! {
             pub fn log(&self) {
                 #thread_header
                 #(#thread_log_lines)*
                 #mem_header
                 #(#mem_log_lines)*
+                #report_budget
             }
         };
 
@@ -448,6 +480,10 @@ pub fn derive_budget(item: TokenStream) -> TokenStream {
         }
     });
 
+    let watch_total_mem = total_mem_field.as_ref().map(|total_field| {
+        quote! { bascet_runtime::budget::MemoryWatch::watch(budget.#total_field.as_u64()); }
+    });
+
     let max_field_len = budget_defs
         .iter()
         .map(|def| def.field_ident.to_string().len())
@@ -487,9 +523,11 @@ pub fn derive_budget(item: TokenStream) -> TokenStream {
         impl #name {
             #[builder]
             pub fn new(#(#new_params),*) -> Self {
-                Self {
+                let budget = Self {
                     #(#field_inits),*
-                }
+                };
+                #watch_total_mem
+                budget
             }
 
             #helper_methods
@@ -1,5 +1,7 @@
 pub mod bbgz;
 pub mod plain;
+pub mod zip_entry;
 
 pub use bbgz::*;
 pub use plain::*;
+pub use zip_entry::*;
@@ -2,14 +2,18 @@ mod block;
 mod consts;
 mod decode;
 mod header;
+mod metadata;
 mod trailer;
 mod utils;
 mod write;
+mod zst;
 
 pub use block::*;
 pub use consts::*;
 pub use decode::*;
 pub use header::*;
+pub use metadata::*;
 pub use trailer::*;
 pub use utils::*;
 pub use write::*;
+pub use zst::*;
@@ -1,7 +1,7 @@
 use bascet_core::ArenaSlice;
 
 use crate::{
-    BBGZCompressionJob, BBGZHeader, BBGZTrailer, BBGZWriter,
+    BBGZCompressionJob, BBGZHeader, BBGZTrailer, BBGZWriter, METADATA_SUBFIELD,
     codec::bbgz::consts::{MAX_SIZEOF_RAW_BLOCKusize, SIZEOF_MARKER_DEFLATE_ALIGN_BYTESusize},
 };
 
@@ -24,13 +24,10 @@ impl<'a> BBGZWriteBlock<'a> {
         }
     }
 
+    /// Start a new block unless `size` more bytes fit into the current one. Writes larger
+    /// than a whole block are still split across blocks of the same header
     pub fn reserve(&mut self, size: usize) {
-        let hsize = self.inner_raw_bytes_written
-            + self.inner_header.size()
-            + SIZEOF_MARKER_DEFLATE_ALIGN_BYTESusize
-            + BBGZTrailer::SSIZE;
-
-        if size + hsize > MAX_SIZEOF_RAW_BLOCKusize && self.inner_raw_bytes_written > 0 {
+        if size > self.sizeof_available() && self.inner_raw_bytes_written > 0 {
             let new_raw = self.inner_compressor.alloc_raw();
             let mut send_raw = std::mem::replace(&mut self.inner_raw, new_raw);
             unsafe {
@@ -41,21 +38,44 @@ impl<'a> BBGZWriteBlock<'a> {
                 };
                 self.inner_compressor.submit_compress(send_job);
             }
+            // metadata only describes the file, so it is carried by the first block alone
+            self.inner_header.remove_extra(&METADATA_SUBFIELD);
             self.inner_raw_bytes_written = 0;
         }
     }
+
+    /// Bytes that still fit into the current block
+    fn sizeof_available(&self) -> usize {
+        MAX_SIZEOF_RAW_BLOCKusize.saturating_sub(
+            self.inner_raw_bytes_written
+                + self.inner_header.size()
+                + SIZEOF_MARKER_DEFLATE_ALIGN_BYTESusize
+                + BBGZTrailer::SSIZE,
+        )
+    }
 }
 
 impl<'a> std::io::Write for BBGZWriteBlock<'a> {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // NOTE:    a full block is sent off and the rest continues in the next block with the
+        //          same header; a header too large to leave room for any data makes
+        //          `write_all` fail with `WriteZero`
+        if self.sizeof_available() == 0 {
+            self.flush()?;
+        }
+        let n = buf.len().min(self.sizeof_available());
+
         let raw_buf = self.inner_raw.as_mut_slice();
         unsafe {
             let raw_buf_ptr = raw_buf.as_mut_ptr().add(self.inner_raw_bytes_written);
-            std::ptr::copy_nonoverlapping(buf.as_ptr(), raw_buf_ptr, buf.len());
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), raw_buf_ptr, n);
         }
-        self.inner_raw_bytes_written += buf.len();
+        self.inner_raw_bytes_written += n;
 
-        Ok(buf.len())
+        Ok(n)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -70,6 +90,8 @@ impl<'a> std::io::Write for BBGZWriteBlock<'a> {
                 };
                 self.inner_compressor.submit_compress(send_job);
             }
+            // metadata only describes the file, so it is carried by the first block alone
+            self.inner_header.remove_extra(&METADATA_SUBFIELD);
             self.inner_raw_bytes_written = 0;
         }
 
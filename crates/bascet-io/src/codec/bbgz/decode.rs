@@ -2,7 +2,8 @@
 use std::os::fd::AsRawFd;
 use std::{
     fs::File,
-    io::{self, BufReader, Read},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
     panic::{AssertUnwindSafe, catch_unwind},
     path::Path,
     sync::{
@@ -13,6 +14,7 @@ use std::{
     time::Duration,
 };
 
+use anyhow::Context;
 use bounded_integer::BoundedU64;
 use crossbeam::channel::{Receiver, RecvTimeoutError, SendTimeoutError, Sender};
 use libdeflater::Decompressor;
@@ -23,7 +25,8 @@ use bascet_core::{
 };
 
 use crate::{
-    BBGZExtra, BBGZHeaderBase, BBGZTrailer,
+    BBGZExtra, BBGZHeaderBase, BBGZTrailer, BlockFormat, SIZEOF_ZST_FRAME_PREFIXusize,
+    ZST_MAGIC_BLOCK_HEADER, ZST_MAGIC_SEEK_TABLE, ZstBlockHeader, ZstSeekTable,
     codec::bbgz::{MARKER_EOF, MAX_SIZEOF_BLOCKusize},
 };
 
@@ -44,13 +47,55 @@ pub struct BBGZDecoder {
 
 struct BBGZDecodeJob {
     seq: usize,
+    format: BlockFormat,
     compressed: Vec<u8>,
     trailer_crc32: u32,
     trailer_isize: u32,
 }
 
+/// A block as read from disk, before it is handed to a decode worker. Zst blocks carry
+/// no CRC32, their frames are checksummed by zstd.
+struct BBGZRawBlock {
+    format: BlockFormat,
+    sizeof_block: u64,
+    id: Option<Vec<u8>>,
+    compressed: Vec<u8>,
+    trailer_crc32: u32,
+    trailer_isize: u32,
+}
+
+/// Decides which blocks a byte-range decoder emits.
+///
+/// A range owns every cell whose first block starts inside it: blocks that continue
+/// a cell begun before the range are skipped, and a cell begun inside the range is
+/// read to its end even when that runs past the range.
+struct BBGZRangeCursor {
+    offset: u64,
+    end: u64,
+    skip_id: Option<Vec<u8>>,
+    last_id: Option<Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum BBGZRangeStep {
+    Emit,
+    Skip,
+    Stop,
+}
+
 type BBGZDecodeResult = anyhow::Result<Vec<u8>>;
 
+/// Called by the reader thread with the on-disk size of every block it hands out, for
+/// progress reporting
+pub type BBGZOnBlockRead = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// Where the reader thread pulls compressed blocks from. Only files can be seeked, so
+/// byte-range decoding is limited to them.
+enum BBGZSource {
+    File(File, Option<Range<u64>>),
+    Reader(Box<dyn Read + Send>),
+}
+
 #[bon::bon]
 impl BBGZDecoder {
     #[builder]
@@ -62,15 +107,53 @@ impl BBGZDecoder {
         >,
         with_opt_rayon_pool: Option<Arc<rayon::ThreadPool>>,
         with_opt_rayon_pool_max_inflight: Option<BoundedU64<1, { u64::MAX }>>,
-    ) -> Self {
-        let file = File::open(with_path.as_ref()).unwrap_or_else(|err| {
-            panic!(
-                "failed to open BBGZ input {}: {err}",
-                with_path.as_ref().display()
-            )
-        });
+        // NOTE:    decode only the cells whose first block starts inside this byte range
+        with_opt_byte_range: Option<Range<u64>>,
+        with_opt_on_block_read: Option<BBGZOnBlockRead>,
+    ) -> anyhow::Result<Self> {
+        let file = File::open(with_path.as_ref()).with_context(|| {
+            format!("failed to open BBGZ input {}", with_path.as_ref().display())
+        })?;
         advise_sequential(&file);
 
+        Ok(Self::spawn(
+            BBGZSource::File(file, with_opt_byte_range),
+            countof_threads,
+            with_opt_rayon_pool,
+            with_opt_rayon_pool_max_inflight,
+            with_opt_on_block_read,
+        ))
+    }
+
+    /// Decode from any byte stream (stdin, a FIFO, a child process pipe) with the same
+    /// parallel block decoding as a file. The stream is read once, front to back.
+    #[builder(finish_fn = build)]
+    pub fn from_reader<R: Read + Send + 'static>(
+        with_reader: R,
+        #[builder(default = BoundedU64::const_new::<1>())] countof_threads: BoundedU64<
+            1,
+            { u64::MAX },
+        >,
+        with_opt_rayon_pool: Option<Arc<rayon::ThreadPool>>,
+        with_opt_rayon_pool_max_inflight: Option<BoundedU64<1, { u64::MAX }>>,
+        with_opt_on_block_read: Option<BBGZOnBlockRead>,
+    ) -> Self {
+        Self::spawn(
+            BBGZSource::Reader(Box::new(with_reader)),
+            countof_threads,
+            with_opt_rayon_pool,
+            with_opt_rayon_pool_max_inflight,
+            with_opt_on_block_read,
+        )
+    }
+
+    fn spawn(
+        source: BBGZSource,
+        countof_threads: BoundedU64<1, { u64::MAX }>,
+        with_opt_rayon_pool: Option<Arc<rayon::ThreadPool>>,
+        with_opt_rayon_pool_max_inflight: Option<BoundedU64<1, { u64::MAX }>>,
+        with_opt_on_block_read: Option<BBGZOnBlockRead>,
+    ) -> Self {
         let worker_count = if let Some(pool) = with_opt_rayon_pool.as_ref() {
             with_opt_rayon_pool_max_inflight
                 .map(|max_inflight| max_inflight.get() as usize)
@@ -88,8 +171,13 @@ impl BBGZDecoder {
         let (job_tx, job_rx) = crossbeam::channel::bounded(job_queue_capacity);
         let (result_tx, result_rx) = bascet_core::channel::ordered_dense::<_, 4096>();
 
-        let reader_handle =
-            spawn_reader(file, job_tx.clone(), result_tx.clone(), Arc::clone(&cancel));
+        let reader_handle = spawn_reader(
+            source,
+            job_tx.clone(),
+            result_tx.clone(),
+            Arc::clone(&cancel),
+            with_opt_on_block_read,
+        );
         let worker_handles = if let Some(rayon_pool) = with_opt_rayon_pool {
             spawn_rayon_workers(
                 job_rx,
@@ -113,6 +201,25 @@ impl BBGZDecoder {
             inner_cancel: cancel,
         }
     }
+
+    /// Split a BBGZ file into `countof_parts` contiguous byte ranges of roughly equal size.
+    ///
+    /// The ranges need not fall on block or cell boundaries; passing each of them to
+    /// `with_opt_byte_range` decodes every cell of the file exactly once.
+    pub fn partition_byte_ranges<P: AsRef<Path>>(
+        path: P,
+        countof_parts: BoundedU64<1, { u64::MAX }>,
+    ) -> io::Result<Vec<Range<u64>>> {
+        let sizeof_file = std::fs::metadata(path)?.len();
+        let countof_parts = countof_parts.get();
+        Ok((0..countof_parts)
+            .map(|part| {
+                let start = (sizeof_file as u128 * part as u128 / countof_parts as u128) as u64;
+                let end = (sizeof_file as u128 * (part + 1) as u128 / countof_parts as u128) as u64;
+                start..end
+            })
+            .collect())
+    }
 }
 
 impl Decode for BBGZDecoder {
@@ -175,31 +282,62 @@ impl Drop for BBGZDecoder {
 }
 
 fn spawn_reader(
-    file: File,
+    source: BBGZSource,
     job_tx: Sender<BBGZDecodeJob>,
     result_tx: OrderedDenseSender<BBGZDecodeResult, 4096>,
     cancel: Arc<AtomicBool>,
+    on_block_read: Option<BBGZOnBlockRead>,
 ) -> JoinHandle<()> {
     std::thread::Builder::new()
         .name("BBGZRead@0".to_string())
         .spawn(move || {
-            let mut reader = BufReader::with_capacity(BBGZ_READER_BUFFER_SIZE, file);
             let mut seq = 0;
+            let send_err = |seq: usize, err: anyhow::Error| {
+                if result_tx.wait_until_sendable(seq) {
+                    result_tx.send(seq, Err(err));
+                }
+            };
+
+            let (source, mut cursor): (Box<dyn Read + Send>, _) = match source {
+                BBGZSource::File(file, Some(range)) => {
+                    let (mut file, cursor) = match seek_range_start(file, range) {
+                        Ok(Some(located)) => located,
+                        Ok(None) => return,
+                        Err(err) => return send_err(seq, err),
+                    };
+                    if let Err(err) = file.seek(SeekFrom::Start(cursor.offset)) {
+                        return send_err(seq, err.into());
+                    }
+                    (Box::new(file), cursor)
+                }
+                BBGZSource::File(file, None) => (Box::new(file), BBGZRangeCursor::unbounded()),
+                BBGZSource::Reader(reader) => (reader, BBGZRangeCursor::unbounded()),
+            };
+            let mut reader = BufReader::with_capacity(BBGZ_READER_BUFFER_SIZE, source);
 
             while !cancel.load(Ordering::Acquire) {
-                let job = match read_next_job(&mut reader, seq) {
-                    Ok(Some(job)) => job,
+                let block = match read_next_block(&mut reader) {
+                    Ok(Some(block)) => block,
                     Ok(None) => break,
-                    Err(err) => {
-                        if !result_tx.wait_until_sendable(seq) {
-                            break;
-                        }
-                        result_tx.send(seq, Err(err));
-                        break;
-                    }
+                    Err(err) => return send_err(seq, err),
                 };
 
-                let mut pending_job = job;
+                match cursor.step(block.sizeof_block, &block.id) {
+                    BBGZRangeStep::Emit => {}
+                    BBGZRangeStep::Skip => continue,
+                    BBGZRangeStep::Stop => break,
+                }
+                if let Some(on_block_read) = &on_block_read {
+                    on_block_read(block.sizeof_block);
+                }
+
+                let mut pending_job = BBGZDecodeJob {
+                    seq,
+                    format: block.format,
+                    compressed: block.compressed,
+                    trailer_crc32: block.trailer_crc32,
+                    trailer_isize: block.trailer_isize,
+                };
                 loop {
                     match job_tx.send_timeout(pending_job, Duration::from_millis(100)) {
                         Ok(()) => break,
@@ -220,6 +358,164 @@ fn spawn_reader(
         .unwrap()
 }
 
+impl BBGZRangeCursor {
+    fn unbounded() -> Self {
+        Self {
+            offset: 0,
+            end: u64::MAX,
+            skip_id: None,
+            last_id: None,
+        }
+    }
+
+    /// Classify the block starting at the current offset and advance past it.
+    fn step(&mut self, sizeof_block: u64, id: &Option<Vec<u8>>) -> BBGZRangeStep {
+        let offset = self.offset;
+        self.offset += sizeof_block;
+
+        // NOTE:    blocks without an ID cannot be tied to a cell, so they never continue one
+        let continues = |other: &Option<Vec<u8>>| id.is_some() && id == other;
+        if offset >= self.end && !continues(&self.last_id) {
+            return BBGZRangeStep::Stop;
+        }
+        if self.skip_id.is_some() {
+            if continues(&self.skip_id) {
+                return BBGZRangeStep::Skip;
+            }
+            self.skip_id = None;
+        }
+        self.last_id.clone_from(id);
+        BBGZRangeStep::Emit
+    }
+}
+
+/// Find the first block starting at or after `range.start`, together with the cell
+/// id of the block before it. Returns `None` if no block starts inside the range.
+fn seek_range_start(
+    mut file: File,
+    range: Range<u64>,
+) -> anyhow::Result<Option<(File, BBGZRangeCursor)>> {
+    let sizeof_file = file.metadata()?.len();
+    if range.start >= range.end || range.start >= sizeof_file {
+        return Ok(None);
+    }
+
+    let mut cursor = BBGZRangeCursor {
+        offset: 0,
+        end: range.end,
+        skip_id: None,
+        last_id: None,
+    };
+    if range.start == 0 {
+        return Ok(Some((file, cursor)));
+    }
+
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+
+    // NOTE:    the block holding the byte just before the range starts no more than one
+    //          maximal block earlier, so a boundary search from there sees its header
+    let scan_from = range.start.saturating_sub(MAX_SIZEOF_BLOCKusize as u64);
+    let mut offset = if BlockFormat::detect(&magic) == Some(BlockFormat::Zst) {
+        // NOTE:    zst files list their block offsets in the seek table, no search needed
+        ZstSeekTable::read_from(&mut file)?
+            .block_offsets()
+            .take_while(|&offset| offset < range.start)
+            .last()
+            .unwrap_or(0)
+    } else if scan_from == 0 {
+        0
+    } else {
+        find_block_boundary(&mut file, scan_from, sizeof_file)?.ok_or_else(|| {
+            anyhow::anyhow!("no BBGZ block boundary found near byte offset {scan_from}")
+        })?
+    };
+
+    let mut prev_id = None;
+    while offset < range.start {
+        file.seek(SeekFrom::Start(offset))?;
+        let Some((sizeof_block, id)) = read_block_header(&mut file)? else {
+            return Ok(None);
+        };
+        prev_id = id;
+        offset += sizeof_block;
+    }
+    if offset >= range.end.min(sizeof_file) {
+        return Ok(None);
+    }
+
+    cursor.offset = offset;
+    cursor.skip_id = prev_id;
+    Ok(Some((file, cursor)))
+}
+
+/// Scan forward from `from` for the first offset holding a BBGZ block header whose
+/// block size lands on another header (or the end of the file).
+fn find_block_boundary(
+    file: &mut File,
+    from: u64,
+    sizeof_file: u64,
+) -> anyhow::Result<Option<u64>> {
+    let sizeof_window = (3 * MAX_SIZEOF_BLOCKusize as u64).min(sizeof_file - from) as usize;
+    let mut window = vec![0u8; sizeof_window];
+    file.seek(SeekFrom::Start(from))?;
+    file.read_exact(&mut window)?;
+
+    for candidate in 0..window.len().min(MAX_SIZEOF_BLOCKusize) {
+        let Some(sizeof_block) = parse_block_size(&window[candidate..]) else {
+            continue;
+        };
+        let next = candidate + sizeof_block;
+        let chained = from + next as u64 == sizeof_file
+            || window.get(next..).is_some_and(|rest| {
+                rest.len() < BBGZHeaderBase::SSIZE || parse_block_size(rest).is_some()
+            });
+        if chained {
+            return Ok(Some(from + candidate as u64));
+        }
+    }
+    Ok(None)
+}
+
+/// Total block size encoded by a header at the start of `bytes`, if it looks valid.
+fn parse_block_size(bytes: &[u8]) -> Option<usize> {
+    let base: &[u8; BBGZHeaderBase::SSIZE] = bytes.get(..BBGZHeaderBase::SSIZE)?.try_into().ok()?;
+    validate_base_header(base).ok()?;
+    let xlen = u16::from_le_bytes([base[10], base[11]]) as usize;
+    let extra = bytes.get(BBGZHeaderBase::SSIZE..BBGZHeaderBase::SSIZE + xlen)?;
+    let sizeof_block = find_bsize(extra).ok()? + 1;
+    (sizeof_block >= BBGZHeaderBase::SSIZE + xlen + BBGZTrailer::SSIZE).then_some(sizeof_block)
+}
+
+/// Read only the header of the block at the reader's position.
+fn read_block_header<R: Read>(reader: &mut R) -> anyhow::Result<Option<(u64, Option<Vec<u8>>)>> {
+    let mut base = [0u8; BBGZHeaderBase::SSIZE];
+    match read_exact_or_eof(reader, &mut base)? {
+        ReadStatus::Eof => return Ok(None),
+        ReadStatus::Read => {}
+    }
+    if let Some(magic) = zst_frame_magic(&base) {
+        return Ok(
+            read_zst_block_header(reader, magic, &base)?.map(|zst_header| {
+                (
+                    zst_header.sizeof_block() as u64,
+                    zst_header.header.get_extra(b"ID").map(<[u8]>::to_vec),
+                )
+            }),
+        );
+    }
+    validate_base_header(&base)?;
+
+    let xlen = u16::from_le_bytes([base[10], base[11]]) as usize;
+    let mut extra = vec![0u8; xlen];
+    reader.read_exact(&mut extra)?;
+    let sizeof_block = find_bsize(&extra)? as u64 + 1;
+    Ok(Some((
+        sizeof_block,
+        find_extra(&extra, b"ID")?.map(<[u8]>::to_vec),
+    )))
+}
+
 #[cfg(target_os = "linux")]
 fn advise_sequential(file: &File) {
     // Best-effort kernel hint for large sequential FASTQ/BBGZ reads, especially
@@ -248,7 +544,7 @@ fn spawn_workers(
             std::thread::Builder::new()
                 .name(format!("BBGZDecode@{idx}"))
                 .spawn(move || {
-                    let mut decompressor = Decompressor::new();
+                    let mut decompressor = BlockDecompressor::new();
 
                     loop {
                         let job = match thread_job_rx.recv_timeout(Duration::from_millis(100)) {
@@ -308,7 +604,7 @@ fn spawn_rayon_workers(
                     scope.spawn(move |_| {
                         let _permit = permit;
                         let seq = job.seq;
-                        let mut decompressor = Decompressor::new();
+                        let mut decompressor = BlockDecompressor::new();
                         let result = decode_job_catching_panics(&mut decompressor, job);
                         task_result_tx.send(seq, result);
                     });
@@ -359,13 +655,46 @@ impl Drop for InFlightPermit {
     }
 }
 
-fn read_next_job<R: Read>(reader: &mut R, seq: usize) -> anyhow::Result<Option<BBGZDecodeJob>> {
+/// Per-worker decompression state. The zstd context is only set up once a zst block
+/// comes along.
+struct BlockDecompressor {
+    deflate: Decompressor,
+    zst: Option<zstd::bulk::Decompressor<'static>>,
+}
+
+impl BlockDecompressor {
+    fn new() -> Self {
+        Self {
+            deflate: Decompressor::new(),
+            zst: None,
+        }
+    }
+}
+
+fn read_next_block<R: Read>(reader: &mut R) -> anyhow::Result<Option<BBGZRawBlock>> {
     let mut base = [0u8; BBGZHeaderBase::SSIZE];
     match read_exact_or_eof(reader, &mut base)? {
         ReadStatus::Eof => return Ok(None),
         ReadStatus::Read => {}
     }
 
+    if let Some(magic) = zst_frame_magic(&base) {
+        let Some(zst_header) = read_zst_block_header(reader, magic, &base)? else {
+            return Ok(None);
+        };
+        let mut compressed = vec![0u8; zst_header.csize as usize];
+        reader.read_exact(&mut compressed)?;
+
+        return Ok(Some(BBGZRawBlock {
+            format: BlockFormat::Zst,
+            sizeof_block: zst_header.sizeof_block() as u64,
+            id: zst_header.header.get_extra(b"ID").map(<[u8]>::to_vec),
+            compressed,
+            trailer_crc32: 0,
+            trailer_isize: zst_header.isize,
+        }));
+    }
+
     validate_base_header(&base)?;
 
     let xlen = u16::from_le_bytes([base[10], base[11]]) as usize;
@@ -397,15 +726,83 @@ fn read_next_job<R: Read>(reader: &mut R, seq: usize) -> anyhow::Result<Option<B
     let trailer_crc32 = trailer.CRC32;
     rest.truncate(compressed_len);
 
-    Ok(Some(BBGZDecodeJob {
-        seq,
+    Ok(Some(BBGZRawBlock {
+        format: BlockFormat::Bbgz,
+        sizeof_block: bsize as u64,
+        id: find_extra(&extra, b"ID")?.map(<[u8]>::to_vec),
         compressed: rest,
         trailer_crc32,
         trailer_isize,
     }))
 }
 
-fn decode_job(decompressor: &mut Decompressor, job: BBGZDecodeJob) -> anyhow::Result<Vec<u8>> {
+/// Skippable frame magic at the start of `base`, if it opens a zst block or the zst seek table
+fn zst_frame_magic(base: &[u8; BBGZHeaderBase::SSIZE]) -> Option<u32> {
+    let magic = u32::from_le_bytes([base[0], base[1], base[2], base[3]]);
+    matches!(magic, ZST_MAGIC_BLOCK_HEADER | ZST_MAGIC_SEEK_TABLE).then_some(magic)
+}
+
+/// Read the rest of the zst header frame starting with `base`. Returns `None` at the seek
+/// table, which ends a zst file the way the EOF marker ends a BBGZ file.
+fn read_zst_block_header<R: Read>(
+    reader: &mut R,
+    magic: u32,
+    base: &[u8; BBGZHeaderBase::SSIZE],
+) -> anyhow::Result<Option<ZstBlockHeader>> {
+    if magic == ZST_MAGIC_SEEK_TABLE {
+        // NOTE:    drain the table so a piped writer is not cut off while still writing it
+        io::copy(reader, &mut io::sink())?;
+        return Ok(None);
+    }
+
+    let sizeof_frame = u32::from_le_bytes([base[4], base[5], base[6], base[7]]) as usize;
+    let sizeof_header = SIZEOF_ZST_FRAME_PREFIXusize + sizeof_frame;
+    if sizeof_header < ZstBlockHeader::SSIZE {
+        return Err(anyhow::anyhow!(
+            "invalid zst block header frame size: {sizeof_frame}"
+        ));
+    }
+    let mut header_bytes = base.to_vec();
+    header_bytes.resize(sizeof_header, 0);
+    reader.read_exact(&mut header_bytes[BBGZHeaderBase::SSIZE..])?;
+
+    ZstBlockHeader::from_bytes(&header_bytes)
+        .map(Some)
+        .map_err(|_| anyhow::anyhow!("malformed zst block header"))
+}
+
+fn decode_job(decompressor: &mut BlockDecompressor, job: BBGZDecodeJob) -> anyhow::Result<Vec<u8>> {
+    match job.format {
+        BlockFormat::Bbgz => decode_bbgz_job(&mut decompressor.deflate, job),
+        BlockFormat::Zst => {
+            if decompressor.zst.is_none() {
+                decompressor.zst = Some(zstd::bulk::Decompressor::new()?);
+            }
+            decode_zst_job(decompressor.zst.as_mut().unwrap(), job)
+        }
+    }
+}
+
+fn decode_zst_job(
+    decompressor: &mut zstd::bulk::Decompressor<'static>,
+    job: BBGZDecodeJob,
+) -> anyhow::Result<Vec<u8>> {
+    let expected_len = job.trailer_isize as usize;
+    let mut decoded = vec![0; expected_len];
+    let decoded_len = decompressor
+        .decompress_to_buffer(&job.compressed, &mut decoded[..])
+        .map_err(|err| anyhow::anyhow!("zst decompression failed: {err}"))?;
+
+    if decoded_len != expected_len {
+        return Err(anyhow::anyhow!(
+            "zst ISIZE mismatch: header={expected_len}, decoded={decoded_len}"
+        ));
+    }
+
+    Ok(decoded)
+}
+
+fn decode_bbgz_job(decompressor: &mut Decompressor, job: BBGZDecodeJob) -> anyhow::Result<Vec<u8>> {
     let expected_len = job.trailer_isize as usize;
     let mut decoded = vec![0; expected_len];
     let decoded_len = decompressor
@@ -431,7 +828,7 @@ fn decode_job(decompressor: &mut Decompressor, job: BBGZDecodeJob) -> anyhow::Re
 }
 
 fn decode_job_catching_panics(
-    decompressor: &mut Decompressor,
+    decompressor: &mut BlockDecompressor,
     job: BBGZDecodeJob,
 ) -> anyhow::Result<Vec<u8>> {
     match catch_unwind(AssertUnwindSafe(|| decode_job(decompressor, job))) {
@@ -497,6 +894,17 @@ fn validate_base_header(base: &[u8; BBGZHeaderBase::SSIZE]) -> anyhow::Result<()
 }
 
 fn find_bsize(extra: &[u8]) -> anyhow::Result<usize> {
+    match find_extra(extra, b"BC")? {
+        Some(data) if data.len() == 2 => Ok(u16::from_le_bytes([data[0], data[1]]) as usize),
+        Some(data) => Err(anyhow::anyhow!(
+            "invalid BBGZ BC field length: {}",
+            data.len()
+        )),
+        None => Err(anyhow::anyhow!("BBGZ block is missing BC extra field")),
+    }
+}
+
+fn find_extra<'a>(extra: &'a [u8], id: &[u8; 2]) -> anyhow::Result<Option<&'a [u8]>> {
     let mut cursor = 0;
     while cursor < extra.len() {
         if cursor + BBGZExtra::SSIZE > extra.len() {
@@ -512,17 +920,14 @@ fn find_bsize(extra: &[u8]) -> anyhow::Result<usize> {
             return Err(anyhow::anyhow!("truncated BBGZ extra field data"));
         }
 
-        if (si1, si2) == (b'B', b'C') {
-            if len != 2 {
-                return Err(anyhow::anyhow!("invalid BBGZ BC field length: {len}"));
-            }
-            return Ok(u16::from_le_bytes([extra[data_start], extra[data_start + 1]]) as usize);
+        if [si1, si2] == *id {
+            return Ok(Some(&extra[data_start..data_end]));
         }
 
         cursor = data_end;
     }
 
-    Err(anyhow::anyhow!("BBGZ block is missing BC extra field"))
+    Ok(None)
 }
 
 fn is_eof_marker(base: &[u8; BBGZHeaderBase::SSIZE], extra: &[u8], rest: &[u8]) -> bool {
@@ -543,7 +948,7 @@ mod tests {
         time::{SystemTime, UNIX_EPOCH},
     };
 
-    use crate::{BBGZHeader, BBGZWriter, Compression};
+    use crate::{BBGZHeader, BBGZWriter, BlockFormat, Compression};
 
     use super::*;
 
@@ -577,10 +982,16 @@ mod tests {
             drop(writer);
         }
 
+        let bytes_read = Arc::new(std::sync::atomic::AtomicU64::new(0));
+        let on_block_read = Arc::clone(&bytes_read);
         let mut decoder = BBGZDecoder::builder()
             .with_path(&path)
             .countof_threads(BoundedU64::const_new::<1>())
-            .build();
+            .with_opt_on_block_read(Arc::new(move |n| {
+                on_block_read.fetch_add(n, Ordering::Relaxed);
+            }))
+            .build()
+            .unwrap();
         let mut buf = vec![0u8; 64];
         let n = match decoder.decode_into(&mut buf) {
             DecodeResult::Decoded(n) => n,
@@ -594,7 +1005,281 @@ mod tests {
             DecodeResult::Decoded(n) => panic!("expected eof, decoded {n} bytes"),
             DecodeResult::Error(err) => panic!("{err}"),
         }
+        // NOTE:    the EOF marker is not handed out as a block
+        assert_eq!(
+            bytes_read.load(Ordering::Relaxed),
+            std::fs::metadata(&path).unwrap().len() - MARKER_EOF.len() as u64
+        );
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_input_is_an_error() {
+        let path = temp_bbgz_path("missing");
+        let err = match BBGZDecoder::builder().with_path(&path).build() {
+            Ok(_) => panic!("opened a missing input"),
+            Err(err) => err,
+        };
+        assert!(err.to_string().contains("failed to open BBGZ input"));
+    }
+
+    fn temp_bbgz_path(tag: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "bascet-bbgz-decode-{tag}-{}.bbgz",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ))
+    }
+
+    fn decode_to_end(decoder: &mut BBGZDecoder) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            match decoder.decode_into(&mut buf) {
+                DecodeResult::Decoded(n) => out.extend_from_slice(&buf[..n]),
+                DecodeResult::Eof => return out,
+                DecodeResult::Error(err) => panic!("{err}"),
+            }
+        }
+    }
+
+    #[test]
+    fn byte_ranges_cover_every_cell_exactly_once() {
+        for format in [BlockFormat::Bbgz, BlockFormat::Zst] {
+            byte_ranges_cover_every_cell_exactly_once_in(format);
+        }
+    }
+
+    fn byte_ranges_cover_every_cell_exactly_once_in(format: BlockFormat) {
+        let path = temp_bbgz_path("range-test");
+
+        {
+            let output = File::create(&path).unwrap();
+            let mut writer = BBGZWriter::builder()
+                .countof_threads(BoundedU64::const_new::<1>())
+                .compression_level(Compression::fastest())
+                .format(format)
+                .with_writer(output)
+                .build();
+
+            let mut state = 0x9e37_79b9_7f4a_7c15_u64;
+            for cell in 0..24 {
+                let id = format!("cell_{cell:02}");
+                let mut header = BBGZHeader::new();
+                unsafe {
+                    header.add_extra_unchecked(b"ID", id.clone().into_bytes());
+                }
+
+                let mut block = writer.begin(header);
+                for _ in 0..(40 + 90 * (cell % 5)) {
+                    let mut line = id.clone().into_bytes();
+                    line.push(b'\t');
+                    for _ in 0..500 {
+                        state ^= state << 13;
+                        state ^= state >> 7;
+                        state ^= state << 17;
+                        line.push(b"ACGT"[(state >> 62) as usize]);
+                    }
+                    line.push(b'\n');
+                    block.reserve(line.len());
+                    block.write_all(&line).unwrap();
+                }
+                block.flush().unwrap();
+            }
+            drop(writer);
+        }
+
+        let whole = decode_to_end(
+            &mut BBGZDecoder::builder()
+                .with_path(&path)
+                .countof_threads(BoundedU64::const_new::<2>())
+                .build()
+                .unwrap(),
+        );
+        assert!(std::fs::metadata(&path).unwrap().len() > 4 * MAX_SIZEOF_BLOCKusize as u64);
+
+        for countof_parts in [1, 2, 3, 7, 64] {
+            let ranges =
+                BBGZDecoder::partition_byte_ranges(&path, BoundedU64::new(countof_parts).unwrap())
+                    .unwrap();
+            assert_eq!(ranges.len(), countof_parts as usize);
+
+            let mut joined = Vec::new();
+            let mut seen_cells = Vec::new();
+            for range in ranges {
+                let part = decode_to_end(
+                    &mut BBGZDecoder::builder()
+                        .with_path(&path)
+                        .countof_threads(BoundedU64::const_new::<2>())
+                        .with_opt_byte_range(range)
+                        .build()
+                        .unwrap(),
+                );
+                let mut part_cells: Vec<&[u8]> = part
+                    .split(|&b| b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(|line| line.split(|&b| b == b'\t').next().unwrap())
+                    .collect();
+                part_cells.dedup();
+                for cell in part_cells {
+                    assert!(!seen_cells.contains(&cell.to_vec()), "cell decoded twice");
+                    seen_cells.push(cell.to_vec());
+                }
+                joined.extend_from_slice(&part);
+            }
+            assert_eq!(seen_cells.len(), 24);
+            assert!(
+                joined == whole,
+                "{countof_parts} parts do not reassemble the {format:?} file"
+            );
+        }
+
+        remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn decodes_zst_blocks_of_concatenated_frames() {
+        // NOTE:    shardify merges zst blocks by concatenating their frames
+        let (a, b) = (b"cell_1\tACGT\n".repeat(100), b"cell_1\tTTGA\n".repeat(50));
+        let mut compressed = zstd::bulk::compress(&a, 3).unwrap();
+        compressed.extend(zstd::bulk::compress(&b, 3).unwrap());
+
+        let job = |compressed: Vec<u8>, isize: usize| BBGZDecodeJob {
+            seq: 0,
+            format: BlockFormat::Zst,
+            compressed,
+            trailer_crc32: 0,
+            trailer_isize: isize as u32,
+        };
+        let mut decompressor = BlockDecompressor::new();
+        let decoded = decode_job(
+            &mut decompressor,
+            job(compressed.clone(), a.len() + b.len()),
+        )
+        .unwrap();
+        assert_eq!(decoded, [a.as_slice(), b.as_slice()].concat());
+
+        assert!(decode_job(&mut decompressor, job(compressed, a.len())).is_err());
+    }
+
+    #[test]
+    fn range_cursor_keeps_cells_whole() {
+        let id = |s: &str| Some(s.as_bytes().to_vec());
+        let mut cursor = BBGZRangeCursor {
+            offset: 100,
+            end: 300,
+            skip_id: id("a"),
+            last_id: None,
+        };
+
+        assert_eq!(cursor.step(50, &id("a")), BBGZRangeStep::Skip);
+        assert_eq!(cursor.step(100, &id("b")), BBGZRangeStep::Emit);
+        assert_eq!(cursor.step(100, &id("b")), BBGZRangeStep::Emit);
+        assert_eq!(cursor.step(50, &id("b")), BBGZRangeStep::Emit);
+        assert_eq!(cursor.step(50, &id("c")), BBGZRangeStep::Stop);
+    }
+
+    #[test]
+    fn decodes_from_reader_like_from_path() {
+        let path = temp_bbgz_path("reader-test");
+
+        {
+            let output = File::create(&path).unwrap();
+            let mut writer = BBGZWriter::builder()
+                .countof_threads(BoundedU64::const_new::<1>())
+                .compression_level(Compression::fastest())
+                .with_writer(output)
+                .build();
+
+            for cell in 0..8 {
+                let mut header = BBGZHeader::new();
+                unsafe {
+                    header.add_extra_unchecked(b"ID", format!("cell_{cell}").into_bytes());
+                }
+                let mut block = writer.begin(header);
+                for read in 0..200 {
+                    let line = format!("cell_{cell}\tread_{read}\tACGTACGTACGT\n");
+                    block.reserve(line.len());
+                    block.write_all(line.as_bytes()).unwrap();
+                }
+                block.flush().unwrap();
+            }
+            drop(writer);
+        }
+
+        let from_path = decode_to_end(
+            &mut BBGZDecoder::builder()
+                .with_path(&path)
+                .countof_threads(BoundedU64::const_new::<2>())
+                .build()
+                .unwrap(),
+        );
+        // NOTE:    a non-seekable stream, as stdin or a pipe would be
+        let bytes = std::fs::read(&path).unwrap();
+        let from_reader = decode_to_end(
+            &mut BBGZDecoder::from_reader()
+                .with_reader(io::Cursor::new(bytes).chain(io::empty()))
+                .countof_threads(BoundedU64::const_new::<2>())
+                .build(),
+        );
+
+        assert!(!from_path.is_empty());
+        assert!(from_path == from_reader);
 
         remove_file(path).unwrap();
     }
+
+    #[test]
+    fn records_larger_than_a_block_are_split_across_blocks() {
+        for format in [BlockFormat::Bbgz, BlockFormat::Zst] {
+            let path = temp_bbgz_path("large-record-test");
+            let mut state = 0x2545_f491_4f6c_dd1d_u64;
+            let mut record = b"cell_1\t".to_vec();
+            for _ in 0..(3 * MAX_SIZEOF_BLOCKusize) {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                record.push(b"ACGT"[(state >> 62) as usize]);
+            }
+            record.push(b'\n');
+
+            {
+                let output = File::create(&path).unwrap();
+                let mut writer = BBGZWriter::builder()
+                    .countof_threads(BoundedU64::const_new::<1>())
+                    .compression_level(Compression::fastest())
+                    .format(format)
+                    .with_writer(output)
+                    .build();
+
+                let mut header = BBGZHeader::new();
+                unsafe {
+                    header.add_extra_unchecked(b"ID", b"cell_1".to_vec());
+                    header.add_extra_unchecked(&crate::METADATA_SUBFIELD, vec![b'm'; 4096]);
+                }
+                let mut block = writer.begin(header);
+                block.write_all(b"cell_1\tshort\n").unwrap();
+                block.reserve(record.len());
+                block.write_all(&record).unwrap();
+                block.flush().unwrap();
+                drop(block);
+                drop(writer);
+            }
+
+            let decoded = decode_to_end(
+                &mut BBGZDecoder::builder()
+                    .with_path(&path)
+                    .countof_threads(BoundedU64::const_new::<2>())
+                    .build()
+                    .unwrap(),
+            );
+            assert_eq!(&decoded[..13], b"cell_1\tshort\n");
+            assert!(decoded[13..] == record[..], "{format:?}");
+
+            remove_file(path).unwrap();
+        }
+    }
 }
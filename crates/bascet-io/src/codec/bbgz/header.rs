@@ -46,6 +46,23 @@ impl BBGZHeader {
         return Ok(self);
     }
 
+    pub fn get_extra(&self, id: &[u8; 2]) -> Option<&[u8]> {
+        self.FEXTRA
+            .iter()
+            .find(|e| e.SI1 == id[0] && e.SI2 == id[1])
+            .map(|e| e.DATA.as_slice())
+    }
+
+    pub fn remove_extra(&mut self, id: &[u8; 2]) -> Option<Vec<u8>> {
+        let idx = self
+            .FEXTRA
+            .iter()
+            .position(|e| e.SI1 == id[0] && e.SI2 == id[1])?;
+        let extra = self.FEXTRA.remove(idx);
+        self.size -= extra.size();
+        return Some(extra.DATA);
+    }
+
     pub fn size(&self) -> usize {
         return self.size;
     }
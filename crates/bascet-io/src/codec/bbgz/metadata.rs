@@ -0,0 +1,375 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    BBGZExtra, BBGZHeader, BBGZHeaderBase, SIZEOF_ZST_FRAME_PREFIXusize, ZST_MAGIC_BLOCK_HEADER,
+    ZstBlockHeader,
+};
+
+/// Gzip subfield carrying `BBGZMetadata`. It sits next to `ID` on the first block of a file,
+/// so readers that ignore unknown subfields (htslib, tabix, the BBGZ parser) are unaffected
+pub const METADATA_SUBFIELD: [u8; 2] = *b"MD";
+
+/// Serialised metadata is capped well below the 64 KiB gzip extra field so the first block
+/// keeps room for its payload; only `source.*` entries are ever dropped to meet the cap
+pub const MAX_SIZEOF_METADATA: usize = 8 * 1024;
+
+/// Keys merged as a set union when files are combined, so a merged shard still states
+/// e.g. its chemistry at the top level
+pub const METADATA_LINEAGE_KEYS: &[&str] = &["chemistry", "library"];
+
+/// Per-library sample annotations from a debarcode sample sheet: `sample.<column>` keys with
+/// `<library>=<value>` values. Kept at the top level on merge, like the lineage keys
+pub const METADATA_SAMPLE_PREFIX: &str = "sample.";
+
+const SOURCE_PREFIX: &str = "source.";
+const TRUNCATED_KEY: &str = "source.truncated";
+
+/// Provenance of a TIRP/BBGZ file: ordered `key -> value` entries, where a key may repeat
+/// (e.g. one `input` per input file). Stored as `key\tvalue\n` lines with `\\`, `\t` and
+/// `\n` escaped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BBGZMetadata {
+    entries: Vec<(String, String)>,
+}
+
+impl BBGZMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `bascet_version`, `command` and `created` (unix seconds) for the running process
+    pub fn for_current_process() -> Self {
+        let command = std::env::args().collect::<Vec<_>>().join(" ");
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut metadata = Self::new();
+        metadata
+            .push("bascet_version", env!("CARGO_PKG_VERSION"))
+            .push("command", command)
+            .push("created", created.to_string());
+        metadata
+    }
+
+    pub fn push(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        self.entries.push((key.into(), value.into()));
+        self
+    }
+
+    /// Replace every entry for `key` with a single one
+    pub fn set(&mut self, key: impl Into<String>, value: impl Into<String>) -> &mut Self {
+        let key = key.into();
+        self.entries.retain(|(k, _)| *k != key);
+        self.entries.push((key, value.into()));
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.get_all(key).next()
+    }
+
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn entries(&self) -> &[(String, String)] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Fold the metadata of the files this one was built from into `source.*` entries, and
+    /// take the union of their `METADATA_LINEAGE_KEYS` at the top level
+    pub fn merge_sources<'a>(&mut self, sources: impl IntoIterator<Item = &'a BBGZMetadata>) {
+        for source in sources {
+            for (key, value) in &source.entries {
+                if METADATA_LINEAGE_KEYS.contains(&key.as_str())
+                    || key.starts_with(METADATA_SAMPLE_PREFIX)
+                {
+                    self.push_unique(key.clone(), value.clone());
+                }
+                let key = if key.starts_with(SOURCE_PREFIX) {
+                    key.clone()
+                } else {
+                    format!("{SOURCE_PREFIX}{key}")
+                };
+                self.push_unique(key, value.clone());
+            }
+        }
+    }
+
+    fn push_unique(&mut self, key: String, value: String) {
+        if !self.entries.iter().any(|(k, v)| *k == key && *v == value) {
+            self.entries.push((key, value));
+        }
+    }
+
+    /// Err describing the first lineage key whose values differ between two files
+    pub fn check_compatible(&self, other: &BBGZMetadata) -> Result<(), String> {
+        for key in METADATA_LINEAGE_KEYS.iter().filter(|k| **k != "library") {
+            let mut ours: Vec<&str> = self.get_all(key).collect();
+            let mut theirs: Vec<&str> = other.get_all(key).collect();
+            ours.sort_unstable();
+            theirs.sort_unstable();
+            if !ours.is_empty() && !theirs.is_empty() && ours != theirs {
+                return Err(format!("{key} differs: {ours:?} vs {theirs:?}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Serialise, dropping trailing `source.*` entries (with a warning each) if over
+    /// `MAX_SIZEOF_METADATA`. Err if the other entries alone do not fit
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let bytes = encode_entries(&self.entries);
+        if bytes.len() <= MAX_SIZEOF_METADATA {
+            return Ok(bytes);
+        }
+
+        let mut entries = self.entries.clone();
+        let marker = (TRUNCATED_KEY.to_string(), "true".to_string());
+        loop {
+            let with_marker: Vec<_> = entries.iter().cloned().chain([marker.clone()]).collect();
+            let bytes = encode_entries(&with_marker);
+            if bytes.len() <= MAX_SIZEOF_METADATA {
+                return Ok(bytes);
+            }
+            let Some(idx) = entries
+                .iter()
+                .rposition(|(k, _)| k.starts_with(SOURCE_PREFIX))
+            else {
+                anyhow::bail!(
+                    "metadata is {} bytes without its source entries, over the {MAX_SIZEOF_METADATA} byte limit",
+                    bytes.len()
+                );
+            };
+            let (key, value) = entries.remove(idx);
+            tracing::warn!(
+                key = %key,
+                value = %value,
+                "Dropping source metadata entry over the size limit"
+            );
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let text = std::str::from_utf8(bytes)?;
+        let mut metadata = Self::new();
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let Some((key, value)) = line.split_once('\t') else {
+                anyhow::bail!("metadata line {line:?} has no tab separator");
+            };
+            metadata.push(unescape(key), unescape(value));
+        }
+        Ok(metadata)
+    }
+
+    /// Metadata of a BBGZ/zst TIRP file, read from the header of its first block
+    pub fn read_from_path(path: impl AsRef<Path>) -> anyhow::Result<Option<Self>> {
+        let mut file = File::open(path.as_ref())?;
+        let mut base = [0u8; BBGZHeaderBase::SSIZE];
+        const FLG_FEXTRA: u8 = 0x04;
+        if file.read_exact(&mut base).is_err() {
+            return Ok(None);
+        }
+        // NOTE:    zst files carry the same subfields in the header frame of their first block
+        if base[..4] == ZST_MAGIC_BLOCK_HEADER.to_le_bytes() {
+            let sizeof_frame = u32::from_le_bytes([base[4], base[5], base[6], base[7]]) as usize;
+            let mut header_bytes = base.to_vec();
+            header_bytes.resize(
+                (SIZEOF_ZST_FRAME_PREFIXusize + sizeof_frame).max(BBGZHeaderBase::SSIZE),
+                0,
+            );
+            file.read_exact(&mut header_bytes[BBGZHeaderBase::SSIZE..])?;
+
+            let zst_header = ZstBlockHeader::from_bytes(&header_bytes)
+                .map_err(|_| anyhow::anyhow!("malformed zst block header"))?;
+            return zst_header
+                .header
+                .get_extra(&METADATA_SUBFIELD)
+                .map(Self::from_bytes)
+                .transpose();
+        }
+        if base[..2] != [0x1f, 0x8b] || base[3] & FLG_FEXTRA == 0 {
+            return Ok(None);
+        }
+        let xlen = u16::from_le_bytes([base[10], base[11]]) as usize;
+        let mut header_bytes = base.to_vec();
+        header_bytes.resize(BBGZHeaderBase::SSIZE + xlen, 0);
+        file.read_exact(&mut header_bytes[BBGZHeaderBase::SSIZE..])?;
+
+        let header = BBGZHeader::from_bytes(&header_bytes)
+            .map_err(|_| anyhow::anyhow!("malformed gzip header"))?;
+        header
+            .get_extra(&METADATA_SUBFIELD)
+            .map(Self::from_bytes)
+            .transpose()
+    }
+
+    /// Metadata for a file built from `paths`: the running process plus the merged metadata
+    /// of every input that carries some. Inputs with conflicting lineage are warned about
+    pub fn derive_from_paths<P: AsRef<Path>>(paths: impl IntoIterator<Item = P>) -> Self {
+        let mut sources: Vec<Self> = Vec::new();
+        for path in paths {
+            let path = path.as_ref();
+            match Self::read_from_path(path) {
+                Ok(Some(source)) => {
+                    if let Some(Err(e)) =
+                        sources.first().map(|first| first.check_compatible(&source))
+                    {
+                        tracing::warn!(path = %path.display(), "Merging incompatible inputs: {e}");
+                    }
+                    sources.push(source);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    tracing::warn!(path = %path.display(), error = %e, "Failed to read metadata")
+                }
+            }
+        }
+
+        let mut metadata = Self::for_current_process();
+        metadata.merge_sources(&sources);
+        metadata
+    }
+
+    /// Size the metadata adds to a block header as an extra subfield
+    pub fn sizeof_subfield(&self) -> anyhow::Result<usize> {
+        Ok(BBGZExtra::SSIZE + self.to_bytes()?.len())
+    }
+
+    pub fn write_text<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for (key, value) in &self.entries {
+            writeln!(writer, "{key}\t{value}")?;
+        }
+        Ok(())
+    }
+}
+
+fn encode_entries(entries: &[(String, String)]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (key, value) in entries {
+        out.extend_from_slice(escape(key).as_bytes());
+        out.push(b'\t');
+        out.extend_from_slice(escape(value).as_bytes());
+        out.push(b'\n');
+    }
+    out
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrips_escaped_entries() {
+        let mut metadata = BBGZMetadata::new();
+        metadata
+            .push("chemistry", "atrandi-wgs")
+            .push("command", "bascet getraw\t--r1 a\\b.fq\nx");
+        let parsed = BBGZMetadata::from_bytes(&metadata.to_bytes().unwrap()).unwrap();
+        assert_eq!(parsed, metadata);
+    }
+
+    #[test]
+    fn merges_sources_and_checks_compatibility() {
+        let mut a = BBGZMetadata::new();
+        a.push("chemistry", "atrandi-wgs").push("library", "lib1");
+        let mut b = BBGZMetadata::new();
+        b.push("chemistry", "atrandi-wgs").push("library", "lib2");
+
+        let mut merged = BBGZMetadata::new();
+        merged.merge_sources([&a, &b]);
+        assert_eq!(
+            merged.get_all("chemistry").collect::<Vec<_>>(),
+            ["atrandi-wgs"]
+        );
+        assert_eq!(
+            merged.get_all("library").collect::<Vec<_>>(),
+            ["lib1", "lib2"]
+        );
+        assert_eq!(merged.get("source.library"), Some("lib1"));
+        assert!(merged.check_compatible(&a).is_ok());
+
+        let mut c = BBGZMetadata::new();
+        c.push("chemistry", "petriseq");
+        assert!(merged.check_compatible(&c).is_err());
+    }
+
+    #[test]
+    fn keeps_sample_annotations_on_merge() {
+        let mut a = BBGZMetadata::new();
+        a.push("sample.donor", "lib1=d1");
+        let mut b = BBGZMetadata::new();
+        b.push("sample.donor", "lib2=d2")
+            .push("sample.donor", "lib1=d1");
+
+        let mut merged = BBGZMetadata::new();
+        merged.merge_sources([&a, &b]);
+        assert_eq!(
+            merged.get_all("sample.donor").collect::<Vec<_>>(),
+            ["lib1=d1", "lib2=d2"]
+        );
+    }
+
+    #[test]
+    fn truncates_source_entries_over_cap() {
+        let mut metadata = BBGZMetadata::new();
+        metadata.push("chemistry", "atrandi-wgs");
+        for i in 0..2000 {
+            metadata.push(
+                "source.input",
+                format!("/some/long/path/to/input_{i}.tirp.gz"),
+            );
+        }
+        let bytes = metadata.to_bytes().unwrap();
+        assert!(bytes.len() <= MAX_SIZEOF_METADATA);
+        let parsed = BBGZMetadata::from_bytes(&bytes).unwrap();
+        assert_eq!(parsed.get("chemistry"), Some("atrandi-wgs"));
+        assert_eq!(parsed.get(TRUNCATED_KEY), Some("true"));
+    }
+
+    #[test]
+    fn errors_instead_of_dropping_lineage_over_cap() {
+        let mut metadata = BBGZMetadata::new();
+        for i in 0..2000 {
+            metadata.push("sample.donor", format!("library_{i}=donor_{i}"));
+        }
+        assert!(metadata.to_bytes().is_err());
+    }
+}
@@ -16,7 +16,7 @@ use crossbeam::channel::{Receiver, Sender};
 use flate2::{Compress as FlateCompress, FlushCompress, Status};
 
 use crate::{
-    BBGZTrailer, BBGZWriteBlock, Compression,
+    BBGZTrailer, BBGZWriteBlock, BlockFormat, Compression, ZstBlockHeader, ZstSeekTable,
     codec::bbgz::{
         BBGZHeader, MARKER_EOF, MAX_SIZEOF_BLOCK, MAX_SIZEOF_BLOCKusize, MAX_SIZEOF_RAW_BLOCKusize,
     },
@@ -101,21 +101,96 @@ impl BbgzCompressor {
     }
 }
 
+struct ZstCompressor {
+    compression_level: Compression,
+    inner: zstd::bulk::Compressor<'static>,
+}
+
+impl ZstCompressor {
+    fn new(compression_level: Compression) -> Self {
+        // NOTE:    the 0..=12 deflate scale is passed through as is; zstd reads 0 as its default
+        let mut inner =
+            zstd::bulk::Compressor::new(compression_level.level()).expect("zstd init failed");
+        inner.include_checksum(true).expect("zstd init failed");
+        inner.include_contentsize(true).expect("zstd init failed");
+        Self {
+            compression_level,
+            inner,
+        }
+    }
+
+    fn compress_into(
+        &mut self,
+        raw: &mut ArenaSlice<u8>,
+        compression_alloc: &ArenaPool<u8>,
+    ) -> ArenaSlice<u8> {
+        // A raw block of at most MAX_SIZEOF_RAW_BLOCK bytes stays within the zstd
+        // compress bound (input + input/256 + frame overhead) of a full block buffer.
+        let mut compressed = compression_alloc.alloc(MAX_SIZEOF_BLOCKusize);
+        let total_out = self
+            .inner
+            .compress_to_buffer(raw.as_slice(), compressed.as_mut_slice())
+            .expect("zstd compression failed");
+
+        unsafe { compressed.truncate(total_out) }
+    }
+}
+
+enum BlockCompressor {
+    Bbgz(BbgzCompressor),
+    Zst(ZstCompressor),
+}
+
+impl BlockCompressor {
+    fn new(format: BlockFormat, compression_level: Compression) -> Self {
+        match format {
+            BlockFormat::Bbgz => Self::Bbgz(BbgzCompressor::new(compression_level)),
+            BlockFormat::Zst => Self::Zst(ZstCompressor::new(compression_level)),
+        }
+    }
+
+    fn is_for(&self, format: BlockFormat, compression_level: Compression) -> bool {
+        match self {
+            Self::Bbgz(c) => {
+                format == BlockFormat::Bbgz
+                    && c.compression_level.level() == compression_level.level()
+            }
+            Self::Zst(c) => {
+                format == BlockFormat::Zst
+                    && c.compression_level.level() == compression_level.level()
+            }
+        }
+    }
+
+    fn compress_into(
+        &mut self,
+        raw: &mut ArenaSlice<u8>,
+        compression_alloc: &ArenaPool<u8>,
+    ) -> ArenaSlice<u8> {
+        match self {
+            Self::Bbgz(c) => c.compress_into(raw, compression_alloc),
+            Self::Zst(c) => c.compress_into(raw, compression_alloc),
+        }
+    }
+}
+
 thread_local! {
-    static THREAD_BBGZ_COMPRESSOR: std::cell::RefCell<Option<BbgzCompressor>> =
+    static THREAD_BBGZ_COMPRESSOR: std::cell::RefCell<Option<BlockCompressor>> =
         std::cell::RefCell::new(None);
 }
 
 fn with_thread_bbgz_compressor<R>(
+    format: BlockFormat,
     compression_level: Compression,
-    f: impl FnOnce(&mut BbgzCompressor) -> R,
+    f: impl FnOnce(&mut BlockCompressor) -> R,
 ) -> R {
     THREAD_BBGZ_COMPRESSOR.with(|cell| {
         let mut slot = cell.borrow_mut();
-        if !slot.as_ref().is_some_and(|compressor| {
-            compressor.compression_level.level() == compression_level.level()
-        }) {
-            *slot = Some(BbgzCompressor::new(compression_level));
+        if !slot
+            .as_ref()
+            .is_some_and(|compressor| compressor.is_for(format, compression_level))
+        {
+            *slot = Some(BlockCompressor::new(format, compression_level));
         }
         f(slot.as_mut().unwrap())
     })
@@ -207,6 +282,8 @@ impl BBGZWriter {
             { u64::MAX },
         >,
         #[builder(default = Compression::balanced())] compression_level: Compression,
+        // NOTE:    zst writes seekable zstd blocks instead of BBGZ, see `BlockFormat`
+        #[builder(default)] format: BlockFormat,
         with_opt_raw_arena_pool: Option<Arc<ArenaPool<u8>>>,
         with_opt_compression_arena_pool: Option<Arc<ArenaPool<u8>>>,
         with_opt_compression_limiter: Option<Arc<BBGZCompressionLimiter>>,
@@ -244,6 +321,7 @@ impl BBGZWriter {
             Self::spawn_rayon_compression_dispatcher(
                 Arc::clone(&compression_allocator),
                 compression_rx,
+                format,
                 compression_level,
                 write_tx,
                 rayon_pool,
@@ -253,13 +331,14 @@ impl BBGZWriter {
             Self::spawn_compression_workers(
                 Arc::clone(&compression_allocator),
                 compression_rx,
+                format,
                 compression_level,
                 write_tx,
                 countof_threads,
             )
         };
 
-        let write_worker = Self::spawn_write_worker(with_writer, write_rx);
+        let write_worker = Self::spawn_write_worker(with_writer, format, write_rx);
 
         return Self {
             inner_raw_allocator: raw_allocator,
@@ -303,6 +382,7 @@ impl BBGZWriter {
     fn spawn_compression_workers(
         compression_alloc: Arc<ArenaPool<u8>>,
         compression_rx: Receiver<(usize, BBGZCompressionJob)>,
+        format: BlockFormat,
         compression_level: Compression,
         write_tx: OrderedDenseSender<BBGZCompressionResult, 16384>,
         countof_threads: BoundedU64<1, { u64::MAX }>,
@@ -317,7 +397,7 @@ impl BBGZWriter {
                 std::thread::Builder::new()
                     .name(format!("BBGZCompression@{}", idx))
                     .spawn(move || {
-                        let mut thread_compressor = BbgzCompressor::new(compression_level);
+                        let mut thread_compressor = BlockCompressor::new(format, compression_level);
 
                         loop {
                             let (k, job) = match thread_compression_rx.recv() {
@@ -353,6 +433,7 @@ impl BBGZWriter {
     fn spawn_rayon_compression_dispatcher(
         compression_alloc: Arc<ArenaPool<u8>>,
         compression_rx: Receiver<(usize, BBGZCompressionJob)>,
+        format: BlockFormat,
         compression_level: Compression,
         write_tx: OrderedDenseSender<BBGZCompressionResult, 16384>,
         rayon_pool: Arc<rayon::ThreadPool>,
@@ -390,10 +471,13 @@ impl BBGZWriter {
                             let _permit = permit;
                             let mut buf_raw = job.raw;
                             let crc32_raw = crc32fast::hash(buf_raw.as_slice());
-                            let buf_compressed =
-                                with_thread_bbgz_compressor(compression_level, |compressor| {
+                            let buf_compressed = with_thread_bbgz_compressor(
+                                format,
+                                compression_level,
+                                |compressor| {
                                     compressor.compress_into(&mut buf_raw, &task_compression_alloc)
-                                });
+                                },
+                            );
 
                             let job_result = BBGZCompressionResult {
                                 header: job.header,
@@ -414,6 +498,7 @@ impl BBGZWriter {
 
     fn spawn_write_worker<W>(
         mut writer: W,
+        format: BlockFormat,
         mut write_rx: OrderedDenseReceiver<BBGZCompressionResult, 16384>,
     ) -> JoinHandle<()>
     where
@@ -422,6 +507,7 @@ impl BBGZWriter {
         std::thread::Builder::new()
             .name("BBGZWrite@0".to_string())
             .spawn(move || {
+                let mut seek_table = ZstSeekTable::new();
                 loop {
                     let res = match write_rx.recv() {
                         Ok(r) => r,
@@ -430,14 +516,30 @@ impl BBGZWriter {
 
                     let mut header = res.header;
                     let compressed = res.compressed;
-                    let trailer = BBGZTrailer::new(res.crc32, res.isize.try_into().unwrap());
 
-                    let _ = header.write_with_csize(&mut writer, compressed.len());
-                    let _ = writer.write_all(&compressed.as_slice());
-                    let _ = trailer.write_with(&mut writer);
+                    match format {
+                        BlockFormat::Bbgz => {
+                            let trailer =
+                                BBGZTrailer::new(res.crc32, res.isize.try_into().unwrap());
+                            let _ = header.write_with_csize(&mut writer, compressed.len());
+                            let _ = writer.write_all(&compressed.as_slice());
+                            let _ = trailer.write_with(&mut writer);
+                        }
+                        BlockFormat::Zst => {
+                            // NOTE:    zstd frames carry their own checksum, crc32 is unused
+                            let zst_header =
+                                ZstBlockHeader::new(header, compressed.len(), res.isize);
+                            let _ = zst_header.write_with(&mut writer);
+                            let _ = writer.write_all(&compressed.as_slice());
+                            seek_table.push(zst_header.sizeof_block(), res.isize);
+                        }
+                    }
                 }
 
-                let _ = writer.write_all(&MARKER_EOF);
+                let _ = match format {
+                    BlockFormat::Bbgz => writer.write_all(&MARKER_EOF),
+                    BlockFormat::Zst => seek_table.write_with(&mut writer),
+                };
                 let _ = writer.flush();
             })
             .unwrap()
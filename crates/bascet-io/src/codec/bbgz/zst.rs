@@ -0,0 +1,307 @@
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{BBGZExtra, BBGZHeader};
+
+// A zst block is a zstd skippable frame carrying the block header, followed by the zstd
+// frame(s) holding the payload:
+//
+//   [MAGIC u32][FRAME SIZE u32][CSIZE u32][ISIZE u32][SI1 SI2 LEN DATA]...   [zstd frames]
+//
+// The subfields use the BBGZ extra layout, so `ID` and `MD` mean the same thing in both
+// formats. CSIZE is the size of the zstd frames that follow, ISIZE their decoded size.
+// A merged block may hold several frames; zstd decodes concatenated frames as one.
+//
+// The file ends with the seek table of the zstd seekable format instead of the BBGZ EOF
+// marker. Each entry spans a header frame and its payload frames, so seekable-format
+// readers land on block boundaries. Plain `zstd -d` skips the skippable frames and
+// decodes the file to the concatenated records.
+
+/// Skippable frame magic used for zst block headers
+pub const ZST_MAGIC_BLOCK_HEADER: u32 = 0x184D2A5B;
+/// Skippable frame magic of the zstd seekable format seek table
+pub const ZST_MAGIC_SEEK_TABLE: u32 = 0x184D2A5E;
+/// Magic closing the seek table footer
+pub const ZST_MAGIC_SEEKABLE: u32 = 0x8F92EAB1;
+
+/// Skippable frame magic + frame size
+#[allow(non_upper_case_globals)]
+pub const SIZEOF_ZST_FRAME_PREFIXusize: usize = 8;
+/// Number of frames + descriptor + seekable magic
+#[allow(non_upper_case_globals)]
+pub const SIZEOF_ZST_SEEK_FOOTERusize: usize = 9;
+
+const ZST_SEEK_DESCRIPTOR_CHECKSUM: u8 = 0x80;
+
+/// On-disk block format of a TIRP file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum BlockFormat {
+    /// Gzip-compatible deflate blocks with BGZF/BBGZ extra subfields
+    #[default]
+    Bbgz,
+    /// Seekable zstd with one header frame per block
+    Zst,
+}
+
+impl BlockFormat {
+    /// Format implied by an output path: `.zst` selects zstd, anything else BBGZ
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension() {
+            Some(ext) if ext == "zst" => Self::Zst,
+            _ => Self::Bbgz,
+        }
+    }
+
+    /// Format of a file starting with `bytes`, if it is either of the two
+    pub fn detect(bytes: &[u8]) -> Option<Self> {
+        if bytes.starts_with(&[0x1f, 0x8b]) {
+            return Some(Self::Bbgz);
+        }
+        let magic = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+        match magic {
+            // NOTE:    an empty zst file is only its seek table
+            ZST_MAGIC_BLOCK_HEADER | ZST_MAGIC_SEEK_TABLE => Some(Self::Zst),
+            _ => None,
+        }
+    }
+
+    /// Format of the file at `path`, read from its first bytes
+    pub fn detect_path<P: AsRef<Path>>(path: P) -> std::io::Result<Option<Self>> {
+        let mut magic = [0u8; 4];
+        let mut file = std::fs::File::open(path)?;
+        match file.read_exact(&mut magic) {
+            Ok(()) => Ok(Self::detect(&magic)),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Extension of TIRP files in this format
+    pub fn tirp_extension(&self) -> &'static str {
+        match self {
+            Self::Bbgz => "tirp.bbgz",
+            Self::Zst => "tirp.zst",
+        }
+    }
+}
+
+/// Header frame of a zst block. Only the extra subfields of `header` are stored; BC and the
+/// gzip trailer are replaced by `csize` and `isize`.
+#[derive(Debug, Clone)]
+pub struct ZstBlockHeader {
+    pub header: BBGZHeader,
+    pub csize: u32,
+    pub isize: u32,
+}
+
+impl ZstBlockHeader {
+    /// Magic, frame size, CSIZE and ISIZE
+    pub const SSIZE: usize = SIZEOF_ZST_FRAME_PREFIXusize + 8;
+
+    pub fn new(header: BBGZHeader, csize: usize, isize: usize) -> Self {
+        Self {
+            header,
+            csize: csize.try_into().expect("Overflow"),
+            isize: isize.try_into().expect("Overflow"),
+        }
+    }
+
+    /// Size of the header frame on disk
+    pub fn size(&self) -> usize {
+        Self::SSIZE
+            + self
+                .header
+                .FEXTRA
+                .iter()
+                .map(BBGZExtra::size)
+                .sum::<usize>()
+    }
+
+    /// Size of the whole block: header frame and payload frames
+    pub fn sizeof_block(&self) -> usize {
+        self.size() + self.csize as usize
+    }
+
+    /// Parse a header frame; `bytes` may extend past it
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ()> {
+        let word = |at: usize| -> Result<u32, ()> {
+            let b = bytes.get(at..at + 4).ok_or(())?;
+            Ok(u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        if word(0)? != ZST_MAGIC_BLOCK_HEADER {
+            return Err(());
+        }
+        let frame_end = SIZEOF_ZST_FRAME_PREFIXusize + word(4)? as usize;
+        if frame_end < Self::SSIZE || bytes.len() < frame_end {
+            return Err(());
+        }
+
+        let mut header = BBGZHeader::new();
+        let mut cursor = Self::SSIZE;
+        while cursor < frame_end {
+            if cursor + BBGZExtra::SSIZE > frame_end {
+                return Err(());
+            }
+            let (si1, si2) = (bytes[cursor], bytes[cursor + 1]);
+            let len = u16::from_le_bytes([bytes[cursor + 2], bytes[cursor + 3]]) as usize;
+            let data_start = cursor + BBGZExtra::SSIZE;
+            let data_end = data_start + len;
+            if data_end > frame_end {
+                return Err(());
+            }
+            header
+                .add_extra(&[si1, si2], bytes[data_start..data_end].to_vec())
+                .map_err(|_| ())?;
+            cursor = data_end;
+        }
+
+        Ok(Self {
+            header,
+            csize: word(8)?,
+            isize: word(12)?,
+        })
+    }
+
+    pub fn write_with<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let sizeof_frame: u32 = (self.size() - SIZEOF_ZST_FRAME_PREFIXusize)
+            .try_into()
+            .expect("Overflow");
+        writer.write_all(&ZST_MAGIC_BLOCK_HEADER.to_le_bytes())?;
+        writer.write_all(&sizeof_frame.to_le_bytes())?;
+        writer.write_all(&self.csize.to_le_bytes())?;
+        writer.write_all(&self.isize.to_le_bytes())?;
+
+        for extra in &self.header.FEXTRA {
+            writer.write_all(&[extra.SI1, extra.SI2])?;
+            writer.write_all(&(extra.DATA.len() as u16).to_le_bytes())?;
+            writer.write_all(&extra.DATA)?;
+        }
+        Ok(())
+    }
+}
+
+/// One seek table entry per block: (size on disk, decoded size)
+#[derive(Debug, Clone, Default)]
+pub struct ZstSeekTable {
+    pub entries: Vec<(u32, u32)>,
+}
+
+impl ZstSeekTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, sizeof_block: usize, isize: usize) {
+        self.entries.push((
+            sizeof_block.try_into().expect("Overflow"),
+            isize.try_into().expect("Overflow"),
+        ));
+    }
+
+    pub fn write_with<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let countof_entries: u32 = self.entries.len().try_into().expect("Overflow");
+        let sizeof_frame = self.entries.len() * 8 + SIZEOF_ZST_SEEK_FOOTERusize;
+        let sizeof_frame: u32 = sizeof_frame.try_into().expect("Overflow");
+
+        writer.write_all(&ZST_MAGIC_SEEK_TABLE.to_le_bytes())?;
+        writer.write_all(&sizeof_frame.to_le_bytes())?;
+        for (sizeof_block, isize) in &self.entries {
+            writer.write_all(&sizeof_block.to_le_bytes())?;
+            writer.write_all(&isize.to_le_bytes())?;
+        }
+        writer.write_all(&countof_entries.to_le_bytes())?;
+        writer.write_all(&[0])?;
+        writer.write_all(&ZST_MAGIC_SEEKABLE.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Read the seek table from the end of a zst file
+    pub fn read_from<R: Read + Seek>(reader: &mut R) -> anyhow::Result<Self> {
+        let mut footer = [0u8; SIZEOF_ZST_SEEK_FOOTERusize];
+        reader.seek(SeekFrom::End(-(SIZEOF_ZST_SEEK_FOOTERusize as i64)))?;
+        reader.read_exact(&mut footer)?;
+        if u32::from_le_bytes(footer[5..9].try_into().unwrap()) != ZST_MAGIC_SEEKABLE {
+            anyhow::bail!("zst file does not end with a seek table");
+        }
+
+        let countof_entries = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as usize;
+        let sizeof_entry = if footer[4] & ZST_SEEK_DESCRIPTOR_CHECKSUM != 0 {
+            12
+        } else {
+            8
+        };
+        let sizeof_entries = countof_entries * sizeof_entry;
+        let mut entries = vec![0u8; sizeof_entries];
+        reader.seek(SeekFrom::End(
+            -((sizeof_entries + SIZEOF_ZST_SEEK_FOOTERusize) as i64),
+        ))?;
+        reader.read_exact(&mut entries)?;
+
+        Ok(Self {
+            entries: entries
+                .chunks_exact(sizeof_entry)
+                .map(|entry| {
+                    (
+                        u32::from_le_bytes(entry[0..4].try_into().unwrap()),
+                        u32::from_le_bytes(entry[4..8].try_into().unwrap()),
+                    )
+                })
+                .collect(),
+        })
+    }
+
+    /// Byte offset of every block, in file order
+    pub fn block_offsets(&self) -> impl Iterator<Item = u64> + '_ {
+        self.entries.iter().scan(0u64, |offset, (sizeof_block, _)| {
+            let start = *offset;
+            *offset += *sizeof_block as u64;
+            Some(start)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_frame_roundtrips_extras() {
+        let mut header = BBGZHeader::new();
+        header.add_extra(b"ID", b"AAACCCGGGTTT".to_vec()).unwrap();
+        header.add_extra(b"MD", b"key\tvalue\n".to_vec()).unwrap();
+        let zst_header = ZstBlockHeader::new(header, 1234, 56789);
+
+        let mut bytes = Vec::new();
+        zst_header.write_with(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), zst_header.size());
+        assert_eq!(BlockFormat::detect(&bytes), Some(BlockFormat::Zst));
+
+        let parsed = ZstBlockHeader::from_bytes(&bytes).unwrap();
+        assert_eq!((parsed.csize, parsed.isize), (1234, 56789));
+        assert_eq!(parsed.header.get_extra(b"ID"), Some(&b"AAACCCGGGTTT"[..]));
+        assert_eq!(parsed.header.get_extra(b"MD"), Some(&b"key\tvalue\n"[..]));
+        assert!(ZstBlockHeader::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn seek_table_roundtrips() {
+        let mut table = ZstSeekTable::new();
+        table.push(100, 400);
+        table.push(250, 1000);
+
+        let mut bytes = b"payload".to_vec();
+        table.write_with(&mut bytes).unwrap();
+        let parsed = ZstSeekTable::read_from(&mut std::io::Cursor::new(bytes)).unwrap();
+        assert_eq!(parsed.entries, vec![(100, 400), (250, 1000)]);
+        assert_eq!(parsed.block_offsets().collect::<Vec<_>>(), vec![0, 100]);
+    }
+
+    #[test]
+    fn format_follows_extension() {
+        assert_eq!(BlockFormat::from_path("a/b.tirp.zst"), BlockFormat::Zst);
+        assert_eq!(BlockFormat::from_path("a/b.tirp.bbgz"), BlockFormat::Bbgz);
+        assert_eq!(BlockFormat::from_path("a/b.tirp.gz"), BlockFormat::Bbgz);
+    }
+}
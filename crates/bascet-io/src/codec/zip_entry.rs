@@ -0,0 +1,124 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::bail;
+use bascet_core::{Decode, DecodeResult};
+use bytesize::ByteSize;
+use zip::CompressionMethod;
+use zip::read::ZipArchive;
+
+///
+/// Decoder over a single entry of a zip archive, inflated as it is read. A `ZipFile` borrows
+/// its archive, so the entry is only located through the archive and then read through a
+/// separate handle on the same archive (`with_reader`, e.g. the file opened again). Stored,
+/// deflated and zstd entries are supported, and the CRC is checked at the end of the entry.
+/// An optional trailer (e.g. `parse::fasta::FASTA_TRAILER`) is emitted after the entry
+///
+pub struct ZipEntryDecoder {
+    inner_name: String,
+    inner_reader: Box<dyn Read + Send>,
+    inner_crc: crc32fast::Hasher,
+    inner_crc_expected: u32,
+    inner_is_entry_done: bool,
+    inner_trailer: Vec<u8>,
+    inner_trailer_cursor: usize,
+    sizeof_target_alloc: ByteSize,
+}
+
+#[bon::bon]
+impl ZipEntryDecoder {
+    #[builder]
+    pub fn new<R, S>(
+        with_archive: &mut ZipArchive<R>,
+        with_entry: usize,
+        mut with_reader: S,
+        #[builder(default = &[])] with_trailer: &[u8],
+        #[builder(default = ByteSize::mib(4))] sizeof_target_alloc: ByteSize,
+    ) -> anyhow::Result<Self>
+    where
+        R: Read + Seek,
+        S: Read + Seek + Send + 'static,
+    {
+        let entry = with_archive.by_index_raw(with_entry)?;
+        let name = entry.name().to_string();
+        if entry.encrypted() {
+            bail!("zip entry {name} is encrypted");
+        }
+        let data_start = entry.data_start();
+        let compressed_size = entry.compressed_size();
+        let compression = entry.compression();
+        let crc_expected = entry.crc32();
+        drop(entry);
+
+        with_reader.seek(SeekFrom::Start(data_start))?;
+        let raw = with_reader.take(compressed_size);
+        let reader: Box<dyn Read + Send> = match compression {
+            CompressionMethod::Stored => Box::new(raw),
+            CompressionMethod::Deflated => Box::new(flate2::read::DeflateDecoder::new(raw)),
+            CompressionMethod::Zstd => Box::new(zstd::stream::read::Decoder::new(raw)?),
+            other => bail!("zip entry {name} uses unsupported compression {other:?}"),
+        };
+
+        Ok(ZipEntryDecoder {
+            inner_name: name,
+            inner_reader: reader,
+            inner_crc: crc32fast::Hasher::new(),
+            inner_crc_expected: crc_expected,
+            inner_is_entry_done: false,
+            inner_trailer: with_trailer.to_vec(),
+            inner_trailer_cursor: 0,
+            sizeof_target_alloc: sizeof_target_alloc,
+        })
+    }
+
+    fn decode_entry(&mut self, buf: &mut [u8]) -> anyhow::Result<usize> {
+        loop {
+            match self.inner_reader.read(buf) {
+                Ok(0) => break,
+                Ok(n) => {
+                    self.inner_crc.update(&buf[..n]);
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => bail!("failed to read zip entry {}: {e}", self.inner_name),
+            }
+        }
+
+        self.inner_is_entry_done = true;
+        let crc = std::mem::take(&mut self.inner_crc).finalize();
+        if crc != self.inner_crc_expected {
+            bail!(
+                "zip entry {} is corrupt: CRC {crc:08x}, expected {:08x}",
+                self.inner_name,
+                self.inner_crc_expected
+            );
+        }
+        Ok(0)
+    }
+}
+
+impl Decode for ZipEntryDecoder {
+    fn sizeof_target_alloc(&self) -> usize {
+        self.sizeof_target_alloc.as_u64() as usize
+    }
+
+    fn decode_into<B: AsMut<[u8]>>(&mut self, mut buf: B) -> DecodeResult {
+        let buf = buf.as_mut();
+        if !self.inner_is_entry_done {
+            match self.decode_entry(buf) {
+                Ok(0) => {}
+                Ok(n) => return DecodeResult::Decoded(n),
+                Err(e) => return DecodeResult::Error(e),
+            }
+        }
+
+        let remaining = &self.inner_trailer[self.inner_trailer_cursor..];
+        if remaining.is_empty() {
+            return DecodeResult::Eof;
+        }
+
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.inner_trailer_cursor += n;
+        DecodeResult::Decoded(n)
+    }
+}
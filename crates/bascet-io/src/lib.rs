@@ -1,4 +1,6 @@
 pub mod codec;
 pub mod parse;
+pub mod serialise;
 pub use codec::*;
 pub use parse::*;
+pub use serialise::*;
@@ -1,7 +1,9 @@
 mod bbgz;
+pub mod fasta;
 pub mod fastq;
 pub mod tirp;
 
 pub use bbgz::bbgz::{BBGZBlock, BBGZParser, bbgz_parser};
+pub use fasta::Fasta;
 pub use fastq::Fastq;
 pub use tirp::Tirp;
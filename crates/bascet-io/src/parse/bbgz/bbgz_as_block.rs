@@ -2,11 +2,72 @@ use bascet_core::{ArenaSlice, Parse, ParseResult};
 use smallvec::{SmallVec, smallvec};
 
 use crate::{
-    BBGZExtra, BBGZHeaderBase, BBGZTrailer,
+    BBGZExtra, BBGZHeaderBase, BBGZTrailer, SIZEOF_ZST_FRAME_PREFIXusize, ZST_MAGIC_BLOCK_HEADER,
+    ZST_MAGIC_SEEK_TABLE, ZstBlockHeader,
     codec::bbgz::{MARKER_EOF, MAX_SIZEOF_BLOCKusize, MIN_SIZEOF_HEADERusize},
     parse::{BBGZBlock, BBGZParser},
 };
 
+/// Layout of a zst block at the start of a slice. Zst blocks have no trailer, so their
+/// `Trailer` attribute is empty
+enum ZstBlockSpan {
+    Block {
+        id: std::ops::Range<usize>,
+        sizeof_header: usize,
+        sizeof_block: usize,
+    },
+    /// More bytes are needed to see the whole block
+    Incomplete,
+    /// The seek table, which ends the file like the BBGZ EOF marker
+    SeekTable,
+}
+
+fn is_zst_frame(slice: &[u8]) -> bool {
+    slice.get(..4).is_some_and(|magic| {
+        magic == ZST_MAGIC_BLOCK_HEADER.to_le_bytes() || magic == ZST_MAGIC_SEEK_TABLE.to_le_bytes()
+    })
+}
+
+fn zst_block_span(slice: &[u8]) -> anyhow::Result<ZstBlockSpan> {
+    if slice.starts_with(&ZST_MAGIC_SEEK_TABLE.to_le_bytes()) {
+        return Ok(ZstBlockSpan::SeekTable);
+    }
+    if slice.len() < ZstBlockHeader::SSIZE {
+        return Ok(ZstBlockSpan::Incomplete);
+    }
+
+    let word = |at: usize| u32::from_le_bytes(slice[at..at + 4].try_into().unwrap()) as usize;
+    let sizeof_header = SIZEOF_ZST_FRAME_PREFIXusize + word(4);
+    let sizeof_block = sizeof_header + word(8);
+    if sizeof_header < ZstBlockHeader::SSIZE {
+        return Err(anyhow::anyhow!(
+            "invalid zst block header frame size: {sizeof_header}"
+        ));
+    }
+    if slice.len() < sizeof_block {
+        return Ok(ZstBlockSpan::Incomplete);
+    }
+
+    let mut cursor_fextra = ZstBlockHeader::SSIZE;
+    while cursor_fextra + BBGZExtra::SSIZE <= sizeof_header {
+        let len = u16::from_le_bytes([slice[cursor_fextra + 2], slice[cursor_fextra + 3]]) as usize;
+        let pos_begin_data = cursor_fextra + BBGZExtra::SSIZE;
+        let pos_end_data = pos_begin_data + len;
+        if pos_end_data > sizeof_header {
+            break;
+        }
+        if slice[cursor_fextra..cursor_fextra + 2] == *b"ID" && len > 0 {
+            return Ok(ZstBlockSpan::Block {
+                id: pos_begin_data..pos_end_data,
+                sizeof_header,
+                sizeof_block,
+            });
+        }
+        cursor_fextra = pos_end_data;
+    }
+    Err(anyhow::anyhow!("missing ID subfield in zst block header"))
+}
+
 impl Parse<ArenaSlice<u8>> for BBGZParser {
     type Item = BBGZBlock;
 
@@ -18,6 +79,35 @@ impl Parse<ArenaSlice<u8>> for BBGZParser {
             return ParseResult::Partial;
         }
 
+        if is_zst_frame(slice_remaining) {
+            let (id, sizeof_header, sizeof_block) = match zst_block_span(slice_remaining) {
+                Ok(ZstBlockSpan::Block {
+                    id,
+                    sizeof_header,
+                    sizeof_block,
+                }) => (id, sizeof_header, sizeof_block),
+                Ok(ZstBlockSpan::Incomplete | ZstBlockSpan::SeekTable) => {
+                    return ParseResult::Partial;
+                }
+                Err(e) => return ParseResult::Error(e),
+            };
+
+            let offset = self.inner_absolute_cursor;
+            self.inner_cursor += sizeof_block;
+            self.inner_absolute_cursor += sizeof_block as u64;
+
+            return ParseResult::Full(BBGZBlock {
+                id: unsafe { std::mem::transmute(&slice_remaining[id]) },
+                offset: offset,
+                header: unsafe { std::mem::transmute(&slice_remaining[..sizeof_header]) },
+                compressed: unsafe {
+                    std::mem::transmute(&slice_remaining[sizeof_header..sizeof_block])
+                },
+                trailer: &[],
+                arena_backing: smallvec![decoded.clone_view()],
+            });
+        }
+
         // SAFETY: checked size above
         if unsafe {
             slice_remaining.get_unchecked(0) != &BBGZHeaderBase::TEMPLATE.ID1 ||  // cargo fmt stop unaligning these!
@@ -188,6 +278,40 @@ impl Parse<ArenaSlice<u8>> for BBGZParser {
         };
         let slice_combined_len = slice_combined.len();
 
+        if is_zst_frame(slice_combined) {
+            let (id, sizeof_header, sizeof_block) = match zst_block_span(slice_combined) {
+                Ok(ZstBlockSpan::Block {
+                    id,
+                    sizeof_header,
+                    sizeof_block,
+                }) => (id, sizeof_header, sizeof_block),
+                Ok(ZstBlockSpan::SeekTable) => return ParseResult::Partial,
+                Ok(ZstBlockSpan::Incomplete) => {
+                    tracing::error!(size = slice_combined_len, "combined zst block too small");
+                    return ParseResult::Error(anyhow::anyhow!(
+                        "combined zst block too small: size {}",
+                        slice_combined_len
+                    ));
+                }
+                Err(e) => return ParseResult::Error(e),
+            };
+
+            let offset = self.inner_absolute_cursor;
+            self.inner_cursor = sizeof_block.saturating_sub(tail_len);
+            self.inner_absolute_cursor += sizeof_block as u64;
+
+            return ParseResult::Full(BBGZBlock {
+                id: unsafe { std::mem::transmute(&slice_combined[id]) },
+                offset: offset,
+                header: unsafe { std::mem::transmute(&slice_combined[..sizeof_header]) },
+                compressed: unsafe {
+                    std::mem::transmute(&slice_combined[sizeof_header..sizeof_block])
+                },
+                trailer: &[],
+                arena_backing: arena_backings.iter().map(|b| b.clone_view()).collect(),
+            });
+        }
+
         if slice_combined_len < MIN_SIZEOF_HEADERusize {
             tracing::error!(
                 size = slice_combined_len,
@@ -0,0 +1,4 @@
+pub mod fasta;
+pub mod fasta_as_record;
+
+pub use fasta::*;
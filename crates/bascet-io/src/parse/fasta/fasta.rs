@@ -0,0 +1,198 @@
+use bascet_core::attr::{meta::*, sequence::*};
+use bascet_core::*;
+use serde::Serialize;
+
+///
+/// Bytes a decoder must append after the last FASTA record. The end of a record is only
+/// known once the next header is seen, and the stream never flushes a trailing partial record.
+///
+pub const FASTA_TRAILER: &[u8] = b"\n>";
+
+pub struct Fasta {
+    pub(crate) inner_cursor: usize,
+    // NOTE: a record that does not end in the buffer after the one it starts in is collected
+    //       here, buffer by buffer, until the next header is found
+    pub(crate) inner_spanning: Vec<u8>,
+}
+
+#[bon::bon]
+impl Fasta {
+    #[builder]
+    pub fn new() -> Self {
+        Self {
+            inner_cursor: 0,
+            inner_spanning: Vec::new(),
+        }
+    }
+}
+
+#[derive(Composite, Default, Serialize)]
+#[bascet(attrs = (Id, R0), backing = ArenaBacking, marker = AsRecord)]
+pub struct Record {
+    pub id: &'static [u8],
+    pub r0: &'static [u8],
+
+    // SAFETY: exposed ONLY to allow conversion outside this crate.
+    //         be VERY careful modifying this at all
+    #[serde(skip)]
+    pub(crate) arena_backing: smallvec::SmallVec<[ArenaView<u8>; 2]>,
+    // NOTE: multi-line sequences are joined here; r0 then points into this allocation.
+    //       never mutate it once r0 has been set
+    #[serde(skip)]
+    pub(crate) joined_backing: Vec<u8>,
+}
+
+#[derive(Composite, Default, Clone, Serialize)]
+#[bascet(attrs = (Id, R0), backing = OwnedBacking, marker = AsRecord)]
+pub struct OwnedRecord {
+    id: Vec<u8>,
+    r0: Vec<u8>,
+
+    #[serde(skip)]
+    owned_backing: (),
+}
+
+impl OwnedRecord {
+    pub fn empty() -> Self {
+        Self {
+            id: vec![],
+            r0: vec![],
+            owned_backing: (),
+        }
+    }
+}
+
+impl Into<OwnedRecord> for Record {
+    fn into(self) -> OwnedRecord {
+        OwnedRecord {
+            id: self.id.to_vec(),
+            r0: self.r0.to_vec(),
+
+            owned_backing: (),
+        }
+    }
+}
+
+impl Record {
+    ///
+    /// Generate a record from a raw FASTA record, starting at '>' and excluding the newline
+    /// before the next header. The id is the header line without '>'
+    ///
+    pub unsafe fn from_raw(buf_record: &[u8], arena_view: ArenaView<u8>) -> Self {
+        let trimmed_len = buf_record
+            .iter()
+            .rposition(|&b| b != b'\n' && b != b'\r')
+            .map_or(0, |pos| pos + 1);
+        // SAFETY: trimmed_len <= buf_record.len()
+        let buf_record = unsafe { buf_record.get_unchecked(..trimmed_len) };
+
+        if likely_unlikely::unlikely(buf_record.first() != Some(&b'>')) {
+            let context_end = buf_record.len().min(512);
+            panic!(
+                "Invalid FASTA header: expected '>', got {:?}\n\
+                Context (first {} bytes): {:?}",
+                buf_record.first().map(|&b| b as char),
+                context_end,
+                String::from_utf8_lossy(&buf_record[..context_end]),
+            );
+        }
+
+        let (hdr, seq) = match memchr::memchr(b'\n', buf_record) {
+            // SAFETY: pos_newline was found by memchr in buf_record
+            Some(pos_newline) => unsafe {
+                (
+                    buf_record.get_unchecked(1..pos_newline),
+                    buf_record.get_unchecked(pos_newline + 1..),
+                )
+            },
+            None => (&buf_record[1..], &buf_record[buf_record.len()..]),
+        };
+        let hdr = hdr.strip_suffix(b"\r").unwrap_or(hdr);
+
+        let mut joined_backing = Vec::new();
+        let seq = if likely_unlikely::likely(memchr::memchr(b'\n', seq).is_none()) {
+            seq
+        } else {
+            joined_backing.reserve_exact(seq.len());
+            for line in seq.split(|&b| b == b'\n') {
+                joined_backing.extend_from_slice(line.strip_suffix(b"\r").unwrap_or(line));
+            }
+            joined_backing.as_slice()
+        };
+
+        // SAFETY: transmute slices to static lifetime kept alive by ArenaView refcount,
+        //         or by the heap allocation of joined_backing which moves with the record
+        let static_id: &'static [u8] = unsafe { std::mem::transmute(hdr) };
+        let static_seq: &'static [u8] = unsafe { std::mem::transmute(seq) };
+
+        Self {
+            id: static_id,
+            r0: static_seq,
+            arena_backing: smallvec::smallvec![arena_view],
+            joined_backing,
+        }
+    }
+
+    ///
+    /// Generate a record from an owned raw FASTA record, as `from_raw`. Used for records
+    /// spanning more than two decode buffers; sequence lines are joined in place and both
+    /// the id and the sequence point into the allocation, which moves with the record
+    ///
+    pub fn from_owned(mut buf_record: Vec<u8>) -> Self {
+        let trimmed_len = buf_record
+            .iter()
+            .rposition(|&b| b != b'\n' && b != b'\r')
+            .map_or(0, |pos| pos + 1);
+        buf_record.truncate(trimmed_len);
+        debug_assert_eq!(buf_record.first(), Some(&b'>'));
+
+        let (hdr_end, seq_start) = match memchr::memchr(b'\n', &buf_record) {
+            Some(pos_newline) => (pos_newline, pos_newline + 1),
+            None => (buf_record.len(), buf_record.len()),
+        };
+        let hdr_end = match buf_record[..hdr_end].last() {
+            Some(b'\r') => hdr_end - 1,
+            _ => hdr_end,
+        };
+
+        let mut pos_write = seq_start;
+        let mut pos_read = seq_start;
+        while pos_read < buf_record.len() {
+            let pos_line_end = memchr::memchr(b'\n', &buf_record[pos_read..])
+                .map_or(buf_record.len(), |pos| pos_read + pos);
+            let line_end = match buf_record[pos_read..pos_line_end].last() {
+                Some(b'\r') => pos_line_end - 1,
+                _ => pos_line_end,
+            };
+            buf_record.copy_within(pos_read..line_end, pos_write);
+            pos_write += line_end - pos_read;
+            pos_read = pos_line_end + 1;
+        }
+        buf_record.truncate(pos_write);
+
+        // SAFETY: transmute slices to static lifetime kept alive by the heap allocation of
+        //         joined_backing, which is not modified again and moves with the record
+        let static_id: &'static [u8] = unsafe { std::mem::transmute(&buf_record[1..hdr_end]) };
+        let static_seq: &'static [u8] = unsafe { std::mem::transmute(&buf_record[seq_start..]) };
+
+        Self {
+            id: static_id,
+            r0: static_seq,
+            arena_backing: smallvec::SmallVec::new(),
+            joined_backing: buf_record,
+        }
+    }
+
+    ///
+    /// Generate an empty record
+    ///
+    pub fn empty() -> Self {
+        const DUMMY_EMPTY_VEC: &[u8] = &[];
+        Self {
+            id: &DUMMY_EMPTY_VEC,
+            r0: &DUMMY_EMPTY_VEC,
+            arena_backing: smallvec::SmallVec::new(),
+            joined_backing: Vec::new(),
+        }
+    }
+}
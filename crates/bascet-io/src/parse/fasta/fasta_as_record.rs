@@ -0,0 +1,169 @@
+use crate::fasta;
+use bascet_core::*;
+
+impl crate::Fasta {
+    fn skip_blank_lines(&mut self, buf: &[u8]) {
+        while let Some(b'\n' | b'\r') = buf.get(self.inner_cursor) {
+            self.inner_cursor += 1;
+        }
+    }
+
+    ///
+    /// Continue a record collected in `inner_spanning`, which already holds every buffer up
+    /// to and including the spanning tail
+    ///
+    fn parse_spanning_collected(&mut self, slice_head: &[u8]) -> ParseResult<fasta::Record> {
+        let is_header_at_start =
+            self.inner_spanning.last() == Some(&b'\n') && slice_head.first() == Some(&b'>');
+        // (bytes of head in the record, bytes of head consumed)
+        let (record_head_len, head_len) = if is_header_at_start {
+            (0, 0)
+        } else {
+            match memchr::memmem::find(slice_head, b"\n>") {
+                Some(pos) => (pos, pos + 1),
+                None => {
+                    self.inner_spanning.extend_from_slice(slice_head);
+                    return ParseResult::Partial;
+                }
+            }
+        };
+
+        self.inner_spanning
+            .extend_from_slice(&slice_head[..record_head_len]);
+        let buf_record = std::mem::take(&mut self.inner_spanning);
+        self.inner_cursor = head_len;
+        ParseResult::Full(fasta::Record::from_owned(buf_record))
+    }
+}
+
+impl Parse<ArenaSlice<u8>> for crate::Fasta {
+    type Item = fasta::Record;
+
+    fn parse_aligned(&mut self, decoded: &ArenaSlice<u8>) -> ParseResult<Self::Item> {
+        self.skip_blank_lines(decoded.as_slice());
+        let cursor = self.inner_cursor;
+        // SAFETY: cursor is maintained internally and always valid
+        let buf_cursor = unsafe { decoded.as_slice().get_unchecked(cursor..) };
+
+        match buf_cursor.first() {
+            None => return ParseResult::Partial,
+            Some(b'>') => {}
+            Some(&b) => {
+                return ParseResult::Error(anyhow::anyhow!(
+                    "malformed FASTA record: expected '>', got {:?}",
+                    b as char
+                ));
+            }
+        }
+
+        // NOTE: a record ends at the newline preceding the next header. A missing header
+        //       indicates end of block, or end of input if the decoder appended FASTA_TRAILER
+        let pos_end = match memchr::memmem::find(&buf_cursor[1..], b"\n>") {
+            Some(pos) => pos + 1,
+            None => return ParseResult::Partial,
+        };
+
+        self.inner_cursor = cursor.checked_add(pos_end + 1).expect("overflow");
+
+        // SAFETY: pos_end was found by memmem in buf_cursor
+        let buf_record = unsafe { buf_cursor.get_unchecked(..pos_end) };
+        let fasta_record = unsafe { fasta::Record::from_raw(buf_record, decoded.clone_view()) };
+        ParseResult::Full(fasta_record)
+    }
+
+    fn parse_finish(&mut self) -> ParseResult<Self::Item> {
+        ParseResult::Finished
+    }
+
+    fn parse_spanning<FA>(
+        &mut self,
+        decoded_spanning_tail: &ArenaSlice<u8>,
+        decoded_spanning_head: &ArenaSlice<u8>,
+        mut alloc: FA,
+    ) -> ParseResult<Self::Item>
+    where
+        FA: FnMut(usize) -> ArenaSlice<u8>,
+    {
+        let slice_tail = decoded_spanning_tail.as_slice();
+        let slice_head = decoded_spanning_head.as_slice();
+        if !self.inner_spanning.is_empty() {
+            return self.parse_spanning_collected(slice_head);
+        }
+        // NOTE: as_ptr_range is [start, end) and [start', end') => end == start'
+        let is_contiguous = slice_tail.as_ptr_range().end == slice_head.as_ptr_range().start;
+
+        self.skip_blank_lines(slice_tail);
+        // SAFETY: inner_cursor is maintained internally and always valid
+        let tail_remaining = unsafe { slice_tail.get_unchecked(self.inner_cursor..) };
+        if tail_remaining.is_empty() {
+            // Tail was consumed entirely, nothing spans
+            self.inner_cursor = 0;
+            return self.parse_aligned(decoded_spanning_head);
+        }
+        if tail_remaining[0] != b'>' {
+            return ParseResult::Error(anyhow::anyhow!(
+                "malformed FASTA record: expected '>', got {:?}",
+                tail_remaining[0] as char
+            ));
+        }
+        let tail_len = tail_remaining.len();
+
+        // (length of the record, bytes of head consumed)
+        let (record_len, head_len) =
+            if tail_remaining.last() == Some(&b'\n') && slice_head.first() == Some(&b'>') {
+                (tail_len - 1, 0)
+            } else {
+                match memchr::memmem::find(slice_head, b"\n>") {
+                    Some(pos) => (tail_len + pos, pos + 1),
+                    None => {
+                        // NOTE: the record continues past this buffer as well
+                        self.inner_spanning.extend_from_slice(tail_remaining);
+                        self.inner_spanning.extend_from_slice(slice_head);
+                        self.inner_cursor = 0;
+                        return ParseResult::Partial;
+                    }
+                }
+            };
+
+        let fasta_record = if head_len == 0 {
+            // SAFETY: record_len < tail_len
+            let buf_record = unsafe { tail_remaining.get_unchecked(..record_len) };
+            unsafe { fasta::Record::from_raw(buf_record, decoded_spanning_tail.clone_view()) }
+        } else if is_contiguous {
+            // Create view spanning both buffers
+            let combined_slice =
+                unsafe { std::slice::from_raw_parts(tail_remaining.as_ptr(), record_len) };
+            let mut record = unsafe {
+                fasta::Record::from_raw(combined_slice, decoded_spanning_tail.clone_view())
+            };
+            // Add head arena view => both arenas must be kept alive
+            record
+                .arena_backing
+                .push(decoded_spanning_head.clone_view());
+            record
+        } else {
+            // Allocate scratch and copy
+            let mut scratch = alloc(record_len);
+            let scratch_slice = scratch.as_mut_slice();
+
+            unsafe {
+                std::ptr::copy_nonoverlapping(
+                    tail_remaining.as_ptr(),
+                    scratch_slice.as_mut_ptr(),
+                    tail_len,
+                );
+                // SAFETY: record_len - tail_len was calculated from memmem results in slice_head
+                std::ptr::copy_nonoverlapping(
+                    slice_head.as_ptr(),
+                    scratch_slice.as_mut_ptr().add(tail_len),
+                    record_len - tail_len,
+                );
+            }
+
+            unsafe { fasta::Record::from_raw(scratch.as_slice(), scratch.clone_view()) }
+        };
+
+        self.inner_cursor = head_len;
+        ParseResult::Full(fasta_record)
+    }
+}
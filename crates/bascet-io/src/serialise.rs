@@ -0,0 +1,5 @@
+mod format;
+mod traits;
+
+pub use format::*;
+pub use traits::Serialiser;
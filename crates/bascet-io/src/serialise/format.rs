@@ -0,0 +1,11 @@
+mod arrow_ipc;
+mod columnar;
+mod csv;
+mod parquet;
+mod tsv;
+
+pub use self::csv::CsvWriter;
+pub use arrow_ipc::{ArrowIpcWriter, IpcSink};
+pub use columnar::{BatchSink, ColumnTable, ColumnarError, ColumnarWriter};
+pub use parquet::{ParquetSink, ParquetWriter};
+pub use tsv::TsvWriter;
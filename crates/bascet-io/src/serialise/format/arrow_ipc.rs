@@ -0,0 +1,157 @@
+use std::io::Write;
+
+use bascet_core::AttrNames;
+use polars_arrow::datatypes::ArrowSchemaRef;
+use polars_arrow::io::ipc::write::{FileWriter, WriteOptions};
+use polars_arrow::record_batch::RecordBatch;
+
+use super::columnar::{BatchSink, ColumnarError, ColumnarWriter};
+
+/// Arrow IPC (feather v2) file. Batches are left uncompressed so that readers can memory-map
+/// the file without copying
+pub type ArrowIpcWriter<W, A = ()> = ColumnarWriter<IpcSink<W>, A>;
+
+pub struct IpcSink<W: Write> {
+    writer: Option<W>,
+    ipc: Option<FileWriter<W>>,
+}
+
+impl<W: Write> IpcSink<W> {
+    fn started(&mut self, schema: &ArrowSchemaRef) -> Result<&mut FileWriter<W>, ColumnarError> {
+        if self.ipc.is_none() {
+            let writer = self.writer.take().expect("the writer is only taken once");
+            let mut ipc = FileWriter::new(
+                writer,
+                ArrowSchemaRef::clone(schema),
+                None,
+                WriteOptions { compression: None },
+            );
+            ipc.start()?;
+            self.ipc = Some(ipc);
+        }
+        Ok(self.ipc.as_mut().expect("started above"))
+    }
+}
+
+impl<W: Write> BatchSink for IpcSink<W> {
+    type Writer = W;
+
+    fn write_batch(
+        &mut self,
+        schema: &ArrowSchemaRef,
+        batch: RecordBatch,
+    ) -> Result<(), ColumnarError> {
+        self.started(schema)?.write(&batch, None)?;
+        Ok(())
+    }
+
+    fn finish(mut self, schema: &ArrowSchemaRef) -> Result<W, ColumnarError> {
+        self.started(schema)?.finish()?;
+        Ok(self.ipc.take().expect("started above").into_inner())
+    }
+}
+
+impl<W: Write> ColumnarWriter<IpcSink<W>, ()> {
+    /// A writer for plain table rows
+    pub fn new(writer: W) -> Self {
+        Self::from_sink(
+            IpcSink {
+                writer: Some(writer),
+                ipc: None,
+            },
+            Vec::new(),
+        )
+    }
+
+    /// A writer for the attributes `A` of composites, named after the attributes
+    pub fn with<A: AttrNames>(writer: W) -> ArrowIpcWriter<W, A> {
+        ColumnarWriter::from_sink(
+            IpcSink {
+                writer: Some(writer),
+                ipc: None,
+            },
+            A::names(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use polars_arrow::array::{Array, ListArray, PrimitiveArray, Utf8Array};
+    use polars_arrow::datatypes::ArrowDataType;
+    use polars_arrow::io::ipc::read::{FileReader, read_file_metadata};
+
+    use super::*;
+
+    #[derive(serde::Serialize)]
+    struct Row<'a> {
+        cell: &'a [u8],
+        reads: u64,
+        lengths: Vec<u32>,
+        mean_q: Option<f64>,
+    }
+
+    #[test]
+    fn round_trips_rows_across_batches() {
+        let mut writer = ArrowIpcWriter::new(Cursor::new(Vec::new())).with_rows_per_batch(2);
+        for (cell, lengths, mean_q) in [
+            (&b"AAAC"[..], vec![150, 149], Some(35.5)),
+            (b"AAAG", vec![], None),
+            (b"AAAT", vec![90], Some(20.0)),
+        ] {
+            let row = Row {
+                cell,
+                reads: lengths.len() as u64,
+                lengths,
+                mean_q,
+            };
+            writer.write_row(&row).unwrap();
+        }
+        let mut file = writer.finish().unwrap();
+
+        file.set_position(0);
+        let metadata = read_file_metadata(&mut file).unwrap();
+        let names: Vec<_> = metadata.schema.iter_names().map(|n| n.as_str()).collect();
+        assert_eq!(names, ["cell", "reads", "lengths", "mean_q"]);
+        assert_eq!(
+            metadata.schema.get("lengths").unwrap().dtype,
+            ListArray::<i64>::default_datatype(ArrowDataType::UInt64)
+        );
+
+        let batches: Vec<_> = FileReader::new(file, metadata, None, None)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].height(), 2);
+
+        let cells = batches[1].arrays()[0]
+            .as_any()
+            .downcast_ref::<Utf8Array<i64>>()
+            .unwrap();
+        assert_eq!(cells.value(0), "AAAT");
+        let mean_q = batches[0].arrays()[3]
+            .as_any()
+            .downcast_ref::<PrimitiveArray<f64>>()
+            .unwrap();
+        assert_eq!(mean_q.value(0), 35.5);
+        assert!(mean_q.is_null(1));
+    }
+
+    #[test]
+    fn writes_named_columns_without_rows() {
+        let sink = IpcSink {
+            writer: Some(Cursor::new(Vec::new())),
+            ipc: None,
+        };
+        let writer = ArrowIpcWriter::<_, ()>::from_sink(sink, vec!["id", "r1"]);
+        let mut file = writer.finish().unwrap();
+
+        file.set_position(0);
+        let metadata = read_file_metadata(&mut file).unwrap();
+        let names: Vec<_> = metadata.schema.iter_names().map(|n| n.as_str()).collect();
+        assert_eq!(names, ["id", "r1"]);
+        assert_eq!(FileReader::new(file, metadata, None, None).count(), 0);
+    }
+}
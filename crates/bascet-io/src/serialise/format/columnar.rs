@@ -0,0 +1,1292 @@
+//! Row-to-column buffering shared by the Arrow IPC and Parquet serialisers.
+//!
+//! A row is any `serde::Serialize` tuple, struct or map; its elements become columns, named by
+//! the attribute names the writer was created with, the struct fields or the map keys. A column
+//! takes its type from the first non-null value: integers, floats, bools, strings, byte strings
+//! (stored as UTF-8 strings, as all TIRP fields are text) and sequences of these (stored as
+//! lists, e.g. the reads of a cell). The schema is fixed once the first batch is written.
+
+use std::fmt;
+use std::io::Write;
+use std::marker::PhantomData;
+
+use bascet_core::{AttrNames, Composite, SerialiseView};
+use polars_arrow::array::{Array, BooleanArray, ListArray, PrimitiveArray, Utf8Array};
+use polars_arrow::bitmap::Bitmap;
+use polars_arrow::datatypes::{ArrowDataType, ArrowSchema, ArrowSchemaRef, Field};
+use polars_arrow::legacy::error::PolarsError;
+use polars_arrow::offset::OffsetsBuffer;
+use polars_arrow::record_batch::RecordBatch;
+use serde::Serialize;
+use serde::ser::{self, Impossible};
+
+use crate::Serialiser;
+
+pub const DEFAULT_ROWS_PER_BATCH: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub struct ColumnarError(String);
+
+impl ColumnarError {
+    fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+
+    fn unsupported(what: &str) -> Self {
+        Self(format!("{what} cannot be written as a column"))
+    }
+}
+
+impl fmt::Display for ColumnarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ColumnarError {}
+
+impl ser::Error for ColumnarError {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Self(msg.to_string())
+    }
+}
+
+impl From<PolarsError> for ColumnarError {
+    fn from(err: PolarsError) -> Self {
+        Self(err.to_string())
+    }
+}
+
+type Result<T> = std::result::Result<T, ColumnarError>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Bool,
+    U8,
+    Int,
+    UInt,
+    Float,
+    Utf8,
+    List,
+}
+
+enum Values {
+    /// Nothing but nulls and empty sequences seen yet
+    Unknown {
+        empty_seqs: Vec<usize>,
+    },
+    Bool(Vec<bool>),
+    U8(Vec<u8>),
+    Int(Vec<i64>),
+    UInt(Vec<u64>),
+    Float(Vec<f64>),
+    Utf8 {
+        offsets: Vec<i64>,
+        bytes: Vec<u8>,
+    },
+    List {
+        offsets: Vec<i64>,
+        items: Box<Column>,
+    },
+}
+
+impl Values {
+    fn kind(&self) -> Option<Kind> {
+        match self {
+            Values::Unknown { .. } => None,
+            Values::Bool(_) => Some(Kind::Bool),
+            Values::U8(_) => Some(Kind::U8),
+            Values::Int(_) => Some(Kind::Int),
+            Values::UInt(_) => Some(Kind::UInt),
+            Values::Float(_) => Some(Kind::Float),
+            Values::Utf8 { .. } => Some(Kind::Utf8),
+            Values::List { .. } => Some(Kind::List),
+        }
+    }
+}
+
+struct Column {
+    validity: Vec<bool>,
+    values: Values,
+}
+
+macro_rules! push_scalar {
+    ($column:expr, $kind:expr, $variant:ident, $value:expr) => {{
+        $column.ensure($kind)?;
+        if let Values::$variant(values) = &mut $column.values {
+            values.push($value);
+        }
+        $column.validity.push(true);
+        Ok(())
+    }};
+}
+
+impl Column {
+    fn new() -> Self {
+        Self {
+            validity: Vec::new(),
+            values: Values::Unknown {
+                empty_seqs: Vec::new(),
+            },
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.validity.len()
+    }
+
+    /// Give an untyped column the type `kind`, or check that a typed one has it
+    fn ensure(&mut self, kind: Kind) -> Result<()> {
+        let empty_seqs = match &mut self.values {
+            Values::Unknown { empty_seqs } => std::mem::take(empty_seqs),
+            values if values.kind() == Some(kind) => return Ok(()),
+            values => {
+                return Err(ColumnarError::new(format!(
+                    "a column of {:?} values cannot take a {kind:?} value",
+                    values.kind().unwrap_or(kind)
+                )));
+            }
+        };
+        if !empty_seqs.is_empty() && !matches!(kind, Kind::Utf8 | Kind::List) {
+            return Err(ColumnarError::new(format!(
+                "a column of sequences cannot take a {kind:?} value"
+            )));
+        }
+
+        // NOTE:    rows so far are nulls or empty sequences, which all hold the default value
+        let n = self.len();
+        self.values = match kind {
+            Kind::Bool => Values::Bool(vec![false; n]),
+            Kind::U8 => Values::U8(vec![0; n]),
+            Kind::Int => Values::Int(vec![0; n]),
+            Kind::UInt => Values::UInt(vec![0; n]),
+            Kind::Float => Values::Float(vec![0.0; n]),
+            Kind::Utf8 => Values::Utf8 {
+                offsets: vec![0; n + 1],
+                bytes: Vec::new(),
+            },
+            Kind::List => Values::List {
+                offsets: vec![0; n + 1],
+                items: Box::new(Column::new()),
+            },
+        };
+        Ok(())
+    }
+
+    fn push_null(&mut self) {
+        self.validity.push(false);
+        match &mut self.values {
+            Values::Unknown { .. } => {}
+            Values::Bool(values) => values.push(false),
+            Values::U8(values) => values.push(0),
+            Values::Int(values) => values.push(0),
+            Values::UInt(values) => values.push(0),
+            Values::Float(values) => values.push(0.0),
+            Values::Utf8 { offsets, .. } | Values::List { offsets, .. } => {
+                offsets.push(offsets[offsets.len() - 1])
+            }
+        }
+    }
+
+    fn push_bool(&mut self, value: bool) -> Result<()> {
+        push_scalar!(self, Kind::Bool, Bool, value)
+    }
+
+    fn push_u8(&mut self, value: u8) -> Result<()> {
+        push_scalar!(self, Kind::U8, U8, value)
+    }
+
+    fn push_i64(&mut self, value: i64) -> Result<()> {
+        push_scalar!(self, Kind::Int, Int, value)
+    }
+
+    fn push_u64(&mut self, value: u64) -> Result<()> {
+        push_scalar!(self, Kind::UInt, UInt, value)
+    }
+
+    fn push_f64(&mut self, value: f64) -> Result<()> {
+        push_scalar!(self, Kind::Float, Float, value)
+    }
+
+    fn push_utf8(&mut self, value: &[u8]) -> Result<()> {
+        std::str::from_utf8(value)
+            .map_err(|_| ColumnarError::new("a byte string is not valid UTF-8"))?;
+        self.ensure(Kind::Utf8)?;
+        if let Values::Utf8 { offsets, bytes } = &mut self.values {
+            bytes.extend_from_slice(value);
+            offsets.push(bytes.len() as i64);
+        }
+        self.validity.push(true);
+        Ok(())
+    }
+
+    fn push_empty_seq(&mut self) -> Result<()> {
+        match &mut self.values {
+            Values::Unknown { empty_seqs } => empty_seqs.push(self.validity.len()),
+            Values::Utf8 { offsets, .. } | Values::List { offsets, .. } => {
+                offsets.push(offsets[offsets.len() - 1])
+            }
+            _ => {
+                return Err(ColumnarError::new(
+                    "a column of scalars cannot take a sequence",
+                ));
+            }
+        }
+        self.validity.push(true);
+        Ok(())
+    }
+
+    fn push_list(&mut self, items: Column) -> Result<()> {
+        self.ensure(Kind::List)?;
+        if let Values::List {
+            offsets,
+            items: column_items,
+        } = &mut self.values
+        {
+            offsets.push(offsets[offsets.len() - 1] + items.len() as i64);
+            column_items.append(items)?;
+        }
+        self.validity.push(true);
+        Ok(())
+    }
+
+    fn append(&mut self, other: Column) -> Result<()> {
+        let Column { validity, values } = other;
+        if let Values::Unknown { empty_seqs } = &values {
+            for row in 0..validity.len() {
+                if empty_seqs.contains(&row) {
+                    self.push_empty_seq()?;
+                } else {
+                    self.push_null();
+                }
+            }
+            return Ok(());
+        }
+        if let Some(kind) = values.kind() {
+            self.ensure(kind)?;
+        }
+
+        match (&mut self.values, values) {
+            (Values::Bool(a), Values::Bool(b)) => a.extend(b),
+            (Values::U8(a), Values::U8(b)) => a.extend(b),
+            (Values::Int(a), Values::Int(b)) => a.extend(b),
+            (Values::UInt(a), Values::UInt(b)) => a.extend(b),
+            (Values::Float(a), Values::Float(b)) => a.extend(b),
+            (
+                Values::Utf8 { offsets, bytes },
+                Values::Utf8 {
+                    offsets: other_offsets,
+                    bytes: other_bytes,
+                },
+            ) => {
+                let base = bytes.len() as i64;
+                offsets.extend(other_offsets[1..].iter().map(|offset| offset + base));
+                bytes.extend(other_bytes);
+            }
+            (
+                Values::List { offsets, items },
+                Values::List {
+                    offsets: other_offsets,
+                    items: other_items,
+                },
+            ) => {
+                let base = items.len() as i64;
+                offsets.extend(other_offsets[1..].iter().map(|offset| offset + base));
+                items.append(*other_items)?;
+            }
+            _ => unreachable!("ensure() matched the column kinds"),
+        }
+        self.validity.extend(validity);
+        Ok(())
+    }
+
+    /// Columns that only saw nulls become strings, so that the schema can be fixed
+    fn resolve(&mut self) {
+        if matches!(self.values, Values::Unknown { .. }) {
+            self.ensure(Kind::Utf8)
+                .expect("a string column holds nulls and empty sequences");
+        }
+        if let Values::List { items, .. } = &mut self.values {
+            items.resolve();
+        }
+    }
+
+    fn dtype(&self) -> ArrowDataType {
+        match &self.values {
+            Values::Bool(_) => ArrowDataType::Boolean,
+            Values::U8(_) => ArrowDataType::UInt8,
+            Values::Int(_) => ArrowDataType::Int64,
+            Values::UInt(_) => ArrowDataType::UInt64,
+            Values::Float(_) => ArrowDataType::Float64,
+            Values::Unknown { .. } | Values::Utf8 { .. } => ArrowDataType::LargeUtf8,
+            Values::List { items, .. } => ListArray::<i64>::default_datatype(items.dtype()),
+        }
+    }
+
+    /// Move the buffered values into an array, leaving an empty column of the same type
+    fn take_array(&mut self) -> Result<Box<dyn Array>> {
+        self.resolve();
+        let dtype = self.dtype();
+        let validity = std::mem::take(&mut self.validity);
+        let validity = (!validity.iter().all(|valid| *valid)).then(|| Bitmap::from_iter(validity));
+
+        let array: Box<dyn Array> = match &mut self.values {
+            Values::Bool(values) => Box::new(BooleanArray::new(
+                dtype,
+                Bitmap::from_iter(std::mem::take(values)),
+                validity,
+            )),
+            Values::U8(values) => Box::new(PrimitiveArray::<u8>::new(
+                dtype,
+                std::mem::take(values).into(),
+                validity,
+            )),
+            Values::Int(values) => Box::new(PrimitiveArray::<i64>::new(
+                dtype,
+                std::mem::take(values).into(),
+                validity,
+            )),
+            Values::UInt(values) => Box::new(PrimitiveArray::<u64>::new(
+                dtype,
+                std::mem::take(values).into(),
+                validity,
+            )),
+            Values::Float(values) => Box::new(PrimitiveArray::<f64>::new(
+                dtype,
+                std::mem::take(values).into(),
+                validity,
+            )),
+            Values::Utf8 { offsets, bytes } => Box::new(Utf8Array::<i64>::try_new(
+                dtype,
+                OffsetsBuffer::try_from(std::mem::replace(offsets, vec![0]))?,
+                std::mem::take(bytes).into(),
+                validity,
+            )?),
+            Values::List { offsets, items } => {
+                let offsets = OffsetsBuffer::try_from(std::mem::replace(offsets, vec![0]))?;
+                Box::new(ListArray::<i64>::try_new(
+                    dtype,
+                    offsets,
+                    items.take_array()?,
+                    validity,
+                )?)
+            }
+            Values::Unknown { .. } => unreachable!("resolve() typed the column"),
+        };
+        Ok(array)
+    }
+}
+
+///////////////////////////////
+/// Rows buffered as columns until they are taken as a `RecordBatch`. A row that fails to
+/// serialise leaves the table inconsistent, so the error should end the write
+pub struct ColumnTable {
+    names: Vec<String>,
+    columns: Vec<Column>,
+    rows: usize,
+    schema: Option<ArrowSchemaRef>,
+}
+
+impl ColumnTable {
+    /// `names` name the leading columns; the others are named by struct fields or map keys
+    pub fn new<N: Into<String>>(names: impl IntoIterator<Item = N>) -> Self {
+        Self {
+            names: names.into_iter().map(Into::into).collect(),
+            columns: Vec::new(),
+            rows: 0,
+            schema: None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows == 0
+    }
+
+    pub fn push_row<R: Serialize + ?Sized>(&mut self, row: &R) -> Result<()> {
+        let mut cursor = 0;
+        row.serialize(RowSerializer {
+            table: self,
+            cursor: &mut cursor,
+        })?;
+        self.end_row(cursor)
+    }
+
+    /// Push one row made of `row` followed by the columns of `derived`
+    pub fn push_row_with<R, D>(&mut self, row: &R, derived: &D) -> Result<()>
+    where
+        R: Serialize + ?Sized,
+        D: Serialize + ?Sized,
+    {
+        let mut cursor = 0;
+        row.serialize(RowSerializer {
+            table: self,
+            cursor: &mut cursor,
+        })?;
+        derived.serialize(RowSerializer {
+            table: self,
+            cursor: &mut cursor,
+        })?;
+        self.end_row(cursor)
+    }
+
+    fn end_row(&mut self, cursor: usize) -> Result<()> {
+        if cursor != self.columns.len() {
+            return Err(ColumnarError::new(format!(
+                "a row has {cursor} columns, but earlier rows have {}",
+                self.columns.len()
+            )));
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    fn column(&mut self, cursor: usize, name: Option<&str>) -> Result<&mut Column> {
+        if cursor == self.columns.len() {
+            if self.rows > 0 || self.schema.is_some() {
+                return Err(ColumnarError::new(format!(
+                    "a row has more columns than the {} of earlier rows",
+                    self.columns.len()
+                )));
+            }
+            self.columns.push(Column::new());
+        }
+        match self.names.get(cursor) {
+            Some(known) => {
+                if let Some(name) = name.filter(|name| *name != known) {
+                    return Err(ColumnarError::new(format!(
+                        "column {cursor} is `{known}`, but a row names it `{name}`"
+                    )));
+                }
+            }
+            None => self.names.push(
+                name.map(String::from)
+                    .unwrap_or_else(|| format!("column_{cursor}")),
+            ),
+        }
+        Ok(&mut self.columns[cursor])
+    }
+
+    /// The schema of the table. Fixed on the first call: no columns can be added afterwards
+    pub fn schema(&mut self) -> ArrowSchemaRef {
+        if let Some(schema) = &self.schema {
+            return ArrowSchemaRef::clone(schema);
+        }
+        // NOTE:    named columns that no row has reached yet are all-null strings
+        while self.columns.len() < self.names.len() {
+            let mut column = Column::new();
+            for _ in 0..self.rows {
+                column.push_null();
+            }
+            self.columns.push(column);
+        }
+        let fields = self
+            .columns
+            .iter_mut()
+            .zip(&self.names)
+            .map(|(column, name)| {
+                column.resolve();
+                Field::new(name.as_str().into(), column.dtype(), true)
+            });
+        let schema = ArrowSchemaRef::new(ArrowSchema::from_iter(fields));
+        self.schema = Some(ArrowSchemaRef::clone(&schema));
+        schema
+    }
+
+    /// Move the buffered rows into a batch
+    pub fn take_batch(&mut self) -> Result<RecordBatch> {
+        let schema = self.schema();
+        let arrays = self
+            .columns
+            .iter_mut()
+            .map(Column::take_array)
+            .collect::<Result<Vec<_>>>()?;
+        let batch = RecordBatch::try_new(std::mem::take(&mut self.rows), schema, arrays)?;
+        Ok(batch)
+    }
+}
+
+///////////////////////////////
+/// Destination of the batches of a `ColumnarWriter`
+pub trait BatchSink {
+    type Writer: Write;
+
+    fn write_batch(&mut self, schema: &ArrowSchemaRef, batch: RecordBatch) -> Result<()>;
+
+    /// Close the file; `schema` is needed when no batch was written
+    fn finish(self, schema: &ArrowSchemaRef) -> Result<Self::Writer>;
+}
+
+///////////////////////////////
+/// Batches `Composite` records, or plain table rows, into columns for a `BatchSink`
+pub struct ColumnarWriter<S, A = ()> {
+    table: ColumnTable,
+    sink: S,
+    rows_per_batch: usize,
+    _marker: PhantomData<A>,
+}
+
+impl<S: BatchSink, A> ColumnarWriter<S, A> {
+    pub(crate) fn from_sink(sink: S, names: Vec<&str>) -> Self {
+        Self {
+            table: ColumnTable::new(names),
+            sink,
+            rows_per_batch: DEFAULT_ROWS_PER_BATCH,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn with_rows_per_batch(mut self, rows_per_batch: usize) -> Self {
+        self.rows_per_batch = rows_per_batch.max(1);
+        self
+    }
+
+    /// Write a table row: a struct, map or tuple
+    pub fn write_row<R: Serialize + ?Sized>(&mut self, row: &R) -> Result<()> {
+        self.table.push_row(row)?;
+        self.flush_if_full()
+    }
+
+    /// Write a row followed by the columns of `derived`
+    pub fn write_row_with<R, D>(&mut self, row: &R, derived: &D) -> Result<()>
+    where
+        R: Serialize + ?Sized,
+        D: Serialize + ?Sized,
+    {
+        self.table.push_row_with(row, derived)?;
+        self.flush_if_full()
+    }
+
+    /// Write the attributes of `cell` followed by per-record values derived from it, e.g. a
+    /// struct of read lengths and qualities
+    pub fn serialize_with<C, D>(&mut self, cell: &C, derived: &D) -> Result<()>
+    where
+        C: Composite,
+        for<'a> SerialiseView<'a, C, A>: Serialize,
+        D: Serialize + ?Sized,
+    {
+        self.table
+            .push_row_with(&SerialiseView::<C, A>::new(cell), derived)?;
+        self.flush_if_full()
+    }
+
+    fn flush_if_full(&mut self) -> Result<()> {
+        if self.table.len() >= self.rows_per_batch {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.table.is_empty() {
+            return Ok(());
+        }
+        let schema = self.table.schema();
+        let batch = self.table.take_batch()?;
+        self.sink.write_batch(&schema, batch)
+    }
+
+    /// Write the remaining rows and close the file
+    pub fn finish(mut self) -> Result<S::Writer> {
+        self.flush()?;
+        let schema = self.table.schema();
+        self.sink.finish(&schema)
+    }
+}
+
+impl<S: BatchSink, A: AttrNames> Serialiser<A> for ColumnarWriter<S, A> {
+    type Writer = S::Writer;
+
+    fn serialize<C>(&mut self, cell: &C) -> std::result::Result<(), Box<dyn std::error::Error>>
+    where
+        C: Composite,
+        for<'a> SerialiseView<'a, C, A>: Serialize,
+    {
+        self.table.push_row(&SerialiseView::<C, A>::new(cell))?;
+        self.flush_if_full()?;
+        Ok(())
+    }
+
+    fn finish(self) -> std::result::Result<Self::Writer, Box<dyn std::error::Error>> {
+        Ok(ColumnarWriter::finish(self)?)
+    }
+}
+
+macro_rules! unsupported {
+    ($what:literal; $($method:ident($($arg:ty),*) -> $ok:ty;)*) => {
+        $(
+            fn $method(self, $(_: $arg),*) -> Result<$ok> {
+                Err(ColumnarError::unsupported($what))
+            }
+        )*
+    };
+}
+
+/// Serialises a row into consecutive columns, starting at `cursor`
+struct RowSerializer<'t> {
+    table: &'t mut ColumnTable,
+    cursor: &'t mut usize,
+}
+
+impl<'t> RowSerializer<'t> {
+    fn push<T: Serialize + ?Sized>(&mut self, name: Option<&str>, value: &T) -> Result<()> {
+        let column = self.table.column(*self.cursor, name)?;
+        value.serialize(ValueSerializer { column })?;
+        *self.cursor += 1;
+        Ok(())
+    }
+}
+
+impl<'t> ser::Serializer for RowSerializer<'t> {
+    type Ok = ();
+    type Error = ColumnarError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Impossible<(), ColumnarError>;
+    type SerializeMap = RowMapSerializer<'t>;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), ColumnarError>;
+
+    unsupported! { "a scalar row";
+        serialize_bool(bool) -> ();
+        serialize_i8(i8) -> ();
+        serialize_i16(i16) -> ();
+        serialize_i32(i32) -> ();
+        serialize_i64(i64) -> ();
+        serialize_u8(u8) -> ();
+        serialize_u16(u16) -> ();
+        serialize_u32(u32) -> ();
+        serialize_u64(u64) -> ();
+        serialize_f32(f32) -> ();
+        serialize_f64(f64) -> ();
+        serialize_char(char) -> ();
+        serialize_str(&str) -> ();
+        serialize_bytes(&[u8]) -> ();
+        serialize_none() -> ();
+        serialize_unit() -> ();
+        serialize_unit_struct(&'static str) -> ();
+        serialize_unit_variant(&'static str, u32, &'static str) -> ();
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<()> {
+        Err(ColumnarError::unsupported("an enum row"))
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<RowMapSerializer<'t>> {
+        Ok(RowMapSerializer {
+            row: self,
+            key: None,
+        })
+    }
+
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self> {
+        Ok(self)
+    }
+}
+
+impl ser::SerializeSeq for RowSerializer<'_> {
+    type Ok = ();
+    type Error = ColumnarError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(None, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for RowSerializer<'_> {
+    type Ok = ();
+    type Error = ColumnarError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(None, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for RowSerializer<'_> {
+    type Ok = ();
+    type Error = ColumnarError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push(None, value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for RowSerializer<'_> {
+    type Ok = ();
+    type Error = ColumnarError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.push(Some(key), value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+struct RowMapSerializer<'t> {
+    row: RowSerializer<'t>,
+    key: Option<String>,
+}
+
+impl ser::SerializeMap for RowMapSerializer<'_> {
+    type Ok = ();
+    type Error = ColumnarError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(key.serialize(KeySerializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        let key = self.key.take();
+        self.row.push(key.as_deref(), value)
+    }
+
+    fn end(self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Serialises one value into a column
+struct ValueSerializer<'c> {
+    column: &'c mut Column,
+}
+
+impl<'c> ser::Serializer for ValueSerializer<'c> {
+    type Ok = ();
+    type Error = ColumnarError;
+    type SerializeSeq = SeqSerializer<'c>;
+    type SerializeTuple = SeqSerializer<'c>;
+    type SerializeTupleStruct = Impossible<(), ColumnarError>;
+    type SerializeTupleVariant = Impossible<(), ColumnarError>;
+    type SerializeMap = Impossible<(), ColumnarError>;
+    type SerializeStruct = Impossible<(), ColumnarError>;
+    type SerializeStructVariant = Impossible<(), ColumnarError>;
+
+    fn serialize_bool(self, value: bool) -> Result<()> {
+        self.column.push_bool(value)
+    }
+
+    fn serialize_i8(self, value: i8) -> Result<()> {
+        self.column.push_i64(value as i64)
+    }
+
+    fn serialize_i16(self, value: i16) -> Result<()> {
+        self.column.push_i64(value as i64)
+    }
+
+    fn serialize_i32(self, value: i32) -> Result<()> {
+        self.column.push_i64(value as i64)
+    }
+
+    fn serialize_i64(self, value: i64) -> Result<()> {
+        self.column.push_i64(value)
+    }
+
+    fn serialize_u8(self, value: u8) -> Result<()> {
+        self.column.push_u8(value)
+    }
+
+    fn serialize_u16(self, value: u16) -> Result<()> {
+        self.column.push_u64(value as u64)
+    }
+
+    fn serialize_u32(self, value: u32) -> Result<()> {
+        self.column.push_u64(value as u64)
+    }
+
+    fn serialize_u64(self, value: u64) -> Result<()> {
+        self.column.push_u64(value)
+    }
+
+    fn serialize_f32(self, value: f32) -> Result<()> {
+        self.column.push_f64(value as f64)
+    }
+
+    fn serialize_f64(self, value: f64) -> Result<()> {
+        self.column.push_f64(value)
+    }
+
+    fn serialize_char(self, value: char) -> Result<()> {
+        self.column
+            .push_utf8(value.encode_utf8(&mut [0; 4]).as_bytes())
+    }
+
+    fn serialize_str(self, value: &str) -> Result<()> {
+        self.column.push_utf8(value.as_bytes())
+    }
+
+    fn serialize_bytes(self, value: &[u8]) -> Result<()> {
+        self.column.push_utf8(value)
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.column.push_null();
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.column.push_null();
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<()> {
+        self.column.push_null();
+        Ok(())
+    }
+
+    fn serialize_unit_variant(self, _: &'static str, _: u32, variant: &'static str) -> Result<()> {
+        self.column.push_utf8(variant.as_bytes())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<()> {
+        Err(ColumnarError::unsupported("an enum with data"))
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<SeqSerializer<'c>> {
+        Ok(SeqSerializer::new(self.column))
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<SeqSerializer<'c>> {
+        Ok(SeqSerializer::new(self.column))
+    }
+
+    unsupported! { "a nested struct, map or enum";
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+/// Collects the elements of a sequence value. A sequence of `u8` (how serde writes `&[u8]`) is
+/// a byte string; anything else becomes a list
+struct SeqSerializer<'c> {
+    column: &'c mut Column,
+    /// Leading `u8` elements, gathered as plain bytes while the sequence is a byte string
+    bytes: Vec<u8>,
+    items: Column,
+}
+
+impl<'c> SeqSerializer<'c> {
+    fn new(column: &'c mut Column) -> Self {
+        Self {
+            column,
+            bytes: Vec::new(),
+            items: Column::new(),
+        }
+    }
+
+    fn push_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        // NOTE:    TIRP fields are byte strings, so bytes skip the per-element column bookkeeping
+        //          until the first element that is not a byte turns the sequence into a list
+        if self.items.len() == 0 {
+            if let Ok(byte) = value.serialize(ByteSerializer) {
+                self.bytes.push(byte);
+                return Ok(());
+            }
+            for byte in std::mem::take(&mut self.bytes) {
+                self.items.push_u8(byte)?;
+            }
+        }
+        value.serialize(ValueSerializer {
+            column: &mut self.items,
+        })
+    }
+
+    fn finish(self) -> Result<()> {
+        let SeqSerializer {
+            column,
+            bytes,
+            items,
+        } = self;
+        match &items.values {
+            Values::Unknown { .. } if items.len() == 0 && !bytes.is_empty() => {
+                column.push_utf8(&bytes)
+            }
+            Values::U8(bytes) if !items.validity.contains(&false) => column.push_utf8(bytes),
+            Values::Unknown { .. } if items.len() == 0 => column.push_empty_seq(),
+            _ => column.push_list(items),
+        }
+    }
+}
+
+impl ser::SerializeSeq for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = ColumnarError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SeqSerializer<'_> {
+    type Ok = ();
+    type Error = ColumnarError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.push_element(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Takes a `u8` sequence element as a plain byte; anything else is an error, so that the
+/// sequence falls back to a column of items
+struct ByteSerializer;
+
+impl ser::Serializer for ByteSerializer {
+    type Ok = u8;
+    type Error = ColumnarError;
+    type SerializeSeq = Impossible<u8, ColumnarError>;
+    type SerializeTuple = Impossible<u8, ColumnarError>;
+    type SerializeTupleStruct = Impossible<u8, ColumnarError>;
+    type SerializeTupleVariant = Impossible<u8, ColumnarError>;
+    type SerializeMap = Impossible<u8, ColumnarError>;
+    type SerializeStruct = Impossible<u8, ColumnarError>;
+    type SerializeStructVariant = Impossible<u8, ColumnarError>;
+
+    fn serialize_u8(self, value: u8) -> Result<u8> {
+        Ok(value)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<u8> {
+        value.serialize(self)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<u8> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<u8> {
+        Err(ColumnarError::unsupported("a non-byte element"))
+    }
+
+    unsupported! { "a non-byte element";
+        serialize_bool(bool) -> u8;
+        serialize_i8(i8) -> u8;
+        serialize_i16(i16) -> u8;
+        serialize_i32(i32) -> u8;
+        serialize_i64(i64) -> u8;
+        serialize_u16(u16) -> u8;
+        serialize_u32(u32) -> u8;
+        serialize_u64(u64) -> u8;
+        serialize_f32(f32) -> u8;
+        serialize_f64(f64) -> u8;
+        serialize_char(char) -> u8;
+        serialize_str(&str) -> u8;
+        serialize_bytes(&[u8]) -> u8;
+        serialize_none() -> u8;
+        serialize_unit() -> u8;
+        serialize_unit_struct(&'static str) -> u8;
+        serialize_unit_variant(&'static str, u32, &'static str) -> u8;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+/// Serialises a map key into a column name
+struct KeySerializer;
+
+impl ser::Serializer for KeySerializer {
+    type Ok = String;
+    type Error = ColumnarError;
+    type SerializeSeq = Impossible<String, ColumnarError>;
+    type SerializeTuple = Impossible<String, ColumnarError>;
+    type SerializeTupleStruct = Impossible<String, ColumnarError>;
+    type SerializeTupleVariant = Impossible<String, ColumnarError>;
+    type SerializeMap = Impossible<String, ColumnarError>;
+    type SerializeStruct = Impossible<String, ColumnarError>;
+    type SerializeStructVariant = Impossible<String, ColumnarError>;
+
+    fn serialize_str(self, value: &str) -> Result<String> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_char(self, value: char) -> Result<String> {
+        Ok(value.to_string())
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<String> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<String> {
+        value.serialize(self)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<String> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<String> {
+        Err(ColumnarError::unsupported("a non-string map key"))
+    }
+
+    unsupported! { "a non-string map key";
+        serialize_bool(bool) -> String;
+        serialize_i8(i8) -> String;
+        serialize_i16(i16) -> String;
+        serialize_i32(i32) -> String;
+        serialize_i64(i64) -> String;
+        serialize_u8(u8) -> String;
+        serialize_u16(u16) -> String;
+        serialize_u32(u32) -> String;
+        serialize_u64(u64) -> String;
+        serialize_f32(f32) -> String;
+        serialize_f64(f64) -> String;
+        serialize_bytes(&[u8]) -> String;
+        serialize_none() -> String;
+        serialize_unit() -> String;
+        serialize_unit_struct(&'static str) -> String;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use polars_arrow::array::{ListArray, Utf8Array};
+
+    use super::*;
+
+    #[test]
+    fn infers_types_from_the_first_value() {
+        let mut table = ColumnTable::new(["id", "reads"]);
+        table.push_row(&(None::<u64>, Vec::<&[u8]>::new())).unwrap();
+        table
+            .push_row(&(Some(3u64), vec![&b"ACGT"[..], b"TT"]))
+            .unwrap();
+        assert!(table.push_row(&(1.5, Vec::<&[u8]>::new())).is_err());
+
+        let mut table = ColumnTable::new(["id", "reads"]);
+        table.push_row(&(None::<u64>, Vec::<&[u8]>::new())).unwrap();
+        table
+            .push_row(&(Some(3u64), vec![&b"ACGT"[..], b"TT"]))
+            .unwrap();
+        let batch = table.take_batch().unwrap();
+        assert_eq!(
+            batch.schema().get("id").unwrap().dtype,
+            ArrowDataType::UInt64
+        );
+
+        let reads = batch.arrays()[1]
+            .as_any()
+            .downcast_ref::<ListArray<i64>>()
+            .unwrap();
+        assert_eq!(reads.value(0).len(), 0);
+        let second = reads.value(1);
+        let second = second.as_any().downcast_ref::<Utf8Array<i64>>().unwrap();
+        assert_eq!(second.value(0), "ACGT");
+        assert_eq!(second.value(1), "TT");
+    }
+
+    #[test]
+    fn appends_derived_columns_by_field_name() {
+        #[derive(Serialize)]
+        struct Derived {
+            len_r1: u64,
+        }
+
+        let mut table = ColumnTable::new(["id", "r1"]);
+        for (id, r1) in [(&b"cell"[..], &b"ACGT"[..]), (b"cell", b"AC")] {
+            let derived = Derived {
+                len_r1: r1.len() as u64,
+            };
+            table.push_row_with(&(id, r1), &derived).unwrap();
+        }
+        let batch = table.take_batch().unwrap();
+        let fields: Vec<_> = batch
+            .schema()
+            .iter_values()
+            .map(|field| (field.name.as_str(), field.dtype.clone()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("id", ArrowDataType::LargeUtf8),
+                ("r1", ArrowDataType::LargeUtf8),
+                ("len_r1", ArrowDataType::UInt64)
+            ]
+        );
+    }
+
+    #[test]
+    fn writes_byte_sequences_as_strings_and_other_sequences_as_lists() {
+        /// Serialises through `serialize_bytes`, like `serde_bytes::Bytes`
+        struct Bytes(&'static [u8]);
+        impl Serialize for Bytes {
+            fn serialize<S: ser::Serializer>(
+                &self,
+                serializer: S,
+            ) -> std::result::Result<S::Ok, S::Error> {
+                serializer.serialize_bytes(self.0)
+            }
+        }
+
+        let mut table = ColumnTable::new(["seq", "bytes", "quals", "empty"]);
+        table
+            .push_row(&(
+                &b"ACGT"[..],
+                Bytes(b"TT"),
+                vec![Some(30u8), None],
+                Vec::<u8>::new(),
+            ))
+            .unwrap();
+        table
+            .push_row(&(&b"GA"[..], Bytes(b""), vec![None, Some(20u8)], vec![b'N']))
+            .unwrap();
+        let batch = table.take_batch().unwrap();
+        let column = |i: usize| {
+            batch.arrays()[i]
+                .as_any()
+                .downcast_ref::<Utf8Array<i64>>()
+                .unwrap()
+                .clone()
+        };
+        assert_eq!(column(0).value(0), "ACGT");
+        assert_eq!(column(0).value(1), "GA");
+        assert_eq!(column(1).value(0), "TT");
+        assert_eq!(column(1).value(1), "");
+        assert_eq!(column(3).value(0), "");
+        assert_eq!(column(3).value(1), "N");
+
+        // NOTE:    a null element makes the sequence a list of bytes
+        let quals = batch.arrays()[2]
+            .as_any()
+            .downcast_ref::<ListArray<i64>>()
+            .unwrap();
+        assert_eq!(quals.value(0).len(), 2);
+        assert_eq!(quals.value(0).null_count(), 1);
+        assert_eq!(quals.value(1).len(), 2);
+    }
+
+    #[test]
+    fn fixes_the_schema_at_the_first_batch() {
+        let mut table = ColumnTable::new(["id"]);
+        table.push_row(&(None::<u64>,)).unwrap();
+        let batch = table.take_batch().unwrap();
+        assert_eq!(
+            batch.schema().get("id").unwrap().dtype,
+            ArrowDataType::LargeUtf8
+        );
+
+        assert!(table.push_row(&(1u64,)).is_err());
+        table.push_row(&("cell",)).unwrap();
+        assert_eq!(table.take_batch().unwrap().height(), 1);
+        assert!(table.push_row(&("cell", 1u64)).is_err());
+    }
+}
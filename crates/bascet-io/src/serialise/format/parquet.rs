@@ -0,0 +1,134 @@
+use std::io::Write;
+
+use bascet_core::AttrNames;
+use polars_arrow::datatypes::ArrowSchemaRef;
+use polars_arrow::record_batch::RecordBatch;
+use polars_parquet::write::{
+    CompressionOptions, Encoding, FileWriter, RowGroupIterator, StatisticsOptions, Version,
+    WriteOptions, transverse,
+};
+
+use super::columnar::{BatchSink, ColumnarError, ColumnarWriter};
+
+/// Parquet file with zstd-compressed pages, one row group per batch
+pub type ParquetWriter<W, A = ()> = ColumnarWriter<ParquetSink<W>, A>;
+
+pub struct ParquetSink<W: Write> {
+    writer: Option<W>,
+    parquet: Option<(FileWriter<W>, Vec<Vec<Encoding>>)>,
+    options: WriteOptions,
+}
+
+impl<W: Write> ParquetSink<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer: Some(writer),
+            parquet: None,
+            options: WriteOptions {
+                statistics: StatisticsOptions::default(),
+                version: Version::V2,
+                compression: CompressionOptions::Zstd(None),
+                data_page_size: None,
+            },
+        }
+    }
+
+    fn started(
+        &mut self,
+        schema: &ArrowSchemaRef,
+    ) -> Result<&mut (FileWriter<W>, Vec<Vec<Encoding>>), ColumnarError> {
+        if self.parquet.is_none() {
+            let writer = self.writer.take().expect("the writer is only taken once");
+            let encodings = schema
+                .iter_values()
+                .map(|field| transverse(&field.dtype, |_| Encoding::Plain))
+                .collect();
+            let parquet = FileWriter::try_new(writer, schema.as_ref().clone(), self.options)?;
+            self.parquet = Some((parquet, encodings));
+        }
+        Ok(self.parquet.as_mut().expect("started above"))
+    }
+}
+
+impl<W: Write> BatchSink for ParquetSink<W> {
+    type Writer = W;
+
+    fn write_batch(
+        &mut self,
+        schema: &ArrowSchemaRef,
+        batch: RecordBatch,
+    ) -> Result<(), ColumnarError> {
+        let options = self.options;
+        let (parquet, encodings) = self.started(schema)?;
+        let row_groups = RowGroupIterator::try_new(
+            std::iter::once(Ok(batch)),
+            schema,
+            options,
+            encodings.clone(),
+        )?;
+        for row_group in row_groups {
+            parquet.write(row_group?)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self, schema: &ArrowSchemaRef) -> Result<W, ColumnarError> {
+        let (parquet, _) = self.started(schema)?;
+        parquet.end(None)?;
+        let (parquet, _) = self.parquet.take().expect("started above");
+        Ok(parquet.into_inner())
+    }
+}
+
+impl<W: Write> ColumnarWriter<ParquetSink<W>, ()> {
+    /// A writer for plain table rows
+    pub fn new(writer: W) -> Self {
+        Self::from_sink(ParquetSink::new(writer), Vec::new())
+    }
+
+    /// A writer for the attributes `A` of composites, named after the attributes
+    pub fn with<A: AttrNames>(writer: W) -> ParquetWriter<W, A> {
+        ColumnarWriter::from_sink(ParquetSink::new(writer), A::names())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use polars_arrow::datatypes::ArrowDataType;
+    use polars_parquet::read::{infer_schema, read_metadata};
+
+    use super::*;
+
+    #[test]
+    fn writes_row_groups_per_batch() {
+        let mut writer = ParquetWriter::new(Cursor::new(Vec::new())).with_rows_per_batch(3);
+        for i in 0..7u32 {
+            let mut row = std::collections::BTreeMap::new();
+            row.insert("a_cell", format!("cell{i}"));
+            row.insert("b_reads", i.to_string());
+            writer.write_row(&row).unwrap();
+        }
+        let mut file = writer.finish().unwrap();
+
+        file.set_position(0);
+        let metadata = read_metadata(&mut file).unwrap();
+        assert_eq!(metadata.num_rows, 7);
+        assert_eq!(metadata.row_groups.len(), 3);
+
+        // NOTE:    the polars reader maps parquet strings to string views
+        let schema = infer_schema(&metadata).unwrap();
+        let fields: Vec<_> = schema
+            .iter_values()
+            .map(|field| (field.name.as_str(), field.dtype.clone()))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("a_cell", ArrowDataType::Utf8View),
+                ("b_reads", ArrowDataType::Utf8View)
+            ]
+        );
+    }
+}
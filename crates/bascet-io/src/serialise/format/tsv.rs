@@ -1,8 +1,8 @@
 use std::marker::PhantomData;
 
-use bascet_core::Composite;
+use bascet_core::{Composite, SerialiseView};
 
-use crate::{SerialiseView, Serialiser};
+use crate::Serialiser;
 
 pub struct TsvWriter<W, A = ()>
 where
@@ -31,15 +31,11 @@ impl<W: std::io::Write, A> Serialiser<A> for TsvWriter<W, A> {
         C: Composite,
         for<'a> SerialiseView<'a, C, A>: serde::Serialize,
     {
-        self.inner.serialize(&SerialiseView::<C, A>::new(cell))?;
+        self.inner.serialize(SerialiseView::<C, A>::new(cell))?;
         Ok(())
     }
 
-    fn inner(&self) -> &Self::Writer {
-        self.inner.get_ref()
-    }
-
-    fn into_inner(self) -> Self::Writer {
-        self.inner.into_inner().unwrap()
+    fn finish(self) -> Result<Self::Writer, Box<dyn std::error::Error>> {
+        Ok(self.inner.into_inner().map_err(|e| e.into_error())?)
     }
 }
@@ -1,5 +1,4 @@
-use crate::SerialiseView;
-use bascet_core::{Attr, Composite, Get};
+use bascet_core::{Composite, SerialiseView};
 
 pub trait Serialiser<A> {
     type Writer: std::io::Write;
@@ -9,18 +8,6 @@ pub trait Serialiser<A> {
         C: Composite,
         for<'a> SerialiseView<'a, C, A>: serde::Serialize;
 
-    fn inner(&self) -> &Self::Writer;
-    fn into_inner(self) -> Self::Writer;
-}
-
-pub trait SerialiseAttr<C>: Attr + Sized
-where
-    C: Get<Self> + Sized,
-{
-    type Output<'a>: serde::Serialize
-    where
-        C: 'a,
-        Self: 'a;
-
-    fn serialise<'a>(cell: &'a C) -> Self::Output<'a>;
+    /// Flush what is buffered, end the file and hand back the underlying writer
+    fn finish(self) -> Result<Self::Writer, Box<dyn std::error::Error>>;
 }
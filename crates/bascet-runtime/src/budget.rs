@@ -1,3 +1,7 @@
+mod limiter;
 mod traits;
+mod watch;
 
+pub use limiter::*;
 pub use traits::*;
+pub use watch::*;
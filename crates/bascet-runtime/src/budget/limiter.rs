@@ -0,0 +1,313 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use parking_lot::{Condvar, Mutex};
+
+/// Byte-charged semaphore for one `#[mem(...)]` budget bucket. `acquire(n)` blocks until `n`
+/// bytes are free; a request larger than the cap is charged the whole cap, so it proceeds once
+/// nothing else is outstanding. Bytes are returned when the permit drops
+pub struct MemoryLimiter {
+    name: String,
+    cap: usize,
+    used: Mutex<usize>,
+    available: Condvar,
+    wait_count: AtomicUsize,
+    max_used: AtomicUsize,
+}
+
+/// Usage of a limiter at one point in time
+#[derive(Clone, Debug)]
+pub struct MemoryLimiterStats {
+    pub name: String,
+    pub cap: usize,
+    pub used: usize,
+    pub max_used: usize,
+    pub wait_count: usize,
+}
+
+impl MemoryLimiter {
+    pub fn new(name: impl Into<String>, cap: usize) -> Self {
+        Self {
+            name: name.into(),
+            cap: cap.max(1),
+            used: Mutex::new(0),
+            available: Condvar::new(),
+            wait_count: AtomicUsize::new(0),
+            max_used: AtomicUsize::new(0),
+        }
+    }
+
+    fn registry() -> &'static Mutex<Vec<Arc<MemoryLimiter>>> {
+        static REGISTRY: Mutex<Vec<Arc<MemoryLimiter>>> = Mutex::new(Vec::new());
+        &REGISTRY
+    }
+
+    /// The process-wide limiter called `name`. A limiter registered with another cap (i.e.
+    /// by an earlier budget) is replaced, so that its high-water mark does not carry over
+    pub fn register(name: &str, cap: usize) -> Arc<Self> {
+        let mut registry = Self::registry().lock();
+        match registry.iter_mut().find(|limiter| limiter.name == name) {
+            Some(limiter) if limiter.cap == cap.max(1) => Arc::clone(limiter),
+            Some(limiter) => {
+                *limiter = Arc::new(Self::new(name, cap));
+                Arc::clone(limiter)
+            }
+            None => {
+                let limiter = Arc::new(Self::new(name, cap));
+                registry.push(Arc::clone(&limiter));
+                limiter
+            }
+        }
+    }
+
+    /// Stats of every registered limiter, in registration order
+    pub fn registered_stats() -> Vec<MemoryLimiterStats> {
+        Self::registry()
+            .lock()
+            .iter()
+            .map(|limiter| limiter.stats())
+            .collect()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    pub fn acquire(self: &Arc<Self>, bytes: usize) -> MemoryPermit {
+        let charge = bytes.min(self.cap);
+        if charge == 0 {
+            return MemoryPermit::new(0, Arc::clone(self));
+        }
+
+        let mut used = self.used.lock();
+        while *used + charge > self.cap {
+            self.wait_count.fetch_add(1, Ordering::Relaxed);
+            self.available.wait(&mut used);
+        }
+        *used += charge;
+        self.max_used.fetch_max(*used, Ordering::Relaxed);
+
+        MemoryPermit::new(charge, Arc::clone(self))
+    }
+
+    /// Like `acquire`, but returns None instead of blocking
+    pub fn try_acquire(self: &Arc<Self>, bytes: usize) -> Option<MemoryPermit> {
+        let charge = bytes.min(self.cap);
+        let mut used = self.used.lock();
+        if *used + charge > self.cap {
+            return None;
+        }
+        *used += charge;
+        self.max_used.fetch_max(*used, Ordering::Relaxed);
+
+        Some(MemoryPermit::new(charge, Arc::clone(self)))
+    }
+
+    fn release(&self, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+
+        let mut used = self.used.lock();
+        *used = used.saturating_sub(bytes);
+        self.available.notify_all();
+    }
+
+    pub fn stats(&self) -> MemoryLimiterStats {
+        MemoryLimiterStats {
+            name: self.name.clone(),
+            cap: self.cap,
+            used: *self.used.lock(),
+            max_used: self.max_used.load(Ordering::Relaxed),
+            wait_count: self.wait_count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Bytes held against a `MemoryLimiter`, returned on drop
+pub struct MemoryPermit {
+    bytes: usize,
+    limiter: Arc<MemoryLimiter>,
+}
+
+impl MemoryPermit {
+    fn new(bytes: usize, limiter: Arc<MemoryLimiter>) -> Self {
+        Self { bytes, limiter }
+    }
+
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    pub fn limiter(&self) -> &Arc<MemoryLimiter> {
+        &self.limiter
+    }
+
+    /// Grow or shrink the permit to `bytes`, for a structure that keeps growing over the run
+    /// (e.g. a count matrix). Never blocks, as nothing would free the bytes: returns false and
+    /// keeps the permit as it was if the limiter cannot fit the growth
+    pub fn try_resize(&mut self, bytes: usize) -> bool {
+        if bytes <= self.bytes {
+            self.limiter.release(self.bytes - bytes);
+            self.bytes = bytes;
+            return true;
+        }
+
+        let growth = bytes - self.bytes;
+        let mut used = self.limiter.used.lock();
+        if *used + growth > self.limiter.cap {
+            return false;
+        }
+        *used += growth;
+        self.limiter.max_used.fetch_max(*used, Ordering::Relaxed);
+        self.bytes = bytes;
+        true
+    }
+}
+
+impl Drop for MemoryPermit {
+    fn drop(&mut self) {
+        self.limiter.release(self.bytes);
+    }
+}
+
+/// Count-charged semaphore bounding the work items in flight between two pipeline stages
+pub struct InFlightLimiter {
+    cap: usize,
+    available: Mutex<usize>,
+    ready: Condvar,
+}
+
+impl InFlightLimiter {
+    pub fn new(cap: usize) -> Self {
+        let cap = cap.max(1);
+        Self {
+            cap,
+            available: Mutex::new(cap),
+            ready: Condvar::new(),
+        }
+    }
+
+    pub fn acquire(self: &Arc<Self>) -> InFlightPermit {
+        let mut available = self.available.lock();
+        while *available == 0 {
+            self.ready.wait(&mut available);
+        }
+        *available -= 1;
+
+        InFlightPermit {
+            limiter: Arc::clone(self),
+        }
+    }
+
+    fn release(&self) {
+        let mut available = self.available.lock();
+        *available += 1;
+        self.ready.notify_one();
+    }
+
+    /// Items in flight and the cap
+    pub fn stats(&self) -> (usize, usize) {
+        let available = *self.available.lock();
+        (self.cap.saturating_sub(available), self.cap)
+    }
+}
+
+/// One work item held against an `InFlightLimiter`, returned on drop
+pub struct InFlightPermit {
+    limiter: Arc<InFlightLimiter>,
+}
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        self.limiter.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits_charge_and_release_bytes() {
+        let limiter = Arc::new(MemoryLimiter::new("test", 100));
+
+        let a = limiter.acquire(60);
+        assert!(limiter.try_acquire(50).is_none());
+        let b = limiter.try_acquire(40).unwrap();
+        assert_eq!(limiter.stats().used, 100);
+
+        drop(a);
+        drop(b);
+        let oversized = limiter.acquire(1000);
+        assert_eq!(oversized.bytes(), 100);
+        drop(oversized);
+
+        let stats = limiter.stats();
+        assert_eq!(stats.used, 0);
+        assert_eq!(stats.max_used, 100);
+    }
+
+    #[test]
+    fn acquire_waits_for_release() {
+        let limiter = Arc::new(MemoryLimiter::new("test", 10));
+        let held = limiter.acquire(10);
+
+        let waiter = {
+            let limiter = Arc::clone(&limiter);
+            std::thread::spawn(move || limiter.acquire(5).bytes())
+        };
+        while limiter.stats().wait_count == 0 {
+            std::thread::yield_now();
+        }
+        drop(held);
+
+        assert_eq!(waiter.join().unwrap(), 5);
+        assert!(limiter.stats().wait_count >= 1);
+    }
+
+    #[test]
+    fn resize_grows_without_blocking_and_shrinks() {
+        let limiter = Arc::new(MemoryLimiter::new("test", 100));
+        let mut permit = limiter.acquire(0);
+
+        assert!(permit.try_resize(60));
+        let other = limiter.acquire(30);
+        assert!(!permit.try_resize(80));
+        assert_eq!(permit.bytes(), 60);
+
+        assert!(permit.try_resize(20));
+        assert_eq!(limiter.stats().used, 50);
+        drop(other);
+        drop(permit);
+
+        let stats = limiter.stats();
+        assert_eq!(stats.used, 0);
+        assert_eq!(stats.max_used, 90);
+    }
+
+    #[test]
+    fn in_flight_permits_are_counted() {
+        let limiter = Arc::new(InFlightLimiter::new(2));
+        let a = limiter.acquire();
+        let _b = limiter.acquire();
+        assert_eq!(limiter.stats(), (2, 2));
+        drop(a);
+        assert_eq!(limiter.stats(), (1, 2));
+    }
+
+    #[test]
+    fn register_reuses_limiters_with_the_same_cap() {
+        let a = MemoryLimiter::register("register-test", 10);
+        let b = MemoryLimiter::register("register-test", 10);
+        assert!(Arc::ptr_eq(&a, &b));
+
+        let c = MemoryLimiter::register("register-test", 20);
+        assert!(!Arc::ptr_eq(&a, &c));
+        assert_eq!(c.cap(), 20);
+    }
+}
@@ -12,4 +12,7 @@ pub trait Threads<T> {
 pub trait Memory<T> {
     type Value;
     fn mem(&self) -> &Self::Value;
+
+    /// Process-wide byte limiter sized to this bucket
+    fn limiter(&self) -> std::sync::Arc<crate::budget::MemoryLimiter>;
 }
@@ -0,0 +1,163 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU8, AtomicU64, Ordering};
+use std::time::Duration;
+
+use tracing::{error, info, warn};
+
+use crate::budget::MemoryLimiter;
+
+/// What to do when the process RSS grows past the declared `--memory` total
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MemoryEnforcement {
+    Off,
+    #[default]
+    Warn,
+    Fail,
+}
+
+/// Background sampler of the process RSS against the memory budget. Started by the first
+/// budget that is built; later budgets only replace the limit
+pub struct MemoryWatch;
+
+type ExceededHook = Box<dyn Fn(&str) + Send + Sync>;
+
+impl MemoryWatch {
+    pub const INTERVAL: Duration = Duration::from_millis(500);
+
+    /// Exit code of a run stopped by `MemoryEnforcement::Fail`
+    pub const EXIT_CODE: i32 = 3;
+
+    fn exceeded_hook() -> &'static OnceLock<ExceededHook> {
+        static EXCEEDED_HOOK: OnceLock<ExceededHook> = OnceLock::new();
+        &EXCEEDED_HOOK
+    }
+
+    /// Run `hook` with the error message before the watch stops the process under
+    /// `MemoryEnforcement::Fail`, e.g. to write the run report and flush the logs
+    pub fn on_exceeded(hook: impl Fn(&str) + Send + Sync + 'static) {
+        if Self::exceeded_hook().set(Box::new(hook)).is_err() {
+            warn!("Memory watch exceeded hook is already set; ignoring the new one");
+        }
+    }
+
+    fn limit() -> &'static AtomicU64 {
+        static LIMIT: AtomicU64 = AtomicU64::new(u64::MAX);
+        &LIMIT
+    }
+
+    fn peak_rss() -> &'static AtomicU64 {
+        static PEAK_RSS: AtomicU64 = AtomicU64::new(0);
+        &PEAK_RSS
+    }
+
+    fn enforcement() -> &'static AtomicU8 {
+        static ENFORCEMENT: AtomicU8 = AtomicU8::new(MemoryEnforcement::Warn as u8);
+        &ENFORCEMENT
+    }
+
+    pub fn set_enforcement(enforcement: MemoryEnforcement) {
+        Self::enforcement().store(enforcement as u8, Ordering::Relaxed);
+    }
+
+    fn current_enforcement() -> MemoryEnforcement {
+        match Self::enforcement().load(Ordering::Relaxed) {
+            0 => MemoryEnforcement::Off,
+            1 => MemoryEnforcement::Warn,
+            _ => MemoryEnforcement::Fail,
+        }
+    }
+
+    fn rss_bytes() -> Option<u64> {
+        memory_stats::memory_stats().map(|memory| memory.physical_mem as u64)
+    }
+
+    pub fn watch(total_mem_bytes: u64) {
+        Self::limit().store(total_mem_bytes.max(1), Ordering::Relaxed);
+        if Self::current_enforcement() == MemoryEnforcement::Off {
+            return;
+        }
+
+        static STARTED: OnceLock<()> = OnceLock::new();
+        STARTED.get_or_init(|| {
+            let spawned = std::thread::Builder::new()
+                .name("memory-watch@0".to_string())
+                .spawn(Self::run);
+            if let Err(err) = spawned {
+                warn!(error = %err, "Cannot start the memory watch; RSS will not be checked");
+            }
+        });
+    }
+
+    fn run() {
+        // NOTE:    warn once each time RSS crosses the limit, not on every sample above it
+        let mut over_limit = false;
+        loop {
+            std::thread::sleep(Self::INTERVAL);
+            let Some(rss) = Self::rss_bytes() else {
+                continue;
+            };
+            Self::peak_rss().fetch_max(rss, Ordering::Relaxed);
+
+            let limit = Self::limit().load(Ordering::Relaxed);
+            if rss <= limit {
+                over_limit = false;
+                continue;
+            }
+
+            match Self::current_enforcement() {
+                MemoryEnforcement::Off => {}
+                MemoryEnforcement::Warn => {
+                    if !std::mem::replace(&mut over_limit, true) {
+                        warn!(
+                            rss_bytes = rss,
+                            budget_bytes = limit,
+                            "Process RSS exceeds the memory budget"
+                        );
+                    }
+                }
+                MemoryEnforcement::Fail => {
+                    // NOTE:    a panic here would only end this thread, so stop the process
+                    //          directly, with its own exit code
+                    let message =
+                        format!("Memory budget exceeded: RSS {rss} bytes > budget {limit} bytes");
+                    error!(
+                        rss_bytes = rss,
+                        budget_bytes = limit,
+                        exit_code = Self::EXIT_CODE,
+                        "Process RSS exceeds the memory budget; stopping"
+                    );
+                    if let Some(hook) = Self::exceeded_hook().get() {
+                        hook(&message);
+                    }
+                    std::process::exit(Self::EXIT_CODE);
+                }
+            }
+        }
+    }
+
+    /// Log the high-water mark of every budget bucket a command charged, and the peak RSS seen
+    /// by the watch
+    pub fn log_summary() {
+        for stats in MemoryLimiter::registered_stats() {
+            info!(
+                bucket = %stats.name,
+                cap_bytes = stats.cap,
+                max_used_bytes = stats.max_used,
+                wait_count = stats.wait_count,
+                "Memory bucket high-water mark"
+            );
+        }
+
+        let limit = Self::limit().load(Ordering::Relaxed);
+        let peak_rss = Self::peak_rss()
+            .load(Ordering::Relaxed)
+            .max(Self::rss_bytes().unwrap_or(0));
+        if limit != u64::MAX {
+            info!(
+                peak_rss_bytes = peak_rss,
+                budget_bytes = limit,
+                "Memory budget high-water mark"
+            );
+        }
+    }
+}
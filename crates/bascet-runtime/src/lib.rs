@@ -1,5 +1,7 @@
 pub mod budget;
 pub mod logging;
+pub mod report;
 
 pub use budget::*;
 pub use logging::*;
+pub use report::*;
@@ -29,6 +29,8 @@ macro_rules! log_strictness_parser {
     };
 }
 
+// Comma separated: `terminal`, `json` or `discard` for the terminal, and/or a log file path.
+// A path alone also logs to the terminal, so `json,./latest.log` is JSON lines plus a file
 #[macro_export]
 macro_rules! log_mode_parser {
     ($mode_type:ty) => {
@@ -36,15 +38,27 @@ macro_rules! log_mode_parser {
             clap::builder::TypedValueParser::try_map(
                 clap::builder::NonEmptyStringValueParser::new(),
                 |s| -> Result<$mode_type, String> {
-                    match s.as_str() {
-                        "terminal" => Ok([<$mode_type>] { terminal: true, file: None }),
-                        "discard" => Ok([<$mode_type>] { terminal: false, file: None }),
-                        path => {
-                            let file = clio::OutputPath::new(path)
-                                .map_err(|e| format!("Invalid path '{}': {}", path, e))?;
-                            Ok([<$mode_type>] { terminal: true, file: Some(file) })
+                    let mut mode = [<$mode_type>] { terminal: true, json: false, file: None };
+                    for part in s.split(',') {
+                        match part.trim() {
+                            "terminal" => mode.terminal = true,
+                            "json" => {
+                                mode.terminal = true;
+                                mode.json = true;
+                            }
+                            "discard" => mode.terminal = false,
+                            "" => return Err(format!("Empty log mode in '{}'", s)),
+                            path => {
+                                if mode.file.is_some() {
+                                    return Err(format!("More than one log file in '{}'", s));
+                                }
+                                let file = clio::OutputPath::new(path)
+                                    .map_err(|e| format!("Invalid path '{}': {}", path, e))?;
+                                mode.file = Some(file);
+                            }
                         }
                     }
+                    Ok(mode)
                 },
             )
         }
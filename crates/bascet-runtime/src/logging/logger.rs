@@ -17,6 +17,8 @@ use crate::logging::writer::{BlockingWriter, MakeFile, MakeLogWriter, MakeStderr
 #[derive(Clone, Debug, Default)]
 pub struct LogMode {
     pub terminal: bool,
+    /// Terminal output as one JSON object per line, for tools like Zorn that parse the log
+    pub json: bool,
     pub file: Option<OutputPath>,
 }
 
@@ -55,6 +57,50 @@ macro_rules! terminal_layer {
     };
 }
 
+macro_rules! json_layer {
+    ($writer:expr) => {
+        fmt::layer()
+            .json()
+            .flatten_event(true)
+            .with_writer($writer)
+            .with_target(true)
+            .with_current_span(false)
+            .with_span_list(false)
+    };
+}
+
+// Layers for stdout (above WARN) and stderr (WARN and below), as text or as JSON lines. Exactly
+// one of the pair is Some
+macro_rules! terminal_layers {
+    ($json:expr, $stdout:expr, $stderr:expr) => {
+        if $json {
+            (
+                None,
+                Some(
+                    json_layer!($stdout)
+                        .with_filter(filter_fn(|meta| *meta.level() > Level::WARN))
+                        .and_then(
+                            json_layer!($stderr)
+                                .with_filter(filter_fn(|meta| *meta.level() <= Level::WARN)),
+                        ),
+                ),
+            )
+        } else {
+            (
+                Some(
+                    terminal_layer!($stdout)
+                        .with_filter(filter_fn(|meta| *meta.level() > Level::WARN))
+                        .and_then(
+                            terminal_layer!($stderr)
+                                .with_filter(filter_fn(|meta| *meta.level() <= Level::WARN)),
+                        ),
+                ),
+                None,
+            )
+        }
+    };
+}
+
 macro_rules! file_layer {
     ($writer:expr) => {
         fmt::layer()
@@ -110,18 +156,13 @@ impl LogGuard {
 
             (true, None) => {
                 // Terminal only
+                let (text, json) = terminal_layers!(config.mode.json, stdout, stderr);
                 tracing_subscriber::registry()
                     .with(
                         config.level, //
                     )
-                    .with(
-                        terminal_layer!(stdout)
-                            .with_filter(filter_fn(|meta| *meta.level() > Level::WARN)),
-                    )
-                    .with(
-                        terminal_layer!(stderr)
-                            .with_filter(filter_fn(|meta| *meta.level() <= Level::WARN)),
-                    )
+                    .with(text)
+                    .with(json)
                     .with(LogStrictnessLayer)
                     .init();
             }
@@ -141,6 +182,7 @@ impl LogGuard {
 
             (true, Some(file)) => {
                 // Terminal + File
+                let (text, json) = terminal_layers!(config.mode.json, stdout, stderr);
                 tracing_subscriber::registry()
                     .with(
                         config.level, //
@@ -148,14 +190,8 @@ impl LogGuard {
                     .with(
                         file_layer!(file), //
                     )
-                    .with(
-                        terminal_layer!(stdout)
-                            .with_filter(filter_fn(|meta| *meta.level() > Level::WARN)),
-                    )
-                    .with(
-                        terminal_layer!(stderr)
-                            .with_filter(filter_fn(|meta| *meta.level() <= Level::WARN)),
-                    )
+                    .with(text)
+                    .with(json)
                     .with(LogStrictnessLayer)
                     .init();
             }
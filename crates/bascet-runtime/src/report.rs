@@ -0,0 +1,10 @@
+mod progress;
+mod run_report;
+
+pub use progress::{Progress, StageGuard};
+pub use run_report::{
+    BudgetSummary, ProgressSummary, RunReport, RunResources, RunSummary, StageSummary,
+};
+
+/// Tracing target of the structured progress events, so they can be told apart from log text
+pub const PROGRESS_TARGET: &str = "bascet::progress";
@@ -0,0 +1,153 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tracing::info;
+
+use crate::report::{PROGRESS_TARGET, ProgressSummary, RunReport};
+
+/// Counters of one streaming stage. Updates are cheap; a progress event is emitted at most
+/// once per `Progress::INTERVAL`, with an ETA once a total is known
+pub struct Progress {
+    stage: String,
+    total_records: Option<u64>,
+    total_bytes_in: Option<u64>,
+    records: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    started: Instant,
+    next_event_millis: AtomicU64,
+}
+
+impl Progress {
+    pub const INTERVAL: Duration = Duration::from_secs(10);
+
+    pub fn new(stage: impl Into<String>) -> Self {
+        Self {
+            stage: stage.into(),
+            total_records: None,
+            total_bytes_in: None,
+            records: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            started: Instant::now(),
+            next_event_millis: AtomicU64::new(Self::INTERVAL.as_millis() as u64),
+        }
+    }
+
+    pub fn with_total_records(mut self, total: Option<u64>) -> Self {
+        self.total_records = total;
+        self
+    }
+
+    pub fn with_total_bytes_in(mut self, total: Option<u64>) -> Self {
+        self.total_bytes_in = total;
+        self
+    }
+
+    /// Register the stage with the run report and announce it
+    pub fn start(mut self) -> Arc<Self> {
+        self.started = Instant::now();
+        let progress = Arc::new(self);
+        RunReport::track(Arc::clone(&progress));
+        progress.emit("started");
+        progress
+    }
+
+    pub fn add(&self, records: u64, bytes_in: u64, bytes_out: u64) {
+        self.records.fetch_add(records, Ordering::Relaxed);
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+
+        let elapsed_millis = self.started.elapsed().as_millis() as u64;
+        let next_event_millis = self.next_event_millis.load(Ordering::Relaxed);
+        if elapsed_millis >= next_event_millis
+            && self
+                .next_event_millis
+                .compare_exchange(
+                    next_event_millis,
+                    elapsed_millis + Self::INTERVAL.as_millis() as u64,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            self.emit("running");
+        }
+    }
+
+    pub fn finish(&self) {
+        self.emit("done");
+    }
+
+    pub fn summary(&self) -> ProgressSummary {
+        ProgressSummary {
+            stage: self.stage.clone(),
+            records: self.records.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+
+    fn fraction_done(&self) -> Option<f64> {
+        let (done, total) = match (self.total_bytes_in, self.total_records) {
+            (Some(total), _) => (self.bytes_in.load(Ordering::Relaxed), total),
+            (None, Some(total)) => (self.records.load(Ordering::Relaxed), total),
+            (None, None) => return None,
+        };
+        (total > 0).then(|| (done as f64 / total as f64).min(1.0))
+    }
+
+    fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        let fraction = self.fraction_done().filter(|fraction| *fraction > 0.0)?;
+        Some(elapsed.mul_f64((1.0 - fraction) / fraction))
+    }
+
+    fn emit(&self, status: &'static str) {
+        let elapsed = self.started.elapsed();
+        let summary = self.summary();
+        info!(
+            target: PROGRESS_TARGET,
+            stage = %summary.stage,
+            status,
+            records = summary.records,
+            bytes_in = summary.bytes_in,
+            bytes_out = summary.bytes_out,
+            fraction_done = self.fraction_done(),
+            elapsed_seconds = elapsed.as_secs_f64(),
+            eta_seconds = self.eta(elapsed).map(|eta| eta.as_secs_f64()),
+            "Progress"
+        );
+    }
+}
+
+/// Wall time of a named stage, added to the run report when dropped
+pub struct StageGuard {
+    name: String,
+    started: Instant,
+}
+
+impl StageGuard {
+    pub fn new(name: impl Into<String>) -> Self {
+        let name = name.into();
+        info!(target: PROGRESS_TARGET, stage = %name, status = "started", "Stage");
+        Self {
+            name,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for StageGuard {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        RunReport::add_stage_time(&self.name, elapsed);
+        info!(
+            target: PROGRESS_TARGET,
+            stage = %self.name,
+            status = "done",
+            wall_seconds = elapsed.as_secs_f64(),
+            "Stage"
+        );
+    }
+}
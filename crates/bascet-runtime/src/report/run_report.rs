@@ -0,0 +1,215 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::report::{Progress, StageGuard};
+
+/// Process-wide record of a run: stages, progress counters, budget and outputs. Commands add to
+/// it as they go; `main` turns it into a `RunSummary` at exit
+pub struct RunReport;
+
+struct RunReportState {
+    command: String,
+    started: Option<Instant>,
+    stages: Vec<(String, Duration)>,
+    progress: Vec<Arc<Progress>>,
+    outputs: Vec<PathBuf>,
+    budget: Option<(u64, u64)>,
+}
+
+/// Resource usage of the whole process, measured by the caller at exit
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RunResources {
+    pub peak_rss_bytes: Option<u64>,
+    pub cpu_seconds: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    pub version: &'static str,
+    pub command: String,
+    pub success: bool,
+    pub error: Option<String>,
+    pub wall_seconds: f64,
+    pub cpu_seconds: Option<f64>,
+    pub peak_rss_bytes: Option<u64>,
+    pub budget: Option<BudgetSummary>,
+    pub stages: Vec<StageSummary>,
+    pub progress: Vec<ProgressSummary>,
+    pub outputs: Vec<PathBuf>,
+}
+
+/// Requested budget, and how much of it the run actually used
+#[derive(Debug, Serialize)]
+pub struct BudgetSummary {
+    pub threads: u64,
+    pub mem_bytes: u64,
+    pub cpu_utilisation: Option<f64>,
+    pub mem_utilisation: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StageSummary {
+    pub name: String,
+    pub wall_seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ProgressSummary {
+    pub stage: String,
+    pub records: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl RunReport {
+    fn state() -> &'static Mutex<RunReportState> {
+        static STATE: Mutex<RunReportState> = Mutex::new(RunReportState {
+            command: String::new(),
+            started: None,
+            stages: Vec::new(),
+            progress: Vec::new(),
+            outputs: Vec::new(),
+            budget: None,
+        });
+        &STATE
+    }
+
+    pub fn start(command: impl Into<String>, started: Instant) {
+        let mut state = Self::state().lock();
+        state.command = command.into();
+        state.started = Some(started);
+        state.stages.clear();
+        state.progress.clear();
+        state.outputs.clear();
+        state.budget = None;
+    }
+
+    pub fn stage(name: impl Into<String>) -> StageGuard {
+        StageGuard::new(name)
+    }
+
+    /// Add time to a stage. Repeated stages (e.g. one per library) are summed
+    pub fn add_stage_time(name: &str, duration: Duration) {
+        let mut state = Self::state().lock();
+        match state.stages.iter_mut().find(|(stage, _)| stage == name) {
+            Some((_, total)) => *total += duration,
+            None => state.stages.push((name.to_string(), duration)),
+        }
+    }
+
+    pub fn add_output(path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let mut state = Self::state().lock();
+        if !state.outputs.contains(&path) {
+            state.outputs.push(path);
+        }
+    }
+
+    pub fn set_budget(threads: u64, mem_bytes: u64) {
+        Self::state().lock().budget = Some((threads, mem_bytes));
+    }
+
+    pub(crate) fn track(progress: Arc<Progress>) {
+        Self::state().lock().progress.push(progress);
+    }
+
+    pub fn summary(success: bool, error: Option<String>, resources: RunResources) -> RunSummary {
+        let state = Self::state().lock();
+        let wall_seconds = state
+            .started
+            .map(|started| started.elapsed().as_secs_f64())
+            .unwrap_or_default();
+
+        let budget = state.budget.map(|(threads, mem_bytes)| BudgetSummary {
+            threads,
+            mem_bytes,
+            cpu_utilisation: resources
+                .cpu_seconds
+                .filter(|_| threads > 0 && wall_seconds > 0.0)
+                .map(|cpu_seconds| cpu_seconds / (wall_seconds * threads as f64)),
+            mem_utilisation: resources
+                .peak_rss_bytes
+                .filter(|_| mem_bytes > 0)
+                .map(|peak_rss_bytes| peak_rss_bytes as f64 / mem_bytes as f64),
+        });
+
+        RunSummary {
+            version: env!("CARGO_PKG_VERSION"),
+            command: state.command.clone(),
+            success,
+            error,
+            wall_seconds,
+            cpu_seconds: resources.cpu_seconds,
+            peak_rss_bytes: resources.peak_rss_bytes,
+            budget,
+            stages: state
+                .stages
+                .iter()
+                .map(|(name, duration)| StageSummary {
+                    name: name.clone(),
+                    wall_seconds: duration.as_secs_f64(),
+                })
+                .collect(),
+            progress: state
+                .progress
+                .iter()
+                .map(|progress| progress.summary())
+                .collect(),
+            outputs: state.outputs.clone(),
+        }
+    }
+}
+
+impl RunSummary {
+    pub fn write_json(&self, path: &Path) -> std::io::Result<()> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        serde_json::to_writer_pretty(&mut file, self)?;
+        writeln!(file)?;
+        file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_reports_stages_outputs_and_utilisation() {
+        RunReport::start("Test", Instant::now() - Duration::from_secs(10));
+        RunReport::set_budget(4, 1000);
+        RunReport::add_stage_time("merge", Duration::from_secs(1));
+        RunReport::add_stage_time("merge", Duration::from_secs(2));
+        RunReport::add_output("out.tirp.bbgz");
+        RunReport::add_output("out.tirp.bbgz");
+        Progress::new("read").start().add(3, 30, 10);
+
+        let summary = RunReport::summary(
+            true,
+            None,
+            RunResources {
+                peak_rss_bytes: Some(500),
+                cpu_seconds: Some(20.0),
+            },
+        );
+
+        assert_eq!(summary.command, "Test");
+        assert_eq!(summary.stages.len(), 1);
+        assert_eq!(summary.stages[0].wall_seconds, 3.0);
+        assert_eq!(summary.outputs.len(), 1);
+        assert_eq!(summary.progress[0].records, 3);
+
+        let budget = summary.budget.as_ref().unwrap();
+        assert_eq!(budget.mem_utilisation, Some(0.5));
+        assert!((budget.cpu_utilisation.unwrap() - 0.5).abs() < 0.01);
+
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["budget"]["threads"], 4);
+        assert_eq!(json["progress"][0]["stage"], "read");
+    }
+}